@@ -0,0 +1,116 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// Commits batches of two sizes, one large batch for every seven small ones,
+// timing each commit. Every batch updates the same keys, so a revision
+// deletes as many nodes as its batch wrote, and the commit that reaps it
+// frees them. Comparing the latency of the commits that reap a large
+// revision with the rest shows how much reaping costs a commit; run it with
+// and without --background-reaping.
+
+use crate::{keep_running, Committed, TestRunner};
+use firewood::db::{BatchOp, Db};
+use firewood::v2::api::{Db as _, Proposal as _};
+use log::info;
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::error::Error;
+use std::time::{Duration, Instant};
+
+#[derive(Clone)]
+pub struct CommitLatency;
+
+/// How many batches are small for each large one
+const SMALL_PER_LARGE: u64 = 7;
+
+impl TestRunner for CommitLatency {
+    async fn run(&self, db: &Db, args: &crate::Args) -> Result<Committed, Box<dyn Error>> {
+        let start = Instant::now();
+        let large = args.batch_size;
+        let small = (large / 100).max(1);
+        let mut committed = Committed::default();
+        // the batch sizes of the revisions that aren't reaped yet
+        let mut retained = VecDeque::new();
+        // the size of the revision each commit reaped, and how long it took
+        let mut samples = Vec::new();
+
+        let mut batch_id = 0;
+        while batch_id < args.number_of_batches && keep_running(start, args) {
+            let size = match batch_id % (SMALL_PER_LARGE + 1) {
+                0 => large,
+                _ => small,
+            };
+            let batch: Vec<_> = (0..size)
+                .map(|row| BatchOp::Put {
+                    key: Sha256::digest(row.to_ne_bytes()),
+                    value: batch_id.to_ne_bytes(),
+                })
+                .collect();
+            let proposal = db.propose(batch).await?;
+            let commit_start = Instant::now();
+            let root_hash = proposal.commit().await?;
+            let latency = commit_start.elapsed();
+            committed.batch(size as usize, root_hash);
+
+            // once --revisions are retained, each commit reaps the oldest
+            retained.push_back(size);
+            if retained.len() > args.revisions {
+                let reaped = retained.pop_front().unwrap_or_default();
+                samples.push((reaped, latency));
+            }
+            batch_id += 1;
+        }
+
+        let (reaping_large, reaping_small): (Vec<_>, Vec<_>) =
+            samples.iter().partition(|(reaped, _)| *reaped == large);
+        let latencies = |samples: &[&(u64, Duration)]| {
+            let mut latencies: Vec<_> = samples.iter().map(|(_, latency)| *latency).collect();
+            latencies.sort();
+            latencies
+        };
+        let (reaping_large, reaping_small) = (latencies(&reaping_large), latencies(&reaping_small));
+        let mut all: Vec<_> = reaping_large
+            .iter()
+            .chain(&reaping_small)
+            .copied()
+            .collect();
+        all.sort();
+        info!(
+            "commit latency p50 {:?}, p99 {:?}; reaping a batch of {large}: p50 {:?}, p99 {:?}; \
+             reaping a batch of {small}: p50 {:?}, p99 {:?}; correlation with reap size {:.3}",
+            percentile(&all, 50),
+            percentile(&all, 99),
+            percentile(&reaping_large, 50),
+            percentile(&reaping_large, 99),
+            percentile(&reaping_small, 50),
+            percentile(&reaping_small, 99),
+            correlation(&samples),
+        );
+        Ok(committed)
+    }
+}
+
+/// The `p`th percentile of `sorted`, or zero if it is empty
+fn percentile(sorted: &[Duration], p: usize) -> Duration {
+    let index = (sorted.len() * p / 100).min(sorted.len().saturating_sub(1));
+    sorted.get(index).copied().unwrap_or_default()
+}
+
+/// The Pearson correlation of the reap sizes and latencies of `samples`,
+/// or zero if either doesn't vary
+fn correlation(samples: &[(u64, Duration)]) -> f64 {
+    let n = samples.len() as f64;
+    let xs = samples.iter().map(|(reaped, _)| *reaped as f64);
+    let ys = samples.iter().map(|(_, latency)| latency.as_secs_f64());
+    let (mean_x, mean_y) = (xs.clone().sum::<f64>() / n, ys.clone().sum::<f64>() / n);
+    let (mut cov, mut var_x, mut var_y) = (0.0, 0.0, 0.0);
+    for (x, y) in xs.zip(ys) {
+        cov += (x - mean_x) * (y - mean_y);
+        var_x += (x - mean_x) * (x - mean_x);
+        var_y += (y - mean_y) * (y - mean_y);
+    }
+    match var_x * var_y {
+        product if product > 0.0 => cov / product.sqrt(),
+        _ => 0.0,
+    }
+}
@@ -7,37 +7,45 @@ use std::time::Instant;
 use fastrace::prelude::SpanContext;
 use fastrace::{func_path, Span};
 use firewood::db::Db;
-use firewood::v2::api::{Db as _, Proposal as _};
+use firewood::v2::api::Proposal as _;
 use log::info;
 
 use pretty_duration::pretty_duration;
 
-use crate::{Args, TestRunner};
+use crate::{interrupted, Args, Committed, TestRunner};
 
 #[derive(Clone)]
 pub struct Create;
 
 impl TestRunner for Create {
-    async fn run(&self, db: &Db, args: &Args) -> Result<(), Box<dyn Error>> {
+    async fn run(&self, db: &Db, args: &Args) -> Result<Committed, Box<dyn Error>> {
         let keys = args.batch_size;
         let start = Instant::now();
+        let mut committed = Committed::default();
 
         for key in 0..args.number_of_batches {
+            if interrupted() {
+                break;
+            }
             let root = Span::root(func_path!(), SpanContext::random());
             let _guard = root.set_local_parent();
 
-            let batch = Self::generate_inserts(key * keys, args.batch_size).collect();
-
-            let proposal = db.propose(batch).await.expect("proposal should succeed");
-            proposal.commit().await?;
+            // the rows are generated as the proposal inserts them
+            let batch = Self::generate_inserts(key * keys, args.batch_size, args.value_size);
+            let proposal = db
+                .propose_iter(batch)
+                .await
+                .expect("proposal should succeed");
+            let root_hash = proposal.commit().await?;
+            committed.batch(args.batch_size as usize, root_hash);
         }
         let duration = start.elapsed();
         info!(
             "Generated and inserted {} batches of size {keys} in {}",
-            args.number_of_batches,
+            committed.batches,
             pretty_duration(&duration, None)
         );
 
-        Ok(())
+        Ok(committed)
     }
 }
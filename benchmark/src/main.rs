@@ -11,22 +11,29 @@
 // 3. 50% of batch size is updating rows in the middle, but setting the value to the hash of the first row inserted
 //
 
-use clap::{Parser, Subcommand};
+use clap::error::ErrorKind;
+use clap::{CommandFactory as _, Parser, Subcommand};
 use fastrace_opentelemetry::OpenTelemetryReporter;
 use firewood::logger::trace;
-use log::LevelFilter;
+use log::{info, warn, LevelFilter};
 use metrics_exporter_prometheus::PrometheusBuilder;
 use metrics_util::MetricKindMask;
+use pretty_duration::pretty_duration;
+use rand::rngs::StdRng;
+use rand::SeedableRng as _;
+use serde::Serialize;
 use sha2::{Digest, Sha256};
 use std::borrow::Cow;
 use std::error::Error;
 use std::net::{Ipv6Addr, SocketAddr};
 use std::num::NonZeroUsize;
 use std::path::PathBuf;
-use std::time::Duration;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, Instant};
 
 use firewood::db::{BatchOp, Db, DbConfig};
-use firewood::manager::RevisionManagerConfig;
+use firewood::manager::{DurabilityMode, RevisionManagerConfig};
+use firewood::v2::api::HashKey;
 
 use fastrace::collector::Config;
 
@@ -61,12 +68,182 @@ struct Args {
         help = "Dump prometheus stats on exit"
     )]
     stats_dump: bool,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Distribution::Uniform,
+        help = "How the tenkrandom test picks the keys it updates"
+    )]
+    distribution: Distribution,
+    #[arg(
+        long,
+        default_value_t = 1.2,
+        help = "Exponent of the zipfian distribution"
+    )]
+    zipf_exponent: f64,
+    #[arg(
+        long,
+        help = "Seed for the random number generator. A random seed is logged if unset"
+    )]
+    seed: Option<u64>,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Size of the values written, in bytes: the 32 byte digest repeated out to this \
+                length. 0 writes the digest as it is, so the value of an inserted key equals \
+                the key"
+    )]
+    value_size: usize,
+    #[arg(
+        long,
+        default_value_t = 0,
+        value_parser = clap::value_parser!(u8).range(0..=100),
+        help = "Percentage of the keys written by each tenkrandom or zipf batch to read back \
+                and check once it is committed"
+    )]
+    read_verify_percent: u8,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Count failed verification reads and report them at the end, instead of \
+                stopping at the first"
+    )]
+    keep_going: bool,
+    #[arg(
+        long,
+        default_value_t = 0,
+        help = "Also check each batch's sample against its revision this many commits later, \
+                holding the revisions until then"
+    )]
+    verify_revision_lag: usize,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = OutputFormat::Text,
+        help = "How to report the results. json prints them to stdout as a single object"
+    )]
+    output_format: OutputFormat,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Workload::ReadWrite,
+        requires_if("read-only", "assume_preloaded_rows"),
+        help = "read-only looks up random keys of a preloaded database instead of running a test"
+    )]
+    workload: Workload,
+    #[arg(
+        long,
+        value_parser = clap::value_parser!(u64).range(1..),
+        help = "Number of rows the create test loaded the database with, which read-only \
+                looks up"
+    )]
+    assume_preloaded_rows: Option<u64>,
+    #[arg(
+        long,
+        value_enum,
+        default_value_t = Durability::Strict,
+        help = "When commits sync the database file to disk"
+    )]
+    durability: Durability,
+    #[arg(
+        long,
+        default_value_t = NonZeroUsize::new(16).expect("is non-zero"),
+        help = "With buffered durability, sync after this many commits"
+    )]
+    sync_commits: NonZeroUsize,
+    #[arg(
+        long,
+        default_value_t = 100,
+        help = "With buffered durability, sync after this many milliseconds"
+    )]
+    sync_interval_ms: u64,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Look up the areas of reaped revisions on a background thread instead of in \
+                the commit that reaps them"
+    )]
+    background_reaping: bool,
+    #[arg(
+        long,
+        default_value_t = false,
+        help = "Open the database with direct I/O, bypassing the page cache (Linux only)"
+    )]
+    direct_io: bool,
+    #[arg(
+        long,
+        default_value_t = NonZeroUsize::MIN,
+        help = "How many threads may hash the new nodes of a large proposal"
+    )]
+    hash_threads: NonZeroUsize,
+    #[arg(
+        long,
+        default_value_t = NonZeroUsize::MIN,
+        help = "How many threads may write the new nodes of a large commit"
+    )]
+    flush_parallelism: NonZeroUsize,
 
     #[clap(flatten)]
     global_opts: GlobalOpts,
 
+    /// The test to run, unless the workload is read-only
     #[clap(subcommand)]
-    test_name: TestName,
+    test_name: Option<TestName>,
+}
+
+impl Args {
+    /// A random number generator seeded with `--seed`, so runs can be
+    /// repeated
+    fn rng(&self) -> StdRng {
+        StdRng::seed_from_u64(self.seed.unwrap_or_default())
+    }
+
+    /// The durability mode `--durability` and the sync options pick
+    fn durability_mode(&self) -> DurabilityMode {
+        match self.durability {
+            Durability::Strict => DurabilityMode::Strict,
+            Durability::Buffered => DurabilityMode::Buffered {
+                commits: self.sync_commits,
+                interval: Duration::from_millis(self.sync_interval_ms),
+            },
+            Durability::None => DurabilityMode::None,
+        }
+    }
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Distribution {
+    /// Update a contiguous range of keys
+    Uniform,
+    /// Update keys drawn from a zipfian distribution, favoring the most
+    /// recently inserted ones
+    Zipfian,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Workload {
+    /// Run the given test
+    ReadWrite,
+    /// Only look up random keys, never proposing or committing
+    ReadOnly,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Durability {
+    /// Sync every commit
+    Strict,
+    /// Sync every --sync-commits commits or --sync-interval-ms milliseconds
+    Buffered,
+    /// Never sync
+    None,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum OutputFormat {
+    /// Log the results for people to read
+    Text,
+    /// Print the results to stdout as JSON, to compare runs with each other
+    Json,
 }
 
 #[derive(clap::Args, Debug)]
@@ -100,9 +277,12 @@ struct GlobalOpts {
     duration_minutes: u64,
 }
 
+mod commitlatency;
 mod create;
+mod readonly;
 mod single;
 mod tenkrandom;
+mod verify;
 mod zipf;
 
 #[derive(Debug, Subcommand, PartialEq)]
@@ -111,28 +291,106 @@ enum TestName {
     TenKRandom,
     Zipf(zipf::Args),
     Single,
+    CommitLatency,
+}
+
+impl TestName {
+    /// The name of the subcommand
+    const fn name(&self) -> &'static str {
+        match self {
+            TestName::Create => "create",
+            TestName::TenKRandom => "ten-k-random",
+            TestName::Zipf(_) => "zipf",
+            TestName::Single => "single",
+            TestName::CommitLatency => "commit-latency",
+        }
+    }
+}
+
+/// What a test committed
+#[derive(Clone, Debug, Default)]
+struct Committed {
+    batches: u64,
+    keys: u64,
+    /// The root hash returned by the last commit
+    root_hash: Option<HashKey>,
+}
+
+impl Committed {
+    /// Count a committed batch of `keys` keys, which made the revision with
+    /// `root_hash`
+    fn batch(&mut self, keys: usize, root_hash: Option<HashKey>) {
+        self.batches += 1;
+        self.keys += keys as u64;
+        self.root_hash = root_hash;
+    }
+}
+
+/// The results of a run, printed with `--output-format json`
+#[derive(Debug, Serialize)]
+struct Report {
+    test_name: &'static str,
+    seed: u64,
+    /// Time taken to open the database
+    setup_secs: f64,
+    /// Time taken by the test
+    run_secs: f64,
+    batches_committed: u64,
+    keys_committed: u64,
+    keys_per_sec: f64,
+    /// The hex root hash of the last revision the test committed, or None if
+    /// it is empty or the test committed nothing
+    root_hash: Option<String>,
+    /// The lookups made by the read-only workload
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reads: Option<readonly::Reads>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reads_per_sec: Option<f64>,
 }
 
 trait TestRunner {
-    async fn run(&self, db: &Db, args: &Args) -> Result<(), Box<dyn Error>>;
+    async fn run(&self, db: &Db, args: &Args) -> Result<Committed, Box<dyn Error>>;
 
     fn generate_inserts(
         start: u64,
         count: u64,
+        value_size: usize,
     ) -> impl Iterator<Item = BatchOp<Box<[u8]>, Box<[u8]>>> {
         (start..start + count)
-            .map(|inner_key| {
+            .map(move |inner_key| {
                 let digest: Box<[u8]> = Sha256::digest(inner_key.to_ne_bytes())[..].into();
                 trace!(
                     "inserting {:?} with digest {}",
                     inner_key,
                     hex::encode(&digest),
                 );
-                (digest.clone(), digest)
+                let value = sized_value(&digest, value_size);
+                (digest, value)
             })
             .map(|(key, value)| BatchOp::Put { key, value })
-            .collect::<Vec<_>>()
-            .into_iter()
+    }
+}
+
+/// Set on Ctrl-C, so the tests stop once the commit in progress finishes
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Whether Ctrl-C has been pressed
+fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::Relaxed)
+}
+
+/// Whether a steady-state test should start another batch: it hasn't been
+/// interrupted, or run for `--duration-minutes` since `start`
+fn keep_running(start: Instant, args: &Args) -> bool {
+    !interrupted() && start.elapsed().as_secs() / 60 < args.global_opts.duration_minutes
+}
+
+/// `digest` repeated out to `value_size` bytes, or `digest` itself if
+/// `value_size` is 0
+fn sized_value(digest: &[u8], value_size: usize) -> Box<[u8]> {
+    match value_size {
+        0 => digest.into(),
+        _ => digest.iter().copied().cycle().take(value_size).collect(),
     }
 }
 
@@ -162,9 +420,23 @@ async fn main() -> Result<(), Box<dyn Error>> {
     );
     fastrace::set_reporter(reporter, Config::default());
 
-    let args = Args::parse();
+    let mut args = Args::parse();
+    let seed = *args.seed.get_or_insert_with(rand::random);
 
-    if args.test_name == TestName::Single && args.batch_size > 1000 {
+    match (args.workload, &args.test_name) {
+        (Workload::ReadWrite, None) => Args::command()
+            .error(ErrorKind::MissingSubcommand, "a test is required")
+            .exit(),
+        (Workload::ReadOnly, Some(_)) => Args::command()
+            .error(
+                ErrorKind::ArgumentConflict,
+                "--workload read-only doesn't run a test",
+            )
+            .exit(),
+        _ => {}
+    }
+
+    if args.test_name == Some(TestName::Single) && args.batch_size > 1000 {
         panic!("Single test is not designed to handle batch sizes > 1000");
     }
 
@@ -177,6 +449,17 @@ async fn main() -> Result<(), Box<dyn Error>> {
             _ => LevelFilter::Info,
         })
         .init();
+    info!("random seed {seed}; pass --seed {seed} to repeat this run");
+
+    tokio::spawn(async {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            warn!("interrupted, stopping after the commit in progress; Ctrl-C again quits now");
+            INTERRUPTED.store(true, Ordering::Relaxed);
+        }
+        if tokio::signal::ctrl_c().await.is_ok() {
+            std::process::exit(130);
+        }
+    });
 
     // Manually set up prometheus
     let builder = PrometheusBuilder::new();
@@ -203,37 +486,98 @@ async fn main() -> Result<(), Box<dyn Error>> {
             NonZeroUsize::new(4 * args.batch_size as usize).expect("batch size > 0"),
         )
         .max_revisions(args.revisions)
+        .durability(args.durability_mode())
+        .background_reaping(args.background_reaping)
+        .direct_io(args.direct_io)
+        .hash_threads(args.hash_threads)
+        .flush_parallelism(args.flush_parallelism)
         .build();
     let cfg = DbConfig::builder()
-        .truncate(matches!(args.test_name, TestName::Create))
+        .truncate(matches!(args.test_name, Some(TestName::Create)))
         .manager(mgrcfg)
         .build();
 
+    let start = Instant::now();
     let db = Db::new(args.global_opts.dbname.clone(), cfg)
         .await
         .expect("db initiation should succeed");
+    let setup = start.elapsed();
 
-    match args.test_name {
-        TestName::Create => {
+    let start = Instant::now();
+    let mut reads = None;
+    let committed = match args.test_name {
+        None => {
+            let runner = readonly::ReadOnly;
+            reads = Some(runner.run(&db, &args).await?);
+            Committed::default()
+        }
+        Some(TestName::Create) => {
             let runner = create::Create;
-            runner.run(&db, &args).await?;
+            runner.run(&db, &args).await?
         }
-        TestName::TenKRandom => {
+        Some(TestName::TenKRandom) => {
             let runner = tenkrandom::TenKRandom;
-            runner.run(&db, &args).await?;
+            runner.run(&db, &args).await?
         }
-        TestName::Zipf(_) => {
+        Some(TestName::Zipf(_)) => {
             let runner = zipf::Zipf;
-            runner.run(&db, &args).await?;
+            runner.run(&db, &args).await?
         }
-        TestName::Single => {
+        Some(TestName::Single) => {
             let runner = single::Single;
-            runner.run(&db, &args).await?;
+            runner.run(&db, &args).await?
         }
-    }
+        Some(TestName::CommitLatency) => {
+            let runner = commitlatency::CommitLatency;
+            runner.run(&db, &args).await?
+        }
+    };
+    let run = start.elapsed();
+    let root_hash = committed.root_hash.as_ref().map(hex::encode);
 
     if args.stats_dump {
-        println!("{}", prometheus_handle.render());
+        // keep stdout for the report alone
+        match args.output_format {
+            OutputFormat::Text => println!("{}", prometheus_handle.render()),
+            OutputFormat::Json => eprintln!("{}", prometheus_handle.render()),
+        }
+    }
+
+    let reads_per_sec = reads
+        .as_ref()
+        .map(|reads| reads.total() as f64 / run.as_secs_f64());
+
+    match (args.output_format, &reads) {
+        (OutputFormat::Text, None) => info!(
+            "committed {} batches, {} keys, in {}; root hash {}",
+            committed.batches,
+            committed.keys,
+            pretty_duration(&run, None),
+            root_hash.as_deref().unwrap_or("none"),
+        ),
+        (OutputFormat::Text, Some(reads)) => info!(
+            "read {} keys in {}, {:.0} reads/sec; {} hits, {} misses",
+            reads.total(),
+            pretty_duration(&run, None),
+            reads_per_sec.unwrap_or_default(),
+            reads.hits,
+            reads.misses,
+        ),
+        (OutputFormat::Json, _) => {
+            let report = Report {
+                test_name: args.test_name.as_ref().map_or("read-only", TestName::name),
+                seed,
+                setup_secs: setup.as_secs_f64(),
+                run_secs: run.as_secs_f64(),
+                batches_committed: committed.batches,
+                keys_committed: committed.keys,
+                keys_per_sec: committed.keys as f64 / run.as_secs_f64(),
+                root_hash,
+                reads,
+                reads_per_sec,
+            };
+            println!("{}", serde_json::to_string(&report)?);
+        }
     }
 
     fastrace::flush();
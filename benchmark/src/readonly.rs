@@ -0,0 +1,67 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// Looks up random keys of a preloaded database without ever proposing or
+// committing, so read throughput can be measured without writes competing
+// with it. The keys are the ones the create test inserts, the SHA256 of the
+// row numbers; rows the steady-state tests deleted count as misses.
+
+use std::error::Error;
+use std::time::Instant;
+
+use firewood::db::Db;
+use firewood::v2::api::{Db as _, DbView as _};
+use log::trace;
+use rand::Rng as _;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{keep_running, Args};
+
+/// The lookups a read-only run made
+#[derive(Clone, Debug, Default, Serialize)]
+pub struct Reads {
+    /// Lookups that found a value
+    pub hits: u64,
+    /// Lookups of keys that weren't there
+    pub misses: u64,
+}
+
+impl Reads {
+    pub const fn total(&self) -> u64 {
+        self.hits + self.misses
+    }
+}
+
+#[derive(Clone)]
+pub struct ReadOnly;
+
+impl ReadOnly {
+    pub async fn run(&self, db: &Db, args: &Args) -> Result<Reads, Box<dyn Error>> {
+        let rows = args
+            .assume_preloaded_rows
+            .expect("clap requires it with --workload read-only");
+        let mut rng = args.rng();
+        let mut reads = Reads::default();
+        let start = Instant::now();
+
+        // each batch reads the latest revision, as a reader would
+        while keep_running(start, args) {
+            let root_hash = db
+                .root_hash()
+                .await?
+                .ok_or("the database is empty; load it with the create test first")?;
+            let revision = db.revision(root_hash).await?;
+            for _ in 0..args.batch_size {
+                let row = rng.gen_range(0..rows);
+                let key = Sha256::digest(row.to_ne_bytes());
+                trace!("reading {row} with digest {}", hex::encode(key));
+                match revision.val(key).await? {
+                    Some(_) => reads.hits += 1,
+                    None => reads.misses += 1,
+                }
+            }
+        }
+        Ok(reads)
+    }
+}
@@ -1,7 +1,7 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use crate::TestRunner;
+use crate::{keep_running, Committed, TestRunner};
 use firewood::db::{BatchOp, Db};
 use firewood::v2::api::{Db as _, Proposal as _};
 use log::debug;
@@ -14,14 +14,15 @@ use std::time::Instant;
 pub struct Single;
 
 impl TestRunner for Single {
-    async fn run(&self, db: &Db, args: &crate::Args) -> Result<(), Box<dyn Error>> {
+    async fn run(&self, db: &Db, args: &crate::Args) -> Result<Committed, Box<dyn Error>> {
         let start = Instant::now();
         let inner_keys: Vec<_> = (0..args.batch_size)
             .map(|i| Sha256::digest(i.to_ne_bytes()))
             .collect();
         let mut batch_id = 0;
+        let mut committed = Committed::default();
 
-        while start.elapsed().as_secs() / 60 < args.global_opts.duration_minutes {
+        while keep_running(start, args) {
             let batch = inner_keys
                 .iter()
                 .map(|key| BatchOp::Put {
@@ -30,7 +31,8 @@ impl TestRunner for Single {
                 })
                 .collect();
             let proposal = db.propose(batch).await.expect("proposal should succeed");
-            proposal.commit().await?;
+            let root_hash = proposal.commit().await?;
+            committed.batch(inner_keys.len(), root_hash);
 
             if log::log_enabled!(log::Level::Debug) && batch_id % 1000 == 999 {
                 debug!(
@@ -41,6 +43,6 @@ impl TestRunner for Single {
             }
             batch_id += 1;
         }
-        Ok(())
+        Ok(committed)
     }
 }
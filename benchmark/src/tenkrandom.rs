@@ -8,40 +8,91 @@ use firewood::db::{BatchOp, Db};
 use firewood::logger::debug;
 use firewood::v2::api::{Db as _, Proposal as _};
 
-use crate::{Args, TestRunner};
+use crate::verify::Verifier;
+use crate::{keep_running, sized_value, Args, Committed, Distribution, TestRunner};
+use rand::prelude::Distribution as _;
+use rand::rngs::StdRng;
 use sha2::{Digest, Sha256};
 
 #[derive(Clone, Default)]
 pub struct TenKRandom;
 
 impl TestRunner for TenKRandom {
-    async fn run(&self, db: &Db, args: &Args) -> Result<(), Box<dyn Error>> {
+    async fn run(&self, db: &Db, args: &Args) -> Result<Committed, Box<dyn Error>> {
         let mut low = 0;
         let mut high = args.number_of_batches * args.batch_size;
         let twenty_five_pct = args.batch_size / 4;
+        let mut rng = args.rng();
+        let mut verifier = Verifier::new(args);
+        let mut committed = Committed::default();
+        // the range of keys moves, but its length doesn't, so neither does
+        // the distribution over it
+        #[allow(deprecated)] // as in the zipf test
+        let zipf = match args.distribution {
+            Distribution::Uniform => None,
+            Distribution::Zipfian => Some(
+                ::zipf::ZipfDistribution::new((high - low) as usize, args.zipf_exponent)
+                    .expect("zipf exponent should be positive"),
+            ),
+        };
 
         let start = Instant::now();
 
-        while start.elapsed().as_secs() / 60 < args.global_opts.duration_minutes {
-            let batch: Vec<BatchOp<_, _>> = Self::generate_inserts(high, twenty_five_pct)
+        while keep_running(start, args) {
+            let batch = Self::generate_inserts(high, twenty_five_pct, args.value_size)
                 .chain(generate_deletes(low, twenty_five_pct))
-                .chain(generate_updates(low + high / 2, twenty_five_pct * 2, low))
-                .collect();
-            let proposal = db.propose(batch).await.expect("proposal should succeed");
-            proposal.commit().await?;
+                .chain(generate_updates(
+                    low + high / 2,
+                    twenty_five_pct * 2,
+                    (low, high),
+                    zipf.as_ref(),
+                    &mut rng,
+                    args.value_size,
+                ));
+            let batch_len = (twenty_five_pct * 4) as usize;
+            // the batch is only collected when sampling needs it; otherwise
+            // the proposal applies the ops as they are generated
+            let (proposal, samples) = if verifier.is_sampling() {
+                let batch: Vec<_> = batch.collect();
+                let samples = verifier.sample(&batch);
+                (db.propose(batch).await, samples)
+            } else {
+                (db.propose_iter(batch).await, Vec::new())
+            };
+            let proposal = proposal.expect("proposal should succeed");
+            let root_hash = proposal.commit().await?;
+            verifier.check(db, root_hash.clone(), samples).await?;
+            committed.batch(batch_len, root_hash);
             low += twenty_five_pct;
             high += twenty_five_pct;
         }
-        Ok(())
+        verifier.summarize()?;
+        Ok(committed)
     }
 }
+/// Updates `count` of the keys in `[low, high)`: the ones from `start` on,
+/// or ones sampled from `zipf` if it is set
+#[allow(deprecated)] // as in the zipf test
 fn generate_updates(
     start: u64,
     count: u64,
-    low: u64,
+    (low, high): (u64, u64),
+    zipf: Option<&::zipf::ZipfDistribution>,
+    rng: &mut StdRng,
+    value_size: usize,
 ) -> impl Iterator<Item = BatchOp<Box<[u8]>, Box<[u8]>>> {
-    let hash_of_low: Box<[u8]> = Sha256::digest(low.to_ne_bytes())[..].into();
-    (start..start + count)
+    let hash_of_low = sized_value(&Sha256::digest(low.to_ne_bytes()), value_size);
+    let inner_keys: Vec<u64> = match zipf {
+        // rank 1 is the most recently inserted key, the hottest one
+        Some(zipf) => zipf
+            .sample_iter(rng)
+            .take(count as usize)
+            .map(|rank| high - rank as u64)
+            .collect(),
+        None => (start..start + count).collect(),
+    };
+    inner_keys
+        .into_iter()
         .map(|inner_key| {
             let digest = Sha256::digest(inner_key.to_ne_bytes())[..].into();
             debug!(
@@ -0,0 +1,152 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// Reads back a sample of each committed batch, to turn long steady-state runs
+// into a correctness soak. The expected value of a sampled key is the last
+// write to it in its batch, so nothing beyond the pending samples is kept.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::error::Error;
+use std::sync::Arc;
+
+use firewood::db::{BatchOp, Db};
+use firewood::v2::api::{self, Db as _, DbView as _, HashKey, KeyType, ValueType};
+use log::{info, warn};
+use rand::rngs::StdRng;
+use rand::Rng as _;
+
+use crate::Args;
+
+/// The value a sampled key should read as, or None if it was deleted
+type Expected = (Box<[u8]>, Option<Box<[u8]>>);
+
+type Revision = Arc<<Db as api::Db>::Historical>;
+
+pub struct Verifier {
+    percent: f64,
+    keep_going: bool,
+    lag: usize,
+    rng: StdRng,
+    /// The revisions committed in the last `lag` batches with their samples,
+    /// oldest first. Holding them pins them against reaping.
+    pending: VecDeque<(Revision, Vec<Expected>)>,
+    reads: u64,
+    failures: u64,
+}
+
+impl Verifier {
+    pub fn new(args: &Args) -> Self {
+        Self {
+            percent: f64::from(args.read_verify_percent),
+            keep_going: args.keep_going,
+            lag: args.verify_revision_lag,
+            // separate from the test's own generator, so verifying doesn't
+            // change the batches
+            rng: args.rng(),
+            pending: VecDeque::new(),
+            reads: 0,
+            failures: 0,
+        }
+    }
+
+    /// Whether any keys are picked to verify, which needs the whole batch
+    pub fn is_sampling(&self) -> bool {
+        self.percent > 0.0
+    }
+
+    /// Pick the keys of `batch` to verify once it's committed
+    pub fn sample<K: KeyType, V: ValueType>(&mut self, batch: &[BatchOp<K, V>]) -> Vec<Expected> {
+        if self.percent == 0.0 {
+            return Vec::new();
+        }
+        let mut last_writes = BTreeMap::new();
+        for op in batch {
+            match op {
+                BatchOp::Put { key, value } => {
+                    last_writes.insert(key.as_ref(), Some(value.as_ref()));
+                }
+                BatchOp::Delete { key } => {
+                    last_writes.insert(key.as_ref(), None);
+                }
+                BatchOp::DeleteRange { .. } => unreachable!("the tests don't delete ranges"),
+            }
+        }
+        last_writes
+            .into_iter()
+            .filter(|_| self.rng.gen_bool(self.percent / 100.0))
+            .map(|(key, value)| (key.into(), value.map(Into::into)))
+            .collect()
+    }
+
+    /// Check `samples` against the revision committed with `root_hash`, and
+    /// the samples taken `--verify-revision-lag` commits ago against theirs
+    pub async fn check(
+        &mut self,
+        db: &Db,
+        root_hash: Option<HashKey>,
+        samples: Vec<Expected>,
+    ) -> Result<(), Box<dyn Error>> {
+        if self.percent == 0.0 {
+            return Ok(());
+        }
+        let Some(root_hash) = root_hash else {
+            // every key was deleted, and there's no revision to read
+            return Ok(());
+        };
+        let revision = db.revision(root_hash).await?;
+        self.check_revision(&revision, &samples, 0).await?;
+
+        if self.lag > 0 {
+            self.pending.push_back((revision, samples));
+            if self.pending.len() > self.lag {
+                let (revision, samples) = self.pending.pop_front().expect("is not empty");
+                self.check_revision(&revision, &samples, self.lag).await?;
+            }
+        }
+        Ok(())
+    }
+
+    async fn check_revision(
+        &mut self,
+        revision: &Revision,
+        samples: &[Expected],
+        lag: usize,
+    ) -> Result<(), Box<dyn Error>> {
+        for (key, expected) in samples {
+            self.reads += 1;
+            let failure = match revision.val(key).await {
+                Ok(actual) if actual == *expected => continue,
+                Ok(actual) => format!(
+                    "read {} for {}, expected {}",
+                    display(actual.as_deref()),
+                    hex::encode(key),
+                    display(expected.as_deref()),
+                ),
+                Err(err) => format!("reading {} failed: {err}", hex::encode(key)),
+            };
+            let failure = format!("{failure}, {lag} commits back");
+            if !self.keep_going {
+                return Err(failure.into());
+            }
+            warn!("verification failed: {failure}");
+            self.failures += 1;
+        }
+        Ok(())
+    }
+
+    /// Log how many reads were verified, and fail if any didn't match
+    pub fn summarize(&self) -> Result<(), Box<dyn Error>> {
+        if self.percent == 0.0 {
+            return Ok(());
+        }
+        info!("verified {} reads, {} failed", self.reads, self.failures);
+        match self.failures {
+            0 => Ok(()),
+            failures => Err(format!("{failures} verification reads failed").into()),
+        }
+    }
+}
+
+fn display(value: Option<&[u8]>) -> String {
+    value.map_or_else(|| "nothing".to_string(), hex::encode)
+}
@@ -1,13 +1,14 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use crate::TestRunner;
+use crate::verify::Verifier;
+use crate::{keep_running, sized_value, Committed, TestRunner};
 use firewood::db::{BatchOp, Db};
 use firewood::v2::api::{Db as _, Proposal as _};
 use log::{debug, trace};
 use pretty_duration::pretty_duration;
 use rand::prelude::Distribution as _;
-use rand::thread_rng;
+use rand::rngs::StdRng;
 use sha2::{Digest, Sha256};
 use std::collections::HashSet;
 use std::error::Error;
@@ -23,8 +24,8 @@ pub struct Args {
 pub struct Zipf;
 
 impl TestRunner for Zipf {
-    async fn run(&self, db: &Db, args: &crate::Args) -> Result<(), Box<dyn Error>> {
-        let exponent = if let crate::TestName::Zipf(args) = &args.test_name {
+    async fn run(&self, db: &Db, args: &crate::Args) -> Result<Committed, Box<dyn Error>> {
+        let exponent = if let Some(crate::TestName::Zipf(args)) = &args.test_name {
             args.exponent
         } else {
             unreachable!()
@@ -33,10 +34,19 @@ impl TestRunner for Zipf {
         let zipf = zipf::ZipfDistribution::new(rows, exponent).unwrap();
         let start = Instant::now();
         let mut batch_id = 0;
+        let mut rng = args.rng();
+        let mut verifier = Verifier::new(args);
+        let mut committed = Committed::default();
 
-        while start.elapsed().as_secs() / 60 < args.global_opts.duration_minutes {
-            let batch: Vec<BatchOp<_, _>> =
-                generate_updates(batch_id, args.batch_size as usize, &zipf).collect();
+        while keep_running(start, args) {
+            let batch: Vec<BatchOp<_, _>> = generate_updates(
+                batch_id,
+                args.batch_size as usize,
+                &zipf,
+                &mut rng,
+                args.value_size,
+            )
+            .collect();
             if log::log_enabled!(log::Level::Debug) {
                 let mut distinct = HashSet::new();
                 for op in &batch {
@@ -53,8 +63,12 @@ impl TestRunner for Zipf {
                     distinct.len()
                 );
             }
+            let samples = verifier.sample(&batch);
+            let batch_len = batch.len();
             let proposal = db.propose(batch).await.expect("proposal should succeed");
-            proposal.commit().await?;
+            let root_hash = proposal.commit().await?;
+            verifier.check(db, root_hash.clone(), samples).await?;
+            committed.batch(batch_len, root_hash);
 
             if log::log_enabled!(log::Level::Debug) {
                 debug!(
@@ -65,16 +79,19 @@ impl TestRunner for Zipf {
             }
             batch_id += 1;
         }
-        Ok(())
+        verifier.summarize()?;
+        Ok(committed)
     }
 }
 fn generate_updates(
     batch_id: u32,
     batch_size: usize,
     zipf: &zipf::ZipfDistribution,
+    rng: &mut StdRng,
+    value_size: usize,
 ) -> impl Iterator<Item = BatchOp<Vec<u8>, Vec<u8>>> {
-    let hash_of_batch_id = Sha256::digest(batch_id.to_ne_bytes()).to_vec();
-    let rng = thread_rng();
+    let hash_of_batch_id =
+        sized_value(&Sha256::digest(batch_id.to_ne_bytes()), value_size).to_vec();
     zipf.sample_iter(rng)
         .take(batch_size)
         .map(|inner_key| {
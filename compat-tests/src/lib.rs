@@ -0,0 +1,317 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Checks that this version of firewood is compatible with the last
+//! release, `firewood_old`: both compute the same root hashes for the same
+//! batches, this version reads and proves everything in files the release
+//! wrote, and every intentional divergence only appears behind its [Gate].
+//!
+//! The two versions are driven through the [Firewood] adapter, so the
+//! workloads in [workload] and the checks below are written once. When an
+//! API changes, only the adapter of the version that changed needs to
+//! follow it.
+
+use std::collections::BTreeMap;
+use std::path::Path;
+
+use async_trait::async_trait;
+
+pub mod workload;
+
+/// A put, or a delete if the value is None
+pub type Op = (Vec<u8>, Option<Vec<u8>>);
+
+/// The ops committed together in one revision
+pub type Batch = Vec<Op>;
+
+/// What every key a workload touched should read as; None for keys that
+/// were deleted
+pub type Model = BTreeMap<Vec<u8>, Option<Vec<u8>>>;
+
+/// The operations the compatibility checks need from a version of firewood.
+/// Failures panic, since any failure fails the check.
+#[async_trait]
+pub trait Firewood: Sized + Send + Sync {
+    /// Create an empty database at `path`
+    async fn create(path: &Path) -> Self;
+
+    /// Open the existing database at `path`
+    async fn open(path: &Path) -> Self;
+
+    /// Whether the existing database at `path` opens, rather than being
+    /// refused
+    async fn can_open(path: &Path) -> bool;
+
+    /// Propose and commit `batch`, and return the new root hash
+    async fn commit(&self, batch: &[Op]) -> Option<[u8; 32]>;
+
+    /// The root hash of the latest revision, or None if it is empty
+    async fn root_hash(&self) -> Option<[u8; 32]>;
+
+    /// Read `key` from the latest revision
+    async fn read(&self, key: &[u8]) -> Option<Vec<u8>>;
+
+    /// Check that a proof from the latest revision shows `key` has `value`
+    async fn verify(&self, key: &[u8], value: Option<&[u8]>) -> bool;
+}
+
+/// Implements [Firewood] for a wrapper around the `Db` of one version. The
+/// versions share this while their APIs agree; a version whose API changes
+/// gets its own implementation.
+macro_rules! adapter {
+    ($name:ident, $firewood:ident, $doc:literal) => {
+        #[doc = $doc]
+        #[derive(Debug)]
+        pub struct $name($firewood::db::Db);
+
+        #[async_trait]
+        impl Firewood for $name {
+            async fn create(path: &Path) -> Self {
+                let config = $firewood::db::DbConfig::builder().truncate(true).build();
+                let db = $firewood::db::Db::new(path, config).await;
+                Self(db.expect("can create a database"))
+            }
+
+            async fn open(path: &Path) -> Self {
+                let config = $firewood::db::DbConfig::builder().truncate(false).build();
+                let db = $firewood::db::Db::new(path, config).await;
+                Self(db.expect("can open the database"))
+            }
+
+            async fn can_open(path: &Path) -> bool {
+                let config = $firewood::db::DbConfig::builder().truncate(false).build();
+                $firewood::db::Db::new(path, config).await.is_ok()
+            }
+
+            async fn commit(&self, batch: &[Op]) -> Option<[u8; 32]> {
+                use $firewood::v2::api::{BatchOp, Db as _, Proposal as _};
+
+                let batch: Vec<BatchOp<Vec<u8>, Vec<u8>>> = batch
+                    .iter()
+                    .map(|(key, value)| match value {
+                        Some(value) => BatchOp::Put {
+                            key: key.clone(),
+                            value: value.clone(),
+                        },
+                        None => BatchOp::Delete { key: key.clone() },
+                    })
+                    .collect();
+                let proposal = self.0.propose(batch).await.expect("can propose");
+                proposal.commit().await.expect("can commit");
+                self.root_hash().await
+            }
+
+            async fn root_hash(&self) -> Option<[u8; 32]> {
+                use $firewood::v2::api::Db as _;
+
+                // an empty database reports its missing root hash as an error
+                let hash = self.0.root_hash().await.ok().flatten()?;
+                Some(hash.as_ref().try_into().expect("root hashes are 32 bytes"))
+            }
+
+            async fn read(&self, key: &[u8]) -> Option<Vec<u8>> {
+                use $firewood::v2::api::{Db as _, DbView as _};
+
+                let root_hash = self.0.root_hash().await.ok().flatten()?;
+                let revision = self.0.revision(root_hash).await;
+                let revision = revision.expect("the latest revision is retained");
+                let value = revision.val(key).await.expect("can read");
+                value.map(Vec::from)
+            }
+
+            async fn verify(&self, key: &[u8], value: Option<&[u8]>) -> bool {
+                use $firewood::v2::api::{Db as _, DbView as _};
+
+                let Some(root_hash) = self.0.root_hash().await.ok().flatten() else {
+                    // there is nothing to prove against in an empty database
+                    return value.is_none();
+                };
+                let revision = self.0.revision(root_hash.clone()).await;
+                let revision = revision.expect("the latest revision is retained");
+                let proof = revision.single_key_proof(key).await.expect("can prove");
+                proof.verify(key, value, &root_hash).is_ok()
+            }
+        }
+    };
+}
+
+adapter!(OldFirewood, firewood_old, "The last release of firewood");
+adapter!(NewFirewood, firewood, "This version of firewood");
+
+/// An intentional divergence from the last release, which must only appear
+/// once its gate is turned on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Gate {
+    /// Frozen prefixes are recorded in the reserved key space, which is part
+    /// of the root hash
+    FrozenPrefixes,
+}
+
+impl Gate {
+    /// Every gate, each of which [assert_divergence_gated] checks
+    pub const ALL: [Gate; 1] = [Gate::FrozenPrefixes];
+}
+
+impl NewFirewood {
+    /// Turn `gate` on or off, committing whatever that takes
+    pub async fn set_gate(&self, gate: Gate, enabled: bool) {
+        const PREFIX: &[u8] = b"compat";
+        match (gate, enabled) {
+            (Gate::FrozenPrefixes, true) => self.0.freeze_prefix(PREFIX).await,
+            (Gate::FrozenPrefixes, false) => {
+                let acknowledgement = format!("unfreeze {}", hex(PREFIX));
+                self.0.unfreeze_prefix(PREFIX, &acknowledgement).await
+            }
+        }
+        .expect("can change the gate");
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+/// Apply `batch` to `model`
+pub fn apply_to_model(model: &mut Model, batch: &[Op]) {
+    for (key, value) in batch {
+        model.insert(key.clone(), value.clone());
+    }
+}
+
+/// Check that every key in `model` reads as expected in `db`, and that
+/// the keys that are present are proven to be.
+///
+/// Proofs that a key is absent aren't checked: neither version can prove
+/// the absence of the empty key, or of any key when the root is a leaf.
+pub async fn assert_matches_model<F: Firewood>(db: &F, model: &Model) {
+    for (key, expected) in model {
+        assert_eq!(&db.read(key).await, expected, "key {key:?}");
+        if expected.is_some() {
+            assert!(db.verify(key, expected.as_deref()).await, "key {key:?}");
+        }
+    }
+}
+
+/// The root hash the last release computes for the contents of `model`,
+/// committed in one batch to a new database at `path`
+pub async fn release_hash(path: &Path, model: &Model) -> Option<[u8; 32]> {
+    let contents: Batch = model
+        .iter()
+        .filter(|(_, value)| value.is_some())
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    let old = OldFirewood::create(path).await;
+    match contents.is_empty() {
+        true => old.root_hash().await,
+        false => old.commit(&contents).await,
+    }
+}
+
+/// Commit `workload` with both versions, in new databases in `dir`, and
+/// check that their root hashes agree after every commit and that both
+/// read every key as they should. Returns the final model.
+///
+/// Releases up to 0.0.4 lose entries when some deletes restructure the
+/// trie, so their root hashes after such a batch are wrong. A difference is
+/// only accepted when the release computes this version's root hash for
+/// the same contents committed from scratch; any change to how contents are
+/// hashed still fails. The check then carries on from that rebuilt
+/// database.
+pub async fn assert_same_hashes(dir: &Path, workload: &[Batch]) -> Model {
+    let mut old = OldFirewood::create(&dir.join("old")).await;
+    let new = NewFirewood::create(&dir.join("new")).await;
+    let mut model = Model::new();
+    for (i, batch) in workload.iter().enumerate() {
+        let old_hash = old.commit(batch).await;
+        let new_hash = new.commit(batch).await;
+        apply_to_model(&mut model, batch);
+        if new_hash != old_hash {
+            let rebuilt = dir.join(format!("rebuilt-{i}"));
+            let release_hash = release_hash(&rebuilt, &model).await;
+            assert_eq!(new_hash, release_hash, "root hashes differ after batch {i}");
+            old = OldFirewood::open(&rebuilt).await;
+        }
+    }
+    assert_matches_model(&old, &model).await;
+    assert_matches_model(&new, &model).await;
+    model
+}
+
+/// Commit `workload` with the last release, copy the file it wrote, and
+/// check that this version opens the copy, computes the same root hash and
+/// reads and proves everything the release reads. Then commit `workload`
+/// again with this version, on top of the copy, to check that it extends a
+/// file written by the release correctly.
+pub async fn assert_new_reads_old(dir: &Path, workload: &[Batch]) {
+    let old_path = dir.join("old");
+    let new_path = dir.join("copied");
+    let old = OldFirewood::create(&old_path).await;
+    let mut keys = Model::new();
+    for batch in workload {
+        old.commit(batch).await;
+        apply_to_model(&mut keys, batch);
+    }
+    // what the release reads, which isn't always what it was given
+    let mut model = Model::new();
+    for key in keys.keys() {
+        model.insert(key.clone(), old.read(key).await);
+    }
+    let old_hash = old.root_hash().await;
+    drop(old);
+    std::fs::copy(&old_path, &new_path).expect("can copy the database");
+
+    let new = NewFirewood::open(&new_path).await;
+    assert_eq!(new.root_hash().await, old_hash);
+    assert_matches_model(&new, &model).await;
+
+    for batch in workload {
+        new.commit(batch).await;
+        apply_to_model(&mut model, batch);
+    }
+    assert_matches_model(&new, &model).await;
+    let release_hash = release_hash(&dir.join("rebuilt"), &model).await;
+    assert_eq!(new.root_hash().await, release_hash);
+}
+
+/// Check that the release refuses a file this version wrote, since the
+/// release can't read the nodes in it, and that refusing it leaves the file
+/// as this version wrote it.
+pub async fn assert_old_refuses_new(dir: &Path, workload: &[Batch]) {
+    let path = dir.join("new");
+    let new = NewFirewood::create(&path).await;
+    for batch in workload {
+        new.commit(batch).await;
+    }
+    let new_hash = new.root_hash().await;
+    drop(new);
+
+    assert!(!OldFirewood::can_open(&path).await);
+    let new = NewFirewood::open(&path).await;
+    assert_eq!(new.root_hash().await, new_hash);
+}
+
+/// For every [Gate], check that this version only diverges from the last
+/// release while the gate is on: commit `workload`, check the root hash is
+/// the one the release computes for the same contents, turn the gate on and
+/// check it now differs, then turn it off and check it is back.
+pub async fn assert_divergence_gated(dir: &Path, workload: &[Batch]) {
+    for gate in Gate::ALL {
+        let new = NewFirewood::create(&dir.join(format!("{gate:?}"))).await;
+        let mut model = Model::new();
+        for batch in workload {
+            new.commit(batch).await;
+            apply_to_model(&mut model, batch);
+        }
+        let release_hash = release_hash(&dir.join(format!("{gate:?}-release")), &model).await;
+        assert_eq!(new.root_hash().await, release_hash, "{gate:?}");
+
+        new.set_gate(gate, true).await;
+        assert_ne!(
+            new.root_hash().await,
+            release_hash,
+            "{gate:?} is documented to diverge when it is on"
+        );
+        new.set_gate(gate, false).await;
+        assert_eq!(new.root_hash().await, release_hash, "{gate:?}");
+    }
+}
@@ -0,0 +1,89 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Deterministic workloads, the same for every version they are run against
+
+use rand::rngs::StdRng;
+use rand::{Rng as _, SeedableRng as _};
+
+use crate::{Batch, Op};
+
+fn put(key: &[u8], value: &[u8]) -> Op {
+    (key.to_vec(), Some(value.to_vec()))
+}
+
+fn delete(key: &[u8]) -> Op {
+    (key.to_vec(), None)
+}
+
+/// Batches for the shapes of trie most likely to hash or encode differently
+/// between versions. Every batch is committed on top of the ones before it.
+pub fn golden_batches() -> Vec<Batch> {
+    let long_key = vec![0xab; 300];
+    let long_value = vec![0xcd; 64 * 1024];
+    vec![
+        // a single leaf at the root
+        vec![put(b"a", b"1")],
+        // keys that are prefixes of each other, so branches have values
+        vec![put(b"ab", b"2"), put(b"abc", b"3"), put(b"abcd", b"4")],
+        // an empty key and an empty value
+        vec![put(b"", b"root"), put(b"empty", b"")],
+        // keys that differ only in their last nibble, and in the first one
+        vec![
+            put(&[0x10], b"x"),
+            put(&[0x11], b"y"),
+            put(&[0x01], b"z"),
+            put(&[0xf0, 0x0f], b"w"),
+        ],
+        // a full branch, with every child set
+        (0u8..16)
+            .map(|nibble| put(&[0x70 | nibble], &[nibble]))
+            .collect(),
+        // a long key and a value too large for a small area
+        vec![put(&long_key, &long_value)],
+        // overwrites, and the same key more than once in a batch
+        vec![put(b"a", b"5"), put(b"ab", b"6"), put(b"ab", b"7")],
+        // a put undone in the same batch, and a delete of a missing key
+        vec![put(b"gone", b"v"), delete(b"gone"), delete(b"never")],
+        // deletes that collapse branches into their only child
+        vec![delete(b"abc"), delete(&[0x11]), delete(&[0x70])],
+        // emptying the trie, then filling it again
+        vec![
+            delete(b"a"),
+            delete(b"ab"),
+            delete(b"abcd"),
+            delete(b""),
+            delete(b"empty"),
+            delete(&[0x10]),
+            delete(&[0x01]),
+            delete(&[0xf0, 0x0f]),
+            delete(&long_key),
+        ],
+        (1u8..16).map(|nibble| delete(&[0x70 | nibble])).collect(),
+        vec![put(b"again", b"v")],
+    ]
+}
+
+/// `batches` batches of up to 64 random ops each, drawn from `seed`. Keys
+/// come from a small space, so they are often overwritten and deleted.
+pub fn seeded(seed: u64, batches: usize) -> Vec<Batch> {
+    let mut rng = StdRng::seed_from_u64(seed);
+    (0..batches)
+        .map(|_| {
+            let len = rng.gen_range(1..=64);
+            (0..len)
+                .map(|_| {
+                    let key_len = rng.gen_range(0..=4);
+                    let key: Vec<u8> = (0..key_len).map(|_| rng.gen_range(0..8)).collect();
+                    if rng.gen_bool(0.25) {
+                        delete(&key)
+                    } else {
+                        let value_len = rng.gen_range(0..=48);
+                        let value: Vec<u8> = (0..value_len).map(|_| rng.gen()).collect();
+                        put(&key, &value)
+                    }
+                })
+                .collect()
+        })
+        .collect()
+}
@@ -0,0 +1,46 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use firewood_compat_tests::workload::{golden_batches, seeded};
+use firewood_compat_tests::{
+    assert_divergence_gated, assert_new_reads_old, assert_old_refuses_new, assert_same_hashes,
+};
+
+const SEEDS: u64 = 8;
+const SEEDED_BATCHES: usize = 32;
+
+#[tokio::test]
+async fn same_hashes_golden() {
+    let dir = tempfile::tempdir().expect("can create a temporary directory");
+    assert_same_hashes(dir.path(), &golden_batches()).await;
+}
+
+#[tokio::test]
+async fn same_hashes_seeded() {
+    for seed in 0..SEEDS {
+        let dir = tempfile::tempdir().expect("can create a temporary directory");
+        assert_same_hashes(dir.path(), &seeded(seed, SEEDED_BATCHES)).await;
+    }
+}
+
+#[tokio::test]
+async fn new_reads_old_files() {
+    let dir = tempfile::tempdir().expect("can create a temporary directory");
+    assert_new_reads_old(dir.path(), &golden_batches()).await;
+    for seed in 0..SEEDS {
+        let dir = tempfile::tempdir().expect("can create a temporary directory");
+        assert_new_reads_old(dir.path(), &seeded(seed, SEEDED_BATCHES)).await;
+    }
+}
+
+#[tokio::test]
+async fn old_refuses_new_files() {
+    let dir = tempfile::tempdir().expect("can create a temporary directory");
+    assert_old_refuses_new(dir.path(), &golden_batches()).await;
+}
+
+#[tokio::test]
+async fn divergence_is_gated() {
+    let dir = tempfile::tempdir().expect("can create a temporary directory");
+    assert_divergence_gated(dir.path(), &seeded(SEEDS, SEEDED_BATCHES)).await;
+}
@@ -0,0 +1,173 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// heap allocation benchmarks; run with 'cargo bench --bench allocations'
+//
+// These count heap allocations instead of timing, so changes to the hot
+// paths can be compared with 'cargo bench -- --save-baseline'.
+
+use criterion::measurement::{Measurement, ValueFormatter};
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use firewood::db::{BatchOp, DbConfig};
+use firewood::merkle::Merkle;
+use firewood::v2::api::{Db as _, DbView as _, Proposal as _};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use storage::{MemStore, NodeStore};
+
+const KEY_LEN: usize = 32;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+struct CountingAllocator;
+
+// SAFETY: every call is forwarded to the system allocator unchanged
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// Measures the number of heap allocations made by the benchmarked code
+struct Allocations;
+
+impl Measurement for Allocations {
+    type Intermediate = usize;
+    type Value = usize;
+
+    fn start(&self) -> Self::Intermediate {
+        ALLOCATIONS.load(Ordering::Relaxed)
+    }
+
+    fn end(&self, start: Self::Intermediate) -> Self::Value {
+        ALLOCATIONS.load(Ordering::Relaxed) - start
+    }
+
+    fn add(&self, a: &Self::Value, b: &Self::Value) -> Self::Value {
+        a + b
+    }
+
+    fn zero(&self) -> Self::Value {
+        0
+    }
+
+    fn to_f64(&self, value: &Self::Value) -> f64 {
+        *value as f64
+    }
+
+    fn formatter(&self) -> &dyn ValueFormatter {
+        &Allocations
+    }
+}
+
+impl ValueFormatter for Allocations {
+    fn scale_values(&self, _typical_value: f64, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+
+    fn scale_throughputs(
+        &self,
+        _typical_value: f64,
+        throughput: &Throughput,
+        values: &mut [f64],
+    ) -> &'static str {
+        match throughput {
+            Throughput::Elements(elements) => {
+                values
+                    .iter_mut()
+                    .for_each(|value| *value /= *elements as f64);
+                "allocs/key"
+            }
+            _ => "allocs",
+        }
+    }
+
+    fn scale_for_machines(&self, _values: &mut [f64]) -> &'static str {
+        "allocs"
+    }
+}
+
+fn random_keys(n: usize) -> Vec<[u8; KEY_LEN]> {
+    let mut rng = StdRng::seed_from_u64(1234);
+    (0..n).map(|_| rng.gen()).collect()
+}
+
+// Counts the allocations made inserting N keys into a proposal, which is
+// what proposing a batch does for each key
+#[allow(clippy::unwrap_used)]
+fn bench_insert<const N: usize>(criterion: &mut Criterion<Allocations>) {
+    let keys = random_keys(N);
+    criterion
+        .benchmark_group("Merkle")
+        .throughput(Throughput::Elements(N as u64))
+        .bench_function("insert_allocations", |b| {
+            b.iter_batched(
+                || {
+                    Merkle::from(NodeStore::new_empty_proposal(Arc::new(MemStore::new(
+                        vec![],
+                    ))))
+                },
+                |mut merkle| {
+                    for key in &keys {
+                        merkle.insert(key, Box::new(*b"v")).unwrap();
+                    }
+                    merkle
+                },
+                BatchSize::SmallInput,
+            );
+        });
+}
+
+// Counts the allocations made reading keys from a committed revision whose
+// nodes are all in the node cache
+#[allow(clippy::unwrap_used)]
+fn bench_get<const N: usize>(criterion: &mut Criterion<Allocations>) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let db_path = std::env::temp_dir().join("benchmark_allocations_db");
+    let keys = random_keys(N);
+
+    let (_db, revision) = runtime.block_on(async {
+        let cfg = DbConfig::builder().truncate(true).build();
+        let db = firewood::db::Db::new(db_path, cfg).await.unwrap();
+        let batch: Vec<_> = keys
+            .iter()
+            .map(|key| BatchOp::Put { key, value: b"v" })
+            .collect();
+        db.propose(batch).await.unwrap().commit().await.unwrap();
+        let root = db.root_hash().await.unwrap().unwrap();
+        let revision = db.revision(root).await.unwrap();
+        for key in &keys {
+            revision.val(key).await.unwrap();
+        }
+        (db, revision)
+    });
+
+    let mut next = keys.iter().cycle();
+    criterion
+        .benchmark_group("Db")
+        .bench_function("get_allocations", |b| {
+            b.iter(|| {
+                let key = next.next().unwrap();
+                runtime.block_on(revision.val(key)).unwrap()
+            })
+        });
+}
+
+criterion_group! {
+    name = benches;
+    // the counts don't vary between samples, which the plots can't handle
+    config = Criterion::default().with_measurement(Allocations).without_plots();
+    targets = bench_insert::<1000>, bench_get::<1000>
+}
+
+criterion_main!(benches);
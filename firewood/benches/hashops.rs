@@ -4,12 +4,16 @@
 // hash benchmarks; run with 'cargo bench'
 
 use criterion::{criterion_group, criterion_main, profiler::Profiler, BatchSize, Criterion};
-use firewood::db::{BatchOp, DbConfig};
+use firewood::db::{BatchOp, BatchOpHint, DbConfig};
+use firewood::manager::{AllocationPolicy, ReadAhead, RevisionManagerConfig};
 use firewood::merkle::Merkle;
-use firewood::v2::api::{Db as _, Proposal as _};
+use firewood::v2::api::{Db as _, DbView as _, Proposal as _};
+use futures::StreamExt as _;
 use pprof::ProfilerGuard;
 use rand::{distributions::Alphanumeric, rngs::StdRng, Rng, SeedableRng};
+use std::num::NonZero;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use std::{fs::File, iter::repeat_with, os::raw::c_int, path::Path};
 use storage::{MemStore, NodeStore};
 
@@ -134,10 +138,234 @@ fn bench_db<const N: usize>(criterion: &mut Criterion) {
         });
 }
 
+// Times reads of a warm revision. Compare runs with and without
+// `--features metrics` to see the cost of the per-call latency tracking.
+#[allow(clippy::unwrap_used)]
+fn bench_get<const N: usize>(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let db_path = std::env::temp_dir().join("benchmark_get_db");
+    let keys: Vec<Vec<u8>> = (0..N as u32).map(|i| i.to_be_bytes().to_vec()).collect();
+
+    let (db, root) = runtime.block_on(async {
+        let cfg = DbConfig::builder().truncate(true).build();
+        let db = firewood::db::Db::new(db_path, cfg).await.unwrap();
+        let batch: Vec<_> = keys
+            .iter()
+            .map(|key| BatchOp::Put {
+                key: key.clone(),
+                value: vec![b'v'],
+            })
+            .collect();
+        db.propose(batch).await.unwrap().commit().await.unwrap();
+        let root = db.root_hash().await.unwrap().unwrap();
+        (db, root)
+    });
+    let revision = runtime.block_on(db.revision(root)).unwrap();
+
+    let mut next = keys.iter().cycle();
+    criterion.benchmark_group("Db").bench_function("get", |b| {
+        b.to_async(&runtime).iter(|| {
+            let key = next.next().unwrap();
+            let revision = revision.clone();
+            async move { revision.val(key).await.unwrap() }
+        })
+    });
+}
+
+// Times proposing a batch of sequential keys past the end of a revision,
+// with and without the append fast path
+#[allow(clippy::unwrap_used)]
+fn bench_append<const N: usize>(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let db_path = std::env::temp_dir().join("benchmark_append_db");
+    let batch = |range: std::ops::Range<u32>| -> Vec<_> {
+        range
+            .map(|i| BatchOp::Put {
+                key: i.to_be_bytes(),
+                value: [b'v'],
+            })
+            .collect()
+    };
+
+    let db = runtime.block_on(async {
+        let cfg = DbConfig::builder().truncate(true).build();
+        let db = firewood::db::Db::new(db_path, cfg).await.unwrap();
+        let proposal = db.propose(batch(0..N as u32)).await.unwrap();
+        proposal.commit().await.unwrap();
+        db
+    });
+
+    let mut group = criterion.benchmark_group("Db");
+    for (name, hint) in [
+        ("append_unordered", BatchOpHint::Unordered),
+        ("append", BatchOpHint::Append),
+    ] {
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter_batched(
+                || batch(N as u32..2 * N as u32),
+                |batch_ops| async { db.propose_with_hint(batch_ops, hint).await.unwrap() },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+}
+
+// Times reading back every key of a batch committed on top of a churned
+// file, with its nodes scattered over the free lists or placed together.
+// The node cache is emptied before each read; the OS page cache is not.
+#[allow(clippy::unwrap_used)]
+fn bench_locality<const N: usize>(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let batch = |keys: std::ops::Range<u32>, round: usize| -> Vec<_> {
+        keys.map(|i| BatchOp::Put {
+            key: i.to_be_bytes(),
+            value: vec![b'v'; 1 + (i as usize * round) % 200],
+        })
+        .collect()
+    };
+
+    let mut group = criterion.benchmark_group("Db");
+    for (name, policy) in [
+        ("read_scattered", AllocationPolicy::Scatter),
+        ("read_reserved", AllocationPolicy::Reserve),
+    ] {
+        let db_path = std::env::temp_dir().join(format!("benchmark_{name}_db"));
+        let (db, revision) = runtime.block_on(async {
+            let cfg = DbConfig::builder()
+                .truncate(true)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .max_revisions(2)
+                        .allocation_policy(policy)
+                        .build(),
+                )
+                .build();
+            let db = firewood::db::Db::new(db_path, cfg).await.unwrap();
+            // rewriting the same keys with other sizes fills the free lists
+            for round in 1..10 {
+                let proposal = db.propose(batch(0..N as u32, round)).await.unwrap();
+                proposal.commit().await.unwrap();
+            }
+            let proposal = db.propose(batch(N as u32..2 * N as u32, 1)).await.unwrap();
+            proposal.commit().await.unwrap();
+            let root = db.root_hash().await.unwrap().unwrap();
+            let revision = db.revision(root).await.unwrap();
+            (db, revision)
+        });
+
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| async {
+                db.shed_cache(1.0).await;
+                for key in N as u32..2 * N as u32 {
+                    revision.val(key.to_be_bytes()).await.unwrap();
+                }
+            })
+        });
+    }
+}
+
+// Times committing a proposal of N spread out keys, writing its nodes on
+// one thread or on several. Only the commit is timed, not the proposal.
+#[allow(clippy::unwrap_used)]
+fn bench_flush<const N: usize>(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let db_path = std::env::temp_dir().join("benchmark_flush_db");
+    let batch = || -> Vec<_> {
+        (0..N as u32)
+            .map(|i| BatchOp::Put {
+                key: i.wrapping_mul(2_654_435_761).to_be_bytes(),
+                value: [b'v'; 32],
+            })
+            .collect()
+    };
+
+    let mut group = criterion.benchmark_group("Db");
+    group.sample_size(10);
+    for threads in [1, 4] {
+        let cfg = DbConfig::builder()
+            .truncate(true)
+            .manager(
+                RevisionManagerConfig::builder()
+                    .flush_parallelism(NonZero::new(threads).unwrap())
+                    .build(),
+            )
+            .build();
+        group.bench_function(format!("commit_flush_{threads}"), |b| {
+            b.iter_custom(|iters| {
+                let mut elapsed = Duration::ZERO;
+                for _ in 0..iters {
+                    runtime.block_on(async {
+                        let db = firewood::db::Db::new(&db_path, cfg.clone()).await.unwrap();
+                        let proposal = db.propose(batch()).await.unwrap();
+                        let start = Instant::now();
+                        proposal.commit().await.unwrap();
+                        elapsed += start.elapsed();
+                    });
+                }
+                elapsed
+            })
+        });
+    }
+}
+
+// Times iterating over every key of a revision after emptying the node
+// cache, with and without reading ahead. The OS page cache is not emptied,
+// so this only shows the part of a read that decoding takes; the gain is
+// larger when reads wait for a disk.
+#[allow(clippy::unwrap_used)]
+fn bench_iterate<const N: usize>(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let batch = || -> Vec<_> {
+        (0..N as u32)
+            .map(|i| BatchOp::Put {
+                key: i.wrapping_mul(2_654_435_761).to_be_bytes(),
+                value: [b'v'; 32],
+            })
+            .collect()
+    };
+
+    let mut group = criterion.benchmark_group("Db");
+    for (name, read_ahead) in [
+        ("iterate", ReadAhead::default()),
+        ("iterate_read_ahead", ReadAhead { width: 4, depth: 2 }),
+    ] {
+        let db_path = std::env::temp_dir().join(format!("benchmark_{name}_db"));
+        let (db, revision) = runtime.block_on(async {
+            let cfg = DbConfig::builder()
+                .truncate(true)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .read_ahead(read_ahead)
+                        .build(),
+                )
+                .build();
+            let db = firewood::db::Db::new(db_path, cfg).await.unwrap();
+            let proposal = db.propose(batch()).await.unwrap();
+            proposal.commit().await.unwrap();
+            let root = db.root_hash().await.unwrap().unwrap();
+            let revision = db.revision(root).await.unwrap();
+            (db, revision)
+        });
+
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| async {
+                db.shed_cache(1.0).await;
+                let mut stream = revision.iter().unwrap();
+                let mut count = 0;
+                while let Some(entry) = stream.next().await {
+                    entry.unwrap();
+                    count += 1;
+                }
+                assert_eq!(count, N);
+            })
+        });
+    }
+}
+
 criterion_group! {
     name = benches;
     config = Criterion::default().with_profiler(FlamegraphProfiler::Init(100));
-    targets = bench_merkle::<3, 4>, bench_merkle<3, 32>, bench_db::<100>
+    targets = bench_merkle::<3, 4>, bench_merkle<3, 32>, bench_db::<100>, bench_get::<1000>, bench_append::<10000>, bench_locality::<1000>, bench_flush::<100000>, bench_iterate::<100000>
 }
 
 criterion_main!(benches);
@@ -0,0 +1,136 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// proof serving benchmarks; run with 'cargo bench --bench proofs'
+//
+// Every proof reads a whole root-to-leaf path, so this compares how well
+// each node cache policy keeps the upper levels of a trie that doesn't fit
+// in the cache. Besides criterion's timings, the hit rate and p99 latency of
+// a cold and a steady state pass are printed for each policy.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use firewood::db::{BatchOp, Db, DbConfig};
+use firewood::manager::{NodeCachePolicy, RevisionManagerConfig};
+use firewood::v2::api::{Db as _, DbView, Proposal as _};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+use std::num::NonZero;
+use std::time::{Duration, Instant};
+
+const KEY_LEN: usize = 32;
+const BATCH: usize = 10_000;
+
+const POLICIES: [(&str, NodeCachePolicy); 3] = [
+    ("lru", NodeCachePolicy::Lru),
+    (
+        "segmented",
+        NodeCachePolicy::Segmented {
+            protected_fraction: 0.8,
+        },
+    ),
+    (
+        "by_depth",
+        NodeCachePolicy::ByDepth {
+            protected_depth: 3,
+            protected_fraction: 0.8,
+            admit_leaves: false,
+        },
+    ),
+];
+
+fn random_keys(n: usize) -> Vec<[u8; KEY_LEN]> {
+    let mut rng = StdRng::seed_from_u64(1234);
+    (0..n).map(|_| rng.gen()).collect()
+}
+
+/// Proves `count` random keys, returning the node cache hit rate and the
+/// p99 latency of the proofs
+#[allow(clippy::unwrap_used)]
+async fn proof_pass(
+    db: &Db,
+    revision: &impl DbView,
+    keys: &[[u8; KEY_LEN]],
+    rng: &mut StdRng,
+    count: usize,
+) -> (f64, Duration) {
+    let (start_hits, start_misses) = db.node_cache_lookups().await;
+    let mut latencies = Vec::with_capacity(count);
+    for _ in 0..count {
+        let key = keys.choose(rng).unwrap();
+        let start = Instant::now();
+        revision.single_key_proof(key).await.unwrap();
+        latencies.push(start.elapsed());
+    }
+    let (hits, misses) = db.node_cache_lookups().await;
+    let (hits, misses) = (hits - start_hits, misses - start_misses);
+    latencies.sort_unstable();
+    let p99 = latencies
+        .get(latencies.len() * 99 / 100)
+        .or(latencies.last())
+        .copied()
+        .unwrap_or_default();
+    (hits as f64 / (hits + misses).max(1) as f64, p99)
+}
+
+// Proves random keys of a trie of N keys, with a node cache that holds
+// CACHE nodes
+#[allow(clippy::unwrap_used)]
+fn bench_proofs<const N: usize, const CACHE: usize>(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let keys = random_keys(N);
+
+    let mut group = criterion.benchmark_group("Proof");
+    for (name, policy) in POLICIES {
+        let db_path = std::env::temp_dir().join(format!("benchmark_proof_{name}_db"));
+        let (db, revision) = runtime.block_on(async {
+            let cfg = DbConfig::builder()
+                .truncate(true)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .node_cache_size(NonZero::new(CACHE).unwrap())
+                        .node_cache_policy(policy)
+                        .build(),
+                )
+                .build();
+            let db = Db::new(db_path, cfg).await.unwrap();
+            for chunk in keys.chunks(BATCH) {
+                let batch: Vec<_> = chunk
+                    .iter()
+                    .map(|key| BatchOp::Put {
+                        key: *key,
+                        value: *key,
+                    })
+                    .collect();
+                db.propose(batch).await.unwrap().commit().await.unwrap();
+            }
+            let root = db.root_hash().await.unwrap().unwrap();
+            let revision = db.revision(root).await.unwrap();
+            (db, revision)
+        });
+
+        runtime.block_on(async {
+            let mut rng = StdRng::seed_from_u64(5678);
+            db.shed_cache(1.0).await;
+            let (rate, p99) = proof_pass(&db, &*revision, &keys, &mut rng, N / 10).await;
+            println!("{name}: cold hit rate {rate:.3}, p99 {p99:?}");
+            let (rate, p99) = proof_pass(&db, &*revision, &keys, &mut rng, N).await;
+            println!("{name}: steady state hit rate {rate:.3}, p99 {p99:?}");
+        });
+
+        let mut rng = StdRng::seed_from_u64(91011);
+        group.bench_function(name, |b| {
+            b.to_async(&runtime).iter(|| {
+                let key = *keys.choose(&mut rng).unwrap();
+                let revision = revision.clone();
+                async move { revision.single_key_proof(key).await.unwrap() }
+            })
+        });
+    }
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(30);
+    targets = bench_proofs::<200_000, 20_000>
+}
+
+criterion_main!(benches);
@@ -0,0 +1,108 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// point read benchmarks; run with 'cargo bench --bench reads'
+//
+// Compares reading a batch of clustered keys with one get_many call against
+// a val call for each of them. Besides criterion's timings, the node cache
+// lookups and misses of a cold pass of each are printed: get_many reads each
+// node the keys share once, where val reads it again for every key.
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use firewood::db::{BatchOp, Db, DbConfig};
+use firewood::v2::api::{Db as _, DbView, Proposal as _};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
+
+const KEY_LEN: usize = 32;
+const BATCH: usize = 10_000;
+
+/// Reads `keys` one at a time
+#[allow(clippy::unwrap_used)]
+async fn val_loop(revision: &impl DbView, keys: &[[u8; KEY_LEN]]) -> usize {
+    let mut found = 0;
+    for key in keys {
+        found += usize::from(revision.val(key).await.unwrap().is_some());
+    }
+    found
+}
+
+/// Reads `keys` with a single get_many call
+#[allow(clippy::unwrap_used)]
+async fn get_many(revision: &impl DbView, keys: &[[u8; KEY_LEN]]) -> usize {
+    let values = revision.get_many(keys).await.unwrap();
+    values.iter().filter(|value| value.is_some()).count()
+}
+
+/// The node cache lookups and misses of reading `keys` with `read` from a
+/// cold cache
+async fn cold_pass<F>(db: &Db, read: F) -> (u64, u64)
+where
+    F: std::future::Future<Output = usize>,
+{
+    db.shed_cache(1.0).await;
+    let (start_hits, start_misses) = db.node_cache_lookups().await;
+    read.await;
+    let (hits, misses) = db.node_cache_lookups().await;
+    (
+        hits + misses - start_hits - start_misses,
+        misses - start_misses,
+    )
+}
+
+// Reads COUNT keys that are next to each other in a trie of N keys
+#[allow(clippy::unwrap_used)]
+fn bench_reads<const N: usize, const COUNT: usize>(criterion: &mut Criterion) {
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let mut rng = StdRng::seed_from_u64(1234);
+    let mut keys: Vec<[u8; KEY_LEN]> = (0..N).map(|_| rng.gen()).collect();
+
+    let db_path = std::env::temp_dir().join("benchmark_reads_db");
+    let (db, revision) = runtime.block_on(async {
+        let cfg = DbConfig::builder().truncate(true).build();
+        let db = Db::new(db_path, cfg).await.unwrap();
+        for chunk in keys.chunks(BATCH) {
+            let batch: Vec<_> = chunk
+                .iter()
+                .map(|key| BatchOp::Put {
+                    key: *key,
+                    value: *key,
+                })
+                .collect();
+            db.propose(batch).await.unwrap().commit().await.unwrap();
+        }
+        let root = db.root_hash().await.unwrap().unwrap();
+        let revision = db.revision(root).await.unwrap();
+        (db, revision)
+    });
+
+    // keys next to each other in key order share the upper levels of the
+    // trie; they are read in a random order, as a request would
+    keys.sort_unstable();
+    let mut clustered: Vec<_> = keys.iter().skip(N / 2).take(COUNT).copied().collect();
+    clustered.shuffle(&mut rng);
+
+    runtime.block_on(async {
+        let (lookups, misses) = cold_pass(&db, val_loop(&*revision, &clustered)).await;
+        println!("val loop: {lookups} node lookups, {misses} misses");
+        let (lookups, misses) = cold_pass(&db, get_many(&*revision, &clustered)).await;
+        println!("get_many: {lookups} node lookups, {misses} misses");
+    });
+
+    let mut group = criterion.benchmark_group("Reads");
+    group.bench_function("val_loop", |b| {
+        b.to_async(&runtime)
+            .iter(|| val_loop(&*revision, &clustered))
+    });
+    group.bench_function("get_many", |b| {
+        b.to_async(&runtime)
+            .iter(|| get_many(&*revision, &clustered))
+    });
+}
+
+criterion_group! {
+    name = benches;
+    config = Criterion::default().sample_size(30);
+    targets = bench_reads::<200_000, 1_000>
+}
+
+criterion_main!(benches);
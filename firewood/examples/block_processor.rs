@@ -0,0 +1,336 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// A block processor, showing how a chain can execute blocks ahead of the
+// ones that are final by stacking proposals, and how it handles the errors
+// commits return when blocks don't become final in the order they were
+// executed in.
+//
+// - Each block is proposed on top of the proposal of the block before it,
+//   so it can be executed before its parent is committed.
+// - Some heights have a competing block, proposed on the same parent. Once
+//   the canonical one is committed, committing the other fails with
+//   SiblingCommitted.
+// - Some blocks become final before their parent. Committing one fails
+//   with NotLatest, which drops it and strands the blocks executed on it, so
+//   they are executed again once its parent is committed.
+// - Finally every root that is still retained is read back, to check that
+//   it holds the state of its block.
+//
+// cargo run --example block_processor -- --blocks 1000
+
+use std::collections::{BTreeMap, VecDeque};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::Parser;
+use firewood::db::{Db, DbConfig, Proposal};
+use firewood::v2::api::{self, vec_into_batch, Db as _, DbView as _, HashKey, Proposal as _};
+use rand::{rngs::StdRng, Rng as _, SeedableRng as _};
+
+#[derive(Debug, Parser)]
+struct Args {
+    /// The database file, which is replaced
+    #[arg(long, default_value = "blocks_db")]
+    db: PathBuf,
+    /// The number of blocks to process
+    #[arg(long, default_value_t = 100)]
+    blocks: u64,
+    /// The number of blocks executed ahead of the last final one
+    #[arg(long, default_value_t = 3)]
+    lag: usize,
+    /// Have a competing block at every height that is a multiple of this;
+    /// 0 never does
+    #[arg(long, default_value_t = 5)]
+    fork_every: u64,
+    /// Have a block become final before its parent at every height that is
+    /// a multiple of this; 0 never does
+    #[arg(long, default_value_t = 7)]
+    reorder_every: u64,
+    /// The number of accounts the blocks update
+    #[arg(long, default_value_t = 1000)]
+    accounts: u64,
+    #[arg(long, default_value_t = 0)]
+    seed: u64,
+}
+
+/// The key every block sets to its height
+const HEIGHT_KEY: &[u8] = b"height";
+
+/// The key/value pairs a block puts
+type Block = Vec<(Vec<u8>, Vec<u8>)>;
+
+/// The block at `height`, which sets the height and a few account balances
+fn make_block(height: u64, seed: u64, accounts: u64) -> Block {
+    let mut rng = StdRng::seed_from_u64(seed ^ height);
+    let mut puts = BTreeMap::new();
+    puts.insert(HEIGHT_KEY.to_vec(), height.to_be_bytes().to_vec());
+    for _ in 0..4 {
+        let account = rng.gen_range(0..accounts.max(1));
+        let balance: u64 = rng.gen();
+        puts.insert(
+            format!("account-{account:06}").into_bytes(),
+            balance.to_be_bytes().to_vec(),
+        );
+    }
+    puts.into_iter().collect()
+}
+
+/// A block that has been executed but isn't final yet
+struct Pending<'db> {
+    height: u64,
+    block: Block,
+    proposal: Arc<Proposal<'db>>,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Stats {
+    /// Blocks committed
+    committed: u64,
+    /// Competing blocks that were discarded
+    forks_discarded: u64,
+    /// Blocks that were executed again after a block under them became
+    /// final too early
+    reexecuted: u64,
+    /// Roots read back after processing
+    verified: u64,
+}
+
+struct Processor<'db> {
+    db: &'db Db,
+    /// Oldest first; each is proposed on the one before it
+    pending: VecDeque<Pending<'db>>,
+    /// Competing blocks, by height
+    forks: Vec<(u64, Arc<Proposal<'db>>)>,
+    roots: BTreeMap<u64, HashKey>,
+    stats: Stats,
+}
+
+impl<'db> Processor<'db> {
+    const fn new(db: &'db Db) -> Self {
+        Self {
+            db,
+            pending: VecDeque::new(),
+            forks: Vec::new(),
+            roots: BTreeMap::new(),
+            stats: Stats {
+                committed: 0,
+                forks_discarded: 0,
+                reexecuted: 0,
+                verified: 0,
+            },
+        }
+    }
+
+    /// Propose `block` on top of the last executed block, or on the last
+    /// commit if every executed block is final
+    async fn execute(&self, block: &Block) -> Result<Arc<Proposal<'db>>, api::Error> {
+        let batch = vec_into_batch(block.clone());
+        match self.pending.back() {
+            Some(parent) => parent.proposal.clone().propose(batch).await,
+            None => self.db.propose(batch).await,
+        }
+    }
+
+    async fn push(&mut self, height: u64, block: Block) -> Result<(), api::Error> {
+        let proposal = self.execute(&block).await?;
+        self.pending.push_back(Pending {
+            height,
+            block,
+            proposal,
+        });
+        Ok(())
+    }
+
+    /// Commit the oldest pending block, whose parent is the last commit
+    async fn commit_oldest(&mut self) -> Result<(), api::Error> {
+        let Some(oldest) = self.pending.pop_front() else {
+            return Ok(());
+        };
+        let root = oldest
+            .proposal
+            .commit()
+            .await?
+            .expect("every block sets its height");
+        self.roots.insert(oldest.height, root);
+        self.stats.committed += 1;
+
+        // the competing blocks at this height lost
+        while let Some(index) = self.forks.iter().position(|(h, _)| *h == oldest.height) {
+            let (_, fork) = self.forks.swap_remove(index);
+            match fork.commit().await {
+                Err(api::Error::SiblingCommitted) => self.stats.forks_discarded += 1,
+                Ok(_) => unreachable!("the canonical block was committed first"),
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(())
+    }
+
+    /// Commit the second oldest pending block before the oldest one, as if
+    /// it became final first
+    async fn commit_out_of_order(&mut self) -> Result<(), api::Error> {
+        let Some(second) = self.pending.remove(1) else {
+            return self.commit_oldest().await;
+        };
+        match second.proposal.commit().await {
+            Err(api::Error::NotLatest) => {}
+            Ok(_) => unreachable!("its parent isn't committed"),
+            Err(err) => return Err(err),
+        }
+
+        // The failed commit dropped the proposal, so the blocks executed on
+        // it can never be committed. Drop them too, newest first, since a
+        // proposal can't be aborted while proposals on top of it are held.
+        let mut stale = vec![];
+        while let Some(index) = self.forks.iter().position(|(h, _)| *h > second.height) {
+            let (_, fork) = self.forks.swap_remove(index);
+            fork.abort().await?;
+            self.stats.forks_discarded += 1;
+        }
+        while self.pending.len() > 1 {
+            let pending = self.pending.pop_back().expect("more than one is pending");
+            pending.proposal.abort().await?;
+            stale.push((pending.height, pending.block));
+        }
+        stale.push((second.height, second.block));
+
+        self.commit_oldest().await?;
+        for (height, block) in stale.into_iter().rev() {
+            self.push(height, block).await?;
+            self.stats.reexecuted += 1;
+        }
+        Ok(())
+    }
+
+    /// Check that each root that is still retained has the height of its
+    /// block
+    async fn verify_history(&mut self) -> Result<(), api::Error> {
+        for (height, root) in &self.roots {
+            let revision = match self.db.revision(root.clone()).await {
+                Ok(revision) => revision,
+                // older revisions are reaped as new ones are committed
+                Err(api::Error::RevisionReaped { .. }) => continue,
+                Err(err) => return Err(err),
+            };
+            let value = revision.val(HEIGHT_KEY).await?;
+            assert_eq!(value.as_deref(), Some(&height.to_be_bytes()[..]));
+            self.stats.verified += 1;
+        }
+        Ok(())
+    }
+}
+
+async fn run(args: &Args) -> Result<Stats, api::Error> {
+    let db = Db::new(&args.db, DbConfig::builder().truncate(true).build()).await?;
+    let mut processor = Processor::new(&db);
+
+    for height in 1..=args.blocks {
+        if args.fork_every > 0 && height % args.fork_every == 0 {
+            let fork = make_block(height, !args.seed, args.accounts);
+            let fork = processor.execute(&fork).await?;
+            processor.forks.push((height, fork));
+        }
+        let block = make_block(height, args.seed, args.accounts);
+        processor.push(height, block).await?;
+
+        if processor.pending.len() > args.lag {
+            if args.reorder_every > 0 && height % args.reorder_every == 0 {
+                processor.commit_out_of_order().await?;
+            } else {
+                processor.commit_oldest().await?;
+            }
+        }
+    }
+    while !processor.pending.is_empty() {
+        processor.commit_oldest().await?;
+    }
+
+    let root = db.root_hash().await?.expect("blocks were committed");
+    let latest = db.revision(root).await?.val(HEIGHT_KEY).await?;
+    assert_eq!(latest.as_deref(), Some(&args.blocks.to_be_bytes()[..]));
+    processor.verify_history().await?;
+    Ok(processor.stats)
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    let args = Args::parse();
+    match run(&args).await {
+        Ok(stats) => println!("{stats:?}"),
+        Err(err) => {
+            eprintln!("error: {err}");
+            std::process::exit(1);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    fn args(dir: &tempfile::TempDir, extra: &[&str]) -> Args {
+        let db = dir.path().join("db");
+        let db = db.to_str().unwrap();
+        Args::try_parse_from(["block_processor", "--db", db].iter().chain(extra)).unwrap()
+    }
+
+    #[tokio::test]
+    async fn in_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = args(
+            &dir,
+            &[
+                "--blocks",
+                "20",
+                "--fork-every",
+                "0",
+                "--reorder-every",
+                "0",
+            ],
+        );
+        let stats = run(&args).await.unwrap();
+        assert_eq!(
+            stats,
+            Stats {
+                committed: 20,
+                forks_discarded: 0,
+                reexecuted: 0,
+                verified: 20,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn forks_and_reordering() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = args(
+            &dir,
+            &[
+                "--blocks",
+                "40",
+                "--fork-every",
+                "5",
+                "--reorder-every",
+                "7",
+            ],
+        );
+        let stats = run(&args).await.unwrap();
+        assert_eq!(stats.committed, 40);
+        assert_eq!(stats.forks_discarded, 8);
+        // each reordering executes the blocks above the oldest again
+        assert_eq!(stats.reexecuted, 5 * args.lag as u64);
+        assert_eq!(stats.verified, 40);
+    }
+
+    #[tokio::test]
+    async fn no_lag() {
+        let dir = tempfile::tempdir().unwrap();
+        let args = args(&dir, &["--blocks", "10", "--lag", "0"]);
+        let stats = run(&args).await.unwrap();
+        assert_eq!(stats.committed, 10);
+        assert_eq!(stats.forks_discarded, 2);
+        assert_eq!(stats.reexecuted, 0);
+    }
+}
@@ -136,7 +136,10 @@ async fn verify_keys(
     verify: HashMap<Vec<u8>, Box<[u8]>>,
 ) -> Result<(), firewood::v2::api::Error> {
     if !verify.is_empty() {
-        let hash = db.root_hash().await?.expect("root hash should exist");
+        let hash = db
+            .root_hash()
+            .await?
+            .ok_or(firewood::v2::api::Error::LatestIsEmpty)?;
         let revision = db.revision(hash).await?;
         for (key, value) in verify {
             assert_eq!(Some(value), revision.val(key).await?);
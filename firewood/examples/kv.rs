@@ -0,0 +1,232 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// A small key/value tool over a database file, showing the basic use of the
+// async API: batches, reads, iteration, and proofs that are verified
+// against the root they were made at. Each run reopens the database, which
+// only keeps the latest revision, so every command works on that one.
+//
+// cargo run --example kv -- --db kv_db put hello world
+// cargo run --example kv -- --db kv_db get hello
+// cargo run --example kv -- --db kv_db scan --prefix he
+// cargo run --example kv -- --db kv_db proof hello
+
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use firewood::db::{BatchOp, Db, DbConfig};
+use firewood::proof::ProofError;
+use firewood::v2::api::{self, Db as _, DbView as _, HashKey, Proposal as _};
+use futures::StreamExt as _;
+
+#[derive(Debug, Parser)]
+struct Cli {
+    /// The database file, which is created if it doesn't exist
+    #[arg(long, default_value = "kv_db")]
+    db: PathBuf,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Set the value of a key
+    Put { key: String, value: String },
+    /// Print the value of a key
+    Get { key: String },
+    /// Delete a key
+    Del { key: String },
+    /// Print the keys and values in order
+    Scan {
+        /// Only the keys that start with this
+        #[arg(long, conflicts_with = "start")]
+        prefix: Option<String>,
+        /// Start at this key
+        #[arg(long)]
+        start: Option<String>,
+        /// Stop after this many keys
+        #[arg(long)]
+        limit: Option<usize>,
+    },
+    /// Prove the value of a key, and check the proof against the root
+    Proof { key: String },
+}
+
+/// Why a command failed
+#[derive(Debug, thiserror::Error)]
+enum KvError {
+    #[error(transparent)]
+    Api(#[from] api::Error),
+    #[error("proof didn't verify: {0}")]
+    Proof(#[from] ProofError),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("the database is empty")]
+    Empty,
+}
+
+async fn open(path: &Path) -> Result<Db, api::Error> {
+    // an existing file has to be opened without truncating it, and a new
+    // one has to be created with it
+    let cfg = DbConfig::builder().truncate(!path.exists()).build();
+    Db::new(path, cfg).await
+}
+
+async fn commit(db: &Db, op: BatchOp<Vec<u8>, Vec<u8>>) -> Result<Option<HashKey>, KvError> {
+    let proposal = db.propose(vec![op]).await?;
+    Ok(proposal.commit().await?)
+}
+
+async fn run(cli: Cli, out: &mut impl Write) -> Result<(), KvError> {
+    let db = open(&cli.db).await?;
+    match cli.command {
+        Command::Put { key, value } => {
+            let op = BatchOp::Put {
+                key: key.into_bytes(),
+                value: value.into_bytes(),
+            };
+            if let Some(root) = commit(&db, op).await? {
+                writeln!(out, "root {}", hex::encode(root))?;
+            }
+        }
+        Command::Del { key } => {
+            let op = BatchOp::Delete {
+                key: key.into_bytes(),
+            };
+            match commit(&db, op).await? {
+                Some(root) => writeln!(out, "root {}", hex::encode(root))?,
+                None => writeln!(out, "empty")?,
+            }
+        }
+        Command::Get { key } => {
+            let root = db.root_hash().await?.ok_or(KvError::Empty)?;
+            let revision = db.revision(root).await?;
+            match revision.val(key.as_bytes()).await? {
+                Some(value) => writeln!(out, "{}", String::from_utf8_lossy(&value))?,
+                None => writeln!(out, "{key} not found")?,
+            }
+        }
+        Command::Scan {
+            prefix,
+            start,
+            limit,
+        } => {
+            let Some(root) = db.root_hash().await? else {
+                return Ok(());
+            };
+            let revision = db.revision(root).await?;
+            let stream = match prefix {
+                Some(prefix) => revision.iter_prefix(prefix.into_bytes())?,
+                None => revision.iter_option(start.map(String::into_bytes))?,
+            };
+            let mut stream = stream.take(limit.unwrap_or(usize::MAX));
+            while let Some(entry) = stream.next().await {
+                let (key, value) = entry?;
+                writeln!(
+                    out,
+                    "{}={}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                )?;
+            }
+        }
+        Command::Proof { key } => {
+            let root = db.root_hash().await?.ok_or(KvError::Empty)?;
+            let revision = db.revision(root.clone()).await?;
+            let proof = revision.single_key_proof(key.as_bytes()).await?;
+            let value = revision.val(key.as_bytes()).await?;
+            // a proof of absence verifies against None
+            proof.verify(key.as_bytes(), value.as_deref(), &root)?;
+            writeln!(
+                out,
+                "{} nodes prove {key} {} at root {}",
+                proof.0.len(),
+                match value {
+                    Some(_) => "is present",
+                    None => "is absent",
+                },
+                hex::encode(root)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    let cli = Cli::parse();
+    if let Err(err) = run(cli, &mut std::io::stdout()).await {
+        eprintln!("error: {err}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    /// Run the tool with `args` on the database at `db`, returning what it
+    /// printed
+    async fn kv(db: &Path, args: &[&str]) -> Result<String, KvError> {
+        let db = db.to_str().unwrap();
+        let cli = Cli::try_parse_from(["kv", "--db", db].iter().chain(args)).unwrap();
+        let mut out = Vec::new();
+        run(cli, &mut out).await?;
+        Ok(String::from_utf8(out).unwrap())
+    }
+
+    #[tokio::test]
+    async fn put_get_del() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+
+        let err = kv(&db, &["get", "a"]).await.unwrap_err();
+        assert!(matches!(err, KvError::Empty), "{err:?}");
+
+        let root = kv(&db, &["put", "a", "1"]).await.unwrap();
+        assert!(root.starts_with("root "), "{root}");
+        kv(&db, &["put", "a", "2"]).await.unwrap();
+        assert_eq!(kv(&db, &["get", "a"]).await.unwrap(), "2\n");
+
+        assert_eq!(kv(&db, &["del", "a"]).await.unwrap(), "empty\n");
+        let err = kv(&db, &["get", "a"]).await.unwrap_err();
+        assert!(matches!(err, KvError::Empty), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn scan() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        for (key, value) in [("b", "2"), ("a", "1"), ("ba", "3"), ("c", "4")] {
+            kv(&db, &["put", key, value]).await.unwrap();
+        }
+
+        let all = kv(&db, &["scan"]).await.unwrap();
+        assert_eq!(all, "a=1\nb=2\nba=3\nc=4\n");
+        let prefixed = kv(&db, &["scan", "--prefix", "b"]).await.unwrap();
+        assert_eq!(prefixed, "b=2\nba=3\n");
+        let limited = kv(&db, &["scan", "--start", "b", "--limit", "2"])
+            .await
+            .unwrap();
+        assert_eq!(limited, "b=2\nba=3\n");
+    }
+
+    #[tokio::test]
+    async fn proofs() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = dir.path().join("db");
+        let err = kv(&db, &["proof", "a"]).await.unwrap_err();
+        assert!(matches!(err, KvError::Empty), "{err:?}");
+
+        for key in ["a", "ab", "b"] {
+            kv(&db, &["put", key, "v"]).await.unwrap();
+        }
+        let present = kv(&db, &["proof", "ab"]).await.unwrap();
+        assert!(present.contains("ab is present"), "{present}");
+        let absent = kv(&db, &["proof", "c"]).await.unwrap();
+        assert!(absent.contains("c is absent"), "{absent}");
+    }
+}
@@ -0,0 +1,373 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// A snapshot server and client, showing how a revision can be copied to
+// another database over a socket, and how the receiving side checks the copy
+// without trusting the sender: the root hash of the trie it builds has to
+// match the one it asked for.
+//
+// cargo run --example snapshot -- serve --db source_db --addr 127.0.0.1:7070
+// cargo run --example snapshot -- fetch --db copy_db --addr 127.0.0.1:7070
+//
+// The protocol is one request and one response per connection. The request
+// is a byte saying whether a root follows, then the 32 byte root. The
+// response is a status byte, then for a found revision its root and its
+// keys and values in order, each a 4 byte big endian length followed by the
+// bytes, ended by a length of u32::MAX.
+//
+// Only user keys are enumerated, so a revision with system entries, such as
+// one with frozen prefixes, can't be copied this way.
+
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand};
+use firewood::db::{BatchOp, Db, DbConfig};
+use firewood::v2::api::{self, Db as _, DbView as _, HashKey, Proposal as _};
+use futures::StreamExt as _;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+
+/// The status of a response with a snapshot
+const FOUND: u8 = 0;
+/// The status of a response for a revision that isn't retained
+const NOT_FOUND: u8 = 1;
+/// The length that ends the keys and values of a snapshot
+const END: u32 = u32::MAX;
+/// The number of keys the client commits at a time
+const BATCH: usize = 10_000;
+
+#[derive(Debug, Parser)]
+struct Cli {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Serve the revisions of a database
+    Serve {
+        #[arg(long)]
+        db: PathBuf,
+        #[arg(long, default_value = "127.0.0.1:7070")]
+        addr: SocketAddr,
+    },
+    /// Copy a revision into a new database
+    Fetch {
+        /// The database file to create, which is replaced if it exists
+        #[arg(long)]
+        db: PathBuf,
+        #[arg(long, default_value = "127.0.0.1:7070")]
+        addr: SocketAddr,
+        /// The root of the revision, in hex; the latest if not given
+        #[arg(long, value_parser = parse_root)]
+        root: Option<HashKey>,
+    },
+}
+
+/// Why a snapshot couldn't be served or fetched
+#[derive(Debug, thiserror::Error)]
+enum SnapshotError {
+    #[error(transparent)]
+    Api(#[from] api::Error),
+    #[error(transparent)]
+    Io(#[from] std::io::Error),
+    #[error("the server doesn't have the revision")]
+    NotFound,
+    #[error("the server sent {0}")]
+    Protocol(&'static str),
+    /// The copy isn't the revision that was asked for, so the server sent
+    /// the wrong keys or values
+    #[error("the copy has root {got:?}, not {expected:?}")]
+    Mismatch {
+        expected: HashKey,
+        got: Option<HashKey>,
+    },
+}
+
+fn parse_root(root: &str) -> Result<HashKey, String> {
+    let bytes = hex::decode(root).map_err(|err| err.to_string())?;
+    HashKey::try_from(bytes.as_slice()).map_err(|_| format!("{root} isn't 32 bytes"))
+}
+
+async fn write_bytes(writer: &mut (impl AsyncWrite + Unpin), bytes: &[u8]) -> std::io::Result<()> {
+    let len = u32::try_from(bytes.len())
+        .ok()
+        .filter(|len| *len != END)
+        .ok_or_else(|| std::io::Error::other("key or value too long"))?;
+    writer.write_u32(len).await?;
+    writer.write_all(bytes).await
+}
+
+/// The next key or value, or None at the end of the snapshot
+async fn read_bytes(
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Option<Vec<u8>>, SnapshotError> {
+    let len = reader.read_u32().await?;
+    if len == END {
+        return Ok(None);
+    }
+    let mut bytes = vec![0; len as usize];
+    reader.read_exact(&mut bytes).await?;
+    Ok(Some(bytes))
+}
+
+async fn read_root(reader: &mut (impl AsyncRead + Unpin)) -> std::io::Result<HashKey> {
+    let mut root = [0; 32];
+    reader.read_exact(&mut root).await?;
+    Ok(root.into())
+}
+
+/// Answer one request on `stream`
+async fn serve_one(db: &Db, stream: TcpStream) -> Result<(), SnapshotError> {
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+
+    let root = match reader.read_u8().await? {
+        0 => db.root_hash().await?,
+        1 => Some(read_root(&mut reader).await?),
+        _ => return Err(SnapshotError::Protocol("an unknown request")),
+    };
+    let revision = match root.clone() {
+        Some(root) => match db.revision(root).await {
+            Ok(revision) => Some(revision),
+            Err(
+                api::Error::HashNotFound { .. }
+                | api::Error::RevisionReaped { .. }
+                | api::Error::LatestIsEmpty,
+            ) => None,
+            Err(err) => return Err(err.into()),
+        },
+        // an empty database has no revision to copy
+        None => None,
+    };
+    let (Some(root), Some(revision)) = (root, revision) else {
+        writer.write_u8(NOT_FOUND).await?;
+        writer.flush().await?;
+        return Ok(());
+    };
+
+    writer.write_u8(FOUND).await?;
+    writer.write_all(&root).await?;
+    let mut entries = revision.iter()?;
+    while let Some(entry) = entries.next().await {
+        let (key, value) = entry?;
+        write_bytes(&mut writer, &key).await?;
+        write_bytes(&mut writer, &value).await?;
+    }
+    writer.write_u32(END).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Answer requests on `listener`, one at a time, stopping after `limit` of
+/// them if it is given. A failed request doesn't stop the server.
+async fn serve(db: &Db, listener: TcpListener, limit: Option<usize>) -> std::io::Result<()> {
+    let mut served = 0;
+    while limit.is_none_or(|limit| served < limit) {
+        let (stream, peer) = listener.accept().await?;
+        if let Err(err) = serve_one(db, stream).await {
+            eprintln!("{peer}: {err}");
+        }
+        served += 1;
+    }
+    Ok(())
+}
+
+async fn commit(db: &Db, batch: Vec<BatchOp<Vec<u8>, Vec<u8>>>) -> Result<(), api::Error> {
+    db.propose(batch).await?.commit().await?;
+    Ok(())
+}
+
+/// Build the revision with `root` in `db` from the keys and values read from
+/// `reader`, and check that it has that root
+async fn import(
+    db: &Db,
+    root: HashKey,
+    reader: &mut (impl AsyncRead + Unpin),
+) -> Result<(), SnapshotError> {
+    let mut batch = Vec::with_capacity(BATCH);
+    while let Some(key) = read_bytes(reader).await? {
+        let value = read_bytes(reader)
+            .await?
+            .ok_or(SnapshotError::Protocol("a key without a value"))?;
+        batch.push(BatchOp::Put { key, value });
+        if batch.len() == BATCH {
+            commit(db, std::mem::take(&mut batch)).await?;
+        }
+    }
+    if !batch.is_empty() {
+        commit(db, batch).await?;
+    }
+
+    // the keys and values are only the revision if they hash to its root
+    let got = db.root_hash().await?;
+    if got.as_ref() != Some(&root) {
+        return Err(SnapshotError::Mismatch {
+            expected: root,
+            got,
+        });
+    }
+    Ok(())
+}
+
+/// Copy the revision with `root`, or the latest one, from the server at
+/// `addr` into a new database at `path`, returning its root
+async fn fetch(
+    addr: SocketAddr,
+    path: &Path,
+    root: Option<HashKey>,
+) -> Result<HashKey, SnapshotError> {
+    let stream = TcpStream::connect(addr).await?;
+    let (reader, writer) = stream.into_split();
+    let mut reader = BufReader::new(reader);
+    let mut writer = BufWriter::new(writer);
+    match &root {
+        Some(root) => {
+            writer.write_u8(1).await?;
+            writer.write_all(root).await?;
+        }
+        None => writer.write_u8(0).await?,
+    }
+    writer.flush().await?;
+
+    match reader.read_u8().await? {
+        FOUND => {}
+        NOT_FOUND => return Err(SnapshotError::NotFound),
+        _ => return Err(SnapshotError::Protocol("an unknown status")),
+    }
+    let sent_root = read_root(&mut reader).await?;
+    if root.as_ref().is_some_and(|root| *root != sent_root) {
+        return Err(SnapshotError::Protocol("a different revision"));
+    }
+
+    let db = Db::new(path, DbConfig::builder().truncate(true).build()).await?;
+    import(&db, sent_root.clone(), &mut reader).await?;
+    Ok(sent_root)
+}
+
+async fn run(cli: Cli) -> Result<(), SnapshotError> {
+    match cli.command {
+        Command::Serve { db, addr } => {
+            let db = Db::new(&db, DbConfig::builder().truncate(false).build()).await?;
+            let listener = TcpListener::bind(addr).await?;
+            println!("serving on {}", listener.local_addr()?);
+            serve(&db, listener, None).await?;
+        }
+        Command::Fetch { db, addr, root } => {
+            let root = fetch(addr, &db, root).await?;
+            println!("copied revision {}", hex::encode(root));
+        }
+    }
+    Ok(())
+}
+
+#[tokio::main(flavor = "multi_thread")]
+async fn main() {
+    if let Err(err) = run(Cli::parse()).await {
+        eprintln!("error: {err}");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    /// A database with three commits of the same keys, and their roots
+    async fn source(dir: &Path) -> (Db, [HashKey; 3]) {
+        let cfg = DbConfig::builder().truncate(true).build();
+        let db = Db::new(dir.join("source"), cfg).await.unwrap();
+        let mut roots = Vec::new();
+        for i in 0..3 {
+            let batch: Vec<_> = (0..100)
+                .map(|j| BatchOp::Put {
+                    key: format!("key-{j:03}").into_bytes(),
+                    value: format!("{i}-{j}").into_bytes(),
+                })
+                .collect();
+            let root = db.propose(batch).await.unwrap().commit().await.unwrap();
+            roots.push(root.unwrap());
+        }
+        (db, roots.try_into().unwrap())
+    }
+
+    async fn listen() -> (TcpListener, SocketAddr) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        (listener, addr)
+    }
+
+    #[tokio::test]
+    async fn copies_revisions() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, [first_root, _, latest_root]) = source(dir.path()).await;
+        let (listener, addr) = listen().await;
+        let latest = dir.path().join("latest");
+        let first = dir.path().join("first");
+
+        let (served, latest_copy, first_copy) = tokio::join!(
+            serve(&db, listener, Some(2)),
+            fetch(addr, &latest, None),
+            fetch(addr, &first, Some(first_root.clone())),
+        );
+        served.unwrap();
+        assert_eq!(latest_copy.unwrap(), latest_root);
+        assert_eq!(first_copy.unwrap(), first_root);
+
+        let cfg = DbConfig::builder().truncate(false).build();
+        let copy = Db::new(&first, cfg).await.unwrap();
+        let revision = copy.revision(first_root).await.unwrap();
+        let value = revision.val(b"key-042").await.unwrap();
+        assert_eq!(value.as_deref(), Some(&b"0-42"[..]));
+    }
+
+    #[tokio::test]
+    async fn missing_revision() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, _) = source(dir.path()).await;
+        let (listener, addr) = listen().await;
+        let copy = dir.path().join("copy");
+        let (served, fetched) = tokio::join!(
+            serve(&db, listener, Some(1)),
+            fetch(addr, &copy, Some([7; 32].into())),
+        );
+        served.unwrap();
+        let err = fetched.unwrap_err();
+        assert!(matches!(err, SnapshotError::NotFound), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn tampered_snapshot_rejected() {
+        let dir = tempfile::tempdir().unwrap();
+        let (db, [_, _, root]) = source(dir.path()).await;
+        let revision = db.revision(root.clone()).await.unwrap();
+        let mut entries = revision.iter().unwrap();
+        let mut snapshot = Vec::new();
+        while let Some(entry) = entries.next().await {
+            let (key, mut value) = entry.unwrap();
+            if &*key == b"key-007" {
+                value = b"forged".to_vec();
+            }
+            write_bytes(&mut snapshot, &key).await.unwrap();
+            write_bytes(&mut snapshot, &value).await.unwrap();
+        }
+        snapshot.write_u32(END).await.unwrap();
+
+        let cfg = DbConfig::builder().truncate(true).build();
+        let copy = Db::new(dir.path().join("copy"), cfg).await.unwrap();
+        let err = import(&copy, root.clone(), &mut snapshot.as_slice())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, SnapshotError::Mismatch { .. }), "{err:?}");
+
+        // a snapshot cut short is an error rather than a smaller copy
+        let cfg = DbConfig::builder().truncate(true).build();
+        let copy = Db::new(dir.path().join("cut"), cfg).await.unwrap();
+        let cut = snapshot.get(..snapshot.len() / 2).unwrap();
+        let err = import(&copy, root, &mut &*cut).await.unwrap_err();
+        assert!(matches!(err, SnapshotError::Io(_)), "{err:?}");
+    }
+}
@@ -0,0 +1,730 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Audit bundles: answers to several reads of one revision, with a single
+//! proof covering all of them.
+//!
+//! A bundle is built with [crate::merkle::Merkle::audit_bundle] or
+//! [crate::db::Db::audit_bundle] and checked with [verify_audit_bundle](crate::audit::verify_audit_bundle),
+//! which needs nothing but the bundle and the root hash it should match.
+//!
+//! The proof is the set of trie nodes visited while answering the requests,
+//! each appearing once however many requests pass through it. Every node
+//! records its full key and the hashes of its children, and a node's hash
+//! covers its key, so checking each node against the child hash in its
+//! parent ties every node to the root. Entries are then read from the proven
+//! nodes alone: a request is only answered if the proof includes every node
+//! under which a matching entry could be.
+
+use std::collections::HashMap;
+use std::iter::once;
+
+use integer_encoding::{VarInt, VarIntReader};
+use sha2::{Digest, Sha256};
+use storage::{BranchNode, Hashable, NibblesIterator, Path, Preimage, TrieHash, ValueDigest};
+use thiserror::Error;
+
+use crate::merkle::MerkleError;
+use crate::proof::ProofNode;
+use crate::stream::key_from_nibble_iter;
+
+/// The version of the encoding written by [AuditBundle::to_bytes]
+pub const AUDIT_BUNDLE_VERSION: u8 = 1;
+
+/// The largest encoded bundle that is written or read
+pub const MAX_AUDIT_BUNDLE_BYTES: usize = 16 << 20;
+
+/// The most entries a single prefix digest or range request can cover
+pub const MAX_AUDIT_ENTRIES: usize = 1024;
+
+const MAGIC: &[u8; 4] = b"FWAB";
+
+/// A key/value pair read for an audit
+pub type AuditEntry = (Box<[u8]>, Box<[u8]>);
+
+/// One read to include in an [AuditBundle]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditRequest {
+    /// The value of a key, or its absence
+    Key(Box<[u8]>),
+    /// A digest of every entry whose key starts with the prefix; see
+    /// [prefix_digest]
+    PrefixDigest(Box<[u8]>),
+    /// Every entry with a key in `[start, end)`
+    Range {
+        /// The first key in the range
+        start: Box<[u8]>,
+        /// The key just past the range
+        end: Box<[u8]>,
+    },
+}
+
+/// The answer to an [AuditRequest]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuditResult {
+    /// The answer to [AuditRequest::Key]
+    Value(Option<Box<[u8]>>),
+    /// The answer to [AuditRequest::PrefixDigest]
+    Digest(TrieHash),
+    /// The answer to [AuditRequest::Range], in key order
+    Range(Vec<AuditEntry>),
+}
+
+/// A request and its answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AuditItem {
+    /// What was read
+    pub request: AuditRequest,
+    /// What was found
+    pub result: AuditResult,
+}
+
+/// Answers to several reads of the revision with `root_hash`, and one proof
+/// covering all of them
+#[derive(Debug, Clone)]
+pub struct AuditBundle {
+    /// The root hash of the revision that was read
+    pub root_hash: TrieHash,
+    /// The reads, in the order they were requested
+    pub items: Vec<AuditItem>,
+    /// The nodes proving every answer, sorted by key without duplicates
+    pub proof: Box<[ProofNode]>,
+}
+
+/// Reasons an audit bundle can't be built, decoded or verified. Errors
+/// about a request carry its index in [AuditBundle::items].
+#[derive(Debug, Error)]
+pub enum AuditError {
+    /// The bundle is for a different root than the one it was checked against
+    #[error("the bundle is for root {0:?}, not the expected root")]
+    Root(TrieHash),
+    /// A proof node isn't linked to the root by the hashes above it
+    #[error("proof node at {key:?} does not match the hash its parent has for it")]
+    ProofNode {
+        /// The nibbles of the node's key
+        key: Box<[u8]>,
+    },
+    /// The proof doesn't cover everything a request reads
+    #[error("request {request} is not covered by the proof")]
+    Unproven {
+        /// The index of the request
+        request: usize,
+    },
+    /// A claimed value differs from the proven one
+    #[error("the value claimed for request {request} does not match the proof")]
+    Value {
+        /// The index of the request
+        request: usize,
+    },
+    /// A claimed prefix digest differs from the digest of the proven entries
+    #[error("the digest claimed for request {request} does not match the proof")]
+    Digest {
+        /// The index of the request
+        request: usize,
+    },
+    /// The claimed entries of a range differ from the proven ones
+    #[error("the entries claimed for request {request} do not match the proof")]
+    Range {
+        /// The index of the request
+        request: usize,
+    },
+    /// A prefix digest or range covers more than [MAX_AUDIT_ENTRIES] entries
+    #[error("request {request} covers more than {MAX_AUDIT_ENTRIES} entries")]
+    TooManyEntries {
+        /// The index of the request
+        request: usize,
+    },
+    /// The encoded bundle is larger than [MAX_AUDIT_BUNDLE_BYTES]
+    #[error("the bundle is larger than {MAX_AUDIT_BUNDLE_BYTES} bytes")]
+    TooLarge,
+    /// The bundle was encoded with a version this code can't read
+    #[error("unsupported audit bundle version {0}")]
+    Version(u8),
+    /// The encoded bundle is malformed
+    #[error("malformed audit bundle: {0}")]
+    Format(&'static str),
+    /// Reading the trie failed while building the bundle
+    #[error(transparent)]
+    Merkle(#[from] MerkleError),
+}
+
+/// The digest of `entries`, which must be in key order: a SHA-256 over
+/// each key and value, each preceded by its length as a varint
+pub fn prefix_digest<'a>(entries: impl IntoIterator<Item = &'a AuditEntry>) -> TrieHash {
+    let mut hasher = Sha256::new();
+    for (key, value) in entries {
+        hasher.update(key.len().encode_var_vec());
+        hasher.update(key);
+        hasher.update(value.len().encode_var_vec());
+        hasher.update(value);
+    }
+    hasher.finalize().into()
+}
+
+/// The keys an [AuditRequest] reads, as nibbles
+#[derive(Debug)]
+pub(crate) enum Span {
+    Key(Path),
+    Prefix(Path),
+    Range { start: Path, end: Path },
+}
+
+impl From<&AuditRequest> for Span {
+    fn from(request: &AuditRequest) -> Self {
+        let nibbles = |key: &[u8]| Path::from_nibbles_iterator(NibblesIterator::new(key));
+        match request {
+            AuditRequest::Key(key) => Span::Key(nibbles(key)),
+            AuditRequest::PrefixDigest(prefix) => Span::Prefix(nibbles(prefix)),
+            AuditRequest::Range { start, end } => Span::Range {
+                start: nibbles(start),
+                end: nibbles(end),
+            },
+        }
+    }
+}
+
+impl Span {
+    /// Whether any key starting with `prefix` is read
+    pub(crate) fn intersects(&self, prefix: &[u8]) -> bool {
+        match self {
+            Span::Key(key) => key.starts_with(prefix),
+            Span::Prefix(span) => span.starts_with(prefix) || prefix.starts_with(span),
+            Span::Range { start, end } => {
+                // the smallest key under `prefix` is `prefix` itself, and
+                // keys under it reach past the start unless it sorts before
+                // the start's first nibbles
+                let start = start.get(..prefix.len()).unwrap_or(start);
+                start <= prefix && prefix < &end[..]
+            }
+        }
+    }
+
+    /// Whether `key` is read
+    pub(crate) fn contains(&self, key: &[u8]) -> bool {
+        match self {
+            Span::Key(span) => &span[..] == key,
+            Span::Prefix(span) => key.starts_with(span),
+            Span::Range { start, end } => &start[..] <= key && key < &end[..],
+        }
+    }
+}
+
+impl AuditRequest {
+    /// The answer to this request, given every entry it reads in key order
+    pub(crate) fn result(&self, entries: Vec<AuditEntry>) -> AuditResult {
+        match self {
+            AuditRequest::Key(_) => {
+                AuditResult::Value(entries.into_iter().next().map(|(_, value)| value))
+            }
+            AuditRequest::PrefixDigest(_) => AuditResult::Digest(prefix_digest(&entries)),
+            AuditRequest::Range { .. } => AuditResult::Range(entries),
+        }
+    }
+
+    /// The error for a claimed answer that doesn't match the proof
+    const fn mismatch(&self, request: usize) -> AuditError {
+        match self {
+            AuditRequest::Key(_) => AuditError::Value { request },
+            AuditRequest::PrefixDigest(_) => AuditError::Digest { request },
+            AuditRequest::Range { .. } => AuditError::Range { request },
+        }
+    }
+}
+
+/// Check that every answer in `bundle` is proven to be what the revision
+/// with `root_hash` holds, and that every read is answered in full
+pub fn verify_audit_bundle(root_hash: &TrieHash, bundle: &AuditBundle) -> Result<(), AuditError> {
+    if bundle.root_hash != *root_hash {
+        return Err(AuditError::Root(bundle.root_hash.clone()));
+    }
+    let tree = ProofTree::link(root_hash, &bundle.proof)?;
+
+    for (request, item) in bundle.items.iter().enumerate() {
+        let span = Span::from(&item.request);
+        let mut entries = Vec::new();
+        tree.collect(0, &span, &mut entries, request)?;
+        if item.request.result(entries) != item.result {
+            return Err(item.request.mismatch(request));
+        }
+    }
+    Ok(())
+}
+
+/// The nodes of a proof, linked to their parents
+struct ProofTree<'a> {
+    nodes: &'a [ProofNode],
+    /// The index of each node's child at each nibble
+    children: HashMap<(usize, u8), usize>,
+}
+
+impl<'a> ProofTree<'a> {
+    /// Check that every node is the child its parent has a hash for, all
+    /// the way up to `root_hash`
+    fn link(root_hash: &TrieHash, nodes: &'a [ProofNode]) -> Result<Self, AuditError> {
+        let invalid = |node: &ProofNode| AuditError::ProofNode {
+            key: node.key.clone(),
+        };
+        let Some(root) = nodes.first() else {
+            return Err(AuditError::Format("the proof is empty"));
+        };
+        if root.to_hash() != *root_hash {
+            return Err(invalid(root));
+        }
+
+        // sorted by key, each node comes after its ancestors, which are the
+        // nodes on the stack whose keys are a prefix of its own
+        let mut children = HashMap::new();
+        let mut ancestors = vec![0];
+        for (index, node) in nodes.iter().enumerate() {
+            if node.key.len() % BranchNode::NIBBLES_PER_BYTE != 0 && node.value_digest.is_some() {
+                return Err(invalid(node));
+            }
+            if index == 0 {
+                continue;
+            }
+            if nodes
+                .get(index - 1)
+                .is_some_and(|prev| prev.key >= node.key)
+            {
+                return Err(AuditError::Format("proof nodes are not sorted"));
+            }
+
+            while let Some(&top) = ancestors.last() {
+                match nodes.get(top) {
+                    Some(ancestor) if node.key.starts_with(&ancestor.key) => break,
+                    _ => ancestors.pop(),
+                };
+            }
+            let parent = ancestors
+                .last()
+                .and_then(|&parent| Some((parent, nodes.get(parent)?)));
+            let Some((parent_index, parent)) = parent else {
+                return Err(invalid(node));
+            };
+            let Some(&nibble) = node.key.get(parent.key.len()) else {
+                return Err(invalid(node));
+            };
+            let expected = parent
+                .child_hashes
+                .get(nibble as usize)
+                .and_then(Option::as_ref);
+            if expected != Some(&node.to_hash()) {
+                return Err(invalid(node));
+            }
+            children.insert((parent_index, nibble), index);
+            ancestors.push(index);
+        }
+
+        Ok(Self { nodes, children })
+    }
+
+    /// Collect the proven entries of `span` under the node at `index`, in
+    /// key order
+    fn collect(
+        &self,
+        index: usize,
+        span: &Span,
+        entries: &mut Vec<AuditEntry>,
+        request: usize,
+    ) -> Result<(), AuditError> {
+        let Some(node) = self.nodes.get(index) else {
+            return Err(AuditError::Unproven { request });
+        };
+
+        if span.contains(&node.key) {
+            match node.value_digest() {
+                None => {}
+                Some(ValueDigest::Value(value)) => {
+                    if entries.len() == MAX_AUDIT_ENTRIES {
+                        return Err(AuditError::TooManyEntries { request });
+                    }
+                    let key = key_from_nibble_iter(node.key.iter().copied());
+                    entries.push((key, value.into()));
+                }
+                // only the value itself can be checked against a claim
+                Some(ValueDigest::_Hash(_)) => return Err(AuditError::Unproven { request }),
+            }
+        }
+
+        for (nibble, _) in node.children() {
+            let child_prefix: Box<[u8]> =
+                node.key.iter().copied().chain(once(nibble as u8)).collect();
+            if !span.intersects(&child_prefix) {
+                continue;
+            }
+            let Some(&child) = self.children.get(&(index, nibble as u8)) else {
+                return Err(AuditError::Unproven { request });
+            };
+            self.collect(child, span, entries, request)?;
+        }
+        Ok(())
+    }
+}
+
+impl AuditBundle {
+    /// Encode this bundle as a single versioned blob
+    pub fn to_bytes(&self) -> Result<Box<[u8]>, AuditError> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.push(AUDIT_BUNDLE_VERSION);
+        bytes.extend_from_slice(&self.root_hash);
+
+        bytes.extend(self.items.len().encode_var_vec());
+        for item in &self.items {
+            match &item.request {
+                AuditRequest::Key(key) => {
+                    bytes.push(0);
+                    write_bytes(&mut bytes, key);
+                }
+                AuditRequest::PrefixDigest(prefix) => {
+                    bytes.push(1);
+                    write_bytes(&mut bytes, prefix);
+                }
+                AuditRequest::Range { start, end } => {
+                    bytes.push(2);
+                    write_bytes(&mut bytes, start);
+                    write_bytes(&mut bytes, end);
+                }
+            }
+            match &item.result {
+                AuditResult::Value(None) => bytes.push(0),
+                AuditResult::Value(Some(value)) => {
+                    bytes.push(1);
+                    write_bytes(&mut bytes, value);
+                }
+                AuditResult::Digest(digest) => {
+                    bytes.push(2);
+                    bytes.extend_from_slice(digest);
+                }
+                AuditResult::Range(entries) => {
+                    bytes.push(3);
+                    bytes.extend(entries.len().encode_var_vec());
+                    for (key, value) in entries {
+                        write_bytes(&mut bytes, key);
+                        write_bytes(&mut bytes, value);
+                    }
+                }
+            }
+        }
+
+        bytes.extend(self.proof.len().encode_var_vec());
+        for node in self.proof.iter() {
+            write_bytes(&mut bytes, &node.key);
+            match node.value_digest() {
+                None => bytes.push(0),
+                Some(ValueDigest::Value(value)) => {
+                    bytes.push(1);
+                    write_bytes(&mut bytes, value);
+                }
+                Some(ValueDigest::_Hash(hash)) => {
+                    bytes.push(2);
+                    write_bytes(&mut bytes, hash);
+                }
+            }
+            bytes.extend(node.children().count().encode_var_vec());
+            for (index, hash) in node.children() {
+                bytes.extend(index.encode_var_vec());
+                bytes.extend_from_slice(hash);
+            }
+        }
+
+        if bytes.len() > MAX_AUDIT_BUNDLE_BYTES {
+            return Err(AuditError::TooLarge);
+        }
+        Ok(bytes.into())
+    }
+
+    /// Decode a bundle written by [AuditBundle::to_bytes]. This only checks
+    /// the encoding; use [verify_audit_bundle] to check the contents.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, AuditError> {
+        if bytes.len() > MAX_AUDIT_BUNDLE_BYTES {
+            return Err(AuditError::TooLarge);
+        }
+        let mut reader = Reader(bytes);
+        if reader.take(MAGIC.len())? != MAGIC {
+            return Err(AuditError::Format("not an audit bundle"));
+        }
+        let version = reader.byte()?;
+        if version != AUDIT_BUNDLE_VERSION {
+            return Err(AuditError::Version(version));
+        }
+        let root_hash = reader.hash()?;
+
+        let mut items = Vec::new();
+        for _ in 0..reader.len()? {
+            let request = match reader.byte()? {
+                0 => AuditRequest::Key(reader.bytes()?),
+                1 => AuditRequest::PrefixDigest(reader.bytes()?),
+                2 => AuditRequest::Range {
+                    start: reader.bytes()?,
+                    end: reader.bytes()?,
+                },
+                _ => return Err(AuditError::Format("unknown request type")),
+            };
+            let result = match reader.byte()? {
+                0 => AuditResult::Value(None),
+                1 => AuditResult::Value(Some(reader.bytes()?)),
+                2 => AuditResult::Digest(reader.hash()?),
+                3 => {
+                    let mut entries = Vec::new();
+                    for _ in 0..reader.len()? {
+                        entries.push((reader.bytes()?, reader.bytes()?));
+                    }
+                    AuditResult::Range(entries)
+                }
+                _ => return Err(AuditError::Format("unknown result type")),
+            };
+            items.push(AuditItem { request, result });
+        }
+
+        let mut proof = Vec::new();
+        for _ in 0..reader.len()? {
+            let key = reader.bytes()?;
+            let value_digest = match reader.byte()? {
+                0 => None,
+                1 => Some(ValueDigest::Value(reader.bytes()?)),
+                2 => Some(ValueDigest::_Hash(reader.bytes()?)),
+                _ => return Err(AuditError::Format("unknown value type")),
+            };
+            let mut child_hashes: [Option<TrieHash>; BranchNode::MAX_CHILDREN] =
+                [const { None }; BranchNode::MAX_CHILDREN];
+            for _ in 0..reader.len()? {
+                let index = reader.len()?;
+                let hash = reader.hash()?;
+                let Some(slot) = child_hashes.get_mut(index) else {
+                    return Err(AuditError::Format("child index out of bounds"));
+                };
+                *slot = Some(hash);
+            }
+            proof.push(ProofNode {
+                key,
+                value_digest,
+                child_hashes,
+            });
+        }
+
+        if !reader.0.is_empty() {
+            return Err(AuditError::Format("trailing bytes"));
+        }
+        Ok(Self {
+            root_hash,
+            items,
+            proof: proof.into(),
+        })
+    }
+}
+
+fn write_bytes(bytes: &mut Vec<u8>, data: &[u8]) {
+    bytes.extend(data.len().encode_var_vec());
+    bytes.extend_from_slice(data);
+}
+
+/// Reads the parts of an encoded bundle, failing on truncation
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    const fn take(&mut self, len: usize) -> Result<&'a [u8], AuditError> {
+        if len > self.0.len() {
+            return Err(AuditError::Format("truncated"));
+        }
+        let (data, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Ok(data)
+    }
+
+    fn byte(&mut self) -> Result<u8, AuditError> {
+        let [byte] = self.take(1)? else {
+            unreachable!("took one byte");
+        };
+        Ok(*byte)
+    }
+
+    fn len(&mut self) -> Result<usize, AuditError> {
+        self.0
+            .read_varint()
+            .map_err(|_| AuditError::Format("truncated"))
+    }
+
+    fn bytes(&mut self) -> Result<Box<[u8]>, AuditError> {
+        let len = self.len()?;
+        self.take(len).map(Into::into)
+    }
+
+    fn hash(&mut self) -> Result<TrieHash, AuditError> {
+        let hash: [u8; 32] = self
+            .take(32)?
+            .try_into()
+            .expect("took the length of a hash");
+        Ok(hash.into())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::indexing_slicing, clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::merkle::Merkle;
+    use std::sync::Arc;
+    use storage::{HashedNodeReader, ImmutableProposal, MemStore, NodeStore};
+
+    fn trie() -> Merkle<NodeStore<Arc<ImmutableProposal>, MemStore>> {
+        let mut merkle = Merkle::from(NodeStore::new_empty_proposal(MemStore::new(vec![]).into()));
+        for prefix in ["acct/", "bal/", "meta/"] {
+            for i in 0..50u8 {
+                let key = format!("{prefix}{i:02}");
+                merkle
+                    .insert(key.as_bytes(), key.to_uppercase().into_bytes().into())
+                    .unwrap();
+            }
+        }
+        merkle.hash()
+    }
+
+    fn key(key: &str) -> Box<[u8]> {
+        key.as_bytes().into()
+    }
+
+    fn requests() -> Vec<AuditRequest> {
+        vec![
+            AuditRequest::Key(key("acct/07")),
+            AuditRequest::Key(key("bal/07")),
+            AuditRequest::Key(key("meta/42")),
+            AuditRequest::Key(key("bal/99")),
+            AuditRequest::Range {
+                start: key("bal/10"),
+                end: key("bal/15"),
+            },
+            AuditRequest::PrefixDigest(key("meta/1")),
+        ]
+    }
+
+    #[test]
+    fn bundle() {
+        let merkle = trie();
+        let root_hash = merkle.nodestore().root_hash().unwrap().unwrap();
+        let bundle = merkle.audit_bundle(requests()).unwrap();
+
+        let results: Vec<_> = bundle.items.iter().map(|item| &item.result).collect();
+        assert_eq!(results[0], &AuditResult::Value(Some(key("ACCT/07"))));
+        assert_eq!(results[3], &AuditResult::Value(None));
+        let AuditResult::Range(entries) = results[4] else {
+            panic!("expected a range");
+        };
+        let keys: Vec<_> = entries.iter().map(|(key, _)| key.clone()).collect();
+        assert_eq!(
+            keys,
+            ["bal/10", "bal/11", "bal/12", "bal/13", "bal/14"].map(key)
+        );
+        let expected: Vec<_> = (10..20)
+            .map(|i| (key(&format!("meta/{i}")), key(&format!("META/{i}"))))
+            .collect();
+        assert_eq!(results[5], &AuditResult::Digest(prefix_digest(&expected)));
+
+        // the paths to the keys share nodes, which appear once
+        let mut keys: Vec<_> = bundle.proof.iter().map(|node| &node.key).collect();
+        keys.dedup();
+        assert_eq!(keys.len(), bundle.proof.len());
+
+        verify_audit_bundle(&root_hash, &bundle).unwrap();
+        let decoded = AuditBundle::from_bytes(&bundle.to_bytes().unwrap()).unwrap();
+        assert_eq!(decoded.items, bundle.items);
+        verify_audit_bundle(&root_hash, &decoded).unwrap();
+    }
+
+    #[test]
+    fn tampered_bundle() {
+        let merkle = trie();
+        let root_hash = merkle.nodestore().root_hash().unwrap().unwrap();
+        let bundle = merkle.audit_bundle(requests()).unwrap();
+        let verify = |tamper: &dyn Fn(&mut AuditBundle)| {
+            let mut bundle = bundle.clone();
+            tamper(&mut bundle);
+            verify_audit_bundle(&root_hash, &bundle).unwrap_err()
+        };
+
+        let err = verify(&|bundle| {
+            bundle.items[1].result = AuditResult::Value(Some(key("forged")));
+        });
+        assert!(matches!(err, AuditError::Value { request: 1 }), "{err}");
+
+        // claiming a value for the absent key
+        let err = verify(&|bundle| {
+            bundle.items[3].result = AuditResult::Value(Some(key("forged")));
+        });
+        assert!(matches!(err, AuditError::Value { request: 3 }), "{err}");
+
+        let err = verify(&|bundle| {
+            let AuditResult::Range(entries) = &mut bundle.items[4].result else {
+                unreachable!();
+            };
+            entries.pop();
+        });
+        assert!(matches!(err, AuditError::Range { request: 4 }), "{err}");
+
+        let err = verify(&|bundle| {
+            bundle.items[5].result = AuditResult::Digest(TrieHash::default());
+        });
+        assert!(matches!(err, AuditError::Digest { request: 5 }), "{err}");
+
+        // changing the value of a proven entry breaks the hash above it
+        let leaf = bundle
+            .proof
+            .iter()
+            .rposition(|node| node.value_digest.is_some())
+            .unwrap();
+        let err = verify(&|bundle| {
+            bundle.proof[leaf].value_digest = Some(ValueDigest::Value(key("forged")));
+        });
+        assert!(
+            matches!(&err, AuditError::ProofNode { key } if *key == bundle.proof[leaf].key),
+            "{err}"
+        );
+
+        // dropping a node leaves the requests under it unproven
+        let err = verify(&|bundle| {
+            let mut proof = bundle.proof.to_vec();
+            proof.remove(leaf);
+            bundle.proof = proof.into();
+        });
+        assert!(matches!(err, AuditError::Unproven { .. }), "{err}");
+
+        let err = verify(&|bundle| bundle.root_hash = TrieHash::default());
+        assert!(matches!(err, AuditError::Root(_)), "{err}");
+        let err = verify_audit_bundle(&TrieHash::default(), &bundle).unwrap_err();
+        assert!(matches!(err, AuditError::Root(_)), "{err}");
+    }
+
+    #[test]
+    fn encoding_limits() {
+        let merkle = trie();
+        let bundle = merkle.audit_bundle(requests()).unwrap();
+        let bytes = bundle.to_bytes().unwrap();
+
+        let mut newer = bytes.to_vec();
+        newer[MAGIC.len()] = AUDIT_BUNDLE_VERSION + 1;
+        assert!(matches!(
+            AuditBundle::from_bytes(&newer),
+            Err(AuditError::Version(_))
+        ));
+        assert!(matches!(
+            AuditBundle::from_bytes(&bytes[..bytes.len() - 1]),
+            Err(AuditError::Format(_))
+        ));
+        assert!(matches!(
+            AuditBundle::from_bytes(&vec![0; MAX_AUDIT_BUNDLE_BYTES + 1]),
+            Err(AuditError::TooLarge)
+        ));
+
+        let mut merkle = Merkle::from(NodeStore::new_empty_proposal(MemStore::new(vec![]).into()));
+        for i in 0..=MAX_AUDIT_ENTRIES as u32 {
+            merkle.insert(&i.to_be_bytes(), Box::new([])).unwrap();
+        }
+        let err = merkle
+            .hash()
+            .audit_bundle(vec![
+                AuditRequest::Key(key("k")),
+                AuditRequest::PrefixDigest(key("")),
+            ])
+            .unwrap_err();
+        assert!(matches!(err, AuditError::TooManyEntries { request: 1 }));
+    }
+}
@@ -0,0 +1,57 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use storage::Hashable;
+
+use crate::proof::{Proof, ProofError};
+
+/// A change proof proves the changes that turn the key-value pairs of one
+/// revision into those of another, over a range of keys.
+///
+/// The range is the one a [crate::range_proof::RangeProof] would cover,
+/// with `key_changes` in place of its key-value pairs, and the boundary
+/// proofs are of the newer revision. The changes can only be verified
+/// against a trie that has the older revision's key-value pairs in the
+/// range: applying them has to leave it with what the newer revision has
+/// there, which [crate::db::Db::apply_change_proof] checks.
+#[derive(Debug)]
+pub struct ChangeProof<K: AsRef<[u8]>, V: AsRef<[u8]>, H: Hashable> {
+    /// The proof of the start of the range, if it has one
+    pub start_proof: Option<Proof<H>>,
+    /// The proof of the last of `key_changes`, or of the end of the range
+    /// if there are none and it has one
+    pub end_proof: Option<Proof<H>>,
+    /// Every key whose value changed in the range, in order, with its new
+    /// value, or None if it was deleted
+    pub key_changes: Box<[(K, Option<V>)]>,
+}
+
+impl<K: AsRef<[u8]>, V: AsRef<[u8]>, H: Hashable> ChangeProof<K, V, H> {
+    /// Check that `key_changes` are in order and from `start` to `end`,
+    /// returning where the range ends: at the last of them, or at `end` if
+    /// there are none
+    pub fn range_end<'a>(
+        &'a self,
+        start: Option<&[u8]>,
+        end: Option<&'a [u8]>,
+    ) -> Result<Option<&'a [u8]>, ProofError> {
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Err(ProofError::EmptyRange);
+            }
+        }
+
+        let mut last: Option<&[u8]> = None;
+        for (key, _) in self.key_changes.iter() {
+            let key = key.as_ref();
+            if last.is_some_and(|last| last >= key) {
+                return Err(ProofError::NonMonotonicIncreaseRange);
+            }
+            if start.is_some_and(|start| key < start) || end.is_some_and(|end| key > end) {
+                return Err(ProofError::KeyOutsideRange);
+            }
+            last = Some(key);
+        }
+        Ok(last.or(end))
+    }
+}
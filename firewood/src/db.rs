@@ -1,23 +1,63 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use crate::merkle::{Merkle, MerkleError};
+use crate::audit::{AuditBundle, AuditRequest};
+use crate::change_proof::ChangeProof;
+use crate::diff::KeyChange;
+use crate::equivalence::{ByteEquality, RewriteFilter, RewriteStats, ValueEquivalence};
+use crate::export;
+use crate::invariant::{self, ChangeSet, CommitInvariant};
+use crate::journal;
+use crate::latency::{self, ApiMethod, Exemplar, OperationTimer};
+use crate::merkle::{HealStats, Key, KeyLookup, Merkle, MerkleError, Value};
+use crate::operations::{
+    CancellationToken, OperationHandle, OperationId, OperationInfo, OperationRegistry,
+};
+use crate::portable::{self, PortableReader, PortableWriter};
 use crate::proof::{Proof, ProofNode};
 use crate::range_proof::RangeProof;
-use crate::stream::MerkleKeyValueStream;
-use crate::v2::api::{self, KeyType, ValueType};
-pub use crate::v2::api::{Batch, BatchOp};
+use crate::restore::{self, RestorePlan, RestoreStep, RestoreTarget};
+use crate::snapshot::OperationalSnapshot;
+use crate::statistics::{self, DbStatistics, TrieStatistics};
+use crate::stream::{MerkleKeyValueStream, MerkleNodeStream};
+use crate::sync::{SyncStatus, SyncTracker};
+use crate::system::{
+    decode_prefixes, encode_prefixes, SystemBatch, SystemKeys, SystemStore, DEFAULT_SYSTEM_PREFIX,
+    FROZEN_PREFIXES,
+};
+use crate::token::ConsistencyToken;
+use crate::v2::api::{self, Db as _, DbView as _, KeyType, ValueType};
+pub use crate::v2::api::{Batch, BatchOp, BatchOpHint};
+use crate::verify::{self, VerifyOptions, VerifyReport};
 
-use crate::manager::{RevisionManager, RevisionManagerConfig};
+use crate::manager::{
+    CommitEvent, CommittedRevision, DurabilityMode, PendingCommit, PinGuard, RevisionManager,
+    RevisionManagerConfig, RevisionManagerError,
+};
+use crate::registry;
 use async_trait::async_trait;
-use metrics::{counter, describe_counter};
+use futures::io::{AsyncRead, AsyncWrite};
+use futures::{Stream, StreamExt};
+use metrics::counter;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
 use std::error::Error;
 use std::fmt;
 use std::io::Write;
-use std::path::Path;
-use std::sync::Arc;
-use storage::{Committed, FileBacked, HashedNodeReader, ImmutableProposal, NodeStore, TrieHash};
-use tokio::sync::RwLock;
+use std::iter::successors;
+use std::mem::take;
+use std::num::NonZeroUsize;
+use std::ops::{Deref, Range};
+use std::path::{Path as FilePath, PathBuf};
+use std::sync::atomic::{self, AtomicBool};
+use std::sync::{Arc, Weak};
+use std::time::Duration;
+use storage::logger::warn;
+use storage::{
+    CacheUsage, Committed, FileBacked, HashedNodeReader, ImmutableProposal, MutableProposal,
+    NibblesIterator, NodeStore, Parentable, Path, ReadableStorage as _, TrieHash, TrieReader,
+};
+use tokio::sync::{broadcast, RwLock};
 use typed_builder::TypedBuilder;
 
 #[derive(Debug)]
@@ -49,12 +89,57 @@ impl Error for DbError {}
 
 type HistoricalRev = NodeStore<Committed, FileBacked>;
 
-/// Metrics for the database.
-/// TODO: Add more metrics
-pub struct DbMetrics {
-    proposals: metrics::Counter,
+/// A retained revision that is kept from being reaped until this is
+/// dropped, returned by [Db::snapshot]. It reads like the revision itself,
+/// which can also still be opened by its root hash in the meantime.
+#[derive(Debug)]
+pub struct Snapshot {
+    revision: Arc<HistoricalRev>,
+    pin: PinGuard,
+}
+
+impl Snapshot {
+    /// The root hash of the revision
+    pub const fn root_hash(&self) -> &TrieHash {
+        self.pin.root_hash()
+    }
+
+    /// The revision, which stays readable after the snapshot is dropped
+    /// only for as long as it is retained
+    pub const fn revision(&self) -> &Arc<HistoricalRev> {
+        &self.revision
+    }
+
+    /// The nodes, keys and values of the revision's trie, counted by
+    /// reading every node of it
+    pub fn statistics(&self) -> Result<TrieStatistics, api::Error> {
+        Ok(statistics::trie(&self.revision)?)
+    }
+
+    /// An estimate of [Snapshot::statistics] from `probes` paths from the
+    /// root down to a leaf, taking a random child at each branch, so only
+    /// those paths are read. The counts and totals are unbiased estimates
+    /// that get closer with more probes, and `max_depth` is the deepest
+    /// key found, which can be less than the real one.
+    pub fn estimate_statistics(&self, probes: usize) -> Result<TrieStatistics, api::Error> {
+        Ok(statistics::estimate_trie(&self.revision, probes)?)
+    }
+}
+
+impl Deref for Snapshot {
+    type Target = HistoricalRev;
+
+    fn deref(&self) -> &HistoricalRev {
+        &self.revision
+    }
 }
 
+/// Metrics for the database.
+///
+/// Metrics are emitted to the global recorder as they happen, so none are
+/// held here; see [crate::metrics] for what is emitted.
+pub struct DbMetrics {}
+
 impl std::fmt::Debug for DbMetrics {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("DbMetrics").finish()
@@ -73,32 +158,122 @@ impl api::DbView for HistoricalRev {
     }
 
     async fn val<K: api::KeyType>(&self, key: K) -> Result<Option<Box<[u8]>>, api::Error> {
+        if self.is_hidden(key.as_ref()) {
+            return Ok(None);
+        }
+        let timer = OperationTimer::start(ApiMethod::Get);
         let merkle = Merkle::from(self);
-        Ok(merkle.get_value(key.as_ref())?)
+        let value = merkle.get_value(key.as_ref())?;
+        timer.finish(Some(key.as_ref()), || self.kind.root_hash());
+        Ok(value)
+    }
+
+    async fn get_many<K: KeyType, I: IntoIterator<Item = K> + Send>(
+        &self,
+        keys: I,
+    ) -> Result<Vec<Option<Box<[u8]>>>, api::Error> {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let mut values = Merkle::from(self).get_values(&keys)?;
+        for (key, value) in keys.iter().zip(values.iter_mut()) {
+            if self.is_hidden(key.as_ref()) {
+                *value = None;
+            }
+        }
+        Ok(values)
+    }
+
+    async fn get_slice<K: KeyType>(
+        &self,
+        key: K,
+        range: Range<usize>,
+    ) -> Result<Option<api::ValueSlice>, api::Error> {
+        if self.is_hidden(key.as_ref()) {
+            return Ok(None);
+        }
+        match Merkle::from(self).get_node(key.as_ref())? {
+            Some(node) => api::ValueSlice::new(node, range),
+            None => Ok(None),
+        }
+    }
+
+    async fn value_len<K: KeyType>(&self, key: K) -> Result<Option<usize>, api::Error> {
+        if self.is_hidden(key.as_ref()) {
+            return Ok(None);
+        }
+        let node = Merkle::from(self).get_node(key.as_ref())?;
+        Ok(node.and_then(|node| node.value().map(<[u8]>::len)))
     }
 
     async fn single_key_proof<K: api::KeyType>(
         &self,
         key: K,
     ) -> Result<Proof<ProofNode>, api::Error> {
+        let timer = OperationTimer::start(ApiMethod::Prove);
         let merkle = Merkle::from(self);
-        merkle.prove(key.as_ref()).map_err(api::Error::from)
+        let proof = merkle.prove(key.as_ref())?;
+        timer.finish(Some(key.as_ref()), || self.kind.root_hash());
+        Ok(proof)
+    }
+
+    async fn get_with_proof<K: api::KeyType>(
+        &self,
+        key: K,
+    ) -> Result<(Option<Box<[u8]>>, Proof<ProofNode>), api::Error> {
+        let timer = OperationTimer::start(ApiMethod::Prove);
+        let merkle = Merkle::from(self);
+        let found = merkle.get_with_proof(key.as_ref())?;
+        timer.finish(Some(key.as_ref()), || self.kind.root_hash());
+        Ok(found)
     }
 
     async fn range_proof<K: api::KeyType, V>(
         &self,
-        _first_key: Option<K>,
-        _last_key: Option<K>,
-        _limit: Option<usize>,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
     ) -> Result<Option<RangeProof<Box<[u8]>, Box<[u8]>, ProofNode>>, api::Error> {
-        todo!()
+        let limit = range_proof_limit(limit)?;
+        let proof = Merkle::from(self)
+            .range_proof(
+                first_key.as_ref().map(AsRef::as_ref),
+                last_key.as_ref().map(AsRef::as_ref),
+                limit,
+            )
+            .await?;
+        Ok(Some(proof))
+    }
+
+    async fn estimate_range<K: KeyType>(
+        &self,
+        first_key: Option<K>,
+        end_key: Option<K>,
+    ) -> Result<api::RangeEstimate, api::Error> {
+        let merkle = Merkle::from(self);
+        merkle
+            .estimate_range(
+                first_key.as_ref().map(AsRef::as_ref),
+                end_key.as_ref().map(AsRef::as_ref),
+            )
+            .map_err(api::Error::from)
     }
 
     fn iter_option<K: KeyType>(
         &self,
-        _first_key: Option<K>,
+        first_key: Option<K>,
     ) -> Result<Self::Stream<'_>, api::Error> {
-        todo!()
+        let stream = match first_key {
+            Some(key) => MerkleKeyValueStream::from_key(self, key),
+            None => MerkleKeyValueStream::from(self),
+        };
+        Ok(stream.hiding(self.hidden_prefix()))
+    }
+
+    fn iter_prefix<K: KeyType>(&self, prefix: K) -> Result<Self::Stream<'_>, api::Error> {
+        Ok(MerkleKeyValueStream::from_prefix(self, prefix).hiding(self.hidden_prefix()))
+    }
+
+    fn iter_range<K: KeyType>(&self, start: K, end: K) -> Result<Self::Stream<'_>, api::Error> {
+        Ok(MerkleKeyValueStream::from_range(self, start, end).hiding(self.hidden_prefix()))
     }
 }
 
@@ -112,15 +287,141 @@ pub struct DbConfig {
     /// Revision manager configuration.
     #[builder(default = RevisionManagerConfig::builder().build())]
     pub manager: RevisionManagerConfig,
+    /// The prefix of keys reserved for firewood itself, at most
+    /// [storage::MAX_RESERVED_PREFIX_LEN] bytes. It is recorded in the header
+    /// when the database is created, and the recorded prefix is used from
+    /// then on.
+    #[builder(default = DEFAULT_SYSTEM_PREFIX.into())]
+    pub system_prefix: Box<[u8]>,
+    /// Whether keys under the system prefix are visible to users
+    #[builder(default)]
+    pub system_keys: SystemKeys,
+    /// Let something outside firewood, such as consensus, decide which
+    /// revision is canonical. Commits then make a revision durable without
+    /// moving the root in the header, and [Db::promote] moves it. On reopen,
+    /// the database is at the promoted revision, and the others are listed
+    /// by [Db::unpromoted_revisions].
+    #[builder(default = false)]
+    pub external_root_authority: bool,
+    /// Keep the batch each retained revision was proposed with, exactly as
+    /// it was passed in, for [Db::op_journal]. Journals are stored
+    /// compressed in a `.journal` directory next to the database file, are
+    /// not part of the root hash, and are removed when their revision is
+    /// reaped.
+    #[builder(default = false)]
+    pub retain_op_journal: bool,
+    /// The number of commits a [ConsistencyToken] is guaranteed to stay
+    /// valid for after it is taken. Enough revisions are retained for this,
+    /// even if [RevisionManagerConfig] asks for fewer.
+    #[builder(default = 0)]
+    pub min_token_validity: usize,
+    /// Invariants checked on each proposal as it is committed, in order,
+    /// before anything is written. See [crate::invariant].
+    #[builder(default, setter(transform = |invariants: Vec<Box<dyn CommitInvariant>>| {
+        invariants.into_iter().map(Arc::from).collect()
+    }))]
+    pub invariants: Vec<Arc<dyn CommitInvariant>>,
+    /// Decides when a put to a key that has a value leaves it unchanged, in
+    /// which case the put is dropped and the old value is kept. See
+    /// [crate::equivalence].
+    #[builder(
+        default = Arc::new(ByteEquality),
+        setter(transform = |equivalence: Box<dyn ValueEquivalence>| Arc::from(equivalence)),
+    )]
+    pub value_equivalence: Arc<dyn ValueEquivalence>,
+    /// The number of bytes of old and new values [DbConfig::value_equivalence]
+    /// may compare for each proposal. Puts past it are written.
+    #[builder(default = 64 << 20)]
+    pub equivalence_budget: usize,
+    /// Open an existing file only to read it, while another process may be
+    /// writing to it. Nothing is written to the file: proposing fails with
+    /// [api::Error::ReadOnly], and [Db::reload] picks up what the writer
+    /// committed. A shared lock is held on the file, so the writer can't
+    /// truncate it meanwhile. It can't be combined with
+    /// [DbConfig::truncate], and op journals, snapshots and unpromoted
+    /// revisions are left to the writer.
+    #[builder(default = false)]
+    pub read_only: bool,
+}
+
+/// What [Db::drain_prefix] should do after handing an entry to its callback
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DrainDecision {
+    /// Delete this entry and move on to the next one
+    DeleteAndContinue,
+    /// Leave this entry in place and move on to the next one
+    KeepAndContinue,
+    /// Delete this entry and stop draining
+    DeleteAndStop,
+    /// Leave this entry in place and stop draining
+    KeepAndStop,
+}
+
+/// How many entries [Db::execute_restore] copies in each proposal
+const RESTORE_BATCH: usize = 10_000;
+
+/// How many entries [Db::compact_with_history] copies into each revision
+/// it builds the oldest kept revision from
+const COMPACTION_BATCH: usize = RESTORE_BATCH;
+
+/// How many of the keys each kept revision changed [Db::compact_with_history]
+/// reads back from both files to compare
+const COMPACTION_SAMPLES: usize = 64;
+
+/// What [Db::compact_with_history] did
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CompactionStats {
+    /// The number of revisions kept
+    pub revisions: usize,
+    /// The size of the file before compaction, in bytes
+    pub size_before: u64,
+    /// The size of the new file, in bytes
+    pub size_after: u64,
 }
 
+impl CompactionStats {
+    /// The number of bytes compaction gave back to the file system, or 0 if
+    /// the new file isn't smaller
+    pub const fn reclaimed(&self) -> u64 {
+        self.size_before.saturating_sub(self.size_after)
+    }
+}
+
+/// A put, or a delete if the value is None
+type Change = (Key, Option<Value>);
+
+/// How many times [Db::drain_prefix] retries its commit when other
+/// commits keep landing first
+const DRAIN_COMMIT_ATTEMPTS: usize = 8;
+
 #[derive(Debug)]
 /// A database instance.
 pub struct Db {
     metrics: Arc<DbMetrics>,
     // TODO: consider using https://docs.rs/lock_api/latest/lock_api/struct.RwLock.html#method.upgradable_read
     // TODO: This should probably use an async RwLock
-    manager: RwLock<RevisionManager>,
+    manager: Arc<RwLock<RevisionManager>>,
+    operations: Arc<OperationRegistry>,
+    retain_op_journal: bool,
+    invariants: Vec<Arc<dyn CommitInvariant>>,
+    value_equivalence: Arc<dyn ValueEquivalence>,
+    equivalence_budget: usize,
+    path: PathBuf,
+    /// The config it was opened with
+    config: DbConfig,
+    /// Set when a commit failed partway through
+    poisoned: AtomicBool,
+    /// Set by [Db::close], while the manager is held for writing
+    closed: AtomicBool,
+    /// The sync from proofs, if one is in progress
+    sync: SyncTracker,
+    /// The task syncing the file with [DurabilityMode::Buffered], if there
+    /// is one. It is never read; dropping it stops the task.
+    #[allow(dead_code)]
+    syncer: Option<AbortOnDrop>,
+    /// Set when it was opened with [Db::open_shared]. It must be the last
+    /// field, to leave the registry after everything else is dropped.
+    registration: Option<registry::Registration>,
 }
 
 #[async_trait]
@@ -136,15 +437,18 @@ where
         Self: 'p;
 
     async fn revision(&self, root_hash: TrieHash) -> Result<Arc<Self::Historical>, api::Error> {
+        self.check_open()?;
         let nodestore = self.manager.read().await.revision(root_hash)?;
         Ok(nodestore)
     }
 
     async fn root_hash(&self) -> Result<Option<TrieHash>, api::Error> {
-        Ok(self.manager.read().await.root_hash()?)
+        self.check_open()?;
+        Ok(self.manager.read().await.root_hash())
     }
 
     async fn all_hashes(&self) -> Result<Vec<TrieHash>, api::Error> {
+        self.check_open()?;
         Ok(self.manager.read().await.all_hashes())
     }
 
@@ -157,59 +461,345 @@ where
         Self: 'p,
     {
         let parent = self.manager.read().await.current_revision();
+        self.propose_on(parent, batch, BatchOpHint::Detect, None)
+            .await
+    }
+}
+
+impl Db {
+    /// Create a proposal on top of a specific committed revision
+    async fn propose_on<K: KeyType, V: ValueType>(
+        &self,
+        parent: CommittedRevision,
+        batch: api::Batch<K, V>,
+        hint: BatchOpHint,
+        system: Option<SystemStore>,
+    ) -> Result<Arc<Proposal<'_>>, api::Error> {
+        self.check_writable()?;
+        let timer = OperationTimer::start(ApiMethod::Propose);
         let proposal = NodeStore::new(parent)?;
         let mut merkle = Merkle::from(proposal);
+        let journal = self.retain_op_journal.then(|| journal::encode(&batch));
         let span = fastrace::Span::enter_with_local_parent("merkleops");
-        for op in batch {
-            match op {
-                BatchOp::Put { key, value } => {
-                    merkle.insert(key.as_ref(), value.as_ref().into())?;
-                }
-                BatchOp::Delete { key } => {
-                    merkle.remove(key.as_ref())?;
-                }
-            }
+        let before = self.values_before(&merkle, &batch).await?;
+        let mut rewrites = self.rewrite_filter();
+        apply_batch(&mut merkle, batch, hint, &mut rewrites)?;
+        let changes = ChangeSet::new(&merkle, before)?;
+        if let Some(system) = system {
+            apply_system_batch(&mut merkle, system.into_batch())?;
         }
 
         drop(span);
+        let proposal = self
+            .add_proposal(merkle, journal, changes, rewrites.stats())
+            .await?;
+        timer.finish(None, || proposal.nodestore.kind.root_hash());
+        Ok(proposal)
+    }
+
+    /// Fails with [api::Error::ReadOnly] if it was opened read-only, or
+    /// with [api::Error::Closed] if it was closed
+    fn check_writable(&self) -> Result<(), api::Error> {
+        match self.config.read_only {
+            true => Err(api::Error::ReadOnly),
+            false => self.check_open(),
+        }
+    }
+
+    /// Fails with [api::Error::Closed] if it was closed
+    fn check_open(&self) -> Result<(), api::Error> {
+        match self.closed.load(atomic::Ordering::Acquire) {
+            true => Err(api::Error::Closed),
+            false => Ok(()),
+        }
+    }
+
+    /// The values in `merkle` of the keys `batch` may change, for the change
+    /// set of the proposal, or nothing when there are no invariants to
+    /// check
+    async fn values_before<K: KeyType, V: ValueType>(
+        &self,
+        merkle: &Merkle<NodeStore<MutableProposal, FileBacked>>,
+        batch: &[BatchOp<K, V>],
+    ) -> Result<BTreeMap<Key, Option<Box<[u8]>>>, api::Error> {
+        if self.invariants.is_empty() {
+            return Ok(BTreeMap::new());
+        }
+        ChangeSet::values_before(merkle, batch).await
+    }
+
+    /// Compares the puts of a new proposal with the values they replace
+    fn rewrite_filter(&self) -> RewriteFilter<'_> {
+        RewriteFilter::new(&*self.value_equivalence, self.equivalence_budget)
+    }
+
+    /// Freeze `merkle` and track it as a proposal on this database. Fails
+    /// with [api::Error::FreeListCorruption] if allocating its nodes finds
+    /// a corrupt free list.
+    async fn add_proposal(
+        &self,
+        merkle: Merkle<NodeStore<MutableProposal, FileBacked>>,
+        journal: Option<Box<[u8]>>,
+        changes: ChangeSet,
+        rewrites: RewriteStats,
+    ) -> Result<Arc<Proposal<'_>>, api::Error> {
         let span = fastrace::Span::enter_with_local_parent("freeze");
 
         let nodestore = merkle.into_inner();
         let immutable: Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>> =
-            Arc::new(nodestore.into());
+            Arc::new(NodeStore::try_from_mutable(nodestore)?);
 
         drop(span);
         self.manager.write().await.add_proposal(immutable.clone());
 
-        self.metrics.proposals.increment(1);
+        counter!("firewood.proposals").increment(1);
 
-        Ok(Self::Proposal {
+        Ok(Proposal {
             nodestore: immutable,
             db: self,
+            staged: Arc::new(Staged {
+                journal,
+                changes,
+                parent: None,
+            }),
+            rewrites,
         }
         .into())
     }
-}
 
-impl Db {
-    /// Create a new database instance.
-    pub async fn new<P: AsRef<Path>>(db_path: P, cfg: DbConfig) -> Result<Self, api::Error> {
-        let metrics = Arc::new(DbMetrics {
-            proposals: counter!("firewood.proposals"),
-        });
-        describe_counter!("firewood.proposals", "Number of proposals created");
-        let manager = RevisionManager::new(
-            db_path.as_ref().to_path_buf(),
-            cfg.truncate,
-            cfg.manager.clone(),
-        )?;
+    /// Commit `proposal` along with the proposals under it that aren't
+    /// committed yet, bottom first, rather than one at a time. The
+    /// invariants of all of them are checked before anything is written,
+    /// and nothing is committed if the bottom one wasn't made on the most
+    /// recent commit, which fails with [api::Error::NotLatest].
+    ///
+    /// Proposals made on one in the chain that aren't part of it can't be
+    /// committed afterwards, and fail with [api::Error::SiblingCommitted].
+    pub async fn commit_chain(
+        &self,
+        proposal: Arc<Proposal<'_>>,
+    ) -> Result<Option<TrieHash>, api::Error> {
+        let Some(proposal) = Arc::into_inner(proposal) else {
+            return Err(api::Error::CannotCommitClonedProposal);
+        };
+        let timer = OperationTimer::start(ApiMethod::Commit);
+        let chain = self
+            .manager
+            .read()
+            .await
+            .proposal_chain(&proposal.nodestore)?;
+        // top first, as the staged parts are linked
+        let staged = successors(Some(&proposal.staged), |staged| staged.parent.as_ref());
+        let chain: Vec<_> = chain.into_iter().rev().zip(staged).collect();
+        for (nodestore, staged) in chain.iter().rev() {
+            invariant::check_all(&self.invariants, nodestore, &staged.changes)?;
+        }
+
+        let mut manager = self.manager.write().await;
+        self.check_open()?;
+        // the bottom of the chain may have been committed since it was read
+        let uncommitted = manager.proposal_chain(&proposal.nodestore)?.len();
+        let mut last = None;
+        for (nodestore, staged) in chain.into_iter().take(uncommitted).rev() {
+            let pending = manager
+                .begin_commit(nodestore, staged.journal.as_deref())
+                .inspect_err(|err| self.poison_on(err))?;
+            if let Err(err) = pending.flush_nodes() {
+                manager.fail_pending();
+                self.poison_on(&err);
+                return Err(err.into());
+            }
+            last = Some(pending);
+        }
+        manager
+            .publish_flushed()
+            .inspect_err(|err| self.poison_on(err))?;
+        let progress = manager.commit_progress();
+        drop(manager);
+
+        // a commit started before the chain may still be flushing
+        let root_hash = match last {
+            Some(pending) => {
+                pending.published(progress).await?;
+                pending.root_hash()
+            }
+            None => None,
+        };
+        timer.finish(None, || root_hash.clone());
+        Ok(root_hash)
+    }
+
+    /// Flush the nodes of a commit started with
+    /// [RevisionManager::begin_commit], without holding the manager so that
+    /// the next commit can start meanwhile, and wait until it is published
+    async fn finish_commit(&self, pending: &PendingCommit) -> Result<(), api::Error> {
+        let flushed = pending.flush_nodes();
+        let progress = {
+            let mut manager = self.manager.write().await;
+            match flushed {
+                Ok(()) => manager.publish_flushed(),
+                Err(err) => {
+                    manager.fail_pending();
+                    Err(err)
+                }
+            }
+            .inspect_err(|err| self.poison_on(err))?;
+            manager.commit_progress()
+        };
+        Ok(pending.published(progress).await?)
+    }
+
+    /// Create a new database instance. It isn't shared with
+    /// [Db::open_shared], and nothing stops another instance of the same
+    /// file being created alongside it.
+    pub async fn new<P: AsRef<FilePath>>(db_path: P, cfg: DbConfig) -> Result<Self, api::Error> {
+        let metrics = Arc::new(DbMetrics {});
+        let config = cfg.clone();
+        crate::metrics::describe_all();
+        let manager_config = cfg
+            .manager
+            .clone()
+            .retain_at_least(cfg.min_token_validity.saturating_add(1));
+        let manager = match cfg.read_only {
+            true if cfg.truncate => {
+                return Err(api::Error::IO(std::io::Error::new(
+                    std::io::ErrorKind::InvalidInput,
+                    "a database opened read-only can't be truncated",
+                )))
+            }
+            true => RevisionManager::open_read_only(
+                db_path.as_ref().to_path_buf(),
+                manager_config,
+                &cfg.system_prefix,
+                cfg.system_keys,
+            )?,
+            false => RevisionManager::new(
+                db_path.as_ref().to_path_buf(),
+                cfg.truncate,
+                manager_config,
+                &cfg.system_prefix,
+                cfg.system_keys,
+                cfg.external_root_authority,
+                cfg.retain_op_journal,
+            )?,
+        };
+        let manager = Arc::new(RwLock::new(manager));
+        let syncer = match cfg.manager.durability() {
+            DurabilityMode::Buffered { interval, .. } if !cfg.read_only => {
+                tokio::runtime::Handle::try_current().ok().map(|runtime| {
+                    let task = runtime.spawn(sync_periodically(Arc::downgrade(&manager), interval));
+                    AbortOnDrop(task.abort_handle())
+                })
+            }
+            _ => None,
+        };
         let db = Self {
             metrics,
-            manager: manager.into(),
+            manager,
+            operations: Default::default(),
+            retain_op_journal: cfg.retain_op_journal,
+            invariants: cfg.invariants,
+            value_equivalence: cfg.value_equivalence,
+            equivalence_budget: cfg.equivalence_budget,
+            path: db_path.as_ref().to_path_buf(),
+            config,
+            poisoned: AtomicBool::new(false),
+            closed: AtomicBool::new(false),
+            sync: Default::default(),
+            syncer,
+            registration: None,
         };
         Ok(db)
     }
 
+    /// Open the database at `db_path`, or return the instance of it this
+    /// process already opened this way. A file is the same whichever path
+    /// it is reached by, including through symbolic or hard links.
+    ///
+    /// An existing instance is only returned if it was opened with the same
+    /// config, and otherwise this fails with
+    /// [api::Error::IncompatibleConfig]. [DbConfig::truncate] only applies
+    /// when the file is opened, not when an open instance is shared. It fails
+    /// with [api::Error::Poisoned] if a commit on it failed partway through,
+    /// and with [api::Error::Closed] if it was closed.
+    /// The instance is closed when the last handle to it is dropped, and a
+    /// call that races with that waits for it to close before opening the
+    /// file again.
+    pub async fn open_shared<P: AsRef<FilePath>>(
+        db_path: P,
+        cfg: DbConfig,
+    ) -> Result<Arc<Self>, api::Error> {
+        let file = registry::FileId::of(db_path.as_ref())?;
+        loop {
+            let opening = match registry::claim(&file) {
+                registry::Claim::Open(db) => {
+                    if db.is_poisoned() {
+                        return Err(api::Error::Poisoned);
+                    }
+                    db.check_open()?;
+                    if let Some(err) = registry::incompatibility(&db.config, &cfg) {
+                        return Err(err);
+                    }
+                    return Ok(db);
+                }
+                registry::Claim::Wait(changed) => {
+                    changed.await;
+                    continue;
+                }
+                registry::Claim::Opening(opening) => opening,
+            };
+            let mut db = Self::new(db_path.as_ref(), cfg).await?;
+            db.registration = Some(opening.registration());
+            let db = Arc::new(db);
+            opening.opened(&db);
+            return Ok(db);
+        }
+    }
+
+    /// Whether a commit failed partway through, which may have left this
+    /// instance inconsistent with the file
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Close the database: wait for the commits in flight to be published,
+    /// write the header of the latest revision, sync the file to disk and
+    /// release the lock on it, returning any error that dropping it would
+    /// have ignored.
+    ///
+    /// Afterwards, the methods of this instance that can fail, including
+    /// those of the other handles to it from [Db::open_shared], fail with
+    /// [api::Error::Closed], as does closing it again. The file stays open
+    /// until the instance is dropped, and a shared instance is not handed
+    /// out again until then.
+    pub async fn close(&self) -> Result<(), api::Error> {
+        let settled = {
+            let manager = self.manager.write().await;
+            self.check_open()?;
+            // commits start while the manager is held, so none start after this
+            self.closed.store(true, atomic::Ordering::Release);
+            manager.settled()
+        };
+        settled.await;
+        Ok(self.manager.write().await.close()?)
+    }
+
+    /// Sync the database file to disk, so that every commit published so
+    /// far is durable whatever [DurabilityMode] it was opened with. Commits
+    /// whose nodes are still being flushed are not waited for.
+    pub async fn sync(&self) -> Result<(), api::Error> {
+        self.check_open()?;
+        Ok(self.manager.write().await.sync()?)
+    }
+
+    /// Poison this instance if `err` came from a commit that failed after
+    /// it started writing
+    fn poison_on(&self, err: &RevisionManagerError) {
+        if matches!(err, RevisionManagerError::IO(_)) {
+            self.poisoned.store(true, atomic::Ordering::Relaxed);
+        }
+    }
+
     /// Dump the Trie of the latest revision.
     pub async fn dump(&self, w: &mut dyn Write) -> Result<(), DbError> {
         let latest_rev_nodestore = self.manager.read().await.current_revision();
@@ -223,178 +813,5737 @@ impl Db {
     pub fn metrics(&self) -> Arc<DbMetrics> {
         self.metrics.clone()
     }
-}
 
-#[derive(Debug)]
-/// A user-visible database proposal
-pub struct Proposal<'p> {
-    nodestore: Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>>,
-    db: &'p Db,
-}
+    /// Make the retained revision with `root_hash` the one the database
+    /// reopens at. Requires [DbConfig::external_root_authority].
+    ///
+    /// This is a single header write: after a crash, the database reopens
+    /// either at the previously promoted revision, with this one still
+    /// unpromoted, or at this one. The promoted revision doesn't have to be
+    /// the newest; newer revisions stay unpromoted, and older ones can be
+    /// reaped.
+    ///
+    /// Unpromoted revisions from before the database was opened can only be
+    /// promoted until something new is committed. Promoting a revision
+    /// committed since then discards them.
+    pub async fn promote(&self, root_hash: TrieHash) -> Result<(), api::Error> {
+        self.check_open()?;
+        Ok(self.manager.write().await.promote(root_hash)?)
+    }
 
-#[async_trait]
-impl api::DbView for Proposal<'_> {
-    type Stream<'b>
-        = MerkleKeyValueStream<'b, NodeStore<Arc<ImmutableProposal>, FileBacked>>
-    where
-        Self: 'b;
+    /// Pick up the revision another process committed to the file since it
+    /// was opened or last reloaded, if it was opened with
+    /// [DbConfig::read_only], and return the root hash of the latest
+    /// revision. The reloaded revision becomes the latest one, and older
+    /// ones are dropped beyond [RevisionManagerConfig]'s retention. Only the
+    /// newest revision of the writer is picked up, not each commit it made.
+    ///
+    /// The writer reuses the space of the revisions it reaps, so a retained
+    /// revision can only be read as long as the writer retains it too.
+    /// Reading one it has reaped returns errors or wrong data.
+    pub async fn reload(&self) -> Result<Option<TrieHash>, api::Error> {
+        self.check_open()?;
+        let mut manager = self.manager.write().await;
+        manager.reload()?;
+        Ok(manager.root_hash())
+    }
 
-    async fn root_hash(&self) -> Result<Option<api::HashKey>, api::Error> {
-        self.nodestore.root_hash().map_err(api::Error::from)
+    /// Keep the revision with `root_hash` from being reaped while the
+    /// returned guard is held, however many commits come after it.
+    /// Revisions are reaped oldest first, so the ones committed after it are
+    /// kept as well. Pins are counted, so the revision can be reaped again from the
+    /// next commit after the last guard for it is dropped. Compaction still
+    /// drops pinned revisions older than the ones it keeps.
+    pub async fn pin(&self, root_hash: TrieHash) -> Result<PinGuard, api::Error> {
+        self.check_open()?;
+        Ok(self.manager.read().await.pin(root_hash)?)
     }
 
-    async fn val<K: KeyType>(&self, key: K) -> Result<Option<Box<[u8]>>, api::Error> {
-        let merkle = Merkle::from(self.nodestore.clone());
-        merkle.get_value(key.as_ref()).map_err(api::Error::from)
+    /// The retained revision with `root_hash`, kept from being reaped for as
+    /// long as the returned snapshot is held, like with [Db::pin]
+    pub async fn snapshot(&self, root_hash: TrieHash) -> Result<Snapshot, api::Error> {
+        self.check_open()?;
+        let manager = self.manager.read().await;
+        let pin = manager.pin(root_hash.clone())?;
+        let revision = manager.revision(root_hash)?;
+        Ok(Snapshot { revision, pin })
     }
 
-    async fn single_key_proof<K: KeyType>(&self, key: K) -> Result<Proof<ProofNode>, api::Error> {
-        let merkle = Merkle::from(self.nodestore.clone());
-        merkle.prove(key.as_ref()).map_err(api::Error::from)
+    /// The committed revision `back` commits before the most recent one,
+    /// which is 0 back, without looking up its root hash first. Fails with
+    /// [api::Error::RevisionOutOfRange] if that revision is no longer
+    /// retained.
+    pub async fn revision_by_offset(&self, back: usize) -> Result<Arc<HistoricalRev>, api::Error> {
+        self.check_open()?;
+        Ok(self.manager.read().await.revision_by_offset(back)?)
     }
 
-    async fn range_proof<K: KeyType, V>(
-        &self,
-        _first_key: Option<K>,
-        _last_key: Option<K>,
-        _limit: Option<usize>,
-    ) -> Result<Option<api::RangeProof<Box<[u8]>, Box<[u8]>, ProofNode>>, api::Error> {
-        todo!()
+    /// Hear about every revision that becomes the latest from now on,
+    /// including those [Db::reload] picks up, in the order they do. An event
+    /// is sent as soon as the revision can be read, which may be before the
+    /// file is synced.
+    ///
+    /// At most [crate::manager::COMMIT_EVENT_CAPACITY] events are kept for a
+    /// subscriber. One that falls further behind misses the oldest: its next
+    /// receive fails with [broadcast::error::RecvError::Lagged], saying how
+    /// many it missed, and it carries on from the oldest event kept.
+    pub async fn subscribe(&self) -> broadcast::Receiver<CommitEvent> {
+        self.manager.read().await.subscribe()
     }
 
-    fn iter_option<K: KeyType>(
-        &self,
-        _first_key: Option<K>,
-    ) -> Result<Self::Stream<'_>, api::Error> {
-        todo!()
+    /// The retained revision made by commit number `height`. Fails with
+    /// [api::Error::RevisionNotFound] if it was reaped, or hasn't been
+    /// committed yet.
+    pub async fn revision_by_height(&self, height: u64) -> Result<Arc<HistoricalRev>, api::Error> {
+        self.check_open()?;
+        Ok(self.manager.read().await.revision_by_height(height)?)
     }
-}
 
-#[async_trait]
-impl<'a> api::Proposal for Proposal<'a> {
-    type Proposal = Proposal<'a>;
+    /// The number of commits that led to the latest revision, which is
+    /// recorded in the file and carries on when it is reopened. Databases
+    /// created before heights were recorded start counting from zero.
+    pub async fn latest_height(&self) -> u64 {
+        self.manager.read().await.latest_height()
+    }
 
-    #[fastrace::trace(short_name = true)]
-    async fn propose<K: KeyType, V: ValueType>(
-        self: Arc<Self>,
-        batch: api::Batch<K, V>,
-    ) -> Result<Arc<Self::Proposal>, api::Error> {
-        let parent = self.nodestore.clone();
-        let proposal = NodeStore::new(parent)?;
-        let mut merkle = Merkle::from(proposal);
-        for op in batch {
-            match op {
-                BatchOp::Put { key, value } => {
-                    merkle.insert(key.as_ref(), value.as_ref().into())?;
-                }
-                BatchOp::Delete { key } => {
-                    merkle.remove(key.as_ref())?;
-                }
-            }
-        }
-        let nodestore = merkle.into_inner();
-        let immutable: Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>> =
-            Arc::new(nodestore.into());
-        self.db
+    /// The heights and root hashes of the retained committed revisions,
+    /// oldest first. Unlike [api::Db::all_hashes], proposals aren't
+    /// included.
+    pub async fn all_revisions(&self) -> Vec<(u64, TrieHash)> {
+        self.manager.read().await.all_revisions()
+    }
+
+    /// The number of committed revisions retained in memory. Compared with
+    /// [RevisionManagerConfig]'s `max_revisions`, it shows how full the
+    /// revision window is.
+    pub async fn revision_count(&self) -> usize {
+        self.manager.read().await.revision_count()
+    }
+
+    /// Change [RevisionManagerConfig]'s `max_revisions` without reopening
+    /// the database. Growing it lets more revisions accumulate from the
+    /// next commit on. Shrinking it reaps the oldest revisions beyond the
+    /// new limit at once, unless something still holds them, and the next
+    /// commit frees their space. Fails with [api::Error::RevisionPinned],
+    /// changing nothing, if one of those revisions is pinned.
+    pub async fn set_max_revisions(&self, max_revisions: usize) -> Result<(), api::Error> {
+        self.check_open()?;
+        Ok(self
             .manager
             .write()
             .await
-            .add_proposal(immutable.clone());
-
-        Ok(Self::Proposal {
-            nodestore: immutable,
-            db: self.db,
-        }
-        .into())
+            .set_max_revisions(max_revisions)?)
     }
 
-    async fn commit(self: Arc<Self>) -> Result<(), api::Error> {
-        match Arc::into_inner(self) {
-            Some(proposal) => {
-                let mut manager = proposal.db.manager.write().await;
-                Ok(manager.commit(proposal.nodestore.clone())?)
+    /// The size of the database and how its space is used. The sizes come
+    /// from the header and are returned at once. With `deep`, the free
+    /// lists and the trie of the latest revision are walked as well, which
+    /// reads every free area and every node of that revision. Commits wait
+    /// while the free lists are walked, but not while the trie is.
+    pub async fn statistics(&self, deep: bool) -> Result<DbStatistics, api::Error> {
+        self.check_open()?;
+        let (mut stats, latest, _pin) = {
+            let manager = self.manager.read().await;
+            let latest = manager.current_revision();
+            let mut stats = DbStatistics {
+                file_size: latest.store_size(),
+                allocated_bytes: latest.allocated_bytes(),
+                free_bytes: latest.free_bytes(),
+                revisions: manager.revision_count(),
+                ..Default::default()
+            };
+            if !deep {
+                return Ok(stats);
             }
-            None => Err(api::Error::CannotCommitClonedProposal),
-        }
+            stats.free_lists = Some(statistics::free_lists(&latest)?);
+            // keep the nodes being walked from being reaped and reused
+            let pin = match latest.kind.root_hash() {
+                Some(root_hash) => Some(manager.pin(root_hash)?),
+                None => None,
+            };
+            (stats, latest, pin)
+        };
+        stats.latest = Some(statistics::trie(&latest)?);
+        Ok(stats)
     }
-}
-#[cfg(test)]
-#[allow(clippy::unwrap_used)]
-mod test {
-    use std::ops::{Deref, DerefMut};
-    use std::path::PathBuf;
 
-    use crate::db::Db;
-    use crate::v2::api::{Db as _, DbView as _, Error, Proposal as _};
+    /// The number of proposals the database tracks, including abandoned
+    /// ones that haven't been pruned yet
+    pub async fn proposal_count(&self) -> usize {
+        self.manager.read().await.proposal_count()
+    }
 
-    use super::{BatchOp, DbConfig};
+    /// The root hash of the oldest retained revision, the next to be reaped
+    /// once the revision window is full, or None if that revision is empty
+    pub async fn oldest_revision_hash(&self) -> Option<TrieHash> {
+        self.manager.read().await.oldest_hash()
+    }
 
-    #[tokio::test]
-    async fn test_cloned_proposal_error() {
-        let db = testdb().await;
-        let proposal = db
-            .propose::<Vec<u8>, Vec<u8>>(Default::default())
-            .await
-            .unwrap();
-        let cloned = proposal.clone();
+    /// The root hashes of revisions that are durable but were never
+    /// promoted, including ones committed before the database was opened.
+    /// They can be read with [api::Db::revision].
+    pub async fn unpromoted_revisions(&self) -> Vec<TrieHash> {
+        self.manager.read().await.unpromoted_hashes()
+    }
 
-        // attempt to commit the clone; this should fail
-        let result = cloned.commit().await;
-        assert!(
-            matches!(result, Err(Error::CannotCommitClonedProposal)),
-            "{result:?}"
-        );
+    /// Forget an unpromoted revision that was committed before the database
+    /// was opened. Its space is not reclaimed.
+    pub async fn discard_unpromoted(&self, root_hash: TrieHash) -> Result<(), api::Error> {
+        Ok(self.manager.write().await.discard_unpromoted(root_hash)?)
+    }
 
-        // the prior attempt consumed the Arc though, so cloned is no longer valid
-        // that means the actual proposal can be committed
-        let result = proposal.commit().await;
-        assert!(matches!(result, Ok(())), "{result:?}");
+    /// Read the value of `key` in each of the `max_revisions` most recent
+    /// revisions, newest first, with the root hash of each. Revisions with
+    /// an empty trie have no root hash, and no value.
+    ///
+    /// Adjacent revisions usually share most of the path to a key, so rather
+    /// than looking the key up in every revision, the lookup in each older
+    /// revision stops as soon as it reaches a node that is identical to one
+    /// on the path in a newer revision, and the newer value is reused. The
+    /// cost is then proportional to the number of revisions in which the
+    /// value actually changed.
+    pub async fn key_history<K: KeyType>(
+        &self,
+        key: K,
+        max_revisions: usize,
+    ) -> Result<Vec<(Option<TrieHash>, Option<Box<[u8]>>)>, api::Error> {
+        Ok(self.key_history_with_lookups(key, max_revisions).await?.0)
     }
 
-    #[tokio::test]
-    async fn test_proposal_reads() {
-        let db = testdb().await;
-        let batch = vec![BatchOp::Put {
-            key: b"k",
-            value: b"v",
-        }];
-        let proposal = db.propose(batch).await.unwrap();
-        assert_eq!(&*proposal.val(b"k").await.unwrap().unwrap(), b"v");
+    /// [Db::key_history], also returning how many revisions needed a full lookup
+    async fn key_history_with_lookups<K: KeyType>(
+        &self,
+        key: K,
+        max_revisions: usize,
+    ) -> Result<(Vec<(Option<TrieHash>, Option<Box<[u8]>>)>, usize), api::Error> {
+        let revisions = self.manager.read().await.newest_revisions(max_revisions);
+        let key = Path::from_nibbles_iterator(NibblesIterator::new(key.as_ref()));
 
-        assert_eq!(proposal.val(b"notfound").await.unwrap(), None);
-        proposal.commit().await.unwrap();
+        let mut history = Vec::with_capacity(revisions.len());
+        let mut lookups = 0;
+        let mut known = Vec::new();
+        let mut value = None;
+        for revision in revisions {
+            let Some(root_hash) = revision.kind.root_hash() else {
+                // an empty trie shares no path with the next revision
+                known = Vec::new();
+                value = None;
+                history.push((None, None));
+                continue;
+            };
+            let merkle = Merkle::from(&revision);
+            known = match merkle.lookup_unless_unchanged(&key, root_hash.clone(), &known)? {
+                KeyLookup::Unchanged(path) => path,
+                KeyLookup::Changed {
+                    value: new_value,
+                    path,
+                } => {
+                    lookups += 1;
+                    value = new_value;
+                    path
+                }
+            };
+            history.push((Some(root_hash), value.clone()));
+        }
 
-        let batch = vec![BatchOp::Put {
-            key: b"k",
-            value: b"v2",
-        }];
-        let proposal = db.propose(batch).await.unwrap();
-        assert_eq!(&*proposal.val(b"k").await.unwrap().unwrap(), b"v2");
+        Ok((history, lookups))
+    }
 
-        let committed = db.root_hash().await.unwrap().unwrap();
-        let historical = db.revision(committed).await.unwrap();
-        assert_eq!(&*historical.val(b"k").await.unwrap().unwrap(), b"v");
+    /// The changes that turn the retained revision with root hash `older`
+    /// into the one with `newer`, in key order. Only the parts of the tries
+    /// that differ are read, so this costs in proportion to the number of
+    /// changes rather than to the size of the database. Changes to system
+    /// keys are left out when they are hidden.
+    pub async fn diff(
+        &self,
+        older: TrieHash,
+        newer: TrieHash,
+    ) -> Result<Vec<KeyChange>, api::Error> {
+        self.check_open()?;
+        let (older, newer) = {
+            let manager = self.manager.read().await;
+            (manager.revision(older)?, manager.revision(newer)?)
+        };
+        let mut changes = Merkle::from(&older).diff(&Merkle::from(&newer))?;
+        changes.retain(|change| !newer.is_hidden(change.key()));
+        Ok(changes)
     }
 
-    #[tokio::test]
-    async fn reopen_test() {
-        let db = testdb().await;
-        let batch = vec![BatchOp::Put {
+    /// Check the database file for corruption: every node reachable from
+    /// the retained revisions, or only the latest one with
+    /// [VerifyOptions::latest_only], is read from disk and checked against
+    /// the hash its parent records for it, and none of them may be on a free
+    /// list. Problems are collected in the report rather than returned as
+    /// errors. Commits wait until the check is done.
+    pub async fn verify(&self, options: VerifyOptions) -> Result<VerifyReport, api::Error> {
+        self.check_open()?;
+        let manager = self.manager.read().await;
+        let max = match options.latest_only {
+            true => 1,
+            false => usize::MAX,
+        };
+        Ok(verify::verify(&manager.newest_revisions(max), options)?)
+    }
+
+    /// Write the retained revision with `root_hash` to `writer` as a stream
+    /// that [Db::import] builds a new database from, returning the number
+    /// of nodes written. Only that revision's trie is written, so it is
+    /// much smaller than the file. The revision is pinned while it is
+    /// written, and commits carry on meanwhile. See [crate::export] for
+    /// the encoding.
+    pub async fn export<W: AsyncWrite + Unpin>(
+        &self,
+        root_hash: TrieHash,
+        mut writer: W,
+    ) -> Result<u64, api::Error> {
+        let snapshot = self.snapshot(root_hash.clone()).await?;
+        let Some(root) = snapshot.root_address() else {
+            return Err(api::Error::HashNotFound {
+                provided: root_hash,
+            });
+        };
+        Ok(export::export(&*snapshot, root, &root_hash, &mut writer).await?)
+    }
+
+    /// Create a database at `db_path`, replacing anything there, whose only
+    /// revision is the one [Db::export] wrote to `reader`. The nodes are
+    /// written as they are read, without hashing them, and the stream is
+    /// rejected if its digest doesn't match or its nodes don't form a
+    /// trie. The root node is hashed when the database is opened, and with
+    /// `verify` every node is, as [Db::verify] does. On error, the file is
+    /// left behind incomplete.
+    pub async fn import<P: AsRef<FilePath>, R: AsyncRead + Unpin>(
+        db_path: P,
+        cfg: DbConfig,
+        reader: R,
+        verify: bool,
+    ) -> Result<Self, api::Error> {
+        // an empty database first, to clear the files next to it
+        let empty = Db::new(
+            &db_path,
+            DbConfig {
+                truncate: true,
+                read_only: false,
+                ..cfg.clone()
+            },
+        )
+        .await?;
+        empty.close().await?;
+        drop(empty);
+
+        let root_hash = {
+            let storage = Arc::new(FileBacked::new(
+                db_path.as_ref().to_path_buf(),
+                NonZeroUsize::MIN,
+                NonZeroUsize::MIN,
+                false,
+            )?);
+            let mut nodestore = NodeStore::open(storage.clone())?;
+            let root_hash = export::import(&mut nodestore, reader).await?;
+            nodestore.flush_header()?;
+            storage.sync()?;
+            root_hash
+        };
+
+        let db = Db::new(
+            db_path,
+            DbConfig {
+                truncate: false,
+                ..cfg
+            },
+        )
+        .await?;
+        if db.root_hash().await? != Some(root_hash.clone()) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("the imported root doesn't hash to {root_hash:?}"),
+            )
+            .into());
+        }
+        if verify {
+            let report = db
+                .verify(VerifyOptions {
+                    latest_only: true,
+                    ..Default::default()
+                })
+                .await?;
+            if let Some(err) = report.errors.first() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!("the imported revision is corrupt: {err}"),
+                )
+                .into());
+            }
+        }
+        Ok(db)
+    }
+
+    /// Write every entry of the retained revision with `root_hash` to a new
+    /// file at `path`, in key order, returning the number of entries. Fails
+    /// if `path` exists. The entries are streamed from the trie, which is
+    /// pinned while they are written. Unlike [Db::export], the file doesn't
+    /// depend on how nodes are stored; see [crate::portable] for the format.
+    pub async fn export_portable<P: AsRef<FilePath>>(
+        &self,
+        root_hash: TrieHash,
+        path: P,
+    ) -> Result<u64, api::Error> {
+        let snapshot = self.snapshot(root_hash.clone()).await?;
+        let system_keys = match snapshot.reserved_prefix() {
+            Some(prefix) if !snapshot.is_hidden(prefix) => SystemKeys::Reject,
+            _ => SystemKeys::Hidden,
+        };
+        let header = portable::Header {
+            root_hash,
+            reserved_prefix: snapshot.reserved_prefix().map(Into::into),
+            system_keys,
+        };
+        let mut writer = PortableWriter::create(path.as_ref(), &header)?;
+        let merkle = Merkle::from(&*snapshot);
+        let mut stream = merkle.key_value_iter();
+        while let Some((key, value)) = stream.next().await.transpose()? {
+            writer.write(&key, &value)?;
+        }
+        Ok(writer.finish()?)
+    }
+
+    /// Create a database at `db_path`, replacing anything there, whose
+    /// latest revision holds the entries [Db::export_portable] wrote to the
+    /// file at `path`. They are committed in batches, so the database has a
+    /// revision for each, and the latest must have the root hash the file
+    /// records; if it doesn't, the file is corrupt and the import fails,
+    /// leaving the database behind. The reserved key space comes from the
+    /// file rather than `cfg`.
+    pub async fn import_portable<P: AsRef<FilePath>, Q: AsRef<FilePath>>(
+        path: P,
+        db_path: Q,
+        cfg: DbConfig,
+    ) -> Result<Self, api::Error> {
+        let (reader, header) = PortableReader::open(path.as_ref())?;
+        let entries = futures::stream::iter(reader.map(|entry| entry.map_err(api::Error::from)));
+        let db = copy_entries(
+            entries,
+            header.reserved_prefix.as_deref(),
+            header.system_keys,
+            cfg,
+            db_path.as_ref(),
+        )
+        .await?;
+        let root_hash = db.root_hash().await?;
+        if root_hash.as_ref() != Some(&header.root_hash) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!(
+                    "the imported root hash {root_hash:?} doesn't match {:?}, so the snapshot is corrupt",
+                    header.root_hash
+                ),
+            )
+            .into());
+        }
+        Ok(db)
+    }
+
+    /// Consume entries under `prefix`, in key order, deleting the ones `f` asks for.
+    ///
+    /// At most `max_items` entries are passed to `f`. The entries all come from
+    /// the revision that was latest when the drain started, which stays pinned
+    /// until the drain is finished. The deletions are committed once, at the
+    /// end, so if the process stops before that, every entry is still there.
+    ///
+    /// If other revisions were committed in the meantime, the deletions are
+    /// applied on top of the newest one, provided none of the deleted entries
+    /// were changed; otherwise [api::Error::Conflict] is returned and nothing
+    /// is deleted.
+    ///
+    /// Returns the root hash after the drain and the number of entries deleted.
+    pub async fn drain_prefix<F>(
+        &self,
+        prefix: &[u8],
+        max_items: usize,
+        mut f: F,
+    ) -> Result<(Option<TrieHash>, usize), api::Error>
+    where
+        F: FnMut(&[u8], &[u8]) -> DrainDecision,
+    {
+        let snapshot = self.manager.read().await.current_revision();
+
+        let mut drained = Vec::new();
+        let merkle = Merkle::from(&snapshot);
+        let mut stream = merkle.key_value_iter_prefix(prefix);
+        let mut seen = 0;
+        while seen < max_items {
+            let Some((key, value)) = stream.next().await.transpose()? else {
+                break;
+            };
+            if snapshot.is_hidden(&key) {
+                continue;
+            }
+            seen += 1;
+
+            let decision = f(&key, &value);
+            if matches!(
+                decision,
+                DrainDecision::DeleteAndContinue | DrainDecision::DeleteAndStop
+            ) {
+                drained.push((key, value));
+            }
+            if matches!(
+                decision,
+                DrainDecision::DeleteAndStop | DrainDecision::KeepAndStop
+            ) {
+                break;
+            }
+        }
+        drop(stream);
+
+        if drained.is_empty() {
+            return Ok((snapshot.kind.root_hash(), 0));
+        }
+
+        for _ in 0..DRAIN_COMMIT_ATTEMPTS {
+            let latest = self.manager.read().await.current_revision();
+            if !Arc::ptr_eq(&latest, &snapshot) {
+                let merkle = Merkle::from(&latest);
+                for (key, value) in drained.iter() {
+                    if merkle.get_value(key)?.as_deref() != Some(value.as_slice()) {
+                        return Err(api::Error::Conflict { key: key.clone() });
+                    }
+                }
+            }
+
+            let batch: api::Batch<_, Box<[u8]>> = drained
+                .iter()
+                .map(|(key, _)| BatchOp::Delete { key: key.clone() })
+                .collect();
+            let proposal = self
+                .propose_on(latest, batch, BatchOpHint::Unordered, None)
+                .await?;
+            match api::Proposal::commit(proposal).await {
+                Ok(root_hash) => return Ok((root_hash, drained.len())),
+                // another commit landed first; check again against it
+                Err(api::Error::SiblingCommitted) => continue,
+                Err(err) => return Err(err),
+            }
+        }
+
+        Err(api::Error::NotLatest)
+    }
+
+    /// A [SystemStore] for building changes to the reserved key space
+    pub(crate) async fn system_store(&self) -> SystemStore {
+        let latest = self.manager.read().await.current_revision();
+        SystemStore::new(latest.reserved_prefix().unwrap_or_default())
+    }
+
+    /// Propose a user batch together with changes to the reserved key space,
+    /// so that both are committed atomically
+    pub(crate) async fn propose_with_system<K: KeyType, V: ValueType>(
+        &self,
+        batch: api::Batch<K, V>,
+        system: SystemStore,
+    ) -> Result<Arc<Proposal<'_>>, api::Error> {
+        let parent = self.manager.read().await.current_revision();
+        self.propose_on(parent, batch, BatchOpHint::Detect, Some(system))
+            .await
+    }
+
+    /// Create a proposal like [api::Db::propose], telling it what is known
+    /// about the keys in `batch`. See [BatchOpHint].
+    pub async fn propose_with_hint<K: KeyType, V: ValueType>(
+        &self,
+        batch: api::Batch<K, V>,
+        hint: BatchOpHint,
+    ) -> Result<Arc<Proposal<'_>>, api::Error> {
+        let parent = self.manager.read().await.current_revision();
+        self.propose_on(parent, batch, hint, None).await
+    }
+
+    /// Create a proposal like [api::Db::propose] from `ops` as they are
+    /// produced, applying each to the new trie as it arrives rather than
+    /// collecting them into a batch first, so a large batch is never held
+    /// in memory at once. A batch that only appends keys doesn't take the
+    /// faster path [BatchOpHint::Append] does, since that needs the whole
+    /// batch up front.
+    pub async fn propose_iter<K: KeyType, V: ValueType>(
+        &self,
+        ops: impl IntoIterator<Item = BatchOp<K, V>>,
+    ) -> Result<Arc<Proposal<'_>>, api::Error> {
+        self.check_writable()?;
+        let timer = OperationTimer::start(ApiMethod::Propose);
+        let parent = self.manager.read().await.current_revision();
+        let mut merkle = Merkle::from(NodeStore::new(parent)?);
+        let span = fastrace::Span::enter_with_local_parent("merkleops");
+        let frozen = read_frozen_prefixes(&merkle, merkle.nodestore().reserved_prefix())?;
+        let reserved_prefix: Option<Box<[u8]>> =
+            merkle.nodestore().reserved_prefix().map(Into::into);
+        let mut journal = self.retain_op_journal.then(Vec::new);
+        let mut before = BTreeMap::new();
+        let mut rewrites = self.rewrite_filter();
+        for (batch_index, op) in ops.into_iter().enumerate() {
+            check_op(&merkle, &frozen, batch_index, &op)?;
+            if let Some(journal) = &mut journal {
+                journal::encode_op(journal, &op);
+            }
+            if !self.invariants.is_empty() {
+                ChangeSet::value_before(&merkle, &mut before, &op).await?;
+            }
+            apply_op(&mut merkle, op, reserved_prefix.as_deref(), &mut rewrites)?;
+        }
+        let changes = ChangeSet::new(&merkle, before)?;
+
+        drop(span);
+        let journal = journal.map(|journal| journal::compress(&journal));
+        let proposal = self
+            .add_proposal(merkle, journal, changes, rewrites.stats())
+            .await?;
+        timer.finish(None, || proposal.nodestore.kind.root_hash());
+        Ok(proposal)
+    }
+
+    /// Read a record from the reserved key space of the latest revision,
+    /// whether or not system keys are hidden from users
+    #[allow(dead_code)]
+    pub(crate) async fn get_system(
+        &self,
+        record_type: u8,
+        key: &[u8],
+    ) -> Result<Option<Box<[u8]>>, api::Error> {
+        let key = self.system_store().await.key(record_type, key);
+        let latest = self.manager.read().await.current_revision();
+        Ok(Merkle::from(&latest).get_value(&key)?)
+    }
+
+    /// Make every key starting with `prefix` read-only. From the next
+    /// revision on, a proposal that puts or deletes any key under the
+    /// prefix is rejected with [api::Error::FrozenPrefix], whichever way it
+    /// is proposed, including by [Db::drain_prefix]. Reads and proofs are
+    /// unaffected.
+    ///
+    /// The frozen prefixes are kept in the reserved key space, so freezing
+    /// commits a new revision, survives reopening and changes the root hash.
+    /// Freezing a prefix that is already frozen does nothing.
+    pub async fn freeze_prefix(&self, prefix: &[u8]) -> Result<(), api::Error> {
+        self.update_frozen_prefixes(|prefixes| {
+            if prefixes.iter().any(|frozen| **frozen == *prefix) {
+                return false;
+            }
+            prefixes.push(prefix.into());
+            true
+        })
+        .await
+    }
+
+    /// Undo [Db::freeze_prefix]. Since writes under a frozen prefix are
+    /// usually a mistake, `acknowledgement` must be `"unfreeze "` followed
+    /// by the prefix in lowercase hex; otherwise
+    /// [api::Error::UnfreezeNotAcknowledged] is returned and the prefix
+    /// stays frozen. Unfreezing a prefix that isn't frozen does nothing.
+    pub async fn unfreeze_prefix(
+        &self,
+        prefix: &[u8],
+        acknowledgement: &str,
+    ) -> Result<(), api::Error> {
+        if acknowledgement != format!("unfreeze {}", hex::encode(prefix)) {
+            return Err(api::Error::UnfreezeNotAcknowledged {
+                prefix: prefix.into(),
+            });
+        }
+        self.update_frozen_prefixes(|prefixes| {
+            let before = prefixes.len();
+            prefixes.retain(|frozen| **frozen != *prefix);
+            prefixes.len() != before
+        })
+        .await
+    }
+
+    /// The prefixes frozen in the latest revision, in the order they were
+    /// frozen
+    pub async fn frozen_prefixes(&self) -> Result<Vec<Box<[u8]>>, api::Error> {
+        let latest = self.manager.read().await.current_revision();
+        read_frozen_prefixes(&Merkle::from(&latest), latest.reserved_prefix())
+    }
+
+    /// Commit a change to the frozen prefixes, if `update` makes one. The
+    /// list is read from the revision the change is proposed on, so
+    /// concurrent changes fail with [api::Error::SiblingCommitted] rather than
+    /// overwrite each other.
+    async fn update_frozen_prefixes(
+        &self,
+        update: impl FnOnce(&mut Vec<Box<[u8]>>) -> bool,
+    ) -> Result<(), api::Error> {
+        let parent = self.manager.read().await.current_revision();
+        let Some(system_prefix) = parent.reserved_prefix().map(Box::<[u8]>::from) else {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "this database has no reserved key space to record frozen prefixes in",
+            )
+            .into());
+        };
+        let mut prefixes = read_frozen_prefixes(&Merkle::from(&parent), Some(&system_prefix))?;
+        if !update(&mut prefixes) {
+            return Ok(());
+        }
+
+        let mut system = SystemStore::new(&system_prefix);
+        if prefixes.is_empty() {
+            system.delete_system(FROZEN_PREFIXES, &[]);
+        } else {
+            system.put_system(FROZEN_PREFIXES, &[], &encode_prefixes(&prefixes));
+        }
+        let proposal = self
+            .propose_on(
+                parent,
+                Vec::<BatchOp<&[u8], &[u8]>>::new(),
+                BatchOpHint::Detect,
+                Some(system),
+            )
+            .await?;
+        api::Proposal::commit(proposal).await?;
+        Ok(())
+    }
+
+    /// Evict the `fraction` of the node cache that its policy evicts first
+    /// and return the number of nodes evicted, for operators who know the
+    /// hot part of the trie has moved, such as after switching to an older
+    /// root.
+    ///
+    /// There is no need to call this after abandoning proposals: nodes of a
+    /// proposal are only cached once it is committed, so proposals never
+    /// leave entries behind in the cache.
+    pub async fn shed_cache(&self, fraction: f64) -> usize {
+        self.manager.read().await.storage().shed_cache(fraction)
+    }
+
+    /// The size of the database file, in bytes, as the file system
+    /// reports it. It is usually smaller than [DbStatistics::file_size],
+    /// the size the header records, since the last area is only written as
+    /// far as its node reaches. It can be larger, for instance after a
+    /// commit was interrupted while extending the file.
+    pub async fn file_size(&self) -> Result<u64, api::Error> {
+        Ok(self.manager.read().await.storage().size()?)
+    }
+
+    /// The number of node cache lookups that hit and missed since the
+    /// database was opened
+    pub async fn node_cache_lookups(&self) -> (u64, u64) {
+        self.manager.read().await.storage().node_cache_lookups()
+    }
+
+    /// How many nodes the node cache holds, and roughly how much memory
+    /// they take. This visits every cached node.
+    pub async fn cache_usage(&self) -> CacheUsage {
+        self.manager.read().await.storage().cache_usage()
+    }
+
+    /// Read the nodes on the paths to `keys` in the latest revision, as
+    /// looking them up would, so the node cache admits them ahead of the
+    /// lookups. The values aren't copied. Whether the nodes are kept
+    /// depends on the
+    /// [NodeCachePolicy](crate::manager::NodeCachePolicy); with
+    /// `WritesOnly`, nodes read aren't cached at all.
+    pub async fn warm_cache<K: KeyType, I: IntoIterator<Item = K>>(
+        &self,
+        keys: I,
+    ) -> Result<(), api::Error> {
+        let revision = self.manager.read().await.current_revision();
+        let merkle = Merkle::from(&*revision);
+        for key in keys {
+            merkle.get_node(key.as_ref())?;
+        }
+        Ok(())
+    }
+
+    /// Read every node under `prefix` in the latest revision, and the nodes
+    /// on the path to them, as [Db::warm_cache] does for single keys.
+    /// Returns the number of nodes under `prefix`.
+    pub async fn warm_cache_prefix(&self, prefix: &[u8]) -> Result<usize, api::Error> {
+        let revision = self.manager.read().await.current_revision();
+        let mut nodes = MerkleNodeStream::new(&*revision, prefix.into());
+        let mut warmed = 0;
+        while let Some(node) = nodes.next().await {
+            let (key, _) = node?;
+            // the nodes come in key order, so the rest are past the prefix
+            if !key.starts_with(prefix) {
+                break;
+            }
+            warmed += 1;
+        }
+        Ok(warmed)
+    }
+
+    /// The `n` slowest recent calls to `method`, slowest first. Exemplars are
+    /// only collected with the `metrics` feature; without it this is always
+    /// empty. They are shared by all databases in the process, so use
+    /// [Exemplar::root_hash] to tell them apart.
+    // only const without the metrics feature
+    #[cfg_attr(not(feature = "metrics"), allow(clippy::missing_const_for_fn))]
+    pub fn slow_operations(&self, method: ApiMethod, n: usize) -> Vec<Exemplar> {
+        latency::slow_operations(method, n)
+    }
+
+    /// Propose a copy of the latest revision in which chains of branches
+    /// with no value and a single child are folded into the node below
+    /// them, so reads of the keys under them visit fewer nodes.
+    ///
+    /// The keys and values are unchanged, but the root hash is not, so this
+    /// only takes effect once the returned proposal is committed. At most
+    /// `budget` branches are folded. The walk is registered as a "heal_paths"
+    /// operation, and `token` can be used to cancel it.
+    pub async fn heal_paths(
+        &self,
+        budget: usize,
+        token: Option<CancellationToken>,
+    ) -> Result<(Arc<Proposal<'_>>, HealStats), api::Error> {
+        self.check_writable()?;
+        let mut handle = self.start_operation("heal_paths", token);
+        handle.check()?;
+
+        let parent = self.manager.read().await.current_revision();
+        let mut merkle = Merkle::from(NodeStore::new(parent)?);
+        let stats = merkle.heal_paths(budget, |nodes| handle.checkpoint(nodes, 0))?;
+        counter!("firewood.heal.folded").increment(stats.nodes_folded as u64);
+
+        let proposal = self
+            .add_proposal(merkle, None, ChangeSet::default(), RewriteStats::default())
+            .await?;
+        Ok((proposal, stats))
+    }
+
+    /// Rewrite the database into a new file that holds only the newest
+    /// `keep` retained revisions, and switch to it. The space the dropped
+    /// revisions and fragmentation took is not carried over, so the new file
+    /// is usually smaller.
+    ///
+    /// The oldest kept revision is written in full, then each newer one as
+    /// the entries it changed, so the kept revisions share their unchanged
+    /// nodes as they did before. Each root hash is checked against the
+    /// original, and a sample of each revision's changed keys is read back
+    /// from both files. Afterwards, [api::Db::revision] works for every kept
+    /// root and reads return the same data.
+    ///
+    /// Commits wait until compaction finishes. Proposals made before it can
+    /// no longer be committed and fail with [api::Error::ProposalInvalidated],
+    /// since their nodes would be written to the old file.
+    ///
+    /// The new file is written next to this one and renamed over it, so a
+    /// crash leaves either the old file or the new one, which have the same
+    /// latest revision. The work is registered as a "compact" operation, and
+    /// `token` can cancel it until the rename. Databases with an external
+    /// root authority can't be compacted, since their unpromoted revisions
+    /// would be lost.
+    pub async fn compact_with_history(
+        &self,
+        keep: usize,
+        token: Option<CancellationToken>,
+    ) -> Result<CompactionStats, api::Error> {
+        self.check_writable()?;
+        let mut handle = self.start_operation("compact", token);
+        let mut manager = self.manager.write().await;
+        // the pending commits wouldn't carry over to the compacted file
+        if manager.is_committing() {
+            return Err(api::Error::IO(std::io::Error::new(
+                std::io::ErrorKind::WouldBlock,
+                "commits are still being flushed",
+            )));
+        }
+        let written = match self.write_compacted(&manager, keep, &mut handle).await {
+            Ok(written) => handle.check().map(|()| written),
+            Err(err) => Err(err),
+        };
+        let (mut compacted, revisions) = written.inspect_err(|_| {
+            // best effort; the next compaction removes it otherwise
+            let _ = std::fs::remove_file(self.compaction_path());
+        })?;
+
+        let stats = CompactionStats {
+            revisions,
+            size_before: manager.current_revision().store_size(),
+            size_after: compacted.current_revision().store_size(),
+        };
+        compacted.sync()?;
+        std::fs::rename(self.compaction_path(), &self.path)?;
+        manager.replace_with(compacted)?;
+        counter!("firewood.compactions").increment(1);
+        Ok(stats)
+    }
+
+    /// Rewrite the database into a new file that holds every retained
+    /// revision and switch to it, reclaiming the space on the free lists.
+    /// This is [Db::compact_with_history] keeping all of them, so the same
+    /// checks and crash safety apply.
+    ///
+    /// Progress is reported as the entries and bytes copied so far by the
+    /// "compact" operation in [Db::operations], and `token` can cancel it.
+    pub async fn compact(
+        &self,
+        token: Option<CancellationToken>,
+    ) -> Result<CompactionStats, api::Error> {
+        self.compact_with_history(usize::MAX, token).await
+    }
+
+    /// Where [Db::compact_with_history] writes the new file
+    fn compaction_path(&self) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(".compacting");
+        path.into()
+    }
+
+    /// Write the newest `keep` revisions of `manager` to a new file at
+    /// [Db::compaction_path], returning a manager for it and the number of
+    /// revisions written
+    async fn write_compacted(
+        &self,
+        manager: &RevisionManager,
+        keep: usize,
+        handle: &mut OperationHandle,
+    ) -> Result<(RevisionManager, usize), api::Error> {
+        if manager.has_external_root_authority() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::Unsupported,
+                "a database with an external root authority can't be compacted",
+            )
+            .into());
+        }
+        let mut kept = manager.newest_revisions(keep.max(1));
+        kept.reverse();
+
+        let path = self.compaction_path();
+        if path.exists() {
+            // left behind by a compaction that didn't finish
+            std::fs::remove_file(&path)?;
+        }
+        let mut compacted = manager.compaction_target(path)?;
+
+        let mut older: Option<&CommittedRevision> = None;
+        for revision in &kept {
+            let samples: Vec<Key> = match older {
+                // The oldest is written in chunks. Only one revision is kept
+                // until it is complete, so each chunk replaces the last.
+                None => {
+                    let merkle = Merkle::from(revision);
+                    let mut stream = merkle.key_value_iter();
+                    let mut chunk = Vec::new();
+                    let mut samples = Vec::new();
+                    while let Some((key, value)) = stream.next().await.transpose()? {
+                        handle.checkpoint(1, (key.len() + value.len()) as u64)?;
+                        chunk.push((key, Some(value)));
+                        if chunk.len() >= COMPACTION_BATCH {
+                            samples.extend(sample_keys(&chunk));
+                            commit_changes(&mut compacted, chunk, revision.height())?;
+                            chunk = Vec::new();
+                        }
+                    }
+                    samples.extend(sample_keys(&chunk));
+                    commit_changes(&mut compacted, chunk, revision.height())?;
+                    compacted.set_max_revisions(kept.len())?;
+                    samples
+                }
+                Some(older) => {
+                    let changes = revision_changes(older, revision).await?;
+                    handle.checkpoint(changes.len() as u64, 0)?;
+                    let samples = sample_keys(&changes).collect();
+                    commit_changes(&mut compacted, changes, revision.height())?;
+                    samples
+                }
+            };
+
+            let copy = compacted.current_revision();
+            if copy.kind.root_hash() != revision.kind.root_hash() {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "compacted revision has root hash {:?}, not {:?}",
+                        copy.kind.root_hash(),
+                        revision.kind.root_hash()
+                    ),
+                )
+                .into());
+            }
+            let (original, copy) = (Merkle::from(revision), Merkle::from(&copy));
+            for key in samples {
+                if original.get_value(&key)? != copy.get_value(&key)? {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidData,
+                        format!("compacted revision reads {key:?} differently"),
+                    )
+                    .into());
+                }
+            }
+            older = Some(revision);
+        }
+        Ok((compacted, kept.len()))
+    }
+
+    /// The batch the retained revision with `root_hash` was proposed with,
+    /// op for op, as it was passed in. Requires [DbConfig::retain_op_journal].
+    ///
+    /// Returns None for revisions that are no longer retained, ones that
+    /// were committed without op journals retained, and ones made by
+    /// [Db::heal_paths], which have no batch. When several retained
+    /// revisions have the same root hash, this is the batch of the newest.
+    pub async fn op_journal(
+        &self,
+        root_hash: &TrieHash,
+    ) -> Result<Option<Batch<Box<[u8]>, Box<[u8]>>>, api::Error> {
+        Ok(self.manager.read().await.op_journal(root_hash)?)
+    }
+
+    /// The space the op journals of retained revisions take on disk, in bytes
+    pub async fn op_journal_bytes(&self) -> Result<u64, api::Error> {
+        Ok(self.manager.read().await.op_journal_bytes()?)
+    }
+
+    /// The state of the database when the retained revision with
+    /// `root_hash` was committed. Requires
+    /// `RevisionManagerConfig::retain_operational_snapshots`.
+    ///
+    /// Returns None for revisions that are no longer retained and ones that
+    /// were committed without snapshots retained.
+    pub async fn operational_snapshot(
+        &self,
+        root_hash: &TrieHash,
+    ) -> Result<Option<OperationalSnapshot>, api::Error> {
+        Ok(self.manager.read().await.operational_snapshot(root_hash)?)
+    }
+
+    /// Work out how to restore the database as it was at `target`, without
+    /// doing anything, so the plan can be reviewed before
+    /// [Db::execute_restore] runs it.
+    ///
+    /// Only retained revisions can be restored. A [RestoreTarget::Timestamp]
+    /// resolves to the revision made by the last commit at or before that
+    /// time, never a later one. Timestamps and commit sequences only resolve
+    /// to commits made since the database was opened. Anything else fails
+    /// with [api::Error::RestoreTargetUnavailable], saying what is missing.
+    pub async fn restore_plan(&self, target: RestoreTarget) -> Result<RestorePlan, api::Error> {
+        let manager = self.manager.read().await;
+        restore::plan(target, manager.commit_log(), |root_hash| {
+            manager.retained_revision(root_hash).is_some()
+        })
+    }
+
+    /// Run `plan` from [Db::restore_plan], copying the revision it resolved
+    /// to into a new database at `destination` and checking the copy has the
+    /// planned root hash. The new database has the same reserved key space
+    /// as this one, but a default configuration otherwise.
+    ///
+    /// This database is left as it is. Fails if `destination` exists, or if
+    /// the revision was reaped after the plan was made.
+    pub async fn execute_restore<P: AsRef<FilePath>>(
+        &self,
+        plan: &RestorePlan,
+        destination: P,
+    ) -> Result<Db, api::Error> {
+        let destination = destination.as_ref();
+        if destination.exists() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::AlreadyExists,
+                format!("{} already exists", destination.display()),
+            )
+            .into());
+        }
+
+        let mut restored = None;
+        for step in &plan.steps {
+            match step {
+                RestoreStep::CopyRevision { root_hash } => {
+                    let revision = self
+                        .manager
+                        .read()
+                        .await
+                        .retained_revision(root_hash.as_ref())
+                        .ok_or_else(|| api::Error::RestoreTargetUnavailable {
+                            target: plan.target.clone(),
+                            missing: "the revision, which was reaped after the plan was made",
+                        })?;
+                    restored = Some(copy_revision(&revision, destination).await?);
+                }
+                RestoreStep::VerifyRootHash { root_hash } => {
+                    let Some(db) = &restored else {
+                        continue;
+                    };
+                    let restored_hash = db.manager.read().await.current_revision().kind.root_hash();
+                    if restored_hash != *root_hash {
+                        return Err(std::io::Error::new(
+                            std::io::ErrorKind::InvalidData,
+                            format!(
+                                "restored root hash {restored_hash:?} doesn't match {root_hash:?}"
+                            ),
+                        )
+                        .into());
+                    }
+                }
+            }
+        }
+        restored.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the restore plan has nothing to copy",
+            )
+            .into()
+        })
+    }
+
+    /// A proof of the changes that turn the retained revision with
+    /// `start_root` into the one with `end_root`, for the keys from
+    /// `start_key` to `end_key`, with at most `limit` changes. Like a range
+    /// proof cut short by its limit, a proof with `limit` changes covers the
+    /// range up to the last of them.
+    pub async fn change_proof(
+        &self,
+        start_root: TrieHash,
+        end_root: TrieHash,
+        start_key: Option<&[u8]>,
+        end_key: Option<&[u8]>,
+        limit: Option<usize>,
+    ) -> Result<ChangeProof<Box<[u8]>, Box<[u8]>, ProofNode>, api::Error> {
+        let limit = range_proof_limit(limit)?;
+        if let (Some(start), Some(end)) = (start_key, end_key) {
+            if start > end {
+                return Err(api::Error::InvalidRange {
+                    start_key: start.into(),
+                    end_key: end.into(),
+                });
+            }
+        }
+        let (older, newer) = {
+            let manager = self.manager.read().await;
+            (manager.revision(start_root)?, manager.revision(end_root)?)
+        };
+        let key_changes = changes_in_range(&older, &newer, start_key, end_key, limit).await?;
+
+        let merkle = Merkle::from(&newer);
+        let start_proof = start_key.map(|key| merkle.prove(key)).transpose()?;
+        let end_proof = key_changes
+            .last()
+            .map(|(key, _)| &**key)
+            .or(end_key)
+            .map(|key| merkle.prove(key))
+            .transpose()?;
+        Ok(ChangeProof {
+            start_proof,
+            end_proof,
+            key_changes: key_changes
+                .into_iter()
+                .map(|(key, value)| (key, value.map(Vec::into_boxed_slice)))
+                .collect(),
+        })
+    }
+
+    /// Sync the latest revision towards the revision of another database
+    /// with root `target`, from a range proof of it from `start` to `end`.
+    /// The proof is verified against `target`, and the keys in the range it
+    /// covers are set to what it proves, deleting the ones it doesn't have,
+    /// in a new commit.
+    ///
+    /// The root won't be `target` until the whole key space is covered, so
+    /// the sync tracks where the next proof has to start. The first proof
+    /// of a sync starts at the first key, with no `start`, and each one
+    /// after it at the `next_key` returned for the one before; a proof that
+    /// starts anywhere else fails with [api::Error::SyncOutOfOrder]. Commits
+    /// made some other way while syncing aren't tracked, and can keep the
+    /// sync from completing.
+    pub async fn apply_range_proof(
+        &self,
+        target: TrieHash,
+        proof: &RangeProof<Box<[u8]>, Box<[u8]>, ProofNode>,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<SyncStatus, api::Error> {
+        self.sync
+            .apply_range_proof(&self.manager, target, proof, start, end)
+            .await
+    }
+
+    /// Sync the latest revision towards the revision of another database
+    /// with root `target`, from a change proof of the changes that turn the
+    /// revision it was synced to before into that one, from `start` to
+    /// `end`. The changes are applied, and the keys in the range they cover
+    /// then have to be what the proof shows `target` has there, or nothing
+    /// is committed. The sync is tracked as it is by
+    /// [Db::apply_range_proof].
+    pub async fn apply_change_proof(
+        &self,
+        target: TrieHash,
+        proof: &ChangeProof<Box<[u8]>, Box<[u8]>, ProofNode>,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<SyncStatus, api::Error> {
+        self.sync
+            .apply_change_proof(&self.manager, target, proof, start, end)
+            .await
+    }
+
+    /// Answer each of `requests` against the retained revision with
+    /// `root_hash`, with one proof covering every answer, for
+    /// [crate::audit::verify_audit_bundle] to check. The revision is held
+    /// until the bundle is built, so it can't be reaped in the meantime.
+    pub async fn audit_bundle(
+        &self,
+        root_hash: TrieHash,
+        requests: Vec<AuditRequest>,
+    ) -> Result<AuditBundle, api::Error> {
+        let revision = self.manager.read().await.revision(root_hash)?;
+        Ok(Merkle::from(revision).audit_bundle(requests)?)
+    }
+
+    /// A token for the latest revision. Reads given the token all see that
+    /// revision, whatever is committed in the meantime, for as long as it is
+    /// retained; see [DbConfig::min_token_validity].
+    pub async fn consistency_token(&self) -> ConsistencyToken {
+        let manager = self.manager.read().await;
+        ConsistencyToken::new(manager.current_revision().kind.root_hash(), manager.epoch())
+    }
+
+    /// The revision `token` refers to, or [api::Error::TokenExpired] if it is
+    /// no longer retained. Holding the returned revision keeps it from being
+    /// reaped, so use it for work such as iteration that spans several
+    /// calls, and drop it when done.
+    pub async fn token_revision(
+        &self,
+        token: &ConsistencyToken,
+    ) -> Result<Arc<HistoricalRev>, api::Error> {
+        self.manager
+            .read()
+            .await
+            .retained_revision(token.root_hash().as_ref())
+            .ok_or(api::Error::TokenExpired {
+                epoch: token.epoch(),
+            })
+    }
+
+    /// The value of `key` in the revision `token` refers to
+    pub async fn get_with_token<K: KeyType>(
+        &self,
+        token: &ConsistencyToken,
+        key: K,
+    ) -> Result<Option<Box<[u8]>>, api::Error> {
+        self.token_revision(token).await?.val(key).await
+    }
+
+    /// The values of `keys` in the revision `token` refers to
+    pub async fn get_many_with_token<K: KeyType>(
+        &self,
+        token: &ConsistencyToken,
+        keys: &[K],
+    ) -> Result<Vec<Option<Box<[u8]>>>, api::Error> {
+        let revision = self.token_revision(token).await?;
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(revision.val(key).await?);
+        }
+        Ok(values)
+    }
+
+    /// A proof of the value of `key` in the revision `token` refers to
+    pub async fn prove_with_token<K: KeyType>(
+        &self,
+        token: &ConsistencyToken,
+        key: K,
+    ) -> Result<Proof<ProofNode>, api::Error> {
+        self.token_revision(token)
+            .await?
+            .single_key_proof(key)
+            .await
+    }
+
+    /// Register a long-running operation so that it shows up in [Db::operations]
+    /// and can be stopped with [Db::cancel]. The operation should call
+    /// [OperationHandle::checkpoint] as it makes progress, and stop once that
+    /// returns [api::Error::Cancelled]. An optional `token` lets the caller
+    /// cancel the operation directly, or give it a deadline.
+    pub fn start_operation(
+        &self,
+        name: &'static str,
+        token: Option<CancellationToken>,
+    ) -> OperationHandle {
+        self.operations.start(name, token)
+    }
+
+    /// List the long-running operations currently in progress
+    pub fn operations(&self) -> Vec<OperationInfo> {
+        self.operations.list()
+    }
+
+    /// Cancel a long-running operation. The operation stops at its next safe
+    /// point. Returns false if no such operation is running.
+    pub fn cancel(&self, id: OperationId) -> bool {
+        self.operations.cancel(id)
+    }
+}
+
+/// Aborts a task when it is dropped
+#[derive(Debug)]
+struct AbortOnDrop(tokio::task::AbortHandle);
+
+impl Drop for AbortOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+/// Sync the file of `manager` every `interval` if [DurabilityMode::Buffered]
+/// calls for it, so the last commits before the database goes idle don't
+/// wait for the next one to be synced
+#[cfg_attr(not(feature = "logger"), allow(unused_variables))]
+async fn sync_periodically(manager: Weak<RwLock<RevisionManager>>, interval: Duration) {
+    loop {
+        tokio::time::sleep(interval).await;
+        let Some(manager) = manager.upgrade() else {
+            return;
+        };
+        let synced = manager.write().await.sync_if_due();
+        if let Err(err) = synced {
+            warn!("Could not sync the database file: {err}");
+        }
+    }
+}
+
+/// Create a database at `destination` holding every entry of `revision`
+async fn copy_revision(revision: &HistoricalRev, destination: &FilePath) -> Result<Db, api::Error> {
+    let system_keys = match revision.reserved_prefix() {
+        Some(prefix) if !revision.is_hidden(prefix) => SystemKeys::Reject,
+        _ => SystemKeys::Hidden,
+    };
+    let merkle = Merkle::from(revision);
+    copy_entries(
+        merkle.key_value_iter(),
+        revision.reserved_prefix(),
+        system_keys,
+        DbConfig::builder().build(),
+        destination,
+    )
+    .await
+}
+
+/// Create a database at `destination`, configured as `config` but with the
+/// given reserved key space, holding `entries`, which come in key order.
+/// User keys are copied first, in batches of [RESTORE_BATCH], and the
+/// reserved key space last, so frozen prefixes don't apply to the copy.
+async fn copy_entries(
+    mut stream: impl Stream<Item = Result<(Key, Value), api::Error>> + Unpin,
+    reserved_prefix: Option<&[u8]>,
+    system_keys: SystemKeys,
+    config: DbConfig,
+    destination: &FilePath,
+) -> Result<Db, api::Error> {
+    let config = DbConfig {
+        truncate: true,
+        read_only: false,
+        system_prefix: reserved_prefix.unwrap_or(DEFAULT_SYSTEM_PREFIX).into(),
+        system_keys,
+        ..config
+    };
+    let db = Db::new(destination, config).await?;
+    let mut system = db.system_store().await;
+
+    let mut batch = Vec::new();
+    loop {
+        let entry = stream.next().await.transpose()?;
+        let copy_batch = match &entry {
+            None => !batch.is_empty(),
+            Some(_) => batch.len() >= RESTORE_BATCH,
+        };
+        if copy_batch {
+            let proposal = db
+                .propose_with_hint(take(&mut batch), BatchOpHint::Append)
+                .await?;
+            api::Proposal::commit(proposal).await?;
+        }
+        let Some((key, value)) = entry else {
+            break;
+        };
+        if reserved_prefix.is_some_and(|prefix| key.starts_with(prefix)) {
+            system.put_key(&key, &value);
+        } else {
+            batch.push(BatchOp::Put { key, value });
+        }
+    }
+    drop(stream);
+
+    let proposal = db
+        .propose_with_system(Vec::<BatchOp<&[u8], &[u8]>>::new(), system)
+        .await?;
+    api::Proposal::commit(proposal).await?;
+    Ok(db)
+}
+
+/// The limit on the keys of a range proof; a proof can't have a limit of 0
+fn range_proof_limit(limit: Option<usize>) -> Result<Option<NonZeroUsize>, api::Error> {
+    limit
+        .map(|limit| NonZeroUsize::new(limit).ok_or(api::Error::RangeTooSmall))
+        .transpose()
+}
+
+/// Commit `changes` to `manager` as one revision, exactly as they are, without
+/// the reserved key and frozen prefix checks of proposals made by users.
+/// Nothing is committed if there are no changes.
+pub(crate) fn commit_changes(
+    manager: &mut RevisionManager,
+    changes: Vec<Change>,
+    height: u64,
+) -> Result<(), api::Error> {
+    if changes.is_empty() {
+        return Ok(());
+    }
+    let mut merkle = Merkle::from(NodeStore::new(manager.current_revision())?);
+    for (key, value) in changes {
+        match value {
+            Some(value) => merkle.insert(&key, value.into_boxed_slice())?,
+            None => {
+                merkle.remove(&key)?;
+            }
+        }
+    }
+    let proposal: Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>> =
+        Arc::new(NodeStore::try_from_mutable(merkle.into_inner())?);
+    manager.commit_at_height(proposal, None, height)?;
+    Ok(())
+}
+
+/// The changes that turn `older` into `newer`, in key order, found by
+/// walking the entries of both
+async fn revision_changes(
+    older: &CommittedRevision,
+    newer: &CommittedRevision,
+) -> Result<Vec<Change>, api::Error> {
+    changes_in_range(older, newer, None, None, None).await
+}
+
+/// The changes that turn `older` into `newer` for the keys from `start` to
+/// `end`, in key order, up to `limit` of them, found by walking the entries
+/// of both in that range
+async fn changes_in_range(
+    older: &CommittedRevision,
+    newer: &CommittedRevision,
+    start: Option<&[u8]>,
+    end: Option<&[u8]>,
+    limit: Option<NonZeroUsize>,
+) -> Result<Vec<Change>, api::Error> {
+    let (older, newer) = (Merkle::from(older), Merkle::from(newer));
+    let (mut older_stream, mut newer_stream) = match start {
+        Some(start) => (
+            older.key_value_iter_from_key(start),
+            newer.key_value_iter_from_key(start),
+        ),
+        None => (older.key_value_iter(), newer.key_value_iter()),
+    };
+    let in_range =
+        |entry: Option<(Key, Value)>| entry.filter(|(key, _)| end.is_none_or(|end| **key <= *end));
+    let limit = limit.map_or(usize::MAX, NonZeroUsize::get);
+    let mut older_entry = in_range(older_stream.next().await.transpose()?);
+    let mut newer_entry = in_range(newer_stream.next().await.transpose()?);
+    let mut changes = Vec::new();
+    while changes.len() < limit {
+        let order = match (&older_entry, &newer_entry) {
+            (None, None) => break,
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (Some((older_key, _)), Some((newer_key, _))) => older_key.cmp(newer_key),
+        };
+        if order != Ordering::Greater {
+            let (key, value) = older_entry.take().expect("checked above");
+            older_entry = in_range(older_stream.next().await.transpose()?);
+            if order == Ordering::Less {
+                changes.push((key, None));
+                continue;
+            }
+            let (_, newer_value) = newer_entry.as_ref().expect("checked above");
+            if *newer_value == value {
+                newer_entry = in_range(newer_stream.next().await.transpose()?);
+                continue;
+            }
+        }
+        let (key, value) = newer_entry.take().expect("checked above");
+        newer_entry = in_range(newer_stream.next().await.transpose()?);
+        changes.push((key, Some(value)));
+    }
+    Ok(changes)
+}
+
+/// Up to [COMPACTION_SAMPLES] keys spread evenly over `changes`
+fn sample_keys(changes: &[Change]) -> impl Iterator<Item = Key> + '_ {
+    let step = changes.len().div_ceil(COMPACTION_SAMPLES).max(1);
+    changes.iter().step_by(step).map(|(key, _)| key.clone())
+}
+
+/// The prefixes frozen in the revision `merkle` reads, given its system
+/// prefix
+fn read_frozen_prefixes<T: TrieReader>(
+    merkle: &Merkle<T>,
+    system_prefix: Option<&[u8]>,
+) -> Result<Vec<Box<[u8]>>, api::Error> {
+    let Some(system_prefix) = system_prefix else {
+        return Ok(Vec::new());
+    };
+    let key = SystemStore::new(system_prefix).key(FROZEN_PREFIXES, &[]);
+    match merkle.get_value(&key)? {
+        Some(value) => Ok(decode_prefixes(&value)?),
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Apply a user batch to a proposal. Writes to reserved keys, and to keys
+/// under a prefix frozen in the parent revision, are rejected, as are
+/// [BatchOp::DeleteRange] ops whose prefix covers a frozen prefix. A
+/// [BatchOp::DeleteRange] that covers the reserved key space leaves it as it
+/// is.
+fn apply_batch<K: KeyType, V: ValueType>(
+    merkle: &mut Merkle<NodeStore<MutableProposal, FileBacked>>,
+    batch: api::Batch<K, V>,
+    hint: BatchOpHint,
+    rewrites: &mut RewriteFilter<'_>,
+) -> Result<(), api::Error> {
+    let frozen = read_frozen_prefixes(merkle, merkle.nodestore().reserved_prefix())?;
+    for (batch_index, op) in batch.iter().enumerate() {
+        check_op(merkle, &frozen, batch_index, op)?;
+    }
+
+    if hint != BatchOpHint::Unordered {
+        if is_append(merkle, &batch)? {
+            counter!("firewood.propose.batch", "path" => "append").increment(1);
+            let entries: Vec<_> = batch
+                .into_iter()
+                .filter_map(|op| match op {
+                    BatchOp::Put { key, value } => Some((
+                        Path::from_nibbles_iterator(NibblesIterator::new(key.as_ref())),
+                        value.as_ref().into(),
+                    )),
+                    BatchOp::Delete { .. } | BatchOp::DeleteRange { .. } => None,
+                })
+                .collect();
+            merkle.insert_sorted(&entries)?;
+            return Ok(());
+        }
+        if hint == BatchOpHint::Append {
+            counter!("firewood.propose.batch", "path" => "append_fallback").increment(1);
+        }
+    }
+
+    let reserved_prefix: Option<Box<[u8]>> = merkle.nodestore().reserved_prefix().map(Into::into);
+    for op in batch {
+        apply_op(merkle, op, reserved_prefix.as_deref(), rewrites)?;
+    }
+    Ok(())
+}
+
+/// Fail if `op`, the `batch_index`th of its batch, touches a reserved key
+/// or one under a prefix in `frozen`
+fn check_op<K: KeyType, V: ValueType>(
+    merkle: &Merkle<NodeStore<MutableProposal, FileBacked>>,
+    frozen: &[Box<[u8]>],
+    batch_index: usize,
+    op: &BatchOp<K, V>,
+) -> Result<(), api::Error> {
+    let (key, is_range) = match op {
+        BatchOp::Put { key, .. } | BatchOp::Delete { key } => (key.as_ref(), false),
+        BatchOp::DeleteRange { prefix } => (prefix.as_ref(), true),
+    };
+    if merkle.nodestore().is_reserved(key) {
+        return Err(api::Error::ReservedKey { key: key.into() });
+    }
+    if let Some(prefix) = frozen
+        .iter()
+        .find(|prefix| key.starts_with(prefix) || is_range && prefix.starts_with(key))
+    {
+        return Err(api::Error::FrozenPrefix {
+            prefix: prefix.clone(),
+            offending_key: key.into(),
+            batch_index,
+        });
+    }
+    Ok(())
+}
+
+/// Apply `op` to `merkle`, unless it puts a value equivalent to the one
+/// the key already has
+fn apply_op<K: KeyType, V: ValueType>(
+    merkle: &mut Merkle<NodeStore<MutableProposal, FileBacked>>,
+    op: BatchOp<K, V>,
+    reserved_prefix: Option<&[u8]>,
+    rewrites: &mut RewriteFilter<'_>,
+) -> Result<(), api::Error> {
+    match op {
+        BatchOp::Put { key, value } => {
+            // an append only puts new keys, so only this path checks
+            let (key, value) = (key.as_ref(), value.as_ref());
+            if let Some(old) = merkle.peek_value(key)? {
+                if rewrites.is_equivalent(key, &old, value) {
+                    return Ok(());
+                }
+            }
+            merkle.insert(key, value.into())?;
+        }
+        BatchOp::Delete { key } => {
+            merkle.remove(key.as_ref())?;
+        }
+        BatchOp::DeleteRange { prefix } => {
+            merkle.remove_prefix(prefix.as_ref(), reserved_prefix)?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns true if `batch` only puts keys in increasing order, all larger
+/// than the largest key in `merkle`. The batch is checked before the trie,
+/// so a batch that isn't sorted costs no reads.
+fn is_append<K: KeyType, V: ValueType>(
+    merkle: &Merkle<NodeStore<MutableProposal, FileBacked>>,
+    batch: &api::Batch<K, V>,
+) -> Result<bool, api::Error> {
+    let mut previous: Option<&[u8]> = None;
+    for op in batch {
+        let BatchOp::Put { key, .. } = op else {
+            return Ok(false);
+        };
+        if previous.is_some_and(|previous| previous >= key.as_ref()) {
+            return Ok(false);
+        }
+        previous = Some(key.as_ref());
+    }
+
+    let Some(BatchOp::Put { key: first, .. }) = batch.first() else {
+        return Ok(false);
+    };
+    Ok(match merkle.max_key_nibbles()? {
+        None => true,
+        Some(max) => NibblesIterator::new(first.as_ref()).gt(max.iter().copied()),
+    })
+}
+
+/// Apply a batch built by a [SystemStore], which only touches reserved keys
+fn apply_system_batch(
+    merkle: &mut Merkle<NodeStore<MutableProposal, FileBacked>>,
+    batch: SystemBatch,
+) -> Result<(), api::Error> {
+    for op in batch {
+        match op {
+            BatchOp::Put { key, value } => {
+                debug_assert!(merkle.nodestore().is_reserved(&key));
+                merkle.insert(&key, value)?;
+            }
+            BatchOp::Delete { key } => {
+                debug_assert!(merkle.nodestore().is_reserved(&key));
+                merkle.remove(&key)?;
+            }
+            BatchOp::DeleteRange { prefix } => {
+                debug_assert!(merkle.nodestore().is_reserved(&prefix));
+                merkle.remove_prefix(&prefix, None)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+/// A user-visible database proposal
+pub struct Proposal<'p> {
+    nodestore: Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>>,
+    db: &'p Db,
+    staged: Arc<Staged>,
+    /// What happened to the puts of the batch to keys that had a value
+    rewrites: RewriteStats,
+}
+
+/// What committing a proposal writes or checks besides its nodes. The
+/// proposals made on top of it keep it too, so [Db::commit_chain] can
+/// commit it along with them.
+#[derive(Debug)]
+struct Staged {
+    /// The encoded batch, when op journals are retained
+    journal: Option<Box<[u8]>>,
+    /// The keys the batch changed, when there are invariants to check
+    changes: ChangeSet,
+    /// That of the proposal this one was made on top of, if it was
+    parent: Option<Arc<Staged>>,
+}
+
+impl Proposal<'_> {
+    /// Run the [DbConfig::invariants] on this proposal, as committing it
+    /// would. A proposal that fails them can still be read, while a failed
+    /// commit consumes it.
+    pub fn check_invariants(&self) -> Result<(), api::Error> {
+        invariant::check_all(&self.db.invariants, &self.nodestore, &self.staged.changes)
+    }
+
+    /// How many of the puts of this proposal's batch to keys that had a
+    /// value were dropped as equivalent to it, written, or written without
+    /// being compared
+    pub const fn rewrite_stats(&self) -> RewriteStats {
+        self.rewrites
+    }
+}
+
+#[async_trait]
+impl api::DbView for Proposal<'_> {
+    type Stream<'b>
+        = MerkleKeyValueStream<'b, NodeStore<Arc<ImmutableProposal>, FileBacked>>
+    where
+        Self: 'b;
+
+    async fn root_hash(&self) -> Result<Option<api::HashKey>, api::Error> {
+        self.nodestore.root_hash().map_err(api::Error::from)
+    }
+
+    async fn val<K: KeyType>(&self, key: K) -> Result<Option<Box<[u8]>>, api::Error> {
+        if self.nodestore.is_hidden(key.as_ref()) {
+            return Ok(None);
+        }
+        let timer = OperationTimer::start(ApiMethod::Get);
+        let merkle = Merkle::from(self.nodestore.clone());
+        let value = merkle.get_value(key.as_ref())?;
+        timer.finish(Some(key.as_ref()), || self.nodestore.kind.root_hash());
+        Ok(value)
+    }
+
+    async fn get_many<K: KeyType, I: IntoIterator<Item = K> + Send>(
+        &self,
+        keys: I,
+    ) -> Result<Vec<Option<Box<[u8]>>>, api::Error> {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let mut values = Merkle::from(self.nodestore.clone()).get_values(&keys)?;
+        for (key, value) in keys.iter().zip(values.iter_mut()) {
+            if self.nodestore.is_hidden(key.as_ref()) {
+                *value = None;
+            }
+        }
+        Ok(values)
+    }
+
+    async fn get_slice<K: KeyType>(
+        &self,
+        key: K,
+        range: Range<usize>,
+    ) -> Result<Option<api::ValueSlice>, api::Error> {
+        if self.nodestore.is_hidden(key.as_ref()) {
+            return Ok(None);
+        }
+        match Merkle::from(self.nodestore.clone()).get_node(key.as_ref())? {
+            Some(node) => api::ValueSlice::new(node, range),
+            None => Ok(None),
+        }
+    }
+
+    async fn value_len<K: KeyType>(&self, key: K) -> Result<Option<usize>, api::Error> {
+        if self.nodestore.is_hidden(key.as_ref()) {
+            return Ok(None);
+        }
+        let node = Merkle::from(self.nodestore.clone()).get_node(key.as_ref())?;
+        Ok(node.and_then(|node| node.value().map(<[u8]>::len)))
+    }
+
+    async fn single_key_proof<K: KeyType>(&self, key: K) -> Result<Proof<ProofNode>, api::Error> {
+        let timer = OperationTimer::start(ApiMethod::Prove);
+        let merkle = Merkle::from(self.nodestore.clone());
+        let proof = merkle.prove(key.as_ref())?;
+        timer.finish(Some(key.as_ref()), || self.nodestore.kind.root_hash());
+        Ok(proof)
+    }
+
+    async fn get_with_proof<K: KeyType>(
+        &self,
+        key: K,
+    ) -> Result<(Option<Box<[u8]>>, Proof<ProofNode>), api::Error> {
+        let timer = OperationTimer::start(ApiMethod::Prove);
+        let merkle = Merkle::from(self.nodestore.clone());
+        let found = merkle.get_with_proof(key.as_ref())?;
+        timer.finish(Some(key.as_ref()), || self.nodestore.kind.root_hash());
+        Ok(found)
+    }
+
+    async fn range_proof<K: KeyType, V>(
+        &self,
+        first_key: Option<K>,
+        last_key: Option<K>,
+        limit: Option<usize>,
+    ) -> Result<Option<api::RangeProof<Box<[u8]>, Box<[u8]>, ProofNode>>, api::Error> {
+        let limit = range_proof_limit(limit)?;
+        let proof = Merkle::from(self.nodestore.clone())
+            .range_proof(
+                first_key.as_ref().map(AsRef::as_ref),
+                last_key.as_ref().map(AsRef::as_ref),
+                limit,
+            )
+            .await?;
+        Ok(Some(proof))
+    }
+
+    async fn estimate_range<K: KeyType>(
+        &self,
+        first_key: Option<K>,
+        end_key: Option<K>,
+    ) -> Result<api::RangeEstimate, api::Error> {
+        let merkle = Merkle::from(self.nodestore.clone());
+        merkle
+            .estimate_range(
+                first_key.as_ref().map(AsRef::as_ref),
+                end_key.as_ref().map(AsRef::as_ref),
+            )
+            .map_err(api::Error::from)
+    }
+
+    fn iter_option<K: KeyType>(
+        &self,
+        first_key: Option<K>,
+    ) -> Result<Self::Stream<'_>, api::Error> {
+        let nodestore = &*self.nodestore;
+        let stream = match first_key {
+            Some(key) => MerkleKeyValueStream::from_key(nodestore, key),
+            None => MerkleKeyValueStream::from(nodestore),
+        };
+        Ok(stream.hiding(nodestore.hidden_prefix()))
+    }
+
+    fn iter_prefix<K: KeyType>(&self, prefix: K) -> Result<Self::Stream<'_>, api::Error> {
+        let nodestore = &*self.nodestore;
+        Ok(MerkleKeyValueStream::from_prefix(nodestore, prefix).hiding(nodestore.hidden_prefix()))
+    }
+
+    fn iter_range<K: KeyType>(&self, start: K, end: K) -> Result<Self::Stream<'_>, api::Error> {
+        let nodestore = &*self.nodestore;
+        let stream = MerkleKeyValueStream::from_range(nodestore, start, end);
+        Ok(stream.hiding(nodestore.hidden_prefix()))
+    }
+}
+
+#[async_trait]
+impl<'a> api::Proposal for Proposal<'a> {
+    type Proposal = Proposal<'a>;
+
+    #[fastrace::trace(short_name = true)]
+    async fn propose<K: KeyType, V: ValueType>(
+        self: Arc<Self>,
+        batch: api::Batch<K, V>,
+    ) -> Result<Arc<Self::Proposal>, api::Error> {
+        let timer = OperationTimer::start(ApiMethod::Propose);
+        let parent = self.nodestore.clone();
+        let proposal = NodeStore::new(parent)?;
+        let mut merkle = Merkle::from(proposal);
+        let journal = self.db.retain_op_journal.then(|| journal::encode(&batch));
+        let before = self.db.values_before(&merkle, &batch).await?;
+        let mut rewrites = self.db.rewrite_filter();
+        apply_batch(&mut merkle, batch, BatchOpHint::Detect, &mut rewrites)?;
+        let changes = ChangeSet::new(&merkle, before)?;
+        let nodestore = merkle.into_inner();
+        let immutable: Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>> =
+            Arc::new(NodeStore::try_from_mutable(nodestore)?);
+        self.db
+            .manager
+            .write()
+            .await
+            .add_proposal(immutable.clone());
+        timer.finish(None, || immutable.kind.root_hash());
+
+        Ok(Self::Proposal {
+            nodestore: immutable,
+            db: self.db,
+            staged: Arc::new(Staged {
+                journal,
+                changes,
+                parent: Some(self.staged.clone()),
+            }),
+            rewrites: rewrites.stats(),
+        }
+        .into())
+    }
+
+    async fn commit(self: Arc<Self>) -> Result<Option<api::HashKey>, api::Error> {
+        match Arc::into_inner(self) {
+            Some(proposal) => {
+                let timer = OperationTimer::start(ApiMethod::Commit);
+                proposal.check_invariants()?;
+                let pending = {
+                    let mut manager = proposal.db.manager.write().await;
+                    proposal.db.check_open()?;
+                    manager
+                        .begin_commit(
+                            proposal.nodestore.clone(),
+                            proposal.staged.journal.as_deref(),
+                        )
+                        .inspect_err(|err| proposal.db.poison_on(err))?
+                };
+                proposal.db.finish_commit(&pending).await?;
+                let root_hash = pending.root_hash();
+                timer.finish(None, || root_hash.clone());
+                Ok(root_hash)
+            }
+            None => Err(api::Error::CannotCommitClonedProposal),
+        }
+    }
+
+    async fn abort(self: Arc<Self>) -> Result<(), api::Error> {
+        match Arc::into_inner(self) {
+            Some(proposal) => {
+                let mut manager = proposal.db.manager.write().await;
+                Ok(manager.abort(proposal.nodestore)?)
+            }
+            None => Err(api::Error::CannotAbortClonedProposal),
+        }
+    }
+}
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::ops::{Deref, DerefMut};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    use crate::db::Db;
+    use crate::merkle::Merkle;
+    use crate::v2::api::{Db as _, DbView as _, Error, Proposal as _};
+    use futures::StreamExt;
+
+    use super::{BatchOp, BatchOpHint, DbConfig, DrainDecision, KeyType, ValueType};
+    use crate::audit::{verify_audit_bundle, AuditRequest, AuditResult};
+    use crate::change_proof::ChangeProof;
+    use crate::delete_log::DeleteLog;
+    use crate::diff::KeyChange;
+    use crate::manager::{AllocationPolicy, DurabilityMode, RevisionManagerConfig};
+    use crate::merkle::HealStats;
+    use crate::operations::CancellationToken;
+    use crate::range_proof::RangeProof;
+    use crate::reaper::Reaper;
+    use crate::sync::SyncStatus;
+    use crate::system::{SystemKeys, DEFAULT_SYSTEM_PREFIX};
+    use crate::token::ConsistencyToken;
+    use crate::verify::VerifyOptions;
+    use storage::TrieHash;
+
+    #[tokio::test]
+    async fn test_cloned_proposal_error() {
+        let db = testdb().await;
+        let proposal = db
+            .propose::<Vec<u8>, Vec<u8>>(Default::default())
+            .await
+            .unwrap();
+        let cloned = proposal.clone();
+
+        // attempt to commit the clone; this should fail
+        let result = cloned.commit().await;
+        assert!(
+            matches!(result, Err(Error::CannotCommitClonedProposal)),
+            "{result:?}"
+        );
+
+        // the prior attempt consumed the Arc though, so cloned is no longer valid
+        // that means the actual proposal can be committed
+        let result = proposal.commit().await;
+        assert!(matches!(result, Ok(None)), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn empty_database_has_no_revisions() {
+        let db = testdb().await;
+        assert!(matches!(db.root_hash().await, Ok(None)));
+
+        let unknown = TrieHash::from(rand::random::<[u8; 32]>());
+        let result = db.revision(unknown).await;
+        assert!(matches!(result, Err(Error::LatestIsEmpty)), "{result:?}");
+    }
+
+    #[tokio::test]
+    async fn unknown_revision_is_not_found() {
+        let db = testdb().await;
+        let proposal = db
+            .propose(vec![BatchOp::Put {
+                key: b"k",
+                value: b"v",
+            }])
+            .await
+            .unwrap();
+        let root = proposal.commit().await.unwrap().unwrap();
+        assert!(db.revision(root).await.is_ok());
+
+        let unknown = TrieHash::from(rand::random::<[u8; 32]>());
+        let result = db.revision(unknown.clone()).await;
+        assert!(
+            matches!(&result, Err(Error::HashNotFound { provided }) if *provided == unknown),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn revision_range_proof() {
+        let db = testdb().await;
+        let batch = [b"a", b"b", b"c", b"d"].map(|key| BatchOp::Put { key, value: key });
+        let proposal = db.propose(Vec::from(batch)).await.unwrap();
+        let root = proposal.commit().await.unwrap().unwrap();
+        let revision = db.revision(root.clone()).await.unwrap();
+
+        let (start, end) = (b"b".as_slice(), b"d".as_slice());
+        let proof = revision
+            .range_proof::<_, ()>(Some(start), Some(end), Some(2))
+            .await
+            .unwrap()
+            .unwrap();
+        let keys: Vec<_> = proof.key_values.iter().map(|(key, _)| &**key).collect();
+        assert_eq!(keys, [b"b", b"c"]);
+        // truncated by the limit, it proves the range up to c
+        proof.verify(&root, Some(start), Some(end)).unwrap();
+
+        let result = revision.range_proof::<&[u8], ()>(None, None, Some(0)).await;
+        assert!(matches!(result, Err(Error::RangeTooSmall)), "{result:?}");
+    }
+
+    /// Apply range proofs of the revision of `source` with root `target`
+    /// to `dest`, `limit` keys at a time, until it has that root; returns
+    /// how many proofs that took
+    async fn sync_with_range_proofs(
+        source: &Db,
+        target: &TrieHash,
+        dest: &Db,
+        limit: usize,
+    ) -> usize {
+        let revision = source.revision(target.clone()).await.unwrap();
+        let mut start: Option<Box<[u8]>> = None;
+        for proofs in 1.. {
+            let proof = revision
+                .range_proof::<&[u8], ()>(start.as_deref(), None, Some(limit))
+                .await
+                .unwrap()
+                .unwrap();
+            let status = dest
+                .apply_range_proof(target.clone(), &proof, start.as_deref(), None)
+                .await
+                .unwrap();
+            match status {
+                SyncStatus::Complete => return proofs,
+                SyncStatus::Partial { next_key } => start = Some(next_key),
+            }
+        }
+        unreachable!()
+    }
+
+    #[tokio::test]
+    async fn copy_database_with_proofs() {
+        let source = testdb().await;
+        // spread over the key space; the multiplier is odd, so they're distinct
+        let key = |i: u32| i.wrapping_mul(2_654_435_761).to_be_bytes();
+        let batch: Vec<_> = (0..100_000u32)
+            .map(|i| BatchOp::Put {
+                key: key(i),
+                value: i.to_le_bytes(),
+            })
+            .collect();
+        let target = source
+            .propose(batch)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap()
+            .unwrap();
+
+        let dest = testdb().await;
+        let proofs = sync_with_range_proofs(&source, &target, &dest, 10_000).await;
+        assert_eq!(proofs, 10);
+        assert_eq!(dest.root_hash().await.unwrap(), Some(target.clone()));
+
+        // then follow the source with change proofs
+        let mut batch: Vec<_> = (0..100u32)
+            .map(|i| BatchOp::Delete {
+                key: key(i).to_vec(),
+            })
+            .collect();
+        batch.extend((100..200u32).map(|i| BatchOp::Put {
+            key: key(i).to_vec(),
+            value: b"changed".to_vec(),
+        }));
+        batch.extend((100_000..100_050u32).map(|i| BatchOp::Put {
+            key: key(i).to_vec(),
+            value: b"new".to_vec(),
+        }));
+        let newer = source
+            .propose(batch)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap()
+            .unwrap();
+
+        let mut start: Option<Box<[u8]>> = None;
+        let mut proofs = 0;
+        loop {
+            let proof = source
+                .change_proof(
+                    target.clone(),
+                    newer.clone(),
+                    start.as_deref(),
+                    None,
+                    Some(100),
+                )
+                .await
+                .unwrap();
+            proofs += 1;
+            let status = dest
+                .apply_change_proof(newer.clone(), &proof, start.as_deref(), None)
+                .await
+                .unwrap();
+            match status {
+                SyncStatus::Complete => break,
+                SyncStatus::Partial { next_key } => start = Some(next_key),
+            }
+        }
+        assert_eq!(proofs, 3);
+        assert_eq!(dest.root_hash().await.unwrap(), Some(newer));
+    }
+
+    #[tokio::test]
+    async fn sync_rejects_bad_proofs() {
+        let source = testdb().await;
+        let batch: Vec<_> = (0..100u8)
+            .map(|i| BatchOp::Put {
+                key: [i],
+                value: [i],
+            })
+            .collect();
+        let target = source
+            .propose(batch)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap()
+            .unwrap();
+        let revision = source.revision(target.clone()).await.unwrap();
+        let dest = testdb().await;
+
+        // a proof has to start where the sync left off
+        let proof = revision
+            .range_proof::<&[u8], ()>(Some([50u8].as_slice()), None, Some(10))
+            .await
+            .unwrap()
+            .unwrap();
+        let err = dest
+            .apply_range_proof(target.clone(), &proof, Some([50u8].as_slice()), None)
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::SyncOutOfOrder { expected: None, .. }),
+            "{err:?}"
+        );
+
+        // and has to prove what it holds
+        let proof = revision
+            .range_proof::<&[u8], ()>(None, None, Some(10))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut key_values = proof.key_values.to_vec();
+        key_values.pop();
+        key_values.push((Box::from([9u8].as_slice()), Box::from([0u8].as_slice())));
+        let tampered = RangeProof {
+            start_proof: proof.start_proof.clone(),
+            end_proof: proof.end_proof.clone(),
+            key_values: key_values.into(),
+        };
+        let err = dest
+            .apply_range_proof(target.clone(), &tampered, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidProof(_)), "{err:?}");
+        assert_eq!(dest.root_hash().await.unwrap(), None);
+
+        let status = dest
+            .apply_range_proof(target.clone(), &proof, None, None)
+            .await
+            .unwrap();
+        assert_eq!(
+            status,
+            SyncStatus::Partial {
+                next_key: Box::from([9u8, 0].as_slice())
+            }
+        );
+
+        // a change proof missing a change leaves the range unlike the target
+        assert_eq!(sync_with_range_proofs(&source, &target, &dest, 30).await, 4);
+        let batch = vec![
+            BatchOp::Delete { key: vec![10] },
+            BatchOp::Put {
+                key: vec![20],
+                value: vec![0],
+            },
+        ];
+        let newer = source
+            .propose(batch)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap()
+            .unwrap();
+        let proof = source
+            .change_proof(target.clone(), newer.clone(), None, None, None)
+            .await
+            .unwrap();
+        assert_eq!(proof.key_changes.len(), 2);
+        let missing = ChangeProof {
+            start_proof: proof.start_proof.clone(),
+            end_proof: proof.end_proof.clone(),
+            key_changes: proof.key_changes.get(1..).unwrap().into(),
+        };
+        let err = dest
+            .apply_change_proof(newer.clone(), &missing, None, None)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::InvalidProof(_)), "{err:?}");
+        assert_eq!(dest.root_hash().await.unwrap(), Some(target));
+
+        let status = dest
+            .apply_change_proof(newer.clone(), &proof, None, None)
+            .await
+            .unwrap();
+        assert_eq!(status, SyncStatus::Complete);
+        assert_eq!(dest.root_hash().await.unwrap(), Some(newer));
+    }
+
+    #[tokio::test]
+    async fn value_slices() {
+        let db = testdb().await;
+        let large: Vec<u8> = (0..1 << 20).map(|i: u32| (i % 251) as u8).collect();
+        let batch = vec![
+            BatchOp::Put {
+                key: b"large".to_vec(),
+                value: large.clone(),
+            },
+            BatchOp::Put {
+                key: b"small".to_vec(),
+                value: b"0123456789".to_vec(),
+            },
+            // a value on a branch
+            BatchOp::Put {
+                key: b"s".to_vec(),
+                value: b"branch".to_vec(),
+            },
+        ];
+        let root = db.propose(batch).await.unwrap().commit().await.unwrap();
+        let revision = db.revision(root.unwrap()).await.unwrap();
+        let slice = |key: &'static [u8], range| revision.get_slice(key, range);
+
+        assert_eq!(&*slice(b"small", 2..5).await.unwrap().unwrap(), b"234");
+        assert_eq!(&*slice(b"small", 7..10).await.unwrap().unwrap(), b"789");
+        assert_eq!(&*slice(b"small", 10..10).await.unwrap().unwrap(), b"");
+        assert_eq!(&*slice(b"s", 0..6).await.unwrap().unwrap(), b"branch");
+        let middle = slice(b"large", 500_000..500_064).await.unwrap().unwrap();
+        assert_eq!(Some(&*middle), large.get(500_000..500_064));
+        assert!(slice(b"missing", 0..1).await.unwrap().is_none());
+
+        // a slice shares the cached node instead of copying the value
+        let again = slice(b"large", 500_000..500_064).await.unwrap().unwrap();
+        assert_eq!(middle.as_ptr(), again.as_ptr());
+
+        // past the end, and backwards
+        for range in [9..11, 11..11, std::ops::Range { start: 5, end: 3 }] {
+            let err = slice(b"small", range.clone()).await.unwrap_err();
+            assert!(
+                matches!(&err, Error::RangeOutOfBounds { range: r, value_len: 10 } if *r == range),
+                "{err:?}"
+            );
+        }
+
+        assert_eq!(revision.value_len(b"large").await.unwrap(), Some(1 << 20));
+        assert_eq!(revision.value_len(b"s").await.unwrap(), Some(6));
+        assert_eq!(revision.value_len(b"missing").await.unwrap(), None);
+
+        // proposals read slices of their own values
+        let batch = vec![BatchOp::Put {
+            key: b"small",
+            value: b"abc",
+        }];
+        let proposal = db.propose(batch).await.unwrap();
+        let slice = proposal.get_slice(b"small", 1..3).await.unwrap().unwrap();
+        assert_eq!(&*slice, b"bc");
+        assert_eq!(proposal.value_len(b"small").await.unwrap(), Some(3));
+    }
+
+    #[tokio::test]
+    async fn abort_proposals() {
+        let db = testdb().await;
+        let put = |key: &'static [u8]| vec![BatchOp::Put { key, value: b"v" }];
+        db.propose(put(b"k")).await.unwrap().commit().await.unwrap();
+        let file_len = || std::fs::metadata(db.path()).unwrap().len();
+        let len = file_len();
+        let tracked = db.manager.read().await.all_hashes().len();
+
+        for i in 0..10_000u32 {
+            let batch: Vec<_> = (0..4u32)
+                .map(|j| BatchOp::Put {
+                    key: (i * 4 + j).to_be_bytes(),
+                    value: [1; 64],
+                })
+                .collect();
+            db.propose(batch).await.unwrap().abort().await.unwrap();
+        }
+        assert_eq!(file_len(), len);
+        assert_eq!(db.manager.read().await.all_hashes().len(), tracked);
+        assert_eq!(db.proposal_count().await, 0);
+
+        // proposals on top of one have to go first
+        let parent = db.propose(put(b"a")).await.unwrap();
+        let child = parent.clone().propose(put(b"b")).await.unwrap();
+        let err = parent.abort().await.unwrap_err();
+        assert!(
+            matches!(err, Error::ProposalHasChildren { children: 1 }),
+            "{err:?}"
+        );
+        // the failed abort dropped the parent, which goes with its child
+        child.abort().await.unwrap();
+        assert_eq!(db.manager.read().await.all_hashes().len(), tracked);
+        assert_eq!(db.proposal_count().await, 0);
+
+        let proposal = db.propose(put(b"a")).await.unwrap();
+        let err = proposal.clone().abort().await.unwrap_err();
+        assert!(matches!(err, Error::CannotAbortClonedProposal), "{err:?}");
+        assert!(proposal.commit().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn commit_returns_root_hash() {
+        let db = testdb().await;
+        let put = |key: &'static [u8]| vec![BatchOp::Put { key, value: b"v" }];
+        let first = db.propose(put(b"a")).await.unwrap();
+        let second = first.clone().propose(put(b"b")).await.unwrap();
+        let first_hash = first.root_hash().await.unwrap();
+        let second_hash = second.root_hash().await.unwrap();
+        assert_ne!(first_hash, second_hash);
+
+        // each commit returns the hash of its own proposal
+        assert_eq!(first.commit().await.unwrap(), first_hash);
+        assert_eq!(second.commit().await.unwrap(), second_hash);
+        assert_eq!(db.root_hash().await.unwrap(), second_hash);
+
+        let batch: Vec<BatchOp<&[u8], &[u8]>> =
+            vec![BatchOp::Delete { key: b"a" }, BatchOp::Delete { key: b"b" }];
+        let emptied = db.propose(batch).await.unwrap();
+        assert_eq!(emptied.commit().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn commit_chain() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .retain_op_journal(true)
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        let put = |key: u8| {
+            vec![BatchOp::Put {
+                key: [key],
+                value: b"v",
+            }]
+        };
+
+        let mut top = db.propose(put(0)).await.unwrap();
+        let mut hashes = vec![top.root_hash().await.unwrap().unwrap()];
+        let mut siblings = Vec::new();
+        for i in 1..5u8 {
+            if i % 2 == 0 {
+                // abandoned, off the middle of the chain
+                siblings.push(top.clone().propose(put(10 + i)).await.unwrap());
+            }
+            top = top.propose(put(i)).await.unwrap();
+            hashes.push(top.root_hash().await.unwrap().unwrap());
+        }
+        assert_eq!(siblings.len(), 2);
+
+        let root_hash = db.commit_chain(top).await.unwrap();
+        assert_eq!(root_hash.as_ref(), hashes.last());
+        assert_eq!(db.root_hash().await.unwrap(), root_hash);
+        for hash in &hashes {
+            assert!(db.op_journal(hash).await.unwrap().is_some());
+        }
+        for sibling in siblings {
+            let err = sibling.commit().await.unwrap_err();
+            assert!(matches!(err, Error::SiblingCommitted), "{err:?}");
+        }
+
+        // a chain that isn't on the latest commit is left alone
+        let bottom = db.propose(put(20)).await.unwrap();
+        let top = bottom.clone().propose(put(21)).await.unwrap();
+        let other = db.propose(put(22)).await.unwrap().commit().await.unwrap();
+        let err = db.commit_chain(top).await.unwrap_err();
+        assert!(matches!(err, Error::NotLatest), "{err:?}");
+        assert_eq!(db.root_hash().await.unwrap(), other);
+        let err = bottom.commit().await.unwrap_err();
+        assert!(matches!(err, Error::SiblingCommitted), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn commit_while_an_earlier_one_flushes() {
+        let db = testdb().await;
+        let put = |key: u8| {
+            vec![BatchOp::Put {
+                key: [key],
+                value: b"v",
+            }]
+        };
+        let first = db.propose(put(1)).await.unwrap();
+        let second = first.clone().propose(put(2)).await.unwrap();
+        let sibling = first.clone().propose(put(3)).await.unwrap();
+        let first_hash = first.root_hash().await.unwrap().unwrap();
+        let second_hash = second.root_hash().await.unwrap();
+
+        // start committing the first, leaving its nodes unflushed
+        let pending = db
+            .manager
+            .write()
+            .await
+            .begin_commit(first.nodestore.clone(), None)
+            .unwrap();
+        drop(first);
+
+        // the second can be committed on top of it, but isn't published first
+        let mut commit = second.commit();
+        assert!(futures::poll!(&mut commit).is_pending());
+        assert_eq!(db.root_hash().await.unwrap(), None);
+        let err = db.revision(first_hash.clone()).await.unwrap_err();
+        assert!(matches!(err, Error::LatestIsEmpty), "{err:?}");
+        let err = sibling.commit().await.unwrap_err();
+        assert!(matches!(err, Error::SiblingCommitted), "{err:?}");
+
+        db.finish_commit(&pending).await.unwrap();
+        assert_eq!(commit.await.unwrap(), second_hash);
+        assert_eq!(db.root_hash().await.unwrap(), second_hash);
+        assert_eq!(db.latest_height().await, 2);
+        db.revision(first_hash).await.unwrap();
+
+        // both survive reopening
+        let db = db.reopen().await;
+        assert_eq!(db.root_hash().await.unwrap(), second_hash);
+    }
+
+    #[test]
+    fn pipelined_commits_from_two_threads() {
+        const COMMITS: usize = 1000;
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(
+                RevisionManagerConfig::builder()
+                    .max_revisions(COMMITS + 1)
+                    .build(),
+            )
+            .build();
+        let db = runtime.block_on(async { testdb().await.reopen_with(dbconfig).await });
+        let key = |i: usize| (i as u32).to_be_bytes();
+
+        // a chain of proposals, each on top of the one before, dealt out to
+        // the two threads in turn
+        let mut hashes = Vec::new();
+        let mut dealt = [Vec::new(), Vec::new()];
+        runtime.block_on(async {
+            let mut parent: Option<Arc<super::Proposal<'_>>> = None;
+            for i in 0..COMMITS {
+                let batch = vec![BatchOp::Put {
+                    key: key(i),
+                    value: key(i),
+                }];
+                let proposal = match parent.take() {
+                    None => db.propose(batch).await.unwrap(),
+                    Some(parent) => parent.propose(batch).await.unwrap(),
+                };
+                hashes.push(proposal.root_hash().await.unwrap());
+                parent = Some(proposal.clone());
+                if let Some(dealt) = dealt.get_mut(i % 2) {
+                    dealt.push((i, proposal));
+                }
+            }
+        });
+
+        // Each commit starts once the one before it is queued for the
+        // manager, so it may begin while that one is still being published.
+        let (turn, _) = tokio::sync::watch::channel(0);
+        std::thread::scope(|scope| {
+            for proposals in dealt {
+                let (runtime, turn, hashes) = (&runtime, &turn, &hashes);
+                scope.spawn(move || {
+                    runtime.block_on(async move {
+                        let mut waiting = turn.subscribe();
+                        for (i, proposal) in proposals {
+                            waiting.wait_for(|turn| *turn == i).await.unwrap();
+                            let mut commit = proposal.commit();
+                            let polled = futures::poll!(&mut commit);
+                            turn.send_replace(i + 1);
+                            let root_hash = match polled {
+                                std::task::Poll::Ready(root_hash) => root_hash,
+                                std::task::Poll::Pending => commit.await,
+                            };
+                            assert_eq!(&root_hash.unwrap(), hashes.get(i).unwrap());
+                        }
+                    })
+                });
+            }
+        });
+
+        runtime.block_on(async {
+            assert_eq!(db.latest_height().await, COMMITS as u64);
+            for hash in hashes.iter().flatten() {
+                db.revision(hash.clone()).await.unwrap();
+            }
+            let db = db.reopen().await;
+            assert_eq!(db.root_hash().await.unwrap(), *hashes.last().unwrap());
+            let latest = db.revision_by_offset(0).await.unwrap();
+            for i in 0..COMMITS {
+                assert_eq!(&*latest.val(key(i)).await.unwrap().unwrap(), key(i));
+            }
+        });
+    }
+
+    #[tokio::test]
+    async fn open_shared() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("db");
+        let config = || DbConfig::builder().truncate(true).build();
+        let db = Db::open_shared(&path, config()).await.unwrap();
+        put_all(&db, &[b"k"], b"v").await;
+
+        // the same file by other paths
+        let dotted = dir.path().join(".").join("db");
+        let symlink = dir.path().join("symlink");
+        std::os::unix::fs::symlink(&path, &symlink).unwrap();
+        let hard_link = dir.path().join("hard_link");
+        std::fs::hard_link(&path, &hard_link).unwrap();
+        for other in [&path, &dotted, &symlink, &hard_link] {
+            let shared = Db::open_shared(other, config()).await.unwrap();
+            assert!(Arc::ptr_eq(&db, &shared), "{}", other.display());
+        }
+
+        let err = Db::open_shared(&path, DbConfig::builder().build())
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::IncompatibleConfig {
+                    field: "truncate",
+                    ..
+                }
+            ),
+            "{err:?}"
+        );
+        let requested = DbConfig::builder()
+            .truncate(true)
+            .retain_op_journal(true)
+            .build();
+        let err = Db::open_shared(&symlink, requested).await.unwrap_err();
+        let Error::IncompatibleConfig {
+            field,
+            existing,
+            requested,
+        } = err
+        else {
+            panic!("{err:?}");
+        };
+        assert_eq!(
+            (field, &*existing, &*requested),
+            ("retain_op_journal", "false", "true")
+        );
+
+        // the last handle closes it, and the next open reads the file again
+        let weak = Arc::downgrade(&db);
+        drop(db);
+        assert!(weak.upgrade().is_none());
+        let db = Db::open_shared(&hard_link, DbConfig::builder().build())
+            .await
+            .unwrap();
+        let root_hash = db.root_hash().await.unwrap().unwrap();
+        let value = db
+            .revision(root_hash)
+            .await
+            .unwrap()
+            .val(b"k")
+            .await
+            .unwrap();
+        assert_eq!(value.as_deref(), Some(&b"v"[..]));
+
+        db.poison_on(&std::io::Error::other("flush failed").into());
+        let err = Db::open_shared(&path, DbConfig::builder().build())
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::Poisoned), "{err:?}");
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 8)]
+    async fn open_shared_concurrently() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = Arc::new(dir.path().join("db"));
+        let open = |path: Arc<PathBuf>, truncate| async move {
+            let config = DbConfig::builder().truncate(truncate).build();
+            Db::open_shared(&*path, config).await.unwrap()
+        };
+
+        let tasks: Vec<_> = (0..32)
+            .map(|_| tokio::spawn(open(path.clone(), true)))
+            .collect();
+        let mut handles = Vec::new();
+        for task in tasks {
+            handles.push(task.await.unwrap());
+        }
+        let db = handles.pop().unwrap();
+        assert!(handles.iter().all(|handle| Arc::ptr_eq(handle, &db)));
+        drop(handles);
+        drop(db);
+
+        // opens racing with the last handle being dropped
+        let tasks: Vec<_> = (0..8u8)
+            .map(|i| {
+                let path = path.clone();
+                tokio::spawn(async move {
+                    for j in 0..16u8 {
+                        let db = open(path.clone(), false).await;
+                        let batch = vec![BatchOp::Put {
+                            key: [i, j],
+                            value: b"v",
+                        }];
+                        let proposal = db.propose(batch).await.unwrap();
+                        match proposal.commit().await {
+                            // another task may commit first
+                            Ok(_) | Err(Error::SiblingCommitted) => {}
+                            Err(err) => panic!("{err:?}"),
+                        }
+                    }
+                })
+            })
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        let db = open(path, false).await;
+        assert!(db.root_hash().await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn revision_window() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(4).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        assert_eq!(db.revision_count().await, 1);
+        assert_eq!(db.oldest_revision_hash().await, None);
+
+        for i in 0u32..8 {
+            put_all(&db, &[&i.to_be_bytes()], b"v").await;
+        }
+        assert_eq!(db.revision_count().await, 4);
+        let hashes = db.all_hashes().await.unwrap();
+        assert_eq!(db.oldest_revision_hash().await.as_ref(), hashes.first());
+
+        let put = |key: &'static [u8]| vec![BatchOp::Put { key, value: b"v" }];
+        let first = db.propose(put(b"a")).await.unwrap();
+        let second = db.propose(put(b"b")).await.unwrap();
+        assert_eq!(db.proposal_count().await, 2);
+        first.commit().await.unwrap();
+        assert_eq!(db.proposal_count().await, 1);
+        second.abort().await.unwrap();
+        assert_eq!(db.proposal_count().await, 0);
+
+        // the oldest was reaped to make room
+        assert_eq!(db.revision_count().await, 4);
+        assert_eq!(db.oldest_revision_hash().await.as_ref(), hashes.get(1));
+    }
+
+    #[tokio::test]
+    async fn delete_log_recovered_on_open() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(2).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig.clone()).await;
+        for i in 0u8..8 {
+            put_all(&db, &[&[i]], b"v").await;
+        }
+        // every commit reaped a revision, and cleared its log when done
+        let log = DeleteLog::open(&db.path(), false).unwrap();
+        assert_eq!(log.read().unwrap(), None);
+
+        // as if the next commit was interrupted after writing it
+        let root = db.root_hash().await.unwrap();
+        log.write(None, &[]).unwrap();
+        let db = db.reopen_with(dbconfig).await;
+        assert_eq!(log.read().unwrap(), None);
+        assert_eq!(db.root_hash().await.unwrap(), root);
+        put_all(&db, &[b"k"], b"v").await;
+    }
+
+    #[tokio::test]
+    async fn reaped_revision() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(2).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        put_all(&db, &[b"first"], b"v").await;
+        let first = db.root_hash().await.unwrap().unwrap();
+        for i in 0u8..2 {
+            put_all(&db, &[&[i]], b"v").await;
+        }
+        let result = db.revision(first.clone()).await;
+        assert!(
+            matches!(&result, Err(Error::RevisionReaped { provided }) if *provided == first),
+            "{result:?}"
+        );
+
+        let unknown = TrieHash::from(rand::random::<[u8; 32]>());
+        let result = db.revision(unknown).await;
+        assert!(
+            matches!(result, Err(Error::HashNotFound { .. })),
+            "{result:?}"
+        );
+
+        // only the last max_revisions reaped revisions are remembered
+        for i in 2u8..4 {
+            put_all(&db, &[&[i]], b"v").await;
+        }
+        let result = db.revision(first).await;
+        assert!(
+            matches!(result, Err(Error::HashNotFound { .. })),
+            "{result:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn shrink_max_revisions() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(8).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        let mut hashes = Vec::new();
+        for i in 0u8..6 {
+            put_all(&db, &[&[i]], &[i; 100]).await;
+            hashes.push(db.root_hash().await.unwrap().unwrap());
+        }
+        // the empty revision the database was created with is retained too
+        assert_eq!(db.revision_count().await, 7);
+
+        // shrinking to 2 would reap the pinned one
+        let second = hashes.get(1).unwrap().clone();
+        let pin = db.pin(second.clone()).await.unwrap();
+        let result = db.set_max_revisions(2).await;
+        assert!(
+            matches!(&result, Err(Error::RevisionPinned { root_hash }) if *root_hash == second),
+            "{result:?}"
+        );
+        assert_eq!(db.revision_count().await, 7);
+        // but not to 5
+        db.set_max_revisions(5).await.unwrap();
+        assert_eq!(db.revision_count().await, 5);
+
+        drop(pin);
+        db.set_max_revisions(2).await.unwrap();
+        assert_eq!(db.revision_count().await, 2);
+        // only the last max_revisions of them are remembered as reaped
+        for (i, reaped) in hashes.iter().take(4).enumerate() {
+            let result = db.revision(reaped.clone()).await;
+            assert!(
+                match i {
+                    0 | 1 => matches!(result, Err(Error::HashNotFound { .. })),
+                    _ => matches!(result, Err(Error::RevisionReaped { .. })),
+                },
+                "{result:?}"
+            );
+        }
+        for retained in hashes.iter().skip(4) {
+            db.revision(retained.clone()).await.unwrap();
+        }
+
+        // the next commit frees the space of the reaped revisions
+        let before = db.statistics(false).await.unwrap().free_bytes;
+        put_all(&db, &[b"k"], b"v").await;
+        assert!(db.statistics(false).await.unwrap().free_bytes > before);
+        assert_eq!(db.revision_count().await, 2);
+
+        // growing lets them accumulate again
+        db.set_max_revisions(4).await.unwrap();
+        for i in 0u8..4 {
+            put_all(&db, &[b"k"], &[i]).await;
+        }
+        assert_eq!(db.revision_count().await, 4);
+    }
+
+    #[tokio::test]
+    async fn pinned_revision() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(4).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        put_all(&db, &[b"pinned"], b"v").await;
+        let hash = db.root_hash().await.unwrap().unwrap();
+        let pin = db.pin(hash.clone()).await.unwrap();
+        let nested = db.pin(hash.clone()).await.unwrap();
+        assert_eq!(pin.root_hash(), &hash);
+
+        for i in 0u8..8 {
+            put_all(&db, &[&[i]], b"v").await;
+        }
+        assert!(db.revision(hash.clone()).await.is_ok());
+        assert_eq!(db.oldest_revision_hash().await, Some(hash.clone()));
+        assert_eq!(db.revision_count().await, 9);
+
+        // still pinned by the other guard
+        drop(pin);
+        put_all(&db, &[b"a"], b"v").await;
+        assert!(db.revision(hash.clone()).await.is_ok());
+
+        // reapable again, but only by the next commit
+        drop(nested);
+        assert!(db.revision(hash.clone()).await.is_ok());
+        put_all(&db, &[b"b"], b"v").await;
+        assert!(db.revision(hash.clone()).await.is_err());
+        assert_eq!(db.revision_count().await, 4);
+
+        // only retained revisions can be pinned
+        assert!(db.pin(hash).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn snapshot() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(2).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        put_all(&db, &[b"snapshot"], b"v").await;
+        let hash = db.root_hash().await.unwrap().unwrap();
+        let snapshot = db.snapshot(hash.clone()).await.unwrap();
+        assert_eq!(snapshot.root_hash(), &hash);
+
+        for i in 0u8..4 {
+            put_all(&db, &[b"snapshot"], &[i]).await;
+        }
+        assert_eq!(&*snapshot.val(b"snapshot").await.unwrap().unwrap(), b"v");
+        let revision = db.revision(hash.clone()).await.unwrap();
+        assert!(Arc::ptr_eq(&revision, snapshot.revision()));
+        drop(revision);
+
+        drop(snapshot);
+        put_all(&db, &[b"snapshot"], b"w").await;
+        assert!(db.revision(hash.clone()).await.is_err());
+        assert!(db.snapshot(hash).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn background_reaping() {
+        let manager = RevisionManagerConfig::builder()
+            .max_revisions(2)
+            .background_reaping(true)
+            .build();
+        let dbconfig = DbConfig::builder().truncate(false).manager(manager).build();
+        let db = testdb().await.reopen_with(dbconfig.clone()).await;
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        for value in [b"a", b"b", b"c", b"d", b"e", b"f"] {
+            put_all(&db, &key_refs, value).await;
+        }
+        assert_eq!(db.revision_count().await, 2);
+
+        // the last reaped revision is left for the next open to free
+        db.close().await.unwrap();
+        let mut deletes = db.path().into_os_string();
+        deletes.push(".deletes");
+        assert!(PathBuf::from(&deletes).exists());
+        let db = db.reopen_with(dbconfig).await;
+        assert!(!PathBuf::from(&deletes).exists());
+        assert!(db.statistics(false).await.unwrap().free_bytes > 0);
+        let free_areas = VerifyOptions {
+            free_areas: true,
+            ..Default::default()
+        };
+        let report = db.verify(free_areas).await.unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        for value in [b"g", b"h", b"i"] {
+            put_all(&db, &key_refs, value).await;
+        }
+        let report = db.verify(free_areas).await.unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+    }
+
+    #[tokio::test]
+    async fn background_reaping_frees_areas_a_commit_later() {
+        let manager = RevisionManagerConfig::builder()
+            .max_revisions(2)
+            .background_reaping(true)
+            .build();
+        let dbconfig = DbConfig::builder().truncate(false).manager(manager).build();
+        let db = testdb().await.reopen_with(dbconfig.clone()).await;
+        // the areas of each reaped revision are looked up as soon as it is sent
+        db.manager.write().await.replace_reaper(Reaper::immediate());
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        for value in [b"a", b"b", b"c", b"d", b"e", b"f"] {
+            put_all(&db, &key_refs, value).await;
+        }
+
+        // a commit only frees what was looked up before it, so the areas of
+        // the revision the last commit reaped are still left for the next open
+        db.close().await.unwrap();
+        let mut deletes = db.path().into_os_string();
+        deletes.push(".deletes");
+        assert!(PathBuf::from(&deletes).exists());
+        let db = db.reopen_with(dbconfig).await;
+        let free_areas = VerifyOptions {
+            free_areas: true,
+            ..Default::default()
+        };
+        let report = db.verify(free_areas).await.unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+    }
+
+    #[tokio::test]
+    async fn revisions_kept_by_age() {
+        let manager = RevisionManagerConfig::builder()
+            .max_revisions(2)
+            .max_revision_age(Some(Duration::from_secs(3600)))
+            .build();
+        let dbconfig = DbConfig::builder().truncate(false).manager(manager).build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        for i in 0u8..10 {
+            put_all(&db, &[b"age"], &[i]).await;
+        }
+        // the revision opened with the database isn't recent
+        assert_eq!(db.revision_count().await, 10);
+
+        let manager = RevisionManagerConfig::builder()
+            .max_revisions(2)
+            .max_revision_age(Some(Duration::from_millis(50)))
+            .build();
+        let dbconfig = DbConfig::builder().truncate(false).manager(manager).build();
+        let db = db.reopen_with(dbconfig).await;
+        for i in 0u8..10 {
+            put_all(&db, &[b"age"], &[i]).await;
+        }
+        std::thread::sleep(Duration::from_millis(100));
+        put_all(&db, &[b"age"], b"old").await;
+        assert_eq!(db.revision_count().await, 2);
+    }
+
+    #[tokio::test]
+    async fn revision_heights() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(4).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig.clone()).await;
+        assert_eq!(db.latest_height().await, 0);
+        assert!(db.revision_by_height(0).await.is_ok());
+
+        let mut hashes = Vec::new();
+        for i in 1u32..=8 {
+            put_all(&db, &[&i.to_be_bytes()], b"v").await;
+            hashes.push((u64::from(i), db.root_hash().await.unwrap().unwrap()));
+        }
+        assert_eq!(db.latest_height().await, 8);
+        assert_eq!(db.all_revisions().await, hashes.get(4..).unwrap());
+        let revision = db.revision_by_height(6).await.unwrap();
+        assert_eq!(
+            revision.root_hash().await.unwrap(),
+            hashes.get(5).map(|(_, hash)| hash.clone())
+        );
+
+        // too old, and not yet committed
+        for height in [4, 9] {
+            let err = db.revision_by_height(height).await.unwrap_err();
+            assert!(
+                matches!(
+                    err,
+                    Error::RevisionNotFound { height: h, oldest_available: 5 } if h == height
+                ),
+                "{err:?}"
+            );
+        }
+
+        // heights carry on after a reopen
+        let db = db.reopen_with(dbconfig).await;
+        assert_eq!(db.latest_height().await, 8);
+        assert_eq!(db.all_revisions().await, hashes.get(7..).unwrap());
+        put_all(&db, &[b"k"], b"v").await;
+        assert_eq!(db.latest_height().await, 9);
+        let revision = db.revision_by_height(9).await.unwrap();
+        assert_eq!(
+            revision.root_hash().await.unwrap(),
+            db.root_hash().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn revision_by_offset() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(4).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        for i in 0u32..8 {
+            put_all(&db, &[&i.to_be_bytes()], b"v").await;
+        }
+        let hashes = db.all_hashes().await.unwrap();
+        assert_eq!(hashes.len(), 4);
+
+        let tip = db.revision_by_offset(0).await.unwrap();
+        assert_eq!(
+            tip.root_hash().await.unwrap(),
+            db.root_hash().await.unwrap()
+        );
+        assert_eq!(tip.root_hash().await.unwrap().as_ref(), hashes.last());
+
+        let oldest = db.revision_by_offset(3).await.unwrap();
+        assert_eq!(oldest.root_hash().await.unwrap().as_ref(), hashes.first());
+        assert_eq!(
+            *oldest.val(0u32.to_be_bytes()).await.unwrap().unwrap(),
+            *b"v"
+        );
+        assert!(oldest.val(5u32.to_be_bytes()).await.unwrap().is_none());
+
+        let err = db.revision_by_offset(4).await.unwrap_err();
+        assert!(
+            matches!(
+                err,
+                Error::RevisionOutOfRange {
+                    back: 4,
+                    retained: 4
+                }
+            ),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_proposal_reads() {
+        let db = testdb().await;
+        let batch = vec![BatchOp::Put {
+            key: b"k",
+            value: b"v",
+        }];
+        let proposal = db.propose(batch).await.unwrap();
+        assert_eq!(&*proposal.val(b"k").await.unwrap().unwrap(), b"v");
+
+        assert_eq!(proposal.val(b"notfound").await.unwrap(), None);
+        proposal.commit().await.unwrap();
+
+        let batch = vec![BatchOp::Put {
+            key: b"k",
+            value: b"v2",
+        }];
+        let proposal = db.propose(batch).await.unwrap();
+        assert_eq!(&*proposal.val(b"k").await.unwrap().unwrap(), b"v2");
+
+        let committed = db.root_hash().await.unwrap().unwrap();
+        let historical = db.revision(committed).await.unwrap();
+        assert_eq!(&*historical.val(b"k").await.unwrap().unwrap(), b"v");
+    }
+
+    #[tokio::test]
+    async fn reopen_test() {
+        let db = testdb().await;
+        let batch = vec![BatchOp::Put {
             key: b"k",
             value: b"v",
         }];
-        let proposal = db.propose(batch).await.unwrap();
+        let proposal = db.propose(batch).await.unwrap();
+        proposal.commit().await.unwrap();
+        println!("{:?}", db.root_hash().await.unwrap().unwrap());
+
+        let db = db.reopen().await;
+        println!("{:?}", db.root_hash().await.unwrap().unwrap());
+        let committed = db.root_hash().await.unwrap().unwrap();
+        let historical = db.revision(committed).await.unwrap();
+        assert_eq!(&*historical.val(b"k").await.unwrap().unwrap(), b"v");
+    }
+
+    #[tokio::test]
+    async fn read_only() {
+        let db = testdb().await;
+        let first = commit_value(&db, b"1").await;
+        let read_only = DbConfig::builder().read_only(true).build();
+        let reader = Db::new(db.path(), read_only).await.unwrap();
+        assert_eq!(reader.root_hash().await.unwrap(), Some(first.clone()));
+
+        // the reader sees what the writer commits once it reloads
+        let second = commit_value(&db, b"2").await;
+        assert_eq!(reader.root_hash().await.unwrap(), Some(first.clone()));
+        let reloaded = reader.reload().await.unwrap();
+        assert_eq!(reloaded, Some(second.clone()));
+        assert_eq!(reader.latest_height().await, db.latest_height().await);
+        let latest = reader.revision(second.clone()).await.unwrap();
+        assert_eq!(&*latest.val(b"k").await.unwrap().unwrap(), b"2");
+        // the writer still retains the revision it had before
+        let earlier = reader.revision(first).await.unwrap();
+        assert_eq!(&*earlier.val(b"k").await.unwrap().unwrap(), b"1");
+
+        // nothing the reader does writes to the file
+        let contents = std::fs::read(db.path()).unwrap();
+        let batch = vec![BatchOp::Put {
+            key: b"k",
+            value: b"3",
+        }];
+        let err = reader.propose(batch).await.unwrap_err();
+        assert!(matches!(err, Error::ReadOnly), "{err:?}");
+        let err = reader.compact_with_history(1, None).await.unwrap_err();
+        assert!(matches!(err, Error::ReadOnly), "{err:?}");
+        assert_eq!(reader.reload().await.unwrap(), Some(second));
+        // and the file can't be truncated under it
+        let truncate = DbConfig::builder().truncate(true).build();
+        let err = Db::new(db.path(), truncate).await.unwrap_err();
+        assert!(
+            matches!(&err, Error::IO(err) if err.kind() == std::io::ErrorKind::WouldBlock),
+            "{err:?}"
+        );
+        drop(reader);
+        assert_eq!(std::fs::read(db.path()).unwrap(), contents);
+
+        let both = DbConfig::builder().read_only(true).truncate(true).build();
+        assert!(Db::new(db.path(), both).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn close() {
+        let db = testdb().await;
+        commit_value(&db, b"1").await;
+        let batch = vec![BatchOp::Put {
+            key: b"k",
+            value: b"2",
+        }];
+        let proposal = db.propose(batch).await.unwrap();
+        let committed = commit_value(&db, b"3").await;
+        db.close().await.unwrap();
+
+        let err = db.root_hash().await.unwrap_err();
+        assert!(matches!(err, Error::Closed), "{err:?}");
+        let err = proposal.commit().await.unwrap_err();
+        assert!(matches!(err, Error::Closed), "{err:?}");
+        let batch = vec![BatchOp::Put {
+            key: b"k",
+            value: b"4",
+        }];
+        let err = db.propose(batch).await.unwrap_err();
+        assert!(matches!(err, Error::Closed), "{err:?}");
+        let err = db.close().await.unwrap_err();
+        assert!(matches!(err, Error::Closed), "{err:?}");
+
+        let db = db.reopen().await;
+        assert_eq!(db.root_hash().await.unwrap(), Some(committed.clone()));
+        let revision = db.revision(committed).await.unwrap();
+        assert_eq!(&*revision.val(b"k").await.unwrap().unwrap(), b"3");
+    }
+
+    #[tokio::test]
+    async fn buffered_durability() {
+        let durability = DurabilityMode::Buffered {
+            commits: std::num::NonZero::new(3).unwrap(),
+            interval: std::time::Duration::from_secs(3600),
+        };
+        let manager = RevisionManagerConfig::builder()
+            .durability(durability)
+            .build();
+        let db = testdb()
+            .await
+            .reopen_with(DbConfig::builder().manager(manager).build())
+            .await;
+        let first = commit_value(&db, b"1").await;
+        db.sync().await.unwrap();
+        let read_only = DbConfig::builder().read_only(true).build();
+        let reader = Db::new(db.path(), read_only).await.unwrap();
+
+        // the root in the header only moves when the file is synced
+        commit_value(&db, b"2").await;
+        commit_value(&db, b"3").await;
+        assert_eq!(reader.reload().await.unwrap(), Some(first));
+        let fourth = commit_value(&db, b"4").await;
+        assert_eq!(reader.reload().await.unwrap(), Some(fourth.clone()));
+        let fifth = commit_value(&db, b"5").await;
+        assert_eq!(reader.reload().await.unwrap(), Some(fourth));
+        db.sync().await.unwrap();
+        assert_eq!(reader.reload().await.unwrap(), Some(fifth));
+        drop(reader);
+
+        // dropping the database syncs what was committed since
+        let sixth = commit_value(&db, b"6").await;
+        let db = db.reopen().await;
+        assert_eq!(db.root_hash().await.unwrap(), Some(sixth));
+    }
+
+    #[tokio::test]
+    async fn close_shared() {
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("testdb");
+        let config = || DbConfig::builder().truncate(true).build();
+        let db = Db::open_shared(&path, config()).await.unwrap();
+        let other = Db::open_shared(&path, config()).await.unwrap();
+        let committed = commit_value(&db, b"1").await;
+        db.close().await.unwrap();
+
+        // every handle to the instance is closed
+        let err = other.root_hash().await.unwrap_err();
+        assert!(matches!(err, Error::Closed), "{err:?}");
+        let err = Db::open_shared(&path, config()).await.unwrap_err();
+        assert!(matches!(err, Error::Closed), "{err:?}");
+
+        drop((db, other));
+        let config = DbConfig::builder().truncate(false).build();
+        let db = Db::open_shared(&path, config).await.unwrap();
+        assert_eq!(db.root_hash().await.unwrap(), Some(committed));
+    }
+
+    async fn put_all(db: &Db, keys: &[&[u8]], value: &[u8]) {
+        let batch = keys
+            .iter()
+            .map(|key| BatchOp::Put {
+                key: key.to_vec(),
+                value: value.to_vec(),
+            })
+            .collect();
+        db.propose(batch).await.unwrap().commit().await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_range() {
+        use crate::system::{encode_prefixes, FROZEN_PREFIXES};
+        use storage::ReadableStorage as _;
+
+        let config = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(2).build())
+            .build();
+        let ranged = testdb().await.reopen_with(config.clone()).await;
+        let single = testdb().await.reopen_with(config).await;
+        let keys: Vec<Vec<u8>> = (0u32..300)
+            .map(|i| format!("p/{i}").into_bytes())
+            .chain([b"p".to_vec(), b"q/1".to_vec(), b"r".to_vec()])
+            .collect();
+        for db in [&ranged, &single] {
+            let keys: Vec<&[u8]> = keys.iter().map(Vec::as_slice).collect();
+            put_all(db, &keys, b"v").await;
+            // a record in the reserved key space, which a range never deletes
+            let mut system = db.system_store().await;
+            system.put_system(FROZEN_PREFIXES, &[], &encode_prefixes(&[]));
+            let proposal = db.propose_with_system(Vec::<BatchOp<&[u8], &[u8]>>::new(), system);
+            proposal.await.unwrap().commit().await.unwrap();
+        }
+
+        // a put under the prefix later in the batch survives it
+        let deletes = |prefix: &[u8]| {
+            keys.iter()
+                .filter(|key| key.starts_with(prefix))
+                .map(|key| BatchOp::Delete { key: key.clone() })
+                .collect::<Vec<_>>()
+        };
+        let put = || BatchOp::Put {
+            key: b"p/new".to_vec(),
+            value: b"v".to_vec(),
+        };
+        let prefix = b"p/".to_vec();
+        let batch = vec![BatchOp::DeleteRange { prefix }, put()];
+        ranged.propose(batch).await.unwrap().commit().await.unwrap();
+        let mut batch = deletes(b"p/");
+        batch.push(put());
+        single.propose(batch).await.unwrap().commit().await.unwrap();
+        let hash = ranged.root_hash().await.unwrap();
+        assert_eq!(hash, single.root_hash().await.unwrap());
+        let latest = ranged.revision(hash.unwrap()).await.unwrap();
+        assert_eq!(&*latest.val(b"p/new").await.unwrap().unwrap(), b"v");
+        assert!(latest.val(b"p/1").await.unwrap().is_none());
+        assert!(latest.val(b"p").await.unwrap().is_some());
+        drop(latest);
+
+        // an empty prefix deletes every key but the reserved ones
+        let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![BatchOp::DeleteRange { prefix: b"" }];
+        ranged.propose(batch).await.unwrap().commit().await.unwrap();
+        let mut batch = deletes(b"");
+        batch.retain(|op| !matches!(op, BatchOp::Delete { key } if key.starts_with(b"p/")));
+        batch.push(BatchOp::Delete {
+            key: b"p/new".to_vec(),
+        });
+        single.propose(batch).await.unwrap().commit().await.unwrap();
+        assert_eq!(
+            ranged.root_hash().await.unwrap(),
+            single.root_hash().await.unwrap()
+        );
+        assert!(ranged
+            .get_system(FROZEN_PREFIXES, &[])
+            .await
+            .unwrap()
+            .is_some());
+
+        // the deleted nodes are reaped and reused like those of single deletes
+        for value in [b"1", b"2", b"3"] {
+            put_all(&ranged, &[b"p/again"], value).await;
+            put_all(&single, &[b"p/again"], value).await;
+        }
+        let size = |db: &Db| db.manager.try_read().unwrap().storage().size().unwrap();
+        assert_eq!(size(&ranged), size(&single));
+
+        // ranges can't cover frozen prefixes, or be in the reserved key space
+        ranged.freeze_prefix(b"q/").await.unwrap();
+        for prefix in [&b""[..], b"q", b"q/1"] {
+            let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![BatchOp::DeleteRange { prefix }];
+            let err = ranged.propose(batch).await.unwrap_err();
+            assert!(matches!(err, Error::FrozenPrefix { .. }), "{err:?}");
+        }
+        let batch: Vec<BatchOp<&[u8], &[u8]>> = vec![BatchOp::DeleteRange {
+            prefix: DEFAULT_SYSTEM_PREFIX,
+        }];
+        let err = ranged.propose(batch).await.unwrap_err();
+        assert!(matches!(err, Error::ReservedKey { .. }), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn drain_prefix() {
+        let db = testdb().await;
+        put_all(&db, &[b"q/1", b"q/2", b"q/3", b"q/4", b"r/1"], b"v").await;
+
+        let mut seen = vec![];
+        let (root, drained) = db
+            .drain_prefix(b"q/", usize::MAX, |key, _| {
+                seen.push(key.to_vec());
+                match key {
+                    b"q/2" => DrainDecision::KeepAndContinue,
+                    b"q/3" => DrainDecision::DeleteAndStop,
+                    _ => DrainDecision::DeleteAndContinue,
+                }
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            seen,
+            vec![b"q/1".to_vec(), b"q/2".to_vec(), b"q/3".to_vec()]
+        );
+        assert_eq!(drained, 2);
+        assert_eq!(root, db.root_hash().await.unwrap());
+
+        let latest = db.revision(root.unwrap()).await.unwrap();
+        assert_eq!(latest.val(b"q/1").await.unwrap(), None);
+        assert!(latest.val(b"q/2").await.unwrap().is_some());
+        assert_eq!(latest.val(b"q/3").await.unwrap(), None);
+        assert!(latest.val(b"q/4").await.unwrap().is_some());
+        assert!(latest.val(b"r/1").await.unwrap().is_some());
+
+        // max_items bounds how many entries are visited
+        let (_, drained) = db
+            .drain_prefix(b"q/", 1, |_, _| DrainDecision::DeleteAndContinue)
+            .await
+            .unwrap();
+        assert_eq!(drained, 1);
+    }
+
+    #[tokio::test]
+    async fn concurrent_drains() {
+        let db = testdb().await;
+        let a_keys: Vec<Vec<u8>> = (0..50u8).map(|i| vec![b'a', i]).collect();
+        let b_keys: Vec<Vec<u8>> = (0..50u8).map(|i| vec![b'b', i]).collect();
+        let mut keys: Vec<&[u8]> = a_keys.iter().chain(b_keys.iter()).map(|k| &k[..]).collect();
+        keys.push(b"c");
+        put_all(&db, &keys, b"v").await;
+
+        let drain = |prefix: &'static [u8]| {
+            let db = &*db;
+            async move {
+                let mut drained = 0;
+                for _ in 0..5 {
+                    drained += db
+                        .drain_prefix(prefix, 10, |_, _| DrainDecision::DeleteAndContinue)
+                        .await
+                        .unwrap()
+                        .1;
+                    tokio::task::yield_now().await;
+                }
+                drained
+            }
+        };
+        let (a, b) = tokio::join!(drain(b"a"), drain(b"b"));
+        assert_eq!((a, b), (50, 50));
+
+        let latest = db
+            .revision(db.root_hash().await.unwrap().unwrap())
+            .await
+            .unwrap();
+        for key in a_keys.iter().chain(b_keys.iter()) {
+            assert_eq!(latest.val(key).await.unwrap(), None);
+        }
+        assert!(latest.val(b"c").await.unwrap().is_some());
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn drain_conflict() {
+        let db = testdb().await;
+        put_all(&db, &[b"q/1", b"q/2"], b"v").await;
+
+        let err = db
+            .drain_prefix(b"q/", usize::MAX, |key, _| {
+                if key == b"q/2" {
+                    // a producer rewrites q/1 after it was read
+                    tokio::task::block_in_place(|| {
+                        tokio::runtime::Handle::current().block_on(put_all(&db, &[b"q/1"], b"new"))
+                    });
+                }
+                DrainDecision::DeleteAndContinue
+            })
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::Conflict { ref key } if &**key == b"q/1"),
+            "{err:?}"
+        );
+
+        // nothing was deleted, even after reopening
+        let db = db.reopen().await;
+        let latest = db
+            .revision(db.root_hash().await.unwrap().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(&*latest.val(b"q/1").await.unwrap().unwrap(), b"new");
+        assert_eq!(&*latest.val(b"q/2").await.unwrap().unwrap(), b"v");
+    }
+
+    /// Commit revisions 1..=10, each changing an unrelated key along with
+    /// the operation `op` returns for that revision, if any
+    async fn commit_history(db: &Db, op: impl Fn(u8) -> Option<BatchOp<Vec<u8>, Vec<u8>>>) {
+        for revision in 1..=10u8 {
+            let mut batch = vec![BatchOp::Put {
+                key: vec![b'o', revision],
+                value: vec![revision],
+            }];
+            batch.extend(op(revision));
+            db.propose(batch).await.unwrap().commit().await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn key_history() {
+        let db = testdb().await;
+        commit_history(&db, |revision| {
+            matches!(revision, 1 | 3 | 7).then(|| BatchOp::Put {
+                key: b"key".to_vec(),
+                value: vec![revision],
+            })
+        })
+        .await;
+
+        let (history, lookups) = db.key_history_with_lookups(b"key", 100).await.unwrap();
+        let values: Vec<_> = history.iter().map(|(_, value)| value.as_deref()).collect();
+        let expected: Vec<Option<&[u8]>> = (1..=10u8)
+            .rev()
+            .map(|revision| match revision {
+                1..=2 => Some(&[1][..]),
+                3..=6 => Some(&[3][..]),
+                _ => Some(&[7][..]),
+            })
+            // the empty revision the database was created with
+            .chain([None])
+            .collect();
+        assert_eq!(values, expected);
+        assert_eq!(
+            history.first().unwrap().0,
+            Some(db.root_hash().await.unwrap().unwrap())
+        );
+
+        // only the newest revision and the ones just before each change
+        // (revisions 6 and 2) were looked up from scratch
+        assert_eq!(lookups, 3);
+
+        let (history, _) = db.key_history_with_lookups(b"key", 4).await.unwrap();
+        assert_eq!(history.len(), 4);
+    }
+
+    #[tokio::test]
+    async fn key_history_created_and_deleted() {
+        let db = testdb().await;
+        commit_history(&db, |revision| match revision {
+            5 => Some(BatchOp::Put {
+                key: b"key".to_vec(),
+                value: b"v".to_vec(),
+            }),
+            8 => Some(BatchOp::Delete {
+                key: b"key".to_vec(),
+            }),
+            _ => None,
+        })
+        .await;
+
+        let history = db.key_history(b"key", 100).await.unwrap();
+        let values: Vec<_> = history.iter().map(|(_, value)| value.as_deref()).collect();
+        let expected: Vec<Option<&[u8]>> = (1..=10u8)
+            .rev()
+            .map(|revision| (5..8).contains(&revision).then_some(&b"v"[..]))
+            .chain([None])
+            .collect();
+        assert_eq!(values, expected);
+    }
+
+    #[tokio::test]
+    async fn key_history_through_empty_revision() {
+        use storage::Parentable as _;
+
+        let db = testdb().await;
+        let mut root_hashes = Vec::new();
+        for batch in [
+            vec![BatchOp::Put {
+                key: b"key".to_vec(),
+                value: b"a".to_vec(),
+            }],
+            vec![BatchOp::Delete {
+                key: b"key".to_vec(),
+            }],
+            vec![BatchOp::Put {
+                key: b"key".to_vec(),
+                value: b"b".to_vec(),
+            }],
+        ] {
+            db.propose(batch).await.unwrap().commit().await.unwrap();
+            let latest = db.manager.read().await.current_revision();
+            root_hashes.push(latest.kind.root_hash());
+        }
+        let [first, middle, last] = <[_; 3]>::try_from(root_hashes).unwrap();
+        assert_eq!(middle, None);
+
+        // the empty revision in the middle is still in the history
+        let history = db.key_history(b"key", 3).await.unwrap();
+        let expected = vec![
+            (last, Some(Box::from(&b"b"[..]))),
+            (None, None),
+            (first, Some(Box::from(&b"a"[..]))),
+        ];
+        assert_eq!(history, expected);
+    }
+
+    #[tokio::test]
+    async fn corrupt_node() {
+        use std::io::{Read, Seek, SeekFrom, Write};
+        use storage::{Child, Node, NodeReader as _};
+
+        let db = testdb().await;
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"v").await;
+
+        // the branch over keys 0 to 15 is 7 nibbles down the path of key 0
+        let revision = db.manager.read().await.current_revision();
+        let mut address = revision.root_address().unwrap();
+        let mut depth = 0;
+        let branch = loop {
+            let node = revision.read_node(address).unwrap();
+            if depth == 7 {
+                break node;
+            }
+            depth += node.partial_path().len() + 1;
+            let Node::Branch(branch) = &*node else {
+                panic!("key 0 is under a branch");
+            };
+            let Some(Child::AddressWithHash(child, _)) = branch.children.first().unwrap() else {
+                panic!("key 0 is under the first child");
+            };
+            address = *child;
+        };
+        let mut serialized = Vec::new();
+        branch.as_bytes(revision.node_format(), 0, &mut serialized);
+
+        // flip a bit of the last byte of the branch, in its last child's hash
+        let offset = address.get() + serialized.len() as u64 - 1;
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(db.path())
+            .unwrap();
+        let mut byte = [0];
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.read_exact(&mut byte).unwrap();
+        let [byte] = byte;
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[byte ^ 1]).unwrap();
+        drop(file);
+        db.shed_cache(1.0).await;
+
+        let revision = db
+            .revision(db.root_hash().await.unwrap().unwrap())
+            .await
+            .unwrap();
+        for (i, key) in keys.iter().enumerate() {
+            match revision.val(key).await {
+                Err(Error::CorruptNode {
+                    address: corrupt, ..
+                }) if i < 16 => assert_eq!(corrupt, address),
+                Ok(Some(value)) if i >= 16 => assert_eq!(&*value, b"v"),
+                other => panic!("unexpected read of key {i}: {other:?}"),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn verify() {
+        use crate::verify::VerifyError;
+        use std::io::{Seek, SeekFrom, Write};
+        use storage::NodeReader as _;
+
+        let config = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(2).build())
+            .build();
+        let db = testdb().await.reopen_with(config).await;
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        for value in [b"a", b"b", b"c", b"d"] {
+            put_all(&db, &key_refs, value).await;
+        }
+
+        let report = db.verify(VerifyOptions::default()).await.unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        let retained = db.manager.read().await.newest_revisions(usize::MAX).len();
+        assert_eq!(report.roots.len(), retained);
+        for root in &report.roots {
+            assert!(root.nodes > 0);
+        }
+        let latest = VerifyOptions {
+            latest_only: true,
+            ..Default::default()
+        };
+        assert_eq!(db.verify(latest).await.unwrap().roots.len(), 1);
+        let free_areas = VerifyOptions {
+            free_areas: true,
+            ..Default::default()
+        };
+        let report = db.verify(free_areas).await.unwrap();
+        assert!(report.is_ok(), "{:?}", report.errors);
+        assert!(report.free_areas > 0);
+
+        // flip a bit of the last byte of the root, in its last child's hash
+        let revision = db.manager.read().await.current_revision();
+        let address = revision.root_address().unwrap();
+        let mut serialized = Vec::new();
+        revision
+            .read_node(address)
+            .unwrap()
+            .as_bytes(revision.node_format(), 0, &mut serialized);
+        let offset = address.get() + serialized.len() as u64 - 1;
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(db.path())
+            .unwrap();
+        let last = serialized.last().copied().unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&[last ^ 1]).unwrap();
+        drop(file);
+
+        let report = db.verify(latest).await.unwrap();
+        assert_eq!(report.roots.first().unwrap().nodes, 0);
+        assert!(matches!(
+            report.errors.as_slice(),
+            [VerifyError::UnreadableNode { address: corrupt, .. }] if *corrupt == address
+        ));
+    }
+
+    #[tokio::test]
+    async fn snapshot_statistics() {
+        let db = testdb().await;
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"v").await;
+        let hash = db.root_hash().await.unwrap().unwrap();
+        let snapshot = db.snapshot(hash).await.unwrap();
+
+        let stats = snapshot.statistics().unwrap();
+        assert_eq!(stats.keys, 1000);
+        assert_eq!(stats.key_bytes, 4000);
+        assert_eq!(stats.value_bytes, 1000);
+        assert_eq!(stats.nodes(), stats.branches + stats.leaves);
+        assert_eq!(db.statistics(true).await.unwrap().latest, Some(stats));
+
+        let estimate = snapshot.estimate_statistics(2000).unwrap();
+        assert_eq!(estimate, snapshot.estimate_statistics(2000).unwrap());
+        assert!(
+            estimate.keys.abs_diff(stats.keys) < stats.keys / 4,
+            "{estimate:?}"
+        );
+        assert!(estimate.nodes().abs_diff(stats.nodes()) < stats.nodes() / 4);
+        assert!(estimate.max_depth <= stats.max_depth);
+
+        // with a single key, there is only one path
+        let db = testdb().await;
+        put_all(&db, &[b"one"], b"value").await;
+        let hash = db.root_hash().await.unwrap().unwrap();
+        let snapshot = db.snapshot(hash).await.unwrap();
+        assert_eq!(
+            snapshot.estimate_statistics(1).unwrap(),
+            snapshot.statistics().unwrap()
+        );
+
+        // the file ends inside the last area the header accounts for
+        let file_size = db.file_size().await.unwrap();
+        assert!(file_size > 0);
+        assert!(file_size <= db.statistics(false).await.unwrap().file_size);
+    }
+
+    #[tokio::test]
+    async fn statistics() {
+        let config = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(2).build())
+            .build();
+        let db = testdb().await.reopen_with(config).await;
+        let stats = db.statistics(true).await.unwrap();
+        let latest = stats.latest.unwrap();
+        assert_eq!(latest.keys, 0);
+        assert_eq!(latest.avg_depth, 0.0);
+
+        let keys: Vec<Vec<u8>> = (0..100u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        // reaping a revision frees the nodes the one after it replaced, so
+        // the fourth commit is the first to free any
+        for value in [&b"a"[..], b"bb", b"ccc", b"dddd"] {
+            put_all(&db, &key_refs, value).await;
+        }
+
+        let stats = db.statistics(false).await.unwrap();
+        assert_eq!(stats.revisions, db.revision_count().await);
+        assert!(stats.free_bytes > 0);
+        assert!(stats.allocated_bytes > 0);
+        assert!(stats.allocated_bytes + stats.free_bytes < stats.file_size);
+        assert_eq!(stats.free_lists, None);
+        assert_eq!(stats.latest, None);
+
+        let deep = db.statistics(true).await.unwrap();
+        assert_eq!(deep.file_size, stats.file_size);
+        let free_lists = deep.free_lists.unwrap();
+        let free_bytes: u64 = free_lists.iter().map(|class| class.bytes).sum();
+        assert_eq!(free_bytes, stats.free_bytes);
+        for class in &free_lists {
+            assert_eq!(class.bytes, class.areas * class.area_size);
+        }
+        assert!(free_lists
+            .windows(2)
+            .all(|pair| matches!(pair, [small, large] if small.area_size < large.area_size)));
+
+        let latest = deep.latest.unwrap();
+        assert_eq!(latest.keys, 100);
+        assert_eq!(latest.key_bytes, 400);
+        assert_eq!(latest.value_bytes, 400);
+        assert_eq!(latest.leaves, 100);
+        assert!(latest.branches > 0);
+        assert!(latest.max_depth > 1);
+        assert!(latest.avg_depth > 1.0 && latest.avg_depth <= latest.max_depth as f64);
+    }
+
+    #[tokio::test]
+    async fn diff() {
+        let db = testdb().await;
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"v").await;
+        let older = db.root_hash().await.unwrap().unwrap();
+
+        let batch = vec![
+            BatchOp::Put {
+                key: 7u32.to_be_bytes().to_vec(),
+                value: b"w".to_vec(),
+            },
+            BatchOp::Delete {
+                key: 500u32.to_be_bytes().to_vec(),
+            },
+        ];
+        db.propose(batch).await.unwrap().commit().await.unwrap();
+        let newer = db.root_hash().await.unwrap().unwrap();
+
+        // only the paths to the two changed keys are read
+        db.shed_cache(1.0).await;
+        let before = storage::ReadStats::current();
+        let changes = db.diff(older.clone(), newer.clone()).await.unwrap();
+        assert!(storage::ReadStats::current().since(&before).cache_misses < 20);
+        assert_eq!(
+            changes,
+            vec![
+                KeyChange::Modified {
+                    key: 7u32.to_be_bytes().into(),
+                    old: b"v"[..].into(),
+                    new: b"w"[..].into(),
+                },
+                KeyChange::Removed {
+                    key: 500u32.to_be_bytes().into(),
+                    old: b"v"[..].into(),
+                },
+            ]
+        );
+
+        let reverse = db.diff(newer, older.clone()).await.unwrap();
+        assert!(matches!(reverse.get(1), Some(KeyChange::Added { .. })));
+        assert!(db.diff(older.clone(), older).await.unwrap().is_empty());
+    }
+
+    /// The cache misses while reading every key of the latest revision
+    async fn cache_misses_reading(db: &Db, keys: &[Vec<u8>]) -> u64 {
+        let root = db.root_hash().await.unwrap().unwrap();
+        let revision = db.revision(root).await.unwrap();
+        let before = storage::ReadStats::current();
+        for key in keys {
+            assert!(revision.val(key).await.unwrap().is_some());
+        }
+        storage::ReadStats::current().since(&before).cache_misses
+    }
+
+    #[tokio::test]
+    async fn abandoned_proposals_leave_cache_alone() {
+        let db = testdb().await;
+        let keys: Vec<Vec<u8>> = (0..500u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"v").await;
+
+        let baseline = cache_misses_reading(&db, &keys).await;
+        let cached = db.manager.read().await.storage().cached_nodes();
+
+        // build a long chain of proposals, then abandon all of it
+        let mut proposal = db
+            .propose::<Vec<u8>, Vec<u8>>(Default::default())
+            .await
+            .unwrap();
+        for round in 0..20u32 {
+            let batch = (0..100u32)
+                .map(|i| BatchOp::Put {
+                    key: (round * 1000 + i + 1_000_000).to_be_bytes().to_vec(),
+                    value: b"abandoned".to_vec(),
+                })
+                .collect();
+            proposal = proposal.propose(batch).await.unwrap();
+        }
+        drop(proposal);
+
+        assert_eq!(db.manager.read().await.storage().cached_nodes(), cached);
+        assert_eq!(cache_misses_reading(&db, &keys).await, baseline);
+
+        // shedding the cache does evict committed nodes
+        assert_eq!(db.shed_cache(1.0).await, cached);
+        assert!(cache_misses_reading(&db, &keys).await > baseline);
+    }
+
+    fn authority_config(truncate: bool, max_revisions: usize) -> DbConfig {
+        DbConfig::builder()
+            .truncate(truncate)
+            .external_root_authority(true)
+            .manager(
+                RevisionManagerConfig::builder()
+                    .max_revisions(max_revisions)
+                    .build(),
+            )
+            .build()
+    }
+
+    async fn commit_value(db: &Db, value: &[u8]) -> TrieHash {
+        put_all(db, &[b"k"], value).await;
+        db.root_hash().await.unwrap().unwrap()
+    }
+
+    #[tokio::test]
+    async fn external_root_authority() {
+        let db = testdb()
+            .await
+            .reopen_with(authority_config(true, 128))
+            .await;
+        let a = commit_value(&db, b"a").await;
+        db.promote(a.clone()).await.unwrap();
+        let b = commit_value(&db, b"b").await;
+        let c = commit_value(&db, b"c").await;
+        assert_eq!(db.unpromoted_revisions().await, vec![b.clone(), c.clone()]);
+
+        // crash with b and c durable but not promoted
+        let db = db.reopen_with(authority_config(false, 128)).await;
+        assert_eq!(db.root_hash().await.unwrap(), Some(a.clone()));
+        assert_eq!(db.latest_height().await, 1);
+        assert_eq!(db.unpromoted_revisions().await, vec![b.clone(), c.clone()]);
+        let revision = db.revision(c.clone()).await.unwrap();
+        assert_eq!(&*revision.val(b"k").await.unwrap().unwrap(), b"c");
+
+        // promote b, which was committed before the reopen; c stays a candidate
+        db.promote(b.clone()).await.unwrap();
+        assert_eq!(db.root_hash().await.unwrap(), Some(b.clone()));
+        assert_eq!(db.unpromoted_revisions().await, vec![c.clone()]);
+        let db = db.reopen_with(authority_config(false, 128)).await;
+        assert_eq!(db.root_hash().await.unwrap(), Some(b.clone()));
+        assert_eq!(db.latest_height().await, 2);
+        let revision = db.revision(c.clone()).await.unwrap();
+        assert_eq!(revision.height(), 3);
+
+        // discard c, then commit and promote something new
+        db.discard_unpromoted(c.clone()).await.unwrap();
+        assert!(db.revision(c.clone()).await.is_err());
+        let d = commit_value(&db, b"d").await;
+        let err = db.discard_unpromoted(d.clone()).await.unwrap_err();
+        assert!(matches!(err, Error::CannotPromote { .. }), "{err:?}");
+        db.promote(d.clone()).await.unwrap();
+        let db = db.reopen_with(authority_config(false, 128)).await;
+        assert_eq!(db.root_hash().await.unwrap(), Some(d));
+        assert!(db.unpromoted_revisions().await.is_empty());
+
+        // without an authority, unpromoted revisions are dropped on open
+        let e = commit_value(&db, b"e").await;
+        assert_eq!(db.unpromoted_revisions().await, vec![e]);
+        let db = db.reopen().await;
+        assert!(db.unpromoted_revisions().await.is_empty());
+        let err = db.promote(b).await.unwrap_err();
+        assert!(matches!(err, Error::CannotPromote { .. }), "{err:?}");
+        let db = db.reopen_with(authority_config(false, 128)).await;
+        assert!(db.unpromoted_revisions().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn promoted_revision_is_not_reaped() {
+        let db = testdb().await.reopen_with(authority_config(true, 2)).await;
+        let promoted = commit_value(&db, b"promoted").await;
+        db.promote(promoted.clone()).await.unwrap();
+
+        // many more revisions than max_revisions, none of them promoted
+        let mut candidates = vec![];
+        for i in 0..6u8 {
+            candidates.push(commit_value(&db, &[i; 40]).await);
+        }
+        assert_eq!(db.unpromoted_revisions().await, candidates);
+
+        let db = db.reopen_with(authority_config(false, 2)).await;
+        assert_eq!(db.root_hash().await.unwrap(), Some(promoted.clone()));
+        let revision = db.revision(promoted.clone()).await.unwrap();
+        assert_eq!(&*revision.val(b"k").await.unwrap().unwrap(), b"promoted");
+        // a revision that is still held isn't reaped
+        drop(revision);
+        for (i, candidate) in candidates.iter().enumerate() {
+            let revision = db.revision(candidate.clone()).await.unwrap();
+            assert_eq!(&*revision.val(b"k").await.unwrap().unwrap(), &[i as u8; 40]);
+        }
+
+        // once something newer is promoted, older revisions are reaped
+        let newest = commit_value(&db, b"newest").await;
+        db.promote(newest.clone()).await.unwrap();
+        commit_value(&db, b"after").await;
+        assert!(db.revision(promoted).await.is_err());
+        assert!(db.revision(newest).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn too_many_unpromoted() {
+        let db = testdb()
+            .await
+            .reopen_with(authority_config(true, 128))
+            .await;
+        for i in 0..storage::MAX_UNPROMOTED {
+            commit_value(&db, &i.to_be_bytes()).await;
+        }
+        let proposal = db
+            .propose(vec![BatchOp::Put {
+                key: b"k",
+                value: b"one too many",
+            }])
+            .await
+            .unwrap();
+        let err = proposal.commit().await.unwrap_err();
+        assert!(matches!(err, Error::TooManyUnpromoted { .. }), "{err:?}");
+    }
+
+    fn system_key(suffix: &[u8]) -> Vec<u8> {
+        [DEFAULT_SYSTEM_PREFIX, suffix].concat()
+    }
+
+    #[tokio::test]
+    async fn reserved_key_rejected() {
+        let db = testdb().await;
+        for batch in [
+            vec![BatchOp::Put {
+                key: system_key(b"x"),
+                value: b"v".to_vec(),
+            }],
+            vec![BatchOp::Delete {
+                key: system_key(b""),
+            }],
+        ] {
+            let err = db.propose(batch).await.unwrap_err();
+            assert!(matches!(err, Error::ReservedKey { .. }), "{err:?}");
+        }
+
+        // nested proposals are checked too
+        let proposal = db
+            .propose::<Vec<u8>, Vec<u8>>(Default::default())
+            .await
+            .unwrap();
+        let err = proposal
+            .propose(vec![BatchOp::Put {
+                key: system_key(b"x"),
+                value: b"v".to_vec(),
+            }])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ReservedKey { .. }), "{err:?}");
+
+        // the prefix recorded at creation wins over a different configured one
+        let db = db
+            .reopen_with(
+                DbConfig::builder()
+                    .truncate(false)
+                    .system_prefix(Box::from(&b"sys"[..]))
+                    .system_keys(SystemKeys::Reject)
+                    .build(),
+            )
+            .await;
+        put_all(&db, &[b"sys/ok"], b"v").await;
+        let err = db
+            .propose(vec![BatchOp::<_, Vec<u8>>::Delete {
+                key: system_key(b"x"),
+            }])
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ReservedKey { .. }), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn hidden_system_keys() {
+        let db = testdb().await;
+        put_all(&db, &[&[0xff, 0x01], &[0xff, 0xff]], b"user").await;
+        let user_only = db.root_hash().await.unwrap();
+
+        let mut system = db.system_store().await;
+        system.put_system(1, b"cursor", b"42");
+        let batch = vec![BatchOp::Put {
+            key: b"a".to_vec(),
+            value: b"v".to_vec(),
+        }];
+        db.propose_with_system(batch, system)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap();
+        let key = db.system_store().await.key(1, b"cursor");
+
+        // system entries are part of the root hash, but invisible to users
+        let root = db.root_hash().await.unwrap().unwrap();
+        assert_ne!(Some(root.clone()), user_only);
+        let latest = db.revision(root.clone()).await.unwrap();
+        assert_eq!(latest.val(&key).await.unwrap(), None);
+        assert_eq!(&*latest.val(b"a").await.unwrap().unwrap(), b"v");
+        let values = latest.get_many([&*key, b"a", &*key]).await.unwrap();
+        let values: Vec<_> = values.iter().map(Option::as_deref).collect();
+        assert_eq!(values, vec![None, Some(b"v".as_slice()), None]);
+        assert_eq!(&*db.get_system(1, b"cursor").await.unwrap().unwrap(), b"42");
+        let proof = latest.single_key_proof(&key).await.unwrap();
+        proof.verify(&key, Some(b"42"), &root).unwrap();
+
+        let mut seen = vec![];
+        let (_, drained) = db
+            .drain_prefix(&[0xff], usize::MAX, |key, _| {
+                seen.push(key.to_vec());
+                DrainDecision::KeepAndContinue
+            })
+            .await
+            .unwrap();
+        assert_eq!(drained, 0);
+        assert_eq!(seen, vec![vec![0xff, 0x01], vec![0xff, 0xff]]);
+
+        // in Reject mode, system entries are visible but still read-only
+        let db = db
+            .reopen_with(
+                DbConfig::builder()
+                    .truncate(false)
+                    .system_keys(SystemKeys::Reject)
+                    .build(),
+            )
+            .await;
+        let latest = db.revision(root).await.unwrap();
+        assert_eq!(&*latest.val(&key).await.unwrap().unwrap(), b"42");
+        let values = latest.get_many([&key]).await.unwrap();
+        assert_eq!(values.first().unwrap().as_deref(), Some(b"42".as_slice()));
+        let err = db
+            .drain_prefix(&[0xff], usize::MAX, |_, _| DrainDecision::DeleteAndContinue)
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::ReservedKey { .. }), "{err:?}");
+    }
+
+    #[tokio::test]
+    async fn iterate_revision_and_proposal() {
+        use std::collections::BTreeMap;
+
+        async fn collect<
+            S: futures::Stream<Item = Result<(Box<[u8]>, Vec<u8>), crate::v2::api::Error>>,
+        >(
+            stream: S,
+        ) -> Vec<(Vec<u8>, Vec<u8>)> {
+            let kvs: Vec<_> = stream.map(|kv| kv.unwrap()).collect().await;
+            kvs.into_iter().map(|(k, v)| (k.to_vec(), v)).collect()
+        }
+
+        let db = testdb().await;
+        let mut expected: BTreeMap<Vec<u8>, Vec<u8>> = (0u32..2000)
+            .map(|i| {
+                (
+                    format!("k/{:x}", i * 7919 % 4096).into_bytes(),
+                    vec![i as u8],
+                )
+            })
+            .collect();
+        // user keys sharing the first bytes of the reserved prefix
+        expected.insert(vec![0xff, 0x01], b"user".to_vec());
+        expected.insert(vec![0xff, 0xff], b"user".to_vec());
+        let batch = expected
+            .iter()
+            .map(|(key, value)| BatchOp::Put {
+                key: key.clone(),
+                value: value.clone(),
+            })
+            .collect();
+        let mut system = db.system_store().await;
+        system.put_system(1, b"cursor", b"42");
+        db.propose_with_system(batch, system)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap();
+        let root = db.root_hash().await.unwrap().unwrap();
+        let revision = db.revision(root).await.unwrap();
+
+        let mut proposed = expected.clone();
+        proposed.insert(b"k/1".to_vec(), b"new".to_vec());
+        proposed.remove(b"k/2".as_slice());
+        let proposal = db
+            .propose(vec![
+                BatchOp::Put {
+                    key: b"k/1".to_vec(),
+                    value: b"new".to_vec(),
+                },
+                BatchOp::Delete {
+                    key: b"k/2".to_vec(),
+                },
+            ])
+            .await
+            .unwrap();
+
+        let keys: [&[u8]; 4] = [b"k/1", b"k/2", b"k/3", b"k/1"];
+        let values = proposal.get_many(keys).await.unwrap();
+        for (key, value) in keys.into_iter().zip(values) {
+            assert_eq!(value.as_deref(), proposed.get(key).map(Vec::as_slice));
+        }
+
+        let starts: [&[u8]; 5] = [b"", b"k/1", b"k/80", b"k/fff0", &[0xff, 0xfe]];
+        let prefixes: [&[u8]; 5] = [b"", b"k/1", b"k/a", b"k/10", &[0xff]];
+        for start in starts {
+            let from = |map: &BTreeMap<Vec<u8>, Vec<u8>>| -> Vec<_> {
+                let range = map.range(start.to_vec()..);
+                range.map(|(k, v)| (k.clone(), v.clone())).collect()
+            };
+            let stream = revision.iter_option(Some(start)).unwrap();
+            assert_eq!(collect(stream).await, from(&expected), "start {start:?}");
+            let stream = proposal.iter_option(Some(start)).unwrap();
+            assert_eq!(collect(stream).await, from(&proposed), "start {start:?}");
+        }
+        for prefix in prefixes {
+            let under = |map: &BTreeMap<Vec<u8>, Vec<u8>>| -> Vec<_> {
+                let range = map.range(prefix.to_vec()..);
+                range
+                    .take_while(|(k, _)| k.starts_with(prefix))
+                    .map(|(k, v)| (k.clone(), v.clone()))
+                    .collect()
+            };
+            let stream = revision.iter_prefix(prefix).unwrap();
+            assert_eq!(collect(stream).await, under(&expected), "prefix {prefix:?}");
+            let stream = proposal.iter_prefix(prefix).unwrap();
+            assert_eq!(collect(stream).await, under(&proposed), "prefix {prefix:?}");
+        }
+        for (start, end) in starts.into_iter().zip(prefixes.into_iter().rev()) {
+            let between = |map: &BTreeMap<Vec<u8>, Vec<u8>>| -> Vec<_> {
+                if start >= end {
+                    return vec![];
+                }
+                let range = map.range(start.to_vec()..end.to_vec());
+                range.map(|(k, v)| (k.clone(), v.clone())).collect()
+            };
+            let stream = revision.iter_range(start, end).unwrap();
+            assert_eq!(
+                collect(stream).await,
+                between(&expected),
+                "{start:?}..{end:?}"
+            );
+            let stream = proposal.iter_range(start, end).unwrap();
+            assert_eq!(
+                collect(stream).await,
+                between(&proposed),
+                "{start:?}..{end:?}"
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn frozen_prefixes() {
+        let db = testdb().await;
+        put_all(&db, &[b"a1", b"b1", b"b2"], b"v").await;
+        db.freeze_prefix(b"b").await.unwrap();
+        db.freeze_prefix(b"b").await.unwrap();
+        let frozen_root = db.root_hash().await.unwrap();
+        assert_eq!(
+            db.frozen_prefixes().await.unwrap(),
+            vec![Box::from(&b"b"[..])]
+        );
+
+        let assert_frozen = |err: Error, key: &[u8], index: usize| match err {
+            Error::FrozenPrefix {
+                prefix,
+                offending_key,
+                batch_index,
+            } => assert_eq!(
+                (&*prefix, &*offending_key, batch_index),
+                (&b"b"[..], key, index)
+            ),
+            err => panic!("{err:?}"),
+        };
+        let batch = || {
+            vec![
+                BatchOp::Put {
+                    key: b"a2",
+                    value: b"v",
+                },
+                BatchOp::Delete { key: b"b1" },
+            ]
+        };
+        let err = db.propose(batch()).await.unwrap_err();
+        assert_frozen(err, b"b1", 1);
+        let err = db.propose_iter(batch()).await.unwrap_err();
+        assert_frozen(err, b"b1", 1);
+        let err = db
+            .propose_with_hint(
+                vec![BatchOp::Put {
+                    key: b"b3",
+                    value: b"v",
+                }],
+                BatchOpHint::Append,
+            )
+            .await
+            .unwrap_err();
+        assert_frozen(err, b"b3", 0);
+        let mut unfrozen = batch();
+        unfrozen.truncate(1);
+        let proposal = db.propose(unfrozen).await.unwrap();
+        let err = proposal.clone().propose(batch()).await.unwrap_err();
+        assert_frozen(err, b"b1", 1);
+        let err = db
+            .drain_prefix(b"b", usize::MAX, |_, _| DrainDecision::DeleteAndContinue)
+            .await
+            .unwrap_err();
+        assert_frozen(err, b"b1", 0);
+
+        // other keys and reads are unaffected
+        drop(proposal);
+        put_all(&db, &[b"a2"], b"v").await;
+        let root = db.root_hash().await.unwrap().unwrap();
+        assert_ne!(Some(root.clone()), frozen_root);
+        let latest = db.revision(root).await.unwrap();
+        assert_eq!(&*latest.val(b"b1").await.unwrap().unwrap(), b"v");
+
+        let err = db.unfreeze_prefix(b"b", "unfreeze b").await.unwrap_err();
+        assert!(
+            matches!(err, Error::UnfreezeNotAcknowledged { .. }),
+            "{err:?}"
+        );
+        let db = db.reopen().await;
+        assert_eq!(db.frozen_prefixes().await.unwrap().len(), 1);
+        assert!(db
+            .propose(vec![BatchOp::<_, Vec<u8>>::Delete { key: b"b2" }])
+            .await
+            .is_err());
+
+        db.unfreeze_prefix(b"b", "unfreeze 62").await.unwrap();
+        assert!(db.frozen_prefixes().await.unwrap().is_empty());
+        db.propose(vec![BatchOp::<_, Vec<u8>>::Delete { key: b"b2" }])
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn proper_prefix_of_system_prefix() {
+        let db = testdb().await;
+        let shared = DEFAULT_SYSTEM_PREFIX.split_last().unwrap().1;
+        let keys: [&[u8]; 2] = [DEFAULT_SYSTEM_PREFIX.get(..2).unwrap(), shared];
+        put_all(&db, &keys, b"v").await;
+
+        let mut system = db.system_store().await;
+        system.put_system(1, b"k", b"s");
+        db.propose_with_system(Vec::<BatchOp<Vec<u8>, Vec<u8>>>::new(), system)
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap();
+
+        let latest = db
+            .revision(db.root_hash().await.unwrap().unwrap())
+            .await
+            .unwrap();
+        for key in keys {
+            assert_eq!(&*latest.val(key).await.unwrap().unwrap(), b"v");
+        }
+        let mut seen = vec![];
+        db.drain_prefix(shared, usize::MAX, |key, _| {
+            seen.push(key.to_vec());
+            DrainDecision::DeleteAndContinue
+        })
+        .await
+        .unwrap();
+        assert_eq!(seen, vec![shared.to_vec()]);
+        assert_eq!(&*db.get_system(1, b"k").await.unwrap().unwrap(), b"s");
+    }
+
+    #[tokio::test]
+    async fn batch_hints() {
+        let db = testdb().await;
+        put_all(&db, &[b"k/00", b"k/05"], b"v").await;
+
+        let batches: [&[&[u8]]; 4] = [
+            &[b"k/10", b"k/11", b"k/2"],
+            &[b"k/05/0", b"k/06"],
+            &[b"k/01", b"k/20"],
+            &[b"k/20", b"k/10"],
+        ];
+        for keys in batches {
+            let batch = || -> Vec<BatchOp<&[u8], &[u8]>> {
+                keys.iter()
+                    .map(|key| BatchOp::Put {
+                        key: *key,
+                        value: &b"new"[..],
+                    })
+                    .collect()
+            };
+            let expected = db
+                .propose_with_hint(batch(), BatchOpHint::Unordered)
+                .await
+                .unwrap();
+            let expected = expected.root_hash().await.unwrap();
+            for hint in [BatchOpHint::Detect, BatchOpHint::Append] {
+                let proposal = db.propose_with_hint(batch(), hint).await.unwrap();
+                assert_eq!(proposal.root_hash().await.unwrap(), expected, "{keys:?}");
+                for key in keys {
+                    assert_eq!(&*proposal.val(key).await.unwrap().unwrap(), b"new");
+                }
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn heal_paths_on_canonical_trie() {
+        let db = testdb().await;
+        put_all(&db, &[b"a", b"ab", b"abc", b"b"], b"v").await;
+        let root = db.root_hash().await.unwrap();
+
+        // inserts never leave anything to fold
+        let (proposal, stats) = db.heal_paths(usize::MAX, None).await.unwrap();
+        assert_eq!(stats, HealStats::default());
+        assert_eq!(proposal.root_hash().await.unwrap(), root);
+        assert!(db.operations().is_empty());
+
+        let token = CancellationToken::new();
+        token.cancel();
+        let err = db.heal_paths(usize::MAX, Some(token)).await.unwrap_err();
+        assert!(matches!(err, Error::Cancelled { .. }));
+    }
+
+    #[cfg(feature = "metrics")]
+    #[tokio::test]
+    async fn slow_operation_exemplars() {
+        use std::sync::atomic::{AtomicBool, Ordering};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        use futures::StreamExt as _;
+
+        use crate::latency::{key_prefix_hash, ApiMethod};
+        use crate::stream::MerkleKeyValueStream;
+
+        const DELAY: Duration = Duration::from_millis(30);
+
+        let db = testdb().await;
+        let keys: Vec<Vec<u8>> = (0..200u32)
+            .map(|i| format!("key{i:05}").into_bytes())
+            .collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"v").await;
+        let root = db.root_hash().await.unwrap().unwrap();
+
+        // start with a cold cache, and make reads slow while `slow` is set
+        let db = db.reopen().await;
+        let slow = Arc::new(AtomicBool::new(false));
+        let hook_slow = slow.clone();
+        db.manager
+            .read()
+            .await
+            .storage()
+            .set_read_hook(Some(Box::new(move |_, _| {
+                if hook_slow.load(Ordering::Relaxed) {
+                    std::thread::sleep(DELAY);
+                }
+            })));
+
+        // a mix of fast reads, proofs and commits, with four cold reads
+        // that hit slow storage
+        let revision = db.revision(root.clone()).await.unwrap();
+        for key in keys.iter().skip(4) {
+            revision.val(key).await.unwrap();
+        }
+        let (slow_gets, warm) = keys.split_at(3);
+        let (slow_proof, warm) = warm.split_first().unwrap();
+        revision
+            .single_key_proof(warm.last().unwrap())
+            .await
+            .unwrap();
+        slow.store(true, Ordering::Relaxed);
+        for key in slow_gets {
+            revision.val(key).await.unwrap();
+        }
+        revision.single_key_proof(slow_proof).await.unwrap();
+        slow.store(false, Ordering::Relaxed);
+        put_all(&db, &[b"other"], b"v").await;
+
+        let gets: Vec<_> = db
+            .slow_operations(ApiMethod::Get, 16)
+            .into_iter()
+            .filter(|exemplar| exemplar.root_hash.as_ref() == Some(&root))
+            .take(3)
+            .collect();
+        let mut slow_keys: Vec<_> = gets.iter().map(|e| e.key_prefix_hash).collect();
+        let mut expected: Vec<_> = slow_gets
+            .iter()
+            .map(|key| Some(key_prefix_hash(key)))
+            .collect();
+        slow_keys.sort();
+        expected.sort();
+        assert_eq!(slow_keys, expected);
+
+        let proof = db
+            .slow_operations(ApiMethod::Prove, 16)
+            .into_iter()
+            .find(|exemplar| exemplar.root_hash.as_ref() == Some(&root))
+            .unwrap();
+        assert_eq!(proof.key_prefix_hash, Some(key_prefix_hash(slow_proof)));
+
+        for exemplar in gets.iter().chain([&proof]) {
+            assert!(exemplar.duration >= DELAY, "{exemplar:?}");
+            assert!(exemplar.cache_misses >= 1, "{exemplar:?}");
+            assert!(exemplar.bytes_read > 0, "{exemplar:?}");
+        }
+        assert!(!db.slow_operations(ApiMethod::Commit, 16).is_empty());
+
+        // each step of an iteration is timed, with the key it returned; the
+        // first one reads the path to the first key from slow storage
+        drop(revision);
+        let db = db.reopen().await;
+        db.manager
+            .read()
+            .await
+            .storage()
+            .set_read_hook(Some(Box::new(|_, _| std::thread::sleep(DELAY))));
+        let latest = db.root_hash().await.unwrap().unwrap();
+        let revision = db.revision(latest).await.unwrap();
+        let mut stream = MerkleKeyValueStream::from(&*revision);
+        let (first, _) = stream.next().await.unwrap().unwrap();
+        let iteration = db
+            .slow_operations(ApiMethod::Iterate, 16)
+            .into_iter()
+            .find(|exemplar| exemplar.key_prefix_hash == Some(key_prefix_hash(&first)))
+            .unwrap();
+        assert!(iteration.duration >= DELAY, "{iteration:?}");
+    }
+
+    // Testdb is a helper struct for testing the Db. Once it's dropped, the directory and file disappear
+    #[tokio::test]
+    async fn audit_bundle() {
+        let db = testdb().await;
+        put_all(&db, &[b"a/1", b"b/1", b"c/1"], b"old").await;
+        let root_hash = db.root_hash().await.unwrap().unwrap();
+        put_all(&db, &[b"a/1"], b"new").await;
+
+        // an older retained revision can still be audited
+        let requests = vec![
+            AuditRequest::Key(b"a/1"[..].into()),
+            AuditRequest::Key(b"c/2"[..].into()),
+            AuditRequest::PrefixDigest(b"b/"[..].into()),
+        ];
+        let bundle = db.audit_bundle(root_hash.clone(), requests).await.unwrap();
+        verify_audit_bundle(&root_hash, &bundle).unwrap();
+        let mut results = bundle.items.into_iter().map(|item| item.result);
+        let old = AuditResult::Value(Some(b"old"[..].into()));
+        assert_eq!(results.next(), Some(old));
+        assert_eq!(results.next(), Some(AuditResult::Value(None)));
+
+        assert!(db.audit_bundle(TrieHash::default(), vec![]).await.is_err());
+    }
+
+    fn journal_config(truncate: bool, max_revisions: usize) -> DbConfig {
+        DbConfig::builder()
+            .truncate(truncate)
+            .retain_op_journal(true)
+            .manager(
+                RevisionManagerConfig::builder()
+                    .max_revisions(max_revisions)
+                    .build(),
+            )
+            .build()
+    }
+
+    /// Batches that a journal of net changes would not reproduce: repeated
+    /// keys, ops that cancel out, deletes of missing keys, empty keys and
+    /// values, and a key put many times
+    fn pathological_batches() -> Vec<Vec<BatchOp<Vec<u8>, Vec<u8>>>> {
+        let put = |key: &[u8], value: &[u8]| BatchOp::Put {
+            key: key.to_vec(),
+            value: value.to_vec(),
+        };
+        let delete = |key: &[u8]| BatchOp::Delete { key: key.to_vec() };
+        vec![
+            vec![
+                put(b"a", b"1"),
+                put(b"a", b"1"),
+                put(b"a", b"2"),
+                put(b"", b""),
+            ],
+            vec![
+                delete(b"a"),
+                put(b"a", b"3"),
+                delete(b"missing"),
+                put(b"b", b""),
+            ],
+            vec![
+                put(b"c", b"x"),
+                delete(b"c"),
+                delete(b"c"),
+                put(b"d", &[0; 4096]),
+            ],
+            (0..1000u32)
+                .map(|i| put(b"hot", &i.to_be_bytes()))
+                .chain([delete(b"b"), put(&[0xff; 300], b"long")])
+                .collect(),
+        ]
+    }
+
+    /// An op of a batch or journal, owning its bytes so they can be compared
+    #[derive(Debug, PartialEq)]
+    enum JournalOp {
+        Put(Vec<u8>, Vec<u8>),
+        Delete(Vec<u8>),
+        DeleteRange(Vec<u8>),
+    }
+
+    fn journal_ops<K: KeyType, V: ValueType>(batch: &[BatchOp<K, V>]) -> Vec<JournalOp> {
+        batch
+            .iter()
+            .map(|op| match op {
+                BatchOp::Put { key, value } => {
+                    JournalOp::Put(key.as_ref().to_vec(), value.as_ref().to_vec())
+                }
+                BatchOp::Delete { key } => JournalOp::Delete(key.as_ref().to_vec()),
+                BatchOp::DeleteRange { prefix } => JournalOp::DeleteRange(prefix.as_ref().to_vec()),
+            })
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn op_journal() {
+        let plain = testdb().await;
+        let db = testdb().await.reopen_with(journal_config(true, 128)).await;
+
+        let mut hashes = Vec::new();
+        for batch in pathological_batches() {
+            let expected = journal_ops(&batch);
+            let copy = batch
+                .iter()
+                .map(|op| match op {
+                    BatchOp::Put { key, value } => BatchOp::Put {
+                        key: key.clone(),
+                        value: value.clone(),
+                    },
+                    BatchOp::Delete { key } => BatchOp::Delete { key: key.clone() },
+                    BatchOp::DeleteRange { prefix } => BatchOp::DeleteRange {
+                        prefix: prefix.clone(),
+                    },
+                })
+                .collect();
+            plain.propose(copy).await.unwrap().commit().await.unwrap();
+            db.propose(batch).await.unwrap().commit().await.unwrap();
+
+            // journaling doesn't change the root hash
+            let hash = db.root_hash().await.unwrap().unwrap();
+            assert_eq!(Some(&hash), plain.root_hash().await.unwrap().as_ref());
+            let journal = db.op_journal(&hash).await.unwrap().unwrap();
+            assert_eq!(journal_ops(&journal), expected);
+            hashes.push(hash);
+        }
+        let latest = db.revision(hashes.last().unwrap().clone()).await.unwrap();
+        let hot = latest.val(b"hot").await.unwrap().unwrap();
+        assert_eq!(*hot, 999u32.to_be_bytes());
+        assert_eq!(plain.op_journal_bytes().await.unwrap(), 0);
+        assert!(db.op_journal_bytes().await.unwrap() > 0);
+
+        // proposals on proposals are journaled too
+        let parent = db
+            .propose(vec![BatchOp::Put {
+                key: b"e",
+                value: b"1",
+            }])
+            .await
+            .unwrap();
+        let child = parent
+            .clone()
+            .propose::<_, &[u8]>(vec![
+                BatchOp::Delete { key: b"e" },
+                BatchOp::Delete { key: b"e" },
+            ])
+            .await
+            .unwrap();
+        parent.commit().await.unwrap();
+        child.commit().await.unwrap();
+        // the child undoes its parent, so the root hash is back to that of
+        // the last batch, and the journal is that of the newest revision
+        let hash = db.root_hash().await.unwrap().unwrap();
+        assert_eq!(Some(&hash), hashes.last());
+        let journal = db.op_journal(&hash).await.unwrap().unwrap();
+        assert_eq!(
+            journal_ops(&journal),
+            [
+                JournalOp::Delete(b"e".to_vec()),
+                JournalOp::Delete(b"e".to_vec())
+            ]
+        );
+
+        // only the latest revision survives a reopen
+        let db = db.reopen_with(journal_config(false, 128)).await;
+        let journal = db.op_journal(&hash).await.unwrap().unwrap();
+        assert_eq!(journal.len(), 2);
+        for old in hashes.iter().take(hashes.len() - 1) {
+            assert!(db.op_journal(old).await.unwrap().is_none());
+        }
+
+        let db = db.reopen().await;
+        assert!(db.op_journal(&hash).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn propose_iter() {
+        let batched = testdb().await;
+        let db = testdb().await.reopen_with(journal_config(true, 128)).await;
+
+        let appends = (0..100u32).map(|i| BatchOp::Put {
+            key: i.to_be_bytes().to_vec(),
+            value: b"v".to_vec(),
+        });
+        let ranges = vec![
+            BatchOp::DeleteRange {
+                prefix: b"\0\0".to_vec(),
+            },
+            BatchOp::Put {
+                key: b"\0\0\0\x01".to_vec(),
+                value: b"back".to_vec(),
+            },
+        ];
+        let batches = [appends.collect(), ranges]
+            .into_iter()
+            .chain(pathological_batches());
+        for batch in batches {
+            let expected = journal_ops(&batch);
+            // the ops are applied as the iterator yields them. Values must
+            // be 'static, so they are copied, while keys borrow the batch.
+            let ops = batch.iter().map(|op| match op {
+                BatchOp::Put { key, value } => BatchOp::Put {
+                    key: key.as_slice(),
+                    value: value.clone(),
+                },
+                BatchOp::Delete { key } => BatchOp::Delete {
+                    key: key.as_slice(),
+                },
+                BatchOp::DeleteRange { prefix } => BatchOp::DeleteRange {
+                    prefix: prefix.as_slice(),
+                },
+            });
+            let proposal = db.propose_iter(ops).await.unwrap();
+            let hash = proposal.commit().await.unwrap().unwrap();
+            batched
+                .propose(batch)
+                .await
+                .unwrap()
+                .commit()
+                .await
+                .unwrap();
+            assert_eq!(Some(&hash), batched.root_hash().await.unwrap().as_ref());
+            let journal = db.op_journal(&hash).await.unwrap().unwrap();
+            assert_eq!(journal_ops(&journal), expected);
+        }
+    }
+
+    #[tokio::test]
+    async fn operational_snapshots() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(
+                RevisionManagerConfig::builder()
+                    .max_revisions(4)
+                    .retain_operational_snapshots(true)
+                    .build(),
+            )
+            .build();
+        let db = testdb().await.reopen_with(dbconfig.clone()).await;
+
+        let mut hashes = Vec::new();
+        for i in 0u32..12 {
+            let outstanding = db
+                .propose(vec![BatchOp::Put {
+                    key: b"unused",
+                    value: b"",
+                }])
+                .await
+                .unwrap();
+            put_all(&db, &[&i.to_be_bytes(), &(i + 1000).to_be_bytes()], b"v").await;
+            drop(outstanding);
+            hashes.push(db.root_hash().await.unwrap().unwrap());
+        }
+
+        let (reaped, retained) = hashes.split_at(hashes.len() - 4);
+        for hash in reaped {
+            assert!(db.operational_snapshot(hash).await.unwrap().is_none());
+        }
+        let mut previous: Option<crate::snapshot::OperationalSnapshot> = None;
+        for hash in retained {
+            let snapshot = db.operational_snapshot(hash).await.unwrap().unwrap();
+            assert_eq!(snapshot.proposals, 2, "{snapshot:?}");
+            assert_eq!(snapshot.reap_backlog, 0);
+            assert!(snapshot.flush_nodes > std::time::Duration::ZERO);
+            assert!(snapshot
+                .cache_hit_rate()
+                .is_none_or(|rate| (0.0..=1.0).contains(&rate)));
+            // inserts only free the nodes they replace, once those are reaped
+            assert!(snapshot.free_bytes > 0);
+            if let Some(previous) = previous {
+                assert_eq!(snapshot.commit_sequence, previous.commit_sequence + 1);
+                assert!(snapshot.store_size >= previous.store_size);
+            }
+            previous = Some(snapshot);
+        }
+        let last = previous.unwrap();
+        assert_eq!(last.commit_sequence, 12);
+        assert!(last.store_size > 2048);
+
+        // snapshots aren't part of the root hash
+        let plain = testdb().await;
+        for i in 0u32..12 {
+            put_all(&plain, &[&i.to_be_bytes(), &(i + 1000).to_be_bytes()], b"v").await;
+        }
+        assert_eq!(plain.root_hash().await.unwrap().as_ref(), hashes.last());
+        assert!(plain
+            .operational_snapshot(hashes.last().unwrap())
+            .await
+            .unwrap()
+            .is_none());
+
+        // the latest revision's snapshot survives a reopen
+        let hash = hashes.last().unwrap();
+        let db = db.reopen_with(dbconfig).await;
+        assert_eq!(db.operational_snapshot(hash).await.unwrap(), Some(last));
+    }
+
+    #[tokio::test]
+    async fn op_journal_reaped() {
+        let db = testdb().await.reopen_with(journal_config(true, 2)).await;
+        let journal_dir = PathBuf::from(format!("{}.journal", db.path().display()));
+
+        let mut hashes = Vec::new();
+        for batch in pathological_batches() {
+            db.propose(batch).await.unwrap().commit().await.unwrap();
+            hashes.push(db.root_hash().await.unwrap().unwrap());
+        }
+
+        let retained = db.all_hashes().await.unwrap();
+        assert_eq!(retained.len(), 2);
+        for hash in &hashes {
+            let journal = db.op_journal(hash).await.unwrap();
+            assert_eq!(journal.is_some(), retained.contains(hash));
+        }
+        assert_eq!(std::fs::read_dir(&journal_dir).unwrap().count(), 2);
+
+        // heal_paths has no batch to journal
+        let (proposal, _) = db.heal_paths(usize::MAX, None).await.unwrap();
         proposal.commit().await.unwrap();
-        println!("{:?}", db.root_hash().await.unwrap().unwrap());
+        assert_eq!(std::fs::read_dir(&journal_dir).unwrap().count(), 1);
+
+        // truncating discards the journals
+        let db = db.reopen_with(journal_config(true, 2)).await;
+        assert_eq!(std::fs::read_dir(&journal_dir).unwrap().count(), 0);
+        assert_eq!(db.op_journal_bytes().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn reserved_allocation() {
+        let config = |truncate| {
+            DbConfig::builder()
+                .truncate(truncate)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .max_revisions(2)
+                        .allocation_policy(AllocationPolicy::Reserve)
+                        .build(),
+                )
+                .build()
+        };
+        let db = testdb().await.reopen_with(config(true)).await;
+
+        // overwriting the same keys frees areas for later reservations
+        let batch = |round: u8| -> Vec<BatchOp<Vec<u8>, Vec<u8>>> {
+            (0..50u8)
+                .map(|i| BatchOp::Put {
+                    key: vec![i, i],
+                    value: vec![round; 1 + (i as usize * round as usize) % 300],
+                })
+                .collect()
+        };
+        for round in 0..10 {
+            let proposal = db.propose(batch(round)).await.unwrap();
+            // an abandoned child gives back its reservation
+            drop(proposal.clone().propose(batch(100)).await.unwrap());
+            proposal.commit().await.unwrap();
+        }
+        let root_hash = db.root_hash().await.unwrap().unwrap();
+
+        let db = db.reopen_with(config(false)).await;
+        assert_eq!(db.root_hash().await.unwrap(), Some(root_hash.clone()));
+        let revision = db.revision(root_hash).await.unwrap();
+        for op in batch(9) {
+            let BatchOp::Put { key, value } = op else {
+                unreachable!()
+            };
+            assert_eq!(&*revision.val(&key).await.unwrap().unwrap(), &*value);
+        }
+    }
+
+    #[tokio::test]
+    async fn parallel_hashing() {
+        // 50,000 ops over spread out keys, deleting some of the keys the
+        // round before wrote
+        let batch = |round: u32| -> Vec<BatchOp<[u8; 4], Vec<u8>>> {
+            (0..50_000u32)
+                .map(|i| {
+                    let key = i.wrapping_mul(2_654_435_761).to_be_bytes();
+                    match (i + round) % 7 {
+                        0 if round > 0 => BatchOp::Delete { key },
+                        _ => BatchOp::Put {
+                            key,
+                            value: vec![round as u8; 1 + (i as usize % 40)],
+                        },
+                    }
+                })
+                .collect()
+        };
+        let mut results = Vec::new();
+        for threads in [1, 8] {
+            let config = DbConfig::builder()
+                .truncate(true)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .hash_threads(std::num::NonZero::new(threads).unwrap())
+                        .build(),
+                )
+                .build();
+            let db = testdb().await.reopen_with(config).await;
+            let mut hashes = Vec::new();
+            for round in 0..2 {
+                let proposal = db.propose(batch(round)).await.unwrap();
+                let root_hash = proposal.root_hash().await.unwrap();
+                assert_eq!(proposal.commit().await.unwrap(), root_hash);
+                hashes.push(root_hash);
+            }
+            results.push((hashes, db.file_size().await.unwrap()));
+        }
+        // the same hashes, and the nodes were allocated in the same order
+        let [serial, parallel] = results.as_slice() else {
+            unreachable!()
+        };
+        assert_eq!(serial, parallel);
+    }
+
+    #[tokio::test]
+    async fn parallel_flush() {
+        // 20,000 spread out keys are well over PARALLEL_FLUSH_THRESHOLD nodes
+        let batch = |round: u32| -> Vec<BatchOp<[u8; 4], Vec<u8>>> {
+            (0..20_000u32)
+                .map(|i| BatchOp::Put {
+                    key: i.wrapping_mul(2_654_435_761).to_be_bytes(),
+                    value: vec![round as u8; 1 + (i as usize % 40)],
+                })
+                .collect()
+        };
+        let mut results = Vec::new();
+        for threads in [1, 4] {
+            let config = |truncate| {
+                DbConfig::builder()
+                    .truncate(truncate)
+                    .manager(
+                        RevisionManagerConfig::builder()
+                            .flush_parallelism(std::num::NonZero::new(threads).unwrap())
+                            .build(),
+                    )
+                    .build()
+            };
+            let db = testdb().await.reopen_with(config(true)).await;
+            let mut hashes = Vec::new();
+            for round in 0..2 {
+                let proposal = db.propose(batch(round)).await.unwrap();
+                hashes.push(proposal.commit().await.unwrap());
+            }
+
+            // every node made it to disk
+            let db = db.reopen_with(config(false)).await;
+            let report = db.verify(VerifyOptions::default()).await.unwrap();
+            assert!(report.is_ok(), "{:?}", report.errors);
+            let revision = db.revision_by_offset(0).await.unwrap();
+            results.push((hashes, entries(&revision).await));
+        }
+        let [serial, parallel] = results.as_slice() else {
+            unreachable!()
+        };
+        assert_eq!(serial, parallel);
+    }
+
+    #[tokio::test]
+    async fn coalesced_flush() {
+        let mut results = Vec::new();
+        // every area on its own, small writes, and the default
+        for max_flush_write in [0, 4096, storage::DEFAULT_MAX_FLUSH_WRITE] {
+            let config = |truncate| {
+                DbConfig::builder()
+                    .truncate(truncate)
+                    .manager(
+                        RevisionManagerConfig::builder()
+                            .max_revisions(2)
+                            .max_flush_write(max_flush_write)
+                            .build(),
+                    )
+                    .build()
+            };
+            let db = testdb().await.reopen_with(config(true)).await;
+            // Reaping frees the areas of the nodes each round replaced, and
+            // the next rounds reuse them, so their new nodes are scattered
+            // between the live ones.
+            let hashes = churn(&db).await;
+            let expected = entries(&db.revision_by_offset(0).await.unwrap()).await;
+
+            let db = db.reopen_with(config(false)).await;
+            assert_eq!(db.root_hash().await.unwrap(), *hashes.last().unwrap());
+            let report = db.verify(VerifyOptions::default()).await.unwrap();
+            assert!(report.is_ok(), "{:?}", report.errors);
+            let revision = db.revision_by_offset(0).await.unwrap();
+            assert_eq!(entries(&revision).await, expected);
+            results.push(hashes);
+        }
+        assert!(results.windows(2).all(|pair| pair.first() == pair.last()));
+    }
+
+    #[tokio::test]
+    async fn read_ahead() {
+        use crate::manager::ReadAhead;
+
+        let keys: Vec<[u8; 4]> = (0..3000u32)
+            .map(|i| i.wrapping_mul(0x9e37_79b9).to_be_bytes())
+            .collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let config = |truncate| {
+            DbConfig::builder()
+                .truncate(truncate)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .max_revisions(2)
+                        .node_cache_size(std::num::NonZero::new(64).unwrap())
+                        .read_ahead(ReadAhead { width: 4, depth: 2 })
+                        .build(),
+                )
+                .build()
+        };
+        let db = testdb().await.reopen_with(config(true)).await;
+        put_all(&db, &key_refs, b"round 0").await;
+
+        // iterating with a cold cache reads every node, most of them ahead
+        let db = db.reopen_with(config(false)).await;
+        let mut expected: Vec<_> = keys
+            .iter()
+            .map(|key| (Box::from(&key[..]), b"round 0".to_vec()))
+            .collect();
+        expected.sort();
+        assert_eq!(
+            entries(&db.revision_by_offset(0).await.unwrap()).await,
+            expected
+        );
+
+        // commits reuse the areas of reaped nodes, which may have been read
+        // ahead; none of those is read back instead of the new node
+        for round in 1..4 {
+            let value = format!("round {round}").into_bytes();
+            put_all(&db, &key_refs, &value).await;
+            let revision = db.revision_by_offset(0).await.unwrap();
+            let found = entries(&revision).await;
+            assert_eq!(found.len(), keys.len());
+            assert!(found.iter().all(|(_, found)| *found == value));
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_resistant_cache() {
+        use crate::manager::NodeCachePolicy;
+
+        let keys: Vec<[u8; 4]> = (0..2000u32)
+            .map(|i| i.wrapping_mul(0x9e37_79b9).to_be_bytes())
+            .collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        let hot = keys.get(..16).unwrap();
 
+        // the share of the hot keys' node lookups that hit the cache after
+        // a scan of the whole trie, with each policy
+        let mut hit_rates = Vec::new();
+        for policy in [
+            NodeCachePolicy::Lru,
+            NodeCachePolicy::Segmented {
+                protected_fraction: 0.5,
+            },
+        ] {
+            let config = DbConfig::builder()
+                .truncate(true)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .node_cache_size(std::num::NonZero::new(256).unwrap())
+                        .node_cache_policy(policy)
+                        .build(),
+                )
+                .build();
+            let db = testdb().await.reopen_with(config).await;
+            put_all(&db, &key_refs, b"value").await;
+
+            // the hot keys are looked up before the scan, and again after
+            db.warm_cache(hot).await.unwrap();
+            db.warm_cache(hot).await.unwrap();
+            let revision = db.revision_by_offset(0).await.unwrap();
+            assert_eq!(entries(&revision).await.len(), keys.len());
+            let (hits, misses) = db.node_cache_lookups().await;
+            for key in hot {
+                assert!(revision.val(key).await.unwrap().is_some());
+            }
+            let (after_hits, after_misses) = db.node_cache_lookups().await;
+            let (hits, misses) = (after_hits - hits, after_misses - misses);
+            hit_rates.push(hits as f64 / (hits + misses) as f64);
+
+            let usage = db.cache_usage().await;
+            assert_eq!(usage.capacity, 256);
+            assert!(usage.entries > 0 && usage.entries <= usage.capacity);
+            assert!(usage.bytes > usage.entries);
+        }
+        let (lru, segmented) = (hit_rates.first().unwrap(), hit_rates.last().unwrap());
+        // the scan flushes the hot nodes out of the single list, but not
+        // out of the protected segment. With LRU, the hot keys still hit
+        // the nodes near the root that the lookups before them read again.
+        assert!(*segmented > 0.9, "{hit_rates:?}");
+        assert!(segmented - lru > 0.4, "{hit_rates:?}");
+    }
+
+    #[tokio::test]
+    async fn warm_cache_prefix() {
+        let config = |truncate| {
+            DbConfig::builder()
+                .truncate(truncate)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .node_cache_policy(crate::manager::NodeCachePolicy::Lru)
+                        .build(),
+                )
+                .build()
+        };
+        let db = testdb().await.reopen_with(config(true)).await;
+        let keys: Vec<Vec<u8>> = (0..50u8).flat_map(|i| [vec![1, i], vec![2, i]]).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"value").await;
+        let db = db.reopen_with(config(false)).await;
+
+        // the 50 leaves under the prefix and the branches above them
+        let warmed = db.warm_cache_prefix(&[1]).await.unwrap();
+        assert!((51..=55).contains(&warmed), "{warmed}");
+        assert!(db.cache_usage().await.entries >= warmed);
+        assert_eq!(db.warm_cache_prefix(&[3]).await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn free_list_corruption() {
+        use std::io::{Seek, SeekFrom, Write};
+
+        // every new node is allocated from the free lists on its own
+        let config = |truncate| {
+            DbConfig::builder()
+                .truncate(truncate)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .max_revisions(2)
+                        .allocation_policy(AllocationPolicy::Scatter)
+                        .build(),
+                )
+                .build()
+        };
+        let db = testdb().await.reopen_with(config(true)).await;
+        let hashes = churn(&db).await;
+        let expected = entries(&db.revision_by_offset(0).await.unwrap()).await;
+        // reopening empties the free list cache, so the heads are read
+        let db = db.reopen_with(config(false)).await;
+
+        // mark the head of every free list as a node
+        let revision = db.manager.read().await.current_revision();
+        let mut heads = Vec::new();
+        for area in revision.free_list_areas().unwrap() {
+            if heads.last().is_none_or(|(_, size)| *size != area.size) {
+                heads.push((area.address, area.size));
+            }
+        }
+        assert!(!heads.is_empty());
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .open(db.path())
+            .unwrap();
+        for (address, _) in &heads {
+            file.seek(SeekFrom::Start(address.get() + 1)).unwrap();
+            file.write_all(&[0]).unwrap();
+        }
+        drop(file);
+
+        let batch = vec![BatchOp::Put {
+            key: b"new",
+            value: b"value",
+        }];
+        match db.propose(batch).await {
+            Err(Error::FreeListCorruption { address, .. }) => {
+                assert!(heads.iter().any(|(head, _)| *head == address));
+            }
+            other => panic!("expected a corrupt free list, got {other:?}"),
+        }
+        // nothing was written, so the latest revision is intact
+        assert_eq!(db.root_hash().await.unwrap(), *hashes.last().unwrap());
+        let revision = db.revision_by_offset(0).await.unwrap();
+        assert_eq!(entries(&revision).await, expected);
+    }
+
+    #[tokio::test]
+    async fn subscribe() {
+        let db = testdb().await;
+        let mut events = db.subscribe().await;
+        let mut hashes = Vec::new();
+        for key in 0..3u8 {
+            let batch = vec![BatchOp::Put {
+                key: [key],
+                value: b"v",
+            }];
+            let root_hash = db.propose(batch).await.unwrap().commit().await.unwrap();
+            hashes.push(root_hash);
+        }
+        for (sequence, root_hash) in (1..).zip(hashes) {
+            let event = events.recv().await.unwrap();
+            assert_eq!(event.sequence, sequence);
+            assert_eq!(event.root_hash, root_hash);
+        }
+        assert!(events.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn consistency_tokens() {
+        let db = testdb().await;
+        let empty = db.consistency_token().await;
+        put_all(&db, &[b"balance", b"nonce"], b"1").await;
+        let token = db.consistency_token().await;
+        assert_eq!(token.epoch(), empty.epoch() + 1);
+
+        // a request whose reads are split around a commit sees one revision
+        let balance = db.get_with_token(&token, b"balance").await.unwrap();
+        put_all(&db, &[b"balance", b"nonce"], b"2").await;
+        let nonce = db.get_with_token(&token, b"nonce").await.unwrap();
+        assert_eq!(balance, nonce);
+        assert_eq!(&*nonce.unwrap(), b"1");
+
+        let values = db
+            .get_many_with_token(&token, &[&b"balance"[..], b"nonce", b"missing"])
+            .await
+            .unwrap();
+        let values: Vec<_> = values.iter().map(|value| value.as_deref()).collect();
+        assert_eq!(values, [Some(&b"1"[..]), Some(b"1"), None]);
+
+        let proof = db.prove_with_token(&token, b"nonce").await.unwrap();
+        proof
+            .verify(b"nonce", Some(b"1"), &token.root_hash().unwrap())
+            .unwrap();
+
+        assert_eq!(db.get_with_token(&empty, b"nonce").await.unwrap(), None);
+
+        // the token only carries plain data
+        let decoded = ConsistencyToken::from_bytes(&token.to_bytes()).unwrap();
+        let sent = std::thread::spawn(move || decoded).join().unwrap();
+        assert_eq!(sent, token);
+    }
+
+    #[tokio::test]
+    async fn expired_tokens() {
+        // retention is raised to cover the token validity
+        let config = DbConfig::builder()
+            .truncate(true)
+            .min_token_validity(3)
+            .manager(RevisionManagerConfig::builder().max_revisions(2).build())
+            .build();
+        let db = testdb().await.reopen_with(config).await;
+        put_all(&db, &[b"k"], b"0").await;
+        let token = db.consistency_token().await;
+
+        for value in 1..=3u8 {
+            put_all(&db, &[b"k"], &[value]).await;
+            assert_eq!(
+                db.get_with_token(&token, b"k").await.unwrap().as_deref(),
+                Some(&b"0"[..])
+            );
+        }
+
+        // once the revision is reaped, reads fail rather than read another one
+        put_all(&db, &[b"k"], b"4").await;
+        let err = db.get_with_token(&token, b"k").await.unwrap_err();
+        assert!(
+            matches!(err, Error::TokenExpired { epoch } if epoch == token.epoch()),
+            "{err:?}"
+        );
+        assert!(db.prove_with_token(&token, b"k").await.is_err());
+
+        // tokens don't survive a restart unless their revision does
+        let latest = db.consistency_token().await;
         let db = db.reopen().await;
-        println!("{:?}", db.root_hash().await.unwrap().unwrap());
-        let committed = db.root_hash().await.unwrap().unwrap();
-        let historical = db.revision(committed).await.unwrap();
-        assert_eq!(&*historical.val(b"k").await.unwrap().unwrap(), b"v");
+        assert!(db.get_with_token(&token, b"k").await.is_err());
+        assert_eq!(
+            db.get_with_token(&latest, b"k").await.unwrap().as_deref(),
+            Some(&b"4"[..])
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn punched_free_areas() {
+        use storage::ReadableStorage as _;
+        const MIB: usize = 1 << 20;
+        let config = |truncate| {
+            DbConfig::builder()
+                .truncate(truncate)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .max_revisions(2)
+                        .hole_punch_threshold(Some(MIB as u64))
+                        .build(),
+                )
+                .build()
+        };
+        let db = testdb().await.reopen_with(config(true)).await;
+        let allocated = |db: &Db| {
+            let manager = db.manager.try_read().unwrap();
+            manager.storage().allocated_bytes().unwrap()
+        };
+
+        put_all(&db, &[b"big"], &vec![1; 3 * MIB]).await;
+        let before = allocated(&db);
+        // the big value's area is freed once the revisions holding it are reaped
+        for value in [b"2", b"3", b"4"] {
+            put_all(&db, &[b"big"], value).await;
+        }
+        let released = before.saturating_sub(allocated(&db));
+        assert!(released >= 2 * MIB as u64, "only {released} bytes released");
+
+        // the punched area is reused rather than the file growing
+        let size = db.manager.read().await.storage().size().unwrap();
+        put_all(&db, &[b"again"], &vec![5; 3 * MIB]).await;
+        assert!(db.manager.read().await.storage().size().unwrap() < size + MIB as u64);
+
+        let db = db.reopen_with(config(false)).await;
+        let latest = db
+            .revision(db.root_hash().await.unwrap().unwrap())
+            .await
+            .unwrap();
+        assert_eq!(&*latest.val(b"big").await.unwrap().unwrap(), b"4");
+        assert_eq!(
+            latest.val(b"again").await.unwrap().unwrap(),
+            vec![5; 3 * MIB].into()
+        );
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn direct_io() {
+        let config = |truncate| {
+            DbConfig::builder()
+                .truncate(truncate)
+                .manager(
+                    RevisionManagerConfig::builder()
+                        .max_revisions(2)
+                        .direct_io(true)
+                        .build(),
+                )
+                .build()
+        };
+        let tmpdir = tempfile::tempdir().unwrap();
+        let path = tmpdir.path().join("testdb");
+        let db = match Db::new(&path, config(true)).await {
+            Ok(db) => db,
+            // the file system doesn't support direct I/O
+            Err(Error::IO(err)) if err.kind() == std::io::ErrorKind::InvalidInput => return,
+            Err(err) => panic!("{err}"),
+        };
+        let keys: Vec<[u8; 4]> = (0u32..500).map(u32::to_be_bytes).collect();
+        let keys: Vec<&[u8]> = keys.iter().map(|key| key.as_slice()).collect();
+        for round in 0u8..4 {
+            put_all(&db, &keys, &[round; 300]).await;
+        }
+        let root = db.root_hash().await.unwrap();
+        db.close().await.unwrap();
+        drop(db);
+
+        let db = Db::new(&path, config(false)).await.unwrap();
+        assert_eq!(db.root_hash().await.unwrap(), root);
+        let latest = db.revision(root.unwrap()).await.unwrap();
+        for key in &keys {
+            assert_eq!(&*latest.val(key).await.unwrap().unwrap(), &[3; 300]);
+        }
+        put_all(&db, &[b"k"], b"v").await;
+    }
+
+    #[tokio::test]
+    async fn restore() {
+        use crate::restore::RestoreTarget;
+        use std::time::{Duration, SystemTime};
+
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(4).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        put_all(&db, &[b"a", b"b"], b"1").await;
+        db.freeze_prefix(b"a").await.unwrap();
+        let frozen = db.root_hash().await.unwrap();
+        std::thread::sleep(Duration::from_millis(10));
+        let between = SystemTime::now();
+        std::thread::sleep(Duration::from_millis(10));
+        put_all(&db, &[b"b", b"c"], b"2").await;
+
+        // a time between two commits restores the earlier one
+        let plan = db
+            .restore_plan(RestoreTarget::Timestamp(between))
+            .await
+            .unwrap();
+        assert_eq!(plan.root_hash, frozen);
+        assert_eq!(plan.commit.as_ref().unwrap().sequence, 2);
+        let destination = db.tmpdir.path().join("restored");
+        let restored = db.execute_restore(&plan, &destination).await.unwrap();
+        assert_eq!(restored.root_hash().await.unwrap(), frozen);
+        assert_eq!(
+            restored.frozen_prefixes().await.unwrap(),
+            vec![Box::from(&b"a"[..])]
+        );
+        let revision = restored.revision(frozen.clone().unwrap()).await.unwrap();
+        assert_eq!(&*revision.val(b"b").await.unwrap().unwrap(), b"1");
+        assert!(revision.val(b"c").await.unwrap().is_none());
+
+        // never in place, or over anything else
+        let err = db.execute_restore(&plan, &destination).await.unwrap_err();
+        assert!(
+            matches!(err, Error::IO(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists)
+        );
+        let err = db.execute_restore(&plan, db.path()).await.unwrap_err();
+        assert!(matches!(err, Error::IO(_)), "{err:?}");
+
+        // once the target is reaped, neither planning nor a stale plan works
+        for i in 0u8..4 {
+            put_all(&db, &[&[i]], b"3").await;
+        }
+        let err = db
+            .restore_plan(RestoreTarget::RootHash(frozen))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::RestoreTargetUnavailable { .. }),
+            "{err:?}"
+        );
+        let err = db
+            .execute_restore(&plan, db.tmpdir.path().join("stale"))
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, Error::RestoreTargetUnavailable { .. }),
+            "{err:?}"
+        );
+        assert!(db
+            .restore_plan(RestoreTarget::Timestamp(SystemTime::UNIX_EPOCH))
+            .await
+            .is_err());
+
+        let latest = db
+            .restore_plan(RestoreTarget::CommitSequence(7))
+            .await
+            .unwrap();
+        let restored = db
+            .execute_restore(&latest, db.tmpdir.path().join("latest"))
+            .await
+            .unwrap();
+        assert_eq!(
+            restored.root_hash().await.unwrap(),
+            db.root_hash().await.unwrap()
+        );
+    }
+
+    /// Every entry of `revision`, in key order
+    async fn entries(revision: &super::HistoricalRev) -> Vec<(Box<[u8]>, Vec<u8>)> {
+        let merkle = Merkle::from(revision);
+        let mut stream = merkle.key_value_iter();
+        let mut entries = Vec::new();
+        while let Some(entry) = stream.next().await {
+            entries.push(entry.unwrap());
+        }
+        entries
+    }
+
+    /// Commit 12 revisions that overwrite, delete and freeze, so the file
+    /// has free space and the revisions share nodes
+    async fn churn(db: &Db) -> Vec<Option<TrieHash>> {
+        let mut hashes = Vec::new();
+        for round in 0u32..12 {
+            let batch = (0u32..200)
+                .map(|i| match (i + round) % 5 {
+                    0 => BatchOp::Delete {
+                        key: i.to_be_bytes().to_vec(),
+                    },
+                    _ => BatchOp::Put {
+                        key: i.to_be_bytes().to_vec(),
+                        value: vec![round as u8; 1 + (i * round) as usize % 100],
+                    },
+                })
+                .collect();
+            db.propose(batch).await.unwrap().commit().await.unwrap();
+            if round == 6 {
+                db.freeze_prefix(&[0xff, 0xff]).await.unwrap();
+            }
+            hashes.push(db.root_hash().await.unwrap());
+        }
+        hashes
+    }
+
+    #[tokio::test]
+    async fn compact() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(8).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig).await;
+        churn(&db).await;
+        let revisions = db.all_revisions().await;
+        let mut expected = Vec::new();
+        for (_, hash) in &revisions {
+            let revision = db.revision(hash.clone()).await.unwrap();
+            expected.push(entries(&revision).await);
+        }
+        let before = db.statistics(false).await.unwrap();
+        assert!(before.free_bytes > 0);
+
+        let stats = db.compact(None).await.unwrap();
+        assert_eq!(stats.revisions, revisions.len());
+        assert!(stats.size_after < stats.size_before, "{stats:?}");
+        assert_eq!(stats.reclaimed(), stats.size_before - stats.size_after);
+        assert_eq!(db.all_revisions().await, revisions);
+        for ((_, hash), expected) in revisions.iter().zip(expected) {
+            let revision = db.revision(hash.clone()).await.unwrap();
+            assert_eq!(entries(&revision).await, expected);
+        }
+        let after = db.statistics(false).await.unwrap();
+        assert_eq!(after.file_size, stats.size_after);
+        assert!(after.free_bytes < before.free_bytes);
+    }
+
+    #[tokio::test]
+    async fn export_import() {
+        let db = testdb().await;
+        let keys: Vec<Vec<u8>> = (0..100_000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"exported").await;
+        let hash = db.root_hash().await.unwrap().unwrap();
+        let expected = entries(&db.revision(hash.clone()).await.unwrap()).await;
+        // an older revision is exported on its own
+        put_all(&db, &[b"newer"], b"v").await;
+
+        let mut stream = Vec::new();
+        let nodes = db.export(hash.clone(), &mut stream).await.unwrap();
+        assert!(nodes > 100_000);
+        let file_size = db.statistics(false).await.unwrap().file_size;
+        assert!((stream.len() as u64) < file_size);
+
+        let config = DbConfig::builder().truncate(false).build();
+        for verify in [false, true] {
+            let path = db.tmpdir.path().join("imported");
+            let imported = Db::import(&path, config.clone(), stream.as_slice(), verify)
+                .await
+                .unwrap();
+            assert_eq!(imported.root_hash().await.unwrap(), Some(hash.clone()));
+            let revision = imported.revision(hash.clone()).await.unwrap();
+            assert_eq!(entries(&revision).await, expected);
+            let report = imported.verify(VerifyOptions::default()).await.unwrap();
+            assert!(report.is_ok(), "{:?}", report.errors);
+            // the imported database takes commits
+            put_all(&imported, &[b"newer"], b"v").await;
+            assert_eq!(
+                imported.root_hash().await.unwrap(),
+                db.root_hash().await.unwrap()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn import_corrupt_stream() {
+        let db = testdb().await;
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"exported").await;
+        let hash = db.root_hash().await.unwrap().unwrap();
+        let mut stream = Vec::new();
+        db.export(hash, &mut stream).await.unwrap();
+
+        let path = db.tmpdir.path().join("imported");
+        let config = DbConfig::builder().truncate(false).build();
+        let mut corrupt = stream.clone();
+        let middle = corrupt.len() / 2;
+        *corrupt.get_mut(middle).unwrap() ^= 1;
+        assert!(Db::import(&path, config.clone(), corrupt.as_slice(), false)
+            .await
+            .is_err());
+        let truncated = stream.get(..stream.len() - 1).unwrap();
+        assert!(Db::import(&path, config.clone(), truncated, false)
+            .await
+            .is_err());
+        let mut wrong_magic = stream.clone();
+        *wrong_magic.first_mut().unwrap() = b'X';
+        assert!(
+            Db::import(&path, config.clone(), wrong_magic.as_slice(), false)
+                .await
+                .is_err()
+        );
+        assert!(Db::import(&path, config, stream.as_slice(), false)
+            .await
+            .is_ok());
+    }
+
+    #[tokio::test]
+    async fn export_import_portable() {
+        let db = testdb().await;
+        churn(&db).await;
+        // more than a batch of entries
+        let keys: Vec<Vec<u8>> = (1000..25_000u32)
+            .map(|i| i.to_be_bytes().to_vec())
+            .collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"exported").await;
+        let hash = db.root_hash().await.unwrap().unwrap();
+        let expected = entries(&db.revision(hash.clone()).await.unwrap()).await;
+        put_all(&db, &[b"newer"], b"v").await;
+
+        let path = db.tmpdir.path().join("portable");
+        let written = db.export_portable(hash.clone(), &path).await.unwrap();
+        assert_eq!(written, expected.len() as u64);
+        // an existing file isn't overwritten
+        assert!(db.export_portable(hash.clone(), &path).await.is_err());
+
+        let imported_path = db.tmpdir.path().join("imported");
+        let imported = Db::import_portable(&path, &imported_path, DbConfig::builder().build())
+            .await
+            .unwrap();
+        assert_eq!(imported.root_hash().await.unwrap(), Some(hash.clone()));
+        let revision = imported.revision(hash).await.unwrap();
+        assert_eq!(entries(&revision).await, expected);
+        // the reserved key space came along
+        assert_eq!(
+            imported.frozen_prefixes().await.unwrap(),
+            vec![Box::from([0xff, 0xff].as_slice())]
+        );
+        put_all(&imported, &[b"newer"], b"v").await;
+        assert_eq!(
+            imported.root_hash().await.unwrap(),
+            db.root_hash().await.unwrap()
+        );
+    }
+
+    #[tokio::test]
+    async fn import_corrupt_portable() {
+        let db = testdb().await;
+        let keys: Vec<Vec<u8>> = (0..1000u32).map(|i| i.to_be_bytes().to_vec()).collect();
+        let key_refs: Vec<&[u8]> = keys.iter().map(|key| &key[..]).collect();
+        put_all(&db, &key_refs, b"exported").await;
+        let hash = db.root_hash().await.unwrap().unwrap();
+        let path = db.tmpdir.path().join("portable");
+        db.export_portable(hash, &path).await.unwrap();
+        let snapshot = std::fs::read(&path).unwrap();
+
+        let imported_path = db.tmpdir.path().join("imported");
+        let import = |contents: Vec<u8>| {
+            let corrupt_path = db.tmpdir.path().join("corrupt");
+            std::fs::write(&corrupt_path, contents).unwrap();
+            Db::import_portable(corrupt_path, &imported_path, DbConfig::builder().build())
+        };
+        // the last byte of the last value, before the end
+        let mut flipped = snapshot.clone();
+        let last_value = flipped.len() - 2;
+        *flipped.get_mut(last_value).unwrap() ^= 1;
+        let err = import(flipped).await.unwrap_err();
+        assert!(err.to_string().contains("corrupt"), "{err}");
+        let truncated = snapshot.get(..snapshot.len() - 1).unwrap().to_vec();
+        assert!(import(truncated).await.is_err());
+        let mut trailing = snapshot.clone();
+        trailing.push(0);
+        assert!(import(trailing).await.is_err());
+        let mut wrong_magic = snapshot.clone();
+        *wrong_magic.first_mut().unwrap() = b'X';
+        assert!(import(wrong_magic).await.is_err());
+        assert!(import(snapshot).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn compact_with_history() {
+        let dbconfig = DbConfig::builder()
+            .truncate(false)
+            .manager(RevisionManagerConfig::builder().max_revisions(8).build())
+            .build();
+        let db = testdb().await.reopen_with(dbconfig.clone()).await;
+        let hashes = churn(&db).await;
+        let (dropped, kept) = hashes.split_at(hashes.len() - 4);
+        let revisions = db.all_revisions().await;
+        let height = db.latest_height().await;
+        let mut expected = Vec::new();
+        for hash in kept {
+            let revision = db.revision(hash.clone().unwrap()).await.unwrap();
+            expected.push(entries(&revision).await);
+        }
+        let outstanding = db
+            .propose(vec![BatchOp::Put {
+                key: b"k",
+                value: b"v",
+            }])
+            .await
+            .unwrap();
+
+        let stats = db.compact_with_history(4, None).await.unwrap();
+        assert_eq!(stats.revisions, 4);
+        assert!(stats.size_after < stats.size_before, "{stats:?}");
+        assert!(!db.compaction_path().exists());
+        for (hash, expected) in kept.iter().zip(expected) {
+            let revision = db.revision(hash.clone().unwrap()).await.unwrap();
+            assert_eq!(entries(&revision).await, expected);
+        }
+        for hash in dropped {
+            assert!(db.revision(hash.clone().unwrap()).await.is_err());
+        }
+        assert_eq!(db.frozen_prefixes().await.unwrap().len(), 1);
+        assert_eq!(
+            db.all_revisions().await,
+            revisions.get(revisions.len() - 4..).unwrap()
+        );
+        assert_eq!(db.latest_height().await, height);
+
+        // proposals from before would write to the old file
+        let err = outstanding.commit().await.unwrap_err();
+        assert!(matches!(err, Error::ProposalInvalidated), "{err:?}");
+        put_all(&db, &[b"after"], b"v").await;
+        let latest = db.root_hash().await.unwrap();
+        let db = db.reopen_with(dbconfig).await;
+        assert_eq!(db.root_hash().await.unwrap(), latest);
+        assert_eq!(db.latest_height().await, height + 1);
+        let revision = db.revision(latest.unwrap()).await.unwrap();
+        assert_eq!(&*revision.val(b"after").await.unwrap().unwrap(), b"v");
+    }
+
+    #[tokio::test]
+    async fn compaction_crash() {
+        let db = testdb().await;
+        let hashes = churn(&db).await;
+        let latest = hashes.last().unwrap().clone().unwrap();
+        let expected = entries(&db.revision(latest.clone()).await.unwrap()).await;
+
+        // cancelled, then a crash before the rename: the old file is intact
+        let token = CancellationToken::new();
+        token.cancel();
+        let err = db.compact_with_history(3, Some(token)).await.unwrap_err();
+        assert!(matches!(err, Error::Cancelled { .. }), "{err:?}");
+        assert!(!db.compaction_path().exists());
+        {
+            let manager = db.manager.write().await;
+            let mut handle = db.start_operation("compact", None);
+            db.write_compacted(&manager, 3, &mut handle).await.unwrap();
+        }
+        assert!(db.compaction_path().exists());
+        let db = db.reopen().await;
+        let revision = db.revision(latest.clone()).await.unwrap();
+        assert_eq!(entries(&revision).await, expected);
+        drop(revision);
+
+        // a crash after the rename: the new file has the same latest revision
+        {
+            let manager = db.manager.write().await;
+            let mut handle = db.start_operation("compact", None);
+            db.write_compacted(&manager, 3, &mut handle).await.unwrap();
+            std::fs::rename(db.compaction_path(), db.path()).unwrap();
+        }
+        let db = db.reopen().await;
+        let revision = db.revision(latest).await.unwrap();
+        assert_eq!(entries(&revision).await, expected);
+    }
+
+    /// Counts the heap allocations made on each thread, so tests can check
+    /// a code path doesn't allocate
+    struct CountingAllocator;
+
+    thread_local! {
+        static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+    }
+
+    // SAFETY: every call is forwarded to the system allocator unchanged
+    unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+        unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+            ALLOCATIONS.with(|count| count.set(count.get() + 1));
+            std::alloc::System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+            std::alloc::System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+    /// The result of `f` and the number of heap allocations it made
+    fn count_allocations<R>(f: impl FnOnce() -> R) -> (R, usize) {
+        let before = ALLOCATIONS.with(std::cell::Cell::get);
+        let result = f();
+        (result, ALLOCATIONS.with(std::cell::Cell::get) - before)
+    }
+
+    #[tokio::test]
+    async fn warm_get_does_not_allocate() {
+        let db = testdb().await;
+        let batch = (0u8..=255)
+            .map(|i| BatchOp::Put {
+                key: [i.rotate_left(3); 32],
+                value: [i; 8],
+            })
+            .collect();
+        db.propose(batch).await.unwrap().commit().await.unwrap();
+        let revision = db
+            .revision(db.root_hash().await.unwrap().unwrap())
+            .await
+            .unwrap();
+        let merkle = Merkle::from(&revision);
+
+        let key = [0x5a; 32];
+        merkle.get_node(&key).unwrap().unwrap();
+        let (node, allocations) = count_allocations(|| merkle.get_node(&key));
+        assert_eq!(allocations, 0);
+        assert_eq!(node.unwrap().unwrap().value(), Some(&[0x4b; 8][..]));
+        // only the copy of the value handed to the caller
+        let (_, allocations) = count_allocations(|| merkle.get_value(&key));
+        assert_eq!(allocations, 1);
     }
 
-    // Testdb is a helper struct for testing the Db. Once it's dropped, the directory and file disappear
     struct TestDb {
         db: Db,
         tmpdir: tempfile::TempDir,
@@ -428,9 +6577,12 @@ mod test {
                 .collect()
         }
         async fn reopen(self) -> Self {
+            self.reopen_with(DbConfig::builder().truncate(false).build())
+                .await
+        }
+        async fn reopen_with(self, dbconfig: DbConfig) -> Self {
             let path = self.path();
             drop(self.db);
-            let dbconfig = DbConfig::builder().truncate(false).build();
 
             let db = Db::new(path, dbconfig).await.unwrap();
             TestDb {
@@ -0,0 +1,141 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The addresses a commit is about to free, persisted so that a crash part
+//! way through the commit can be recovered from on the next open.
+//!
+//! Reaping a revision writes a free area record into each area it frees as
+//! it goes, while the free list heads that lead to them are only written
+//! later in the commit. The log is written before any of that, and removed
+//! once the commit has moved the root; finding it on open means the commit
+//! was interrupted, and [RevisionManager::new](crate::manager::RevisionManager::new)
+//! puts its areas back on the free lists with [storage::NodeStore::recover_freed].
+//!
+//! The file is a sequence of little-endian u64s: the address of the root the
+//! commit was moving to, or 0 for an empty trie, followed by the addresses
+//! being freed.
+
+use std::fs::{self, File};
+use std::io::{Error, ErrorKind, Write as _};
+use std::path::{Path, PathBuf};
+
+use storage::LinearAddress;
+
+const ENTRY_SIZE: usize = std::mem::size_of::<u64>();
+
+/// What an interrupted commit had recorded in the log
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct DeleteRecord {
+    /// The root the commit was moving to
+    pub(crate) target_root: Option<LinearAddress>,
+    /// The areas the commit was freeing
+    pub(crate) freed: Vec<LinearAddress>,
+}
+
+/// The log file next to the database file
+#[derive(Debug)]
+pub(crate) struct DeleteLog {
+    path: PathBuf,
+}
+
+impl DeleteLog {
+    /// The log of the database file at `db_path`, removing any left behind
+    /// if `truncate` is set, since it was for the old contents
+    pub(crate) fn open(db_path: &Path, truncate: bool) -> Result<Self, Error> {
+        let mut path = db_path.as_os_str().to_owned();
+        path.push(".deletes");
+        let log = Self {
+            path: PathBuf::from(path),
+        };
+        if truncate {
+            log.clear()?;
+        }
+        Ok(log)
+    }
+
+    /// Record that the commit moving the root to `target_root` is freeing
+    /// `freed`. The log is synced before this returns.
+    pub(crate) fn write(
+        &self,
+        target_root: Option<LinearAddress>,
+        freed: &[LinearAddress],
+    ) -> Result<(), Error> {
+        let entries = std::iter::once(target_root.map_or(0, LinearAddress::get))
+            .chain(freed.iter().map(|addr| addr.get()));
+        let mut contents = Vec::with_capacity((freed.len() + 1) * ENTRY_SIZE);
+        for entry in entries {
+            contents.extend_from_slice(&entry.to_le_bytes());
+        }
+
+        // write then rename, so a crash never leaves a partial log
+        let mut tmp = self.path.clone().into_os_string();
+        tmp.push(".tmp");
+        let mut file = File::create(&tmp)?;
+        file.write_all(&contents)?;
+        file.sync_all()?;
+        fs::rename(&tmp, &self.path)
+    }
+
+    /// What the log holds, if a commit was interrupted
+    pub(crate) fn read(&self) -> Result<Option<DeleteRecord>, Error> {
+        let contents = match fs::read(&self.path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        if contents.is_empty() || contents.len() % ENTRY_SIZE != 0 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("delete log {} has a partial entry", self.path.display()),
+            ));
+        }
+        let mut entries = contents
+            .chunks_exact(ENTRY_SIZE)
+            .map(|entry| u64::from_le_bytes(entry.try_into().expect("chunk of an entry")));
+        let target_root = entries.next().and_then(LinearAddress::new);
+        let freed = entries
+            .map(|addr| {
+                LinearAddress::new(addr)
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, "delete log frees address 0"))
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Some(DeleteRecord { target_root, freed }))
+    }
+
+    /// Remove the log, once the commit that wrote it has moved the root
+    pub(crate) fn clear(&self) -> Result<(), Error> {
+        match fs::remove_file(&self.path) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let log = DeleteLog::open(&dir.path().join("db"), false).unwrap();
+        assert_eq!(log.read().unwrap(), None);
+
+        let addr = |addr| LinearAddress::new(addr).unwrap();
+        let record = DeleteRecord {
+            target_root: Some(addr(4096)),
+            freed: vec![addr(2048), addr(2112)],
+        };
+        log.write(record.target_root, &record.freed).unwrap();
+        assert_eq!(log.read().unwrap(), Some(record));
+
+        // an empty trie, freeing its last nodes
+        log.write(None, &[addr(2048)]).unwrap();
+        assert_eq!(log.read().unwrap().unwrap().target_root, None);
+
+        log.clear().unwrap();
+        assert_eq!(log.read().unwrap(), None);
+        log.clear().unwrap();
+    }
+}
@@ -0,0 +1,259 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use std::sync::Arc;
+
+use storage::{BranchNode, Child, Node, TrieReader};
+
+use crate::merkle::{Key, Merkle, MerkleError};
+use crate::stream::key_from_nibble_iter;
+
+/// How the value of a key differs between two revisions, as returned by
+/// [Merkle::diff] and [crate::db::Db::diff]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum KeyChange {
+    /// The key is only in the newer revision
+    Added {
+        /// The key
+        key: Key,
+        /// Its value in the newer revision
+        value: Box<[u8]>,
+    },
+    /// The key is only in the older revision
+    Removed {
+        /// The key
+        key: Key,
+        /// Its value in the older revision
+        old: Box<[u8]>,
+    },
+    /// The key has a different value in each revision
+    Modified {
+        /// The key
+        key: Key,
+        /// Its value in the older revision
+        old: Box<[u8]>,
+        /// Its value in the newer revision
+        new: Box<[u8]>,
+    },
+}
+
+impl KeyChange {
+    /// The key whose value changed
+    pub fn key(&self) -> &[u8] {
+        match self {
+            Self::Added { key, .. } | Self::Removed { key, .. } | Self::Modified { key, .. } => key,
+        }
+    }
+}
+
+impl<T: TrieReader> Merkle<T> {
+    /// The changes that turn this trie into `newer`, in key order.
+    ///
+    /// The tries are walked together, and a child with the same hash in
+    /// both is skipped without being read, since the subtries under it are
+    /// the same. The cost is then proportional to the number of changes
+    /// rather than to the size of the tries.
+    pub fn diff<U: TrieReader>(&self, newer: &Merkle<U>) -> Result<Vec<KeyChange>, MerkleError> {
+        let side = |node| Side {
+            path: Vec::new(),
+            node,
+            level: 0,
+        };
+        let mut differ = Differ {
+            older: self.nodestore(),
+            newer: newer.nodestore(),
+            changes: Vec::new(),
+        };
+        differ.compare(self.root().map(side), newer.root().map(side))?;
+        Ok(differ.changes)
+    }
+}
+
+/// A node of one of the tries
+struct Side {
+    /// The nibbles of the path to the node, up to its partial path
+    path: Vec<u8>,
+    node: Arc<Node>,
+    /// How many nodes are above it
+    level: usize,
+}
+
+impl Side {
+    /// The nibbles of the path to the key at the node, which the keys below
+    /// it start with
+    fn full_path(&self) -> Vec<u8> {
+        self.path
+            .iter()
+            .chain(self.node.partial_path().iter())
+            .copied()
+            .collect()
+    }
+
+    fn child(&self, index: usize) -> Option<&Child> {
+        match &*self.node {
+            Node::Branch(branch) => branch.children.get(index)?.as_ref(),
+            Node::Leaf(_) => None,
+        }
+    }
+}
+
+/// Whether both children are hashed, with the same hash, so that the
+/// subtries under them are the same
+fn same_hash(older: Option<&Child>, newer: Option<&Child>) -> bool {
+    matches!(
+        (older, newer),
+        (Some(Child::AddressWithHash(_, old)), Some(Child::AddressWithHash(_, new))) if old == new
+    )
+}
+
+/// Which of the two tries a node is in
+#[derive(Clone, Copy)]
+enum Which {
+    Older,
+    Newer,
+}
+
+struct Differ<'a, A, B> {
+    older: &'a A,
+    newer: &'a B,
+    changes: Vec<KeyChange>,
+}
+
+impl<A: TrieReader, B: TrieReader> Differ<'_, A, B> {
+    /// Record the changes between the keys at and below `older` and those
+    /// at and below `newer`, which are at the same place in their tries
+    fn compare(&mut self, older: Option<Side>, newer: Option<Side>) -> Result<(), MerkleError> {
+        let (older, newer) = match (older, newer) {
+            (None, None) => return Ok(()),
+            (Some(older), None) => return self.all(Which::Older, &older),
+            (None, Some(newer)) => return self.all(Which::Newer, &newer),
+            (Some(older), Some(newer)) => (older, newer),
+        };
+        let (older_path, newer_path) = (older.full_path(), newer.full_path());
+        if older_path == newer_path {
+            let key = key_from_nibble_iter(older_path.iter().copied());
+            let change = match (older.node.value(), newer.node.value()) {
+                (Some(old), Some(new)) if old != new => Some(KeyChange::Modified {
+                    key,
+                    old: old.into(),
+                    new: new.into(),
+                }),
+                (Some(old), None) => Some(KeyChange::Removed {
+                    key,
+                    old: old.into(),
+                }),
+                (None, Some(value)) => Some(KeyChange::Added {
+                    key,
+                    value: value.into(),
+                }),
+                _ => None,
+            };
+            self.changes.extend(change);
+
+            for index in 0..BranchNode::MAX_CHILDREN {
+                if same_hash(older.child(index), newer.child(index)) {
+                    continue;
+                }
+                let older_child = self.child(Which::Older, &older, index)?;
+                let newer_child = self.child(Which::Newer, &newer, index)?;
+                self.compare(older_child, newer_child)?;
+            }
+            Ok(())
+        } else if newer_path.starts_with(&older_path) {
+            self.descend(Which::Older, older, newer, &newer_path)
+        } else if older_path.starts_with(&newer_path) {
+            self.descend(Which::Newer, newer, older, &older_path)
+        } else if older_path < newer_path {
+            // the paths part ways, so no key is in both
+            self.all(Which::Older, &older)?;
+            self.all(Which::Newer, &newer)
+        } else {
+            self.all(Which::Newer, &newer)?;
+            self.all(Which::Older, &older)
+        }
+    }
+
+    /// Compare `upper`, from the trie `which` is, with `lower`, from the
+    /// other trie, whose keys all start with `lower_path` and so are below
+    /// one child of `upper`
+    fn descend(
+        &mut self,
+        which: Which,
+        upper: Side,
+        lower: Side,
+        lower_path: &[u8],
+    ) -> Result<(), MerkleError> {
+        let upper_path = upper.full_path();
+        self.only(which, &upper_path, upper.node.value());
+        let under = lower_path.get(upper_path.len()).copied();
+        let mut lower = Some(lower);
+        for index in 0..BranchNode::MAX_CHILDREN {
+            let child = self.child(which, &upper, index)?;
+            if under == Some(index as u8) {
+                match which {
+                    Which::Older => self.compare(child, lower.take())?,
+                    Which::Newer => self.compare(lower.take(), child)?,
+                }
+            } else if let Some(child) = child {
+                self.all(which, &child)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record every key at and below `side` as only in the trie `which` is
+    fn all(&mut self, which: Which, side: &Side) -> Result<(), MerkleError> {
+        let path = side.full_path();
+        self.only(which, &path, side.node.value());
+        for index in 0..BranchNode::MAX_CHILDREN {
+            if let Some(child) = self.child(which, side, index)? {
+                self.all(which, &child)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Record `value`, at the key with the nibbles of `path`, as only in the
+    /// trie `which` is
+    fn only(&mut self, which: Which, path: &[u8], value: Option<&[u8]>) {
+        let Some(value) = value else {
+            return;
+        };
+        let key = key_from_nibble_iter(path.iter().copied());
+        self.changes.push(match which {
+            Which::Older => KeyChange::Removed {
+                key,
+                old: value.into(),
+            },
+            Which::Newer => KeyChange::Added {
+                key,
+                value: value.into(),
+            },
+        });
+    }
+
+    /// The child at `index` of `parent`, from the trie `which` is
+    fn child(
+        &self,
+        which: Which,
+        parent: &Side,
+        index: usize,
+    ) -> Result<Option<Side>, MerkleError> {
+        let Some(child) = parent.child(index) else {
+            return Ok(None);
+        };
+        let level = parent.level + 1;
+        let node = match (child, which) {
+            (Child::Node(node), _) => Arc::new(node.clone()),
+            (Child::AddressWithHash(addr, _), Which::Older) => {
+                self.older.read_node_at_depth(*addr, level)?
+            }
+            (Child::AddressWithHash(addr, _), Which::Newer) => {
+                self.newer.read_node_at_depth(*addr, level)?
+            }
+        };
+        let mut path = parent.full_path();
+        path.push(index as u8);
+        Ok(Some(Side { path, node, level }))
+    }
+}
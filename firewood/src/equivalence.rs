@@ -0,0 +1,238 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Deciding when a put leaves the value of a key unchanged.
+//!
+//! When a put in a proposal targets a key that already has a value, the
+//! [ValueEquivalence](crate::equivalence::ValueEquivalence) in [DbConfig::value_equivalence](crate::db::DbConfig::value_equivalence)
+//! is asked whether the new value is equivalent to the old one. If it is,
+//! the put is dropped: the key keeps its **old** bytes, the new bytes are
+//! discarded, no node on its path is rewritten, and the root hash and the
+//! proposal's change set don't see it. The default, [ByteEquality](crate::equivalence::ByteEquality), only
+//! drops puts of the value the key already has.
+//!
+//! The values compared for one proposal are limited to
+//! [DbConfig::equivalence_budget](crate::db::DbConfig::equivalence_budget)
+//! bytes. Puts past the budget are written without being compared, and
+//! counted in [RewriteStats::over_budget](crate::equivalence::RewriteStats::over_budget).
+
+use std::fmt::Debug;
+
+use metrics::counter;
+
+/// Decides whether a new value of a key can be dropped in favor of its
+/// old one. It must be pure and cheap, since it is called for every put to
+/// a key that has a value.
+pub trait ValueEquivalence: Debug + Send + Sync {
+    /// Whether `new` means the same as `old`, the value `key` has
+    fn equivalent(&self, key: &[u8], old: &[u8], new: &[u8]) -> bool;
+}
+
+/// Values are only equivalent if they are the same bytes
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ByteEquality;
+
+impl ValueEquivalence for ByteEquality {
+    fn equivalent(&self, _key: &[u8], old: &[u8], new: &[u8]) -> bool {
+        old == new
+    }
+}
+
+/// What happened to the puts of a proposal to keys that already had a value
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RewriteStats {
+    /// Puts dropped because the new value was equivalent to the old one
+    pub equivalent: u64,
+    /// Puts written because the new value was different
+    pub changed: u64,
+    /// Puts written without comparing the values, since the equivalence
+    /// budget was used up
+    pub over_budget: u64,
+}
+
+/// Compares the values of the puts of one proposal
+#[derive(Debug)]
+pub(crate) struct RewriteFilter<'a> {
+    equivalence: &'a dyn ValueEquivalence,
+    /// The bytes that can still be compared
+    budget: usize,
+    stats: RewriteStats,
+}
+
+impl<'a> RewriteFilter<'a> {
+    pub(crate) fn new(equivalence: &'a dyn ValueEquivalence, budget: usize) -> Self {
+        Self {
+            equivalence,
+            budget,
+            stats: RewriteStats::default(),
+        }
+    }
+
+    /// Whether the put of `new` to `key`, which has the value `old`, should
+    /// be dropped
+    pub(crate) fn is_equivalent(&mut self, key: &[u8], old: &[u8], new: &[u8]) -> bool {
+        let cost = old.len() + new.len();
+        let (result, equivalent) = if cost > self.budget {
+            self.stats.over_budget += 1;
+            ("over_budget", false)
+        } else {
+            self.budget -= cost;
+            match self.equivalence.equivalent(key, old, new) {
+                true => {
+                    self.stats.equivalent += 1;
+                    ("equivalent", true)
+                }
+                false => {
+                    self.stats.changed += 1;
+                    ("changed", false)
+                }
+            }
+        };
+        counter!("firewood.propose.rewrite", "result" => result).increment(1);
+        equivalent
+    }
+
+    pub(crate) const fn stats(&self) -> RewriteStats {
+        self.stats
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod test {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::db::{BatchOp, Db, DbConfig};
+    use crate::invariant::{ChangeSet, CommitInvariant, InvariantError, InvariantView};
+    use crate::v2::api::{Db as _, DbView as _, Proposal as _};
+
+    /// Values whose last 8 bytes are a timestamp that doesn't matter
+    #[derive(Debug)]
+    struct IgnoreTimestamp;
+
+    impl ValueEquivalence for IgnoreTimestamp {
+        fn equivalent(&self, _key: &[u8], old: &[u8], new: &[u8]) -> bool {
+            data(old).is_some() && data(old) == data(new)
+        }
+    }
+
+    fn data(value: &[u8]) -> Option<&[u8]> {
+        value.len().checked_sub(8).map(|len| &value[..len])
+    }
+
+    /// Keeps the number of keys each checked proposal changed
+    #[derive(Debug)]
+    struct Changes(Arc<Mutex<Vec<usize>>>);
+
+    impl CommitInvariant for Changes {
+        fn name(&self) -> &str {
+            "changes"
+        }
+
+        fn check(&self, _: &InvariantView<'_>, changes: &ChangeSet) -> Result<(), InvariantError> {
+            self.0.lock().unwrap().push(changes.len());
+            Ok(())
+        }
+    }
+
+    fn value(data: &[u8], timestamp: u64) -> Vec<u8> {
+        [data, &timestamp.to_be_bytes()].concat()
+    }
+
+    #[tokio::test]
+    async fn equivalent_rewrites_are_dropped() {
+        let dir = tempfile::tempdir().unwrap();
+        let changes = Arc::new(Mutex::new(Vec::new()));
+        let config = DbConfig::builder()
+            .truncate(true)
+            .value_equivalence(Box::new(IgnoreTimestamp))
+            .invariants(vec![Box::new(Changes(changes.clone()))])
+            .build();
+        let db = Db::new(dir.path().join("db"), config).await.unwrap();
+        let put = |key: &'static [u8], value| BatchOp::Put { key, value };
+
+        let batch = vec![put(b"a", value(b"aaaa", 1)), put(b"b", value(b"bbbb", 1))];
+        let root = db.propose(batch).await.unwrap().commit().await.unwrap();
+
+        // only the timestamps change, so nothing does
+        let batch = vec![put(b"a", value(b"aaaa", 2)), put(b"b", value(b"bbbb", 2))];
+        let proposal = db.propose(batch).await.unwrap();
+        let expected = RewriteStats {
+            equivalent: 2,
+            ..Default::default()
+        };
+        assert_eq!(proposal.rewrite_stats(), expected);
+        assert_eq!(proposal.root_hash().await.unwrap(), root);
+        // the old bytes are kept
+        assert_eq!(
+            *proposal.val(b"a").await.unwrap().unwrap(),
+            value(b"aaaa", 1)
+        );
+        assert_eq!(proposal.commit().await.unwrap(), root);
+
+        // a change to the data is written, new timestamp and all
+        let batch = vec![put(b"a", value(b"aaab", 3)), put(b"b", value(b"bbbb", 3))];
+        let proposal = db.propose(batch).await.unwrap();
+        let expected = RewriteStats {
+            equivalent: 1,
+            changed: 1,
+            ..Default::default()
+        };
+        assert_eq!(proposal.rewrite_stats(), expected);
+        assert_eq!(
+            *proposal.val(b"a").await.unwrap().unwrap(),
+            value(b"aaab", 3)
+        );
+        assert_eq!(
+            *proposal.val(b"b").await.unwrap().unwrap(),
+            value(b"bbbb", 1)
+        );
+        assert_ne!(proposal.commit().await.unwrap(), root);
+
+        assert_eq!(*changes.lock().unwrap(), [2, 0, 1]);
+    }
+
+    #[tokio::test]
+    async fn rewrites_past_the_budget_are_written() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DbConfig::builder()
+            .truncate(true)
+            .equivalence_budget(100)
+            .build();
+        let db = Db::new(dir.path().join("db"), config).await.unwrap();
+        let batch = |value: &'static [u8]| {
+            vec![
+                BatchOp::Put {
+                    key: b"small",
+                    value: &b"v"[..],
+                },
+                BatchOp::Put {
+                    key: b"large",
+                    value,
+                },
+            ]
+        };
+        let root = db
+            .propose(batch(&[1; 200]))
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap();
+
+        let proposal = db.propose(batch(&[1; 200])).await.unwrap();
+        let expected = RewriteStats {
+            equivalent: 1,
+            over_budget: 1,
+            ..Default::default()
+        };
+        assert_eq!(proposal.rewrite_stats(), expected);
+        // the same bytes were written, so the hash doesn't change
+        assert_eq!(proposal.root_hash().await.unwrap(), root);
+
+        let proposal = db.propose(batch(&[2; 200])).await.unwrap();
+        assert_eq!(proposal.rewrite_stats().over_budget, 1);
+        assert_eq!(*proposal.val(b"large").await.unwrap().unwrap(), [2; 200]);
+    }
+}
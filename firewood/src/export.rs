@@ -0,0 +1,274 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Exporting a single revision as a stream, and building a new database from
+//! one, to ship state to a new node without copying every revision in the
+//! file. See [crate::db::Db::export] and [crate::db::Db::import].
+//!
+//! The stream starts with [EXPORT_MAGIC](crate::export::EXPORT_MAGIC), the version of the encoding, the
+//! number of bits per nibble and the root hash. Then come the nodes of the
+//! trie in post order, children in index order, so every node follows its
+//! children. Each is its length as a varint followed by the node as it is
+//! stored in [NodeFormat::Compact](storage::NodeFormat::Compact), except that each child's address is the
+//! position of the child in the stream, counting from 1. A length of 0 ends
+//! the nodes, and the SHA-256 of everything before it ends the stream.
+//!
+//! The nodes keep the hashes of their children, so importing a stream
+//! writes each node once without hashing it. The digest at the end catches
+//! a stream corrupted in transit; re-hashing every node on import also
+//! catches one that was built wrong.
+
+use std::io::{Error, ErrorKind};
+use std::sync::Arc;
+
+use futures::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use integer_encoding::VarInt as _;
+use sha2::{Digest as _, Sha256};
+use storage::{
+    BranchNode, Child, Committed, LinearAddress, Node, NodeFormat, NodeReader, NodeStore, TrieHash,
+    WritableStorage,
+};
+
+/// The first bytes of an exported revision
+pub const EXPORT_MAGIC: &[u8; 4] = b"FWEX";
+
+/// The version of the encoding written by [crate::db::Db::export]
+pub const EXPORT_VERSION: u8 = 1;
+
+/// No stored node is larger than this, in bytes
+const MAX_RECORD_LEN: u64 = 16 << 20;
+
+/// How many bytes are buffered before they are written out
+const WRITE_BUFFER: usize = 1 << 20;
+
+/// A node whose children are being exported
+struct Frame {
+    node: Arc<Node>,
+    /// The index of the next child to export
+    next: usize,
+    /// The positions in the stream of the children exported so far
+    positions: [Option<u64>; BranchNode::MAX_CHILDREN],
+}
+
+impl Frame {
+    const fn new(node: Arc<Node>) -> Self {
+        Frame {
+            node,
+            next: 0,
+            positions: [None; BranchNode::MAX_CHILDREN],
+        }
+    }
+
+    /// The address of the next child that isn't exported yet
+    fn next_child(&mut self) -> Result<Option<LinearAddress>, Error> {
+        let Node::Branch(branch) = &*self.node else {
+            return Ok(None);
+        };
+        while let Some(child) = branch.children.get(self.next) {
+            self.next += 1;
+            match child {
+                None => continue,
+                Some(Child::AddressWithHash(addr, _)) => return Ok(Some(*addr)),
+                Some(Child::Node(_)) => {
+                    return Err(Error::new(
+                        ErrorKind::InvalidData,
+                        "a committed revision has an unhashed child",
+                    ))
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// The record of the node, once its children are exported
+    fn record(&self) -> Vec<u8> {
+        let node = match &*self.node {
+            Node::Leaf(_) => (*self.node).clone(),
+            Node::Branch(branch) => {
+                let mut branch = branch.clone();
+                for (child, position) in branch.children.iter_mut().zip(self.positions) {
+                    if let (Some(Child::AddressWithHash(addr, _)), Some(position)) =
+                        (child, position)
+                    {
+                        *addr = LinearAddress::new(position).expect("positions start at 1");
+                    }
+                }
+                Node::Branch(branch)
+            }
+        };
+        let mut encoded = Vec::new();
+        node.as_bytes(NodeFormat::Compact, 0, &mut encoded);
+        let node = encoded.get(1..).unwrap_or_default();
+        let mut record = node.len().encode_var_vec();
+        record.extend_from_slice(node);
+        record
+    }
+}
+
+/// Write the trie of `revision`, whose root hash is `root_hash`, to
+/// `writer`, returning the number of nodes written
+pub(crate) async fn export<T: NodeReader, W: AsyncWrite + Unpin>(
+    revision: &T,
+    root: LinearAddress,
+    root_hash: &TrieHash,
+    writer: &mut W,
+) -> Result<u64, Error> {
+    let mut hasher = Sha256::new();
+    let mut buffer = Vec::with_capacity(WRITE_BUFFER);
+    buffer.extend_from_slice(EXPORT_MAGIC);
+    buffer.push(EXPORT_VERSION);
+    buffer.push(BranchNode::BITS_PER_NIBBLE as u8);
+    buffer.extend_from_slice(root_hash);
+
+    let mut stack = vec![Frame::new(revision.read_node(root)?)];
+    let mut written = 0;
+    while let Some(frame) = stack.last_mut() {
+        if let Some(addr) = frame.next_child()? {
+            stack.push(Frame::new(revision.read_node(addr)?));
+            continue;
+        }
+        let frame = stack.pop().expect("the stack isn't empty");
+        buffer.extend_from_slice(&frame.record());
+        written += 1;
+        if let Some(parent) = stack.last_mut() {
+            // the parent's next child is the one after this one
+            if let Some(position) = parent.positions.get_mut(parent.next - 1) {
+                *position = Some(written);
+            }
+        }
+        if buffer.len() >= WRITE_BUFFER {
+            hasher.update(&buffer);
+            writer.write_all(&buffer).await?;
+            buffer.clear();
+        }
+    }
+
+    buffer.push(0);
+    hasher.update(&buffer);
+    buffer.extend_from_slice(&hasher.finalize());
+    writer.write_all(&buffer).await?;
+    writer.flush().await?;
+    Ok(written)
+}
+
+/// Reads from a stream, hashing what it reads
+struct HashingReader<R> {
+    inner: R,
+    hasher: Sha256,
+}
+
+impl<R: AsyncRead + Unpin> HashingReader<R> {
+    async fn read_exact(&mut self, buf: &mut [u8]) -> Result<(), Error> {
+        self.inner.read_exact(buf).await?;
+        self.hasher.update(&*buf);
+        Ok(())
+    }
+
+    async fn read_varint(&mut self) -> Result<u64, Error> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let mut byte = [0];
+            self.read_exact(&mut byte).await?;
+            let [byte] = byte;
+            value |= u64::from(byte & 0x7f) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err(invalid("length is too long"))
+    }
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid exported revision: {reason}"),
+    )
+}
+
+/// Read an exported revision from `reader` into `nodestore`, which must be
+/// empty, and make it the root. Returns the root hash from the stream,
+/// which is not checked against the nodes.
+pub(crate) async fn import<S: WritableStorage, R: AsyncRead + Unpin>(
+    nodestore: &mut NodeStore<Committed, S>,
+    reader: R,
+) -> Result<TrieHash, Error> {
+    let mut reader = HashingReader {
+        inner: reader,
+        hasher: Sha256::new(),
+    };
+    let mut magic = [0; EXPORT_MAGIC.len()];
+    reader.read_exact(&mut magic).await?;
+    if magic != *EXPORT_MAGIC {
+        return Err(invalid("not an exported revision"));
+    }
+    let mut versions = [0; 2];
+    reader.read_exact(&mut versions).await?;
+    let [version, bits_per_nibble] = versions;
+    if version != EXPORT_VERSION {
+        return Err(invalid(&format!("unknown version {version}")));
+    }
+    if bits_per_nibble as usize != BranchNode::BITS_PER_NIBBLE {
+        return Err(invalid(&format!(
+            "exported with {bits_per_nibble} bits per nibble, not {}",
+            BranchNode::BITS_PER_NIBBLE
+        )));
+    }
+    let mut root_hash = [0; 32];
+    reader.read_exact(&mut root_hash).await?;
+    let root_hash = TrieHash::from(root_hash);
+
+    // the addresses of the nodes read so far, and whether each has a parent
+    let mut nodes: Vec<(LinearAddress, bool)> = Vec::new();
+    loop {
+        let len = reader.read_varint().await?;
+        if len == 0 {
+            break;
+        }
+        if len > MAX_RECORD_LEN {
+            return Err(invalid(&format!(
+                "node length {len} is larger than any node"
+            )));
+        }
+        let mut record = vec![0; len as usize];
+        reader.read_exact(&mut record).await?;
+        let mut node = Node::from_reader(NodeFormat::Compact, record.as_slice())?;
+        if let Node::Branch(branch) = &mut node {
+            for child in branch.children.iter_mut().flatten() {
+                let Child::AddressWithHash(addr, _) = child else {
+                    unreachable!("read nodes have hashed children")
+                };
+                let position = addr.get() - 1;
+                let Some((child_addr, has_parent)) = usize::try_from(position)
+                    .ok()
+                    .and_then(|position| nodes.get_mut(position))
+                else {
+                    return Err(invalid(&format!("child {addr} isn't before its parent")));
+                };
+                if std::mem::replace(has_parent, true) {
+                    return Err(invalid(&format!("node {addr} has more than one parent")));
+                }
+                *addr = *child_addr;
+            }
+        }
+        nodes.push((nodestore.append_node(&node)?, false));
+    }
+
+    let digest = reader.hasher.finalize_reset();
+    let mut expected = [0; 32];
+    reader.read_exact(&mut expected).await?;
+    if digest.as_slice() != expected {
+        return Err(invalid(
+            "the digest doesn't match, so the stream is corrupt",
+        ));
+    }
+
+    let Some(((root, _), children)) = nodes.split_last() else {
+        return Err(invalid("there are no nodes"));
+    };
+    if children.iter().any(|(_, has_parent)| !has_parent) {
+        return Err(invalid("a node is not in the trie"));
+    }
+    nodestore.set_root(*root, root_hash.clone());
+    Ok(root_hash)
+}
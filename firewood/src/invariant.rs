@@ -0,0 +1,581 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Checks of application invariants run on each proposal as it is committed.
+//!
+//! The [CommitInvariant](crate::invariant::CommitInvariant)s in [DbConfig::invariants](crate::db::DbConfig::invariants)
+//! run once the proposal is hashed, before anything is written. Each one is
+//! given a read view of the proposal and the keys it changed, and any
+//! violation fails the commit with [api::Error::InvariantViolated](crate::v2::api::Error::InvariantViolated), leaving
+//! the database as it was. [Proposal::check_invariants](crate::db::Proposal::check_invariants)
+//! runs the same checks without committing, so a failing proposal can be
+//! inspected through its handle.
+//!
+//! Each invariant has an [InvariantBudget](crate::invariant::InvariantBudget). Reads through the view fail once
+//! it is used up, and an invariant that finishes after its time is up fails
+//! too, with [api::Error::InvariantBudgetExhausted](crate::v2::api::Error::InvariantBudgetExhausted). Invariants that spin
+//! without reading can't be stopped, only reported once they return.
+
+use std::cell::Cell;
+use std::collections::btree_map::Entry;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt as _;
+use storage::{
+    FileBacked, ImmutableProposal, MutableProposal, NodeStore, Parentable as _, TrieHash,
+};
+
+use crate::merkle::{Key, Merkle, MerkleError};
+use crate::v2::api::{self, BatchOp, KeyType, ValueType};
+
+type ProposedRevision = Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>>;
+
+/// An invariant checked before each commit
+pub trait CommitInvariant: Debug + Send + Sync {
+    /// The name the invariant is reported by
+    fn name(&self) -> &str;
+
+    /// How much the invariant may read, and for how long it may run
+    fn budget(&self) -> InvariantBudget {
+        InvariantBudget::default()
+    }
+
+    /// Check the proposal `view` reads, which made `changes` to its parent.
+    /// Return [InvariantError::Violated] to refuse the commit.
+    fn check(&self, view: &InvariantView<'_>, changes: &ChangeSet) -> Result<(), InvariantError>;
+}
+
+/// Why an invariant didn't pass
+#[derive(Debug, thiserror::Error)]
+pub enum InvariantError {
+    /// The proposal violates the invariant, for the given reason
+    #[error("{0}")]
+    Violated(String),
+    /// The invariant used up its [InvariantBudget]
+    #[error("budget exhausted")]
+    BudgetExhausted,
+    /// A read through the view failed
+    #[error("read failed: {0}")]
+    Read(#[from] MerkleError),
+}
+
+/// Limits on what one invariant may do for one commit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvariantBudget {
+    /// The number of keys it may read through its [InvariantView]
+    pub reads: u64,
+    /// How long it may run
+    pub time: Duration,
+}
+
+impl Default for InvariantBudget {
+    fn default() -> Self {
+        Self {
+            reads: 10_000,
+            time: Duration::from_millis(100),
+        }
+    }
+}
+
+/// A key a proposal changed, with its value in the proposal's parent and
+/// in the proposal. A None value means the key isn't there.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyChange {
+    /// The key
+    pub key: Key,
+    /// The value before
+    pub old: Option<Box<[u8]>>,
+    /// The value after
+    pub new: Option<Box<[u8]>>,
+}
+
+/// The keys a proposal changed, in key order. Keys it wrote with the value
+/// they already had are left out.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChangeSet {
+    changes: Box<[KeyChange]>,
+}
+
+impl ChangeSet {
+    /// All the changes
+    pub fn iter(&self) -> impl Iterator<Item = &KeyChange> {
+        self.changes.iter()
+    }
+
+    /// The changes to keys that start with `prefix`
+    pub fn prefix<'a>(&'a self, prefix: &'a [u8]) -> impl Iterator<Item = &'a KeyChange> {
+        let start = self.changes.partition_point(|change| *change.key < *prefix);
+        self.changes
+            .get(start..)
+            .unwrap_or_default()
+            .iter()
+            .take_while(move |change| change.key.starts_with(prefix))
+    }
+
+    /// The change to `key`, if it changed
+    pub fn get(&self, key: &[u8]) -> Option<&KeyChange> {
+        let index = self
+            .changes
+            .binary_search_by(|change| (*change.key).cmp(key))
+            .ok()?;
+        self.changes.get(index)
+    }
+
+    /// The number of keys changed
+    pub fn len(&self) -> usize {
+        self.changes.len()
+    }
+
+    /// Whether no key changed
+    pub fn is_empty(&self) -> bool {
+        self.changes.is_empty()
+    }
+
+    /// The values in `merkle` of the keys `batch` may change, before it is
+    /// applied to it
+    pub(crate) async fn values_before<K: KeyType, V: ValueType>(
+        merkle: &Merkle<NodeStore<MutableProposal, FileBacked>>,
+        batch: &[BatchOp<K, V>],
+    ) -> Result<BTreeMap<Key, Option<Box<[u8]>>>, api::Error> {
+        let mut before = BTreeMap::new();
+        for op in batch {
+            Self::value_before(merkle, &mut before, op).await?;
+        }
+        Ok(before)
+    }
+
+    /// Add the values in `merkle` of the keys `op` may change to `before`,
+    /// unless an earlier op of the batch already did. Called before `op`
+    /// is applied, for a batch applied one op at a time.
+    pub(crate) async fn value_before<K: KeyType, V: ValueType>(
+        merkle: &Merkle<NodeStore<MutableProposal, FileBacked>>,
+        before: &mut BTreeMap<Key, Option<Box<[u8]>>>,
+        op: &BatchOp<K, V>,
+    ) -> Result<(), api::Error> {
+        match op {
+            BatchOp::Put { key, .. } | BatchOp::Delete { key } => {
+                if let Entry::Vacant(entry) = before.entry(Key::from(key.as_ref())) {
+                    let value = merkle.peek_value(entry.key())?;
+                    entry.insert(value);
+                }
+            }
+            BatchOp::DeleteRange { prefix } => {
+                let mut stream = merkle.key_value_iter_prefix(prefix.as_ref());
+                while let Some((key, value)) = stream.next().await.transpose()? {
+                    before
+                        .entry(key)
+                        .or_insert_with(|| Some(value.into_boxed_slice()));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// The changes from the values in `before` to the ones in `merkle`
+    pub(crate) fn new(
+        merkle: &Merkle<NodeStore<MutableProposal, FileBacked>>,
+        before: BTreeMap<Key, Option<Box<[u8]>>>,
+    ) -> Result<Self, MerkleError> {
+        let mut changes = Vec::new();
+        for (key, old) in before {
+            let new = merkle.peek_value(&key)?;
+            if new != old {
+                changes.push(KeyChange { key, old, new });
+            }
+        }
+        Ok(Self {
+            changes: changes.into(),
+        })
+    }
+}
+
+/// Reads of the proposal being committed, counted against an invariant's
+/// [InvariantBudget]
+#[derive(Debug)]
+pub struct InvariantView<'a> {
+    proposal: &'a ProposedRevision,
+    budget: InvariantBudget,
+    deadline: Instant,
+    reads: Cell<u64>,
+}
+
+impl InvariantView<'_> {
+    /// The value of `key` in the proposal
+    pub fn get(&self, key: &[u8]) -> Result<Option<Box<[u8]>>, InvariantError> {
+        if self.reads.get() >= self.budget.reads || Instant::now() > self.deadline {
+            return Err(InvariantError::BudgetExhausted);
+        }
+        self.reads.set(self.reads.get() + 1);
+        if self.proposal.is_hidden(key) {
+            return Ok(None);
+        }
+        Ok(Merkle::from(self.proposal.clone()).get_value(key)?)
+    }
+
+    /// The root hash of the proposal, or None if it is empty
+    pub fn root_hash(&self) -> Option<TrieHash> {
+        self.proposal.kind.root_hash()
+    }
+
+    /// The number of reads made so far
+    pub const fn reads(&self) -> u64 {
+        self.reads.get()
+    }
+}
+
+/// Run each of `invariants` on `proposal`, stopping at the first that
+/// doesn't pass
+pub(crate) fn check_all(
+    invariants: &[Arc<dyn CommitInvariant>],
+    proposal: &ProposedRevision,
+    changes: &ChangeSet,
+) -> Result<(), api::Error> {
+    for invariant in invariants {
+        let budget = invariant.budget();
+        let view = InvariantView {
+            proposal,
+            budget,
+            deadline: Instant::now() + budget.time,
+            reads: Cell::new(0),
+        };
+        let result = match invariant.check(&view, changes) {
+            Ok(()) if Instant::now() > view.deadline => Err(InvariantError::BudgetExhausted),
+            result => result,
+        };
+        let name = invariant.name().to_string();
+        match result {
+            Ok(()) => {}
+            Err(InvariantError::Violated(detail)) => {
+                return Err(api::Error::InvariantViolated { name, detail })
+            }
+            Err(InvariantError::BudgetExhausted) => {
+                return Err(api::Error::InvariantBudgetExhausted { name, budget })
+            }
+            Err(InvariantError::Read(err)) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// The values of the keys under a prefix, read as big-endian u64s, must add
+/// up to the value at a total key, which is 0 when it's missing.
+///
+/// Only the changes are checked, so the invariant must hold when it is
+/// added: each commit must change the total by as much as it changes the
+/// values under the prefix. The total key may be under the prefix; it isn't
+/// counted in the sum.
+#[derive(Debug, Clone)]
+pub struct SumEqualsTotal {
+    name: String,
+    prefix: Box<[u8]>,
+    total_key: Box<[u8]>,
+}
+
+impl SumEqualsTotal {
+    /// Check that the values under `prefix` add up to the one at `total_key`
+    pub fn new(name: impl Into<String>, prefix: &[u8], total_key: &[u8]) -> Self {
+        Self {
+            name: name.into(),
+            prefix: prefix.into(),
+            total_key: total_key.into(),
+        }
+    }
+
+    fn amount(key: &[u8], value: Option<&[u8]>) -> Result<i128, InvariantError> {
+        let Some(value) = value else {
+            return Ok(0);
+        };
+        let bytes: [u8; 8] = value.try_into().map_err(|_| {
+            InvariantError::Violated(format!(
+                "the value of {} is {} bytes long, not 8",
+                hex::encode(key),
+                value.len()
+            ))
+        })?;
+        Ok(u64::from_be_bytes(bytes).into())
+    }
+}
+
+impl CommitInvariant for SumEqualsTotal {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn check(&self, view: &InvariantView<'_>, changes: &ChangeSet) -> Result<(), InvariantError> {
+        let mut sum_change = 0;
+        for change in changes.prefix(&self.prefix) {
+            if change.key == self.total_key {
+                continue;
+            }
+            sum_change += Self::amount(&change.key, change.new.as_deref())?
+                - Self::amount(&change.key, change.old.as_deref())?;
+        }
+
+        let new_total = Self::amount(&self.total_key, view.get(&self.total_key)?.as_deref())?;
+        let old_total = match changes.get(&self.total_key) {
+            Some(change) => Self::amount(&change.key, change.old.as_deref())?,
+            None => new_total,
+        };
+        if new_total - old_total != sum_change {
+            return Err(InvariantError::Violated(format!(
+                "the values under {} changed by {sum_change}, but the total at {} by {}",
+                hex::encode(&self.prefix),
+                hex::encode(&self.total_key),
+                new_total - old_total,
+            )));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use std::sync::Mutex;
+
+    use super::*;
+    use crate::db::{Db, DbConfig};
+    use crate::v2::api::{Db as _, DbView as _, Proposal as _};
+
+    async fn open(dir: &tempfile::TempDir, invariants: Vec<Box<dyn CommitInvariant>>) -> Db {
+        let config = DbConfig::builder()
+            .truncate(true)
+            .invariants(invariants)
+            .build();
+        Db::new(dir.path().join("db"), config).await.unwrap()
+    }
+
+    fn supply() -> Box<dyn CommitInvariant> {
+        Box::new(SumEqualsTotal::new("supply", b"bal/", b"total"))
+    }
+
+    fn put(key: &'static [u8], amount: u64) -> BatchOp<&'static [u8], [u8; 8]> {
+        BatchOp::Put {
+            key,
+            value: amount.to_be_bytes(),
+        }
+    }
+
+    #[tokio::test]
+    async fn sum_equals_total() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir, vec![supply()]).await;
+        let batches = vec![
+            vec![put(b"bal/a", 5), put(b"bal/b", 7), put(b"total", 12)],
+            // a transfer leaves the total alone
+            vec![put(b"bal/a", 3), put(b"bal/b", 9)],
+            vec![
+                BatchOp::DeleteRange { prefix: b"bal/a" },
+                put(b"total", 9),
+                put(b"other", 1),
+            ],
+        ];
+        for batch in batches {
+            db.propose(batch).await.unwrap().commit().await.unwrap();
+        }
+
+        let err = db
+            .propose(vec![put(b"bal/c", 1)])
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap_err();
+        let api::Error::InvariantViolated { name, detail } = err else {
+            panic!("{err:?}");
+        };
+        assert_eq!(name, "supply");
+        assert!(detail.contains("changed by 1"), "{detail}");
+
+        let err = db
+            .propose(vec![BatchOp::Put {
+                key: b"bal/c",
+                value: b"1",
+            }])
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap_err();
+        assert!(
+            matches!(err, api::Error::InvariantViolated { .. }),
+            "{err:?}"
+        );
+    }
+
+    #[tokio::test]
+    async fn failed_commit_leaves_everything_usable() {
+        let dir = tempfile::tempdir().unwrap();
+        let db = open(&dir, vec![supply()]).await;
+        db.propose(vec![put(b"bal/a", 5), put(b"total", 5)])
+            .await
+            .unwrap()
+            .commit()
+            .await
+            .unwrap();
+        let root_hash = db.root_hash().await.unwrap();
+        let file_len = || std::fs::metadata(dir.path().join("db")).unwrap().len();
+        let len = file_len();
+
+        let proposal = db.propose(vec![put(b"bal/a", 6)]).await.unwrap();
+        let err = proposal.check_invariants().unwrap_err();
+        assert!(
+            matches!(err, api::Error::InvariantViolated { .. }),
+            "{err:?}"
+        );
+        // the proposal can still be read
+        let value = proposal.val(b"bal/a").await.unwrap().unwrap();
+        assert_eq!(*value, 6u64.to_be_bytes());
+
+        assert!(proposal.commit().await.is_err());
+        assert_eq!(db.root_hash().await.unwrap(), root_hash);
+        assert_eq!(file_len(), len);
+
+        let proposal = db
+            .propose(vec![put(b"bal/a", 6), put(b"total", 6)])
+            .await
+            .unwrap();
+        assert!(proposal.commit().await.unwrap().is_some());
+        let revision = db
+            .revision(db.root_hash().await.unwrap().unwrap())
+            .await
+            .unwrap();
+        let total = revision.val(b"total").await.unwrap().unwrap();
+        assert_eq!(*total, 6u64.to_be_bytes());
+    }
+
+    /// Reads `reads` keys, then sleeps for `sleep`
+    #[derive(Debug)]
+    struct Greedy {
+        reads: u64,
+        sleep: Duration,
+        budget: InvariantBudget,
+    }
+
+    impl CommitInvariant for Greedy {
+        fn name(&self) -> &str {
+            "greedy"
+        }
+
+        fn budget(&self) -> InvariantBudget {
+            self.budget
+        }
+
+        fn check(&self, view: &InvariantView<'_>, _: &ChangeSet) -> Result<(), InvariantError> {
+            for i in 0..self.reads {
+                view.get(&i.to_be_bytes())?;
+            }
+            std::thread::sleep(self.sleep);
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn budget_exhausted() {
+        let budget = InvariantBudget {
+            reads: 10,
+            time: Duration::from_millis(50),
+        };
+        for (reads, sleep, passes) in [
+            (10, Duration::ZERO, true),
+            (11, Duration::ZERO, false),
+            (0, Duration::from_millis(100), false),
+        ] {
+            let dir = tempfile::tempdir().unwrap();
+            let greedy = Greedy {
+                reads,
+                sleep,
+                budget,
+            };
+            let db = open(&dir, vec![Box::new(greedy)]).await;
+            let proposal = db.propose(vec![put(b"k", 1)]).await.unwrap();
+            match proposal.commit().await {
+                Ok(_) => assert!(passes),
+                Err(api::Error::InvariantBudgetExhausted { name, budget: b }) => {
+                    assert!(!passes);
+                    assert_eq!((name.as_str(), b), ("greedy", budget));
+                }
+                Err(err) => panic!("{err:?}"),
+            }
+        }
+    }
+
+    /// Keeps the change set of the last proposal it checked
+    #[derive(Debug, Default)]
+    struct Recorder(Arc<Mutex<ChangeSet>>);
+
+    impl CommitInvariant for Recorder {
+        fn name(&self) -> &str {
+            "recorder"
+        }
+
+        fn check(&self, _: &InvariantView<'_>, changes: &ChangeSet) -> Result<(), InvariantError> {
+            *self.0.lock().unwrap() = changes.clone();
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn change_sets() {
+        let dir = tempfile::tempdir().unwrap();
+        let recorded = Arc::new(Mutex::new(ChangeSet::default()));
+        let db = open(&dir, vec![Box::new(Recorder(recorded.clone()))]).await;
+        let batch = vec![put(b"a/1", 1), put(b"a/2", 2), put(b"b", 3)];
+        db.propose(batch).await.unwrap().commit().await.unwrap();
+
+        let batch = vec![
+            put(b"b", 3),
+            BatchOp::DeleteRange { prefix: b"a/" },
+            put(b"a/2", 4),
+            put(b"c", 5),
+        ];
+        let proposal = db.propose(batch).await.unwrap();
+        // on top of another proposal, the changes are to that proposal
+        let proposal = proposal
+            .propose(vec![put(b"c", 6), BatchOp::Delete { key: b"b" }])
+            .await
+            .unwrap();
+        proposal.check_invariants().unwrap();
+
+        let value = |amount: u64| Some(Box::from(amount.to_be_bytes()));
+        let change = |key: &[u8], old, new| KeyChange {
+            key: key.into(),
+            old,
+            new,
+        };
+        let changes = recorded.lock().unwrap().clone();
+        assert_eq!(
+            changes.iter().cloned().collect::<Vec<_>>(),
+            [
+                change(b"b", value(3), None),
+                change(b"c", value(5), value(6)),
+            ]
+        );
+
+        let parent = db.propose(vec![
+            put(b"b", 3),
+            BatchOp::DeleteRange { prefix: b"a/" },
+            put(b"a/2", 4),
+        ]);
+        parent.await.unwrap().check_invariants().unwrap();
+        let changes = recorded.lock().unwrap().clone();
+        assert_eq!(
+            changes.iter().cloned().collect::<Vec<_>>(),
+            [
+                change(b"a/1", value(1), None),
+                change(b"a/2", value(2), value(4)),
+            ]
+        );
+        assert_eq!(changes.prefix(b"a/").count(), 2);
+        assert_eq!(changes.prefix(b"a/2").count(), 1);
+        assert_eq!(changes.prefix(b"b").count(), 0);
+        assert_eq!(
+            changes.get(b"a/2"),
+            Some(&change(b"a/2", value(2), value(4)))
+        );
+        assert_eq!(changes.get(b"b"), None);
+    }
+}
@@ -0,0 +1,230 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The op journal: the batch each revision was proposed with, exactly as the
+//! caller passed it.
+//!
+//! The trie only records the net effect of a batch. When the way a batch is
+//! applied is itself in question, the original ops, including duplicates
+//! and ops that cancel out, are what is needed to replay it.
+//!
+//! Journals are kept outside the trie, in a directory next to the database
+//! file with one compressed file per revision, named by its root hash. They
+//! are not part of any root hash. A journal is removed when its revision is
+//! reaped, so only retained revisions have one.
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use integer_encoding::{VarInt, VarIntReader};
+use miniz_oxide::deflate::compress_to_vec;
+use miniz_oxide::inflate::decompress_to_vec;
+use storage::TrieHash;
+
+use crate::v2::api::{Batch, BatchOp, KeyType, ValueType};
+
+/// The ops of a journal, as returned by [crate::db::Db::op_journal]
+pub type JournalBatch = Batch<Box<[u8]>, Box<[u8]>>;
+
+const PUT: u8 = 0;
+const DELETE: u8 = 1;
+const DELETE_RANGE: u8 = 2;
+
+/// How hard to compress journals, from 0 to 10
+const COMPRESSION_LEVEL: u8 = 6;
+
+/// Encode and compress `batch`
+pub(crate) fn encode<K: KeyType, V: ValueType>(batch: &Batch<K, V>) -> Box<[u8]> {
+    let mut bytes = Vec::new();
+    for op in batch {
+        encode_op(&mut bytes, op);
+    }
+    compress(&bytes)
+}
+
+/// Append `op` to the uncompressed journal in `bytes`, for a batch whose
+/// ops are encoded one at a time
+pub(crate) fn encode_op<K: KeyType, V: ValueType>(bytes: &mut Vec<u8>, op: &BatchOp<K, V>) {
+    let write_bytes = |bytes: &mut Vec<u8>, data: &[u8]| {
+        bytes.extend(data.len().encode_var_vec());
+        bytes.extend_from_slice(data);
+    };
+    match op {
+        BatchOp::Put { key, value } => {
+            bytes.push(PUT);
+            write_bytes(bytes, key.as_ref());
+            write_bytes(bytes, value.as_ref());
+        }
+        BatchOp::Delete { key } => {
+            bytes.push(DELETE);
+            write_bytes(bytes, key.as_ref());
+        }
+        BatchOp::DeleteRange { prefix } => {
+            bytes.push(DELETE_RANGE);
+            write_bytes(bytes, prefix.as_ref());
+        }
+    }
+}
+
+/// Compress the ops encoded with [encode_op]
+pub(crate) fn compress(bytes: &[u8]) -> Box<[u8]> {
+    compress_to_vec(bytes, COMPRESSION_LEVEL).into()
+}
+
+/// Decompress and decode a batch written by [encode]
+pub(crate) fn decode(compressed: &[u8]) -> Result<JournalBatch, Error> {
+    let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("op journal: {what}"));
+    let bytes = decompress_to_vec(compressed).map_err(|_| invalid("can't decompress"))?;
+
+    let mut reader = bytes.as_slice();
+    let read_bytes = |reader: &mut &[u8]| -> Result<Box<[u8]>, Error> {
+        let len: usize = reader.read_varint()?;
+        if len > reader.len() {
+            return Err(invalid("truncated"));
+        }
+        let (data, rest) = reader.split_at(len);
+        *reader = rest;
+        Ok(data.into())
+    };
+
+    let mut batch = Vec::new();
+    while let Some((tag, rest)) = reader.split_first() {
+        reader = rest;
+        let op = match *tag {
+            PUT => BatchOp::Put {
+                key: read_bytes(&mut reader)?,
+                value: read_bytes(&mut reader)?,
+            },
+            DELETE => BatchOp::Delete {
+                key: read_bytes(&mut reader)?,
+            },
+            DELETE_RANGE => BatchOp::DeleteRange {
+                prefix: read_bytes(&mut reader)?,
+            },
+            _ => return Err(invalid("unknown op")),
+        };
+        batch.push(op);
+    }
+    Ok(batch)
+}
+
+/// A directory next to the database file with one file per retained
+/// revision, named by its root hash. Holds op journals, and the operational
+/// snapshots of [crate::snapshot].
+#[derive(Debug)]
+pub(crate) struct RevisionFiles {
+    dir: PathBuf,
+}
+
+impl RevisionFiles {
+    /// Open the directory named `extension` for the database file at
+    /// `db_path`, emptying it if `truncate` is set
+    pub(crate) fn open(db_path: &Path, extension: &str, truncate: bool) -> Result<Self, Error> {
+        let mut dir = db_path.as_os_str().to_owned();
+        dir.push(".");
+        dir.push(extension);
+        let dir = PathBuf::from(dir);
+        if truncate && dir.exists() {
+            fs::remove_dir_all(&dir)?;
+        }
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, root_hash: &TrieHash) -> PathBuf {
+        self.dir.join(hex::encode(root_hash))
+    }
+
+    /// Record the file of the revision with `root_hash`
+    pub(crate) fn write(&self, root_hash: &TrieHash, contents: &[u8]) -> Result<(), Error> {
+        // write then rename, so a crash never leaves a partial file
+        let path = self.path(root_hash);
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(".tmp");
+        fs::write(&tmp, contents)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// The file of the revision with `root_hash`, if there is one
+    pub(crate) fn read(&self, root_hash: &TrieHash) -> Result<Option<Vec<u8>>, Error> {
+        match fs::read(self.path(root_hash)) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) if err.kind() == ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Remove the file of a reaped revision
+    pub(crate) fn remove(&self, root_hash: &TrieHash) -> Result<(), Error> {
+        match fs::remove_file(self.path(root_hash)) {
+            Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Remove every file except those of `retained`
+    pub(crate) fn retain(&self, retained: &[TrieHash]) -> Result<(), Error> {
+        let retained: Vec<_> = retained.iter().map(hex::encode).collect();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            if !retained
+                .iter()
+                .any(|name| entry.file_name() == name.as_str())
+            {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The space the files take on disk, in bytes
+    pub(crate) fn disk_usage(&self) -> Result<u64, Error> {
+        fs::read_dir(&self.dir)?.try_fold(0, |total, entry| Ok(total + entry?.metadata()?.len()))
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let batch: Batch<&[u8], &[u8]> = vec![
+            BatchOp::Put {
+                key: b"k",
+                value: b"v",
+            },
+            BatchOp::Put {
+                key: b"k",
+                value: b"v",
+            },
+            BatchOp::Delete { key: b"missing" },
+            BatchOp::Put {
+                key: b"",
+                value: b"",
+            },
+            BatchOp::Delete { key: b"k" },
+            BatchOp::DeleteRange { prefix: b"" },
+        ];
+        let decoded = decode(&encode(&batch)).unwrap();
+        assert_eq!(decoded.len(), batch.len());
+        for (decoded, original) in decoded.iter().zip(&batch) {
+            match (decoded, original) {
+                (BatchOp::Put { key, value }, BatchOp::Put { key: k, value: v }) => {
+                    assert_eq!((&**key, &**value), (*k, *v));
+                }
+                (BatchOp::Delete { key }, BatchOp::Delete { key: k }) => assert_eq!(&**key, *k),
+                (BatchOp::DeleteRange { prefix }, BatchOp::DeleteRange { prefix: p }) => {
+                    assert_eq!(&**prefix, *p)
+                }
+                _ => panic!("ops don't match"),
+            }
+        }
+
+        assert!(decode(&encode::<&[u8], &[u8]>(&vec![])).unwrap().is_empty());
+        assert!(decode(b"not compressed").is_err());
+        assert!(decode(&compress_to_vec(&[PUT, 5, b'a'], 1)).is_err());
+    }
+}
@@ -0,0 +1,314 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Latency tracking for the public API.
+//!
+//! With the `metrics` feature enabled, every call to an instrumented method
+//! records its duration in the `firewood.api.latency` histogram, labelled by
+//! method. Calls slower than the ones currently held are also kept as
+//! [Exemplar](crate::latency::Exemplar)s, which record enough about the call to find out why it was
+//! slow. Exemplars are returned by [crate::db::Db::slow_operations].
+//!
+//! The fast path is a clock read, a histogram update and an atomic load.
+//! Without the feature, the timers compile to nothing.
+
+use std::time::Duration;
+
+use storage::TrieHash;
+
+/// The instrumented API methods
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApiMethod {
+    /// Reading a value from a revision or proposal
+    Get,
+    /// Creating a proposal
+    Propose,
+    /// Committing a proposal
+    Commit,
+    /// Creating a single key proof
+    Prove,
+    /// Reading the next key and value of an iteration
+    Iterate,
+}
+
+impl ApiMethod {
+    /// Every instrumented method
+    pub const ALL: [ApiMethod; 5] = [
+        Self::Get,
+        Self::Propose,
+        Self::Commit,
+        Self::Prove,
+        Self::Iterate,
+    ];
+
+    /// The label used for this method in metrics
+    pub const fn as_str(&self) -> &'static str {
+        match self {
+            Self::Get => "get",
+            Self::Propose => "propose",
+            Self::Commit => "commit",
+            Self::Prove => "prove",
+            Self::Iterate => "iterate",
+        }
+    }
+}
+
+/// A recent slow call to an API method
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Exemplar {
+    /// How long the call took
+    pub duration: Duration,
+    /// A hash of the first [KEY_PREFIX_LEN] bytes of the key, for methods
+    /// that take one. See [key_prefix_hash].
+    pub key_prefix_hash: Option<u64>,
+    /// The root hash of the revision or proposal the call used, if any
+    pub root_hash: Option<TrieHash>,
+    /// The number of node reads that missed the cache
+    pub cache_misses: u64,
+    /// The number of bytes read from storage
+    pub bytes_read: u64,
+}
+
+/// The number of key bytes that go into [Exemplar::key_prefix_hash]
+pub const KEY_PREFIX_LEN: usize = 8;
+
+/// The hash stored in [Exemplar::key_prefix_hash] for `key`. Only a prefix
+/// is hashed, so keys are not retained; this is stable within a process.
+pub fn key_prefix_hash(key: &[u8]) -> u64 {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    key.get(..KEY_PREFIX_LEN).unwrap_or(key).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(feature = "metrics")]
+pub(crate) use enabled::{slow_operations, OperationTimer};
+
+#[cfg(not(feature = "metrics"))]
+pub(crate) use disabled::{slow_operations, OperationTimer};
+
+#[cfg(feature = "metrics")]
+mod enabled {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Mutex, OnceLock};
+    use std::time::Instant;
+
+    use metrics::histogram;
+    use storage::{ReadStats, TrieHash};
+
+    use super::{key_prefix_hash, ApiMethod, Exemplar};
+
+    /// How many exemplars are kept for each method
+    const EXEMPLARS_PER_METHOD: usize = 16;
+
+    /// The slowest recent calls to one method. Once full, a new exemplar
+    /// replaces the oldest one, but only if it is at least as slow as the
+    /// fastest one held, so most calls only pay for the atomic load of
+    /// `threshold_nanos`.
+    #[derive(Debug, Default)]
+    struct ExemplarRing {
+        threshold_nanos: AtomicU64,
+        ring: Mutex<Ring>,
+    }
+
+    #[derive(Debug, Default)]
+    struct Ring {
+        entries: Vec<Exemplar>,
+        next: usize,
+    }
+
+    impl ExemplarRing {
+        fn admits(&self, nanos: u64) -> bool {
+            nanos >= self.threshold_nanos.load(Ordering::Relaxed)
+        }
+
+        fn insert(&self, exemplar: Exemplar) {
+            let mut ring = self.ring.lock().expect("poisoned lock");
+            let next = ring.next;
+            match ring.entries.get_mut(next) {
+                Some(oldest) => *oldest = exemplar,
+                None => ring.entries.push(exemplar),
+            }
+            ring.next = (next + 1) % EXEMPLARS_PER_METHOD;
+
+            if ring.entries.len() == EXEMPLARS_PER_METHOD {
+                let fastest = ring
+                    .entries
+                    .iter()
+                    .map(|exemplar| exemplar.duration)
+                    .min()
+                    .unwrap_or_default();
+                self.threshold_nanos
+                    .store(fastest.as_nanos() as u64, Ordering::Relaxed);
+            }
+        }
+
+        fn slowest(&self, n: usize) -> Vec<Exemplar> {
+            let mut entries = self.ring.lock().expect("poisoned lock").entries.clone();
+            entries.sort_by_key(|exemplar| std::cmp::Reverse(exemplar.duration));
+            entries.truncate(n);
+            entries
+        }
+    }
+
+    /// Exemplars are shared by every database in the process, like the
+    /// histograms they accompany
+    fn rings() -> &'static [ExemplarRing; ApiMethod::ALL.len()] {
+        static RINGS: OnceLock<[ExemplarRing; ApiMethod::ALL.len()]> = OnceLock::new();
+        RINGS.get_or_init(|| ApiMethod::ALL.map(|_| ExemplarRing::default()))
+    }
+
+    fn ring(method: ApiMethod) -> &'static ExemplarRing {
+        let [get, propose, commit, prove, iterate] = rings();
+        match method {
+            ApiMethod::Get => get,
+            ApiMethod::Propose => propose,
+            ApiMethod::Commit => commit,
+            ApiMethod::Prove => prove,
+            ApiMethod::Iterate => iterate,
+        }
+    }
+
+    pub(crate) fn slow_operations(method: ApiMethod, n: usize) -> Vec<Exemplar> {
+        ring(method).slowest(n)
+    }
+
+    /// Measures one call to an API method
+    #[derive(Debug)]
+    pub(crate) struct OperationTimer {
+        method: ApiMethod,
+        started: Instant,
+        reads: ReadStats,
+    }
+
+    impl OperationTimer {
+        pub(crate) fn start(method: ApiMethod) -> Self {
+            Self {
+                method,
+                started: Instant::now(),
+                reads: ReadStats::current(),
+            }
+        }
+
+        /// Record the call. `root_hash` is only evaluated if the call is
+        /// slow enough to become an exemplar.
+        pub(crate) fn finish(
+            self,
+            key: Option<&[u8]>,
+            root_hash: impl FnOnce() -> Option<TrieHash>,
+        ) {
+            let duration = self.started.elapsed();
+            histogram!("firewood.api.latency", "method" => self.method.as_str())
+                .record(duration.as_secs_f64());
+
+            let ring = ring(self.method);
+            if !ring.admits(duration.as_nanos() as u64) {
+                return;
+            }
+            let reads = ReadStats::current().since(&self.reads);
+            ring.insert(Exemplar {
+                duration,
+                key_prefix_hash: key.map(key_prefix_hash),
+                root_hash: root_hash(),
+                cache_misses: reads.cache_misses,
+                bytes_read: reads.bytes_read,
+            });
+        }
+    }
+
+    #[cfg(test)]
+    #[allow(clippy::unwrap_used)]
+    mod tests {
+        use std::time::Duration;
+
+        use super::*;
+
+        fn exemplar(millis: u64) -> Exemplar {
+            Exemplar {
+                duration: Duration::from_millis(millis),
+                key_prefix_hash: None,
+                root_hash: None,
+                cache_misses: 0,
+                bytes_read: 0,
+            }
+        }
+
+        #[test]
+        fn ring_keeps_slow_recent_calls() {
+            let ring = ExemplarRing::default();
+            for millis in 1..=EXEMPLARS_PER_METHOD as u64 {
+                assert!(ring.admits(0));
+                ring.insert(exemplar(millis));
+            }
+
+            // full: only calls at least as slow as the fastest held get in
+            assert!(!ring.admits(0));
+            assert!(ring.admits(Duration::from_millis(1).as_nanos() as u64));
+
+            // replacing the oldest (1ms) raises the bar to 2ms
+            ring.insert(exemplar(100));
+            assert!(!ring.admits(Duration::from_millis(1).as_nanos() as u64));
+
+            let slowest = ring.slowest(3);
+            let millis: Vec<_> = slowest
+                .iter()
+                .map(|exemplar| exemplar.duration.as_millis())
+                .collect();
+            assert_eq!(millis, vec![100, 16, 15]);
+        }
+    }
+}
+
+#[cfg(not(feature = "metrics"))]
+mod disabled {
+    use storage::TrieHash;
+
+    use super::{ApiMethod, Exemplar};
+
+    pub(crate) const fn slow_operations(_method: ApiMethod, _n: usize) -> Vec<Exemplar> {
+        Vec::new()
+    }
+
+    #[derive(Debug)]
+    pub(crate) struct OperationTimer;
+
+    impl OperationTimer {
+        #[inline(always)]
+        pub(crate) const fn start(_method: ApiMethod) -> Self {
+            Self
+        }
+
+        #[inline(always)]
+        pub(crate) fn finish(
+            self,
+            _key: Option<&[u8]>,
+            _root_hash: impl FnOnce() -> Option<TrieHash>,
+        ) {
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefix_hash_ignores_suffix() {
+        assert_eq!(
+            key_prefix_hash(b"abcdefgh-1"),
+            key_prefix_hash(b"abcdefgh-2")
+        );
+        assert_ne!(key_prefix_hash(b"abcdefg1"), key_prefix_hash(b"abcdefg2"));
+        assert_eq!(key_prefix_hash(b"short"), key_prefix_hash(b"short"));
+    }
+
+    #[test]
+    fn method_labels_are_unique() {
+        for (i, a) in ApiMethod::ALL.iter().enumerate() {
+            for b in ApiMethod::ALL.iter().skip(i + 1) {
+                assert_ne!(a.as_str(), b.as_str());
+            }
+        }
+    }
+}
@@ -106,26 +106,89 @@
 //! abandoned, nothing has actually been written to disk.
 //!
 #![warn(missing_debug_implementations, rust_2018_idioms, missing_docs)]
+/// Audit bundles: several reads of one revision proven together
+pub mod audit;
+
+/// Change proof module
+pub mod change_proof;
+
 /// Database module for Firewood.
 pub mod db;
 
+/// The addresses a commit is freeing, kept to recover from a crash
+pub(crate) mod delete_log;
+
+/// What changed between two revisions
+pub mod diff;
+
+/// Deciding when a put leaves the value of a key unchanged
+pub mod equivalence;
+
+/// Exporting a single revision, and building a database from one
+pub mod export;
+
+/// Application invariants checked as proposals are committed
+pub mod invariant;
+
 /// Database manager module
 pub mod manager;
 
+/// The original batches of retained revisions
+pub mod journal;
+
+/// Per-method latency histograms and exemplars of slow calls
+pub mod latency;
+
 /// Merkle module, containing merkle operations
 pub mod merkle;
 
+/// The metrics firewood emits, and their descriptions
+pub mod metrics;
+
+/// Cancellation and progress tracking for long-running operations
+pub mod operations;
+
 /// Proof module
 pub mod proof;
 
 /// Range proof module
 pub mod range_proof;
 
+/// The databases opened in the process to be shared
+pub(crate) mod registry;
+
+/// A revision written to a file as its keys and values
+pub mod portable;
+
+/// Reaping revisions off the commit path
+pub(crate) mod reaper;
+
+/// Restoring the database as it was at a point in time
+pub mod restore;
+
+/// The operational state of the database as each revision was committed
+pub mod snapshot;
+
+/// The size of the database and how its space is used
+pub mod statistics;
+
 /// Stream module, for both node and key-value streams
 pub mod stream;
 
+/// Syncing a database to a root from proofs of another
+pub mod sync;
+
+/// The key space reserved for firewood's own records
+pub mod system;
+
+/// Tokens that keep several reads on one revision
+pub mod token;
+
 /// Version 2 API
 pub mod v2;
 
+/// Checking a database file for corruption
+pub mod verify;
+
 /// Expose the storage logger
 pub use storage::logger;
@@ -5,16 +5,41 @@
 
 use std::collections::{HashMap, VecDeque};
 use std::io::Error;
+use std::mem::take;
 use std::num::NonZero;
 use std::path::PathBuf;
-use std::sync::Arc;
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime};
 
+use fastrace::local::LocalSpan;
+use metrics::histogram;
 use storage::logger::warn;
+use tokio::sync::{broadcast, watch};
 use typed_builder::TypedBuilder;
 
+use crate::delete_log::DeleteLog;
+use crate::journal::{self, JournalBatch, RevisionFiles};
+use crate::reaper::Reaper;
+use crate::restore::CommitRecord;
+use crate::snapshot::OperationalSnapshot;
+use crate::system::{SystemKeys, DEFAULT_SYSTEM_PREFIX};
 use crate::v2::api::HashKey;
 
-use storage::{Committed, FileBacked, ImmutableProposal, NodeStore, Parentable, TrieHash};
+use storage::{
+    Committed, FileBacked, ImmutableProposal, LinearAddress, NodeStore, Parentable, TrieHash,
+    DEFAULT_MAX_FLUSH_WRITE, MAX_UNPROMOTED,
+};
+
+pub use storage::{AllocationPolicy, NodeCachePolicy, ReadAhead};
+
+/// The most revisions [RevisionManagerConfig]'s `max_revision_age` keeps,
+/// so that a burst of commits can't fill the disk with old nodes. A larger
+/// `max_revisions` still applies.
+pub const MAX_REVISIONS_KEPT_BY_AGE: usize = 4096;
+
+/// How many [CommitEvent]s a subscriber can fall behind by before it misses
+/// the oldest ones
+pub const COMMIT_EVENT_CAPACITY: usize = 1024;
 
 #[derive(Clone, Debug, TypedBuilder)]
 /// Revision manager configuratoin
@@ -23,15 +48,153 @@ pub struct RevisionManagerConfig {
     #[builder(default = 128)]
     max_revisions: usize,
 
+    /// Also keep every revision committed less than this long ago, however
+    /// many there are, up to [MAX_REVISIONS_KEPT_BY_AGE]. Revisions
+    /// committed before the database was opened don't count as recent.
+    #[builder(default)]
+    max_revision_age: Option<Duration>,
+
     /// The size of the node cache
     #[builder(default_code = "NonZero::new(1500000).expect(\"non-zero\")")]
     node_cache_size: NonZero<usize>,
 
+    /// Which nodes the node cache keeps
+    #[builder(default)]
+    node_cache_policy: NodeCachePolicy,
+
     #[builder(default_code = "NonZero::new(40000).expect(\"non-zero\")")]
     free_list_cache_size: NonZero<usize>,
+
+    /// How proposals place their new nodes in the file
+    #[builder(default)]
+    allocation_policy: AllocationPolicy,
+
+    /// How many threads may hash the new nodes of a proposal. Proposals
+    /// with fewer than [storage::PARALLEL_HASH_THRESHOLD] new nodes are
+    /// hashed on one thread. The hashes don't depend on it.
+    #[builder(default = NonZero::<usize>::MIN)]
+    hash_threads: NonZero<usize>,
+
+    /// How many threads may write the new nodes of a proposal when it is
+    /// committed. Commits with fewer than [storage::PARALLEL_FLUSH_THRESHOLD]
+    /// new nodes write them on one thread. The file is still synced once.
+    #[builder(default = NonZero::<usize>::MIN)]
+    flush_parallelism: NonZero<usize>,
+
+    /// The most bytes of adjacent node areas a commit writes with a single
+    /// call. Larger writes mean fewer system calls but larger buffers.
+    #[builder(default = DEFAULT_MAX_FLUSH_WRITE)]
+    max_flush_write: usize,
+
+    /// Punch holes in the file for free areas of at least this many bytes,
+    /// giving their disk space back to the file system where it supports
+    /// that. Only areas that no retained revision can reach are ever freed.
+    #[builder(default)]
+    hole_punch_threshold: Option<u64>,
+
+    /// Open the file with direct I/O on Linux, bypassing the page cache,
+    /// which would otherwise hold the nodes the node cache already does.
+    /// Opening fails on file systems that don't support it. Ignored with a
+    /// warning on other platforms.
+    #[builder(default)]
+    direct_io: bool,
+
+    /// How far iterations read ahead of themselves, on a background thread.
+    /// Nodes read ahead are kept apart from the node cache until they are
+    /// used, so they don't evict nodes point lookups need. Off by default.
+    #[builder(default)]
+    read_ahead: ReadAhead,
+
+    /// Record an [OperationalSnapshot] with every commit, for
+    /// [crate::db::Db::operational_snapshot]. Snapshots are stored in a
+    /// directory next to the database file.
+    #[builder(default)]
+    retain_operational_snapshots: bool,
+
+    /// When commits sync the file to disk
+    #[builder(default)]
+    durability: DurabilityMode,
+
+    /// Look up the areas of reaped revisions on a background thread
+    /// instead of in the commit that reaps them, so that reaping a large
+    /// revision doesn't slow that commit down. The areas are freed by a
+    /// later commit. See [crate::reaper] for what a crash does to them.
+    #[builder(default)]
+    background_reaping: bool,
+}
+
+/// When commits sync the database file to disk, trading how many of the
+/// latest commits a crash can lose for commit throughput
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum DurabilityMode {
+    /// Sync the nodes of each commit before its root is written to the
+    /// header, and the header after it, so a commit is durable once it is
+    /// published
+    #[default]
+    Strict,
+    /// Sync once `commits` commits were published since the last sync, or
+    /// once `interval` has passed since it, whichever comes first. The root
+    /// in the header only moves when the file is synced, so a crash reopens
+    /// the database at the revision synced last, never at one whose nodes
+    /// may not have reached the disk.
+    Buffered {
+        /// How many commits to sync after
+        commits: NonZero<usize>,
+        /// How long to sync after
+        interval: Duration,
+    },
+    /// Never sync, leaving it to the file system when the writes reach the
+    /// disk. Only for tests and benchmarks: a crash can leave the header
+    /// pointing at nodes that were never written.
+    None,
+}
+
+impl RevisionManagerConfig {
+    /// Keep at least `revisions` revisions
+    pub(crate) fn retain_at_least(mut self, revisions: usize) -> Self {
+        self.max_revisions = self.max_revisions.max(revisions);
+        self
+    }
+
+    /// When commits sync the file to disk
+    pub const fn durability(&self) -> DurabilityMode {
+        self.durability
+    }
 }
 
-type CommittedRevision = Arc<NodeStore<Committed, FileBacked>>;
+/// The number of [PinGuard]s held for each revision
+#[derive(Debug, Default)]
+struct Pins(Mutex<HashMap<TrieHash, usize>>);
+
+/// Keeps a revision from being reaped while it is held, so it can still be
+/// opened by its root hash. Returned by [crate::db::Db::pin]; once the last
+/// guard for a revision is dropped, it can be reaped by the next commit.
+#[derive(Debug)]
+pub struct PinGuard {
+    pins: Arc<Pins>,
+    root_hash: TrieHash,
+}
+
+impl PinGuard {
+    /// The root hash of the pinned revision
+    pub const fn root_hash(&self) -> &TrieHash {
+        &self.root_hash
+    }
+}
+
+impl Drop for PinGuard {
+    fn drop(&mut self) {
+        let mut pins = self.pins.0.lock().expect("poisoned lock");
+        if let Some(count) = pins.get_mut(&self.root_hash) {
+            *count -= 1;
+            if *count == 0 {
+                pins.remove(&self.root_hash);
+            }
+        }
+    }
+}
+
+pub(crate) type CommittedRevision = Arc<NodeStore<Committed, FileBacked>>;
 type ProposedRevision = Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>>;
 
 #[derive(Debug)]
@@ -45,9 +208,68 @@ pub(crate) struct RevisionManager {
     /// The list of revisions that are on disk; these point to the different roots
     /// stored in the filebacked storage.
     historical: VecDeque<CommittedRevision>,
+    /// When set, commits leave the root in the header alone, and it only
+    /// moves when [RevisionManager::promote] is called
+    external_root_authority: bool,
+    /// The revision whose root is in the header
+    promoted: CommittedRevision,
+    /// Durable unpromoted revisions found in the header on open. They are
+    /// not part of `historical`, since how they relate to each other is not
+    /// recorded.
+    reopened: Vec<CommittedRevision>,
     proposals: Vec<ProposedRevision>,
-    // committing_proposals: VecDeque<Arc<ProposedImmutable>>,
+    /// Where the areas a commit is freeing are recorded until it completes
+    delete_log: DeleteLog,
+    /// Where the op journals of retained revisions are kept, if they are
+    journal: Option<RevisionFiles>,
+    /// Where the operational snapshots of retained revisions are kept, if
+    /// they are
+    snapshots: Option<RevisionFiles>,
+    /// The node cache hits and misses as of the last commit
+    cache_lookups: (u64, u64),
+    /// Commits that started but aren't published yet, oldest first. Each
+    /// is on top of the one before it, and the oldest is on top of the
+    /// latest revision in `historical`.
+    committing: VecDeque<Arc<PendingCommit>>,
+    /// The number of commits started since the database was opened
+    begun: u64,
+    /// Whether the delete log has to be cleared once nothing is committing
+    delete_log_written: bool,
+    /// Where commits waiting for the ones before them find out they were
+    /// published
+    progress: watch::Sender<CommitProgress>,
+    /// Where subscribers hear about each revision that becomes the latest
+    commits: broadcast::Sender<CommitEvent>,
     by_hash: HashMap<TrieHash, CommittedRevision>,
+    /// The root hashes of the most recently reaped revisions, oldest first,
+    /// so that looking one up can tell it was reaped. At most
+    /// `max_revisions` are remembered.
+    reaped: VecDeque<TrieHash>,
+    /// The number of commits since the database was opened
+    epoch: u64,
+    /// The revisions held by a [PinGuard]
+    pins: Arc<Pins>,
+    /// The commits since the database was opened whose revisions may still
+    /// be retained, oldest first
+    commit_log: VecDeque<CommitRecord>,
+    /// Set when it was opened with [RevisionManager::open_read_only]:
+    /// nothing is written to the file, and the commits another process
+    /// makes to it are picked up by [RevisionManager::reload]
+    read_only: bool,
+    /// The number of commits published since the file was last synced,
+    /// with [DurabilityMode::Buffered]
+    unsynced: usize,
+    /// When the file was last synced
+    synced_at: Instant,
+    /// The areas the commits published since the file was last synced
+    /// freed, which stay in the delete log until it is synced
+    unsynced_freed: Vec<LinearAddress>,
+    /// Where reaped revisions are sent, with background reaping
+    reaper: Option<Reaper>,
+    /// Revisions reaped by [RevisionManager::set_max_revisions], whose
+    /// nodes the next commit frees
+    deferred: Vec<NodeStore<Committed, FileBacked>>,
+    config: RevisionManagerConfig,
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -60,6 +282,113 @@ pub(crate) enum RevisionManagerError {
     NotLatest,
     #[error("An IO error occurred during the commit")]
     IO(#[from] std::io::Error),
+    #[error("There are already {0} unpromoted revisions")]
+    TooManyUnpromoted(usize),
+    #[error("The revision cannot be promoted: {0}")]
+    CannotPromote(&'static str),
+    #[error("The proposal was made before the database was compacted")]
+    Invalidated,
+    #[error("The proposal has {0} proposals on top of it")]
+    HasChildren(usize),
+    #[error("There is no revision {back} commits back, only {retained} are retained")]
+    OutOfRange { back: usize, retained: usize },
+    #[error(
+        "There is no revision at height {height}, the oldest retained is at {oldest_available}"
+    )]
+    HeightNotFound { height: u64, oldest_available: u64 },
+    #[error("No revision with root hash {0:?} was committed recently")]
+    UnknownRevision(TrieHash),
+    #[error("The revision with root hash {0:?} was reaped")]
+    RevisionReaped(TrieHash),
+    #[error("The database is empty, so it has no revisions to look up")]
+    EmptyDatabase,
+    #[error("A commit started before this one failed, so it was not published")]
+    EarlierCommitFailed,
+    #[error("The database was opened read-only")]
+    ReadOnly,
+    #[error("The revision with root hash {0:?} is pinned, so it can't be reaped")]
+    RevisionPinned(TrieHash),
+}
+
+/// A commit that has started, and is the parent of the next one to start,
+/// but isn't published yet. Its nodes are flushed without holding the
+/// manager, and it is published once they and those of every commit
+/// started before it are on disk.
+#[derive(Debug)]
+pub(crate) struct PendingCommit {
+    /// Its place among the commits started since the database was opened
+    sequence: u64,
+    proposal: ProposedRevision,
+    committed: CommittedRevision,
+    journal: Option<Box<[u8]>>,
+    /// The areas it freed by reaping, which stay in the delete log until
+    /// it is published
+    freed: Box<[LinearAddress]>,
+    reap: Duration,
+    flush_freelist: Duration,
+    /// How long flushing its nodes took, once they are flushed
+    flushed: OnceLock<Duration>,
+    /// Whether the file is synced once its nodes are flushed
+    sync: bool,
+}
+
+impl PendingCommit {
+    /// Step 6 of [RevisionManager::commit], which doesn't need the manager
+    pub fn flush_nodes(&self) -> Result<(), RevisionManagerError> {
+        let flush_start = Instant::now();
+        let _span = LocalSpan::enter_with_local_parent("node_flush");
+        self.proposal.flush_nodes()?;
+        if self.sync {
+            self.proposal.storage.sync()?;
+        }
+        // a commit is only flushed once
+        let _ = self.flushed.set(flush_start.elapsed());
+        Ok(())
+    }
+
+    /// The root hash of the revision it commits, or None if it is empty
+    pub fn root_hash(&self) -> Option<HashKey> {
+        self.committed.kind.root_hash()
+    }
+
+    /// Wait until it is published. Fails if a commit started before it
+    /// failed, since then it never will be.
+    pub async fn published(
+        &self,
+        mut progress: watch::Receiver<CommitProgress>,
+    ) -> Result<(), RevisionManagerError> {
+        let progress = *progress
+            .wait_for(|progress| {
+                progress.published >= self.sequence || progress.failed >= self.sequence
+            })
+            .await
+            .map_err(|_| RevisionManagerError::EarlierCommitFailed)?;
+        match progress.published >= self.sequence {
+            true => Ok(()),
+            false => Err(RevisionManagerError::EarlierCommitFailed),
+        }
+    }
+}
+
+/// How far the commits started since the database was opened have got
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct CommitProgress {
+    /// The sequence of the last one published
+    published: u64,
+    /// The sequence of the last one that will never be published, since it
+    /// or one before it failed
+    failed: u64,
+}
+
+/// A revision that became the latest one, from [crate::db::Db::subscribe]
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CommitEvent {
+    /// The number of commits since the database was opened, including this
+    /// one, as in [CommitRecord::sequence]
+    pub sequence: u64,
+    /// The root hash of the revision, or None if it is empty
+    pub root_hash: Option<TrieHash>,
 }
 
 impl RevisionManager {
@@ -67,30 +396,99 @@ impl RevisionManager {
         filename: PathBuf,
         truncate: bool,
         config: RevisionManagerConfig,
+        system_prefix: &[u8],
+        system_keys: SystemKeys,
+        external_root_authority: bool,
+        retain_op_journal: bool,
     ) -> Result<Self, Error> {
-        let storage = Arc::new(FileBacked::new(
-            filename,
-            config.node_cache_size,
-            config.free_list_cache_size,
-            truncate,
-        )?);
-        let nodestore = match truncate {
-            true => Arc::new(NodeStore::new_empty_committed(storage.clone())?),
-            false => Arc::new(NodeStore::open(storage.clone())?),
+        // the file is opened first, since truncating it fails while a
+        // reader has it open, and the files next to it must be left alone then
+        let storage = Arc::new(
+            FileBacked::new(
+                filename.clone(),
+                config.node_cache_size,
+                config.free_list_cache_size,
+                truncate,
+            )?
+            .with_allocation_policy(config.allocation_policy)
+            .with_hash_threads(config.hash_threads)
+            .with_flush_parallelism(config.flush_parallelism)
+            .with_max_flush_write(config.max_flush_write)
+            .with_node_cache_policy(config.node_cache_policy)
+            .with_hole_punch_threshold(config.hole_punch_threshold)
+            .with_direct_io(config.direct_io)?
+            .with_read_ahead(config.read_ahead)?,
+        );
+        let journal = match retain_op_journal {
+            true => Some(RevisionFiles::open(&filename, "journal", truncate)?),
+            false => None,
         };
-        let mut manager = Self {
-            max_revisions: config.max_revisions,
-            filebacked: storage,
-            historical: VecDeque::from([nodestore.clone()]),
-            by_hash: Default::default(),
-            proposals: Default::default(),
-            // committing_proposals: Default::default(),
+        let snapshots = match config.retain_operational_snapshots {
+            true => Some(RevisionFiles::open(&filename, "snapshots", truncate)?),
+            false => None,
+        };
+        let delete_log = DeleteLog::open(&filename, truncate)?;
+        let mut nodestore = match truncate {
+            true => NodeStore::new_empty_committed(storage.clone())?,
+            false => NodeStore::open(storage.clone())?,
         };
-        if nodestore.kind.root_hash().is_some() {
-            manager.by_hash.insert(
-                nodestore.kind.root_hash().expect("root hash is present"),
-                nodestore.clone(),
+        // A commit was interrupted after it started freeing areas. Whether
+        // it got as far as moving the root, the areas it freed are no longer
+        // used by the root in the header.
+        if let Some(record) = delete_log.read()? {
+            #[cfg_attr(not(feature = "logger"), allow(unused_variables))]
+            let recovery = nodestore.recover_freed(&record.freed)?;
+            warn!(
+                "Recovered an interrupted commit to {:?}, root in header {:?}: {} of {} freed areas requeued, {} free lists cut",
+                record.target_root,
+                nodestore.root_address(),
+                recovery.requeued,
+                record.freed.len(),
+                recovery.cut
             );
+            nodestore.flush_header()?;
+            delete_log.clear()?;
+        }
+        nodestore.set_reserved_keys(system_prefix, system_keys == SystemKeys::Hidden)?;
+
+        // Without an external authority, nothing will ever promote the
+        // unpromoted revisions, so they are discarded. Their nodes are
+        // leaked rather than freed.
+        let reopened = match external_root_authority {
+            true => nodestore
+                .unpromoted_roots()
+                .into_iter()
+                .map(|(root, height)| nodestore.open_root(root, height).map(Arc::new))
+                .collect::<Result<Vec<_>, _>>()?,
+            false => {
+                if !nodestore.unpromoted_roots().is_empty() {
+                    nodestore.clear_unpromoted();
+                    nodestore.flush_header()?;
+                }
+                Vec::new()
+            }
+        };
+
+        let reaper = match config.background_reaping {
+            true => Some(Reaper::start()?),
+            false => None,
+        };
+        let nodestore = Arc::new(nodestore);
+        let mut manager = Self::with_revision(storage, nodestore.clone(), delete_log, config);
+        manager.reaper = reaper;
+        manager.external_root_authority = external_root_authority;
+        manager.journal = journal;
+        manager.snapshots = snapshots;
+        for revision in &reopened {
+            if let Some(hash) = revision.kind.root_hash() {
+                manager.by_hash.entry(hash).or_insert(revision.clone());
+            }
+        }
+        manager.reopened = reopened;
+        // the revisions before the opened ones were reaped with the old manager
+        let retained: Vec<_> = manager.by_hash.keys().cloned().collect();
+        for files in manager.journal.iter().chain(&manager.snapshots) {
+            files.retain(&retained)?;
         }
 
         if truncate {
@@ -100,6 +498,126 @@ impl RevisionManager {
         Ok(manager)
     }
 
+    /// Open an existing file without writing to it, while another process
+    /// may be committing to it. Only the revision whose root is in the
+    /// header is retained at first, and [RevisionManager::reload] adds the
+    /// ones committed after it. Op journals, snapshots, unpromoted
+    /// revisions and the delete log are left to the writer.
+    pub fn open_read_only(
+        filename: PathBuf,
+        config: RevisionManagerConfig,
+        system_prefix: &[u8],
+        system_keys: SystemKeys,
+    ) -> Result<Self, Error> {
+        // the log is only read and written by commits, which can't happen
+        let delete_log = DeleteLog::open(&filename, false)?;
+        let storage = Arc::new(
+            FileBacked::open_read_only(
+                filename,
+                config.node_cache_size,
+                config.free_list_cache_size,
+            )?
+            .with_node_cache_policy(config.node_cache_policy)
+            .with_direct_io(config.direct_io)?
+            .with_read_ahead(config.read_ahead)?,
+        );
+        let mut nodestore = NodeStore::open(storage.clone())?;
+        nodestore.set_reserved_keys(system_prefix, system_keys == SystemKeys::Hidden)?;
+        let mut manager = Self::with_revision(storage, Arc::new(nodestore), delete_log, config);
+        manager.read_only = true;
+        Ok(manager)
+    }
+
+    /// A manager retaining only `revision`, with nothing else set up
+    fn with_revision(
+        storage: Arc<FileBacked>,
+        revision: CommittedRevision,
+        delete_log: DeleteLog,
+        config: RevisionManagerConfig,
+    ) -> Self {
+        let cache_lookups = storage.node_cache_lookups();
+        let by_hash = revision
+            .kind
+            .root_hash()
+            .map(|hash| (hash, revision.clone()))
+            .into_iter()
+            .collect();
+        Self {
+            max_revisions: config.max_revisions,
+            filebacked: storage,
+            historical: VecDeque::from([revision.clone()]),
+            external_root_authority: false,
+            promoted: revision,
+            reopened: Default::default(),
+            by_hash,
+            reaped: Default::default(),
+            proposals: Default::default(),
+            delete_log,
+            journal: None,
+            snapshots: None,
+            cache_lookups,
+            epoch: 0,
+            pins: Default::default(),
+            commit_log: Default::default(),
+            read_only: false,
+            unsynced: 0,
+            synced_at: Instant::now(),
+            unsynced_freed: Vec::new(),
+            reaper: None,
+            deferred: Vec::new(),
+            config,
+            committing: Default::default(),
+            begun: 0,
+            delete_log_written: false,
+            progress: watch::Sender::new(CommitProgress::default()),
+            commits: broadcast::channel(COMMIT_EVENT_CAPACITY).0,
+        }
+    }
+
+    /// Read the header again, to pick up what the process writing to the
+    /// file committed since it was last read. If the header has a new
+    /// revision, it becomes the latest one, and the oldest are dropped to
+    /// keep `max_revisions`; only the writer frees their nodes. The writer
+    /// may reuse the space of the revisions it reaped, so those retained
+    /// here can only be read until it does. Does nothing unless it was
+    /// opened with [RevisionManager::open_read_only], since it would have
+    /// made every commit to the file itself.
+    pub fn reload(&mut self) -> Result<(), RevisionManagerError> {
+        if !self.read_only {
+            return Ok(());
+        }
+        let current = self.current_revision();
+        let prefix = current.reserved_prefix().unwrap_or(DEFAULT_SYSTEM_PREFIX);
+        let hidden = current.is_hidden(prefix);
+        let mut reloaded = NodeStore::open(self.filebacked.clone())?;
+        reloaded.set_reserved_keys(prefix, hidden)?;
+        if reloaded.kind.root_hash() == current.kind.root_hash()
+            && reloaded.height() == current.height()
+        {
+            return Ok(());
+        }
+
+        // nodes cached at an address the writer has since reused are stale
+        self.filebacked.shed_cache(1.0);
+        while self.historical.len() >= self.max_revisions.max(1) {
+            if self
+                .historical
+                .front()
+                .is_some_and(|oldest| self.is_pinned(oldest))
+                || self.oldest_is_recent(self.historical.len())
+            {
+                break;
+            }
+            let oldest = self.historical.pop_front().expect("must be present");
+            if let Some(hash) = oldest.kind.root_hash() {
+                self.by_hash.remove(&hash);
+                self.remember_reaped(hash);
+            }
+        }
+        self.push_latest(Arc::new(reloaded));
+        Ok(())
+    }
+
     pub fn all_hashes(&self) -> Vec<TrieHash> {
         self.historical
             .iter()
@@ -108,19 +626,42 @@ impl RevisionManager {
             .collect()
     }
 
+    /// The number of committed revisions retained in memory, up to
+    /// `max_revisions` unless more are kept for promotion, pinned or
+    /// recent
+    pub fn revision_count(&self) -> usize {
+        self.historical.len()
+    }
+
+    /// The number of proposals tracked, including abandoned ones that
+    /// haven't been pruned yet
+    pub const fn proposal_count(&self) -> usize {
+        self.proposals.len()
+    }
+
+    /// The root hash of the oldest retained revision, which is the next to
+    /// be reaped once `max_revisions` are retained, or None if it is empty
+    pub fn oldest_hash(&self) -> Option<TrieHash> {
+        self.historical
+            .front()
+            .and_then(|revision| revision.kind.root_hash())
+    }
+
     /// Commit a proposal
     /// To commit a proposal involves a few steps:
     /// 1. Commit check.
     ///    The proposal’s parent must be the last committed revision, otherwise the commit fails.
+    ///    While commits are pending, it must instead be the proposal of the last pending one.
     /// 2. Persist delete list.
-    ///    The list of all nodes that were to be deleted for this proposal must be fully flushed to disk.
-    ///    The address of the root node and the root hash is also persisted.
+    ///    The list of all nodes that are freed by reaping in step 3 must be fully flushed to disk,
+    ///    along with the address of the new root. It is removed once step 7 is done, and if it is
+    ///    found on open, its nodes are put back on the free lists.
     ///    Note that this is *not* a write ahead log.
     ///    It only contains the address of the nodes that are deleted, which should be very small.
     /// 3. Revision reaping. If more than the maximum number of revisions are kept in memory, the
     ///    oldest revision is reaped.
     /// 4. Set last committed revision.
-    ///    Set last committed revision in memory.
+    ///    The commit is added to the pending list, so the next commit checks its parent against it.
     ///    Another commit can start after this but before the node flush is completed.
     /// 5. Free list flush.
     ///    Persist/write the free list header.
@@ -133,75 +674,505 @@ impl RevisionManager {
     /// 7. Root move.
     ///    The root address on disk must be updated.
     ///    This write can be delayed, but would mean that recovery will not roll forward to this revision.
+    ///    The commit is published here, in the order commits started, once the nodes of every
+    ///    commit before it are flushed too; until then, readers see the revision before it.
     /// 8. Proposal Cleanup.
     ///    Any other proposals that have this proposal as a parent should be reparented to the committed version.
+    ///
+    /// Steps 1 to 5 are [RevisionManager::begin_commit], step 6 is [PendingCommit::flush_nodes],
+    /// which doesn't need the manager, and steps 7 and 8 are [RevisionManager::publish_flushed].
+    /// This function does all of them, and the commit is published when it returns unless one
+    /// started before it is still flushing.
+    ///
+    /// With an external root authority, step 7 instead records the new revision as unpromoted,
+    /// leaving the root in the header where it was, and step 3 never reaps a revision newer than
+    /// the promoted one, since the revisions after it still need the nodes it would free.
+    ///
+    /// With op journals retained, `journal` is written before step 7, and the journal of a
+    /// revision is removed when it is reaped in step 3. Operational snapshots are written and
+    /// removed at the same points.
+    ///
+    /// With [DurabilityMode::Strict], the file is synced after step 6 and again after step 7.
+    /// With [DurabilityMode::Buffered], step 7 is left to the next sync, which writes the root of
+    /// the latest published revision, and the delete log keeps the areas freed until then.
+    ///
+    /// Returns the root hash of the new revision, or None if it is empty.
     #[fastrace::trace(short_name = true)]
-    pub fn commit(&mut self, proposal: ProposedRevision) -> Result<(), RevisionManagerError> {
+    pub fn commit(
+        &mut self,
+        proposal: ProposedRevision,
+        journal: Option<&[u8]>,
+    ) -> Result<Option<HashKey>, RevisionManagerError> {
+        let height = self.next_height();
+        self.commit_at_height(proposal, journal, height)
+    }
+
+    /// Commit a proposal as [RevisionManager::commit] does, but recording
+    /// it at `height`, for revisions copied from another database
+    pub(crate) fn commit_at_height(
+        &mut self,
+        proposal: ProposedRevision,
+        journal: Option<&[u8]>,
+        height: u64,
+    ) -> Result<Option<HashKey>, RevisionManagerError> {
+        let pending = self.begin_commit_at_height(proposal, journal, height)?;
+        if let Err(err) = pending.flush_nodes() {
+            self.fail_pending();
+            return Err(err);
+        }
+        self.publish_flushed()?;
+        Ok(pending.root_hash())
+    }
+
+    /// Steps 1 to 5 of [RevisionManager::commit]. The commit is pending
+    /// once this returns: a proposal on top of it can be committed next,
+    /// while its nodes are flushed with [PendingCommit::flush_nodes].
+    pub fn begin_commit(
+        &mut self,
+        proposal: ProposedRevision,
+        journal: Option<&[u8]>,
+    ) -> Result<Arc<PendingCommit>, RevisionManagerError> {
+        let height = self.next_height();
+        self.begin_commit_at_height(proposal, journal, height)
+    }
+
+    fn begin_commit_at_height(
+        &mut self,
+        proposal: ProposedRevision,
+        journal: Option<&[u8]>,
+        height: u64,
+    ) -> Result<Arc<PendingCommit>, RevisionManagerError> {
         // 1. Commit check
-        let current_revision = self.current_revision();
-        if !proposal
-            .kind
-            .parent_hash_is(current_revision.kind.root_hash())
-        {
-            return Err(RevisionManagerError::NotLatest);
+        if self.read_only {
+            return Err(RevisionManagerError::ReadOnly);
+        }
+        if !Arc::ptr_eq(&proposal.storage, &self.filebacked) {
+            return Err(RevisionManagerError::Invalidated);
+        }
+        if !self.is_on_latest(&proposal) {
+            // a committed parent that isn't the latest had another proposal
+            // on top of it committed, and so did a pending one
+            let sibling = proposal.kind.parent_is_committed()
+                || self
+                    .committing
+                    .iter()
+                    .any(|pending| pending.proposal.is_parent_of(&proposal));
+            return Err(match sibling {
+                true => RevisionManagerError::SiblingCommitted,
+                false => RevisionManagerError::NotLatest,
+            });
+        }
+        if self.external_root_authority {
+            let unpromoted = self.unpromoted().count() + self.committing.len();
+            if unpromoted >= MAX_UNPROMOTED {
+                return Err(RevisionManagerError::TooManyUnpromoted(unpromoted));
+            }
         }
 
         let mut committed = proposal.as_committed();
+        committed.set_height(height);
 
-        // 2. Persist delete list for this committed revision to disk for recovery
+        // 3. Pick the oldest revisions to reap; their deleted entries are freed below
+        let reap_start = Instant::now();
+        let reap_span = LocalSpan::enter_with_local_parent("reap");
+        // the ones set_max_revisions reaped are freed along with them
+        let mut reaped = take(&mut self.deferred);
+        while self.historical.len() + self.committing.len() >= self.max_revisions {
+            if self.oldest_is_kept() {
+                break;
+            }
+            match self.unlink_oldest()? {
+                Some(oldest) => reaped.push(oldest),
+                None => break,
+            }
+        }
 
-        // 3 Take the deleted entries from the oldest revision and mark them as free for this revision
-        // If you crash after freeing some of these, then the free list will point to nodes that are not actually free.
-        // TODO: Handle the case where we get something off the free list that is not free
-        while self.historical.len() >= self.max_revisions {
-            let oldest = self.historical.pop_front().expect("must be present");
-            if let Some(oldest_hash) = oldest.kind.root_hash() {
-                self.by_hash.remove(&oldest_hash);
-            }
-
-            // This `try_unwrap` is safe because nobody else will call `try_unwrap` on this Arc
-            // in a different thread, so we don't have to worry about the race condition where
-            // the Arc we get back is not usable as indicated in the docs for `try_unwrap`.
-            // This guarantee is there because we have a `&mut self` reference to the manager, so
-            // the compiler guarantees we are the only one using this manager.
-            match Arc::try_unwrap(oldest) {
-                Ok(oldest) => oldest.reap_deleted(&mut committed)?,
-                Err(original) => {
-                    warn!("Oldest revision could not be reaped; still referenced");
-                    self.historical.push_front(original);
-                    break;
+        // With background reaping, the reaped revisions go to the reaper, and the areas
+        // it looked up for the ones before are freed instead. Taking them before sending
+        // keeps a fast reaper from handing back this commit's own revisions.
+        let resolved = match &self.reaper {
+            Some(reaper) => {
+                let resolved = reaper.take_resolved();
+                for revision in take(&mut reaped) {
+                    reaper.send(revision);
                 }
+                resolved
             }
+            None => Vec::new(),
+        };
+
+        // 2. Persist delete list for this committed revision to disk for recovery. Freeing
+        // writes into the areas before the free lists leading to them are flushed, so a
+        // crash in between is repaired from this list on the next open. There is only one
+        // log, so it also keeps the areas the pending commits freed until they are published.
+        let freed: Vec<_> = reaped
+            .iter()
+            .flat_map(|revision| revision.deleted())
+            .chain(resolved.iter().map(|(addr, _)| addr))
+            .copied()
+            .collect();
+        if !freed.is_empty() {
+            let logged: Vec<_> = self
+                .committing
+                .iter()
+                .flat_map(|pending| pending.freed.iter())
+                .chain(&self.unsynced_freed)
+                .chain(&freed)
+                .copied()
+                .collect();
+            self.delete_log.write(committed.root_address(), &logged)?;
+            self.delete_log_written = true;
+        }
+        for oldest in reaped {
+            oldest.reap_deleted(&mut committed)?;
         }
+        committed.free_resolved(&resolved)?;
 
-        // 4. Set last committed revision
+        // Space the proposal reserved but didn't use is free from this revision on
+        committed.free_unused_reservation(&proposal.kind)?;
+        let reap = reap_start.elapsed();
+        drop(reap_span);
+
+        // 4. Set last committed revision, as the parent the next commit must have
         let committed: CommittedRevision = committed.into();
-        self.historical.push_back(committed.clone());
-        if let Some(hash) = committed.kind.root_hash() {
-            self.by_hash.insert(hash, committed.clone());
+
+        // 5. Free list flush, which will prevent allocating on top of the nodes we are about to write.
+        // The free lists come from the committed revision, which also has the areas freed above.
+        let flush_start = Instant::now();
+        let flush_span = LocalSpan::enter_with_local_parent("freelist_flush");
+        committed.flush_freelist()?;
+        drop(flush_span);
+        let flush_freelist = flush_start.elapsed();
+
+        self.begun += 1;
+        let pending = Arc::new(PendingCommit {
+            sequence: self.begun,
+            proposal,
+            committed,
+            journal: journal.map(Box::from),
+            freed: freed.into(),
+            reap,
+            flush_freelist,
+            flushed: OnceLock::new(),
+            sync: self.config.durability == DurabilityMode::Strict,
+        });
+        self.committing.push_back(pending.clone());
+        Ok(pending)
+    }
+
+    /// Steps 7 and 8 of [RevisionManager::commit] for the pending commits
+    /// whose nodes are flushed, oldest first, stopping at the first one
+    /// that isn't. If one fails, none of the pending commits are published.
+    pub fn publish_flushed(&mut self) -> Result<(), RevisionManagerError> {
+        while let Some(&flush_nodes) = self
+            .committing
+            .front()
+            .and_then(|pending| pending.flushed.get())
+        {
+            let pending = self.committing.pop_front().expect("must be present");
+            if let Err(err) = self.publish(&pending, flush_nodes) {
+                self.fail_pending();
+                return Err(err.into());
+            }
+            self.progress.send_modify(|progress| {
+                progress.published = pending.sequence;
+            });
+        }
+        if self.sync_due() {
+            self.sync()?;
         }
-        // TODO: We could allow other commits to start here using the pending list
+        Ok(())
+    }
 
-        // 5. Free list flush, which will prevent allocating on top of the nodes we are about to write
-        proposal.flush_freelist()?;
+    fn publish(&mut self, pending: &PendingCommit, flush_nodes: Duration) -> Result<(), Error> {
+        let committed = pending.committed.clone();
+        self.push_latest(committed.clone());
+        for (phase, name, duration) in [
+            ("reap", "firewood.commit.reap", pending.reap),
+            (
+                "free_list",
+                "firewood.commit.freelist_flush",
+                pending.flush_freelist,
+            ),
+            ("nodes", "firewood.commit.node_flush", flush_nodes),
+        ] {
+            histogram!("firewood.commit.latency", "phase" => phase).record(duration.as_secs_f64());
+            histogram!(name).record(duration.as_secs_f64());
+        }
 
-        // 6. Node flush
-        proposal.flush_nodes()?;
+        let snapshot = self.snapshots.is_some().then(|| {
+            self.snapshot(
+                &committed,
+                pending.reap,
+                pending.flush_freelist,
+                flush_nodes,
+            )
+        });
+        if let Some(hash) = committed.kind.root_hash() {
+            if let (Some(store), Some(journal)) = (&self.journal, &pending.journal) {
+                store.write(&hash, journal)?;
+            }
+            if let (Some(store), Some(snapshot)) = (&self.snapshots, snapshot) {
+                store.write(&hash, &snapshot.encode())?;
+            }
+        }
 
-        // 7. Root move
-        proposal.flush_header()?;
+        // 7. Root move. The free lists come from the newest commit, since
+        // the pending ones after this one may have allocated from its lists.
+        // With buffered durability, it waits until the file is synced.
+        if let DurabilityMode::Buffered { .. } = self.config.durability {
+            self.unsynced += 1;
+            self.unsynced_freed.extend(pending.freed.iter());
+        } else {
+            let flush_header = Instant::now();
+            let span = LocalSpan::enter_with_local_parent("root_move");
+            if self.external_root_authority {
+                self.flush_promoted_header()?;
+            } else {
+                self.newest_revision().flush_header_with_root(
+                    committed.root_address(),
+                    committed.height(),
+                    &[],
+                )?;
+            }
+            if self.config.durability == DurabilityMode::Strict {
+                self.filebacked.sync()?;
+            }
+            drop(span);
+            let flush_header = flush_header.elapsed().as_secs_f64();
+            histogram!("firewood.commit.latency", "phase" => "header").record(flush_header);
+            histogram!("firewood.commit.root_move").record(flush_header);
+            if self.committing.is_empty() && self.delete_log_written {
+                self.delete_log.clear()?;
+                self.delete_log_written = false;
+            }
+        }
 
         // 8. Proposal Cleanup
         // first remove the committing proposal from the list of outstanding proposals
-        self.proposals.retain(|p| !Arc::ptr_eq(&proposal, p));
+        self.proposals
+            .retain(|p| !Arc::ptr_eq(&pending.proposal, p));
 
         // then reparent any proposals that have this proposal as a parent
         for p in self.proposals.iter() {
-            proposal.commit_reparent(p);
+            pending.proposal.commit_reparent(p);
+        }
+        Ok(())
+    }
+
+    /// Whether the oldest retained revision is kept since it was committed
+    /// less than `max_revision_age` ago, given that `retained` revisions
+    /// are. Its commit is in the commit log unless it was committed before
+    /// the database was opened.
+    fn oldest_is_recent(&self, retained: usize) -> bool {
+        let Some(max_age) = self.config.max_revision_age else {
+            return false;
+        };
+        if retained >= MAX_REVISIONS_KEPT_BY_AGE.max(self.max_revisions) {
+            return false;
+        }
+        // the log keeps the commits of the retained revisions, newest last
+        let Some(oldest) = self
+            .commit_log
+            .len()
+            .checked_sub(self.historical.len())
+            .and_then(|index| self.commit_log.get(index))
+        else {
+            return false;
+        };
+        // a clock that went back makes the commit recent
+        oldest.committed_at.elapsed().unwrap_or_default() < max_age
+    }
+
+    /// Make `committed` the latest revision, and record its commit
+    fn push_latest(&mut self, committed: CommittedRevision) {
+        self.epoch += 1;
+        self.historical.push_back(committed.clone());
+        if let Some(hash) = committed.kind.root_hash() {
+            self.by_hash.insert(hash, committed.clone());
+        }
+        if !self.header_lags() {
+            self.promoted = committed.clone();
+        }
+        self.commit_log.push_back(CommitRecord {
+            sequence: self.epoch,
+            committed_at: SystemTime::now(),
+            root_hash: committed.kind.root_hash(),
+        });
+        while self.commit_log.len() > self.historical.len() {
+            self.commit_log.pop_front();
+        }
+        // it fails only when nobody is subscribed
+        let _ = self.commits.send(CommitEvent {
+            sequence: self.epoch,
+            root_hash: committed.kind.root_hash(),
+        });
+    }
+
+    /// Give up on the pending commits after one of them failed. Their
+    /// proposals can't be committed anymore, and if a reap freed areas, the
+    /// delete log is left for the next open to recover them.
+    pub fn fail_pending(&mut self) {
+        for pending in take(&mut self.committing) {
+            self.proposals
+                .retain(|p| !Arc::ptr_eq(&pending.proposal, p));
+        }
+        let failed = self.begun;
+        self.progress
+            .send_modify(|progress| progress.failed = failed);
+    }
+
+    /// Whether commits have started that aren't published yet
+    pub fn is_committing(&self) -> bool {
+        !self.committing.is_empty()
+    }
+
+    /// Follows how far commits have got, for [PendingCommit::published]
+    pub fn commit_progress(&self) -> watch::Receiver<CommitProgress> {
+        self.progress.subscribe()
+    }
+
+    /// Hears about every revision that becomes the latest from now on; see
+    /// [crate::db::Db::subscribe]
+    pub fn subscribe(&self) -> broadcast::Receiver<CommitEvent> {
+        self.commits.subscribe()
+    }
+
+    /// Resolves once every commit started so far is published, or will
+    /// never be since one failed
+    pub fn settled(&self) -> impl std::future::Future<Output = ()> + Send + 'static {
+        let mut progress = self.progress.subscribe();
+        let begun = self.begun;
+        async move {
+            // the sender lives as long as the manager
+            let _ = progress
+                .wait_for(|progress| progress.published >= begun || progress.failed >= begun)
+                .await;
+        }
+    }
+
+    /// Reap in the background with `reaper` from the next commit on
+    #[cfg(test)]
+    pub(crate) fn replace_reaper(&mut self, reaper: Reaper) {
+        self.reaper = Some(reaper);
+    }
+
+    /// Write the header of the latest revision, sync the file to disk and
+    /// release its lock, once nothing is committing anymore
+    pub fn close(&mut self) -> Result<(), RevisionManagerError> {
+        self.sync()?;
+        // the next open frees the areas no commit got to, as if a commit
+        // freeing them was interrupted
+        let mut leftover: Vec<_> = take(&mut self.deferred)
+            .iter()
+            .flat_map(|revision| revision.deleted())
+            .copied()
+            .collect();
+        if let Some(reaper) = self.reaper.take() {
+            leftover.extend(reaper.shut_down().into_iter().map(|(addr, _)| addr));
+        }
+        if !leftover.is_empty() {
+            self.delete_log
+                .write(self.promoted.root_address(), &leftover)?;
         }
+        Ok(self.filebacked.unlock()?)
+    }
 
+    /// Sync the file to disk, then move the root in the header to the
+    /// latest published revision, or write it for the promoted one with an
+    /// external root authority, and sync it too. Nothing is written to a
+    /// read-only file.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        if self.read_only {
+            return Ok(());
+        }
+        self.filebacked.sync()?;
+        if !self.external_root_authority {
+            self.promoted = self.current_revision();
+        }
+        self.flush_promoted_header()?;
+        self.filebacked.sync()?;
+        self.unsynced = 0;
+        self.unsynced_freed.clear();
+        self.synced_at = Instant::now();
+        if self.committing.is_empty() && self.delete_log_written {
+            self.delete_log.clear()?;
+            self.delete_log_written = false;
+        }
         Ok(())
     }
+
+    /// Whether [DurabilityMode::Buffered] calls for a sync
+    fn sync_due(&self) -> bool {
+        match self.config.durability {
+            DurabilityMode::Buffered { commits, interval } => {
+                self.unsynced >= commits.get()
+                    || (self.unsynced > 0 && self.synced_at.elapsed() >= interval)
+            }
+            _ => false,
+        }
+    }
+
+    /// Sync if [DurabilityMode::Buffered] calls for it, for when no commit
+    /// comes along to do it
+    pub fn sync_if_due(&mut self) -> Result<(), Error> {
+        match self.sync_due() {
+            true => self.sync(),
+            false => Ok(()),
+        }
+    }
+
+    /// Whether the root in the header can be behind the latest revision,
+    /// which is then kept from being reaped: until it is promoted with an
+    /// external root authority, or until the file is synced with
+    /// [DurabilityMode::Buffered]
+    const fn header_lags(&self) -> bool {
+        self.external_root_authority
+            || matches!(self.config.durability, DurabilityMode::Buffered { .. })
+    }
+
+    fn is_pending(&self, proposal: &ProposedRevision) -> bool {
+        self.committing
+            .iter()
+            .any(|pending| Arc::ptr_eq(&pending.proposal, proposal))
+    }
+
+    /// Whether `proposal` was made on the latest pending commit, or on the
+    /// latest revision if none are pending
+    fn is_on_latest(&self, proposal: &ProposedRevision) -> bool {
+        match self.committing.back() {
+            Some(pending) => pending.proposal.is_parent_of(proposal),
+            None => proposal
+                .kind
+                .parent_hash_is(self.current_revision().kind.root_hash()),
+        }
+    }
+
+    /// The height of the next commit
+    fn next_height(&self) -> u64 {
+        self.newest_revision().height() + 1
+    }
+
+    /// The revision of the latest pending commit, or the latest revision if
+    /// none are pending. It has the newest free lists.
+    fn newest_revision(&self) -> CommittedRevision {
+        self.committing.back().map_or_else(
+            || self.current_revision(),
+            |pending| pending.committed.clone(),
+        )
+    }
+}
+
+impl Drop for RevisionManager {
+    #[cfg_attr(not(feature = "logger"), allow(unused_variables))]
+    fn drop(&mut self) {
+        // otherwise the database reopens at the revision synced last
+        if self.unsynced > 0 {
+            if let Err(err) = self.sync() {
+                warn!("Could not sync the commits since the last sync: {err}");
+            }
+        }
+    }
 }
 
 impl RevisionManager {
@@ -209,25 +1180,580 @@ impl RevisionManager {
         self.proposals.push(proposal);
     }
 
+    /// The proposals under `proposal` that aren't committed yet, bottom
+    /// first, followed by `proposal`. Fails if the bottom one wasn't made
+    /// on the most recent commit, which may be a pending one.
+    pub fn proposal_chain(
+        &self,
+        proposal: &ProposedRevision,
+    ) -> Result<Vec<ProposedRevision>, RevisionManagerError> {
+        let mut chain = vec![proposal.clone()];
+        while let Some(parent) = chain
+            .last()
+            .and_then(|top| self.proposals.iter().find(|p| p.is_parent_of(top)))
+            .filter(|parent| !self.is_pending(parent))
+        {
+            chain.push(parent.clone());
+        }
+        chain.reverse();
+
+        let bottom = chain.first().expect("holds proposal");
+        if !self.is_on_latest(bottom) {
+            return Err(RevisionManagerError::NotLatest);
+        }
+        Ok(chain)
+    }
+
+    /// Stop tracking `proposal`, which is being dropped without being
+    /// committed, along with the proposals that were abandoned without being
+    /// aborted. Fails if proposals on top of it are still held, since they
+    /// could never be committed; they must be aborted first.
+    ///
+    /// A proposal's nodes are only written to the file when it commits, and
+    /// the space it allocated for them is only taken from its own copy of
+    /// the free lists, so there is no space to give back.
+    pub fn abort(&mut self, proposal: ProposedRevision) -> Result<(), RevisionManagerError> {
+        let children = self
+            .proposals
+            .iter()
+            .filter(|p| proposal.is_parent_of(p) && !is_abandoned(p))
+            .count();
+        if children > 0 {
+            return Err(RevisionManagerError::HasChildren(children));
+        }
+        self.proposals.retain(|p| !Arc::ptr_eq(p, &proposal));
+        drop(proposal);
+
+        // dropping a proposal can leave its parent abandoned
+        while let Some(index) = self.proposals.iter().position(is_abandoned) {
+            self.proposals.remove(index);
+        }
+        Ok(())
+    }
+
+    /// Keep the revision with `root_hash`, and the ones after it, from
+    /// being reaped until the returned guard and any others for it are
+    /// dropped
+    pub fn pin(&self, root_hash: HashKey) -> Result<PinGuard, RevisionManagerError> {
+        self.revision(root_hash.clone())?;
+        *self
+            .pins
+            .0
+            .lock()
+            .expect("poisoned lock")
+            .entry(root_hash.clone())
+            .or_default() += 1;
+        Ok(PinGuard {
+            pins: self.pins.clone(),
+            root_hash,
+        })
+    }
+
+    fn is_pinned(&self, revision: &CommittedRevision) -> bool {
+        revision.kind.root_hash().is_some_and(|hash| {
+            self.pins
+                .0
+                .lock()
+                .expect("poisoned lock")
+                .contains_key(&hash)
+        })
+    }
+
+    /// The retained revision with `root_hash`. Fails with
+    /// [RevisionManagerError::RevisionReaped] if it was one of the recently
+    /// reaped ones, with [RevisionManagerError::EmptyDatabase] if no
+    /// retained revision has a root, and with
+    /// [RevisionManagerError::UnknownRevision] otherwise.
     pub fn revision(&self, root_hash: HashKey) -> Result<CommittedRevision, RevisionManagerError> {
-        self.by_hash
-            .get(&root_hash)
+        if let Some(revision) = self.by_hash.get(&root_hash) {
+            return Ok(revision.clone());
+        }
+        if self.reaped.contains(&root_hash) {
+            Err(RevisionManagerError::RevisionReaped(root_hash))
+        } else if self.by_hash.is_empty() {
+            Err(RevisionManagerError::EmptyDatabase)
+        } else {
+            Err(RevisionManagerError::UnknownRevision(root_hash))
+        }
+    }
+
+    /// Remember that the revision with `root_hash` was reaped, forgetting
+    /// the oldest reaped one if `max_revisions` are already remembered
+    fn remember_reaped(&mut self, root_hash: TrieHash) {
+        while self.reaped.len() >= self.max_revisions.max(1) {
+            self.reaped.pop_front();
+        }
+        self.reaped.push_back(root_hash);
+    }
+
+    /// The committed revision `back` commits before the most recent one,
+    /// which is 0 back
+    pub fn revision_by_offset(
+        &self,
+        back: usize,
+    ) -> Result<CommittedRevision, RevisionManagerError> {
+        self.historical
+            .iter()
+            .rev()
+            .nth(back)
             .cloned()
-            .ok_or(RevisionManagerError::IO(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Revision not found",
-            )))
+            .ok_or(RevisionManagerError::OutOfRange {
+                back,
+                retained: self.historical.len(),
+            })
     }
 
-    pub fn root_hash(&self) -> Result<Option<HashKey>, RevisionManagerError> {
-        self.current_revision()
-            .kind
-            .root_hash()
-            .map(Option::Some)
-            .ok_or(RevisionManagerError::IO(std::io::Error::new(
-                std::io::ErrorKind::NotFound,
-                "Root hash not found",
-            )))
+    /// The retained revision committed at `height`. Fails with
+    /// [RevisionManagerError::HeightNotFound] if it was reaped or hasn't
+    /// been committed yet.
+    pub fn revision_by_height(
+        &self,
+        height: u64,
+    ) -> Result<CommittedRevision, RevisionManagerError> {
+        self.historical
+            .iter()
+            .rev()
+            .find(|revision| revision.height() == height)
+            .cloned()
+            .ok_or(RevisionManagerError::HeightNotFound {
+                height,
+                oldest_available: self.oldest_height(),
+            })
+    }
+
+    /// The height of the most recent commit
+    pub fn latest_height(&self) -> u64 {
+        self.current_revision().height()
+    }
+
+    /// The height of the oldest retained revision
+    fn oldest_height(&self) -> u64 {
+        self.historical
+            .front()
+            .expect("there is always one revision")
+            .height()
+    }
+
+    /// The heights and root hashes of the retained committed revisions,
+    /// oldest first. Empty revisions have no root hash and are left out.
+    pub fn all_revisions(&self) -> Vec<(u64, TrieHash)> {
+        self.historical
+            .iter()
+            .filter_map(|r| r.kind.root_hash().map(|hash| (r.height(), hash)))
+            .collect()
+    }
+
+    /// The retained revision with `root_hash`, or an empty one if
+    /// `root_hash` is None
+    pub fn retained_revision(&self, root_hash: Option<&HashKey>) -> Option<CommittedRevision> {
+        match root_hash {
+            Some(root_hash) => self.by_hash.get(root_hash).cloned(),
+            None => self
+                .historical
+                .iter()
+                .rev()
+                .find(|revision| revision.kind.root_hash().is_none())
+                .cloned(),
+        }
+    }
+
+    /// The number of commits since the database was opened
+    pub const fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// The commits since the database was opened whose revisions may still
+    /// be retained, oldest first
+    pub const fn commit_log(&self) -> &VecDeque<CommitRecord> {
+        &self.commit_log
+    }
+
+    /// The root hash of the latest revision, or None if it is empty
+    pub fn root_hash(&self) -> Option<HashKey> {
+        self.current_revision().kind.root_hash()
+    }
+
+    /// An empty manager for a new file at `path`, configured like this one
+    /// except that it keeps only one revision and has no op journals,
+    /// snapshots or external root authority. Compaction writes into it.
+    pub fn compaction_target(&self, path: PathBuf) -> Result<Self, Error> {
+        let current = self.current_revision();
+        let prefix = current.reserved_prefix().unwrap_or(DEFAULT_SYSTEM_PREFIX);
+        let system_keys = match current.is_hidden(prefix) {
+            true => SystemKeys::Hidden,
+            false => SystemKeys::Reject,
+        };
+        // the compacted file is synced once, before it replaces this one
+        let config = RevisionManagerConfig {
+            max_revisions: 1,
+            max_revision_age: None,
+            retain_operational_snapshots: false,
+            background_reaping: false,
+            durability: DurabilityMode::None,
+            ..self.config.clone()
+        };
+        Self::new(path, true, config, prefix, system_keys, false, false)
+    }
+
+    /// Change how many revisions are kept. Growing lets more accumulate
+    /// from the next commit on, while shrinking reaps the oldest beyond the
+    /// new limit at once, as a commit would, and the next commit frees
+    /// their nodes. Fails without changing anything if a revision it would
+    /// reap is pinned.
+    pub fn set_max_revisions(&mut self, max_revisions: usize) -> Result<(), RevisionManagerError> {
+        let excess =
+            (self.historical.len() + self.committing.len()).saturating_sub(max_revisions.max(1));
+        if let Some(pinned) = self
+            .historical
+            .iter()
+            .take(excess)
+            .filter(|revision| self.is_pinned(revision))
+            .find_map(|revision| revision.kind.root_hash())
+        {
+            return Err(RevisionManagerError::RevisionPinned(pinned));
+        }
+
+        self.max_revisions = max_revisions;
+        while self.historical.len() + self.committing.len() > max_revisions.max(1) {
+            if self.oldest_is_kept() {
+                break;
+            }
+            let Some(oldest) = self.unlink_oldest()? else {
+                break;
+            };
+            // only the writer frees nodes
+            match (&self.reaper, self.read_only) {
+                (Some(reaper), _) => reaper.send(oldest),
+                (None, false) => self.deferred.push(oldest),
+                (None, true) => {}
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the oldest retained revision has to be kept for now, even
+    /// though more than `max_revisions` are retained
+    fn oldest_is_kept(&self) -> bool {
+        // the revision whose root is in the header must keep its nodes
+        if self.header_lags() && !self.is_retained(&self.promoted) {
+            return true;
+        }
+        // readers see the latest published revision until the pending
+        // commits are published
+        if !self.committing.is_empty() && self.historical.len() == 1 {
+            return true;
+        }
+        // a pinned revision is kept, and so are the ones after it, since
+        // reaping one frees the nodes the revision before it used
+        if self
+            .historical
+            .front()
+            .is_some_and(|oldest| self.is_pinned(oldest))
+        {
+            return true;
+        }
+        // so is one committed recently, with max_revision_age
+        self.oldest_is_recent(self.historical.len() + self.committing.len())
+    }
+
+    /// Stop retaining the oldest revision, returning it for its nodes to be
+    /// freed, or None if it is still referenced, in which case it is kept
+    fn unlink_oldest(&mut self) -> Result<Option<NodeStore<Committed, FileBacked>>, Error> {
+        let oldest = self.historical.pop_front().expect("must be present");
+        let oldest_hash = oldest.kind.root_hash();
+        if let Some(oldest_hash) = &oldest_hash {
+            self.by_hash.remove(oldest_hash);
+        }
+
+        // This `try_unwrap` is safe because nobody else will call `try_unwrap` on this Arc
+        // in a different thread, so we don't have to worry about the race condition where
+        // the Arc we get back is not usable as indicated in the docs for `try_unwrap`.
+        // This guarantee is there because we have a `&mut self` reference to the manager, so
+        // the compiler guarantees we are the only one using this manager.
+        match Arc::try_unwrap(oldest) {
+            Ok(oldest) => {
+                if let Some(oldest_hash) = oldest_hash {
+                    self.remove_revision_files(&oldest_hash)?;
+                    self.remember_reaped(oldest_hash);
+                }
+                Ok(Some(oldest))
+            }
+            Err(original) => {
+                warn!("Oldest revision could not be reaped; still referenced");
+                // it can still be looked up by hash
+                if let Some(oldest_hash) = oldest_hash {
+                    self.by_hash.insert(oldest_hash, original.clone());
+                }
+                self.historical.push_front(original);
+                Ok(None)
+            }
+        }
+    }
+
+    /// Whether commits leave the root in the header for [RevisionManager::promote]
+    pub const fn has_external_root_authority(&self) -> bool {
+        self.external_root_authority
+    }
+
+    /// Switch to `compacted`, which holds the newest revisions of this
+    /// manager in another file, renamed over this one. The op journals,
+    /// snapshots and commit log of the revisions it holds carry over, and
+    /// the rest are dropped; the delete log stays at this file's path.
+    /// Proposals made on this manager can no longer be committed, and
+    /// neither can pending commits, so there must be none.
+    pub fn replace_with(&mut self, mut compacted: RevisionManager) -> Result<(), Error> {
+        let kept: Vec<_> = compacted.by_hash.keys().cloned().collect();
+        for files in self.journal.iter().chain(&self.snapshots) {
+            files.retain(&kept)?;
+        }
+        // the revisions that didn't carry over count as reaped
+        let dropped: Vec<_> = self
+            .by_hash
+            .keys()
+            .filter(|&hash| !kept.contains(hash))
+            .cloned()
+            .collect();
+        for hash in dropped {
+            self.remember_reaped(hash);
+        }
+        compacted.reaped = take(&mut self.reaped);
+        compacted.journal = self.journal.take();
+        compacted.snapshots = self.snapshots.take();
+        std::mem::swap(&mut compacted.delete_log, &mut self.delete_log);
+        compacted.epoch = self.epoch;
+        compacted.begun = self.begun;
+        // commits waiting to be published keep following the same progress
+        std::mem::swap(&mut compacted.progress, &mut self.progress);
+        std::mem::swap(&mut compacted.commits, &mut self.commits);
+        compacted.pins = self.pins.clone();
+        let mut commit_log = take(&mut self.commit_log);
+        commit_log.retain(|commit| {
+            compacted
+                .retained_revision(commit.root_hash.as_ref())
+                .is_some()
+        });
+        compacted.commit_log = commit_log;
+        compacted.max_revisions = self.max_revisions;
+        compacted.config = self.config.clone();
+        // the areas the reaper looked up are in the file being replaced
+        if self.config.background_reaping {
+            compacted.reaper = Some(Reaper::start()?);
+        }
+        // everything this file held is in the compacted one
+        self.unsynced = 0;
+        *self = compacted;
+        Ok(())
+    }
+
+    /// The storage all revisions are read from
+    pub(crate) const fn storage(&self) -> &Arc<FileBacked> {
+        &self.filebacked
+    }
+
+    /// Up to `max` of the most recent revisions, newest first
+    pub fn newest_revisions(&self, max: usize) -> Vec<CommittedRevision> {
+        self.historical.iter().rev().take(max).cloned().collect()
+    }
+
+    fn is_retained(&self, revision: &CommittedRevision) -> bool {
+        self.historical.iter().any(|r| Arc::ptr_eq(r, revision))
+    }
+
+    /// Revisions that are durable but not promoted: the ones committed after
+    /// the promoted revision, then the ones found on open
+    fn unpromoted(&self) -> impl Iterator<Item = &CommittedRevision> {
+        // without an external root authority, the revisions after the
+        // promoted one are only waiting for the file to be synced
+        let after_promoted = match self.external_root_authority {
+            true => self
+                .historical
+                .iter()
+                .position(|r| Arc::ptr_eq(r, &self.promoted))
+                .map_or(self.historical.len(), |index| index + 1),
+            false => self.historical.len(),
+        };
+        self.historical
+            .iter()
+            .skip(after_promoted)
+            .chain(self.reopened.iter())
+    }
+
+    fn unpromoted_roots(&self) -> Vec<(LinearAddress, u64)> {
+        self.unpromoted()
+            .filter_map(|r| r.root_address().map(|root| (root, r.height())))
+            .collect()
+    }
+
+    /// Persist the header of the newest revision, which has the newest free
+    /// lists, pointing at the promoted revision
+    fn flush_promoted_header(&self) -> Result<(), Error> {
+        self.newest_revision().flush_header_with_root(
+            self.promoted.root_address(),
+            self.promoted.height(),
+            &self.unpromoted_roots(),
+        )
+    }
+
+    /// The root hashes of the durable revisions that have not been promoted
+    pub fn unpromoted_hashes(&self) -> Vec<TrieHash> {
+        self.unpromoted()
+            .filter_map(|r| r.kind.root_hash())
+            .collect()
+    }
+
+    /// Move the root in the header to the retained revision with `root_hash`.
+    ///
+    /// Revisions older than it stop being unpromoted and can be reaped.
+    /// Newer revisions committed since the database was opened remain
+    /// unpromoted.
+    ///
+    /// Unpromoted revisions found on open can only be promoted before
+    /// anything new is committed, and the promoted one then replaces the
+    /// opened revision as the latest one. Once a revision committed since
+    /// opening is promoted, the ones found on open are discarded: reaping
+    /// may now free nodes they share with the opened revision.
+    pub fn promote(&mut self, root_hash: TrieHash) -> Result<(), RevisionManagerError> {
+        if self.read_only {
+            return Err(RevisionManagerError::ReadOnly);
+        }
+        if !self.external_root_authority {
+            return Err(RevisionManagerError::CannotPromote(
+                "the database was not opened with an external root authority",
+            ));
+        }
+
+        let revision = self.revision(root_hash.clone())?;
+        let was_reopened = self.reopened.iter().any(|r| Arc::ptr_eq(r, &revision));
+        if was_reopened {
+            if self.historical.len() > 1 {
+                return Err(RevisionManagerError::CannotPromote(
+                    "revisions from before the database was opened can only be promoted before anything else is committed",
+                ));
+            }
+            if let Some(base) = self.historical.pop_front() {
+                if let Some(hash) = base.kind.root_hash() {
+                    self.by_hash.remove(&hash);
+                    self.remove_revision_files(&hash)?;
+                    self.remember_reaped(hash);
+                }
+            }
+            self.historical.push_back(revision.clone());
+            self.reopened.retain(|r| !Arc::ptr_eq(r, &revision));
+        } else if !Arc::ptr_eq(&revision, &self.promoted) {
+            for discarded in take(&mut self.reopened) {
+                if let Some(hash) = discarded.kind.root_hash() {
+                    self.by_hash.remove(&hash);
+                    self.remove_revision_files(&hash)?;
+                    self.remember_reaped(hash);
+                }
+            }
+        }
+        self.promoted = revision;
+
+        // the unpromoted revisions the header lists must be on disk
+        match self.config.durability {
+            DurabilityMode::None => self.flush_promoted_header()?,
+            _ => self.sync()?,
+        }
+        Ok(())
+    }
+
+    /// Forget an unpromoted revision that was found on open. Its nodes are
+    /// leaked rather than freed.
+    pub fn discard_unpromoted(&mut self, root_hash: TrieHash) -> Result<(), RevisionManagerError> {
+        let Some(index) = self
+            .reopened
+            .iter()
+            .position(|r| r.kind.root_hash().as_ref() == Some(&root_hash))
+        else {
+            return Err(RevisionManagerError::CannotPromote(
+                "only unpromoted revisions from before the database was opened can be discarded",
+            ));
+        };
+        self.reopened.remove(index);
+        self.by_hash.remove(&root_hash);
+        self.remove_revision_files(&root_hash)?;
+        self.flush_promoted_header()?;
+        Ok(())
+    }
+
+    /// The op journal of the retained revision with `root_hash`, if op
+    /// journals are retained and it has one
+    pub fn op_journal(&self, root_hash: &TrieHash) -> Result<Option<JournalBatch>, Error> {
+        self.read_revision_file(&self.journal, root_hash)?
+            .map(|compressed| journal::decode(&compressed))
+            .transpose()
+    }
+
+    /// The space taken by op journals on disk, in bytes
+    pub fn op_journal_bytes(&self) -> Result<u64, Error> {
+        self.journal
+            .as_ref()
+            .map_or(Ok(0), RevisionFiles::disk_usage)
+    }
+
+    /// The operational snapshot of the retained revision with `root_hash`,
+    /// if snapshots are retained and it has one
+    pub fn operational_snapshot(
+        &self,
+        root_hash: &TrieHash,
+    ) -> Result<Option<OperationalSnapshot>, Error> {
+        self.read_revision_file(&self.snapshots, root_hash)?
+            .map(|bytes| OperationalSnapshot::decode(&bytes))
+            .transpose()
+    }
+
+    /// The snapshot of a commit, taken after `committed` became the latest
+    /// revision
+    fn snapshot(
+        &mut self,
+        committed: &CommittedRevision,
+        reap: Duration,
+        flush_freelist: Duration,
+        flush_nodes: Duration,
+    ) -> OperationalSnapshot {
+        let (hits, misses) = self.filebacked.node_cache_lookups();
+        let (last_hits, last_misses) = std::mem::replace(&mut self.cache_lookups, (hits, misses));
+        OperationalSnapshot {
+            commit_sequence: self.epoch,
+            reap,
+            flush_freelist,
+            flush_nodes,
+            cache_hits: hits - last_hits,
+            cache_misses: misses - last_misses,
+            store_size: committed.store_size(),
+            free_bytes: committed.free_bytes(),
+            reap_backlog: self.historical.len().saturating_sub(self.max_revisions) as u64,
+            // abandoned proposals stay in the list until one is aborted
+            proposals: self
+                .proposals
+                .iter()
+                .filter(|proposal| !is_abandoned(proposal))
+                .count() as u64,
+        }
+    }
+
+    fn read_revision_file(
+        &self,
+        files: &Option<RevisionFiles>,
+        root_hash: &TrieHash,
+    ) -> Result<Option<Vec<u8>>, Error> {
+        match files {
+            Some(files) if self.by_hash.contains_key(root_hash) => files.read(root_hash),
+            _ => Ok(None),
+        }
+    }
+
+    /// Remove the op journal and snapshot of a revision that is no longer
+    /// retained, unless another retained revision has the same root hash
+    fn remove_revision_files(&self, root_hash: &TrieHash) -> Result<(), Error> {
+        if self.by_hash.contains_key(root_hash) {
+            return Ok(());
+        }
+        for files in self.journal.iter().chain(&self.snapshots) {
+            files.remove(root_hash)?;
+        }
+        Ok(())
     }
 
     pub fn current_revision(&self) -> CommittedRevision {
@@ -238,6 +1764,12 @@ impl RevisionManager {
     }
 }
 
+/// Whether nothing but the manager holds `proposal`, and nothing was
+/// proposed on top of it, so it can never be committed
+fn is_abandoned(proposal: &ProposedRevision) -> bool {
+    Arc::strong_count(proposal) == 1 && Arc::strong_count(&proposal.kind) == 1
+}
+
 #[cfg(test)]
 mod tests {
     // TODO
@@ -1,16 +1,19 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
+use crate::audit::{
+    AuditBundle, AuditEntry, AuditError, AuditItem, AuditRequest, Span, MAX_AUDIT_ENTRIES,
+};
 use crate::proof::{Proof, ProofError, ProofNode};
 use crate::range_proof::RangeProof;
-use crate::stream::{MerkleKeyValueStream, PathIterator};
-use crate::v2::api;
-use futures::{StreamExt, TryStreamExt};
+use crate::stream::{key_from_nibble_iter, MerkleKeyValueStream, PathIterator};
+use crate::v2::api::{self, RangeEstimate};
+use futures::StreamExt;
 use metrics::counter;
 use smallvec::SmallVec;
-use std::collections::HashSet;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
 use std::fmt::Debug;
-use std::future::ready;
 use std::io::Write;
 use std::iter::once;
 use std::num::NonZeroUsize;
@@ -18,7 +21,6 @@ use std::sync::Arc;
 use storage::{
     BranchNode, Child, Hashable, HashedNodeReader, ImmutableProposal, LeafNode, LinearAddress,
     MutableProposal, NibblesIterator, Node, NodeStore, Path, ReadableStorage, TrieHash, TrieReader,
-    ValueDigest,
 };
 
 use thiserror::Error;
@@ -30,6 +32,9 @@ pub type Key = Box<[u8]>;
 /// TODO: change to Box<[u8]>
 pub type Value = Vec<u8>;
 
+/// The value of a key, or None if it isn't in the trie, and the proof of it
+pub type ValueWithProof = (Option<Box<[u8]>>, Proof<ProofNode>);
+
 #[derive(Debug, Error)]
 /// Errors that can occur when interacting with the Merkle trie
 pub enum MerkleError {
@@ -90,9 +95,14 @@ macro_rules! write_attributes {
 }
 
 /// Returns the value mapped to by `key` in the subtrie rooted at `node`.
+/// `shared` is `node` itself when it was read from the nodestore, so a match
+/// can be returned without copying it. `node` is `level` levels below the
+/// root.
 fn get_helper<T: TrieReader>(
     nodestore: &T,
     node: &Node,
+    shared: Option<&Arc<Node>>,
+    level: usize,
     key: &[u8],
 ) -> Result<Option<Arc<Node>>, MerkleError> {
     // 4 possibilities for the position of the `key` relative to `node`:
@@ -112,7 +122,10 @@ fn get_helper<T: TrieReader>(
             // Case (2) or (4)
             Ok(None)
         }
-        (None, None) => Ok(Some(Arc::new(node.clone()))), // 1. The node is at `key`
+        // 1. The node is at `key`
+        (None, None) => Ok(Some(
+            shared.map_or_else(|| Arc::new(node.clone()), Arc::clone),
+        )),
         (Some((child_index, remaining_key)), None) => {
             // 3. The key is below the node (i.e. its descendant)
             match node {
@@ -123,10 +136,12 @@ fn get_helper<T: TrieReader>(
                     .expect("index is in bounds")
                 {
                     None => Ok(None),
-                    Some(Child::Node(ref child)) => get_helper(nodestore, child, remaining_key),
+                    Some(Child::Node(ref child)) => {
+                        get_helper(nodestore, child, None, level + 1, remaining_key)
+                    }
                     Some(Child::AddressWithHash(addr, _)) => {
-                        let child = nodestore.read_node(*addr)?;
-                        get_helper(nodestore, &child, remaining_key)
+                        let child = nodestore.read_node_at_depth(*addr, level + 1)?;
+                        get_helper(nodestore, &child, Some(&child), level + 1, remaining_key)
                     }
                 },
             }
@@ -134,6 +149,70 @@ fn get_helper<T: TrieReader>(
     }
 }
 
+/// Like [get_helper], but returns a copy of the value of the node at `key`
+/// rather than the node, so nodes in memory aren't copied
+fn value_helper<T: TrieReader>(
+    nodestore: &T,
+    node: &Node,
+    level: usize,
+    key: &[u8],
+) -> Result<Option<Box<[u8]>>, MerkleError> {
+    let path_overlap = PrefixOverlap::from(key, node.partial_path());
+    match (
+        path_overlap.unique_a.split_first(),
+        path_overlap.unique_b.split_first(),
+    ) {
+        (_, Some(_)) => Ok(None),
+        (None, None) => Ok(node.value().map(Into::into)),
+        (Some((child_index, remaining_key)), None) => {
+            let Node::Branch(branch) = node else {
+                return Ok(None);
+            };
+            match branch
+                .children
+                .get(*child_index as usize)
+                .expect("index is in bounds")
+            {
+                None => Ok(None),
+                Some(Child::Node(child)) => {
+                    value_helper(nodestore, child, level + 1, remaining_key)
+                }
+                Some(Child::AddressWithHash(addr, _)) => {
+                    let child = nodestore.read_node_at_depth(*addr, level + 1)?;
+                    value_helper(nodestore, &child, level + 1, remaining_key)
+                }
+            }
+        }
+    }
+}
+
+/// The hashes of the nodes along the path to a key, each paired with the
+/// number of key nibbles above that node
+pub(crate) type PathHashes = Vec<(usize, TrieHash)>;
+
+/// The result of [Merkle::lookup_unless_unchanged]
+#[derive(Debug)]
+pub(crate) enum KeyLookup {
+    /// A node on the path to the key is identical to one of the known nodes,
+    /// so the key has the same value. The hashes of the nodes above that
+    /// point are returned, followed by the known nodes below it.
+    Unchanged(PathHashes),
+    /// The key was looked up from scratch
+    Changed {
+        value: Option<Box<[u8]>>,
+        path: PathHashes,
+    },
+}
+
+/// What [crate::db::Db::heal_paths] changed
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HealStats {
+    /// The number of branches folded into the node below them
+    pub nodes_folded: usize,
+    /// The most nodes removed from the path to any one key
+    pub levels_removed: usize,
+}
+
 #[derive(Debug)]
 /// Merkle operations against a nodestore
 pub struct Merkle<T> {
@@ -141,9 +220,15 @@ pub struct Merkle<T> {
 }
 
 impl<T> Merkle<T> {
-    pub(crate) fn into_inner(self) -> T {
+    /// Returns the underlying nodestore
+    pub fn into_inner(self) -> T {
         self.nodestore
     }
+
+    /// Returns a reference to the underlying nodestore
+    pub const fn nodestore(&self) -> &T {
+        &self.nodestore
+    }
 }
 
 impl<T> From<T> for Merkle<T> {
@@ -157,18 +242,30 @@ impl<T: TrieReader> Merkle<T> {
         self.nodestore.root_node()
     }
 
-    #[cfg(test)]
-    pub(crate) const fn nodestore(&self) -> &T {
-        &self.nodestore
-    }
-
     fn read_node(&self, addr: LinearAddress) -> Result<Arc<Node>, MerkleError> {
         self.nodestore.read_node(addr).map_err(Into::into)
     }
 
+    fn read_node_at_depth(
+        &self,
+        addr: LinearAddress,
+        depth: usize,
+    ) -> Result<Arc<Node>, MerkleError> {
+        self.nodestore
+            .read_node_at_depth(addr, depth)
+            .map_err(Into::into)
+    }
+
     /// Returns a proof that the given key has a certain value,
     /// or that the key isn't in the trie.
     pub fn prove(&self, key: &[u8]) -> Result<Proof<ProofNode>, MerkleError> {
+        self.get_with_proof(key).map(|(_, proof)| proof)
+    }
+
+    /// Returns the value of `key`, or None if it isn't in the trie, along
+    /// with the proof of it [Merkle::prove] returns. The path to `key` is
+    /// only walked once for both.
+    pub fn get_with_proof(&self, key: &[u8]) -> Result<ValueWithProof, MerkleError> {
         let Some(root) = self.root() else {
             return Err(MerkleError::Empty);
         };
@@ -176,35 +273,51 @@ impl<T: TrieReader> Merkle<T> {
         // Get the path to the key
         let path_iter = self.path_iter(key)?;
         let mut proof = Vec::new();
-        for node in path_iter {
-            let node = node?;
-            proof.push(ProofNode::from(node));
+        let mut diverging = None;
+        let mut value = None;
+        let key_nibbles: Vec<u8> = NibblesIterator::new(key).collect();
+        for item in path_iter {
+            let item = item?;
+            // the path ends at the node of `key`, if it is in the trie
+            if item.next_nibble.is_none() && *item.key_nibbles == *key_nibbles {
+                value = item.node.value().map(Box::from);
+            }
+            // the child on the path to `key`, which is the next item unless
+            // its partial path diverges from `key`
+            diverging = match (item.next_nibble, item.node.as_branch()) {
+                (Some(nibble), Some(branch)) => branch
+                    .children
+                    .get(nibble as usize)
+                    .cloned()
+                    .flatten()
+                    .map(|child| (item.key_nibbles.clone(), nibble, child)),
+                _ => None,
+            };
+            proof.push(ProofNode::from(item));
+        }
+
+        // The path stopped above a child whose partial path diverges from
+        // `key`, so that child is what proves `key` isn't in the trie.
+        if let Some((parent_key, nibble, child)) = diverging {
+            let node = self.read_child(&child)?;
+            let key_nibbles = parent_key
+                .iter()
+                .copied()
+                .chain(once(nibble))
+                .chain(node.partial_path().iter().copied())
+                .collect();
+            proof.push(ProofNode::new(key_nibbles, &node));
         }
 
         if proof.is_empty() {
             // No nodes, even the root, are before `key`.
-            // The root alone proves the non-existence of `key`.
-            // TODO reduce duplicate code with ProofNode::from<PathIterItem>
-            let mut child_hashes: [Option<TrieHash>; BranchNode::MAX_CHILDREN] =
-                [const { None }; BranchNode::MAX_CHILDREN];
-            if let Some(branch) = root.as_branch() {
-                // TODO danlaine: can we avoid indexing?
-                #[allow(clippy::indexing_slicing)]
-                for (i, hash) in branch.children_iter() {
-                    child_hashes[i] = Some(hash.clone());
-                }
-            }
-
-            proof.push(ProofNode {
-                key: root.partial_path().bytes(),
-                value_digest: root
-                    .value()
-                    .map(|value| ValueDigest::Value(value.to_vec().into_boxed_slice())),
-                child_hashes,
-            })
+            // The root alone proves the non-existence of `key`. Its key is its
+            // partial path, in nibbles like the keys of every proof node.
+            let key_nibbles = root.partial_path().iter().copied().collect();
+            proof.push(ProofNode::new(key_nibbles, &root));
         }
 
-        Ok(Proof(proof.into_boxed_slice()))
+        Ok((value, Proof(proof.into_boxed_slice())))
     }
 
     /// Verify a proof that a key has a certain value, or that the key isn't in the trie.
@@ -238,7 +351,22 @@ impl<T: TrieReader> Merkle<T> {
         MerkleKeyValueStream::from_key(&self.nodestore, key.as_ref())
     }
 
-    #[allow(dead_code)]
+    pub(super) fn key_value_iter_prefix<K: AsRef<[u8]>>(
+        &self,
+        prefix: K,
+    ) -> MerkleKeyValueStream<'_, T> {
+        MerkleKeyValueStream::from_prefix(&self.nodestore, prefix)
+    }
+
+    #[cfg(test)]
+    pub(super) fn key_value_iter_range<K: AsRef<[u8]>>(
+        &self,
+        start: K,
+        end: K,
+    ) -> MerkleKeyValueStream<'_, T> {
+        MerkleKeyValueStream::from_range(&self.nodestore, start, end)
+    }
+
     pub(super) async fn range_proof(
         &self,
         start_key: Option<&[u8]>,
@@ -260,93 +388,515 @@ impl<T: TrieReader> Merkle<T> {
             None => self.key_value_iter(),
         };
 
-        // fetch the first key from the stream
-        let first_result = stream.next().await;
-
-        // transpose the Option<Result<T, E>> to Result<Option<T>, E>
-        // If this is an error, the ? operator will return it
-        let Some((first_key, first_value)) = first_result.transpose()? else {
-            // The trie is empty.
-            if start_key.is_none() && end_key.is_none() {
-                // The caller requested a range proof over an empty trie.
-                return Err(api::Error::RangeProofOnEmptyTrie);
-            }
-
-            let start_proof = start_key
-                .map(|start_key| self.prove(start_key))
-                .transpose()?;
-
-            let end_proof = end_key.map(|end_key| self.prove(end_key)).transpose()?;
-
-            return Ok(RangeProof {
-                start_proof,
-                key_values: Box::new([]),
-                end_proof,
-            });
-        };
-
-        let start_proof = self.prove(&first_key)?;
-        let limit = limit.map(|old_limit| old_limit.get() - 1);
-
-        let mut key_values = vec![(first_key, first_value.into_boxed_slice())];
-
         // we stop streaming if either we hit the limit or the key returned was larger
         // than the largest key requested
-        #[allow(clippy::unwrap_used)]
-        key_values.extend(
-            stream
-                .take(limit.unwrap_or(usize::MAX))
-                .take_while(|kv| {
-                    // no last key asked for, so keep going
-                    let Some(last_key) = end_key else {
-                        return ready(true);
-                    };
-
-                    // return the error if there was one
-                    let Ok(kv) = kv else {
-                        return ready(true);
-                    };
+        let limit = limit.map_or(usize::MAX, NonZeroUsize::get);
+        let mut key_values = Vec::new();
+        while key_values.len() < limit {
+            let Some((key, value)) = stream.next().await.transpose()? else {
+                break;
+            };
+            if end_key.is_some_and(|end_key| *key > *end_key) {
+                break;
+            }
+            key_values.push((key, value.into_boxed_slice()));
+        }
 
-                    // keep going if the key returned is less than the last key requested
-                    ready(&*kv.0 <= last_key)
-                })
-                .map(|kv| kv.map(|(k, v)| (k, v.into())))
-                .try_collect::<Vec<(Box<[u8]>, Box<[u8]>)>>()
-                .await?,
-        );
+        if key_values.is_empty() && start_key.is_none() && end_key.is_none() {
+            // The caller requested a range proof over an empty trie.
+            return Err(api::Error::RangeProofOnEmptyTrie);
+        }
 
+        // The range ends at the last key returned, so that a range cut short
+        // by the limit can be resumed after it
+        let start_proof = start_key.map(|key| self.prove(key)).transpose()?;
         let end_proof = key_values
             .last()
-            .map(|(largest_key, _)| self.prove(largest_key))
+            .map(|(largest_key, _)| &**largest_key)
+            .or(end_key)
+            .map(|key| self.prove(key))
             .transpose()?;
 
-        debug_assert!(end_proof.is_some());
-
         Ok(RangeProof {
-            start_proof: Some(start_proof),
+            start_proof,
             key_values: key_values.into(),
             end_proof,
         })
     }
 
-    pub(crate) fn get_value(&self, key: &[u8]) -> Result<Option<Box<[u8]>>, MerkleError> {
+    /// Returns the value of `key`, or None if it isn't in the trie
+    pub fn get_value(&self, key: &[u8]) -> Result<Option<Box<[u8]>>, MerkleError> {
         let Some(node) = self.get_node(key)? else {
             return Ok(None);
         };
         Ok(node.value().map(|v| v.to_vec().into_boxed_slice()))
     }
 
+    /// Returns the value of each of `keys`, in the same order, or None for
+    /// the ones that aren't in the trie. The trie is walked once for all of
+    /// them, so the nodes on the paths that keys share are only read once.
+    pub fn get_values<K: AsRef<[u8]>>(
+        &self,
+        keys: &[K],
+    ) -> Result<Vec<Option<Box<[u8]>>>, MerkleError> {
+        let mut values = vec![None; keys.len()];
+        let Some(root) = self.root() else {
+            return Ok(values);
+        };
+
+        let nibbles: Vec<Path> = keys
+            .iter()
+            .map(|key| Path::from_nibbles_iterator(NibblesIterator::new(key.as_ref())))
+            .collect();
+        let mut sorted: Vec<(usize, &[u8])> =
+            nibbles.iter().map(|key| &**key).enumerate().collect();
+        sorted.sort_unstable_by_key(|(_, key)| *key);
+        get_many_helper(&self.nodestore, &root, 0, 0, &sorted, &mut values)?;
+        Ok(values)
+    }
+
+    fn max_key_helper(&self, node: &Node, nibbles: &mut Path) -> Result<(), MerkleError> {
+        nibbles.extend(node.partial_path().iter().copied());
+        let Node::Branch(branch) = node else {
+            return Ok(());
+        };
+        let Some((index, child)) = branch
+            .children
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(index, child)| child.as_ref().map(|child| (index, child)))
+        else {
+            return Ok(());
+        };
+        nibbles.extend(once(index as u8));
+        match child {
+            Child::Node(child) => self.max_key_helper(child, nibbles),
+            Child::AddressWithHash(addr, _) => {
+                let child = self.read_node(*addr)?;
+                self.max_key_helper(&child, nibbles)
+            }
+        }
+    }
+
+    /// Looks up the value of the key with nibbles `key` in the trie with root
+    /// hash `root_hash`, but stops as soon as it reaches a node whose hash and
+    /// position in the key match one of `known`. Such a node roots a subtrie
+    /// identical to the one the key was last looked up in, so the value can
+    /// be reused without descending any further.
+    pub(crate) fn lookup_unless_unchanged(
+        &self,
+        key: &[u8],
+        root_hash: TrieHash,
+        known: &[(usize, TrieHash)],
+    ) -> Result<KeyLookup, MerkleError> {
+        let is_known = |depth: usize, hash: &TrieHash| {
+            known
+                .iter()
+                .position(|(known_depth, known_hash)| *known_depth == depth && known_hash == hash)
+        };
+        let unchanged = |mut path: PathHashes, known_index: usize| {
+            path.extend(known.iter().skip(known_index).cloned());
+            KeyLookup::Unchanged(path)
+        };
+
+        if let Some(index) = is_known(0, &root_hash) {
+            return Ok(unchanged(PathHashes::new(), index));
+        }
+        let Some(mut node) = self.root() else {
+            return Ok(KeyLookup::Changed {
+                value: None,
+                path: PathHashes::new(),
+            });
+        };
+
+        let mut path = vec![(0, root_hash)];
+        let mut depth = 0;
+        let mut level = 0;
+        loop {
+            let remaining_key = key.get(depth..).unwrap_or_default();
+            let partial_path = node.partial_path();
+            if !remaining_key.starts_with(partial_path) {
+                return Ok(KeyLookup::Changed { value: None, path });
+            }
+            depth += partial_path.len();
+
+            let Some(&child_index) = key.get(depth) else {
+                let value = node.value().map(Box::from);
+                return Ok(KeyLookup::Changed { value, path });
+            };
+            let Node::Branch(branch) = node.as_ref() else {
+                return Ok(KeyLookup::Changed { value: None, path });
+            };
+            depth += 1;
+            level += 1;
+
+            let child = match branch.children.get(child_index as usize) {
+                Some(Some(Child::AddressWithHash(addr, hash))) => {
+                    if let Some(index) = is_known(depth, hash) {
+                        return Ok(unchanged(path, index));
+                    }
+                    path.push((depth, hash.clone()));
+                    self.read_node_at_depth(*addr, level)?
+                }
+                Some(Some(Child::Node(child))) => Arc::new(child.clone()),
+                _ => return Ok(KeyLookup::Changed { value: None, path }),
+            };
+            node = child;
+        }
+    }
+
     pub(crate) fn get_node(&self, key: &[u8]) -> Result<Option<Arc<Node>>, MerkleError> {
         let Some(root) = self.root() else {
             return Ok(None);
         };
 
         let key = Path::from_nibbles_iterator(NibblesIterator::new(key));
-        get_helper(&self.nodestore, &root, &key)
+        get_helper(&self.nodestore, &root, Some(&root), 0, &key)
+    }
+}
+
+/// Looks up the values of `keys` at or below `node`, which is `depth`
+/// nibbles and `level` nodes below the root. Each key is its index in
+/// `values` and its nibbles. The keys are sorted and share their first
+/// `depth` nibbles, so the ones below each child are next to each other and
+/// the child is read once for all of them.
+fn get_many_helper<T: TrieReader>(
+    nodestore: &T,
+    node: &Node,
+    depth: usize,
+    level: usize,
+    keys: &[(usize, &[u8])],
+    values: &mut [Option<Box<[u8]>>],
+) -> Result<(), MerkleError> {
+    let partial_path: &[u8] = node.partial_path();
+    let before = |key: &[u8]| key.get(depth..).unwrap_or_default() < partial_path;
+    let through = |key: &[u8]| {
+        key.get(depth..)
+            .unwrap_or_default()
+            .starts_with(partial_path)
+    };
+    // only the keys that run through the partial path are at or below the node
+    let from = keys.partition_point(|(_, key)| before(key));
+    let to = keys.partition_point(|(_, key)| before(key) || through(key));
+    let mut rest = keys.get(from..to).unwrap_or_default();
+    let depth = depth + partial_path.len();
+
+    while let Some(&(index, key)) = rest.first() {
+        let Some(&child_index) = key.get(depth) else {
+            // the key is at the node
+            if let Some(value) = values.get_mut(index) {
+                *value = node.value().map(Box::from);
+            }
+            rest = rest.get(1..).unwrap_or_default();
+            continue;
+        };
+        let count = rest.partition_point(|(_, key)| key.get(depth) == Some(&child_index));
+        let (under, after) = rest.split_at(count);
+        rest = after;
+
+        let Node::Branch(branch) = node else {
+            continue;
+        };
+        match branch.children.get(child_index as usize) {
+            Some(Some(Child::Node(child))) => {
+                get_many_helper(nodestore, child, depth + 1, level + 1, under, values)?
+            }
+            Some(Some(Child::AddressWithHash(addr, _))) => {
+                let child = nodestore.read_node_at_depth(*addr, level + 1)?;
+                get_many_helper(nodestore, &child, depth + 1, level + 1, under, values)?
+            }
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+/// Number of random walks taken through each subtree that lies entirely
+/// inside a range being estimated.
+const ESTIMATE_SAMPLES: u64 = 4;
+
+/// Where a node's partial path falls relative to a range boundary.
+#[derive(Debug)]
+enum BoundPosition<'a> {
+    /// Every key in the subtree sorts before the boundary
+    Below,
+    /// Every key in the subtree sorts at or after the boundary
+    Above,
+    /// The boundary passes through the subtree; the remaining nibbles of the
+    /// boundary below this node are returned
+    Within(&'a [u8]),
+}
+
+impl<'a> BoundPosition<'a> {
+    fn from(partial_path: &[u8], bound: &'a [u8]) -> Self {
+        for (path_nibble, bound_nibble) in partial_path.iter().zip(bound) {
+            match path_nibble.cmp(bound_nibble) {
+                Ordering::Less => return BoundPosition::Below,
+                Ordering::Greater => return BoundPosition::Above,
+                Ordering::Equal => {}
+            }
+        }
+
+        // the boundary either ends at or above this node, or continues below it
+        match bound.get(partial_path.len()..) {
+            Some(remaining) if !remaining.is_empty() => BoundPosition::Within(remaining),
+            _ => BoundPosition::Above,
+        }
+    }
+}
+
+impl<T: TrieReader> Merkle<T> {
+    /// Estimates the number of keys, and the number of key and value bytes,
+    /// in the range `[start_key, end_key)`. A bound of `None` leaves that side
+    /// of the range open.
+    ///
+    /// The trie is only descended along the two range boundaries. Each subtree
+    /// that lies entirely inside the range is estimated with a handful of
+    /// random walks: every walk multiplies the fanout of the branches it passes
+    /// through to extrapolate how many values sit at each level (Knuth's tree
+    /// size estimator). This touches roughly `depth * fanout * samples` nodes
+    /// regardless of how many keys are in the range.
+    ///
+    /// Accuracy: each walk is an unbiased estimate, so for tries built from
+    /// uniformly distributed keys (such as hashes) the result is typically
+    /// within 10-30% of the real count. Tries with heavily skewed key
+    /// distributions can be off by much more. Empty ranges, and ranges that
+    /// only contain keys along the boundaries, are counted exactly.
+    pub fn estimate_range(
+        &self,
+        start_key: Option<&[u8]>,
+        end_key: Option<&[u8]>,
+    ) -> Result<RangeEstimate, MerkleError> {
+        let Some(root) = self.root() else {
+            return Ok(RangeEstimate::default());
+        };
+
+        if let (Some(start_key), Some(end_key)) = (start_key, end_key) {
+            if start_key >= end_key {
+                return Ok(RangeEstimate::default());
+            }
+        }
+
+        let start = start_key.map(|key| Path::from_nibbles_iterator(NibblesIterator::new(key)));
+        let end = end_key.map(|key| Path::from_nibbles_iterator(NibblesIterator::new(key)));
+
+        let (keys, bytes) = self.estimate_helper(&root, 0, start.as_deref(), end.as_deref())?;
+
+        Ok(RangeEstimate {
+            approx_keys: keys.round() as u64,
+            approx_bytes: bytes.round() as u64,
+        })
+    }
+
+    /// Estimates the keys and bytes of the subtrie rooted at `node` that fall in
+    /// `[start, end)`. `depth` is the number of nibbles in the key above `node`,
+    /// and `start` and `end` are the nibbles of each boundary below that point.
+    fn estimate_helper(
+        &self,
+        node: &Arc<Node>,
+        depth: usize,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(f64, f64), MerkleError> {
+        let partial_path = node.partial_path();
+
+        let start = match start.map(|start| BoundPosition::from(partial_path, start)) {
+            None | Some(BoundPosition::Above) => None,
+            Some(BoundPosition::Below) => return Ok((0.0, 0.0)),
+            Some(BoundPosition::Within(remaining)) => Some(remaining),
+        };
+
+        let end = match end.map(|end| BoundPosition::from(partial_path, end)) {
+            None | Some(BoundPosition::Below) => None,
+            Some(BoundPosition::Above) => return Ok((0.0, 0.0)),
+            Some(BoundPosition::Within(remaining)) => Some(remaining),
+        };
+
+        if start.is_none() && end.is_none() {
+            return self.sample_subtree(node, depth);
+        }
+
+        let depth = depth + partial_path.len();
+        let (mut keys, mut bytes) = (0.0, 0.0);
+
+        // if the start boundary continues below this node, this node's key is
+        // a strict prefix of it and so sorts before the range
+        if start.is_none() {
+            if let Some(value) = node.value() {
+                keys += 1.0;
+                bytes += (depth / BranchNode::NIBBLES_PER_BYTE + value.len()) as f64;
+            }
+        }
+
+        let Node::Branch(branch) = node.as_ref() else {
+            return Ok((keys, bytes));
+        };
+
+        for (child_index, child) in branch.children.iter().enumerate() {
+            let Some(child) = child else {
+                continue;
+            };
+
+            let child_start = match start.and_then(|start| start.split_first()) {
+                None => None,
+                Some((&nibble, _)) if child_index < nibble as usize => continue,
+                Some((&nibble, remaining)) if child_index == nibble as usize => {
+                    Some(remaining).filter(|remaining| !remaining.is_empty())
+                }
+                Some(_) => None,
+            };
+
+            let child_end = match end.and_then(|end| end.split_first()) {
+                None => None,
+                Some((&nibble, _)) if child_index > nibble as usize => continue,
+                Some((&nibble, remaining)) if child_index == nibble as usize => {
+                    // every key below this child starts with the end boundary
+                    if remaining.is_empty() {
+                        continue;
+                    }
+                    Some(remaining)
+                }
+                Some(_) => None,
+            };
+
+            let child = self.read_child(child)?;
+            let (child_keys, child_bytes) =
+                self.estimate_helper(&child, depth + 1, child_start, child_end)?;
+            keys += child_keys;
+            bytes += child_bytes;
+        }
+
+        Ok((keys, bytes))
+    }
+
+    /// Estimates the keys and bytes in the subtrie rooted at `node` by averaging
+    /// [ESTIMATE_SAMPLES] random walks from `node` down to a leaf.
+    fn sample_subtree(&self, node: &Arc<Node>, depth: usize) -> Result<(f64, f64), MerkleError> {
+        let (mut keys, mut bytes) = (0.0, 0.0);
+
+        for sample in 0..ESTIMATE_SAMPLES {
+            // a small deterministic generator keeps estimates repeatable
+            let mut seed = sample.wrapping_add(depth as u64);
+            let mut weight = 1.0;
+            let mut depth = depth;
+            let mut node = node.clone();
+
+            loop {
+                depth += node.partial_path().len();
+                if let Some(value) = node.value() {
+                    keys += weight;
+                    bytes += weight * (depth / BranchNode::NIBBLES_PER_BYTE + value.len()) as f64;
+                }
+
+                let Node::Branch(branch) = node.as_ref() else {
+                    break;
+                };
+                let children: SmallVec<[&Child; BranchNode::MAX_CHILDREN]> =
+                    branch.children.iter().flatten().collect();
+                if children.is_empty() {
+                    break;
+                }
+
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                let pick = (seed >> 33) as usize % children.len();
+                let child = self.read_child(children.get(pick).expect("index is in bounds"))?;
+
+                weight *= children.len() as f64;
+                depth += 1;
+                drop(children);
+                node = child;
+            }
+        }
+
+        Ok((
+            keys / ESTIMATE_SAMPLES as f64,
+            bytes / ESTIMATE_SAMPLES as f64,
+        ))
+    }
+
+    fn read_child(&self, child: &Child) -> Result<Arc<Node>, MerkleError> {
+        match child {
+            Child::Node(node) => Ok(Arc::new(node.clone())),
+            Child::AddressWithHash(addr, _) => self.read_node(*addr),
+        }
     }
 }
 
 impl<T: HashedNodeReader> Merkle<T> {
+    /// Answers each of `requests` against this trie, with one proof covering
+    /// every answer; see [crate::audit]. Hold the revision for as long as
+    /// the bundle is being built, so that every read sees the same root.
+    ///
+    /// Prefix digests and ranges are read in full, so each is limited to
+    /// [MAX_AUDIT_ENTRIES] entries.
+    pub fn audit_bundle(&self, requests: Vec<AuditRequest>) -> Result<AuditBundle, AuditError> {
+        let (Some(root), Some(root_hash)) = (
+            self.root(),
+            self.nodestore.root_hash().map_err(MerkleError::from)?,
+        ) else {
+            return Err(MerkleError::Empty.into());
+        };
+
+        let mut proof = BTreeMap::new();
+        let mut items = Vec::with_capacity(requests.len());
+        for (index, request) in requests.into_iter().enumerate() {
+            let span = Span::from(&request);
+            let mut entries = Vec::new();
+            self.audit_helper(&root, Path::new(), &span, &mut proof, &mut entries, index)?;
+            let result = request.result(entries);
+            items.push(AuditItem { request, result });
+        }
+
+        Ok(AuditBundle {
+            root_hash,
+            items,
+            proof: proof.into_values().collect(),
+        })
+    }
+
+    /// Adds `node`, whose key is `key` followed by its partial path, to
+    /// `proof`, along with every node below it that `span` could read, and
+    /// collects the entries `span` reads in key order
+    fn audit_helper(
+        &self,
+        node: &Arc<Node>,
+        mut key: Path,
+        span: &Span,
+        proof: &mut BTreeMap<Box<[u8]>, ProofNode>,
+        entries: &mut Vec<AuditEntry>,
+        request: usize,
+    ) -> Result<(), AuditError> {
+        key.extend(node.partial_path().iter().copied());
+        if let Some(value) = node.value().filter(|_| span.contains(&key)) {
+            if entries.len() == MAX_AUDIT_ENTRIES {
+                return Err(AuditError::TooManyEntries { request });
+            }
+            entries.push((key_from_nibble_iter(key.iter().copied()), value.into()));
+        }
+        proof
+            .entry(key.to_vec().into())
+            .or_insert_with(|| ProofNode::new(key.to_vec().into(), node));
+
+        let Node::Branch(branch) = node.as_ref() else {
+            return Ok(());
+        };
+        for (child_index, child) in branch.children.iter().enumerate() {
+            let Some(child) = child else {
+                continue;
+            };
+            let mut child_key = key.clone();
+            child_key.extend(once(child_index as u8));
+            if span.intersects(&child_key) {
+                let child = self.read_child(child)?;
+                self.audit_helper(&child, child_key, span, proof, entries, request)?;
+            }
+        }
+        Ok(())
+    }
+
     pub(crate) fn dump_node(
         &self,
         addr: LinearAddress,
@@ -423,6 +973,17 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
         self.into()
     }
 
+    /// The value of `key`. Unlike [Merkle::get_value], this doesn't copy the
+    /// nodes of the proposal that are in memory, so it stays cheap while a
+    /// large batch is applied.
+    pub(crate) fn peek_value(&self, key: &[u8]) -> Result<Option<Box<[u8]>>, MerkleError> {
+        let Some(root) = self.nodestore.root_ref() else {
+            return Ok(None);
+        };
+        let key = Path::from_nibbles_iterator(NibblesIterator::new(key));
+        value_helper(&self.nodestore, root, 0, &key)
+    }
+
     /// Map `key` to `value` in the trie.
     /// Each element of key is 2 nibbles.
     pub fn insert(&mut self, key: &[u8], value: Box<[u8]>) -> Result<(), MerkleError> {
@@ -465,13 +1026,14 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
         let unique_key = path_overlap.unique_a;
         let unique_node = path_overlap.unique_b;
 
+        // The rest of the key stays borrowed until a node is made for it. The
+        // rest of the node's path is only non-empty when the node is moved
+        // below a new branch, which needs it owned.
         match (
-            unique_key
-                .split_first()
-                .map(|(index, path)| (*index, path.into())),
+            unique_key.split_first().map(|(index, path)| (*index, path)),
             unique_node
                 .split_first()
-                .map(|(index, path)| (*index, path.into())),
+                .map(|(index, path)| (*index, Path::from(path))),
         ) {
             (None, None) => {
                 // 1. The node is at `key`
@@ -517,7 +1079,7 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
                                 // Create a new leaf and put it here.
                                 let new_leaf = Node::Leaf(LeafNode {
                                     value: SmallVec::from(&value[..]),
-                                    partial_path,
+                                    partial_path: partial_path.into(),
                                 });
                                 branch.update_child(child_index, Some(Child::Node(new_leaf)));
                                 counter!("firewood.insert", "merkle"=>"below").increment(1);
@@ -529,7 +1091,7 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
                             }
                         };
 
-                        let child = self.insert_helper(child, partial_path.as_ref(), value)?;
+                        let child = self.insert_helper(child, partial_path, value)?;
                         branch.update_child(child_index, Some(Child::Node(child)));
                         Ok(node)
                     }
@@ -543,7 +1105,7 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
 
                         let new_leaf = Node::Leaf(LeafNode {
                             value: SmallVec::from(&value[..]),
-                            partial_path,
+                            partial_path: partial_path.into(),
                         });
 
                         branch.update_child(child_index, Some(Child::Node(new_leaf)));
@@ -572,7 +1134,7 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
 
                 let new_leaf = Node::Leaf(LeafNode {
                     value: SmallVec::from(&value[..]),
-                    partial_path: key_partial_path,
+                    partial_path: key_partial_path.into(),
                 });
                 branch.update_child(key_index, Some(Child::Node(new_leaf)));
 
@@ -582,6 +1144,235 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
         }
     }
 
+    /// Returns the nibbles of the largest key in the trie, reading only the
+    /// rightmost path
+    pub(crate) fn max_key_nibbles(&self) -> Result<Option<Path>, MerkleError> {
+        let Some(root) = self.nodestore.root_ref() else {
+            return Ok(None);
+        };
+        let mut nibbles = Path::new();
+        self.max_key_helper(root, &mut nibbles)?;
+        Ok(Some(nibbles))
+    }
+
+    /// Map each key in `entries` to its value. `entries` holds the nibbles of
+    /// each key and must be sorted by key, with no duplicates.
+    ///
+    /// This is the fast path for appends, where every key is larger than any
+    /// already in the trie. Instead of descending from the root for each key,
+    /// it walks the trie once for the whole batch: new subtries are built
+    /// bottom up, and each existing node along the way is copied only once.
+    /// The result is the same trie as inserting the entries one at a time.
+    pub fn insert_sorted(&mut self, entries: &[(Path, Box<[u8]>)]) -> Result<(), MerkleError> {
+        debug_assert!(entries
+            .windows(2)
+            .all(|pair| matches!(pair, [a, b] if *a.0 < *b.0)));
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        let root = std::mem::take(self.nodestore.mut_root());
+        let root = self.merge_sorted(root, entries, 0)?;
+        *self.nodestore.mut_root() = Some(root);
+        counter!("firewood.insert", "merkle" => "sorted").increment(entries.len() as u64);
+        Ok(())
+    }
+
+    /// Merge the non-empty, sorted `entries` into the subtrie rooted at
+    /// `node`. The first `depth` nibbles of every key lead to `node`.
+    fn merge_sorted(
+        &mut self,
+        node: Option<Node>,
+        entries: &[SortedEntry],
+        depth: usize,
+    ) -> Result<Node, MerkleError> {
+        let Some(mut node) = node else {
+            return Ok(build_sorted(entries, depth));
+        };
+
+        // The nibbles shared by the node's partial path and every key. The
+        // keys are sorted, so only the first and last need to be checked.
+        let partial_path = node.partial_path();
+        let shared = [entries.first(), entries.last()]
+            .into_iter()
+            .flatten()
+            .map(|(key, _)| common_prefix_len(partial_path, key.get(depth..).unwrap_or_default()))
+            .min()
+            .unwrap_or_default();
+
+        // The partial path is only copied when the node moves below a new branch
+        let split = match partial_path.split_at(shared) {
+            (_, []) => None,
+            (shared_path, [child_index, child_path @ ..]) => Some((
+                Path::from(shared_path),
+                *child_index,
+                Path::from(child_path),
+            )),
+        };
+        let branch = match split {
+            None => match node {
+                Node::Branch(branch) => branch,
+                Node::Leaf(mut leaf) if matches!(entries, [(key, _)] if key.len() == depth + shared) =>
+                {
+                    // The only key is this leaf's, so just replace its value.
+                    if let [(_, value)] = entries {
+                        leaf.value = SmallVec::from(&value[..]);
+                    }
+                    return Ok(Node::Leaf(leaf));
+                }
+                Node::Leaf(leaf) => {
+                    // Some keys are below this leaf, so it becomes a branch.
+                    Box::new(BranchNode {
+                        partial_path: leaf.partial_path,
+                        value: Some(leaf.value.into_vec().into_boxed_slice()),
+                        children: [const { None }; BranchNode::MAX_CHILDREN],
+                    })
+                }
+            },
+            Some((shared_path, child_index, child_path)) => {
+                // The keys diverge from the node inside its partial path, so a
+                // new branch goes above it.
+                node.update_partial_path(child_path);
+                let mut branch = Box::new(BranchNode {
+                    partial_path: shared_path,
+                    value: None,
+                    children: [const { None }; BranchNode::MAX_CHILDREN],
+                });
+                branch.update_child(child_index, Some(Child::Node(node)));
+                branch
+            }
+        };
+        self.merge_into_branch(branch, entries, depth + shared)
+    }
+
+    /// Merge the non-empty, sorted `entries` into the children of `branch`,
+    /// where the first `depth` nibbles of every key lead to `branch`'s
+    /// children and the first key may end at `branch` itself.
+    fn merge_into_branch(
+        &mut self,
+        mut branch: Box<BranchNode>,
+        entries: &[SortedEntry],
+        depth: usize,
+    ) -> Result<Node, MerkleError> {
+        let entries = match entries.split_first() {
+            Some(((key, value), rest)) if key.len() == depth => {
+                branch.value = Some(value.clone());
+                rest
+            }
+            _ => entries,
+        };
+
+        for (child_index, group) in group_by_nibble(entries, depth) {
+            let child = match branch
+                .children
+                .get_mut(child_index as usize)
+                .and_then(Option::take)
+            {
+                None => None,
+                Some(Child::Node(child)) => Some(child),
+                Some(Child::AddressWithHash(addr, _)) => {
+                    Some(self.nodestore.read_for_update(addr)?)
+                }
+            };
+            let child = self.merge_sorted(child, group, depth + 1)?;
+            branch.update_child(child_index, Some(Child::Node(child)));
+        }
+        Ok(Node::Branch(branch))
+    }
+
+    /// Fold chains of branches that have no value and a single child into
+    /// the node below them, prepending their paths to its partial path. The
+    /// trie holds the same keys and values afterwards, but reads take fewer
+    /// steps and the root hash changes.
+    ///
+    /// At most `budget` branches are folded. Only the nodes above a fold are
+    /// rewritten. `checkpoint` is called with the number of nodes visited as
+    /// the walk goes, and the walk stops with its error if it fails.
+    pub(crate) fn heal_paths(
+        &mut self,
+        budget: usize,
+        mut checkpoint: impl FnMut(u64) -> Result<(), api::Error>,
+    ) -> Result<HealStats, api::Error> {
+        let mut stats = HealStats::default();
+        let Some(root) = self.nodestore.mut_root().take() else {
+            return Ok(stats);
+        };
+        let mut budget = budget;
+        let healed = self.heal_subtrie(&root, 0, &mut budget, &mut stats, &mut checkpoint);
+        let (root, result) = match healed {
+            Ok(healed) => (healed.unwrap_or(root), Ok(stats)),
+            Err(err) => (root, Err(err)),
+        };
+        *self.nodestore.mut_root() = Some(root);
+        result
+    }
+
+    /// Returns the healed copy of `node`, or None if nothing below it
+    /// changed. `folded_above` branches were already folded on the way here.
+    fn heal_subtrie(
+        &mut self,
+        node: &Node,
+        folded_above: usize,
+        budget: &mut usize,
+        stats: &mut HealStats,
+        checkpoint: &mut impl FnMut(u64) -> Result<(), api::Error>,
+    ) -> Result<Option<Node>, api::Error> {
+        checkpoint(1)?;
+        if *budget == 0 {
+            return Ok(None);
+        }
+        let Node::Branch(branch) = node else {
+            return Ok(None);
+        };
+
+        if let (None, Some(index)) = (&branch.value, branch.has_single_child()) {
+            let mut folded = match branch.child(index) {
+                Some(Child::Node(child)) => child.clone(),
+                Some(Child::AddressWithHash(addr, _)) => self.nodestore.read_for_update(*addr)?,
+                None => unreachable!("the branch has a child at {index}"),
+            };
+            let partial_path = branch
+                .partial_path
+                .iter()
+                .copied()
+                .chain(once(index))
+                .chain(folded.partial_path().iter().copied());
+            folded.update_partial_path(Path::from_nibbles_iterator(partial_path));
+            *budget -= 1;
+            stats.nodes_folded += 1;
+            stats.levels_removed = stats.levels_removed.max(folded_above + 1);
+
+            // the node below may start a chain too
+            let healed = self.heal_subtrie(&folded, folded_above + 1, budget, stats, checkpoint)?;
+            return Ok(Some(healed.unwrap_or(folded)));
+        }
+
+        let mut healed_branch = None;
+        for (index, child) in branch.children.iter().enumerate() {
+            let healed = match child {
+                None => None,
+                Some(Child::Node(child)) => {
+                    self.heal_subtrie(child, folded_above, budget, stats, checkpoint)?
+                }
+                Some(Child::AddressWithHash(addr, _)) => {
+                    let child = self.read_node(*addr)?;
+                    let healed =
+                        self.heal_subtrie(&child, folded_above, budget, stats, checkpoint)?;
+                    if healed.is_some() {
+                        self.nodestore.delete_node(*addr);
+                    }
+                    healed
+                }
+            };
+            if let Some(healed) = healed {
+                healed_branch
+                    .get_or_insert_with(|| branch.clone())
+                    .update_child(index as u8, Some(Child::Node(healed)));
+            }
+        }
+        Ok(healed_branch.map(Node::Branch))
+    }
+
     /// Removes the value associated with the given `key`.
     /// Returns the value that was removed, if any.
     /// Otherwise returns `None`.
@@ -626,9 +1417,7 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
         let unique_node = path_overlap.unique_b;
 
         match (
-            unique_key
-                .split_first()
-                .map(|(index, path)| (*index, Path::from(path))),
+            unique_key.split_first().map(|(index, path)| (*index, path)),
             unique_node.split_first(),
         ) {
             (_, Some(_)) => {
@@ -741,7 +1530,7 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
                         };
 
                         let (child, removed_value) =
-                            self.remove_helper(child, child_partial_path.as_ref())?;
+                            self.remove_helper(child, child_partial_path)?;
 
                         if let Some(child) = child {
                             branch.update_child(child_index, Some(Child::Node(child)));
@@ -769,8 +1558,9 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
                             return Ok((Some(leaf), removed_value));
                         };
 
-                        if children_iter.next().is_some() {
-                            // The branch has more than 1 child. Return the branch.
+                        if children_iter.next().is_some() || branch.value.is_some() {
+                            // The branch has more than 1 child, or a value of its own.
+                            // Return the branch.
                             return Ok((Some(node), removed_value));
                         }
 
@@ -788,26 +1578,319 @@ impl<S: ReadableStorage> Merkle<NodeStore<MutableProposal, S>> {
                             }
                         };
 
-                        // The child's partial path is the concatenation of its (now removed) parent,
-                        // its (former) child index, and its partial path.
-                        let branch_partial_path =
-                            std::mem::replace(&mut branch.partial_path, Path::new());
+                        // The child's partial path is the concatenation of its (now removed) parent,
+                        // its (former) child index, and its partial path.
+                        let branch_partial_path =
+                            std::mem::replace(&mut branch.partial_path, Path::new());
+
+                        let child_partial_path = Path::from_nibbles_iterator(
+                            branch_partial_path
+                                .iter()
+                                .chain(once(&(child_index as u8)))
+                                .chain(child.partial_path().iter())
+                                .copied(),
+                        );
+                        child.update_partial_path(child_partial_path);
+
+                        Ok((Some(child), removed_value))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Removes every key that starts with `prefix`, except the keys that
+    /// start with `keep`, by unlinking the subtries they are in rather than
+    /// removing their keys one at a time. Every node of an unlinked subtrie
+    /// that is on disk is deleted in this proposal, and the resulting trie
+    /// is the one removing each of the keys would give.
+    pub fn remove_prefix(&mut self, prefix: &[u8], keep: Option<&[u8]>) -> Result<(), MerkleError> {
+        let prefix = Path::from_nibbles_iterator(NibblesIterator::new(prefix));
+        let keep = keep.map(|keep| Path::from_nibbles_iterator(NibblesIterator::new(keep)));
+        if keep.as_ref().is_some_and(|keep| prefix.starts_with(keep)) {
+            // every key under the prefix is kept
+            return Ok(());
+        }
+        // keys under `keep` are only removed if they are under the prefix
+        let keep = keep.as_ref().filter(|keep| keep.starts_with(&prefix));
+
+        counter!("firewood.remove_prefix").increment(1);
+        let Some(root_node) = std::mem::take(self.nodestore.mut_root()) else {
+            return Ok(());
+        };
+        let (root_node, _) =
+            self.remove_prefix_helper(root_node, &prefix, keep.map(|keep| &keep[..]))?;
+        *self.nodestore.mut_root() = root_node;
+        Ok(())
+    }
+
+    /// Removes every key under `prefix` from the subtrie rooted at `node`,
+    /// except those under `keep`, which starts with `prefix` if it is set.
+    /// Both are in nibbles, relative to `node`. Returns the new root of the
+    /// subtrie, and whether any key was removed.
+    fn remove_prefix_helper(
+        &mut self,
+        node: Node,
+        prefix: &[u8],
+        keep: Option<&[u8]>,
+    ) -> Result<(Option<Node>, bool), MerkleError> {
+        let path_overlap = PrefixOverlap::from(prefix, node.partial_path().as_ref());
+        let depth = path_overlap.shared.len();
+
+        match (
+            path_overlap.unique_a.split_first(),
+            path_overlap.unique_b.is_empty(),
+        ) {
+            (None, _) => {
+                // Every key in the subtrie is under the prefix
+                self.retain_subtrie(node, keep)
+            }
+            (Some(_), false) => {
+                // The prefix and the node diverge, so no key is under it
+                Ok((Some(node), false))
+            }
+            (Some((child_index, child_prefix)), true) => {
+                // The prefix is below the node
+                let Node::Branch(mut branch) = node else {
+                    return Ok((Some(node), false));
+                };
+                let child_keep = keep.and_then(|keep| keep.get(depth + 1..));
+                let changed =
+                    self.update_branch_child(&mut branch, *child_index, |merkle, child| {
+                        merkle.remove_prefix_helper(child, child_prefix, child_keep)
+                    })?;
+                if !changed {
+                    return Ok((Some(Node::Branch(branch)), false));
+                }
+                Ok((self.collapse_branch(branch)?, true))
+            }
+        }
+    }
+
+    /// Removes every key in the subtrie rooted at `node` that isn't under
+    /// `keep`, in nibbles relative to `node`. Returns the new root of the
+    /// subtrie, and whether any key was removed.
+    fn retain_subtrie(
+        &mut self,
+        node: Node,
+        keep: Option<&[u8]>,
+    ) -> Result<(Option<Node>, bool), MerkleError> {
+        let Some(keep) = keep else {
+            self.delete_subtrie(&node)?;
+            return Ok((None, true));
+        };
+        let path_overlap = PrefixOverlap::from(keep, node.partial_path().as_ref());
+
+        match (
+            path_overlap.unique_a.split_first(),
+            path_overlap.unique_b.is_empty(),
+        ) {
+            (None, _) => {
+                // Every key in the subtrie is kept
+                Ok((Some(node), false))
+            }
+            (Some(_), false) => {
+                // No key in the subtrie is kept
+                self.delete_subtrie(&node)?;
+                Ok((None, true))
+            }
+            (Some((keep_index, child_keep)), true) => {
+                // Only keys below the node are kept
+                let Node::Branch(mut branch) = node else {
+                    return Ok((None, true));
+                };
+                let mut changed = branch.value.take().is_some();
+                for (index, child) in branch.children.iter_mut().enumerate() {
+                    if index == *keep_index as usize {
+                        continue;
+                    }
+                    if let Some(child) = child.take() {
+                        self.delete_child(&child)?;
+                        changed = true;
+                    }
+                }
+                changed |=
+                    self.update_branch_child(&mut branch, *keep_index, |merkle, child| {
+                        merkle.retain_subtrie(child, Some(child_keep))
+                    })?;
+                if !changed {
+                    return Ok((Some(Node::Branch(branch)), false));
+                }
+                Ok((self.collapse_branch(branch)?, true))
+            }
+        }
+    }
+
+    /// Replaces the child of `branch` at `child_index` with the one `update`
+    /// returns, if it says the child changed. A child that is on disk is
+    /// only deleted in this proposal if it changed. Returns whether it did.
+    fn update_branch_child(
+        &mut self,
+        branch: &mut BranchNode,
+        child_index: u8,
+        update: impl FnOnce(&mut Self, Node) -> Result<(Option<Node>, bool), MerkleError>,
+    ) -> Result<bool, MerkleError> {
+        let Some(child) = branch
+            .children
+            .get_mut(child_index as usize)
+            .and_then(Option::take)
+        else {
+            return Ok(false);
+        };
+        let (updated, changed) = match child {
+            Child::Node(child) => update(self, child)?,
+            Child::AddressWithHash(addr, hash) => {
+                let node = (*self.read_node(addr)?).clone();
+                let (updated, changed) = update(self, node)?;
+                if !changed {
+                    branch.update_child(child_index, Some(Child::AddressWithHash(addr, hash)));
+                    return Ok(false);
+                }
+                self.nodestore.delete_node(addr);
+                (updated, changed)
+            }
+        };
+        branch.update_child(child_index, updated.map(Child::Node));
+        Ok(changed)
+    }
+
+    /// Deletes the nodes below `node` that are on disk. `node` itself has
+    /// already been taken out of the trie.
+    fn delete_subtrie(&mut self, node: &Node) -> Result<(), MerkleError> {
+        if let Node::Branch(branch) = node {
+            for child in branch.children.iter().flatten() {
+                self.delete_child(child)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Deletes `child` and every node below it that is on disk
+    fn delete_child(&mut self, child: &Child) -> Result<(), MerkleError> {
+        match child {
+            Child::Node(node) => self.delete_subtrie(node),
+            Child::AddressWithHash(addr, _) => {
+                self.nodestore.delete_node(*addr);
+                let node = self.read_node(*addr)?;
+                self.delete_subtrie(&node)
+            }
+        }
+    }
+
+    /// Returns what `branch` becomes once some of its children or its value
+    /// have been removed: nothing if it has neither left, a leaf if it only
+    /// has its value, or its only child if it has no value.
+    fn collapse_branch(
+        &mut self,
+        mut branch: Box<BranchNode>,
+    ) -> Result<Option<Node>, MerkleError> {
+        let mut children_iter = branch
+            .children
+            .iter_mut()
+            .enumerate()
+            .filter_map(|(index, child)| child.as_mut().map(|child| (index, child)));
+
+        let Some((child_index, child)) = children_iter.next() else {
+            return Ok(branch.value.take().map(|value| {
+                Node::Leaf(LeafNode {
+                    value: SmallVec::from(&value[..]),
+                    partial_path: std::mem::replace(&mut branch.partial_path, Path::new()),
+                })
+            }));
+        };
+
+        if children_iter.next().is_some() || branch.value.is_some() {
+            return Ok(Some(Node::Branch(branch)));
+        }
+
+        let mut child = match child {
+            Child::Node(child_node) => std::mem::replace(
+                child_node,
+                Node::Leaf(LeafNode {
+                    value: SmallVec::default(),
+                    partial_path: Path::new(),
+                }),
+            ),
+            Child::AddressWithHash(addr, _) => self.nodestore.read_for_update(*addr)?,
+        };
+
+        // The child's partial path is the concatenation of its (now removed) parent,
+        // its (former) child index, and its partial path.
+        let child_partial_path = Path::from_nibbles_iterator(
+            branch
+                .partial_path
+                .iter()
+                .chain(once(&(child_index as u8)))
+                .chain(child.partial_path().iter())
+                .copied(),
+        );
+        child.update_partial_path(child_partial_path);
+        Ok(Some(child))
+    }
+}
 
-                        let child_partial_path = Path::from_nibbles_iterator(
-                            branch_partial_path
-                                .iter()
-                                .chain(once(&(child_index as u8)))
-                                .chain(child.partial_path().iter())
-                                .copied(),
-                        );
-                        child.update_partial_path(child_partial_path);
+/// The nibbles of a key and its value, as passed to [Merkle::insert_sorted]
+type SortedEntry = (Path, Box<[u8]>);
+
+/// Build a new subtrie holding the non-empty, sorted `entries`, whose first
+/// `depth` nibbles lead to the subtrie
+fn build_sorted(entries: &[SortedEntry], depth: usize) -> Node {
+    let (first, last) = match (entries.first(), entries.last()) {
+        (Some(first), Some(last)) if entries.len() > 1 => (first, last),
+        _ => {
+            let (key, value) = entries.first().expect("entries can't be empty");
+            return Node::Leaf(LeafNode {
+                partial_path: Path::from(key.get(depth..).unwrap_or_default()),
+                value: SmallVec::from(&value[..]),
+            });
+        }
+    };
 
-                        Ok((Some(child), removed_value))
-                    }
-                }
-            }
+    let first_key = first.0.get(depth..).unwrap_or_default();
+    let shared = common_prefix_len(first_key, last.0.get(depth..).unwrap_or_default());
+    let mut branch = Box::new(BranchNode {
+        partial_path: Path::from(first_key.get(..shared).unwrap_or_default()),
+        value: None,
+        children: [const { None }; BranchNode::MAX_CHILDREN],
+    });
+
+    let depth = depth + shared;
+    let entries = match entries.split_first() {
+        Some(((key, value), rest)) if key.len() == depth => {
+            branch.value = Some(value.clone());
+            rest
         }
+        _ => entries,
+    };
+    for (child_index, group) in group_by_nibble(entries, depth) {
+        let child = build_sorted(group, depth + 1);
+        branch.update_child(child_index, Some(Child::Node(child)));
     }
+    Node::Branch(branch)
+}
+
+/// Split sorted `entries`, each longer than `depth` nibbles, into runs that
+/// share the nibble at `depth`
+fn group_by_nibble(
+    entries: &[SortedEntry],
+    depth: usize,
+) -> impl Iterator<Item = (u8, &[SortedEntry])> {
+    let nibble = move |(key, _): &SortedEntry| key.get(depth).copied().unwrap_or_default();
+    let mut rest = entries;
+    std::iter::from_fn(move || {
+        let child_index = nibble(rest.first()?);
+        let len = rest
+            .iter()
+            .position(|entry| nibble(entry) != child_index)
+            .unwrap_or(rest.len());
+        let (group, tail) = rest.split_at(len);
+        rest = tail;
+        Some((child_index, group))
+    })
+}
+
+fn common_prefix_len(a: &[u8], b: &[u8]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
 }
 
 /// Returns an iterator where each element is the result of combining
@@ -823,15 +1906,16 @@ pub fn nibbles_to_bytes_iter(nibbles: &[u8]) -> impl Iterator<Item = u8> + '_ {
 /// As the type-name implies, the `shared` property only constitues a shared *prefix*.
 /// The `unique_*` properties, [`unique_a`][`PrefixOverlap::unique_a`] and [`unique_b`][`PrefixOverlap::unique_b`]
 /// are set based on the argument order passed into the [`from`][`PrefixOverlap::from`] constructor.
+/// Only `unique_b` borrows from `b`, so the owner of `b` can be changed while the rest is in use.
 #[derive(Debug)]
-struct PrefixOverlap<'a, T> {
+struct PrefixOverlap<'a, 'b, T> {
     shared: &'a [T],
     unique_a: &'a [T],
-    unique_b: &'a [T],
+    unique_b: &'b [T],
 }
 
-impl<'a, T: PartialEq> PrefixOverlap<'a, T> {
-    fn from(a: &'a [T], b: &'a [T]) -> Self {
+impl<'a, 'b, T: PartialEq> PrefixOverlap<'a, 'b, T> {
+    fn from(a: &'a [T], b: &'b [T]) -> Self {
         let split_index = a
             .iter()
             .zip(b)
@@ -853,9 +1937,10 @@ impl<'a, T: PartialEq> PrefixOverlap<'a, T> {
 #[allow(clippy::indexing_slicing, clippy::unwrap_used)]
 mod tests {
     use super::*;
+    use futures::TryStreamExt;
     use rand::rngs::StdRng;
     use rand::{thread_rng, Rng, SeedableRng};
-    use storage::{MemStore, MutableProposal, NodeStore, RootReader};
+    use storage::{MemStore, MutableProposal, NodeStore, RootReader, ValueDigest};
     use test_case::test_case;
 
     // Returns n random key-value pairs.
@@ -944,6 +2029,109 @@ mod tests {
         }
     }
 
+    #[test]
+    fn get_values_matches_get_value() {
+        // short keys over a small alphabet, so many are prefixes of others
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut random_key = |max_len| -> Vec<u8> {
+            let len = rng.gen_range(0..=max_len);
+            (0..len).map(|_| rng.gen_range(0..4) * 0x11).collect()
+        };
+        let mut merkle = create_in_memory_merkle();
+        assert_eq!(merkle.get_values(&[b"a"]).unwrap(), vec![None]);
+        for _ in 0..2000 {
+            let key = random_key(6);
+            merkle.insert(&key, key.clone().into()).unwrap();
+        }
+        // some keys are looked up more than once, and some aren't in the trie
+        let mut keys: Vec<_> = (0..500).map(|_| random_key(7)).collect();
+        keys.extend(keys.clone().into_iter().take(50));
+
+        let expected: Vec<_> = keys
+            .iter()
+            .map(|key| merkle.get_value(key).unwrap())
+            .collect();
+        assert!(expected.iter().any(Option::is_none));
+        assert_eq!(merkle.get_values(&keys).unwrap(), expected);
+        let merkle = merkle.hash();
+        assert_eq!(merkle.get_values(&keys).unwrap(), expected);
+    }
+
+    #[test]
+    fn diff_matches_btreemap() {
+        use crate::diff::KeyChange;
+
+        // short keys over a small alphabet, so many are prefixes of others
+        let mut rng = StdRng::seed_from_u64(7);
+        let random_key = |rng: &mut StdRng| -> Vec<u8> {
+            let len = rng.gen_range(0..=4);
+            (0..len).map(|_| rng.gen_range(0..4) * 0x11).collect()
+        };
+        let older: BTreeMap<Vec<u8>, Vec<u8>> = (0..300)
+            .map(|_| {
+                let key = random_key(&mut rng);
+                (key.clone(), key)
+            })
+            .collect();
+        let mut newer = older.clone();
+        for _ in 0..30 {
+            let key = random_key(&mut rng);
+            match rng.gen_range(0..3) {
+                0 => newer.remove(&key),
+                1 => newer.insert(key, vec![rng.gen()]),
+                _ => newer.insert(key.clone(), key),
+            };
+        }
+
+        let expected: Vec<_> = older
+            .keys()
+            .chain(newer.keys())
+            .collect::<std::collections::BTreeSet<_>>()
+            .into_iter()
+            .filter_map(|key| {
+                let key = key.clone().into_boxed_slice();
+                match (older.get(&*key), newer.get(&*key)) {
+                    (Some(old), Some(new)) if old != new => Some(KeyChange::Modified {
+                        key,
+                        old: old.clone().into(),
+                        new: new.clone().into(),
+                    }),
+                    (Some(old), None) => Some(KeyChange::Removed {
+                        key,
+                        old: old.clone().into(),
+                    }),
+                    (None, Some(value)) => Some(KeyChange::Added {
+                        key,
+                        value: value.clone().into(),
+                    }),
+                    _ => None,
+                }
+            })
+            .collect();
+        assert!(!expected.is_empty());
+
+        let older_merkle = merkle_build_test(older.clone().into_iter().collect())
+            .unwrap()
+            .hash();
+        let newer_merkle = merkle_build_test(newer.into_iter().collect())
+            .unwrap()
+            .hash();
+        assert_eq!(older_merkle.diff(&newer_merkle).unwrap(), expected);
+        assert!(older_merkle.diff(&older_merkle).unwrap().is_empty());
+
+        // against an empty trie, every key is added or removed
+        let empty = merkle_build_test::<Vec<u8>, Vec<u8>>(vec![])
+            .unwrap()
+            .hash();
+        let added = empty.diff(&older_merkle).unwrap();
+        assert_eq!(added.len(), older.len());
+        for (change, key) in added.iter().zip(older.keys()) {
+            assert!(matches!(change, KeyChange::Added { .. }));
+            assert_eq!(change.key(), key.as_slice());
+        }
+        assert_eq!(older_merkle.diff(&empty).unwrap().len(), older.len());
+    }
+
     #[test]
     fn remove_root() {
         let key0 = vec![0];
@@ -1003,6 +2191,283 @@ mod tests {
         assert!(merkle.nodestore.root_node().is_none());
     }
 
+    #[test]
+    fn remove_missing_key_below_branch_with_value() {
+        let mut merkle = create_in_memory_merkle();
+        merkle.insert(&[0x02], Box::from([1])).unwrap();
+        merkle.insert(&[], Box::from([2])).unwrap();
+        // Trie is:
+        //   [] (value 2)
+        //    |
+        //   [0x02]
+
+        // the missing key shares the only child's index but not its path
+        assert!(merkle.remove(&[0x01]).unwrap().is_none());
+        assert_eq!(merkle.get_value(&[]).unwrap(), Some(Box::from([2])));
+        assert_eq!(merkle.get_value(&[0x02]).unwrap(), Some(Box::from([1])));
+
+        // removing the child leaves the branch's value in a leaf
+        assert_eq!(merkle.remove(&[0x02]).unwrap(), Some(Box::from([1])));
+        assert_eq!(merkle.get_value(&[]).unwrap(), Some(Box::from([2])));
+    }
+
+    fn be_keys(range: std::ops::Range<u32>) -> Vec<Vec<u8>> {
+        range.map(|i| i.to_be_bytes().to_vec()).collect()
+    }
+
+    #[test_case(vec![], vec![vec![1], vec![2], vec![0x10, 0]]; "empty trie")]
+    #[test_case(vec![vec![0x10]], vec![vec![0x20], vec![0x21]]; "new top level child")]
+    #[test_case(vec![vec![0x12, 0x34]], vec![vec![0x12, 0x35], vec![0x12, 0x40]]; "split partial path")]
+    #[test_case(vec![vec![0x12]], vec![vec![0x12, 0x00], vec![0x12, 0x01]]; "extend leaf")]
+    #[test_case(vec![vec![0x12], vec![0x12, 0x00]], vec![vec![0x12, 0x01]]; "below branch value")]
+    #[test_case(vec![vec![0x50]], vec![vec![0x10], vec![0x50], vec![0x60]]; "not an append")]
+    #[test_case(be_keys(0..1), be_keys(1..2); "single entry")]
+    #[test_case(be_keys(0..100), be_keys(100..1000); "many entries")]
+    #[test_case(be_keys(0..255), be_keys(255..257); "carry into next byte")]
+    #[test_case(be_keys(0..4096), be_keys(4096..70000); "crosses branch boundaries")]
+    fn insert_sorted_matches_insert(existing: Vec<Vec<u8>>, batch: Vec<Vec<u8>>) {
+        let mut expected = create_in_memory_merkle();
+        let mut merkle = create_in_memory_merkle();
+        for key in &existing {
+            expected.insert(key, Box::from(&key[..])).unwrap();
+            merkle.insert(key, Box::from(&key[..])).unwrap();
+        }
+
+        let entries: Vec<_> = batch
+            .iter()
+            .map(|key| {
+                let value = key.iter().copied().chain([0xff]).collect::<Box<[u8]>>();
+                expected.insert(key, value.clone()).unwrap();
+                (
+                    Path::from_nibbles_iterator(NibblesIterator::new(key)),
+                    value,
+                )
+            })
+            .collect();
+        merkle.insert_sorted(&entries).unwrap();
+
+        for (key, (_, value)) in batch.iter().zip(&entries).step_by(4999) {
+            assert_eq!(merkle.get_value(key).unwrap().as_ref(), Some(value));
+        }
+        assert_eq!(
+            merkle.hash().nodestore().root_hash().unwrap(),
+            expected.hash().nodestore().root_hash().unwrap()
+        );
+    }
+
+    #[test]
+    fn max_key() {
+        let mut merkle = create_in_memory_merkle();
+        assert_eq!(merkle.max_key_nibbles().unwrap(), None);
+
+        for key in [&[0x12, 0x34][..], &[0x12], &[0x12, 0x34, 0x56], &[0x05]] {
+            merkle.insert(key, Box::from([1])).unwrap();
+        }
+        assert_eq!(
+            merkle.max_key_nibbles().unwrap(),
+            Some(Path::from([1, 2, 3, 4, 5, 6]))
+        );
+    }
+
+    fn branch(partial_path: &[u8], value: Option<&[u8]>, children: Vec<(u8, Node)>) -> Node {
+        let mut branch = BranchNode {
+            partial_path: Path::from(partial_path),
+            value: value.map(Box::from),
+            children: [const { None }; BranchNode::MAX_CHILDREN],
+        };
+        for (index, child) in children {
+            branch.update_child(index, Some(Child::Node(child)));
+        }
+        Node::Branch(Box::new(branch))
+    }
+
+    fn leaf(partial_path: &[u8], value: &[u8]) -> Node {
+        Node::Leaf(LeafNode {
+            partial_path: Path::from(partial_path),
+            value: SmallVec::from(value),
+        })
+    }
+
+    /// A committed trie where the path to two of the keys goes through a
+    /// chain of two branches with no value and a single child, which
+    /// inserts never create
+    fn committed_chain() -> Arc<NodeStore<storage::Committed, MemStore>> {
+        let inner = branch(
+            &[4],
+            None,
+            vec![(0, leaf(&[5], &[1])), (1, leaf(&[6], &[2]))],
+        );
+        let mid = branch(&[], None, vec![(3, inner)]);
+        let head = branch(&[], None, vec![(2, mid)]);
+        let root = branch(&[], Some(&[9]), vec![(1, head), (7, leaf(&[7], &[3]))]);
+
+        let storage = Arc::new(MemStore::new(vec![]));
+        let empty = NodeStore::new_empty_committed(storage.clone()).unwrap();
+        empty.flush_header_with_padding().unwrap();
+        let mut proposal = NodeStore::new(Arc::new(empty)).unwrap();
+        *proposal.mut_root() = Some(root);
+        let proposal = Merkle::from(proposal).hash().into_inner();
+        proposal.flush_freelist().unwrap();
+        proposal.flush_nodes().unwrap();
+        proposal.flush_header().unwrap();
+        Arc::new(NodeStore::open(storage).unwrap())
+    }
+
+    const CHAIN_KEYS: [&[u8]; 4] = [&[], &[0x12, 0x34, 0x05], &[0x12, 0x34, 0x16], &[0x77]];
+
+    fn path_len<T: TrieReader>(merkle: &Merkle<T>, key: &[u8]) -> usize {
+        merkle.path_iter(key).unwrap().count()
+    }
+
+    async fn contents<T: TrieReader>(merkle: &Merkle<T>) -> Vec<(Key, Vec<u8>)> {
+        merkle.key_value_iter().try_collect().await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn heal_paths_folds_chains() {
+        let committed = committed_chain();
+        let before = Merkle::from(committed.clone());
+        assert_eq!(path_len(&before, CHAIN_KEYS[1]), 5);
+
+        let mut merkle = Merkle::from(NodeStore::new(committed.clone()).unwrap());
+        let mut visited = 0;
+        let stats = merkle
+            .heal_paths(usize::MAX, |nodes| {
+                visited += nodes;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(
+            stats,
+            HealStats {
+                nodes_folded: 2,
+                levels_removed: 2
+            }
+        );
+        assert!(visited > 0);
+
+        let after = merkle.hash();
+        assert_eq!(path_len(&after, CHAIN_KEYS[1]), 3);
+        assert_eq!(path_len(&after, CHAIN_KEYS[2]), 3);
+        assert_eq!(path_len(&after, CHAIN_KEYS[3]), 2);
+        assert_eq!(contents(&after).await, contents(&before).await);
+        assert_ne!(
+            after.nodestore().root_hash().unwrap(),
+            committed.root_hash().unwrap()
+        );
+
+        // the healed trie reads back the same once it is committed
+        let after = after.into_inner();
+        after.flush_freelist().unwrap();
+        after.flush_nodes().unwrap();
+        after.flush_header().unwrap();
+        let reopened = Arc::new(NodeStore::open(committed.storage.clone()).unwrap());
+        assert_eq!(
+            contents(&Merkle::from(reopened.clone())).await,
+            contents(&before).await
+        );
+
+        // nothing is left to fold
+        let mut again = Merkle::from(NodeStore::new(reopened).unwrap());
+        assert_eq!(
+            again.heal_paths(usize::MAX, |_| Ok(())).unwrap(),
+            HealStats::default()
+        );
+    }
+
+    #[tokio::test]
+    async fn heal_paths_budget() {
+        let committed = committed_chain();
+        let mut merkle = Merkle::from(NodeStore::new(committed.clone()).unwrap());
+        let stats = merkle.heal_paths(1, |_| Ok(())).unwrap();
+        assert_eq!(stats.nodes_folded, 1);
+
+        let after = merkle.hash();
+        assert_eq!(path_len(&after, CHAIN_KEYS[1]), 4);
+        assert_eq!(
+            contents(&after).await,
+            contents(&Merkle::from(committed)).await
+        );
+    }
+
+    #[test]
+    fn heal_paths_stops_on_error() {
+        let committed = committed_chain();
+        let mut merkle = Merkle::from(NodeStore::new(committed.clone()).unwrap());
+        let err = merkle
+            .heal_paths(usize::MAX, |_| Err(api::Error::NotLatest))
+            .unwrap_err();
+        assert!(matches!(err, api::Error::NotLatest));
+        assert_eq!(
+            merkle.hash().nodestore().root_hash().unwrap(),
+            committed.root_hash().unwrap()
+        );
+    }
+
+    /// A committed trie holding `keys`, each with itself as its value
+    fn committed(keys: &[&[u8]]) -> Arc<NodeStore<storage::Committed, MemStore>> {
+        let storage = Arc::new(MemStore::new(vec![]));
+        let empty = NodeStore::new_empty_committed(storage.clone()).unwrap();
+        empty.flush_header_with_padding().unwrap();
+        let mut merkle = Merkle::from(NodeStore::new(Arc::new(empty)).unwrap());
+        for key in keys {
+            merkle.insert(key, Box::from(*key)).unwrap();
+        }
+        let proposal = merkle.hash().into_inner();
+        proposal.flush_freelist().unwrap();
+        proposal.flush_nodes().unwrap();
+        proposal.flush_header().unwrap();
+        Arc::new(NodeStore::open(storage).unwrap())
+    }
+
+    const PREFIX_KEYS: [&[u8]; 12] = [
+        b"",
+        b"a",
+        b"ab",
+        b"abc",
+        b"abd",
+        b"abcde",
+        b"b",
+        b"ba",
+        b"xyz1",
+        b"xyz2",
+        b"\xff\xfe",
+        b"\xff\xfe1",
+    ];
+
+    #[test_case(b"ab", None; "exact key")]
+    #[test_case(b"abcde", None; "exact leaf key")]
+    #[test_case(b"xy", None; "splits a partial path")]
+    #[test_case(b"", None; "empty prefix")]
+    #[test_case(b"q", None; "no keys")]
+    #[test_case(b"abcdef", None; "below a leaf")]
+    #[test_case(b"", Some(b"\xff\xfe"); "empty prefix keeping a subtrie")]
+    #[test_case(b"\xff", Some(b"\xff\xfe"); "keeping a subtrie")]
+    #[test_case(b"a", Some(b"abx"); "keeping nothing")]
+    #[test_case(b"abc", Some(b"a"); "keeping everything")]
+    fn remove_prefix_matches_remove(prefix: &[u8], keep: Option<&[u8]>) {
+        let committed = committed(&PREFIX_KEYS);
+        let removed = |key: &&[u8]| {
+            key.starts_with(prefix) && !keep.is_some_and(|keep| key.starts_with(keep))
+        };
+
+        let mut expected = Merkle::from(NodeStore::new(committed.clone()).unwrap());
+        for key in PREFIX_KEYS.iter().filter(|key| removed(key)) {
+            expected.remove(key).unwrap().unwrap();
+        }
+        let mut merkle = Merkle::from(NodeStore::new(committed).unwrap());
+        merkle.remove_prefix(prefix, keep).unwrap();
+
+        for key in PREFIX_KEYS {
+            let value = merkle.get_value(key).unwrap();
+            assert_eq!(value.is_none(), removed(&key), "{key:?}");
+        }
+        assert_eq!(
+            merkle.hash().nodestore().root_hash().unwrap(),
+            expected.hash().nodestore().root_hash().unwrap()
+        );
+    }
+
     #[test]
     fn remove_many() {
         let mut merkle = create_in_memory_merkle();
@@ -1088,6 +2553,282 @@ mod tests {
         }
     }
 
+    fn hashed_merkle(
+        items: Vec<(Vec<u8>, Vec<u8>)>,
+    ) -> (
+        Merkle<NodeStore<Arc<ImmutableProposal>, MemStore>>,
+        TrieHash,
+    ) {
+        let merkle = merkle_build_test(items).unwrap().hash();
+        let root_hash = merkle.nodestore.root_hash().unwrap().unwrap();
+        (merkle, root_hash)
+    }
+
+    /// Copies of `proof` with one byte of one of its nodes flipped, for
+    /// each node and each of its key, value and child hashes
+    fn tampered(proof: &Proof<ProofNode>) -> Vec<Proof<ProofNode>> {
+        let mut copies = Vec::new();
+        for index in 0..proof.0.len() {
+            let mut tamper = |flip: fn(&mut ProofNode) -> bool| {
+                let mut copy = proof.clone();
+                if copy.0.get_mut(index).is_some_and(flip) {
+                    copies.push(copy);
+                }
+            };
+            tamper(|node| node.key.first_mut().map(|nibble| *nibble ^= 1).is_some());
+            tamper(|node| match &mut node.value_digest {
+                Some(ValueDigest::Value(value)) => value.first_mut().map(|b| *b ^= 1).is_some(),
+                _ => false,
+            });
+            tamper(|node| {
+                let hash = node.child_hashes.iter_mut().flatten().next();
+                hash.and_then(|hash| hash.first_mut().map(|b| *b ^= 1))
+                    .is_some()
+            });
+        }
+        copies
+    }
+
+    #[test]
+    fn get_with_proof() {
+        let (merkle, root_hash) = hashed_merkle(vec![
+            (vec![0x12], b"branch".to_vec()),
+            (vec![0x12, 0x34], b"a".to_vec()),
+            (vec![0x12, 0x56], b"b".to_vec()),
+            (vec![0x78], b"c".to_vec()),
+        ]);
+
+        for key in [&[0x12][..], &[0x12, 0x34], &[0x12, 0x56], &[0x78]] {
+            let (value, proof) = merkle.get_with_proof(key).unwrap();
+            assert_eq!(value, merkle.get_value(key).unwrap());
+            let value = value.unwrap();
+            proof.verify(key, Some(&value), &root_hash).unwrap();
+            let proved = merkle.prove(key).unwrap();
+            assert_eq!(format!("{proof:?}"), format!("{proved:?}"));
+        }
+        // a missing key comes with the proof that it is missing
+        for key in [
+            &[][..],
+            &[0x12, 0x30],
+            &[0x12, 0x34, 0x00],
+            &[0x13],
+            &[0x78, 0x9a],
+        ] {
+            let (value, proof) = merkle.get_with_proof(key).unwrap();
+            assert_eq!(value, None);
+            proof.verify(key, None::<&[u8]>, &root_hash).unwrap();
+        }
+    }
+
+    #[test]
+    fn proof_of_value_at_branch() {
+        // 0x12 is a branch with a value and children at nibbles 3 and 5
+        let (merkle, root_hash) = hashed_merkle(vec![
+            (vec![0x12], b"branch".to_vec()),
+            (vec![0x12, 0x34], b"a".to_vec()),
+            (vec![0x12, 0x56], b"b".to_vec()),
+        ]);
+
+        let proof = merkle.prove(&[0x12]).unwrap();
+        proof.verify([0x12], Some(b"branch"), &root_hash).unwrap();
+        assert!(matches!(
+            proof.verify([0x12], Some(b"other"), &root_hash),
+            Err(ProofError::ValueMismatch)
+        ));
+        assert!(matches!(
+            proof.verify([0x12], None::<&[u8]>, &root_hash),
+            Err(ProofError::UnexpectedValue)
+        ));
+        for tampered in tampered(&proof) {
+            assert!(tampered
+                .verify([0x12], Some(b"branch"), &root_hash)
+                .is_err());
+        }
+
+        // the branch has no child at nibble 7
+        let proof = merkle.prove(&[0x12, 0x78]).unwrap();
+        proof
+            .verify([0x12, 0x78], None::<&[u8]>, &root_hash)
+            .unwrap();
+        assert!(matches!(
+            proof.verify([0x12, 0x78], Some(b"a"), &root_hash),
+            Err(ProofError::ExpectedValue)
+        ));
+    }
+
+    #[test]
+    fn exclusion_proof_shows_divergence() {
+        let (merkle, root_hash) = hashed_merkle(vec![
+            (vec![0x12], b"branch".to_vec()),
+            (vec![0x12, 0x34], b"a".to_vec()),
+            (vec![0x12, 0x56], b"b".to_vec()),
+        ]);
+
+        // the path to 0x1235 diverges at the leaf 0x1234, so the proof ends
+        // with it
+        let proof = merkle.prove(&[0x12, 0x35]).unwrap();
+        assert_eq!(proof.0.len(), 2);
+        proof
+            .verify([0x12, 0x35], None::<&[u8]>, &root_hash)
+            .unwrap();
+        for tampered in tampered(&proof) {
+            assert!(tampered
+                .verify([0x12, 0x35], None::<&[u8]>, &root_hash)
+                .is_err());
+        }
+
+        // without the leaf, the proof doesn't show where the path diverges
+        let truncated = Proof(proof.0.iter().take(1).cloned().collect());
+        assert!(matches!(
+            truncated.verify([0x12, 0x35], None::<&[u8]>, &root_hash),
+            Err(ProofError::IncompleteExclusion)
+        ));
+
+        // a proof for a sibling doesn't prove anything about 0x1234
+        let sibling = merkle.prove(&[0x12, 0x56]).unwrap();
+        assert!(matches!(
+            sibling.verify([0x12, 0x34], None::<&[u8]>, &root_hash),
+            Err(ProofError::NotOnPathToProvenKey)
+        ));
+    }
+
+    #[test]
+    fn exclusion_proof_diverging_in_root_path() {
+        let (merkle, root_hash) = hashed_merkle(vec![
+            (vec![0x12, 0x34], b"a".to_vec()),
+            (vec![0x12, 0x56], b"b".to_vec()),
+        ]);
+
+        // the root's partial path is 0x12, so these keys diverge from it
+        // before reaching any child, and the root alone proves their absence
+        for key in [[0x13], [0x02]] {
+            let proof = merkle.prove(&key).unwrap();
+            assert_eq!(proof.0.len(), 1);
+            proof.verify(key, None::<&[u8]>, &root_hash).unwrap();
+            assert!(proof.verify(key, Some(b"a"), &root_hash).is_err());
+            for tampered in tampered(&proof) {
+                assert!(tampered.verify(key, None::<&[u8]>, &root_hash).is_err());
+            }
+        }
+    }
+
+    #[test]
+    fn proofs_survive_serialization() {
+        let (merkle, root_hash) = hashed_merkle(vec![
+            (vec![0x12], b"branch".to_vec()),
+            (vec![0x12, 0x34], b"a".to_vec()),
+            (vec![0x12, 0x56], b"b".to_vec()),
+        ]);
+
+        for (key, value) in [([0x12, 0x34], Some(b"a")), ([0x12, 0x35], None)] {
+            let proof = merkle.prove(&key).unwrap();
+            let bytes = bincode::serialize(&proof).unwrap();
+            let decoded: Proof<ProofNode> = bincode::deserialize(&bytes).unwrap();
+            decoded.verify(key, value, &root_hash).unwrap();
+            assert_eq!(decoded.0.len(), proof.0.len());
+        }
+
+        // a child index past the last child doesn't deserialize
+        let node = (
+            Box::<[u8]>::from([1u8]),
+            None::<()>,
+            vec![(99u8, root_hash)],
+        );
+        let bytes = bincode::serialize(&node).unwrap();
+        assert!(bincode::deserialize::<ProofNode>(&bytes).is_err());
+    }
+
+    #[test]
+    fn verify_proof_without_trie() {
+        use crate::proof::verify_proof;
+
+        let (merkle, root_hash) = hashed_merkle(vec![
+            (vec![0x12], b"branch".to_vec()),
+            (vec![0x12, 0x34], b"a".to_vec()),
+            (vec![0x12, 0x56], b"b".to_vec()),
+        ]);
+
+        for (key, value) in [
+            (&[0x12, 0x34][..], Some(&b"a"[..])),
+            (&[0x12][..], Some(&b"branch"[..])),
+            (&[0x12, 0x35][..], None),
+            (&[0x13][..], None),
+        ] {
+            let proof = merkle.prove(key).unwrap();
+            let proven = verify_proof(&root_hash, key, &proof).unwrap();
+            assert_eq!(proven.as_deref(), value, "{key:?}");
+            for tampered in tampered(&proof) {
+                assert!(verify_proof(&root_hash, key, &tampered).is_err());
+            }
+            // the proof is only of this root, and of this key
+            let other_root = TrieHash::from([0xab; 32]);
+            assert!(verify_proof(&other_root, key, &proof).is_err());
+        }
+
+        // flipping a byte of the value in the middle of the path breaks
+        // the hash the root has for it
+        let mut proof = merkle.prove(&[0x12, 0x34]).unwrap();
+        assert_eq!(proof.0.len(), 2);
+        let intermediate = proof.0.first_mut().unwrap();
+        let Some(ValueDigest::Value(value)) = &mut intermediate.value_digest else {
+            panic!("the branch at 0x12 has a value");
+        };
+        *value.first_mut().unwrap() ^= 1;
+        assert!(matches!(
+            verify_proof(&root_hash, &[0x12, 0x34], &proof),
+            Err(ProofError::UnexpectedHash)
+        ));
+
+        // a proof of one key doesn't prove another under the same branch
+        let proof = merkle.prove(&[0x12, 0x56]).unwrap();
+        assert!(matches!(
+            verify_proof(&root_hash, &[0x12, 0x34], &proof),
+            Err(ProofError::NotOnPathToProvenKey)
+        ));
+    }
+
+    #[test]
+    fn proofs_under_deep_partial_paths() {
+        let deep =
+            |suffix: &[u8]| -> Vec<u8> { [0xab; 20].iter().chain(suffix).copied().collect() };
+        let kvs = vec![
+            (deep(&[0x01]), b"one".to_vec()),
+            (deep(&[0x01, 0xcd, 0xef, 0x01]), b"deep".to_vec()),
+            (deep(&[0x02]), b"two".to_vec()),
+        ];
+        let (merkle, root_hash) = hashed_merkle(kvs.clone());
+
+        for (key, value) in &kvs {
+            let proof = merkle.prove(key).unwrap();
+            proof.verify(key, Some(value), &root_hash).unwrap();
+            for tampered in tampered(&proof) {
+                assert!(tampered.verify(key, Some(value), &root_hash).is_err());
+            }
+        }
+
+        let absent = [
+            // ends inside the root's partial path
+            vec![0xab; 10],
+            // diverges inside the root's partial path
+            vec![0xac; 21],
+            // diverges inside the partial path of the deepest leaf
+            deep(&[0x01, 0xcd, 0xe0]),
+            // extends the deepest leaf
+            deep(&[0x01, 0xcd, 0xef, 0x01, 0x00]),
+        ];
+        for key in absent {
+            let proof = merkle.prove(&key).unwrap();
+            proof.verify(&key, None::<&[u8]>, &root_hash).unwrap();
+            assert!(matches!(
+                proof.verify(&key, Some(b"one"), &root_hash),
+                Err(ProofError::ExpectedValue)
+            ));
+            for tampered in tampered(&proof) {
+                assert!(tampered.verify(&key, None::<&[u8]>, &root_hash).is_err());
+            }
+        }
+    }
+
     #[tokio::test]
     async fn empty_range_proof() {
         let merkle = create_in_memory_merkle();
@@ -1098,6 +2839,203 @@ mod tests {
         ));
     }
 
+    /// Two byte keys, and one byte keys that are at branches over them
+    fn range_proof_kvs() -> Vec<(Vec<u8>, Vec<u8>)> {
+        let mut kvs: Vec<_> = (0u16..200)
+            .map(|i| ((i * 3).to_be_bytes().to_vec(), i.to_le_bytes().to_vec()))
+            .chain((0u8..3).map(|i| (vec![i], vec![i; 40])))
+            .collect();
+        kvs.sort();
+        kvs
+    }
+
+    type TrieRangeProof = RangeProof<Box<[u8]>, Box<[u8]>, ProofNode>;
+
+    fn with_key_values(
+        proof: &TrieRangeProof,
+        key_values: Vec<(Key, Box<[u8]>)>,
+    ) -> TrieRangeProof {
+        RangeProof {
+            start_proof: proof.start_proof.clone(),
+            end_proof: proof.end_proof.clone(),
+            key_values: key_values.into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn range_proof_of_whole_trie() {
+        let kvs = range_proof_kvs();
+        let (merkle, root_hash) = hashed_merkle(kvs.clone());
+
+        let proof = merkle.range_proof(None, None, None).await.unwrap();
+        assert!(proof.start_proof.is_none());
+        assert_eq!(proof.key_values.len(), kvs.len());
+        proof.verify(&root_hash, None, None).unwrap();
+
+        // leaving out a key-value pair, or changing a value, is caught
+        let mut missing = proof.key_values.to_vec();
+        missing.remove(kvs.len() / 2);
+        assert!(with_key_values(&proof, missing)
+            .verify(&root_hash, None, None)
+            .is_err());
+        let mut changed = proof.key_values.to_vec();
+        if let Some((_, value)) = changed.first_mut() {
+            *value = Box::from(&b"changed"[..]);
+        }
+        assert!(with_key_values(&proof, changed)
+            .verify(&root_hash, None, None)
+            .is_err());
+        let mut added = proof.key_values.to_vec();
+        added.push((Box::from(&[9u8; 3][..]), Box::from(&b"added"[..])));
+        added.sort();
+        assert!(with_key_values(&proof, added)
+            .verify(&root_hash, None, None)
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn range_proof_with_limit_resumes() {
+        let kvs = range_proof_kvs();
+        let (merkle, root_hash) = hashed_merkle(kvs.clone());
+        let limit = NonZeroUsize::new(17).unwrap();
+
+        let mut start: Option<Vec<u8>> = None;
+        let mut seen = Vec::new();
+        loop {
+            let proof = merkle
+                .range_proof(start.as_deref(), None, Some(limit))
+                .await
+                .unwrap();
+            proof.verify(&root_hash, start.as_deref(), None).unwrap();
+            assert!(proof.key_values.len() <= limit.get());
+            seen.extend(
+                proof
+                    .key_values
+                    .iter()
+                    .map(|(key, value)| (key.to_vec(), value.to_vec())),
+            );
+            // the next range starts at the smallest key after the last one
+            let Some((last, _)) = proof.key_values.last() else {
+                break;
+            };
+            start = Some(last.iter().copied().chain([0]).collect());
+        }
+        assert_eq!(seen, kvs);
+    }
+
+    #[tokio::test]
+    async fn bounded_range_proof() {
+        let kvs = range_proof_kvs();
+        let (merkle, root_hash) = hashed_merkle(kvs.clone());
+
+        // bounds that are keys, and bounds between keys
+        let bounds: [(&[u8], &[u8]); 3] =
+            [(&[0, 30], &[0, 150]), (&[0, 31], &[1, 1]), (&[1], &[2])];
+        for (start, end) in bounds {
+            let proof = merkle
+                .range_proof(Some(start), Some(end), None)
+                .await
+                .unwrap();
+            proof.verify(&root_hash, Some(start), Some(end)).unwrap();
+
+            let expected: Vec<_> = kvs
+                .iter()
+                .filter(|(key, _)| key.as_slice() >= start && key.as_slice() <= end)
+                .collect();
+            assert_eq!(proof.key_values.len(), expected.len());
+
+            // the first key-value pair can't be left out
+            let rest = proof.key_values.iter().skip(1).cloned().collect();
+            assert!(with_key_values(&proof, rest)
+                .verify(&root_hash, Some(start), Some(end))
+                .is_err());
+            // nor can the bounds be widened
+            assert!(proof
+                .verify(&root_hash, Some([0u8].as_slice()), Some(end))
+                .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn range_proof_without_keys() {
+        let (merkle, root_hash) = hashed_merkle(range_proof_kvs());
+
+        // there are no keys between 0x0003 and 0x0006
+        let (start, end) = ([0u8, 4], [0u8, 5]);
+        let proof = merkle
+            .range_proof(Some(start.as_slice()), Some(end.as_slice()), None)
+            .await
+            .unwrap();
+        assert!(proof.key_values.is_empty());
+        assert!(proof.start_proof.is_some());
+        assert!(proof.end_proof.is_some());
+        proof
+            .verify(&root_hash, Some(start.as_slice()), Some(end.as_slice()))
+            .unwrap();
+
+        // it doesn't prove a range holding 0x0003 is empty
+        assert!(proof
+            .verify(&root_hash, Some([0u8, 2].as_slice()), Some(end.as_slice()))
+            .is_err());
+        // and a key-value pair in it isn't in the trie
+        let added = vec![(Box::from(start.as_slice()), Box::from(&b"v"[..]))];
+        assert!(with_key_values(&proof, added)
+            .verify(&root_hash, Some(start.as_slice()), Some(end.as_slice()))
+            .is_err());
+
+        // past the last key
+        let start = [9u8];
+        let proof = merkle
+            .range_proof(Some(start.as_slice()), None, None)
+            .await
+            .unwrap();
+        assert!(proof.key_values.is_empty());
+        assert!(proof.end_proof.is_none());
+        proof
+            .verify(&root_hash, Some(start.as_slice()), None)
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn empty_or_inverted_range() {
+        let (merkle, root_hash) = hashed_merkle(range_proof_kvs());
+        let (low, high) = ([1u8].as_slice(), [2u8].as_slice());
+
+        assert!(matches!(
+            merkle.range_proof(Some(high), Some(low), None).await,
+            Err(api::Error::InvalidRange { .. })
+        ));
+        let proof = merkle
+            .range_proof(Some(low), Some(high), None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            proof.verify(&root_hash, Some(high), Some(low)),
+            Err(ProofError::EmptyRange)
+        ));
+
+        // a range of a single key, which is or isn't in the trie
+        let present = [0u8, 3].as_slice();
+        let proof = merkle
+            .range_proof(Some(present), Some(present), None)
+            .await
+            .unwrap();
+        assert_eq!(proof.key_values.len(), 1);
+        proof
+            .verify(&root_hash, Some(present), Some(present))
+            .unwrap();
+
+        let absent = [0u8, 4].as_slice();
+        let proof = merkle
+            .range_proof(Some(absent), Some(absent), None)
+            .await
+            .unwrap();
+        assert!(proof.key_values.is_empty());
+        proof
+            .verify(&root_hash, Some(absent), Some(absent))
+            .unwrap();
+    }
+
     //     #[tokio::test]
     //     async fn range_proof_invalid_bounds() {
     //         let merkle = create_in_memory_merkle();
@@ -1537,6 +3475,71 @@ mod tests {
         assert_eq!(&*merkle.get_value(b"do").unwrap().unwrap(), b"verb");
     }
 
+    #[test]
+    fn estimate_range() {
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut kvs: Vec<([u8; 32], [u8; 8])> = (0..4000).map(|_| (rng.gen(), rng.gen())).collect();
+        kvs.sort();
+
+        let mut merkle = create_in_memory_merkle();
+        for (key, value) in kvs.iter() {
+            merkle.insert(key, Box::new(*value)).unwrap();
+        }
+        let merkle = merkle.hash();
+
+        let exact = |start: &[u8], end: &[u8]| {
+            kvs.iter()
+                .filter(|(key, _)| key.as_slice() >= start && key.as_slice() < end)
+                .count() as u64
+        };
+        let assert_close = |estimate: RangeEstimate, expected: u64| {
+            let (low, high) = (expected / 2, expected + expected / 2);
+            assert!(
+                (low..=high).contains(&estimate.approx_keys),
+                "{estimate:?} is not close to {expected} keys"
+            );
+            // every key is 32 bytes and every value is 8 bytes
+            let bytes = estimate.approx_keys * 40;
+            assert!(estimate.approx_bytes.abs_diff(bytes) <= bytes / 10);
+        };
+
+        assert_close(merkle.estimate_range(None, None).unwrap(), 4000);
+        assert_close(
+            merkle.estimate_range(Some(&[0x40]), Some(&[0x80])).unwrap(),
+            exact(&[0x40], &[0x80]),
+        );
+        assert_close(
+            merkle.estimate_range(Some(&[0x12, 0x34]), None).unwrap(),
+            exact(&[0x12, 0x34], &[0xff; 33]),
+        );
+
+        // empty and inverted ranges are exact
+        assert_eq!(
+            merkle.estimate_range(Some(&[0x40]), Some(&[0x40])).unwrap(),
+            RangeEstimate::default()
+        );
+        assert_eq!(
+            merkle.estimate_range(Some(&[0x80]), Some(&[0x40])).unwrap(),
+            RangeEstimate::default()
+        );
+        assert_eq!(
+            merkle.estimate_range(Some(&[0xff; 33]), None).unwrap(),
+            RangeEstimate::default()
+        );
+
+        // a range holding a single key is exact
+        let (key, _) = kvs[1234];
+        let mut end = key.to_vec();
+        end.push(0);
+        assert_eq!(
+            merkle.estimate_range(Some(&key), Some(&end)).unwrap(),
+            RangeEstimate {
+                approx_keys: 1,
+                approx_bytes: 40
+            }
+        );
+    }
+
     // #[test]
     // #[allow(clippy::unwrap_used)]
     // fn test_root_hash_reversed_deletions() -> Result<(), MerkleError> {
@@ -0,0 +1,523 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The metrics firewood emits.
+//!
+//! Metrics go to the global recorder of the `metrics` crate, which is looked
+//! up each time one is emitted rather than when the database is opened, so
+//! a recorder installed or replaced later sees everything from then on.
+//! Descriptions are only kept by the recorder that was installed when they
+//! were given; [Db::new](crate::db::Db::new) gives them, and [describe_all](crate::metrics::describe_all)
+//! gives them again to a recorder installed afterwards.
+
+use ::metrics::{KeyName, SharedString, Unit};
+
+/// The kinds of metric
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricKind {
+    /// A count that only goes up
+    Counter,
+    /// A value that goes up and down
+    Gauge,
+    /// A distribution of values
+    Histogram,
+}
+
+/// A metric firewood emits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetricInfo {
+    /// The name it is emitted with
+    pub name: &'static str,
+    /// What kind of metric it is
+    pub kind: MetricKind,
+    /// The unit of its values, if they have one
+    pub unit: Option<Unit>,
+    /// The keys of the labels it is emitted with
+    pub labels: &'static [&'static str],
+    /// What it measures
+    pub help: &'static str,
+}
+
+const fn counter(
+    name: &'static str,
+    unit: Option<Unit>,
+    labels: &'static [&'static str],
+    help: &'static str,
+) -> MetricInfo {
+    MetricInfo {
+        name,
+        kind: MetricKind::Counter,
+        unit,
+        labels,
+        help,
+    }
+}
+
+const fn histogram(
+    name: &'static str,
+    unit: Option<Unit>,
+    labels: &'static [&'static str],
+    help: &'static str,
+) -> MetricInfo {
+    MetricInfo {
+        name,
+        kind: MetricKind::Histogram,
+        unit,
+        labels,
+        help,
+    }
+}
+
+static CATALOG: &[MetricInfo] = &[
+    counter(
+        "firewood.proposals",
+        None,
+        &[],
+        "Number of proposals created",
+    ),
+    counter(
+        "firewood.propose.batch",
+        None,
+        &["path"],
+        "Batches proposed with the append path, or that fell back from it",
+    ),
+    counter(
+        "firewood.propose.rewrite",
+        None,
+        &["result"],
+        "Puts to keys that had a value, by whether the new value was equivalent",
+    ),
+    counter(
+        "firewood.insert",
+        None,
+        &["merkle"],
+        "Keys inserted into a trie, by how the trie changed",
+    ),
+    counter(
+        "firewood.remove",
+        None,
+        &["result"],
+        "Keys removed from a trie, by whether they were present",
+    ),
+    counter(
+        "firewood.remove_prefix",
+        None,
+        &[],
+        "Prefixes removed from a trie",
+    ),
+    counter(
+        "firewood.heal.folded",
+        None,
+        &[],
+        "Nodes folded into their only child by healing",
+    ),
+    counter("firewood.compactions", None, &[], "Compactions completed"),
+    counter(
+        "firewood.flush.write_calls",
+        None,
+        &[],
+        "Writes of adjacent node areas made flushing commits",
+    ),
+    counter(
+        "firewood.flush.bytes",
+        Some(Unit::Bytes),
+        &[],
+        "Bytes of node areas written flushing commits",
+    ),
+    counter(
+        "firewood.commit.nodes_written",
+        None,
+        &[],
+        "Nodes written flushing commits",
+    ),
+    histogram(
+        "firewood.commit.latency",
+        Some(Unit::Seconds),
+        &["phase"],
+        "Time commits took to reap, flush the free lists, flush the nodes and write the header, by phase",
+    ),
+    histogram(
+        "firewood.commit.reap",
+        Some(Unit::Seconds),
+        &[],
+        "Time commits took to reap the oldest revisions and free their nodes (step 3)",
+    ),
+    histogram(
+        "firewood.commit.freelist_flush",
+        Some(Unit::Seconds),
+        &[],
+        "Time commits took to flush the free lists (step 5)",
+    ),
+    histogram(
+        "firewood.commit.node_flush",
+        Some(Unit::Seconds),
+        &[],
+        "Time commits took to flush their nodes, and sync them if durability is strict (step 6)",
+    ),
+    histogram(
+        "firewood.commit.root_move",
+        Some(Unit::Seconds),
+        &[],
+        "Time commits took to write the header with the new root, and sync it if durability is strict (step 7)",
+    ),
+    counter(
+        "firewood.reap.background",
+        None,
+        &["result"],
+        "Reaped revisions whose areas were looked up off the commit path, by result",
+    ),
+    counter(
+        "firewood.cache.node",
+        None,
+        &["type"],
+        "Node cache hits and misses",
+    ),
+    counter(
+        "firewood.cache.freelist",
+        None,
+        &["type"],
+        "Free list cache hits and misses",
+    ),
+    counter(
+        "firewood.cache.shed",
+        None,
+        &[],
+        "Nodes shed from the node cache",
+    ),
+    counter(
+        "firewood.io.read.bytes",
+        Some(Unit::Bytes),
+        &[],
+        "Bytes read from the file",
+    ),
+    histogram(
+        "firewood.io.read.latency",
+        Some(Unit::Seconds),
+        &[],
+        "Latency of reads from the file",
+    ),
+    counter(
+        "firewood.delete_node",
+        None,
+        &["index"],
+        "Nodes deleted, by area size",
+    ),
+    counter(
+        "firewood.space.reused",
+        Some(Unit::Bytes),
+        &["index"],
+        "Space allocated from a free list, by area size",
+    ),
+    counter(
+        "firewood.space.wasted",
+        Some(Unit::Bytes),
+        &["index"],
+        "Space allocated from a free list beyond what was needed, by area size",
+    ),
+    counter(
+        "firewood.space.from_end",
+        Some(Unit::Bytes),
+        &["index"],
+        "Space allocated at the end of the file, by area size",
+    ),
+    counter(
+        "firewood.space.reserved",
+        Some(Unit::Bytes),
+        &["from"],
+        "Space reserved for the nodes of a proposal, by where it came from",
+    ),
+    counter(
+        "firewood.space.unreserved",
+        Some(Unit::Bytes),
+        &[],
+        "Reserved space a proposal didn't use",
+    ),
+    counter(
+        "firewood.space.freed",
+        Some(Unit::Bytes),
+        &["index"],
+        "Space put on a free list, by area size",
+    ),
+    counter(
+        "firewood.space.punched",
+        Some(Unit::Bytes),
+        &[],
+        "Space of free areas returned to the file system",
+    ),
+    counter(
+        "firewood.remote.fetch",
+        None,
+        &[],
+        "Extents fetched from remote storage",
+    ),
+    #[cfg(feature = "metrics")]
+    histogram(
+        "firewood.api.latency",
+        Some(Unit::Seconds),
+        &["method"],
+        "Latency of public API calls, by method",
+    ),
+];
+
+/// Every metric firewood emits, including the storage layer's
+pub fn metric_catalog() -> &'static [MetricInfo] {
+    CATALOG
+}
+
+/// Give the description and unit of every metric in [metric_catalog] to
+/// the current recorder
+pub fn describe_all() {
+    ::metrics::with_recorder(|recorder| {
+        for metric in CATALOG {
+            let name = KeyName::from_const_str(metric.name);
+            let help = SharedString::const_str(metric.help);
+            match metric.kind {
+                MetricKind::Counter => recorder.describe_counter(name, metric.unit, help),
+                MetricKind::Gauge => recorder.describe_gauge(name, metric.unit, help),
+                MetricKind::Histogram => recorder.describe_histogram(name, metric.unit, help),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use metrics_util::debugging::{DebugValue, DebuggingRecorder, Snapshotter};
+    use tokio::runtime::Runtime;
+
+    use super::*;
+    use crate::db::{BatchOp, Db, DbConfig};
+    use crate::manager::{NodeCachePolicy, RevisionManagerConfig};
+    use crate::v2::api::{Db as _, DbView as _, Proposal as _};
+
+    // The recorders are local to the test's thread, so the database is
+    // driven on it too
+    fn runtime() -> Runtime {
+        tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap()
+    }
+
+    async fn open(dir: &tempfile::TempDir) -> Db {
+        let config = DbConfig::builder().truncate(true).build();
+        Db::new(dir.path().join("db"), config).await.unwrap()
+    }
+
+    async fn commit(db: &Db) {
+        let batch = vec![BatchOp::Put {
+            key: b"k",
+            value: b"v",
+        }];
+        db.propose(batch).await.unwrap().commit().await.unwrap();
+    }
+
+    /// The value of `name`'s counter, over all its labels
+    fn counter(snapshotter: &Snapshotter, name: &str) -> u64 {
+        snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .filter(|(key, ..)| key.key().name() == name)
+            .map(|(.., value)| match value {
+                DebugValue::Counter(value) => value,
+                value => panic!("{name} is a {value:?}"),
+            })
+            .sum()
+    }
+
+    /// The value of `name`'s counter with the label `key` set to `value`
+    fn labelled_counter(snapshotter: &Snapshotter, name: &str, key: &str, value: &str) -> u64 {
+        snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .filter(|(composite, ..)| {
+                composite.key().name() == name
+                    && composite
+                        .key()
+                        .labels()
+                        .any(|label| label.key() == key && label.value() == value)
+            })
+            .map(|(.., value)| match value {
+                DebugValue::Counter(value) => value,
+                value => panic!("{name} is a {value:?}"),
+            })
+            .sum()
+    }
+
+    /// The number of values recorded in `name`'s histograms. Every
+    /// snapshot takes the values out of the histograms, so this must come
+    /// before anything else looks at `snapshotter`.
+    fn samples(snapshotter: &Snapshotter, name: &str) -> usize {
+        snapshotter
+            .snapshot()
+            .into_vec()
+            .into_iter()
+            .filter(|(key, ..)| key.key().name() == name)
+            .map(|(.., value)| match value {
+                DebugValue::Histogram(values) => values.len(),
+                value => panic!("{name} is a {value:?}"),
+            })
+            .sum()
+    }
+
+    /// Check that everything `snapshotter` saw is in the catalog, and was
+    /// described as it says
+    fn assert_described(snapshotter: &Snapshotter) {
+        let snapshot = snapshotter.snapshot().into_vec();
+        assert!(!snapshot.is_empty());
+        for (key, unit, help, _) in snapshot {
+            let name = key.key().name();
+            let info = metric_catalog()
+                .iter()
+                .find(|info| info.name == name)
+                .unwrap_or_else(|| panic!("{name} is missing from the catalog"));
+            assert_eq!(help.as_deref(), Some(info.help), "{name}");
+            assert_eq!(unit, info.unit, "{name}");
+            for label in key.key().labels() {
+                assert!(info.labels.contains(&label.key()), "{name} {label:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn recorder_installed_after_open() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = runtime();
+        let db = runtime.block_on(open(&dir));
+        runtime.block_on(commit(&db));
+
+        let recorder = DebuggingRecorder::new();
+        let snapshotter = recorder.snapshotter();
+        ::metrics::with_local_recorder(&recorder, || {
+            describe_all();
+            runtime.block_on(commit(&db));
+        });
+        assert_eq!(counter(&snapshotter, "firewood.proposals"), 1);
+        // the same value again, which is dropped rather than inserted
+        assert_eq!(counter(&snapshotter, "firewood.propose.rewrite"), 1);
+        assert_described(&snapshotter);
+    }
+
+    #[test]
+    fn recorders_in_turn_start_clean() {
+        for _ in 0..2 {
+            let dir = tempfile::tempdir().unwrap();
+            let recorder = DebuggingRecorder::new();
+            let snapshotter = recorder.snapshotter();
+            ::metrics::with_local_recorder(&recorder, || {
+                runtime().block_on(async {
+                    let db = open(&dir).await;
+                    commit(&db).await;
+                    commit(&db).await;
+                })
+            });
+            assert_eq!(counter(&snapshotter, "firewood.proposals"), 2);
+            assert_described(&snapshotter);
+        }
+    }
+
+    #[test]
+    fn read_metrics() {
+        let dir = tempfile::tempdir().unwrap();
+        let runtime = runtime();
+        let keys: Vec<[u8; 4]> = (0..100u32).map(u32::to_be_bytes).collect();
+
+        let recorder = DebuggingRecorder::new();
+        let commits = recorder.snapshotter();
+        let db = ::metrics::with_local_recorder(&recorder, || {
+            describe_all();
+            runtime.block_on(async {
+                let db = open(&dir).await;
+                let batch: Vec<_> = keys
+                    .iter()
+                    .map(|key| BatchOp::Put {
+                        key: *key,
+                        value: *key,
+                    })
+                    .collect();
+                db.propose(batch).await.unwrap().commit().await.unwrap();
+                db
+            })
+        });
+        // each snapshot drains the histograms, so they are counted at once
+        let snapshot = commits.snapshot().into_vec();
+        let commit_samples = |name: &str, phase: Option<&str>| -> usize {
+            snapshot
+                .iter()
+                .filter(|(key, ..)| key.key().name() == name)
+                .filter(|(key, ..)| {
+                    phase.is_none_or(|phase| {
+                        key.key()
+                            .labels()
+                            .any(|label| label.key() == "phase" && label.value() == phase)
+                    })
+                })
+                .map(|(.., value)| match value {
+                    DebugValue::Histogram(values) => values.len(),
+                    value => panic!("{name} is a {value:?}"),
+                })
+                .sum()
+        };
+        for (phase, stage) in [
+            ("reap", "firewood.commit.reap"),
+            ("free_list", "firewood.commit.freelist_flush"),
+            ("nodes", "firewood.commit.node_flush"),
+            ("header", "firewood.commit.root_move"),
+        ] {
+            assert_eq!(commit_samples(stage, None), 1, "{stage}");
+            assert_eq!(
+                commit_samples("firewood.commit.latency", Some(phase)),
+                1,
+                "{phase}"
+            );
+        }
+        assert!(counter(&commits, "firewood.commit.nodes_written") >= keys.len() as u64);
+        assert_described(&commits);
+        drop(db);
+
+        // reopened, the cache is empty, and nodes read are admitted to it
+        let config = DbConfig::builder()
+            .truncate(false)
+            .manager(
+                RevisionManagerConfig::builder()
+                    .node_cache_policy(NodeCachePolicy::Lru)
+                    .build(),
+            )
+            .build();
+        let db = runtime
+            .block_on(Db::new(dir.path().join("db"), config))
+            .unwrap();
+        let read = |key: &[u8]| {
+            let recorder = DebuggingRecorder::new();
+            let snapshotter = recorder.snapshotter();
+            ::metrics::with_local_recorder(&recorder, || {
+                describe_all();
+                runtime.block_on(async {
+                    let root_hash = db.root_hash().await.unwrap().unwrap();
+                    let revision = db.revision(root_hash).await.unwrap();
+                    assert_eq!(revision.val(key).await.unwrap().as_deref(), Some(key));
+                })
+            });
+            snapshotter
+        };
+
+        // the first read finds the nodes on the key's path on disk
+        let key = 7u32.to_be_bytes();
+        let first = read(&key);
+        assert!(samples(&first, "firewood.io.read.latency") > 0);
+        assert!(labelled_counter(&first, "firewood.cache.node", "type", "miss") > 0);
+        assert!(counter(&first, "firewood.io.read.bytes") > 0);
+        assert_described(&first);
+
+        // the second finds them all in the cache
+        let second = read(&key);
+        assert_eq!(
+            labelled_counter(&second, "firewood.cache.node", "type", "miss"),
+            0
+        );
+        assert!(labelled_counter(&second, "firewood.cache.node", "type", "hit") > 0);
+        assert_eq!(counter(&second, "firewood.io.read.bytes"), 0);
+    }
+}
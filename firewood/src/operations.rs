@@ -0,0 +1,285 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use std::collections::BTreeMap;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::v2::api;
+
+/// Identifies a long-running operation in the [OperationRegistry]
+pub type OperationId = u64;
+
+/// How many units of work (nodes or bytes, whichever comes first) an operation
+/// may do between checks of its [CancellationToken]
+const CHECK_INTERVAL_NODES: u64 = 1024;
+const CHECK_INTERVAL_BYTES: u64 = 4 * 1024 * 1024;
+
+/// A token used to ask a long-running operation to stop.
+///
+/// Clones of a token share the same cancellation state, so the caller can
+/// keep one clone and hand another to the operation. A token may also carry
+/// a deadline, after which it behaves as if it was cancelled.
+#[derive(Debug, Clone, Default)]
+pub struct CancellationToken {
+    cancelled: Arc<AtomicBool>,
+    deadline: Option<Instant>,
+}
+
+impl CancellationToken {
+    /// Create a token that is only cancelled by calling [CancellationToken::cancel]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a token that is cancelled once `deadline` has passed
+    pub fn with_deadline(deadline: Instant) -> Self {
+        Self {
+            cancelled: Default::default(),
+            deadline: Some(deadline),
+        }
+    }
+
+    /// Create a token that is cancelled once `timeout` has elapsed
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self::with_deadline(Instant::now() + timeout)
+    }
+
+    /// Ask any operation holding this token to stop at its next safe point
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Relaxed);
+    }
+
+    /// Returns true if this token was cancelled or its deadline has passed
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Relaxed)
+            || self
+                .deadline
+                .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+}
+
+/// How far a long-running operation got
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Progress {
+    /// The number of nodes processed
+    pub nodes: u64,
+    /// The number of bytes processed
+    pub bytes: u64,
+}
+
+/// A snapshot of a running operation, as returned by [crate::db::Db::operations]
+#[derive(Debug, Clone)]
+pub struct OperationInfo {
+    /// The identifier to pass to [crate::db::Db::cancel]
+    pub id: OperationId,
+    /// A short name for the operation, such as "verify" or "export"
+    pub name: &'static str,
+    /// When the operation started
+    pub started: Instant,
+    /// How much work the operation has done so far
+    pub progress: Progress,
+}
+
+#[derive(Debug)]
+struct OperationState {
+    name: &'static str,
+    started: Instant,
+    token: CancellationToken,
+    nodes: AtomicU64,
+    bytes: AtomicU64,
+}
+
+impl OperationState {
+    fn progress(&self) -> Progress {
+        Progress {
+            nodes: self.nodes.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// The set of long-running operations currently in progress on a database
+#[derive(Debug, Default)]
+pub struct OperationRegistry {
+    next_id: AtomicU64,
+    running: Mutex<BTreeMap<OperationId, Arc<OperationState>>>,
+}
+
+impl OperationRegistry {
+    /// Register a new operation. The operation is removed from the registry
+    /// when the returned handle is dropped.
+    pub fn start(
+        self: &Arc<Self>,
+        name: &'static str,
+        token: Option<CancellationToken>,
+    ) -> OperationHandle {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let state = Arc::new(OperationState {
+            name,
+            started: Instant::now(),
+            token: token.unwrap_or_default(),
+            nodes: AtomicU64::new(0),
+            bytes: AtomicU64::new(0),
+        });
+        self.running
+            .lock()
+            .expect("poisoned lock")
+            .insert(id, state.clone());
+
+        OperationHandle {
+            id,
+            state,
+            registry: self.clone(),
+            unchecked_nodes: 0,
+            unchecked_bytes: 0,
+        }
+    }
+
+    /// List the operations that are currently running, oldest first
+    pub fn list(&self) -> Vec<OperationInfo> {
+        self.running
+            .lock()
+            .expect("poisoned lock")
+            .iter()
+            .map(|(id, state)| OperationInfo {
+                id: *id,
+                name: state.name,
+                started: state.started,
+                progress: state.progress(),
+            })
+            .collect()
+    }
+
+    /// Cancel a running operation. Returns false if no operation with this
+    /// id is running.
+    pub fn cancel(&self, id: OperationId) -> bool {
+        match self.running.lock().expect("poisoned lock").get(&id) {
+            Some(state) => {
+                state.token.cancel();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Held by a running operation to report progress and check for cancellation
+#[derive(Debug)]
+pub struct OperationHandle {
+    id: OperationId,
+    state: Arc<OperationState>,
+    registry: Arc<OperationRegistry>,
+    unchecked_nodes: u64,
+    unchecked_bytes: u64,
+}
+
+impl OperationHandle {
+    /// The identifier of this operation
+    pub const fn id(&self) -> OperationId {
+        self.id
+    }
+
+    /// The progress reported so far
+    pub fn progress(&self) -> Progress {
+        self.state.progress()
+    }
+
+    /// Record that `nodes` nodes and `bytes` bytes of work were completed.
+    ///
+    /// Callers must only call this at a safe point: if the operation was
+    /// cancelled, [api::Error::Cancelled] is returned and the caller is
+    /// expected to stop without doing any more work. The token is only
+    /// consulted every few thousand nodes or few megabytes so that checking
+    /// stays cheap.
+    pub fn checkpoint(&mut self, nodes: u64, bytes: u64) -> Result<(), api::Error> {
+        self.state.nodes.fetch_add(nodes, Ordering::Relaxed);
+        self.state.bytes.fetch_add(bytes, Ordering::Relaxed);
+        self.unchecked_nodes += nodes;
+        self.unchecked_bytes += bytes;
+
+        if self.unchecked_nodes < CHECK_INTERVAL_NODES
+            && self.unchecked_bytes < CHECK_INTERVAL_BYTES
+        {
+            return Ok(());
+        }
+        self.unchecked_nodes = 0;
+        self.unchecked_bytes = 0;
+        self.check()
+    }
+
+    /// Check for cancellation immediately, regardless of how much work was
+    /// done since the last check
+    pub fn check(&self) -> Result<(), api::Error> {
+        if self.state.token.is_cancelled() {
+            return Err(api::Error::Cancelled {
+                progress: self.progress(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl Drop for OperationHandle {
+    fn drop(&mut self) {
+        if let Ok(mut running) = self.registry.running.lock() {
+            running.remove(&self.id);
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::indexing_slicing, clippy::unwrap_used)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cancel_running_operation() {
+        let registry = Arc::new(OperationRegistry::default());
+        let mut handle = registry.start("verify", None);
+
+        let running = registry.list();
+        assert_eq!(running.len(), 1);
+        assert_eq!(running[0].name, "verify");
+        assert_eq!(running[0].id, handle.id());
+
+        handle.checkpoint(10, 100).unwrap();
+        assert!(registry.cancel(handle.id()));
+
+        // small amounts of work don't consult the token
+        handle.checkpoint(1, 1).unwrap();
+
+        let err = handle.checkpoint(CHECK_INTERVAL_NODES, 0).unwrap_err();
+        let api::Error::Cancelled { progress } = err else {
+            panic!("unexpected error {err:?}");
+        };
+        assert_eq!(
+            progress,
+            Progress {
+                nodes: 11 + CHECK_INTERVAL_NODES,
+                bytes: 101
+            }
+        );
+
+        drop(handle);
+        assert!(registry.list().is_empty());
+        assert!(!registry.cancel(0));
+    }
+
+    #[test]
+    fn deadline_cancels() {
+        let registry = Arc::new(OperationRegistry::default());
+        let token = CancellationToken::with_deadline(Instant::now());
+        let handle = registry.start("export", Some(token.clone()));
+
+        assert!(token.is_cancelled());
+        assert!(matches!(handle.check(), Err(api::Error::Cancelled { .. })));
+
+        let token = CancellationToken::with_timeout(Duration::from_secs(3600));
+        let handle = registry.start("export", Some(token.clone()));
+        assert!(handle.check().is_ok());
+        token.cancel();
+        assert!(handle.check().is_err());
+    }
+}
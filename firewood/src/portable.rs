@@ -0,0 +1,199 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Portable snapshots: a single revision written to a file as its keys and
+//! values, for backups and for bootstrapping a node. See
+//! [crate::db::Db::export_portable] and [crate::db::Db::import_portable].
+//!
+//! Unlike the node stream of [crate::export], a portable snapshot doesn't
+//! depend on how nodes are stored, so any version that reads the format can
+//! rebuild the revision from it, but importing one hashes the whole trie
+//! again. The root hash in the header is checked against the rebuilt trie,
+//! which catches a corrupt file.
+//!
+//! The file starts with [PORTABLE_MAGIC](crate::portable::PORTABLE_MAGIC), the version of the format, the
+//! root hash, and the reserved key prefix: its length plus one as a varint,
+//! or 0 if there is none, the prefix, and 1 if reserved keys are hidden from
+//! users or 0 if not. Then come the entries in key order, each the length of
+//! its key plus one as a varint, the key, the length of its value as a
+//! varint and the value. A key length of 0 ends the file.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Error, ErrorKind, Read, Write};
+use std::path::Path;
+
+use integer_encoding::{VarIntReader as _, VarIntWriter as _};
+use storage::TrieHash;
+
+use crate::merkle::{Key, Value};
+use crate::system::SystemKeys;
+
+/// The first bytes of a portable snapshot
+pub const PORTABLE_MAGIC: &[u8; 4] = b"FWKV";
+
+/// The version of the format written by [crate::db::Db::export_portable]
+pub const PORTABLE_VERSION: u8 = 1;
+
+/// What a portable snapshot records about its revision
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct Header {
+    /// The root hash of the revision
+    pub(crate) root_hash: TrieHash,
+    /// The reserved key prefix of the revision, if it has one
+    pub(crate) reserved_prefix: Option<Box<[u8]>>,
+    /// Whether the reserved keys are hidden from users
+    pub(crate) system_keys: SystemKeys,
+}
+
+/// Writes a portable snapshot to a new file
+pub(crate) struct PortableWriter {
+    file: BufWriter<File>,
+    entries: u64,
+}
+
+impl PortableWriter {
+    /// Create the file at `path`, which mustn't exist yet, and write `header`
+    pub(crate) fn create(path: &Path, header: &Header) -> Result<Self, Error> {
+        let file = OpenOptions::new().write(true).create_new(true).open(path)?;
+        let mut file = BufWriter::new(file);
+        file.write_all(PORTABLE_MAGIC)?;
+        file.write_all(&[PORTABLE_VERSION])?;
+        file.write_all(&header.root_hash)?;
+        match &header.reserved_prefix {
+            None => {
+                file.write_varint(0u64)?;
+            }
+            Some(prefix) => {
+                file.write_varint(prefix.len() as u64 + 1)?;
+                file.write_all(prefix)?;
+            }
+        }
+        let hidden = match header.system_keys {
+            SystemKeys::Hidden => 1,
+            SystemKeys::Reject => 0,
+        };
+        file.write_all(&[hidden])?;
+        Ok(Self { file, entries: 0 })
+    }
+
+    /// Write the next entry, whose key must come after the last one
+    pub(crate) fn write(&mut self, key: &[u8], value: &[u8]) -> Result<(), Error> {
+        self.file.write_varint(key.len() as u64 + 1)?;
+        self.file.write_all(key)?;
+        self.file.write_varint(value.len() as u64)?;
+        self.file.write_all(value)?;
+        self.entries += 1;
+        Ok(())
+    }
+
+    /// End the file and sync it to disk, returning the number of entries
+    pub(crate) fn finish(mut self) -> Result<u64, Error> {
+        self.file.write_varint(0u64)?;
+        let file = self.file.into_inner().map_err(|err| err.into_error())?;
+        file.sync_all()?;
+        Ok(self.entries)
+    }
+}
+
+/// Reads the entries of a portable snapshot, in key order
+pub(crate) struct PortableReader {
+    file: BufReader<File>,
+    /// The key of the last entry read, to check the order
+    last: Option<Key>,
+    done: bool,
+}
+
+fn invalid(reason: &str) -> Error {
+    Error::new(
+        ErrorKind::InvalidData,
+        format!("invalid portable snapshot: {reason}"),
+    )
+}
+
+impl PortableReader {
+    /// Open the file at `path` and read its header
+    pub(crate) fn open(path: &Path) -> Result<(Self, Header), Error> {
+        let mut file = BufReader::new(File::open(path)?);
+        let mut magic = [0; PORTABLE_MAGIC.len()];
+        file.read_exact(&mut magic)?;
+        if magic != *PORTABLE_MAGIC {
+            return Err(invalid("not a portable snapshot"));
+        }
+        let mut version = [0];
+        file.read_exact(&mut version)?;
+        let [version] = version;
+        if version != PORTABLE_VERSION {
+            return Err(invalid(&format!("unknown version {version}")));
+        }
+        let mut root_hash = [0; 32];
+        file.read_exact(&mut root_hash)?;
+        let reserved_prefix = match file.read_varint::<u64>()? {
+            0 => None,
+            len => Some(read_bytes(&mut file, len - 1)?.into_boxed_slice()),
+        };
+        let mut hidden = [0];
+        file.read_exact(&mut hidden)?;
+        let system_keys = match hidden {
+            [0] => SystemKeys::Reject,
+            [1] => SystemKeys::Hidden,
+            [other] => return Err(invalid(&format!("unknown reserved key mode {other}"))),
+        };
+        let header = Header {
+            root_hash: TrieHash::from(root_hash),
+            reserved_prefix,
+            system_keys,
+        };
+        let reader = Self {
+            file,
+            last: None,
+            done: false,
+        };
+        Ok((reader, header))
+    }
+
+    fn read_entry(&mut self) -> Result<Option<(Key, Value)>, Error> {
+        let key_len = self.file.read_varint::<u64>()?;
+        if key_len == 0 {
+            // nothing may follow the end
+            if self.file.read(&mut [0])? != 0 {
+                return Err(invalid("there is data after the end"));
+            }
+            return Ok(None);
+        }
+        let key = read_bytes(&mut self.file, key_len - 1)?.into_boxed_slice();
+        if self.last.as_ref().is_some_and(|last| *last >= key) {
+            return Err(invalid("the keys aren't in order"));
+        }
+        let value_len = self.file.read_varint::<u64>()?;
+        let value = read_bytes(&mut self.file, value_len)?;
+        self.last = Some(key.clone());
+        Ok(Some((key, value)))
+    }
+}
+
+impl Iterator for PortableReader {
+    type Item = Result<(Key, Value), Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let entry = self.read_entry().transpose();
+        // stop after the end, or after an error
+        self.done = !matches!(entry, Some(Ok(_)));
+        entry
+    }
+}
+
+/// Read `len` bytes, without trusting `len` enough to allocate it up front
+fn read_bytes<R: Read>(reader: &mut R, len: u64) -> Result<Vec<u8>, Error> {
+    let mut bytes = Vec::new();
+    reader.take(len).read_to_end(&mut bytes)?;
+    if (bytes.len() as u64) < len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            "the portable snapshot is truncated",
+        ));
+    }
+    Ok(bytes)
+}
@@ -2,9 +2,11 @@
 // See the file LICENSE.md for licensing terms.
 
 use crate::merkle::MerkleError;
+use serde::{ser::SerializeStruct as _, Deserialize, Serialize};
 use sha2::{Digest, Sha256};
+use smallvec::SmallVec;
 use storage::{
-    BranchNode, Hashable, NibblesIterator, PathIterItem, Preimage, TrieHash, ValueDigest,
+    BranchNode, Hashable, NibblesIterator, Node, PathIterItem, Preimage, TrieHash, ValueDigest,
 };
 use thiserror::Error;
 
@@ -62,6 +64,27 @@ pub enum ProofError {
     /// Empty range
     #[error("empty range")]
     EmptyRange,
+
+    /// A proof node after the first isn't on the path to the proven key
+    #[error("a proof node isn't on the path to the proven key")]
+    NotOnPathToProvenKey,
+
+    /// A key in a range proof is outside of the range
+    #[error("a key in the range proof is outside of the range")]
+    KeyOutsideRange,
+
+    /// A range proof is missing a node on the path to one of its bounds
+    #[error("the range proof is missing a node on the path to a bound")]
+    MissingBoundaryNode,
+
+    /// An exclusion proof ends at a node with a child on the path to the
+    /// proven key, so it doesn't show where that path diverges
+    #[error("exclusion proof ends above where the path to the proven key diverges")]
+    IncompleteExclusion,
+
+    /// The proof has the hash of the proven value rather than the value
+    #[error("the proof has the hash of the value, not the value")]
+    ValueIsHashed,
 }
 
 #[derive(Clone, Debug)]
@@ -97,12 +120,13 @@ impl Hashable for ProofNode {
     }
 }
 
-impl From<PathIterItem> for ProofNode {
-    fn from(item: PathIterItem) -> Self {
+impl ProofNode {
+    /// The proof node for `node`, whose key from the root is `key_nibbles`
+    pub(crate) fn new(key_nibbles: Box<[u8]>, node: &Node) -> Self {
         let mut child_hashes: [Option<TrieHash>; BranchNode::MAX_CHILDREN] =
             [const { None }; BranchNode::MAX_CHILDREN];
 
-        if let Some(branch) = item.node.as_branch() {
+        if let Some(branch) = node.as_branch() {
             // TODO danlaine: can we avoid indexing?
             #[allow(clippy::indexing_slicing)]
             for (i, hash) in branch.children_iter() {
@@ -111,9 +135,8 @@ impl From<PathIterItem> for ProofNode {
         }
 
         Self {
-            key: item.key_nibbles,
-            value_digest: item
-                .node
+            key: key_nibbles,
+            value_digest: node
                 .value()
                 .map(|value| ValueDigest::Value(value.to_vec().into_boxed_slice())),
             child_hashes,
@@ -121,6 +144,61 @@ impl From<PathIterItem> for ProofNode {
     }
 }
 
+// Only the children that exist are serialized, with their indexes, as a
+// branch node's are on disk.
+impl Serialize for ProofNode {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        let mut state = serializer.serialize_struct("ProofNode", 3)?;
+        state.serialize_field("key", &self.key)?;
+        state.serialize_field("value_digest", &self.value_digest)?;
+        let children: SmallVec<[(u8, &TrieHash); BranchNode::MAX_CHILDREN]> = self
+            .children()
+            .map(|(index, hash)| (index as u8, hash))
+            .collect();
+        state.serialize_field("children", &children)?;
+        state.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for ProofNode {
+    fn deserialize<D>(deserializer: D) -> Result<ProofNode, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        #[derive(Deserialize)]
+        struct SerializedProofNode {
+            key: Box<[u8]>,
+            value_digest: Option<ValueDigest<Box<[u8]>>>,
+            children: SmallVec<[(u8, TrieHash); BranchNode::MAX_CHILDREN]>,
+        }
+
+        let s: SerializedProofNode = Deserialize::deserialize(deserializer)?;
+
+        let mut child_hashes = [const { None }; BranchNode::MAX_CHILDREN];
+        for (index, hash) in s.children {
+            let child_hash = child_hashes
+                .get_mut(index as usize)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid child index {index}")))?;
+            *child_hash = Some(hash);
+        }
+
+        Ok(ProofNode {
+            key: s.key,
+            value_digest: s.value_digest,
+            child_hashes,
+        })
+    }
+}
+
+impl From<PathIterItem> for ProofNode {
+    fn from(item: PathIterItem) -> Self {
+        Self::new(item.key_nibbles, &item.node)
+    }
+}
+
 impl From<&ProofNode> for TrieHash {
     fn from(node: &ProofNode) -> Self {
         node.to_hash()
@@ -128,7 +206,7 @@ impl From<&ProofNode> for TrieHash {
 }
 
 /// A proof that a given key-value pair either exists or does not exist in a trie.
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct Proof<T: Hashable>(pub Box<[T]>);
 
 impl<T: Hashable> Proof<T> {
@@ -198,19 +276,20 @@ impl<T: Hashable> Proof<T> {
                 return Err(ProofError::UnexpectedHash);
             }
 
-            // Assert that only nodes whose keys are an even number of nibbles
+            // Assert that only nodes whose keys are a whole number of bytes
             // have a `value_digest`.
-            #[cfg(not(feature = "branch_factor_256"))]
-            if node.key().count() % 2 != 0 && node.value_digest().is_some() {
+            if node.key().count() % BranchNode::NIBBLES_PER_BYTE != 0
+                && node.value_digest().is_some()
+            {
                 return Err(ProofError::ValueAtOddNibbleLength);
             }
 
             if let Some(next_node) = iter.peek() {
                 // Assert that every node's key is a prefix of `key`, except for the last node,
-                // whose key can be equal to or a suffix of `key` in an exclusion proof.
-                if next_nibble(node.key(), key.iter().copied()).is_none() {
+                // whose key can be equal to or diverge from `key` in an exclusion proof.
+                let Some(key_nibble) = next_nibble(node.key(), key.iter().copied()) else {
                     return Err(ProofError::ShouldBePrefixOfProvenKey);
-                }
+                };
 
                 // Assert that every node's key is a prefix of the next node's key.
                 let next_node_index = next_nibble(node.key(), next_node.key());
@@ -219,6 +298,11 @@ impl<T: Hashable> Proof<T> {
                     return Err(ProofError::ShouldBePrefixOfNextKey);
                 };
 
+                // Assert that the next node is the child on the path to `key`.
+                if next_nibble != key_nibble {
+                    return Err(ProofError::NotOnPathToProvenKey);
+                }
+
                 expected_hash = node
                     .children()
                     .find_map(|(i, hash)| {
@@ -232,15 +316,38 @@ impl<T: Hashable> Proof<T> {
             }
         }
 
-        if last_node.key().count() == key.len() {
+        if last_node.key().eq(key.iter().copied()) {
             return Ok(last_node.value_digest());
         }
 
-        // This is an exclusion proof.
+        // This is an exclusion proof. If the last node's key is a prefix of
+        // `key`, it must not have a child on the path to `key`; otherwise
+        // the last node's key diverges from `key`.
+        if let Some(key_nibble) = next_nibble(last_node.key(), key.iter().copied()) {
+            if last_node.children().any(|(i, _)| i == key_nibble as usize) {
+                return Err(ProofError::IncompleteExclusion);
+            }
+        }
         Ok(None)
     }
 }
 
+/// Verify `proof` against `root_hash` without the trie it was made from,
+/// returning the value it proves `key` has, or None if it proves that `key`
+/// isn't in the trie. Starting from `root_hash`, each node of the proof must
+/// hash to what the node before it has for its child on the path to `key`.
+pub fn verify_proof<T: Hashable>(
+    root_hash: &TrieHash,
+    key: &[u8],
+    proof: &Proof<T>,
+) -> Result<Option<Box<[u8]>>, ProofError> {
+    match proof.value_digest(key, root_hash)? {
+        None => Ok(None),
+        Some(ValueDigest::Value(value)) => Ok(Some(value.into())),
+        Some(ValueDigest::_Hash(_)) => Err(ProofError::ValueIsHashed),
+    }
+}
+
 /// Returns the next nibble in `c` after `b`.
 /// Returns None if `b` is not a strict prefix of `c`.
 fn next_nibble<B, C>(b: B, c: C) -> Option<u8>
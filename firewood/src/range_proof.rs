@@ -1,18 +1,281 @@
 // Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use storage::Hashable;
+use std::collections::BTreeMap;
 
-use crate::proof::Proof;
+use storage::{BranchNode, Hashable, NibblesIterator, Preimage, TrieHash, ValueDigest};
+
+use crate::proof::{Proof, ProofError, ProofNode};
 
 /// A range proof proves that a given set of key-value pairs
 /// are in the trie with a given root hash.
+///
+/// The range it covers starts at the key `start_proof` proves, or at the
+/// lowest key if there is no `start_proof`, and ends at the last of
+/// `key_values`. If there are none, it ends at the key `end_proof` proves,
+/// or at the highest key if there is no `end_proof`. A proof whose
+/// `key_values` were truncated by a limit covers the truncated range, so
+/// the next one can start after the last of them.
 #[derive(Debug)]
 pub struct RangeProof<K: AsRef<[u8]>, V: AsRef<[u8]>, H: Hashable> {
-    #[allow(dead_code)]
-    pub(crate) start_proof: Option<Proof<H>>,
-    #[allow(dead_code)]
-    pub(crate) end_proof: Option<Proof<H>>,
-    #[allow(dead_code)]
-    pub(crate) key_values: Box<[(K, V)]>,
+    /// The proof of the start of the range, if it has one
+    pub start_proof: Option<Proof<H>>,
+    /// The proof of the last of `key_values`, or of the end of the range if
+    /// there are none and it has one
+    pub end_proof: Option<Proof<H>>,
+    /// Every key-value pair in the range, in order
+    pub key_values: Box<[(K, V)]>,
+}
+
+impl<K: AsRef<[u8]>, V: AsRef<[u8]>, H: Hashable> RangeProof<K, V, H> {
+    /// Verify that `key_values` are every key-value pair from `start` on in
+    /// the trie with `root_hash`, up to the last of them, or up to `end` if
+    /// there are none. The trie is rebuilt from `key_values` and the nodes
+    /// of the boundary proofs, which must hash to `root_hash`.
+    pub fn verify(
+        &self,
+        root_hash: &TrieHash,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(), ProofError> {
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Err(ProofError::EmptyRange);
+            }
+        }
+        let last = self.key_values.last().map(|(key, _)| key.as_ref());
+        if let (Some(last), Some(end)) = (last, end) {
+            if last > end {
+                return Err(ProofError::KeyOutsideRange);
+            }
+        }
+        self.verify_range(root_hash, start, last.or(end))
+    }
+
+    /// Verify that `key_values` are every key-value pair from `start` to
+    /// `end` in the trie with `root_hash`, where `end_proof` proves `end`
+    /// even if it is after the last of them
+    pub(crate) fn verify_range(
+        &self,
+        root_hash: &TrieHash,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<(), ProofError> {
+        if let (Some(start), Some(end)) = (start, end) {
+            if start > end {
+                return Err(ProofError::EmptyRange);
+            }
+        }
+
+        let mut last: Option<&[u8]> = None;
+        for (key, _) in self.key_values.iter() {
+            let key = key.as_ref();
+            if last.is_some_and(|last| last >= key) {
+                return Err(ProofError::NonMonotonicIncreaseRange);
+            }
+            if start.is_some_and(|start| key < start) || end.is_some_and(|end| key > end) {
+                return Err(ProofError::KeyOutsideRange);
+            }
+            last = Some(key);
+        }
+
+        let Some(root) = self
+            .start_proof
+            .iter()
+            .chain(&self.end_proof)
+            .find_map(|proof| proof.0.first())
+        else {
+            return Err(ProofError::Empty);
+        };
+
+        let mut nodes = BTreeMap::new();
+        for node in self
+            .start_proof
+            .iter()
+            .chain(&self.end_proof)
+            .flat_map(|p| p.0.iter())
+        {
+            nodes.insert(node.key().collect::<Box<[u8]>>(), node);
+        }
+        let mut trie = PartialTrie {
+            start: start.map(nibbles),
+            end: end.map(nibbles),
+            nodes,
+            key_values: self
+                .key_values
+                .iter()
+                .map(|(key, value)| (nibbles(key.as_ref()), value.as_ref()))
+                .collect(),
+            used: 0,
+        };
+
+        let root_key: Box<[u8]> = root.key().collect();
+        if trie.node_hash(&root_key)? != *root_hash {
+            return Err(ProofError::UnexpectedHash);
+        }
+        // every key-value pair has to be somewhere in the rebuilt trie
+        if trie.used != trie.key_values.len() {
+            return Err(ProofError::NodeNotInTrie);
+        }
+        Ok(())
+    }
+}
+
+fn nibbles(key: &[u8]) -> Box<[u8]> {
+    NibblesIterator::new(key).collect()
+}
+
+/// Where the keys under a node are, relative to the range
+enum Span {
+    /// All of them are outside of it
+    Outside,
+    /// All of them are in it
+    Inside,
+    /// A bound of the range is under the node
+    Straddles,
+}
+
+/// The part of a trie a range proof shows: the nodes of the boundary
+/// proofs, and the key-value pairs between them. All keys are in nibbles.
+struct PartialTrie<'a, H> {
+    start: Option<Box<[u8]>>,
+    end: Option<Box<[u8]>>,
+    nodes: BTreeMap<Box<[u8]>, &'a H>,
+    key_values: Vec<(Box<[u8]>, &'a [u8])>,
+    /// How many of `key_values` are in the trie rebuilt so far
+    used: usize,
+}
+
+impl<H: Hashable> PartialTrie<'_, H> {
+    fn contains(&self, key: &[u8]) -> bool {
+        self.start.as_deref().is_none_or(|start| key >= start)
+            && self.end.as_deref().is_none_or(|end| key <= end)
+    }
+
+    fn span(&self, prefix: &[u8]) -> Span {
+        let start = self.start.as_deref();
+        let end = self.end.as_deref();
+        let before = start.is_some_and(|start| prefix < start && !start.starts_with(prefix));
+        let after = end.is_some_and(|end| prefix > end);
+        if before || after {
+            return Span::Outside;
+        }
+        let from_start = start.is_none_or(|start| prefix >= start);
+        let to_end = end.is_none_or(|end| prefix < end && !end.starts_with(prefix));
+        if from_start && to_end {
+            Span::Inside
+        } else {
+            Span::Straddles
+        }
+    }
+
+    /// The key-value pairs whose keys start with `prefix`
+    fn key_values_under(&self, prefix: &[u8]) -> &[(Box<[u8]>, &'_ [u8])] {
+        let from = self.key_values.partition_point(|(key, _)| **key < *prefix);
+        let to = self
+            .key_values
+            .partition_point(|(key, _)| **key < *prefix || key.starts_with(prefix));
+        self.key_values.get(from..to).unwrap_or_default()
+    }
+
+    /// The hash of the boundary proof node at `key`. The value of a node in
+    /// the range, and the children that are in it, come from the key-value
+    /// pairs; the rest come from the node.
+    fn node_hash(&mut self, key: &[u8]) -> Result<TrieHash, ProofError> {
+        let node = *self.nodes.get(key).ok_or(ProofError::MissingBoundaryNode)?;
+
+        let value_digest = if self.contains(key) {
+            let found = self
+                .key_values
+                .binary_search_by(|(k, _)| (**k).cmp(key))
+                .ok()
+                .and_then(|index| self.key_values.get(index))
+                .map(|(_, value)| *value);
+            if found.is_some() {
+                self.used += 1;
+            }
+            found.map(|value| ValueDigest::Value(Box::from(value)))
+        } else {
+            node.value_digest().map(|digest| match digest {
+                ValueDigest::Value(value) => ValueDigest::Value(Box::from(value)),
+                ValueDigest::_Hash(hash) => ValueDigest::_Hash(Box::from(hash)),
+            })
+        };
+
+        let mut child_hashes = [const { None }; BranchNode::MAX_CHILDREN];
+        for (index, child_hash) in child_hashes.iter_mut().enumerate() {
+            let proven = node
+                .children()
+                .find_map(|(i, hash)| (i == index).then(|| hash.clone()));
+            let prefix: Box<[u8]> = key.iter().copied().chain([index as u8]).collect();
+            *child_hash = match self.span(&prefix) {
+                Span::Outside => proven,
+                Span::Inside => {
+                    let under = self.key_values_under(&prefix);
+                    let (hash, count) = (subtree_hash(under), under.len());
+                    self.used += count;
+                    hash
+                }
+                Span::Straddles => {
+                    let child = self
+                        .nodes
+                        .range(prefix.clone()..)
+                        .next()
+                        .map(|(child, _)| child.clone())
+                        .filter(|child| child.starts_with(&prefix));
+                    match child {
+                        Some(child) => Some(self.node_hash(&child)?),
+                        None if proven.is_some() => return Err(ProofError::MissingBoundaryNode),
+                        None => None,
+                    }
+                }
+            };
+        }
+
+        Ok(ProofNode {
+            key: key.into(),
+            value_digest,
+            child_hashes,
+        }
+        .to_hash())
+    }
+}
+
+/// The hash of the smallest subtrie holding `key_values`, which are in
+/// order, or None if there are none
+fn subtree_hash(key_values: &[(Box<[u8]>, &[u8])]) -> Option<TrieHash> {
+    let (first, first_value) = key_values.first()?;
+    let (last, _) = key_values.last()?;
+    let shared = first
+        .iter()
+        .zip(last.iter())
+        .take_while(|(a, b)| a == b)
+        .count();
+
+    // the first key is the shortest, so only it can be at the node itself
+    let (value_digest, mut rest) = if first.len() == shared {
+        (
+            Some(ValueDigest::Value(Box::from(*first_value))),
+            key_values.get(1..).unwrap_or_default(),
+        )
+    } else {
+        (None, key_values)
+    };
+
+    let mut child_hashes = [const { None }; BranchNode::MAX_CHILDREN];
+    for (index, child_hash) in child_hashes.iter_mut().enumerate() {
+        let count = rest.partition_point(|(key, _)| key.get(shared) == Some(&(index as u8)));
+        let (under, after) = rest.split_at(count);
+        *child_hash = subtree_hash(under);
+        rest = after;
+    }
+
+    Some(
+        ProofNode {
+            key: first.get(..shared).unwrap_or_default().into(),
+            value_digest,
+            child_hashes,
+        }
+        .to_hash(),
+    )
 }
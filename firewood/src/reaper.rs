@@ -0,0 +1,133 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Reaping revisions off the commit path, with
+//! [RevisionManagerConfig](crate::manager::RevisionManagerConfig)'s
+//! `background_reaping`.
+//!
+//! Freeing the nodes of a reaped revision reads the area of each of them to
+//! find its size, so a commit that reaps a large revision is slow. With
+//! background reaping, commits only unlink the revisions they reap and send
+//! them to a thread that does those reads. The areas it looked up are handed
+//! back to the next commit, which puts them on its free lists, since only a
+//! commit may change the free lists.
+//!
+//! The areas are freed a commit or more later than they would be otherwise.
+//! Those still being looked up when the database is closed are put in the
+//! delete log, so the next open frees them, but a crash, or dropping the
+//! database without closing it, leaks them until it is compacted.
+
+use std::mem::take;
+use std::sync::mpsc::{channel, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use metrics::counter;
+use storage::logger::warn;
+use storage::{AreaIndex, Committed, FileBacked, LinearAddress, NodeStore};
+
+/// The areas looked up so far, for the next commit to free
+type Resolved = Arc<Mutex<Vec<(LinearAddress, AreaIndex)>>>;
+
+/// The thread that looks up the areas of reaped revisions
+#[derive(Debug)]
+pub(crate) struct Reaper {
+    sender: Option<Sender<NodeStore<Committed, FileBacked>>>,
+    resolved: Resolved,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reaper {
+    /// Start the thread
+    pub(crate) fn start() -> Result<Self, std::io::Error> {
+        let (sender, receiver) = channel::<NodeStore<Committed, FileBacked>>();
+        let resolved = Resolved::default();
+        let thread = std::thread::Builder::new()
+            .name("firewood-reaper".into())
+            .spawn({
+                let resolved = resolved.clone();
+                move || {
+                    for revision in receiver {
+                        reap(revision, &resolved);
+                    }
+                }
+            })?;
+        Ok(Self {
+            sender: Some(sender),
+            resolved,
+            thread: Some(thread),
+        })
+    }
+
+    /// A reaper without a thread, that looks up the areas of each revision
+    /// as it is sent, for tests that need the areas looked up right away
+    #[cfg(test)]
+    pub(crate) fn immediate() -> Self {
+        Self {
+            sender: None,
+            resolved: Resolved::default(),
+            thread: None,
+        }
+    }
+
+    /// Look up the areas of `revision` off the commit path
+    #[cfg_attr(not(feature = "logger"), allow(unused_variables))]
+    pub(crate) fn send(&self, revision: NodeStore<Committed, FileBacked>) {
+        let Some(sender) = self.sender.as_ref() else {
+            // only a reaper without a thread has no sender before it is dropped
+            reap(revision, &self.resolved);
+            return;
+        };
+        if let Err(revision) = sender.send(revision) {
+            // the thread panicked, so it would leak the areas anyway
+            warn!(
+                "Leaking {} areas of a reaped revision: the reaper stopped",
+                revision.0.deleted().len()
+            );
+        }
+    }
+
+    /// The areas looked up since the last call, for a commit to free
+    pub(crate) fn take_resolved(&self) -> Vec<(LinearAddress, AreaIndex)> {
+        take(&mut *self.resolved.lock().expect("poisoned lock"))
+    }
+
+    /// Wait for the thread to look up the areas of every revision sent to
+    /// it, and stop it. Returns the areas no commit took.
+    pub(crate) fn shut_down(mut self) -> Vec<(LinearAddress, AreaIndex)> {
+        self.stop();
+        self.take_resolved()
+    }
+
+    fn stop(&mut self) {
+        // the thread ends once the channel is empty and closed
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                warn!("The reaper panicked");
+            }
+        }
+    }
+}
+
+/// Look up the areas of `revision` for the next commit to free
+#[cfg_attr(not(feature = "logger"), allow(unused_variables))]
+fn reap(revision: NodeStore<Committed, FileBacked>, resolved: &Resolved) {
+    let deleted = revision.deleted().len();
+    match revision.resolve_deleted() {
+        Ok(areas) => {
+            counter!("firewood.reap.background", "result" => "resolved").increment(1);
+            resolved.lock().expect("poisoned lock").extend(areas);
+        }
+        Err(err) => {
+            counter!("firewood.reap.background", "result" => "failed").increment(1);
+            warn!("Leaking {deleted} areas of a reaped revision: {err}");
+        }
+    }
+}
+
+impl Drop for Reaper {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
@@ -0,0 +1,237 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The databases opened with [Db::open_shared](crate::db::Db::open_shared), so each file is open at
+//! most once in the process through it.
+//!
+//! A file is identified by its canonical path, and on unix also by its
+//! device and inode, so hard links find the same instance. An entry is
+//! added when a database starts opening and removed once the last handle
+//! to it has been dropped, after everything else in the [Db](crate::db::Db) is. Callers
+//! that find a database still opening or closing wait for it to finish.
+
+use std::fmt::Debug;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
+
+use tokio::sync::futures::OwnedNotified;
+use tokio::sync::Notify;
+
+use crate::db::{Db, DbConfig};
+use crate::v2::api;
+
+static REGISTRY: Mutex<Vec<Entry>> = Mutex::new(Vec::new());
+
+/// The identity of a database file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct FileId {
+    path: PathBuf,
+    /// The device and inode of the file, once it exists
+    inode: Option<(u64, u64)>,
+}
+
+impl FileId {
+    pub(crate) fn of(path: &Path) -> io::Result<Self> {
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            // the file doesn't exist yet, but its directory has to
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let name = path.file_name().ok_or(err)?;
+                let parent = match path.parent() {
+                    Some(parent) if !parent.as_os_str().is_empty() => parent,
+                    _ => Path::new("."),
+                };
+                parent.canonicalize()?.join(name)
+            }
+            Err(err) => return Err(err),
+        };
+        let inode = inode(&path);
+        Ok(Self { path, inode })
+    }
+
+    fn is(&self, other: &Self) -> bool {
+        self.path == other.path || (self.inode.is_some() && self.inode == other.inode)
+    }
+}
+
+#[cfg(unix)]
+fn inode(path: &Path) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt as _;
+    let metadata = std::fs::metadata(path).ok()?;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+const fn inode(_path: &Path) -> Option<(u64, u64)> {
+    None
+}
+
+#[derive(Debug)]
+struct Entry {
+    file: FileId,
+    instance: Instance,
+    /// Woken when the database finishes opening, or the entry is removed
+    changed: Arc<Notify>,
+}
+
+#[derive(Debug)]
+enum Instance {
+    Opening,
+    /// Once the last handle is dropped, it is closing until the entry is
+    /// removed
+    Open(Weak<Db>),
+}
+
+/// What [claim] found for a file
+#[derive(Debug)]
+pub(crate) enum Claim {
+    /// The open database
+    Open(Arc<Db>),
+    /// The database is opening or closing; claim it again once this
+    /// completes
+    Wait(OwnedNotified),
+    /// Nothing had it open, and the caller is to open it
+    Opening(Opening),
+}
+
+/// Find the open database for `file`, or claim the right to open it
+pub(crate) fn claim(file: &FileId) -> Claim {
+    let mut registry = REGISTRY.lock().expect("poisoned lock");
+    let Some(entry) = registry.iter().find(|entry| entry.file.is(file)) else {
+        registry.push(Entry {
+            file: file.clone(),
+            instance: Instance::Opening,
+            changed: Default::default(),
+        });
+        return Claim::Opening(Opening {
+            file: file.clone(),
+            opened: false,
+        });
+    };
+    if let Instance::Open(db) = &entry.instance {
+        if let Some(db) = db.upgrade() {
+            return Claim::Open(db);
+        }
+    }
+    // created before the lock is released, so it sees the wakeup
+    Claim::Wait(entry.changed.clone().notified_owned())
+}
+
+/// The right to open a file. Dropping it before [Opening::opened] gives the
+/// right up, as when opening fails.
+#[derive(Debug)]
+pub(crate) struct Opening {
+    file: FileId,
+    opened: bool,
+}
+
+impl Opening {
+    /// The registration to keep in `db`, which removes it from the registry
+    /// when `db` is dropped
+    pub(crate) fn registration(&self) -> Registration {
+        Registration {
+            file: self.file.clone(),
+        }
+    }
+
+    /// Record that `db` is open, and wake the callers waiting for it
+    pub(crate) fn opened(mut self, db: &Arc<Db>) {
+        let mut registry = REGISTRY.lock().expect("poisoned lock");
+        if let Some(entry) = registry.iter_mut().find(|entry| entry.file.is(&self.file)) {
+            entry.instance = Instance::Open(Arc::downgrade(db));
+            // the file exists now, even if it didn't before
+            entry.file.inode = entry.file.inode.or_else(|| inode(&entry.file.path));
+            entry.changed.notify_waiters();
+        }
+        self.opened = true;
+    }
+}
+
+impl Drop for Opening {
+    fn drop(&mut self) {
+        if !self.opened {
+            remove(&self.file);
+        }
+    }
+}
+
+/// Kept in a shared [Db] as its last field, so the registry entry is removed
+/// once everything else in it has been dropped
+#[derive(Debug)]
+pub(crate) struct Registration {
+    file: FileId,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        remove(&self.file);
+    }
+}
+
+fn remove(file: &FileId) {
+    let mut registry = REGISTRY.lock().expect("poisoned lock");
+    if let Some(index) = registry.iter().position(|entry| entry.file.is(file)) {
+        registry.swap_remove(index).changed.notify_waiters();
+    }
+}
+
+/// Why a database opened with `existing` can't be shared with a caller
+/// that asked for `requested`
+pub(crate) fn incompatibility(existing: &DbConfig, requested: &DbConfig) -> Option<api::Error> {
+    let names = |config: &DbConfig| {
+        config
+            .invariants
+            .iter()
+            .map(|invariant| invariant.name().to_string())
+            .collect::<Vec<_>>()
+    };
+    let (existing_names, requested_names) = (names(existing), names(requested));
+    let fields: [(&'static str, &dyn Debug, &dyn Debug); 11] = [
+        ("truncate", &existing.truncate, &requested.truncate),
+        ("manager", &existing.manager, &requested.manager),
+        (
+            "system_prefix",
+            &existing.system_prefix,
+            &requested.system_prefix,
+        ),
+        ("system_keys", &existing.system_keys, &requested.system_keys),
+        (
+            "external_root_authority",
+            &existing.external_root_authority,
+            &requested.external_root_authority,
+        ),
+        (
+            "retain_op_journal",
+            &existing.retain_op_journal,
+            &requested.retain_op_journal,
+        ),
+        (
+            "min_token_validity",
+            &existing.min_token_validity,
+            &requested.min_token_validity,
+        ),
+        ("invariants", &existing_names, &requested_names),
+        (
+            "value_equivalence",
+            &existing.value_equivalence,
+            &requested.value_equivalence,
+        ),
+        (
+            "equivalence_budget",
+            &existing.equivalence_budget,
+            &requested.equivalence_budget,
+        ),
+        ("read_only", &existing.read_only, &requested.read_only),
+    ];
+    let incompatible = fields.into_iter().find_map(|(field, existing, requested)| {
+        let existing = format!("{existing:?}");
+        let requested = format!("{requested:?}");
+        (existing != requested).then_some(api::Error::IncompatibleConfig {
+            field,
+            existing,
+            requested,
+        })
+    });
+    incompatible
+}
@@ -0,0 +1,211 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Restoring the database as it was at a point in time into a new file.
+//!
+//! A restore is split in two: [crate::db::Db::restore_plan] resolves a
+//! [RestoreTarget](crate::restore::RestoreTarget) to a revision and describes the steps that will run,
+//! without doing any IO, and [crate::db::Db::execute_restore] runs them.
+//! Operators can review a plan before acting on it.
+//!
+//! Only retained revisions can be restored. Commit times and sequence
+//! numbers are recorded in memory for the revisions committed since the
+//! database was opened; the revision it was opened at can only be restored
+//! by its root hash.
+
+use std::collections::VecDeque;
+use std::time::SystemTime;
+
+use storage::TrieHash;
+
+use crate::v2::api;
+
+/// The point to restore the database to
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RestoreTarget {
+    /// The retained revision with this root hash, or an empty one for None
+    RootHash(Option<TrieHash>),
+    /// The revision made by the commit with this [CommitRecord::sequence]
+    CommitSequence(u64),
+    /// The revision that was latest at this time: the one made by the last
+    /// commit at or before it, never one after it
+    Timestamp(SystemTime),
+}
+
+/// A commit made since the database was opened
+#[derive(Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct CommitRecord {
+    /// The number of commits since the database was opened, including this
+    /// one, as in [crate::db::Db::consistency_token]
+    pub sequence: u64,
+    /// When the commit made its revision the latest one
+    pub committed_at: SystemTime,
+    /// The root hash of the revision, or None if it is empty
+    pub root_hash: Option<TrieHash>,
+}
+
+/// One step of a [RestorePlan]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RestoreStep {
+    /// Create the new file and copy every entry of the retained revision
+    /// with this root hash into it, including the reserved key space
+    CopyRevision {
+        /// The root hash of the revision to copy
+        root_hash: Option<TrieHash>,
+    },
+    /// Check that the new file has this root hash
+    VerifyRootHash {
+        /// The expected root hash
+        root_hash: Option<TrieHash>,
+    },
+}
+
+/// What a restore will do, from [crate::db::Db::restore_plan]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RestorePlan {
+    /// The target the plan was made for
+    pub target: RestoreTarget,
+    /// The root hash the restored database will have
+    pub root_hash: Option<TrieHash>,
+    /// The commit that made the revision, if it was made since the database
+    /// was opened
+    pub commit: Option<CommitRecord>,
+    /// The steps [crate::db::Db::execute_restore] will run, in order
+    pub steps: Vec<RestoreStep>,
+}
+
+/// Resolve `target` against the commits in `log`, oldest first, and the
+/// revisions for which `is_retained` returns true
+pub(crate) fn plan(
+    target: RestoreTarget,
+    log: &VecDeque<CommitRecord>,
+    is_retained: impl Fn(Option<&TrieHash>) -> bool,
+) -> Result<RestorePlan, api::Error> {
+    let commit = match &target {
+        RestoreTarget::RootHash(root_hash) => log
+            .iter()
+            .rev()
+            .find(|commit| commit.root_hash == *root_hash)
+            .cloned(),
+        RestoreTarget::CommitSequence(sequence) => log
+            .iter()
+            .find(|commit| commit.sequence == *sequence)
+            .cloned(),
+        RestoreTarget::Timestamp(time) => log
+            .iter()
+            .rev()
+            .find(|commit| commit.committed_at <= *time)
+            .cloned(),
+    };
+    let root_hash = match (&target, &commit) {
+        (_, Some(commit)) => commit.root_hash.clone(),
+        (RestoreTarget::RootHash(root_hash), None) => root_hash.clone(),
+        (RestoreTarget::CommitSequence(_), None) => {
+            return Err(api::Error::RestoreTargetUnavailable {
+                target,
+                missing: "a record of the commit, which is kept while its revision is retained",
+            })
+        }
+        (RestoreTarget::Timestamp(_), None) => {
+            return Err(api::Error::RestoreTargetUnavailable {
+                target,
+                missing: "a commit at or before this time since the database was opened",
+            })
+        }
+    };
+    if !is_retained(root_hash.as_ref()) {
+        return Err(api::Error::RestoreTargetUnavailable {
+            target,
+            missing: "the revision, which has been reaped",
+        });
+    }
+
+    Ok(RestorePlan {
+        target,
+        steps: vec![
+            RestoreStep::CopyRevision {
+                root_hash: root_hash.clone(),
+            },
+            RestoreStep::VerifyRootHash {
+                root_hash: root_hash.clone(),
+            },
+        ],
+        root_hash,
+        commit,
+    })
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn log() -> VecDeque<CommitRecord> {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1000);
+        (1..=3)
+            .map(|sequence| CommitRecord {
+                sequence,
+                committed_at: start + Duration::from_secs(10 * sequence),
+                root_hash: Some(TrieHash::from([sequence as u8; 32])),
+            })
+            .collect()
+    }
+
+    fn at(secs: u64) -> RestoreTarget {
+        RestoreTarget::Timestamp(SystemTime::UNIX_EPOCH + Duration::from_secs(secs))
+    }
+
+    #[test]
+    fn timestamps_round_down() {
+        let log = log();
+        let sequence = |target| {
+            plan(target, &log, |_| true)
+                .unwrap()
+                .commit
+                .unwrap()
+                .sequence
+        };
+        assert_eq!(sequence(at(1010)), 1);
+        assert_eq!(sequence(at(1019)), 1);
+        assert_eq!(sequence(at(1020)), 2);
+        assert_eq!(sequence(at(9999)), 3);
+
+        // before the first recorded commit, and after one that was reaped
+        let err = plan(at(1009), &log, |_| true).unwrap_err();
+        assert!(matches!(err, api::Error::RestoreTargetUnavailable { .. }));
+        let retained = |hash: Option<&TrieHash>| hash != log[0].root_hash.as_ref();
+        assert!(plan(at(1015), &log, retained).is_err());
+        assert!(plan(at(1025), &log, retained).is_ok());
+    }
+
+    #[test]
+    fn targets() {
+        let log = log();
+        let plan_for = |target| plan(target, &log, |_| true).unwrap();
+
+        let by_sequence = plan_for(RestoreTarget::CommitSequence(2));
+        assert_eq!(by_sequence.root_hash, log[1].root_hash);
+        assert_eq!(
+            by_sequence.steps,
+            vec![
+                RestoreStep::CopyRevision {
+                    root_hash: log[1].root_hash.clone()
+                },
+                RestoreStep::VerifyRootHash {
+                    root_hash: log[1].root_hash.clone()
+                },
+            ]
+        );
+        assert!(plan(RestoreTarget::CommitSequence(4), &log, |_| true).is_err());
+
+        // a root hash from before the log is fine while it is retained
+        let opened = Some(TrieHash::from([9; 32]));
+        let by_hash = plan_for(RestoreTarget::RootHash(opened.clone()));
+        assert_eq!((by_hash.root_hash, by_hash.commit), (opened.clone(), None));
+        let retained = |hash: Option<&TrieHash>| hash != opened.as_ref();
+        assert!(plan(RestoreTarget::RootHash(opened.clone()), &log, retained).is_err());
+    }
+}
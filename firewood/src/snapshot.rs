@@ -0,0 +1,158 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Operational snapshots: the state of the database as each revision was
+//! committed, for postmortems that need to line operational data up with
+//! specific roots.
+//!
+//! With `RevisionManagerConfig::retain_operational_snapshots`
+//! set, every commit records an [OperationalSnapshot](crate::snapshot::OperationalSnapshot) of a few dozen bytes.
+//! Like op journals, snapshots are kept next to the database file with one
+//! file per revision, named by its root hash. They are not part of any root
+//! hash, and are removed when their revision is reaped.
+//!
+//! Everything in a snapshot is read from values the commit already has at
+//! hand or from atomic counters, so taking one adds no lock acquisitions to
+//! the commit.
+
+use std::io::{Error, ErrorKind};
+use std::time::Duration;
+
+/// The version of the snapshot encoding written by this version of firewood
+const SNAPSHOT_VERSION: u8 = 1;
+
+/// The number of fields in a version 1 snapshot
+const V1_FIELDS: usize = 10;
+
+/// No version of the encoding is longer than this, in bytes
+pub const MAX_SNAPSHOT_LEN: usize = 256;
+
+/// The state of the database when a revision was committed, as returned by
+/// [crate::db::Db::operational_snapshot]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct OperationalSnapshot {
+    /// The number of commits since the database was opened, including this
+    /// one
+    pub commit_sequence: u64,
+    /// How long reaping old revisions took in this commit
+    pub reap: Duration,
+    /// How long writing the free lists took in this commit
+    pub flush_freelist: Duration,
+    /// How long writing the new nodes took in this commit
+    pub flush_nodes: Duration,
+    /// Node cache lookups that hit since the previous commit
+    pub cache_hits: u64,
+    /// Node cache lookups that missed since the previous commit
+    pub cache_misses: u64,
+    /// The size of the store after this commit, in bytes
+    pub store_size: u64,
+    /// The total size of the free areas after this commit, in bytes
+    pub free_bytes: u64,
+    /// The number of retained revisions over the configured maximum, which
+    /// couldn't be reaped because they were still in use
+    pub reap_backlog: u64,
+    /// The number of outstanding proposals, including the committed one
+    pub proposals: u64,
+}
+
+impl OperationalSnapshot {
+    /// The fraction of node cache lookups since the previous commit that
+    /// hit, or None if there were none
+    pub fn cache_hit_rate(&self) -> Option<f64> {
+        let lookups = self.cache_hits + self.cache_misses;
+        (lookups > 0).then(|| self.cache_hits as f64 / lookups as f64)
+    }
+
+    /// Encode the snapshot with the current version of the encoding
+    pub(crate) fn encode(&self) -> Box<[u8]> {
+        let nanos = |duration: Duration| u64::try_from(duration.as_nanos()).unwrap_or(u64::MAX);
+        let fields: [u64; V1_FIELDS] = [
+            self.commit_sequence,
+            nanos(self.reap),
+            nanos(self.flush_freelist),
+            nanos(self.flush_nodes),
+            self.cache_hits,
+            self.cache_misses,
+            self.store_size,
+            self.free_bytes,
+            self.reap_backlog,
+            self.proposals,
+        ];
+        std::iter::once(SNAPSHOT_VERSION)
+            .chain(fields.iter().flat_map(|field| field.to_le_bytes()))
+            .collect()
+    }
+
+    /// Decode a snapshot written by this or an earlier version of firewood
+    pub(crate) fn decode(bytes: &[u8]) -> Result<Self, Error> {
+        let invalid = |what: &str| Error::new(ErrorKind::InvalidData, format!("snapshot: {what}"));
+        if bytes.len() > MAX_SNAPSHOT_LEN {
+            return Err(invalid("too long"));
+        }
+        let Some((version, rest)) = bytes.split_first() else {
+            return Err(invalid("empty"));
+        };
+        match *version {
+            1 => {
+                let mut fields = rest.chunks_exact(8);
+                let mut next = || {
+                    fields
+                        .next()
+                        .and_then(|field| field.try_into().ok())
+                        .map(u64::from_le_bytes)
+                        .ok_or_else(|| invalid("truncated"))
+                };
+                let snapshot = Self {
+                    commit_sequence: next()?,
+                    reap: Duration::from_nanos(next()?),
+                    flush_freelist: Duration::from_nanos(next()?),
+                    flush_nodes: Duration::from_nanos(next()?),
+                    cache_hits: next()?,
+                    cache_misses: next()?,
+                    store_size: next()?,
+                    free_bytes: next()?,
+                    reap_backlog: next()?,
+                    proposals: next()?,
+                };
+                if rest.len() != V1_FIELDS * 8 {
+                    return Err(invalid("wrong length"));
+                }
+                Ok(snapshot)
+            }
+            version => Err(invalid(&format!("unknown version {version}"))),
+        }
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        let snapshot = OperationalSnapshot {
+            commit_sequence: 3,
+            reap: Duration::from_micros(5),
+            flush_nodes: Duration::from_millis(2),
+            cache_hits: 3,
+            cache_misses: 1,
+            store_size: 4096,
+            proposals: 1,
+            ..Default::default()
+        };
+        let bytes = snapshot.encode();
+        assert!(bytes.len() <= MAX_SNAPSHOT_LEN);
+        assert_eq!(OperationalSnapshot::decode(&bytes).unwrap(), snapshot);
+        assert_eq!(snapshot.cache_hit_rate(), Some(0.75));
+        assert_eq!(OperationalSnapshot::default().cache_hit_rate(), None);
+
+        assert!(OperationalSnapshot::decode(&bytes[..bytes.len() - 1]).is_err());
+        assert!(OperationalSnapshot::decode(&[bytes.as_ref(), &[0]].concat()).is_err());
+        assert!(OperationalSnapshot::decode(&[]).is_err());
+        let mut unknown = bytes.to_vec();
+        unknown[0] = 2;
+        assert!(OperationalSnapshot::decode(&unknown).is_err());
+    }
+}
@@ -0,0 +1,260 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! How big the database is and how its space is used, as returned by
+//! [crate::db::Db::statistics], for capacity planning and for deciding when
+//! to compact.
+//!
+//! The sizes of the file and of its free and allocated space come from the
+//! header and cost nothing to read. The free lists and the trie of the
+//! latest revision are only walked when asked for, since that reads every
+//! free area and every node. All of it is exact; nothing is sampled.
+//!
+//! The trie of any retained revision can be walked as well, with
+//! [crate::db::Snapshot::statistics], or estimated from a few paths down
+//! it with [crate::db::Snapshot::estimate_statistics] when it is too large
+//! to read every node.
+
+use std::collections::BTreeMap;
+use std::io::Error;
+
+use std::sync::Arc;
+
+use storage::{BranchNode, Child, Node, NodeReader as _, Parentable as _};
+
+use crate::manager::CommittedRevision;
+
+/// The size and shape of the database
+#[derive(Clone, Debug, Default, PartialEq)]
+#[non_exhaustive]
+pub struct DbStatistics {
+    /// The size of the database file, in bytes
+    pub file_size: u64,
+    /// The total size of the areas that aren't free, in bytes. Besides the
+    /// nodes of the retained revisions, this includes areas leaked by a
+    /// crash.
+    pub allocated_bytes: u64,
+    /// The total size of the free areas, in bytes. Databases created before
+    /// this was tracked only count the areas freed since.
+    pub free_bytes: u64,
+    /// The number of committed revisions retained
+    pub revisions: usize,
+    /// The free lists, by size class, smallest first, if asked for
+    pub free_lists: Option<Vec<FreeListStatistics>>,
+    /// The trie of the latest revision, if asked for
+    pub latest: Option<TrieStatistics>,
+}
+
+/// The free areas of one size
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct FreeListStatistics {
+    /// The size of each area, in bytes
+    pub area_size: u64,
+    /// The number of areas
+    pub areas: u64,
+    /// Their total size, in bytes
+    pub bytes: u64,
+}
+
+/// The nodes, keys and values of a trie
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct TrieStatistics {
+    /// The number of branch nodes
+    pub branches: u64,
+    /// The number of leaf nodes
+    pub leaves: u64,
+    /// The number of keys
+    pub keys: u64,
+    /// The total length of the keys, in bytes
+    pub key_bytes: u64,
+    /// The total length of the values, in bytes
+    pub value_bytes: u64,
+    /// The most nodes on the path from the root to a key, including the
+    /// root and the node with the key
+    pub max_depth: usize,
+    /// The average number of nodes on the path from the root to a key, or 0
+    /// if there are no keys
+    pub avg_depth: f64,
+}
+
+impl TrieStatistics {
+    /// The number of nodes, branches and leaves
+    pub const fn nodes(&self) -> u64 {
+        self.branches + self.leaves
+    }
+}
+
+/// The free areas of `revision`, by size class
+pub(crate) fn free_lists(revision: &CommittedRevision) -> Result<Vec<FreeListStatistics>, Error> {
+    let mut classes = BTreeMap::new();
+    for area in revision.free_list_areas()? {
+        let class = classes
+            .entry(area.size)
+            .or_insert_with(|| FreeListStatistics {
+                area_size: area.size,
+                ..Default::default()
+            });
+        class.areas += 1;
+        class.bytes += area.size;
+    }
+    Ok(classes.into_values().collect())
+}
+
+/// Walk the whole trie of `revision`
+pub(crate) fn trie(revision: &CommittedRevision) -> Result<TrieStatistics, Error> {
+    let mut walk = Walk::default();
+    if let Some(root) = revision.root_address() {
+        walk.node(revision, &*revision.read_node(root)?, 0, 1)?;
+    }
+    let mut statistics = walk.statistics;
+    if statistics.keys > 0 {
+        statistics.avg_depth = walk.total_depth as f64 / statistics.keys as f64;
+    }
+    Ok(statistics)
+}
+
+/// Estimate the trie of `revision` from `probes` paths from the root down
+/// to a leaf, taking a child at random at each branch. A node on a path
+/// stands for as many nodes as the product of the numbers of children of
+/// the branches above it, which makes the counts and totals unbiased
+/// estimates (Knuth's estimator). `max_depth` is the deepest key found, so
+/// it can be less than the real one.
+///
+/// Children are picked the same way every time for a revision, so its
+/// estimate doesn't change.
+pub(crate) fn estimate_trie(
+    revision: &CommittedRevision,
+    probes: usize,
+) -> Result<TrieStatistics, Error> {
+    let Some(root) = revision.root_address() else {
+        return Ok(TrieStatistics::default());
+    };
+    let root = revision.read_node(root)?;
+    let seed = revision
+        .kind
+        .root_hash()
+        .and_then(|hash| hash.get(..8)?.try_into().ok())
+        .map_or(0, u64::from_le_bytes);
+    let mut random = SplitMix(seed);
+
+    let probes = probes.max(1);
+    let mut estimate = Estimate::default();
+    for _ in 0..probes {
+        let mut node = root.clone();
+        let mut weight = 1.0;
+        let (mut nibbles, mut depth) = (0, 1);
+        loop {
+            nibbles += node.partial_path().len();
+            if let Some(value) = node.value() {
+                estimate.keys += weight;
+                estimate.key_bytes += weight * (nibbles / BranchNode::NIBBLES_PER_BYTE) as f64;
+                estimate.value_bytes += weight * value.len() as f64;
+                estimate.total_depth += weight * depth as f64;
+                estimate.max_depth = estimate.max_depth.max(depth);
+            }
+            let Node::Branch(branch) = &*node else {
+                estimate.leaves += weight;
+                break;
+            };
+            estimate.branches += weight;
+            let children: Vec<_> = branch.children.iter().flatten().collect();
+            let Some(child) = children.get(random.below(children.len())) else {
+                break;
+            };
+            weight *= children.len() as f64;
+            node = match child {
+                Child::Node(child) => Arc::new(child.clone()),
+                Child::AddressWithHash(addr, _) => revision.read_node(*addr)?,
+            };
+            nibbles += 1;
+            depth += 1;
+        }
+    }
+
+    let average = |total: f64| (total / probes as f64).round() as u64;
+    Ok(TrieStatistics {
+        branches: average(estimate.branches),
+        leaves: average(estimate.leaves),
+        keys: average(estimate.keys),
+        key_bytes: average(estimate.key_bytes),
+        value_bytes: average(estimate.value_bytes),
+        max_depth: estimate.max_depth,
+        avg_depth: match estimate.keys > 0.0 {
+            true => estimate.total_depth / estimate.keys,
+            false => 0.0,
+        },
+    })
+}
+
+/// The totals of [estimate_trie]'s paths, before they are averaged
+#[derive(Default)]
+struct Estimate {
+    branches: f64,
+    leaves: f64,
+    keys: f64,
+    key_bytes: f64,
+    value_bytes: f64,
+    /// The sum of the depths of the keys
+    total_depth: f64,
+    max_depth: usize,
+}
+
+/// The SplitMix64 generator, which is enough to pick children
+struct SplitMix(u64);
+
+impl SplitMix {
+    /// A number below `n`, or 0 if `n` is 0
+    fn below(&mut self, n: usize) -> usize {
+        self.0 = self.0.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^= z >> 31;
+        z.checked_rem(n as u64).unwrap_or_default() as usize
+    }
+}
+
+#[derive(Default)]
+struct Walk {
+    statistics: TrieStatistics,
+    /// The sum of the depths of the keys
+    total_depth: u64,
+}
+
+impl Walk {
+    /// Count `node`, whose key is `nibbles` nibbles long up to its partial
+    /// path and which is `depth` nodes down, and the nodes below it
+    fn node(
+        &mut self,
+        revision: &CommittedRevision,
+        node: &Node,
+        nibbles: usize,
+        depth: usize,
+    ) -> Result<(), Error> {
+        let nibbles = nibbles + node.partial_path().len();
+        if let Some(value) = node.value() {
+            let statistics = &mut self.statistics;
+            statistics.keys += 1;
+            statistics.key_bytes += (nibbles / BranchNode::NIBBLES_PER_BYTE) as u64;
+            statistics.value_bytes += value.len() as u64;
+            statistics.max_depth = statistics.max_depth.max(depth);
+            self.total_depth += depth as u64;
+        }
+
+        let Node::Branch(branch) = node else {
+            self.statistics.leaves += 1;
+            return Ok(());
+        };
+        self.statistics.branches += 1;
+        for child in branch.children.iter().flatten() {
+            match child {
+                Child::Node(child) => self.node(revision, child, nibbles + 1, depth + 1)?,
+                Child::AddressWithHash(addr, _) => {
+                    let child = revision.read_node(*addr)?;
+                    self.node(revision, &child, nibbles + 1, depth + 1)?
+                }
+            }
+        }
+        Ok(())
+    }
+}
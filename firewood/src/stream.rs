@@ -1,6 +1,7 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
+use crate::latency::{ApiMethod, OperationTimer};
 use crate::merkle::{Key, MerkleError, Value};
 use crate::v2::api;
 
@@ -26,7 +27,7 @@ enum IterationNode {
         key: Key,
         /// Returns the non-empty children of this node and their positions
         /// in the node's children array.
-        children_iter: Box<dyn Iterator<Item = (u8, Child)> + Send>,
+        children_iter: std::vec::IntoIter<(u8, Child)>,
     },
 }
 
@@ -117,11 +118,18 @@ impl<T: TrieReader> Stream for MerkleNodeStream<'_, T> {
                                 Node::Leaf(_) => {}
                                 Node::Branch(branch) => {
                                     // `node` is a branch node. Visit its children next.
+                                    let children: Vec<_> =
+                                        as_enumerated_children_iter(branch).collect();
+                                    // the first child is read next, so read
+                                    // ahead from the one after it
+                                    let width = merkle.read_ahead().width;
+                                    read_ahead(
+                                        *merkle,
+                                        children.iter().skip(1).take(width.saturating_sub(1)),
+                                    );
                                     iter_stack.push(IterationNode::Visited {
                                         key: key.clone(),
-                                        children_iter: Box::new(as_enumerated_children_iter(
-                                            branch,
-                                        )),
+                                        children_iter: children.into_iter(),
                                     });
                                 }
                             }
@@ -139,6 +147,15 @@ impl<T: TrieReader> Stream for MerkleNodeStream<'_, T> {
                                 continue;
                             };
 
+                            // The next `width` siblings are read ahead while
+                            // `child` is visited; the last of them is new.
+                            if let Some(last) = merkle.read_ahead().width.checked_sub(1) {
+                                read_ahead(
+                                    *merkle,
+                                    children_iter.as_slice().iter().skip(last).take(1),
+                                );
+                            }
+
                             let child = match child {
                                 Child::AddressWithHash(addr, _) => merkle.read_node(addr)?,
                                 Child::Node(node) => Arc::new(node.clone()),
@@ -218,6 +235,10 @@ fn get_iterator_intial_state<T: TrieReader>(
             }
             Ordering::Equal => match &*node {
                 Node::Leaf(_) => {
+                    if unmatched_key_nibbles.next().is_some() {
+                        // `node`'s key is a strict prefix of `key`, so it's before `key`.
+                        return Ok(NodeStreamState::Iterating { iter_stack });
+                    }
                     iter_stack.push(IterationNode::Unvisited {
                         key: matched_key_nibbles.clone().into_boxed_slice(),
                         node,
@@ -238,12 +259,14 @@ fn get_iterator_intial_state<T: TrieReader>(
                     // There is no child at `next_unmatched_key_nibble`.
                     // We'll visit `node`'s first child at index > `next_unmatched_key_nibble`
                     // first (if it exists).
+                    let children: Vec<_> = as_enumerated_children_iter(branch)
+                        .filter(|(pos, _)| *pos > next_unmatched_key_nibble)
+                        .collect();
+                    // they are visited after the child at `next_unmatched_key_nibble`
+                    read_ahead(merkle, children.iter().take(merkle.read_ahead().width));
                     iter_stack.push(IterationNode::Visited {
                         key: matched_key_nibbles.clone().into_boxed_slice(),
-                        children_iter: Box::new(
-                            as_enumerated_children_iter(branch)
-                                .filter(move |(pos, _)| *pos > next_unmatched_key_nibble),
-                        ),
+                        children_iter: children.into_iter(),
                     });
 
                     #[allow(clippy::indexing_slicing)]
@@ -269,6 +292,8 @@ enum MerkleKeyValueStreamState<'a, T> {
     /// The iterator works by iterating over the nodes in the merkle trie
     /// and returning the key-value pairs for nodes that have values.
     Initialized { node_iter: MerkleNodeStream<'a, T> },
+    /// The iterator has passed the last key with its prefix
+    Exhausted,
 }
 
 impl<T, K: AsRef<[u8]>> From<K> for MerkleKeyValueStreamState<'_, T> {
@@ -289,6 +314,12 @@ impl<T: TrieReader> MerkleKeyValueStreamState<'_, T> {
 pub struct MerkleKeyValueStream<'a, T> {
     state: MerkleKeyValueStreamState<'a, T>,
     merkle: &'a T,
+    /// If set, the stream ends at the first key without this prefix
+    prefix: Option<Key>,
+    /// If set, the stream ends at the first key that isn't before this one
+    end: Option<Key>,
+    /// If set, keys with this prefix are skipped
+    hidden: Option<Key>,
 }
 
 impl<'a, T: TrieReader> From<&'a T> for MerkleKeyValueStream<'a, T> {
@@ -296,13 +327,20 @@ impl<'a, T: TrieReader> From<&'a T> for MerkleKeyValueStream<'a, T> {
         Self {
             state: MerkleKeyValueStreamState::_new(),
             merkle,
+            prefix: None,
+            end: None,
+            hidden: None,
         }
     }
 }
 
 impl<T: TrieReader> FusedStream for MerkleKeyValueStream<'_, T> {
     fn is_terminated(&self) -> bool {
-        matches!(&self.state, MerkleKeyValueStreamState::Initialized { node_iter } if node_iter.is_terminated())
+        match &self.state {
+            MerkleKeyValueStreamState::_Uninitialized(_) => false,
+            MerkleKeyValueStreamState::Initialized { node_iter } => node_iter.is_terminated(),
+            MerkleKeyValueStreamState::Exhausted => true,
+        }
     }
 }
 
@@ -313,46 +351,118 @@ impl<'a, T: TrieReader> MerkleKeyValueStream<'a, T> {
         Self {
             state: MerkleKeyValueStreamState::from(key.as_ref()),
             merkle,
+            prefix: None,
+            end: None,
+            hidden: None,
+        }
+    }
+
+    /// Construct a [MerkleKeyValueStream] that will iterate over the key-value pairs in `merkle`
+    /// whose keys start with `prefix`
+    pub fn from_prefix<K: AsRef<[u8]>>(merkle: &'a T, prefix: K) -> Self {
+        Self {
+            prefix: Some(prefix.as_ref().into()),
+            ..Self::from_key(merkle, prefix)
         }
     }
+
+    /// Construct a [MerkleKeyValueStream] that will iterate over the key-value pairs in `merkle`
+    /// whose keys are at least `start` and before `end`. It is empty if `end` isn't after `start`.
+    pub fn from_range<K: AsRef<[u8]>>(merkle: &'a T, start: K, end: K) -> Self {
+        let mut stream = Self {
+            end: Some(end.as_ref().into()),
+            ..Self::from_key(merkle, start.as_ref())
+        };
+        if end.as_ref() <= start.as_ref() {
+            stream.state = MerkleKeyValueStreamState::Exhausted;
+        }
+        stream
+    }
+
+    /// Skip the keys that start with `hidden`, if it is set
+    pub(crate) fn hiding(mut self, hidden: Option<&[u8]>) -> Self {
+        self.hidden = hidden.map(Into::into);
+        self
+    }
 }
 
 impl<T: TrieReader> Stream for MerkleKeyValueStream<'_, T> {
     type Item = Result<(Key, Value), api::Error>;
 
     fn poll_next(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        let timer = OperationTimer::start(ApiMethod::Iterate);
+        let next = self.poll_next_entry(cx);
+        if let Poll::Ready(entry) = &next {
+            let key = match entry {
+                Some(Ok((key, _))) => Some(&**key),
+                _ => None,
+            };
+            timer.finish(key, || None);
+        }
+        next
+    }
+}
+
+impl<T: TrieReader> MerkleKeyValueStream<'_, T> {
+    /// [Stream::poll_next], without timing it
+    fn poll_next_entry(
         mut self: std::pin::Pin<&mut Self>,
         _cx: &mut std::task::Context<'_>,
-    ) -> Poll<Option<Self::Item>> {
+    ) -> Poll<Option<Result<(Key, Value), api::Error>>> {
         // destructuring is necessary here because we need mutable access to `key_state`
         // at the same time as immutable access to `merkle`
-        let Self { state, merkle } = &mut *self;
+        let Self { state, merkle, .. } = &mut *self;
 
         match state {
+            MerkleKeyValueStreamState::Exhausted => Poll::Ready(None),
             MerkleKeyValueStreamState::_Uninitialized(key) => {
                 let iter = MerkleNodeStream::new(*merkle, key.clone());
                 self.state = MerkleKeyValueStreamState::Initialized { node_iter: iter };
-                self.poll_next(_cx)
+                self.poll_next_entry(_cx)
             }
             MerkleKeyValueStreamState::Initialized { node_iter: iter } => {
                 match iter.poll_next_unpin(_cx) {
                     Poll::Ready(node) => match node {
-                        Some(Ok((key, node))) => match &*node {
-                            Node::Branch(branch) => {
-                                let Some(value) = branch.value.as_ref() else {
-                                    // This node doesn't have a value to return.
-                                    // Continue to the next node.
-                                    return self.poll_next(_cx);
-                                };
-
-                                let value = value.to_vec();
-                                Poll::Ready(Some(Ok((key, value))))
+                        Some(Ok((key, node))) => {
+                            if self
+                                .prefix
+                                .as_ref()
+                                .is_some_and(|prefix| !key.starts_with(prefix))
+                            {
+                                // Keys are in order, so no later key has the prefix either.
+                                self.state = MerkleKeyValueStreamState::Exhausted;
+                                return Poll::Ready(None);
                             }
-                            Node::Leaf(leaf) => {
-                                let value = leaf.value.to_vec();
-                                Poll::Ready(Some(Ok((key, value))))
+                            if self.end.as_ref().is_some_and(|end| key >= *end) {
+                                // Nor is any later key before the end.
+                                self.state = MerkleKeyValueStreamState::Exhausted;
+                                return Poll::Ready(None);
                             }
-                        },
+
+                            let value = match &*node {
+                                Node::Branch(branch) => {
+                                    let Some(value) = branch.value.as_ref() else {
+                                        // This node doesn't have a value to return.
+                                        // Continue to the next node.
+                                        return self.poll_next_entry(_cx);
+                                    };
+                                    value.to_vec()
+                                }
+                                Node::Leaf(leaf) => leaf.value.to_vec(),
+                            };
+
+                            if self
+                                .hidden
+                                .as_ref()
+                                .is_some_and(|hidden| key.starts_with(hidden))
+                            {
+                                return self.poll_next_entry(_cx);
+                            }
+                            Poll::Ready(Some(Ok((key, value))))
+                        }
                         Some(Err(e)) => Poll::Ready(Some(Err(e))),
                         None => Poll::Ready(None),
                     },
@@ -375,6 +485,8 @@ enum PathIteratorState<'a> {
         matched_key: Vec<u8>,
         unmatched_key: NibblesIterator<'a>,
         node: Arc<Node>,
+        /// The number of levels `node` is below the root
+        level: usize,
     },
     Exhausted,
 }
@@ -405,6 +517,7 @@ impl<'a, 'b, T: TrieReader> PathIterator<'a, 'b, T> {
                 matched_key: vec![],
                 unmatched_key: NibblesIterator::new(key),
                 node: root,
+                level: 0,
             },
         })
     }
@@ -424,6 +537,7 @@ impl<T: TrieReader> Iterator for PathIterator<'_, '_, T> {
                 matched_key,
                 unmatched_key,
                 node,
+                level,
             } => {
                 let partial_path = match &**node {
                     Node::Branch(branch) => &branch.partial_path,
@@ -482,7 +596,9 @@ impl<T: TrieReader> Iterator for PathIterator<'_, '_, T> {
                                         }))
                                     }
                                     Some(Child::AddressWithHash(child_addr, _)) => {
-                                        let child = match merkle.read_node(*child_addr) {
+                                        let child = match merkle
+                                            .read_node_at_depth(*child_addr, *level + 1)
+                                        {
                                             Ok(child) => child,
                                             Err(e) => return Some(Err(e.into())),
                                         };
@@ -492,6 +608,7 @@ impl<T: TrieReader> Iterator for PathIterator<'_, '_, T> {
 
                                         let ret = node.clone();
                                         *node = child;
+                                        *level += 1;
 
                                         Some(Ok(PathIterItem {
                                             key_nibbles: node_key,
@@ -505,6 +622,7 @@ impl<T: TrieReader> Iterator for PathIterator<'_, '_, T> {
 
                                         let ret = node.clone();
                                         *node = Arc::new(child.clone());
+                                        *level += 1;
 
                                         Some(Ok(PathIterItem {
                                             key_nibbles: node_key,
@@ -554,6 +672,20 @@ where
     (Ordering::Equal, unmatched_key_nibbles_iter)
 }
 
+/// Ask `merkle` to read the stored nodes of `children` in the background,
+/// so they are there when the iteration reaches them
+fn read_ahead<'a, T: TrieReader>(merkle: &T, children: impl Iterator<Item = &'a (u8, Child)>) {
+    let addrs: Vec<_> = children
+        .filter_map(|(_, child)| match child {
+            Child::AddressWithHash(addr, _) => Some(*addr),
+            Child::Node(_) => None,
+        })
+        .collect();
+    if !addrs.is_empty() {
+        merkle.prefetch(&addrs);
+    }
+}
+
 /// Returns an iterator that returns (`pos`,`child`) for each non-empty child of `branch`,
 /// where `pos` is the position of the child in `branch`'s children array.
 fn as_enumerated_children_iter(branch: &BranchNode) -> impl Iterator<Item = (u8, Child)> {
@@ -566,12 +698,12 @@ fn as_enumerated_children_iter(branch: &BranchNode) -> impl Iterator<Item = (u8,
 }
 
 #[cfg(feature = "branch_factor_256")]
-fn key_from_nibble_iter<Iter: Iterator<Item = u8>>(nibbles: Iter) -> Key {
+pub(crate) fn key_from_nibble_iter<Iter: Iterator<Item = u8>>(nibbles: Iter) -> Key {
     nibbles.collect()
 }
 
 #[cfg(not(feature = "branch_factor_256"))]
-fn key_from_nibble_iter<Iter: Iterator<Item = u8>>(mut nibbles: Iter) -> Key {
+pub(crate) fn key_from_nibble_iter<Iter: Iterator<Item = u8>>(mut nibbles: Iter) -> Key {
     let mut data = Vec::with_capacity(nibbles.size_hint().0 / 2);
 
     while let (Some(hi), Some(lo)) = (nibbles.next(), nibbles.next()) {
@@ -1383,6 +1515,120 @@ mod tests {
         check_stream_is_done(stream).await;
     }
 
+    #[tokio::test]
+    async fn key_value_start_after_leaf_prefix() {
+        let mut merkle = create_test_merkle();
+        merkle.insert(&[0xab], Box::new([0x01])).unwrap();
+
+        let stream = merkle.key_value_iter_from_key([0xab, 0xcd]);
+
+        check_stream_is_done(stream).await;
+    }
+
+    #[tokio::test]
+    async fn key_value_prefix_stops_after_prefix() {
+        let mut merkle = create_test_merkle();
+        for key in [[0x12, 0x34], [0x12, 0x35], [0x12, 0x40], [0x13, 0x00]] {
+            merkle.insert(&key, key.into()).unwrap();
+        }
+
+        let mut stream = merkle.key_value_iter_prefix([0x12, 0x3]);
+        check_stream_is_done(&mut stream).await;
+
+        let mut stream = merkle.key_value_iter_prefix([0x12]);
+        for key in [[0x12, 0x34], [0x12, 0x35], [0x12, 0x40]] {
+            assert_eq!(&*stream.next().await.unwrap().unwrap().0, key);
+        }
+        check_stream_is_done(stream).await;
+    }
+
+    #[tokio::test]
+    async fn key_value_range_stops_before_end() {
+        let mut merkle = create_test_merkle();
+        let keys = [
+            vec![0x12, 0x34, 0x56],
+            vec![0x12, 0x34, 0x57],
+            vec![0x12, 0x34, 0x60],
+            vec![0x13],
+        ];
+        for key in &keys {
+            merkle.insert(key, key.clone().into()).unwrap();
+        }
+
+        // the start ends partway through the partial path the keys share
+        let (start, end) = ([0x12, 0x30].as_slice(), [0x12, 0x34, 0x60].as_slice());
+        let mut stream = merkle.key_value_iter_range(start, end);
+        for key in keys.iter().take(2) {
+            assert_eq!(&*stream.next().await.unwrap().unwrap().0, key.as_slice());
+        }
+        check_stream_is_done(stream).await;
+
+        // an empty or inverted range has no keys
+        let stream = merkle.key_value_iter_range([0x12].as_slice(), &[0x12][..]);
+        check_stream_is_done(stream).await;
+        let stream = merkle.key_value_iter_range([0x13].as_slice(), &[0x12][..]);
+        check_stream_is_done(stream).await;
+    }
+
+    #[tokio::test]
+    async fn key_value_matches_btreemap() {
+        use rand::{rngs::StdRng, Rng, SeedableRng};
+        use std::collections::BTreeMap;
+
+        // short keys over a small alphabet, so many are prefixes of others
+        // and start keys often end partway through a partial path
+        let mut rng = StdRng::seed_from_u64(42);
+        let mut random_key = |max_len| -> Vec<u8> {
+            let len = rng.gen_range(0..=max_len);
+            (0..len).map(|_| rng.gen_range(0..4) * 0x11).collect()
+        };
+        let mut merkle = create_test_merkle();
+        let mut expected = BTreeMap::new();
+        for _ in 0..20_000 {
+            let key = random_key(8);
+            merkle.insert(&key, key.clone().into()).unwrap();
+            expected.insert(key.clone(), key);
+        }
+        // iterating a hashed trie reads children instead of cloning them
+        let merkle = merkle.hash();
+        let bounds: Vec<_> = (0..50).map(|_| random_key(6)).collect();
+
+        for start in &bounds {
+            let stream = merkle.key_value_iter_from_key(start);
+            let actual: Vec<_> = stream.map(|kv| kv.unwrap()).collect().await;
+            let actual: Vec<_> = actual.into_iter().map(|(key, _)| key.to_vec()).collect();
+            let wanted: Vec<_> = expected
+                .range(start.clone()..)
+                .map(|(key, _)| key.clone())
+                .collect();
+            assert_eq!(actual, wanted, "start {start:?}");
+        }
+
+        for prefix in &bounds {
+            let stream = merkle.key_value_iter_prefix(prefix);
+            let actual: Vec<_> = stream.map(|kv| kv.unwrap()).collect().await;
+            let actual: Vec<_> = actual.into_iter().map(|(key, _)| key.to_vec()).collect();
+            let wanted: Vec<_> = expected
+                .range(prefix.clone()..)
+                .map(|(key, _)| key.clone())
+                .take_while(|key| key.starts_with(prefix))
+                .collect();
+            assert_eq!(actual, wanted, "prefix {prefix:?}");
+        }
+
+        for (start, end) in bounds.iter().zip(bounds.iter().rev()) {
+            let stream = merkle.key_value_iter_range(start, end);
+            let actual: Vec<_> = stream.map(|kv| kv.unwrap()).collect().await;
+            let actual: Vec<_> = actual.into_iter().map(|(key, _)| key.to_vec()).collect();
+            let wanted: Vec<_> = expected
+                .range(start.clone()..)
+                .map(|(key, _)| key.clone())
+                .take_while(|key| key < end)
+                .collect();
+            assert_eq!(actual, wanted, "range {start:?}..{end:?}");
+        }
+    }
+
     async fn check_stream_is_done<S>(mut stream: S)
     where
         S: FusedStream + Unpin,
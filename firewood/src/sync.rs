@@ -0,0 +1,212 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use std::sync::{Arc, Mutex};
+
+use futures::StreamExt as _;
+use storage::{FileBacked, ImmutableProposal, NodeStore, Parentable as _, TrieHash, TrieReader};
+use tokio::sync::RwLock;
+
+use crate::change_proof::ChangeProof;
+use crate::db::commit_changes;
+use crate::manager::RevisionManager;
+use crate::merkle::{Key, Merkle, Value};
+use crate::proof::{ProofError, ProofNode};
+use crate::range_proof::RangeProof;
+use crate::v2::api::{self, HashKey};
+
+/// How far applying proofs has synced the database to a target root, as
+/// returned by [crate::db::Db::apply_range_proof] and
+/// [crate::db::Db::apply_change_proof]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SyncStatus {
+    /// The keys before `next_key` match the target, and the next proof has
+    /// to start at it
+    Partial {
+        /// The smallest key after the range that was applied
+        next_key: Box<[u8]>,
+    },
+    /// The latest revision has the target root
+    Complete,
+}
+
+/// Where a sync to `target` has got: every key before `next_key` has the
+/// value it has in the target
+#[derive(Clone, Debug)]
+pub(crate) struct SyncFrontier {
+    pub(crate) target: HashKey,
+    pub(crate) next_key: Box<[u8]>,
+}
+
+/// Applies proofs to the latest revision of a database, and tracks where
+/// the sync they are part of left off
+#[derive(Debug, Default)]
+pub(crate) struct SyncTracker {
+    /// Where the sync left off, if one is in progress
+    frontier: Mutex<Option<SyncFrontier>>,
+}
+
+impl SyncTracker {
+    /// See [crate::db::Db::apply_range_proof]
+    pub(crate) async fn apply_range_proof(
+        &self,
+        manager: &RwLock<RevisionManager>,
+        target: TrieHash,
+        proof: &RangeProof<Box<[u8]>, Box<[u8]>, ProofNode>,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<SyncStatus, api::Error> {
+        proof.verify(&target, start, end)?;
+        let range_end = proof.key_values.last().map(|(key, _)| &**key).or(end);
+
+        let mut manager = manager.write().await;
+        self.check_frontier(&target, start)?;
+        let current = Merkle::from(manager.current_revision());
+        let mut changes = Vec::new();
+        let mut proven = proof.key_values.iter().peekable();
+        for (key, value) in range_key_values(&current, start, range_end).await? {
+            while let Some((proven_key, proven_value)) = proven.next_if(|(k, _)| *k < key) {
+                changes.push((proven_key.clone(), Some(proven_value.to_vec())));
+            }
+            match proven.next_if(|(k, _)| *k == key) {
+                Some((_, proven_value)) if **proven_value == *value => {}
+                Some((_, proven_value)) => changes.push((key, Some(proven_value.to_vec()))),
+                None => changes.push((key, None)),
+            }
+        }
+        changes.extend(proven.map(|(key, value)| (key.clone(), Some(value.to_vec()))));
+
+        let height = manager.latest_height() + 1;
+        commit_changes(&mut manager, changes, height)?;
+        self.advance(&manager, target, range_end)
+    }
+
+    /// See [crate::db::Db::apply_change_proof]
+    pub(crate) async fn apply_change_proof(
+        &self,
+        manager: &RwLock<RevisionManager>,
+        target: TrieHash,
+        proof: &ChangeProof<Box<[u8]>, Box<[u8]>, ProofNode>,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+    ) -> Result<SyncStatus, api::Error> {
+        let range_end = proof.range_end(start, end)?;
+
+        let mut manager = manager.write().await;
+        self.check_frontier(&target, start)?;
+        let mut merkle = Merkle::from(NodeStore::new(manager.current_revision())?);
+        for (key, value) in proof.key_changes.iter() {
+            match value {
+                Some(value) => merkle.insert(key, value.clone())?,
+                None => {
+                    merkle.remove(key)?;
+                }
+            }
+        }
+        let proposal: Arc<NodeStore<Arc<ImmutableProposal>, FileBacked>> =
+            Arc::new(NodeStore::try_from_mutable(merkle.into_inner())?);
+
+        // the range now has to hold what the target holds there
+        if start.is_none() && range_end.is_none() {
+            // nothing is left to prove it with; the whole trie has to match
+            if proposal.kind.root_hash().as_ref() != Some(&target) {
+                return Err(ProofError::UnexpectedHash.into());
+            }
+        } else {
+            let applied = Merkle::from(proposal.clone());
+            let key_values = range_key_values(&applied, start, range_end)
+                .await?
+                .into_iter()
+                .map(|(key, value)| (key, value.into_boxed_slice()))
+                .collect();
+            RangeProof {
+                start_proof: proof.start_proof.clone(),
+                end_proof: proof.end_proof.clone(),
+                key_values,
+            }
+            .verify_range(&target, start, range_end)?;
+        }
+
+        if !proof.key_changes.is_empty() {
+            let height = manager.latest_height() + 1;
+            manager.commit_at_height(proposal, None, height)?;
+        }
+        self.advance(&manager, target, range_end)
+    }
+
+    /// Check that a proof for the sync to `target` starts at `start`, where
+    /// the sync left off. Starting at the first key starts it over.
+    fn check_frontier(&self, target: &TrieHash, start: Option<&[u8]>) -> Result<(), api::Error> {
+        let Some(start) = start else {
+            return Ok(());
+        };
+        let frontier = self.frontier.lock().expect("poisoned lock");
+        let expected = frontier
+            .as_ref()
+            .filter(|frontier| frontier.target == *target)
+            .map(|frontier| &frontier.next_key);
+        match expected {
+            Some(next_key) if **next_key == *start => Ok(()),
+            _ => Err(api::Error::SyncOutOfOrder {
+                expected: expected.cloned(),
+                provided: Some(start.into()),
+            }),
+        }
+    }
+
+    /// Record how far the sync to `target` has got, now that the keys up to
+    /// `range_end` match it
+    fn advance(
+        &self,
+        manager: &RevisionManager,
+        target: TrieHash,
+        range_end: Option<&[u8]>,
+    ) -> Result<SyncStatus, api::Error> {
+        let mut frontier = self.frontier.lock().expect("poisoned lock");
+        let root_hash = manager.root_hash();
+        if root_hash.as_ref() == Some(&target) {
+            *frontier = None;
+            return Ok(SyncStatus::Complete);
+        }
+        let Some(range_end) = range_end else {
+            // every key matches, so the root should have too
+            *frontier = None;
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("synced root hash {root_hash:?} doesn't match {target:?}"),
+            )
+            .into());
+        };
+        let next_key = key_after(range_end);
+        *frontier = Some(SyncFrontier {
+            target,
+            next_key: next_key.clone(),
+        });
+        Ok(SyncStatus::Partial { next_key })
+    }
+}
+
+/// The key-value pairs `merkle` has from `start` to `end`, in order
+async fn range_key_values<T: TrieReader>(
+    merkle: &Merkle<T>,
+    start: Option<&[u8]>,
+    end: Option<&[u8]>,
+) -> Result<Vec<(Key, Value)>, api::Error> {
+    let mut stream = match start {
+        Some(start) => merkle.key_value_iter_from_key(start),
+        None => merkle.key_value_iter(),
+    };
+    let mut key_values = Vec::new();
+    while let Some((key, value)) = stream.next().await.transpose()? {
+        if end.is_some_and(|end| *key > *end) {
+            break;
+        }
+        key_values.push((key, value));
+    }
+    Ok(key_values)
+}
+
+/// The smallest key after `key`
+pub(crate) fn key_after(key: &[u8]) -> Box<[u8]> {
+    key.iter().copied().chain([0]).collect()
+}
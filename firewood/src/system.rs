@@ -0,0 +1,146 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The reserved key space.
+//!
+//! Keys starting with the system prefix belong to firewood itself. The prefix
+//! is chosen when a database is created and recorded in its header. Users can
+//! never write under it; depending on [SystemKeys](crate::system::SystemKeys), they may or may not see
+//! the entries stored there.
+//!
+//! System entries are ordinary trie entries, so they are written in the same
+//! proposals as user data and are versioned and crash consistent with it.
+//! They are also included in root hashes: two databases with the same user
+//! data but different system entries have different root hashes.
+
+use std::io::{Error, ErrorKind};
+
+use integer_encoding::{VarInt, VarIntReader};
+
+use crate::v2::api::{Batch, BatchOp};
+
+/// The default system prefix, chosen to be very unlikely in user keys
+pub const DEFAULT_SYSTEM_PREFIX: &[u8] = b"\xff\xfe\x00firewood\x00";
+
+/// How the reserved key space appears to users. Writes under the system
+/// prefix are always rejected with [crate::v2::api::Error::ReservedKey].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SystemKeys {
+    /// System entries are visible to reads and enumeration
+    Reject,
+    /// System entries are skipped by reads and enumeration. Proofs of system
+    /// keys still work when they are asked for explicitly.
+    #[default]
+    Hidden,
+}
+
+/// The record listing the frozen prefixes, see [crate::db::Db::freeze_prefix].
+/// It has an empty key, so the whole list is read with a single lookup.
+pub(crate) const FROZEN_PREFIXES: u8 = 0;
+
+/// Changes to the reserved key space
+pub(crate) type SystemBatch = Batch<Box<[u8]>, Box<[u8]>>;
+
+/// Builds the changes firewood's own features make to the reserved key space.
+///
+/// Each record type gets its own keys under the system prefix, so features
+/// can't collide with each other. The changes are committed along with user
+/// data by [crate::db::Db::propose_with_system].
+#[derive(Debug)]
+pub(crate) struct SystemStore {
+    prefix: Box<[u8]>,
+    batch: SystemBatch,
+}
+
+impl SystemStore {
+    pub(crate) fn new(prefix: &[u8]) -> Self {
+        Self {
+            prefix: prefix.into(),
+            batch: Vec::new(),
+        }
+    }
+
+    /// The full key of a record of type `record_type`
+    pub(crate) fn key(&self, record_type: u8, key: &[u8]) -> Box<[u8]> {
+        self.prefix
+            .iter()
+            .copied()
+            .chain(std::iter::once(record_type))
+            .chain(key.iter().copied())
+            .collect()
+    }
+
+    /// Store a record
+    pub(crate) fn put_system(&mut self, record_type: u8, key: &[u8], value: &[u8]) {
+        let key = self.key(record_type, key);
+        self.batch.push(BatchOp::Put {
+            key,
+            value: value.into(),
+        });
+    }
+
+    /// Remove a record
+    pub(crate) fn delete_system(&mut self, record_type: u8, key: &[u8]) {
+        let key = self.key(record_type, key);
+        self.batch.push(BatchOp::Delete { key });
+    }
+
+    /// Store an entry under its full key, for copying the reserved key
+    /// space of another database as it is
+    pub(crate) fn put_key(&mut self, key: &[u8], value: &[u8]) {
+        self.batch.push(BatchOp::Put {
+            key: key.into(),
+            value: value.into(),
+        });
+    }
+
+    pub(crate) fn into_batch(self) -> SystemBatch {
+        self.batch
+    }
+}
+
+/// Encode the value of the [FROZEN_PREFIXES] record
+pub(crate) fn encode_prefixes(prefixes: &[Box<[u8]>]) -> Box<[u8]> {
+    let mut bytes = Vec::new();
+    for prefix in prefixes {
+        bytes.extend(prefix.len().encode_var_vec());
+        bytes.extend_from_slice(prefix);
+    }
+    bytes.into()
+}
+
+/// Decode the value of the [FROZEN_PREFIXES] record
+pub(crate) fn decode_prefixes(bytes: &[u8]) -> Result<Vec<Box<[u8]>>, Error> {
+    let mut reader = bytes;
+    let mut prefixes = Vec::new();
+    while !reader.is_empty() {
+        let len: usize = reader.read_varint()?;
+        if len > reader.len() {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "frozen prefixes: truncated",
+            ));
+        }
+        let (prefix, rest) = reader.split_at(len);
+        prefixes.push(prefix.into());
+        reader = rest;
+    }
+    Ok(prefixes)
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn prefixes_round_trip() {
+        let prefixes: Vec<Box<[u8]>> = vec![b"abc".as_slice().into(), b"".as_slice().into()];
+        assert_eq!(
+            decode_prefixes(&encode_prefixes(&prefixes)).unwrap(),
+            prefixes
+        );
+        assert!(decode_prefixes(&[]).unwrap().is_empty());
+        assert!(decode_prefixes(&[5, b'a']).is_err());
+    }
+}
@@ -0,0 +1,102 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Consistency tokens: a way to make several reads see the same revision
+//! without passing a revision handle around.
+//!
+//! A [ConsistencyToken](crate::token::ConsistencyToken) names a revision by its root hash. Reads that take
+//! a token look the revision up among the retained ones each time, so the
+//! token can be copied between tasks, or encoded and sent to another
+//! process, and nothing is pinned in the meantime. Once the revision is
+//! reaped, reads with the token fail with [crate::v2::api::Error::TokenExpired]
+//! rather than reading some other revision.
+
+use storage::TrieHash;
+
+/// The number of bytes in an encoded [ConsistencyToken]
+pub const ENCODED_TOKEN_LEN: usize = 1 + 32 + 8;
+
+/// Identifies the revision that was latest when the token was taken, with
+/// [crate::db::Db::consistency_token]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ConsistencyToken {
+    root_hash: Option<[u8; 32]>,
+    epoch: u64,
+}
+
+impl ConsistencyToken {
+    pub(crate) fn new(root_hash: Option<TrieHash>, epoch: u64) -> Self {
+        Self {
+            root_hash: root_hash.map(|hash| (*hash).into()),
+            epoch,
+        }
+    }
+
+    /// The root hash of the revision, or None if it is empty
+    pub fn root_hash(&self) -> Option<TrieHash> {
+        self.root_hash.map(TrieHash::from)
+    }
+
+    /// The number of commits the database had made since it was opened
+    /// when the token was taken. A token is guaranteed to stay valid until
+    /// the database's epoch passes this by more than
+    /// [crate::db::DbConfig::min_token_validity].
+    pub const fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    /// Encode the token
+    pub fn to_bytes(&self) -> [u8; ENCODED_TOKEN_LEN] {
+        let mut bytes = [0; ENCODED_TOKEN_LEN];
+        let (flag, rest) = bytes.split_at_mut(1);
+        let (hash, epoch) = rest.split_at_mut(32);
+        if let Some(root_hash) = &self.root_hash {
+            flag.fill(1);
+            hash.copy_from_slice(root_hash);
+        }
+        epoch.copy_from_slice(&self.epoch.to_le_bytes());
+        bytes
+    }
+
+    /// Decode a token encoded by [ConsistencyToken::to_bytes], or None if
+    /// `bytes` isn't one
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        let bytes: &[u8; ENCODED_TOKEN_LEN] = bytes.try_into().ok()?;
+        let (flag, rest) = bytes.split_first()?;
+        let (hash, epoch) = rest.split_at(32);
+        let root_hash = match flag {
+            0 if hash.iter().all(|byte| *byte == 0) => None,
+            1 => Some(hash.try_into().ok()?),
+            _ => return None,
+        };
+        Some(Self {
+            root_hash,
+            epoch: u64::from_le_bytes(epoch.try_into().ok()?),
+        })
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for token in [
+            ConsistencyToken::new(Some(TrieHash::from([7; 32])), 12),
+            ConsistencyToken::new(None, u64::MAX),
+        ] {
+            let bytes = token.to_bytes();
+            assert_eq!(ConsistencyToken::from_bytes(&bytes), Some(token));
+        }
+
+        let mut bytes = ConsistencyToken::new(None, 3).to_bytes();
+        assert!(ConsistencyToken::from_bytes(&bytes[1..]).is_none());
+        bytes[0] = 2;
+        assert!(ConsistencyToken::from_bytes(&bytes).is_none());
+        bytes[0] = 0;
+        bytes[5] = 1;
+        assert!(ConsistencyToken::from_bytes(&bytes).is_none());
+    }
+}
@@ -1,14 +1,17 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
+use crate::audit::AuditError;
 use crate::manager::RevisionManagerError;
-use crate::proof::ProofNode;
+use crate::operations::Progress;
+use crate::proof::{ProofError, ProofNode};
 pub use crate::range_proof::RangeProof;
 use crate::{merkle::MerkleError, proof::Proof};
 use async_trait::async_trait;
 use futures::Stream;
+use std::ops::{Deref, Range};
 use std::{fmt::Debug, sync::Arc};
-use storage::TrieHash;
+use storage::{CorruptNode, FreeListCorruption, LinearAddress, Node, TrieHash};
 
 /// A `KeyType` is something that can be xcast to a u8 reference,
 /// and can be sent and shared across threads. References with
@@ -35,8 +38,8 @@ impl<T> ValueType for T where T: AsRef<[u8]> + Send + Sync + Debug + 'static {}
 ///    proof
 pub type HashKey = storage::TrieHash;
 
-/// A key/value pair operation. Only put (upsert) and delete are
-/// supported
+/// A key/value pair operation: put (upsert), delete, or delete every key
+/// under a prefix
 #[derive(Debug)]
 pub enum BatchOp<K: KeyType, V: ValueType> {
     /// Upsert a key/value pair
@@ -52,12 +55,35 @@ pub enum BatchOp<K: KeyType, V: ValueType> {
         /// The key
         key: K,
     },
+
+    /// Delete every key that starts with `prefix`, which deletes every key
+    /// when it is empty. Later ops in the same batch apply on top of it.
+    DeleteRange {
+        /// The prefix
+        prefix: K,
+    },
 }
 
 /// A list of operations to consist of a batch that
 /// can be proposed
 pub type Batch<K, V> = Vec<BatchOp<K, V>>;
 
+/// What the caller knows about the keys in a batch. Batches that only put
+/// keys in increasing order, all larger than any key already present, are
+/// applied with a faster path that builds the new right edge of the trie
+/// directly.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BatchOpHint {
+    /// Check whether the batch is an append
+    #[default]
+    Detect,
+    /// The batch is expected to be an append. This is still checked, and
+    /// the general path is used if it isn't.
+    Append,
+    /// The keys are in no particular order, so don't check for an append
+    Unordered,
+}
+
 /// A convenience implementation to convert a vector of key/value
 /// pairs into a batch of insert operations
 #[must_use]
@@ -68,6 +94,90 @@ pub fn vec_into_batch<K: KeyType, V: ValueType>(value: Vec<(K, V)>) -> Batch<K,
         .collect()
 }
 
+/// An approximation of the size of a range of keys, as returned by
+/// [DbView::estimate_range]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RangeEstimate {
+    /// The approximate number of keys in the range
+    pub approx_keys: u64,
+    /// The approximate number of key and value bytes in the range
+    pub approx_bytes: u64,
+}
+
+/// A range of the bytes of a value, from [DbView::get_slice]. It shares the
+/// node the value is stored in, so the value isn't copied.
+///
+/// Values are only ever written whole; a slice can't be written back.
+#[derive(Debug, Clone)]
+pub struct ValueSlice {
+    source: ValueSource,
+    range: Range<usize>,
+}
+
+/// Where the bytes of a [ValueSlice] are held
+#[derive(Debug, Clone)]
+enum ValueSource {
+    /// The node the value is stored in
+    Node(Arc<Node>),
+    /// A value that was already copied out of its node, such as one written
+    /// by a proposal
+    Owned(Arc<[u8]>),
+}
+
+impl ValueSlice {
+    /// `range` of the value in `node`, or None if it has no value
+    pub(crate) fn new(node: Arc<Node>, range: Range<usize>) -> Result<Option<Self>, Error> {
+        let Some(value) = node.value() else {
+            return Ok(None);
+        };
+        Self::check(value, &range)?;
+        Ok(Some(Self {
+            source: ValueSource::Node(node),
+            range,
+        }))
+    }
+
+    /// `range` of a value that is already held outside of a node
+    pub(crate) fn from_value(value: Box<[u8]>, range: Range<usize>) -> Result<Self, Error> {
+        Self::check(&value, &range)?;
+        Ok(Self {
+            source: ValueSource::Owned(value.into()),
+            range,
+        })
+    }
+
+    fn check(value: &[u8], range: &Range<usize>) -> Result<(), Error> {
+        match value.get(range.clone()) {
+            Some(_) => Ok(()),
+            None => Err(Error::RangeOutOfBounds {
+                range: range.clone(),
+                value_len: value.len(),
+            }),
+        }
+    }
+}
+
+impl Deref for ValueSlice {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        let value = match &self.source {
+            ValueSource::Node(node) => node.value(),
+            ValueSource::Owned(value) => Some(&**value),
+        };
+        // the range was checked against the value when the slice was made
+        value
+            .and_then(|value| value.get(self.range.clone()))
+            .unwrap_or_default()
+    }
+}
+
+impl AsRef<[u8]> for ValueSlice {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
 /// Errors returned through the API
 #[derive(thiserror::Error, Debug)]
 #[non_exhaustive]
@@ -79,6 +189,13 @@ pub enum Error {
         provided: HashKey,
     },
 
+    /// The revision with a given hash key was reaped; it is too old to read
+    #[error("Revision was reaped: {provided:?}")]
+    RevisionReaped {
+        /// the provided hash key
+        provided: HashKey,
+    },
+
     /// Incorrect root hash for commit
     #[error("Incorrect root hash for commit: {provided:?} != {current:?}")]
     IncorrectRootHash {
@@ -99,7 +216,30 @@ pub enum Error {
 
     #[error("IO error: {0}")]
     /// An IO error occurred
-    IO(#[from] std::io::Error),
+    IO(std::io::Error),
+
+    /// A node read from disk doesn't match its checksum, so the file is
+    /// corrupt; every key under the node is unreadable
+    #[error("node at {address} is corrupt: checksum {actual:#010x}, expected {expected:#010x}")]
+    CorruptNode {
+        /// The address of the node's area
+        address: LinearAddress,
+        /// The checksum stored after the node
+        expected: u32,
+        /// The checksum of the node's bytes as read
+        actual: u32,
+    },
+
+    /// An area taken off a free list to store a new node isn't a free area
+    /// of that list's size, so the free list is corrupt. Nothing was
+    /// written to the area, which may hold a live node.
+    #[error("free list {index} is corrupt: the area at {address} isn't a free area of its size")]
+    FreeListCorruption {
+        /// The address taken off the free list
+        address: LinearAddress,
+        /// The index of the free list
+        index: u8,
+    },
 
     /// Cannot commit a cloned proposal
     ///
@@ -109,6 +249,77 @@ pub enum Error {
     #[error("Cannot commit a cloned proposal")]
     CannotCommitClonedProposal,
 
+    /// Cannot abort a cloned proposal, since its clones could still use it
+    #[error("Cannot abort a cloned proposal")]
+    CannotAbortClonedProposal,
+
+    /// A [crate::invariant::CommitInvariant] refused a commit
+    #[error("commit invariant {name} violated: {detail}")]
+    InvariantViolated {
+        /// The name of the invariant
+        name: String,
+        /// How it was violated
+        detail: String,
+    },
+
+    /// A [crate::invariant::CommitInvariant] used up its budget before it
+    /// passed, so the commit was refused
+    #[error("commit invariant {name} exceeded its budget of {budget:?}")]
+    InvariantBudgetExhausted {
+        /// The name of the invariant
+        name: String,
+        /// The budget it had
+        budget: crate::invariant::InvariantBudget,
+    },
+
+    /// Cannot abort a proposal while proposals on top of it are held
+    #[error("Cannot abort a proposal with {children} proposals on top of it")]
+    ProposalHasChildren {
+        /// How many proposals on top of it are held
+        children: usize,
+    },
+
+    /// There is no retained revision that many commits back
+    #[error("There is no revision {back} commits back, only {retained} are retained")]
+    RevisionOutOfRange {
+        /// How many commits back the revision was asked for
+        back: usize,
+        /// How many revisions are retained
+        retained: usize,
+    },
+
+    /// There is no retained revision at the requested height. It was reaped
+    /// if `height` is below `oldest_available`, and hasn't been committed
+    /// yet otherwise.
+    #[error("no revision at height {height}; the oldest available is at {oldest_available}")]
+    RevisionNotFound {
+        /// The height asked for
+        height: u64,
+        /// The height of the oldest retained revision
+        oldest_available: u64,
+    },
+
+    /// The database is already open with [Db::open_shared](crate::db::Db::open_shared)
+    /// with a config that differs from the requested one
+    #[error("the database is already open with {field} {existing}, not {requested}")]
+    IncompatibleConfig {
+        /// The field of [DbConfig](crate::db::DbConfig) that differs
+        field: &'static str,
+        /// Its value in the open database
+        existing: String,
+        /// Its value in the requested config
+        requested: String,
+    },
+
+    /// The shared instance of the database failed in a way that may have
+    /// left it inconsistent, so it isn't handed out again
+    #[error("the shared instance of the database is poisoned")]
+    Poisoned,
+
+    /// The database was closed with [Db::close](crate::db::Db::close)
+    #[error("the database was closed")]
+    Closed,
+
     /// Internal error
     #[error("Internal error")]
     InternalError(Box<dyn std::error::Error + Send>),
@@ -135,15 +346,197 @@ pub enum Error {
 
     /// Generic merkle error
     #[error("merkle error: {0}")]
-    Merkle(#[from] MerkleError),
+    Merkle(MerkleError),
+
+    /// A key that was read from a snapshot was changed by another commit
+    /// before the changes based on that read could be committed
+    #[error("key {key:?} was modified by a concurrent commit")]
+    Conflict {
+        /// The key that was modified
+        key: Box<[u8]>,
+    },
+
+    /// Too many revisions are waiting to be promoted by the external root
+    /// authority for another one to be committed
+    #[error("{count} revisions are already waiting to be promoted")]
+    TooManyUnpromoted {
+        /// The number of unpromoted revisions
+        count: usize,
+    },
+
+    /// A revision could not be promoted or discarded
+    #[error("cannot promote: {reason}")]
+    CannotPromote {
+        /// Why not
+        reason: &'static str,
+    },
+
+    /// A user write targeted a key in the reserved system key space
+    #[error("key {key:?} is reserved for internal use")]
+    ReservedKey {
+        /// The rejected key
+        key: Box<[u8]>,
+    },
+
+    /// A user write targeted a key under a frozen prefix
+    #[error("op {batch_index} writes key {offending_key:?} under frozen prefix {prefix:?}")]
+    FrozenPrefix {
+        /// The frozen prefix
+        prefix: Box<[u8]>,
+        /// The rejected key
+        offending_key: Box<[u8]>,
+        /// The position of the rejected op in its batch
+        batch_index: usize,
+    },
+
+    /// An unfreeze was attempted without the acknowledgement it requires
+    #[error("unfreezing prefix {prefix:?} was not acknowledged")]
+    UnfreezeNotAcknowledged {
+        /// The prefix that stays frozen
+        prefix: Box<[u8]>,
+    },
+
+    /// A long-running operation was cancelled or timed out before completing
+    #[error("operation cancelled after {progress:?}")]
+    Cancelled {
+        /// How much work was completed before the operation stopped
+        progress: Progress,
+    },
+
+    /// An audit bundle could not be built
+    #[error("audit error: {0}")]
+    Audit(#[from] AuditError),
+
+    /// The revision a consistency token refers to is no longer retained
+    #[error("consistency token from epoch {epoch} has expired")]
+    TokenExpired {
+        /// The epoch of the token
+        epoch: u64,
+    },
+
+    /// The proposal was made on the file [crate::db::Db::compact_with_history]
+    /// replaced, so it can't be committed; propose it again
+    #[error("the proposal was made before the database was compacted")]
+    ProposalInvalidated,
+
+    /// A range of a value was asked for that isn't within it
+    #[error("range {range:?} is outside the value of length {value_len}")]
+    RangeOutOfBounds {
+        /// The range asked for
+        range: Range<usize>,
+        /// The length of the value, so a valid range can be asked for
+        value_len: usize,
+    },
+
+    /// A proof didn't verify
+    #[error("invalid proof: {0}")]
+    InvalidProof(#[from] ProofError),
+
+    /// A proof applied to sync to a root doesn't start where the ones
+    /// applied before it left off
+    #[error("the proof starts at {provided:?}, but the sync is at {expected:?}")]
+    SyncOutOfOrder {
+        /// Where the next proof has to start, or None if no sync to the
+        /// root is in progress, so it has to start at the first key
+        expected: Option<Box<[u8]>>,
+        /// Where the proof starts
+        provided: Option<Box<[u8]>>,
+    },
+
+    /// The database was opened with [DbConfig::read_only](crate::db::DbConfig::read_only),
+    /// so nothing can be proposed or committed on it
+    #[error("the database was opened read-only")]
+    ReadOnly,
+
+    /// A restore target doesn't resolve to a retained revision
+    #[error("cannot restore to {target:?}: missing {missing}")]
+    RestoreTargetUnavailable {
+        /// The target that couldn't be resolved
+        target: crate::restore::RestoreTarget,
+        /// What the restore would need that isn't available
+        missing: &'static str,
+    },
+
+    /// The revision window can't shrink, since a revision it would drop is
+    /// pinned
+    #[error("revision {root_hash:?} is pinned, so it can't be reaped")]
+    RevisionPinned {
+        /// The root hash of the pinned revision
+        root_hash: HashKey,
+    },
+}
+
+impl From<CorruptNode> for Error {
+    fn from(corrupt: CorruptNode) -> Self {
+        Error::CorruptNode {
+            address: corrupt.address,
+            expected: corrupt.expected,
+            actual: corrupt.actual,
+        }
+    }
+}
+
+impl From<FreeListCorruption> for Error {
+    fn from(corrupt: FreeListCorruption) -> Self {
+        Error::FreeListCorruption {
+            address: corrupt.address,
+            index: corrupt.index,
+        }
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(err: std::io::Error) -> Self {
+        if let Some(corrupt) = FreeListCorruption::from_io(&err) {
+            return corrupt.into();
+        }
+        match CorruptNode::from_io(&err) {
+            Some(corrupt) => corrupt.into(),
+            None => Error::IO(err),
+        }
+    }
+}
+
+impl From<MerkleError> for Error {
+    fn from(err: MerkleError) -> Self {
+        match &err {
+            MerkleError::IO(io_err) => match CorruptNode::from_io(io_err) {
+                Some(corrupt) => corrupt.into(),
+                None => Error::Merkle(err),
+            },
+            _ => Error::Merkle(err),
+        }
+    }
 }
 
 impl From<RevisionManagerError> for Error {
     fn from(err: RevisionManagerError) -> Self {
         match err {
-            RevisionManagerError::IO(io_err) => Error::IO(io_err),
+            RevisionManagerError::IO(io_err) => io_err.into(),
             RevisionManagerError::NotLatest => Error::NotLatest,
             RevisionManagerError::SiblingCommitted => Error::SiblingCommitted,
+            RevisionManagerError::TooManyUnpromoted(count) => Error::TooManyUnpromoted { count },
+            RevisionManagerError::CannotPromote(reason) => Error::CannotPromote { reason },
+            RevisionManagerError::Invalidated => Error::ProposalInvalidated,
+            RevisionManagerError::HasChildren(children) => Error::ProposalHasChildren { children },
+            RevisionManagerError::OutOfRange { back, retained } => {
+                Error::RevisionOutOfRange { back, retained }
+            }
+            RevisionManagerError::HeightNotFound {
+                height,
+                oldest_available,
+            } => Error::RevisionNotFound {
+                height,
+                oldest_available,
+            },
+            RevisionManagerError::UnknownRevision(provided) => Error::HashNotFound { provided },
+            RevisionManagerError::RevisionReaped(provided) => Error::RevisionReaped { provided },
+            RevisionManagerError::EmptyDatabase => Error::LatestIsEmpty,
+            RevisionManagerError::EarlierCommitFailed => {
+                Error::IO(std::io::Error::other(err.to_string()))
+            }
+            RevisionManagerError::ReadOnly => Error::ReadOnly,
+            RevisionManagerError::RevisionPinned(root_hash) => Error::RevisionPinned { root_hash },
         }
     }
 }
@@ -214,9 +607,40 @@ pub trait DbView {
     /// Get the value of a specific key
     async fn val<K: KeyType>(&self, key: K) -> Result<Option<Box<[u8]>>, Error>;
 
+    /// Get the value of each of `keys`, in the same order, even if some of
+    /// them are repeated. The trie is walked once for all of them, so the
+    /// nodes on the paths that keys share are only read once.
+    async fn get_many<K: KeyType, I: IntoIterator<Item = K> + Send>(
+        &self,
+        keys: I,
+    ) -> Result<Vec<Option<Box<[u8]>>>, Error>;
+
+    /// Get `range` of the bytes of the value of a specific key, or None if
+    /// the key is absent. Fails with [Error::RangeOutOfBounds] if the range
+    /// doesn't fit in the value; see [DbView::value_len].
+    async fn get_slice<K: KeyType>(
+        &self,
+        key: K,
+        range: Range<usize>,
+    ) -> Result<Option<ValueSlice>, Error>;
+
+    /// Get the length of the value of a specific key, or None if the key is
+    /// absent
+    async fn value_len<K: KeyType>(&self, key: K) -> Result<Option<usize>, Error>;
+
     /// Obtain a proof for a single key
     async fn single_key_proof<K: KeyType>(&self, key: K) -> Result<Proof<ProofNode>, Error>;
 
+    /// Get the value of a specific key, or None if it is absent, along with
+    /// the proof [DbView::single_key_proof] returns for it: an inclusion
+    /// proof of the value, or an exclusion proof of the key. The trie is
+    /// walked once for both. Like the proof, which shows the value, reserved
+    /// keys aren't hidden.
+    async fn get_with_proof<K: KeyType>(
+        &self,
+        key: K,
+    ) -> Result<(Option<Box<[u8]>>, Proof<ProofNode>), Error>;
+
     /// Obtain a range proof over a set of keys
     ///
     /// # Arguments
@@ -232,6 +656,23 @@ pub trait DbView {
         limit: Option<usize>,
     ) -> Result<Option<RangeProof<Box<[u8]>, Box<[u8]>, ProofNode>>, Error>;
 
+    /// Estimate the number of keys and bytes in a range without scanning it
+    ///
+    /// # Arguments
+    ///
+    /// * `first_key` - If None, start at the lowest key
+    /// * `end_key` - If None, continue to the end of the database; otherwise
+    ///               the range stops just before this key
+    ///
+    /// The estimate is computed by sampling the trie structure between the
+    /// two boundaries, so it is cheap but approximate. See
+    /// [crate::merkle::Merkle::estimate_range] for the expected accuracy.
+    async fn estimate_range<K: KeyType>(
+        &self,
+        first_key: Option<K>,
+        end_key: Option<K>,
+    ) -> Result<RangeEstimate, Error>;
+
     /// Obtain a stream over the keys/values of this view, using an optional starting point
     ///
     /// # Arguments
@@ -254,6 +695,14 @@ pub trait DbView {
     fn iter_from<K: KeyType + 'static>(&self, first_key: K) -> Result<Self::Stream<'_>, Error> {
         self.iter_option(Some(first_key))
     }
+
+    /// Obtain a stream over the key/values whose keys start with `prefix`,
+    /// which ends after the last of them
+    fn iter_prefix<K: KeyType>(&self, prefix: K) -> Result<Self::Stream<'_>, Error>;
+
+    /// Obtain a stream over the key/values whose keys are at least `start`
+    /// and before `end`, which is empty if `end` isn't after `start`
+    fn iter_range<K: KeyType>(&self, start: K, end: K) -> Result<Self::Stream<'_>, Error>;
 }
 
 /// A proposal for a new revision of the database.
@@ -272,8 +721,16 @@ pub trait Proposal: DbView + Send + Sync {
     /// The type of a proposal
     type Proposal: DbView + Proposal;
 
-    /// Commit this revision
-    async fn commit(self: Arc<Self>) -> Result<(), Error>;
+    /// Commit this revision, returning its root hash, or None if it is
+    /// empty. This is the hash of this proposal, even if another commit
+    /// follows before the call returns.
+    async fn commit(self: Arc<Self>) -> Result<Option<HashKey>, Error>;
+
+    /// Drop this proposal without committing it, and stop the database
+    /// tracking it. Aborting doesn't cascade: it fails with
+    /// [Error::ProposalHasChildren] while proposals made on top of this one
+    /// are still held, and they must be aborted or committed first.
+    async fn abort(self: Arc<Self>) -> Result<(), Error>;
 
     /// Propose a new revision on top of an existing proposal
     ///
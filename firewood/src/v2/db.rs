@@ -8,7 +8,7 @@ impl From<DbError> for api::Error {
     fn from(value: DbError) -> Self {
         match value {
             DbError::Merkle(e) => api::Error::InternalError(Box::new(e)),
-            DbError::IO(e) => api::Error::IO(e),
+            DbError::IO(e) => e.into(),
         }
     }
 }
@@ -7,11 +7,12 @@ use crate::{
 };
 
 use super::{
-    api::{Batch, Db, DbView, Error, HashKey, KeyType, ValueType},
+    api::{Batch, Db, DbView, Error, HashKey, KeyType, RangeEstimate, ValueSlice, ValueType},
     propose::{Proposal, ProposalBase},
 };
 use async_trait::async_trait;
 use futures::Stream;
+use std::ops::Range;
 use std::sync::Arc;
 
 /// An EmptyDb is a simple implementation of api::Db
@@ -70,10 +71,36 @@ impl DbView for HistoricalImpl {
         Ok(None)
     }
 
+    async fn get_many<K: KeyType, I: IntoIterator<Item = K> + Send>(
+        &self,
+        keys: I,
+    ) -> Result<Vec<Option<Box<[u8]>>>, Error> {
+        Ok(keys.into_iter().map(|_| None).collect())
+    }
+
+    async fn get_slice<K: KeyType>(
+        &self,
+        _key: K,
+        _range: Range<usize>,
+    ) -> Result<Option<ValueSlice>, Error> {
+        Ok(None)
+    }
+
+    async fn value_len<K: KeyType>(&self, _key: K) -> Result<Option<usize>, Error> {
+        Ok(None)
+    }
+
     async fn single_key_proof<K: KeyType>(&self, _key: K) -> Result<Proof<ProofNode>, Error> {
         Err(Error::RangeProofOnEmptyTrie)
     }
 
+    async fn get_with_proof<K: KeyType>(
+        &self,
+        _key: K,
+    ) -> Result<(Option<Box<[u8]>>, Proof<ProofNode>), Error> {
+        Err(Error::RangeProofOnEmptyTrie)
+    }
+
     async fn range_proof<K: KeyType, V>(
         &self,
         _first_key: Option<K>,
@@ -83,9 +110,25 @@ impl DbView for HistoricalImpl {
         Ok(None)
     }
 
+    async fn estimate_range<K: KeyType>(
+        &self,
+        _first_key: Option<K>,
+        _end_key: Option<K>,
+    ) -> Result<RangeEstimate, Error> {
+        Ok(RangeEstimate::default())
+    }
+
     fn iter_option<K: KeyType>(&self, _first_key: Option<K>) -> Result<EmptyStreamer, Error> {
         Ok(EmptyStreamer {})
     }
+
+    fn iter_prefix<K: KeyType>(&self, _prefix: K) -> Result<EmptyStreamer, Error> {
+        Ok(EmptyStreamer {})
+    }
+
+    fn iter_range<K: KeyType>(&self, _start: K, _end: K) -> Result<EmptyStreamer, Error> {
+        Ok(EmptyStreamer {})
+    }
 }
 
 #[derive(Debug)]
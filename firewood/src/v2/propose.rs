@@ -1,7 +1,7 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
-use std::{collections::BTreeMap, fmt::Debug, sync::Arc};
+use std::{collections::BTreeMap, fmt::Debug, ops::Range, sync::Arc};
 
 use async_trait::async_trait;
 use futures::stream::Empty;
@@ -69,6 +69,9 @@ impl<T: api::DbView> Clone for ProposalBase<T> {
 pub struct Proposal<T> {
     pub(crate) base: ProposalBase<T>,
     pub(crate) delta: BTreeMap<Box<[u8]>, KeyOp<Box<[u8]>>>,
+    /// Prefixes deleted by [api::BatchOp::DeleteRange]. Keys under them
+    /// that aren't in `delta` read as deleted.
+    pub(crate) cleared: Vec<Box<[u8]>>,
 }
 
 // Implement Clone because T doesn't need to be Clone
@@ -78,6 +81,7 @@ impl<T: api::DbView> Clone for Proposal<T> {
         Self {
             base: self.base.clone(),
             delta: self.delta.clone(),
+            cleared: self.cleared.clone(),
         }
     }
 }
@@ -87,20 +91,31 @@ impl<T> Proposal<T> {
         base: ProposalBase<T>,
         batch: api::Batch<K, V>,
     ) -> Arc<Self> {
-        let delta = batch
-            .into_iter()
-            .map(|op| match op {
-                api::BatchOp::Put { key, value } => (
-                    key.as_ref().to_vec().into_boxed_slice(),
-                    KeyOp::Put(value.as_ref().to_vec().into_boxed_slice()),
-                ),
+        let mut delta: BTreeMap<Box<[u8]>, _> = BTreeMap::new();
+        let mut cleared = Vec::new();
+        for op in batch {
+            match op {
+                api::BatchOp::Put { key, value } => {
+                    delta.insert(
+                        key.as_ref().to_vec().into_boxed_slice(),
+                        KeyOp::Put(value.as_ref().to_vec().into_boxed_slice()),
+                    );
+                }
                 api::BatchOp::Delete { key } => {
-                    (key.as_ref().to_vec().into_boxed_slice(), KeyOp::Delete)
+                    delta.insert(key.as_ref().to_vec().into_boxed_slice(), KeyOp::Delete);
+                }
+                api::BatchOp::DeleteRange { prefix } => {
+                    delta.retain(|key, _| !key.starts_with(prefix.as_ref()));
+                    cleared.push(prefix.as_ref().into());
                 }
-            })
-            .collect::<BTreeMap<_, _>>();
+            }
+        }
 
-        Arc::new(Self { base, delta })
+        Arc::new(Self {
+            base,
+            delta,
+            cleared,
+        })
     }
 }
 
@@ -124,6 +139,13 @@ impl<T: api::DbView + Send + Sync> api::DbView for Proposal<T> {
                 KeyOp::Put(val) => Ok(Some(val.clone())),
                 KeyOp::Delete => Ok(None), // key was deleted in this proposal
             },
+            None if self
+                .cleared
+                .iter()
+                .any(|prefix| key.as_ref().starts_with(prefix)) =>
+            {
+                Ok(None)
+            }
             None => match &self.base {
                 // key not in this proposal, so delegate to base
                 ProposalBase::Proposal(p) => p.val(key).await,
@@ -132,10 +154,44 @@ impl<T: api::DbView + Send + Sync> api::DbView for Proposal<T> {
         }
     }
 
+    async fn get_slice<K: KeyType>(
+        &self,
+        key: K,
+        range: Range<usize>,
+    ) -> Result<Option<api::ValueSlice>, api::Error> {
+        self.val(key)
+            .await?
+            .map(|value| api::ValueSlice::from_value(value, range))
+            .transpose()
+    }
+
+    async fn value_len<K: KeyType>(&self, key: K) -> Result<Option<usize>, api::Error> {
+        Ok(self.val(key).await?.map(|value| value.len()))
+    }
+
+    async fn get_many<K: KeyType, I: IntoIterator<Item = K> + Send>(
+        &self,
+        keys: I,
+    ) -> Result<Vec<Option<Box<[u8]>>>, api::Error> {
+        let keys: Vec<K> = keys.into_iter().collect();
+        let mut values = Vec::with_capacity(keys.len());
+        for key in keys {
+            values.push(self.val(key).await?);
+        }
+        Ok(values)
+    }
+
     async fn single_key_proof<K: KeyType>(&self, _key: K) -> Result<Proof<ProofNode>, api::Error> {
         todo!();
     }
 
+    async fn get_with_proof<K: KeyType>(
+        &self,
+        _key: K,
+    ) -> Result<(Option<Box<[u8]>>, Proof<ProofNode>), api::Error> {
+        todo!();
+    }
+
     async fn range_proof<KT: KeyType, VT>(
         &self,
         _first_key: Option<KT>,
@@ -145,12 +201,26 @@ impl<T: api::DbView + Send + Sync> api::DbView for Proposal<T> {
         todo!();
     }
 
+    async fn estimate_range<K: KeyType>(
+        &self,
+        _first_key: Option<K>,
+        _end_key: Option<K>,
+    ) -> Result<api::RangeEstimate, api::Error> {
+        todo!();
+    }
+
     fn iter_option<K: KeyType>(
         &self,
         _first_key: Option<K>,
     ) -> Result<Self::Stream<'_>, api::Error> {
         todo!();
     }
+    fn iter_prefix<K: KeyType>(&self, _prefix: K) -> Result<Self::Stream<'_>, api::Error> {
+        todo!();
+    }
+    fn iter_range<K: KeyType>(&self, _start: K, _end: K) -> Result<Self::Stream<'_>, api::Error> {
+        todo!();
+    }
 }
 
 #[async_trait]
@@ -165,25 +235,34 @@ impl<T: api::DbView + Send + Sync> api::Proposal for Proposal<T> {
         Ok(Proposal::new(ProposalBase::Proposal(self), data))
     }
 
-    async fn commit(self: Arc<Self>) -> Result<(), api::Error> {
+    async fn commit(self: Arc<Self>) -> Result<Option<api::HashKey>, api::Error> {
         match &self.base {
             ProposalBase::Proposal(base) => base.clone().commit().await,
-            ProposalBase::View(_) => Ok(()),
+            ProposalBase::View(_) => Ok(None),
         }
     }
+
+    async fn abort(self: Arc<Self>) -> Result<(), api::Error> {
+        // the changes are only held in memory
+        Ok(())
+    }
 }
 
 impl<T: api::DbView> std::ops::Add for Proposal<T> {
     type Output = Arc<Proposal<T>>;
 
     fn add(self, rhs: Self) -> Self::Output {
-        let mut delta = self.delta.clone();
-
+        let mut delta = self.delta;
+        delta.retain(|key, _| !rhs.cleared.iter().any(|prefix| key.starts_with(prefix)));
         delta.extend(rhs.delta);
 
+        let mut cleared = self.cleared;
+        cleared.extend(rhs.cleared);
+
         let proposal = Proposal {
             base: self.base,
             delta,
+            cleared,
         };
 
         Arc::new(proposal)
@@ -195,12 +274,16 @@ impl<T: api::DbView> std::ops::Add for &Proposal<T> {
 
     fn add(self, rhs: Self) -> Self::Output {
         let mut delta = self.delta.clone();
-
+        delta.retain(|key, _| !rhs.cleared.iter().any(|prefix| key.starts_with(prefix)));
         delta.extend(rhs.delta.clone());
 
+        let mut cleared = self.cleared.clone();
+        cleared.extend(rhs.cleared.iter().cloned());
+
         let proposal = Proposal {
             base: self.base.clone(),
             delta,
+            cleared,
         };
 
         Arc::new(proposal)
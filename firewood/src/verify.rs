@@ -0,0 +1,300 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Checking a database file with [crate::db::Db::verify], after an unclean
+//! shutdown or before trusting a file copied from elsewhere.
+//!
+//! Every node reachable from the checked roots is read from storage, past
+//! the node cache, and hashed, and the hash is compared with the one its
+//! parent records for it. A node shared by several revisions is only read
+//! once. Problems are collected into a [VerifyReport](crate::verify::VerifyReport) instead of ending the
+//! check.
+
+use std::collections::HashMap;
+use std::iter::once;
+
+use storage::{hash_node, Child, LinearAddress, Node, Parentable as _, Path, TrieHash};
+use thiserror::Error;
+
+use crate::manager::CommittedRevision;
+
+/// What [crate::db::Db::verify] checks
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VerifyOptions {
+    /// Only check the trie of the latest revision, not the older retained
+    /// ones
+    pub latest_only: bool,
+    /// Also check that every area on the free lists is a free area within
+    /// the file, and that none of them overlap
+    pub free_areas: bool,
+}
+
+/// A revision whose trie was checked
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RootReport {
+    /// The root hash of the revision, or None if it is empty
+    pub root_hash: Option<TrieHash>,
+    /// The address of its root node, or None if it is empty
+    pub root_address: Option<LinearAddress>,
+    /// The number of nodes reachable from the root, including the ones
+    /// shared with other revisions
+    pub nodes: u64,
+}
+
+/// A problem found by [crate::db::Db::verify]
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum VerifyError {
+    /// A reachable node can't be read, for instance because it doesn't
+    /// match its checksum
+    #[error("node at {address} can't be read: {reason}")]
+    UnreadableNode {
+        /// The address of the node
+        address: LinearAddress,
+        /// Why it can't be read
+        reason: String,
+    },
+
+    /// A node doesn't hash to what its parent records for it, or, for a
+    /// root, to the root hash of its revision
+    #[error("node at {address} hashes to {computed:?}, not {recorded:?}")]
+    HashMismatch {
+        /// The address of the node
+        address: LinearAddress,
+        /// The hash its parent or revision records for it
+        recorded: TrieHash,
+        /// The hash of the node as read
+        computed: TrieHash,
+    },
+
+    /// A node is below itself, so the trie never ends
+    #[error("node at {address} is below itself")]
+    Cycle {
+        /// The address of the node
+        address: LinearAddress,
+    },
+
+    /// A reachable node is on a free list, so it can be overwritten
+    #[error("node at {address} is reachable but on a free list")]
+    ReachableAndFree {
+        /// The address of the node
+        address: LinearAddress,
+    },
+
+    /// An area on a free list isn't a free area of its list's size, which
+    /// ends the list, or isn't within the file
+    #[error("area at {address} on a free list {reason}")]
+    InvalidFreeArea {
+        /// The address of the area
+        address: LinearAddress,
+        /// What is wrong with it
+        reason: &'static str,
+    },
+
+    /// Two areas on the free lists overlap. An area listed twice overlaps
+    /// itself.
+    #[error("areas at {address} and {other} on the free lists overlap")]
+    OverlappingFreeAreas {
+        /// The address of the first area
+        address: LinearAddress,
+        /// The address of the area it overlaps
+        other: LinearAddress,
+    },
+}
+
+/// What [crate::db::Db::verify] found
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// The revisions whose tries were checked, newest first
+    pub roots: Vec<RootReport>,
+    /// The number of areas on the free lists
+    pub free_areas: usize,
+    /// Every problem found
+    pub errors: Vec<VerifyError>,
+}
+
+impl VerifyReport {
+    /// Whether no problem was found
+    pub const fn is_ok(&self) -> bool {
+        self.errors.is_empty()
+    }
+}
+
+/// Check the tries of `revisions`, newest first, then the free lists of the
+/// newest one
+pub(crate) fn verify(
+    revisions: &[CommittedRevision],
+    options: VerifyOptions,
+) -> Result<VerifyReport, std::io::Error> {
+    let mut walk = Walk::default();
+    let roots = revisions
+        .iter()
+        .map(|revision| walk.check_root(revision))
+        .collect();
+    let mut report = VerifyReport {
+        roots,
+        free_areas: 0,
+        errors: walk.errors,
+    };
+
+    let Some(latest) = revisions.first() else {
+        return Ok(report);
+    };
+    let mut areas = latest.free_list_areas()?;
+    report.free_areas = areas.len();
+    for area in &areas {
+        if walk.checked.contains_key(&area.address) {
+            report.errors.push(VerifyError::ReachableAndFree {
+                address: area.address,
+            });
+        }
+    }
+    if !options.free_areas {
+        return Ok(report);
+    }
+
+    for area in &areas {
+        let reason = if !area.valid {
+            "is not a free area of its list's size"
+        } else if area.address.get() + area.size > latest.store_size() {
+            "extends past the end of the file"
+        } else {
+            continue;
+        };
+        report.errors.push(VerifyError::InvalidFreeArea {
+            address: area.address,
+            reason,
+        });
+    }
+    areas.sort_by_key(|area| area.address);
+    for pair in areas.windows(2) {
+        if let [area, next] = pair {
+            if area.address.get() + area.size > next.address.get() {
+                report.errors.push(VerifyError::OverlappingFreeAreas {
+                    address: area.address,
+                    other: next.address,
+                });
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// A node that has been checked
+#[derive(Clone)]
+struct Checked {
+    /// The number of nodes reachable from it, including itself
+    nodes: u64,
+    /// Its hash, or None if it couldn't be read
+    hash: Option<TrieHash>,
+}
+
+#[derive(Default)]
+struct Walk {
+    /// The nodes reached so far, or None for the ones still being checked
+    checked: HashMap<LinearAddress, Option<Checked>>,
+    errors: Vec<VerifyError>,
+}
+
+impl Walk {
+    fn check_root(&mut self, revision: &CommittedRevision) -> RootReport {
+        let root_hash = revision.kind.root_hash();
+        let root_address = revision.root_address();
+        let mut nodes = 0;
+        if let Some(address) = root_address {
+            let root = self.check(revision, address, &mut Path::new());
+            nodes = root.nodes;
+            if let Some(recorded) = &root_hash {
+                self.check_hash(address, recorded, root.hash);
+            }
+        }
+        RootReport {
+            root_hash,
+            root_address,
+            nodes,
+        }
+    }
+
+    /// Check the node at `address`, whose path is `path`, and the nodes
+    /// below it
+    fn check(
+        &mut self,
+        revision: &CommittedRevision,
+        address: LinearAddress,
+        path: &mut Path,
+    ) -> Checked {
+        match self.checked.get(&address) {
+            Some(Some(checked)) => return checked.clone(),
+            Some(None) => {
+                self.errors.push(VerifyError::Cycle { address });
+                return Checked {
+                    nodes: 0,
+                    hash: None,
+                };
+            }
+            None => {}
+        }
+        self.checked.insert(address, None);
+
+        let checked = match revision.read_node_uncached(address) {
+            Ok(node) => {
+                let mut nodes = 1;
+                if let Node::Branch(branch) = &node {
+                    for (index, child) in branch.children.iter().enumerate() {
+                        // stored branches only have hashed children
+                        let Some(Child::AddressWithHash(child_address, recorded)) = child else {
+                            continue;
+                        };
+                        let len = path.len();
+                        path.0
+                            .extend(branch.partial_path.iter().copied().chain(once(index as u8)));
+                        let child = self.check(revision, *child_address, path);
+                        path.0.truncate(len);
+                        nodes += child.nodes;
+                        self.check_hash(*child_address, recorded, child.hash);
+                    }
+                }
+                Checked {
+                    nodes,
+                    hash: Some(hash_node(&node, path)),
+                }
+            }
+            Err(err) => {
+                self.errors.push(VerifyError::UnreadableNode {
+                    address,
+                    reason: err.to_string(),
+                });
+                Checked {
+                    nodes: 0,
+                    hash: None,
+                }
+            }
+        };
+        self.checked.insert(address, Some(checked.clone()));
+        checked
+    }
+
+    /// Record a mismatch if the node at `address` hashes to something other
+    /// than `recorded`. Nodes shared by several parents are only reported
+    /// once.
+    fn check_hash(
+        &mut self,
+        address: LinearAddress,
+        recorded: &TrieHash,
+        computed: Option<TrieHash>,
+    ) {
+        let Some(computed) = computed else {
+            return;
+        };
+        if computed == *recorded {
+            return;
+        }
+        let err = VerifyError::HashMismatch {
+            address,
+            recorded: recorded.clone(),
+            computed,
+        };
+        if !self.errors.contains(&err) {
+            self.errors.push(err);
+        }
+    }
+}
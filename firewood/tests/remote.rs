@@ -0,0 +1,168 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+// Read replicas that fetch a database file on demand, with the local file
+// system standing in for the remote
+
+#![allow(clippy::unwrap_used)]
+
+use std::io::Error;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+use firewood::db::{BatchOp, Db, DbConfig};
+use firewood::merkle::{Merkle, MerkleError};
+use firewood::stream::MerkleKeyValueStream;
+use firewood::v2::api::{self, Db as _, Proposal as _};
+use futures::TryStreamExt;
+use storage::{
+    extent_digests, Committed, FileFetcher, NodeStore, RangeFetcher, RemoteBacked, RemoteError,
+    TrieHash,
+};
+use tempfile::tempdir;
+
+#[derive(Debug)]
+struct Counting {
+    inner: FileFetcher,
+    fetches: AtomicUsize,
+}
+
+impl RangeFetcher for Counting {
+    fn size(&self) -> Result<u64, Error> {
+        self.inner.size()
+    }
+
+    fn fetch(&self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        self.fetches.fetch_add(1, Ordering::Relaxed);
+        self.inner.fetch(offset, len)
+    }
+
+    fn extent_digest(&self, index: u64) -> Result<Option<[u8; 32]>, Error> {
+        self.inner.extent_digest(index)
+    }
+}
+
+fn entries() -> Vec<(Box<[u8]>, Vec<u8>)> {
+    (0..5000u32)
+        .map(|i| {
+            let key = i.wrapping_mul(2_654_435_761).to_be_bytes();
+            (Box::from(key), i.to_le_bytes().repeat(8))
+        })
+        .collect()
+}
+
+/// Create a database holding [entries] and return its root hash
+async fn create_primary(path: &Path) -> TrieHash {
+    let db = Db::new(path, DbConfig::builder().truncate(true).build())
+        .await
+        .unwrap();
+    let batch = entries()
+        .into_iter()
+        .map(|(key, value)| BatchOp::Put { key, value })
+        .collect();
+    db.propose(batch).await.unwrap().commit().await.unwrap();
+    api::Db::root_hash(&db).await.unwrap().unwrap()
+}
+
+async fn check_contents(replica: &NodeStore<Committed, RemoteBacked>, root_hash: &TrieHash) {
+    let mut expected = entries();
+    expected.sort();
+    let merkle = Merkle::from(replica);
+
+    for (key, value) in expected.iter().step_by(97) {
+        assert_eq!(merkle.get_value(key).unwrap().as_deref(), Some(&value[..]));
+        let proof = merkle.prove(key).unwrap();
+        proof.verify(key, Some(value), root_hash).unwrap();
+    }
+    assert_eq!(merkle.get_value(b"missing").unwrap(), None);
+
+    let all: Vec<_> = MerkleKeyValueStream::from(replica)
+        .try_collect()
+        .await
+        .unwrap();
+    assert_eq!(all.len(), expected.len());
+    assert!(all.into_iter().eq(expected));
+}
+
+fn remote_error(err: &MerkleError) -> Option<&RemoteError> {
+    match err {
+        MerkleError::IO(err) => RemoteError::from_io(err),
+        _ => None,
+    }
+}
+
+#[tokio::test]
+async fn replica_reads() {
+    let dir = tempdir().unwrap();
+    let primary = dir.path().join("primary");
+    let cache = dir.path().join("cache");
+    let root_hash = create_primary(&primary).await;
+
+    let fetcher = Arc::new(Counting {
+        inner: FileFetcher::new(&primary).unwrap(),
+        fetches: AtomicUsize::new(0),
+    });
+    let replica = RemoteBacked::open(fetcher.clone(), cache.clone(), &root_hash).unwrap();
+    check_contents(&replica, &root_hash).await;
+    assert!(fetcher.fetches.load(Ordering::Relaxed) > 0);
+
+    // a new replica sharing the cache file reads everything locally
+    drop(replica);
+    let fetcher = Arc::new(Counting {
+        inner: FileFetcher::new(&primary).unwrap(),
+        fetches: AtomicUsize::new(0),
+    });
+    let replica = RemoteBacked::open(fetcher.clone(), cache, &root_hash).unwrap();
+    check_contents(&replica, &root_hash).await;
+    assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 0);
+}
+
+#[tokio::test]
+async fn replica_of_wrong_root() {
+    let dir = tempdir().unwrap();
+    let primary = dir.path().join("primary");
+    create_primary(&primary).await;
+
+    let fetcher = Arc::new(FileFetcher::new(&primary).unwrap());
+    let err =
+        RemoteBacked::open(fetcher, dir.path().join("cache"), &TrieHash::default()).unwrap_err();
+    assert!(matches!(
+        RemoteError::from_io(&err),
+        Some(RemoteError::RootMismatch { .. })
+    ));
+}
+
+#[tokio::test]
+async fn tampered_replica() {
+    let dir = tempdir().unwrap();
+    let primary = dir.path().join("primary");
+    let root_hash = create_primary(&primary).await;
+    let digests = extent_digests(&primary).unwrap();
+
+    // change a byte in the middle of the remote after the digests were published
+    let remote = std::fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(&primary)
+        .unwrap();
+    let offset = remote.metadata().unwrap().len() / 2;
+    let mut byte = [0];
+    remote.read_exact_at(&mut byte, offset).unwrap();
+    remote.write_all_at(&[byte[0] ^ 0x55], offset).unwrap();
+
+    let fetcher = FileFetcher::new(&primary).unwrap().with_digests(digests);
+    let replica =
+        RemoteBacked::open(Arc::new(fetcher), dir.path().join("cache"), &root_hash).unwrap();
+
+    let merkle = Merkle::from(&replica);
+    let errors: Vec<_> = entries()
+        .iter()
+        .filter_map(|(key, _)| merkle.get_value(key).err())
+        .collect();
+    assert!(!errors.is_empty());
+    assert!(errors
+        .iter()
+        .all(|err| matches!(remote_error(err), Some(RemoteError::Digest { .. }))));
+}
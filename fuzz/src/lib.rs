@@ -0,0 +1,246 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Harnesses for the fuzz targets in `fuzz_targets/`.
+//!
+//! Each harness takes the raw fuzzer input. Invalid input must be rejected
+//! with an error, so a harness only panics when the library panicked or an
+//! invariant was broken.
+
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+use firewood::merkle::Merkle;
+use firewood::proof::{Proof, ProofNode};
+use firewood::stream::MerkleKeyValueStream;
+use futures::executor::block_on;
+use futures::StreamExt as _;
+use storage::{
+    HashedNodeReader, ImmutableProposal, MemStore, Node, NodeFormat, NodeStore, TrieHash,
+    TrieReader, ValueDigest,
+};
+
+/// The most entries read from a store opened from fuzzer input, which may
+/// contain a trie with cycles
+const MAX_WALK: usize = 1024;
+
+/// The longest key generated from fuzzer input, long enough for leaves with
+/// partial paths that don't fit in their first byte
+const MAX_KEY_LEN: usize = 80;
+
+/// The most batches applied by [proposal_apply]
+const MAX_BATCHES: usize = 16;
+
+/// Splits fuzzer input into the pieces a harness needs. Once the input runs
+/// out, every read returns whatever is left, so short inputs still do
+/// something.
+#[derive(Debug)]
+struct Input<'a>(&'a [u8]);
+
+impl<'a> Input<'a> {
+    const fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    fn byte(&mut self) -> Option<u8> {
+        let (first, rest) = self.0.split_first()?;
+        self.0 = rest;
+        Some(*first)
+    }
+
+    fn bytes(&mut self, len: usize) -> &'a [u8] {
+        let (bytes, rest) = self.0.split_at(len.min(self.0.len()));
+        self.0 = rest;
+        bytes
+    }
+
+    /// A length-prefixed byte string of at most `max_len` bytes
+    fn sized(&mut self, max_len: usize) -> &'a [u8] {
+        let len = self.byte().unwrap_or_default() as usize % (max_len + 1);
+        self.bytes(len)
+    }
+
+    fn key(&mut self) -> &'a [u8] {
+        self.sized(MAX_KEY_LEN)
+    }
+
+    fn value(&mut self) -> &'a [u8] {
+        self.sized(u8::MAX as usize)
+    }
+}
+
+/// Decode a node, in each format. Anything that decodes must survive a
+/// round trip.
+pub fn node_decode(data: &[u8]) {
+    for format in NodeFormat::ALL {
+        let Ok(node) = Node::from_reader(format, data) else {
+            continue;
+        };
+
+        let mut encoded = Vec::new();
+        node.as_bytes(format, 0, &mut encoded);
+        let decoded = Node::from_reader(format, encoded.get(1..).unwrap_or_default())
+            .expect("a re-encoded node must decode");
+        assert_eq!(node, decoded);
+    }
+}
+
+/// Open a store whose contents, including the header, are the input, then
+/// read from whatever trie it points at.
+pub fn store_open(data: &[u8]) {
+    let storage = Arc::new(MemStore::new(data.to_vec()));
+    let Ok(nodestore) = NodeStore::open(storage) else {
+        return;
+    };
+
+    let mut stream = MerkleKeyValueStream::from(&nodestore).take(MAX_WALK);
+    while let Some(item) = block_on(stream.next()) {
+        if item.is_err() {
+            break;
+        }
+    }
+
+    let merkle = Merkle::from(&nodestore);
+    let _ = merkle.get_value(b"fuzz");
+    let _ = merkle.prove(b"fuzz");
+}
+
+/// Build a small trie and prove a key in it. The proof must verify; after
+/// corrupting it as the input describes, it must never prove a wrong value.
+pub fn proof_verify(data: &[u8]) {
+    let mut input = Input(data);
+    let key = input.key().to_vec();
+
+    let mut merkle = Merkle::from(empty_proposal());
+    merkle.insert(&key, input.value().into()).unwrap();
+    for _ in 0..input.byte().unwrap_or_default() % 16 {
+        merkle.insert(input.key(), input.value().into()).unwrap();
+    }
+    let merkle = merkle.hash();
+    let root_hash = merkle.nodestore().root_hash().unwrap().unwrap();
+
+    let value = merkle.get_value(&key).unwrap();
+    let proof = merkle.prove(&key).unwrap();
+    proof.verify(&key, value.as_deref(), &root_hash).unwrap();
+
+    let mut nodes = proof.0.into_vec();
+    while let (Some(node), Some(edit)) = (input.byte(), input.byte()) {
+        let len = nodes.len();
+        if let Some(node) = nodes.get_mut(node as usize % len) {
+            corrupt(node, edit, &mut input);
+        }
+    }
+    let proof = Proof(nodes.into_boxed_slice());
+
+    // the proof may or may not still verify, but it can't prove anything else
+    let _ = proof.verify(&key, value.as_deref(), &root_hash);
+    let wrong_value = match &value {
+        Some(value) => value.iter().copied().chain([0]).collect::<Box<[u8]>>(),
+        None => Box::from(&b"wrong"[..]),
+    };
+    assert!(proof.verify(&key, Some(wrong_value), &root_hash).is_err());
+    if value.is_some() {
+        assert!(proof.verify(&key, None::<&[u8]>, &root_hash).is_err());
+    }
+}
+
+/// Change one part of a proof node
+fn corrupt(node: &mut ProofNode, edit: u8, input: &mut Input<'_>) {
+    let byte = input.byte().unwrap_or_default();
+    match edit % 5 {
+        0 => node.key = input.sized(MAX_KEY_LEN * 2).into(),
+        1 => node.value_digest = Some(ValueDigest::Value(input.value().into())),
+        2 => node.value_digest = None,
+        3 => {
+            if let Some(child) = node.child_hashes.get_mut(byte as usize % 16) {
+                *child = None;
+            }
+        }
+        _ => {
+            let hash: [u8; 32] = std::array::from_fn(|_| input.byte().unwrap_or_default());
+            if let Some(child) = node.child_hashes.get_mut(byte as usize % 16) {
+                *child = Some(TrieHash::from(hash));
+            }
+        }
+    }
+}
+
+/// Apply batches of puts and deletes from the input, committing each one to
+/// in-memory storage. After every batch the trie must match a model, and must
+/// hash the same as a trie built from the model in key order.
+pub fn proposal_apply(data: &[u8]) {
+    let mut input = Input(data);
+    let storage = Arc::new(MemStore::new(vec![]));
+    let empty = NodeStore::new_empty_committed(storage.clone()).unwrap();
+    empty.flush_header_with_padding().unwrap();
+    let mut parent = Arc::new(empty);
+    let mut model = BTreeMap::<Box<[u8]>, Box<[u8]>>::new();
+
+    for _ in 0..MAX_BATCHES {
+        if input.is_empty() {
+            break;
+        }
+
+        let mut merkle = Merkle::from(NodeStore::new(parent.clone()).unwrap());
+        while let Some(op) = input.byte() {
+            if op & 0x80 != 0 {
+                // end of batch
+                break;
+            }
+            let key = input.key();
+            if op & 1 == 0 {
+                let value = input.value();
+                merkle.insert(key, value.into()).unwrap();
+                model.insert(key.into(), value.into());
+            } else {
+                let removed = merkle.remove(key).unwrap();
+                assert_eq!(removed, model.remove(key));
+            }
+        }
+
+        let proposal = merkle.hash();
+        check_model(&proposal, &model);
+        let proposal = proposal.into_inner();
+        assert_eq!(proposal.root_hash().unwrap(), model_root_hash(&model));
+
+        commit(&proposal);
+        parent = Arc::new(NodeStore::open(storage.clone()).unwrap());
+        check_model(&Merkle::from(parent.clone()), &model);
+        assert_eq!(parent.root_hash().unwrap(), model_root_hash(&model));
+    }
+}
+
+fn empty_proposal() -> NodeStore<storage::MutableProposal, MemStore> {
+    NodeStore::new_empty_proposal(Arc::new(MemStore::new(vec![])))
+}
+
+fn commit(proposal: &NodeStore<Arc<ImmutableProposal>, MemStore>) {
+    proposal.flush_freelist().unwrap();
+    proposal.flush_nodes().unwrap();
+    proposal.flush_header().unwrap();
+}
+
+fn check_model<T: TrieReader>(merkle: &Merkle<T>, model: &BTreeMap<Box<[u8]>, Box<[u8]>>) {
+    for (key, value) in model {
+        assert_eq!(merkle.get_value(key).unwrap().as_ref(), Some(value));
+    }
+
+    let entries: Vec<_> = block_on(
+        MerkleKeyValueStream::from(merkle.nodestore())
+            .map(|item| item.unwrap())
+            .collect(),
+    );
+    let expected: Vec<_> = model
+        .iter()
+        .map(|(key, value)| (key.clone(), value.to_vec()))
+        .collect();
+    assert_eq!(entries, expected);
+}
+
+fn model_root_hash(model: &BTreeMap<Box<[u8]>, Box<[u8]>>) -> Option<TrieHash> {
+    let mut merkle = Merkle::from(empty_proposal());
+    for (key, value) in model {
+        merkle.insert(key, value.clone()).unwrap();
+    }
+    merkle.hash().nodestore().root_hash().unwrap()
+}
@@ -0,0 +1,62 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+use clap::Args;
+use std::io::{Error, ErrorKind};
+
+use firewood::db::{Db, DbConfig};
+use firewood::v2::api;
+use firewood::verify::VerifyOptions;
+
+#[derive(Debug, Args)]
+pub struct Options {
+    /// The database path (if no path is provided, return an error). Defaults to firewood.
+    #[arg(
+        long,
+        required = false,
+        value_name = "DB_NAME",
+        default_value_t = String::from("firewood"),
+        help = "Name of the database"
+    )]
+    pub db: String,
+
+    /// Only check the latest revision
+    #[arg(long, help = "Only check the trie of the latest revision")]
+    pub latest_only: bool,
+
+    /// Also check the free lists
+    #[arg(
+        long,
+        help = "Also check that the areas on the free lists are within the file and don't overlap"
+    )]
+    pub free_areas: bool,
+}
+
+pub(super) async fn run(opts: &Options) -> Result<(), api::Error> {
+    let cfg = DbConfig::builder().truncate(false);
+
+    let db = Db::new(opts.db.clone(), cfg.build()).await?;
+
+    let report = db
+        .verify(VerifyOptions {
+            latest_only: opts.latest_only,
+            free_areas: opts.free_areas,
+        })
+        .await?;
+
+    for root in &report.roots {
+        println!("{:?}: {} nodes", root.root_hash, root.nodes);
+    }
+    println!("{} areas on the free lists", report.free_areas);
+    for err in &report.errors {
+        println!("{err}");
+    }
+
+    if report.is_ok() {
+        return Ok(());
+    }
+    Err(api::Error::IO(Error::new(
+        ErrorKind::InvalidData,
+        format!("{} problems found", report.errors.len()),
+    )))
+}
@@ -4,6 +4,7 @@
 use clap::{Parser, Subcommand};
 use firewood::v2::api;
 
+pub mod check;
 pub mod create;
 pub mod delete;
 pub mod dump;
@@ -47,6 +48,8 @@ enum Commands {
     Dump(dump::Options),
     /// Produce a dot file of the database
     Graph(graph::Options),
+    /// Check the database file for corruption
+    Check(check::Options),
 }
 
 #[tokio::main]
@@ -66,5 +69,6 @@ async fn main() -> Result<(), api::Error> {
         Commands::Root(opts) => root::run(opts).await,
         Commands::Dump(opts) => dump::run(opts).await,
         Commands::Graph(opts) => graph::run(opts).await,
+        Commands::Check(opts) => check::run(opts).await,
     }
 }
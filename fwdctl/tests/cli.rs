@@ -178,6 +178,39 @@ fn fwdctl_root_hash() -> Result<()> {
     Ok(())
 }
 
+#[test]
+#[serial]
+fn fwdctl_check() -> Result<()> {
+    Command::cargo_bin(PRG)?
+        .arg("create")
+        .arg(tmpdb::path())
+        .assert()
+        .success();
+
+    Command::cargo_bin(PRG)?
+        .arg("insert")
+        .args(["year"])
+        .args(["2023"])
+        .args(["--db"])
+        .args([tmpdb::path()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("year"));
+
+    Command::cargo_bin(PRG)?
+        .arg("check")
+        .args(["--free-areas"])
+        .args(["--db"])
+        .args([tmpdb::path()])
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("1 nodes"));
+
+    fwdctl_delete_db().map_err(|e| anyhow!(e))?;
+
+    Ok(())
+}
+
 #[test]
 #[serial]
 fn fwdctl_dump() -> Result<()> {
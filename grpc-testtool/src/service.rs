@@ -32,6 +32,10 @@ impl<T> IntoStatusResultExt<T> for Result<T, Error> {
             Error::IncorrectRootHash { .. } | Error::HashNotFound { .. } | Error::RangeTooSmall => {
                 Status::invalid_argument(err.to_string())
             }
+            Error::RevisionReaped { .. } => Status::not_found(err.to_string()),
+            Error::CorruptNode { .. } | Error::FreeListCorruption { .. } => {
+                Status::data_loss(err.to_string())
+            }
             Error::IO { .. } | Error::InternalError { .. } => Status::internal(err.to_string()),
             _ => Status::internal(err.to_string()),
         })
@@ -7,7 +7,7 @@ use bincode::Options;
 use criterion::{criterion_group, criterion_main, profiler::Profiler, Criterion};
 use pprof::ProfilerGuard;
 use smallvec::SmallVec;
-use storage::{LeafNode, Node, Path};
+use storage::{LeafNode, Node, NodeFormat, Path};
 
 use std::path::Path as FsPath;
 
@@ -69,7 +69,7 @@ fn leaf(c: &mut Criterion) {
     group.bench_with_input("manual", &input, |b, input| {
         b.iter(|| {
             let mut bytes = Vec::<u8>::new();
-            input.as_bytes(0, &mut bytes);
+            input.as_bytes(NodeFormat::LATEST, 0, &mut bytes);
         })
     });
     group.finish();
@@ -101,7 +101,7 @@ fn branch(c: &mut Criterion) {
     let manual_serializer = |b: &mut criterion::Bencher, input: &storage::Node| {
         b.iter(|| {
             let mut bytes = Vec::new();
-            input.as_bytes(0, &mut bytes);
+            input.as_bytes(NodeFormat::LATEST, 0, &mut bytes);
         })
     };
 
@@ -0,0 +1,142 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The CRC32C checksums stored after each node, so that a node corrupted on
+//! disk is reported instead of being read as a different node.
+
+use std::fmt;
+use std::io::{Error, ErrorKind, Read};
+
+use crate::LinearAddress;
+
+/// The number of bytes of the checksum stored after each node
+pub(crate) const CHECKSUM_LEN: usize = 4;
+
+/// The reflected CRC32C (Castagnoli) polynomial
+const POLYNOMIAL: u32 = 0x82f6_3b78;
+
+#[allow(clippy::indexing_slicing)]
+const TABLE: [u32; 256] = {
+    let mut table = [0; 256];
+    let mut byte = 0;
+    while byte < table.len() {
+        let mut crc = byte as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ POLYNOMIAL
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[byte] = crc;
+        byte += 1;
+    }
+    table
+};
+
+/// Extend the checksum `crc` of some bytes to the checksum of those bytes
+/// followed by `bytes`. The checksum of no bytes is 0.
+pub(crate) fn crc32c(crc: u32, bytes: &[u8]) -> u32 {
+    !bytes.iter().fold(!crc, |crc, byte| {
+        #[allow(clippy::indexing_slicing)]
+        let entry = TABLE[((crc ^ *byte as u32) & 0xff) as usize];
+        entry ^ (crc >> 8)
+    })
+}
+
+/// A [Read] that computes the checksum of the bytes read through it
+#[derive(Debug)]
+pub(crate) struct ChecksumReader<R> {
+    inner: R,
+    checksum: u32,
+}
+
+impl<R: Read> ChecksumReader<R> {
+    pub(crate) const fn new(inner: R) -> Self {
+        Self { inner, checksum: 0 }
+    }
+
+    /// The checksum of the bytes read so far
+    pub(crate) const fn checksum(&self) -> u32 {
+        self.checksum
+    }
+
+    /// Read the checksum stored after the bytes read so far
+    pub(crate) fn read_stored(mut self) -> Result<u32, Error> {
+        let mut stored = [0; CHECKSUM_LEN];
+        self.inner.read_exact(&mut stored)?;
+        Ok(u32::from_le_bytes(stored))
+    }
+}
+
+impl<R: Read> Read for ChecksumReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        let len = self.inner.read(buf)?;
+        self.checksum = crc32c(self.checksum, buf.get(..len).unwrap_or_default());
+        Ok(len)
+    }
+}
+
+/// A node whose bytes don't match the checksum stored after them, so the
+/// area it is in was corrupted on disk. Reading it fails with an [Error] of
+/// kind [ErrorKind::InvalidData] that wraps this; see [CorruptNode::from_io].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CorruptNode {
+    /// The address of the node's area
+    pub address: LinearAddress,
+    /// The checksum stored after the node
+    pub expected: u32,
+    /// The checksum of the node's bytes as read
+    pub actual: u32,
+}
+
+impl CorruptNode {
+    /// The [CorruptNode] that `err` reports, if any
+    pub fn from_io(err: &Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref::<Self>().copied()
+    }
+}
+
+impl fmt::Display for CorruptNode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "node at {} is corrupt: checksum {:#010x}, expected {:#010x}",
+            self.address, self.actual, self.expected
+        )
+    }
+}
+
+impl std::error::Error for CorruptNode {}
+
+impl From<CorruptNode> for Error {
+    fn from(corrupt: CorruptNode) -> Self {
+        Error::new(ErrorKind::InvalidData, corrupt)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn known_checksums() {
+        assert_eq!(crc32c(0, b""), 0);
+        assert_eq!(crc32c(0, b"123456789"), 0xe306_9283);
+        assert_eq!(crc32c(crc32c(0, b"1234"), b"56789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn reader_checks_what_it_reads() {
+        let mut bytes = b"node".to_vec();
+        bytes.extend_from_slice(&crc32c(0, b"node").to_le_bytes());
+        let mut reader = ChecksumReader::new(bytes.as_slice());
+        let mut node = [0; 4];
+        reader.read_exact(&mut node).unwrap();
+        let checksum = reader.checksum();
+        assert_eq!(reader.read_stored().unwrap(), checksum);
+    }
+}
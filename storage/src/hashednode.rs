@@ -1,8 +1,9 @@
 // Copyright (C) 2023, Ava Labs, Inc. All rights reserved.
 // See the file LICENSE.md for licensing terms.
 
+use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
-use std::iter::{self};
+use std::iter::{self, once};
 
 use crate::{BranchNode, Child, LeafNode, TrieHash};
 use crate::{Node, Path};
@@ -36,6 +37,141 @@ pub fn hash_node(node: &Node, path_prefix: &Path) -> TrieHash {
     }
 }
 
+/// The hashes of a node that isn't hashed yet and of the unhashed nodes
+/// below it, from [hash_unhashed]
+#[derive(Debug)]
+pub(crate) struct PendingHashes {
+    /// The hash of the node
+    pub(crate) hash: TrieHash,
+    /// The hashes below each child that isn't hashed yet, by child index;
+    /// empty for a leaf
+    children: Vec<Option<PendingHashes>>,
+}
+
+impl PendingHashes {
+    /// Take the hashes below child `index`, if it wasn't hashed yet
+    pub(crate) fn take_child(&mut self, index: usize) -> Option<PendingHashes> {
+        self.children.get_mut(index).and_then(Option::take)
+    }
+}
+
+/// The number of nodes in the trie below `node`, including it, that aren't
+/// hashed yet, counting no further than `limit`
+pub(crate) fn count_unhashed(node: &Node, limit: usize) -> usize {
+    let mut count = 0;
+    let mut stack = vec![node];
+    while let Some(node) = stack.pop() {
+        count += 1;
+        if count >= limit {
+            break;
+        }
+        if let Node::Branch(branch) = node {
+            stack.extend(branch.children.iter().filter_map(|child| match child {
+                Some(Child::Node(child)) => Some(child),
+                _ => None,
+            }));
+        }
+    }
+    count
+}
+
+/// Hashes `node`, which is at the given `path_prefix`, and every node below
+/// it that isn't hashed yet, sharing the subtries below a branch among up
+/// to `threads` threads. Since sibling subtries are hashed independently,
+/// the hashes are those [hash_node] gives, however many threads there are.
+pub(crate) fn hash_unhashed(node: &Node, path_prefix: &mut Path, threads: usize) -> PendingHashes {
+    let Node::Branch(branch) = node else {
+        return PendingHashes {
+            hash: hash_node(node, path_prefix),
+            children: Vec::new(),
+        };
+    };
+    let unhashed: Vec<(usize, &Node)> = branch
+        .children
+        .iter()
+        .enumerate()
+        .filter_map(|(index, child)| match child {
+            Some(Child::Node(child)) => Some((index, child)),
+            _ => None,
+        })
+        .collect();
+
+    // hashes the subtrie below child `index`, extending and truncating
+    // `path_prefix` to reduce memory allocations
+    let hash_child = |path_prefix: &mut Path, index: usize, child: &Node, threads: usize| {
+        let original_length = path_prefix.len();
+        path_prefix.0.extend(
+            branch
+                .partial_path
+                .0
+                .iter()
+                .copied()
+                .chain(once(index as u8)),
+        );
+        let hashes = hash_unhashed(child, path_prefix, threads);
+        path_prefix.0.truncate(original_length);
+        (index, hashes)
+    };
+    let hashed: Vec<(usize, PendingHashes)> = if threads < 2 || unhashed.len() < 2 {
+        unhashed
+            .into_iter()
+            .map(|(index, child)| hash_child(path_prefix, index, child, threads))
+            .collect()
+    } else {
+        // the children are dealt out to the workers in turn, and each worker
+        // shares its part of the threads among its children
+        let workers = threads.min(unhashed.len());
+        let threads_per_worker = threads / workers;
+        std::thread::scope(|scope| {
+            let handles: Vec<_> = (0..workers)
+                .map(|worker| {
+                    let mut path_prefix = path_prefix.clone();
+                    let children: Vec<_> = unhashed
+                        .iter()
+                        .skip(worker)
+                        .step_by(workers)
+                        .copied()
+                        .collect();
+                    scope.spawn(move || {
+                        children
+                            .into_iter()
+                            .map(|(index, child)| {
+                                hash_child(&mut path_prefix, index, child, threads_per_worker)
+                            })
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            handles
+                .into_iter()
+                .flat_map(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect()
+        })
+    };
+
+    let mut children: Vec<Option<PendingHashes>> = iter::repeat_with(|| None)
+        .take(BranchNode::MAX_CHILDREN)
+        .collect();
+    for (index, hashes) in hashed {
+        if let Some(slot) = children.get_mut(index) {
+            *slot = Some(hashes);
+        }
+    }
+    let hash = NodeAndPrefix {
+        node: &PendingBranch {
+            branch,
+            children: &children,
+        },
+        prefix: path_prefix,
+    }
+    .into();
+    PendingHashes { hash, children }
+}
+
 /// Returns the serialized representation of `node` used as the pre-image
 /// when hashing the node. The node is at the given `path_prefix`.
 pub fn hash_preimage(node: &Node, path_prefix: &Path) -> Box<[u8]> {
@@ -75,7 +211,7 @@ impl HasUpdate for Vec<u8> {
     }
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
 /// A ValueDigest is either a node's value or the hash of its value.
 pub enum ValueDigest<T> {
     /// The node's value.
@@ -176,6 +312,35 @@ impl HashableNode for LeafNode {
     }
 }
 
+/// A branch whose unhashed children were hashed by [hash_unhashed]
+struct PendingBranch<'a> {
+    branch: &'a BranchNode,
+    children: &'a [Option<PendingHashes>],
+}
+
+impl HashableNode for PendingBranch<'_> {
+    fn partial_path(&self) -> impl Iterator<Item = u8> + Clone {
+        self.branch.partial_path.0.iter().copied()
+    }
+
+    fn value(&self) -> Option<&[u8]> {
+        self.branch.value.as_deref()
+    }
+
+    fn children_iter(&self) -> impl Iterator<Item = (usize, &TrieHash)> + Clone {
+        self.branch
+            .children
+            .iter()
+            .zip(self.children)
+            .enumerate()
+            .filter_map(|(index, (child, pending))| match child {
+                None => None,
+                Some(Child::AddressWithHash(_, hash)) => Some((index, hash)),
+                Some(Child::Node(_)) => pending.as_ref().map(|pending| (index, &pending.hash)),
+            })
+    }
+}
+
 struct NodeAndPrefix<'a, N: HashableNode> {
     node: &'a N,
     prefix: &'a Path,
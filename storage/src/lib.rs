@@ -11,26 +11,45 @@
 //!
 //! A [NodeStore] is backed by a [ReadableStorage] which is persisted storage.
 
+mod checksum;
 mod hashednode;
 mod linear;
 mod node;
+mod nodecache;
 mod nodestore;
+mod region;
 mod trie_hash;
 
 /// Logger module for handling logging functionality
 pub mod logger;
 
 // re-export these so callers don't need to know where they are
+pub use checksum::CorruptNode;
 pub use hashednode::{hash_node, hash_preimage, Hashable, Preimage, ValueDigest};
-pub use linear::{ReadableStorage, WritableStorage};
+pub use linear::{ReadStats, ReadableStorage, WritableStorage};
 pub use node::{
-    path::NibblesIterator, path::Path, BranchNode, Child, LeafNode, Node, PathIterItem,
+    path::NibblesIterator, path::Path, BranchNode, Child, LeafNode, Node, NodeFormat, PathIterItem,
 };
+pub use nodecache::{CacheUsage, NodeCachePolicy};
 pub use nodestore::{
-    Committed, HashedNodeReader, ImmutableProposal, LinearAddress, MutableProposal, NodeReader,
-    NodeStore, Parentable, ReadInMemoryNode, RootReader, TrieReader, UpdateError,
+    AllocationPolicy, AreaIndex, Committed, FreeListArea, FreeListCorruption, FreeListRecovery,
+    HashedNodeReader, ImmutableProposal, LinearAddress, MutableProposal, NodeReader, NodeStore,
+    Parentable, ReadInMemoryNode, RootReader, TrieReader, UpdateError, MAX_RESERVED_PREFIX_LEN,
+    MAX_UNPROMOTED, PARALLEL_FLUSH_THRESHOLD, PARALLEL_HASH_THRESHOLD,
 };
 
-pub use linear::{filebacked::FileBacked, memory::MemStore};
+pub use linear::{
+    filebacked::{FileBacked, ReadHook},
+    memory::MemStore,
+    readahead::ReadAhead,
+    DEFAULT_MAX_FLUSH_WRITE,
+};
+
+#[cfg(feature = "remote")]
+pub use linear::remote::{
+    extent_digests, FileFetcher, RangeFetcher, RemoteBacked, RemoteError, EXTENT_SIZE,
+};
+
+pub use region::{Region, RegionId, RegionLocks, RegionMap, WriteWitness};
 
 pub use trie_hash::TrieHash;
@@ -2,34 +2,83 @@
 // See the file LICENSE.md for licensing terms.
 
 // This synchronous file layer is a simple implementation of what we
-// want to do for I/O. This uses a [Mutex] lock around a simple `File`
-// object. Instead, we probably should use an IO system that can perform multiple
-// read/write operations at once
+// want to do for I/O. Reads and writes are positioned, so several threads
+// can use the `File` at once; only direct I/O writes, which read back the
+// blocks at either end of them, take a lock.
 
-use std::fs::{File, OpenOptions};
-use std::io::{Error, Read, Seek};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::io::{Error, ErrorKind, IoSlice, Read};
 use std::num::NonZero;
-use std::os::unix::fs::FileExt;
+use std::os::unix::fs::{FileExt, MetadataExt};
 use std::path::PathBuf;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Instant;
 
 use lru::LruCache;
-use metrics::counter;
+use metrics::{counter, histogram};
 
-use crate::{LinearAddress, Node};
+use crate::nodecache::{CacheUsage, NodeCache};
+use crate::nodestore::{check_area_address, read_area_node};
+use crate::region::{RegionLocks, WriteWitness};
+use crate::{AllocationPolicy, LinearAddress, Node, NodeCachePolicy, NodeFormat};
 
-use super::{ReadableStorage, WritableStorage};
+use super::readahead::{ReadAhead, ReadAheadThread};
+use super::{ReadStats, ReadableStorage, WritableStorage, DEFAULT_MAX_FLUSH_WRITE};
+
+/// Called with the offset and length of every read from the file, before the
+/// read happens. Used to inject latency or faults in tests.
+pub type ReadHook = Box<dyn Fn(u64, usize) + Send + Sync>;
+
+#[derive(Default)]
+struct ReadHookSlot(Mutex<Option<Arc<ReadHook>>>);
+
+impl std::fmt::Debug for ReadHookSlot {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let installed = self.0.lock().expect("poisoned lock").is_some();
+        f.debug_struct("ReadHook")
+            .field("installed", &installed)
+            .finish()
+    }
+}
 
 #[derive(Debug)]
 /// A [ReadableStorage] backed by a file
 pub struct FileBacked {
-    fd: Mutex<File>,
-    cache: Mutex<LruCache<LinearAddress, Arc<Node>>>,
+    fd: Arc<File>,
+    /// Held by direct I/O writes, so two of them don't read back and
+    /// rewrite the same block at once
+    direct_write: Mutex<()>,
+    cache: Mutex<NodeCache>,
     free_list_cache: Mutex<LruCache<LinearAddress, Option<LinearAddress>>>,
+    regions: RegionLocks,
+    read_hook: Arc<ReadHookSlot>,
+    allocation_policy: AllocationPolicy,
+    /// How many threads may hash a large proposal
+    hash_threads: NonZero<usize>,
+    /// How many threads may write the nodes of a large proposal
+    flush_parallelism: NonZero<usize>,
+    /// The most bytes of adjacent node areas written with a single call
+    max_flush_write: usize,
+    /// Free space at least this large is punched out of the file
+    hole_punch_threshold: Option<u64>,
+    /// The file system's block size; only whole blocks are punched
+    block_size: u64,
+    /// With direct I/O, the size and alignment of the blocks every read
+    /// and write covers
+    direct_block: Option<u64>,
+    /// Reads nodes ahead of iterations, if that is turned on
+    read_ahead: Option<ReadAheadThread>,
+    /// Node cache lookups that found the node
+    cache_hits: AtomicU64,
+    /// Node cache lookups that didn't
+    cache_misses: AtomicU64,
 }
 
 impl FileBacked {
-    /// Create or open a file at a given path
+    /// Create or open a file at a given path. Truncating it fails with
+    /// [ErrorKind::WouldBlock] while it is open with
+    /// [FileBacked::open_read_only].
     pub fn new(
         path: PathBuf,
         node_cache_size: NonZero<usize>,
@@ -40,15 +89,225 @@ impl FileBacked {
             .read(true)
             .write(true)
             .create(true)
-            .truncate(truncate)
+            .truncate(false)
             .open(path)?;
+        if truncate {
+            fd.try_lock()
+                .map_err(|err| lock_error(err, "open read-only"))?;
+            fd.set_len(0)?;
+            fd.unlock()?;
+        }
+        Self::from_fd(fd, node_cache_size, free_list_cache_size)
+    }
+
+    /// Open an existing file at a given path without writing to it, so that
+    /// another process can keep writing to it. A shared lock is held on the
+    /// file until it is dropped, which keeps the file from being truncated,
+    /// but not from being written to. Writes through it fail.
+    pub fn open_read_only(
+        path: PathBuf,
+        node_cache_size: NonZero<usize>,
+        free_list_cache_size: NonZero<usize>,
+    ) -> Result<Self, Error> {
+        let fd = OpenOptions::new().read(true).open(path)?;
+        fd.try_lock_shared()
+            .map_err(|err| lock_error(err, "being truncated"))?;
+        Self::from_fd(fd, node_cache_size, free_list_cache_size)
+    }
+
+    fn from_fd(
+        fd: File,
+        node_cache_size: NonZero<usize>,
+        free_list_cache_size: NonZero<usize>,
+    ) -> Result<Self, Error> {
+        let block_size = fd.metadata()?.blksize().max(1);
 
         Ok(Self {
-            fd: Mutex::new(fd),
-            cache: Mutex::new(LruCache::new(node_cache_size)),
+            fd: Arc::new(fd),
+            direct_write: Mutex::new(()),
+            cache: Mutex::new(NodeCache::new(NodeCachePolicy::default(), node_cache_size)),
             free_list_cache: Mutex::new(LruCache::new(free_list_cache_size)),
+            regions: RegionLocks::new(),
+            read_hook: Default::default(),
+            allocation_policy: Default::default(),
+            hash_threads: NonZero::<usize>::MIN,
+            flush_parallelism: NonZero::<usize>::MIN,
+            max_flush_write: DEFAULT_MAX_FLUSH_WRITE,
+            hole_punch_threshold: None,
+            block_size,
+            direct_block: None,
+            read_ahead: None,
+            cache_hits: AtomicU64::new(0),
+            cache_misses: AtomicU64::new(0),
         })
     }
+
+    /// Set how proposals on this file place their new nodes
+    pub const fn with_allocation_policy(mut self, policy: AllocationPolicy) -> Self {
+        self.allocation_policy = policy;
+        self
+    }
+
+    /// Set how many threads may hash the new nodes of a large proposal on
+    /// this file. The hashes don't depend on it.
+    pub const fn with_hash_threads(mut self, threads: NonZero<usize>) -> Self {
+        self.hash_threads = threads;
+        self
+    }
+
+    /// Set how many threads may write the new nodes of a large proposal to
+    /// this file when it is committed. The file is still synced once.
+    pub const fn with_flush_parallelism(mut self, threads: NonZero<usize>) -> Self {
+        self.flush_parallelism = threads;
+        self
+    }
+
+    /// Set the most bytes of adjacent node areas that flushing a proposal
+    /// writes with a single call
+    pub const fn with_max_flush_write(mut self, bytes: usize) -> Self {
+        self.max_flush_write = bytes;
+        self
+    }
+
+    /// Set which nodes the node cache keeps. This empties the cache, so set
+    /// it before reading.
+    pub fn with_node_cache_policy(mut self, policy: NodeCachePolicy) -> Self {
+        let cache = self.cache.get_mut().expect("poisoned lock");
+        *cache = NodeCache::new(policy, cache.capacity());
+        self
+    }
+
+    /// Which nodes the node cache keeps
+    pub fn node_cache_policy(&self) -> NodeCachePolicy {
+        self.cache.lock().expect("poisoned lock").policy()
+    }
+
+    /// Give free areas of at least `threshold` bytes back to the file
+    /// system by punching holes in the file, on file systems that support
+    /// it. `None`, the default, never punches holes.
+    pub const fn with_hole_punch_threshold(mut self, threshold: Option<u64>) -> Self {
+        self.hole_punch_threshold = threshold;
+        self
+    }
+
+    /// Read and write the file with direct I/O, bypassing the page cache so
+    /// that nodes are only cached once, in the node cache. Every read and
+    /// write then covers whole blocks of the file system, so a write that
+    /// doesn't first reads back the rest of the blocks at either end of it.
+    /// Fails on file systems that don't support direct I/O; on platforms
+    /// other than Linux, it is ignored with a warning.
+    pub fn with_direct_io(mut self, enabled: bool) -> Result<Self, Error> {
+        if !enabled {
+            return Ok(self);
+        }
+        let block = self.block_size.max(512);
+        if !block.is_power_of_two() {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("direct I/O needs a block size that is a power of two, not {block}"),
+            ));
+        }
+        if enable_direct_io(&self.fd)? {
+            self.direct_block = Some(block);
+        }
+        Ok(self)
+    }
+
+    /// Read nodes ahead of iterations on a background thread; see
+    /// [ReadAhead]. Set it after [FileBacked::with_direct_io], since the
+    /// thread reads the way the file is read then.
+    pub fn with_read_ahead(mut self, read_ahead: ReadAhead) -> Result<Self, Error> {
+        self.read_ahead = None;
+        if read_ahead.width == 0 || read_ahead.depth == 0 {
+            return Ok(self);
+        }
+        let fd = self.fd.clone();
+        let hook = self.read_hook.clone();
+        let direct_block = self.direct_block;
+        let read = move |addr: LinearAddress, format: NodeFormat, checksums: bool| {
+            check_area_address(addr)?;
+            // skip the length byte
+            let stream =
+                PredictiveReader::from_parts(fd.clone(), &hook, direct_block, addr.get() + 1);
+            read_area_node(addr, stream, format, checksums)
+        };
+        self.read_ahead = Some(ReadAheadThread::start(read_ahead, Box::new(read))?);
+        Ok(self)
+    }
+
+    /// The number of bytes the file takes on disk, which is less than its
+    /// size when it has holes
+    pub fn allocated_bytes(&self) -> Result<u64, Error> {
+        // st_blocks is always in 512 byte units
+        Ok(self.fd.metadata()?.blocks() * 512)
+    }
+
+    /// Flush everything written to the file to disk
+    pub fn sync(&self) -> Result<(), Error> {
+        self.fd.sync_all()
+    }
+
+    /// Release the lock held on the file, if any. A file opened with
+    /// [FileBacked::open_read_only] can be truncated afterwards.
+    pub fn unlock(&self) -> Result<(), Error> {
+        self.fd.unlock()
+    }
+
+    /// The number of node cache lookups that hit and missed since the file
+    /// was opened
+    pub fn node_cache_lookups(&self) -> (u64, u64) {
+        (
+            self.cache_hits.load(Ordering::Relaxed),
+            self.cache_misses.load(Ordering::Relaxed),
+        )
+    }
+
+    /// How many nodes the node cache holds, and roughly how much memory
+    /// they take
+    pub fn cache_usage(&self) -> CacheUsage {
+        self.cache.lock().expect("poisoned lock").usage()
+    }
+
+    /// Evict the `fraction` of the node cache that its policy evicts first,
+    /// rounded up, and return the number of nodes evicted. `fraction` is
+    /// clamped to `[0, 1]`.
+    pub fn shed_cache(&self, fraction: f64) -> usize {
+        let mut guard = self.cache.lock().expect("poisoned lock");
+        let fraction = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0)
+        };
+        let to_shed = (guard.len() as f64 * fraction).ceil() as usize;
+        for _ in 0..to_shed {
+            guard.evict();
+        }
+        counter!("firewood.cache.shed").increment(to_shed as u64);
+        to_shed
+    }
+
+    /// The number of nodes in the node cache
+    pub fn cached_nodes(&self) -> usize {
+        self.cache.lock().expect("poisoned lock").len()
+    }
+
+    /// Install a hook that runs before every read from the file, or remove
+    /// it with `None`. Streams that are already open keep the old hook.
+    pub fn set_read_hook(&self, hook: Option<ReadHook>) {
+        *self.read_hook.0.lock().expect("poisoned lock") = hook.map(Arc::new);
+    }
+}
+
+/// The error for a lock on the file that couldn't be taken because the
+/// file is `held`
+fn lock_error(err: TryLockError, held: &str) -> Error {
+    match err {
+        TryLockError::WouldBlock => Error::new(
+            ErrorKind::WouldBlock,
+            format!("the file is locked while it is {held}"),
+        ),
+        TryLockError::Error(err) => err,
+    }
 }
 
 impl ReadableStorage for FileBacked {
@@ -57,34 +316,117 @@ impl ReadableStorage for FileBacked {
     }
 
     fn size(&self) -> Result<u64, Error> {
-        self.fd
-            .lock()
-            .expect("poisoned lock")
-            .seek(std::io::SeekFrom::End(0))
+        Ok(self.fd.metadata()?.len())
     }
 
     fn read_cached_node(&self, addr: LinearAddress) -> Option<Arc<Node>> {
         let mut guard = self.cache.lock().expect("poisoned lock");
-        let cached = guard.get(&addr).cloned();
-        counter!("firewood.cache.node", "type" => if cached.is_some() { "hit" } else { "miss" })
-            .increment(1);
+        let cached = guard.get(&addr);
+        // labels that are all literals are static, so counting doesn't allocate
+        match cached {
+            Some(_) => {
+                counter!("firewood.cache.node", "type" => "hit").increment(1);
+                self.cache_hits.fetch_add(1, Ordering::Relaxed);
+            }
+            None => {
+                counter!("firewood.cache.node", "type" => "miss").increment(1);
+                ReadStats::add_cache_miss();
+                self.cache_misses.fetch_add(1, Ordering::Relaxed);
+            }
+        }
         cached
     }
 
+    fn cache_read_node(&self, addr: LinearAddress, depth: Option<usize>, node: &Arc<Node>) {
+        let mut guard = self.cache.lock().expect("poisoned lock");
+        guard.admit_read(addr, depth, node);
+    }
+
     fn free_list_cache(&self, addr: LinearAddress) -> Option<Option<LinearAddress>> {
         let mut guard = self.free_list_cache.lock().expect("poisoned lock");
         let cached = guard.pop(&addr);
         counter!("firewood.cache.freelist", "type" => if cached.is_some() { "hit" } else { "miss" }).increment(1);
         cached
     }
+
+    fn allocation_policy(&self) -> AllocationPolicy {
+        self.allocation_policy
+    }
+
+    fn hash_threads(&self) -> NonZero<usize> {
+        self.hash_threads
+    }
+
+    fn read_ahead(&self) -> ReadAhead {
+        self.read_ahead
+            .as_ref()
+            .map_or_else(ReadAhead::default, ReadAheadThread::config)
+    }
+
+    fn prefetch(&self, mut addrs: Vec<LinearAddress>, format: NodeFormat, checksums: bool) {
+        let Some(read_ahead) = &self.read_ahead else {
+            return;
+        };
+        {
+            let cache = self.cache.lock().expect("poisoned lock");
+            addrs.retain(|addr| !cache.contains(addr) && !read_ahead.is_staged(addr));
+        }
+        if !addrs.is_empty() {
+            read_ahead.request(addrs, format, checksums);
+        }
+    }
+
+    fn take_prefetched(&self, addr: LinearAddress) -> Option<Arc<Node>> {
+        self.read_ahead.as_ref()?.take(&addr)
+    }
 }
 
 impl WritableStorage for FileBacked {
-    fn write(&self, offset: u64, object: &[u8]) -> Result<usize, Error> {
-        self.fd
-            .lock()
-            .expect("poisoned lock")
-            .write_at(object, offset)
+    fn write(
+        &self,
+        witness: &WriteWitness<'_>,
+        offset: u64,
+        object: &[u8],
+    ) -> Result<usize, Error> {
+        witness.check(offset, object.len() as u64);
+        match self.direct_block {
+            Some(block) => {
+                let _guard = self.direct_write.lock().expect("poisoned lock");
+                write_direct(&self.fd, block, &[IoSlice::new(object)], offset)?;
+                Ok(object.len())
+            }
+            None => self.fd.write_at(object, offset),
+        }
+    }
+
+    fn write_vectored(
+        &self,
+        witness: &WriteWitness<'_>,
+        offset: u64,
+        parts: &[IoSlice<'_>],
+    ) -> Result<usize, Error> {
+        let len: usize = parts.iter().map(|part| part.len()).sum();
+        witness.check(offset, len as u64);
+        match self.direct_block {
+            Some(block) => {
+                let _guard = self.direct_write.lock().expect("poisoned lock");
+                write_direct(&self.fd, block, parts, offset)?;
+            }
+            None => write_all_vectored_at(&self.fd, parts, offset)?,
+        }
+        Ok(len)
+    }
+
+    fn max_flush_write(&self) -> usize {
+        self.max_flush_write
+    }
+
+    fn flush_parallelism(&self) -> NonZero<usize> {
+        self.flush_parallelism
+    }
+
+    fn region_locks(&self) -> &RegionLocks {
+        &self.regions
     }
 
     fn write_cached_nodes<'a>(
@@ -92,16 +434,30 @@ impl WritableStorage for FileBacked {
         nodes: impl Iterator<Item = (&'a std::num::NonZero<u64>, &'a std::sync::Arc<crate::Node>)>,
     ) -> Result<(), Error> {
         let mut guard = self.cache.lock().expect("poisoned lock");
+        let mut written = Vec::new();
         for (addr, node) in nodes {
-            guard.put(*addr, node.clone());
+            guard.admit_written(*addr, node);
+            if self.read_ahead.is_some() {
+                written.push(*addr);
+            }
+        }
+        if let Some(read_ahead) = &self.read_ahead {
+            read_ahead.invalidate(written.iter());
         }
         Ok(())
     }
 
     fn invalidate_cached_nodes<'a>(&self, addresses: impl Iterator<Item = &'a LinearAddress>) {
         let mut guard = self.cache.lock().expect("poisoned lock");
+        let mut invalidated = Vec::new();
         for addr in addresses {
             guard.pop(addr);
+            if self.read_ahead.is_some() {
+                invalidated.push(addr);
+            }
+        }
+        if let Some(read_ahead) = &self.read_ahead {
+            read_ahead.invalidate(invalidated.into_iter());
         }
     }
 
@@ -109,12 +465,190 @@ impl WritableStorage for FileBacked {
         let mut guard = self.free_list_cache.lock().expect("poisoned lock");
         guard.put(addr, next);
     }
+
+    fn release_free_space(
+        &self,
+        witness: &WriteWitness<'_>,
+        offset: u64,
+        len: u64,
+    ) -> Result<(), Error> {
+        if self
+            .hole_punch_threshold
+            .is_none_or(|threshold| len < threshold)
+        {
+            return Ok(());
+        }
+        let start = offset.next_multiple_of(self.block_size);
+        let end = (offset + len) / self.block_size * self.block_size;
+        if end <= start {
+            return Ok(());
+        }
+        witness.check(start, end - start);
+        if punch_hole(&self.fd, start, end - start)? {
+            counter!("firewood.space.punched").increment(end - start);
+        }
+        Ok(())
+    }
+}
+
+/// Write all of `parts` at `offset` with as few system calls as it takes
+#[cfg(target_os = "linux")]
+fn write_all_vectored_at(fd: &File, parts: &[IoSlice<'_>], mut offset: u64) -> Result<(), Error> {
+    /// The most parts a single call takes
+    const IOV_MAX: usize = 1024;
+
+    let mut parts = parts.to_vec();
+    let mut remaining = parts.as_mut_slice();
+    while !remaining.is_empty() {
+        let batch = remaining
+            .get(..remaining.len().min(IOV_MAX))
+            .unwrap_or_default();
+        let written = rustix::io::pwritev(fd, batch, offset)?;
+        if written == 0 {
+            return Err(Error::new(
+                ErrorKind::WriteZero,
+                "failed to write node areas",
+            ));
+        }
+        offset += written as u64;
+        IoSlice::advance_slices(&mut remaining, written);
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_all_vectored_at(fd: &File, parts: &[IoSlice<'_>], mut offset: u64) -> Result<(), Error> {
+    for part in parts {
+        fd.write_all_at(part, offset)?;
+        offset += part.len() as u64;
+    }
+    Ok(())
+}
+
+/// Write all of `parts` at `offset` to a file opened for direct I/O, whose
+/// writes have to cover whole blocks of `block` bytes from a buffer aligned
+/// to them. The blocks are staged in such a buffer, after reading back what
+/// the first and last of them hold around `parts`. The file's lock is held,
+/// so nothing else writes to those blocks meanwhile.
+fn write_direct(fd: &File, block: u64, parts: &[IoSlice<'_>], offset: u64) -> Result<(), Error> {
+    let len: u64 = parts.iter().map(|part| part.len() as u64).sum();
+    let start = offset / block * block;
+    let end = (offset + len).next_multiple_of(block);
+    let mut staging = AlignedBuffer::new((end - start) as usize, block as usize);
+    let staged = staging.as_mut_slice();
+    let block_len = block as usize;
+    let partial_start = offset != start;
+    if partial_start {
+        read_direct(fd, staged.get_mut(..block_len).unwrap_or_default(), start)?;
+    }
+    // unless it is the first block, which was just read
+    let partial_end = (offset + len) % block != 0;
+    if partial_end && !(partial_start && end - start == block) {
+        let last = staged.len() - block_len;
+        read_direct(fd, staged.get_mut(last..).unwrap_or_default(), end - block)?;
+    }
+    let mut at = (offset - start) as usize;
+    for part in parts {
+        staged
+            .get_mut(at..at + part.len())
+            .expect("the staging buffer covers every part")
+            .copy_from_slice(part);
+        at += part.len();
+    }
+    fd.write_all_at(staged, start)
+}
+
+/// Fill as much of `buf` as the file holds at `offset`, leaving the rest
+/// past its end as it is
+fn read_direct(fd: &File, buf: &mut [u8], mut offset: u64) -> Result<(), Error> {
+    let mut buf = buf;
+    while !buf.is_empty() {
+        match fd.read_at(buf, offset)? {
+            0 => break,
+            read => {
+                buf = buf.get_mut(read..).unwrap_or_default();
+                offset += read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Turn on direct I/O for `fd`. Returns false where it isn't available.
+#[cfg(target_os = "linux")]
+fn enable_direct_io(fd: &File) -> Result<bool, Error> {
+    use rustix::fs::{fcntl_getfl, fcntl_setfl, OFlags};
+
+    let flags = fcntl_getfl(fd)?;
+    fcntl_setfl(fd, flags | OFlags::DIRECT)?;
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn enable_direct_io(_fd: &File) -> Result<bool, Error> {
+    crate::logger::warn!("Direct I/O is only supported on Linux; using the page cache");
+    Ok(false)
+}
+
+/// A zeroed buffer whose start is aligned, for direct I/O
+struct AlignedBuffer {
+    bytes: Vec<u8>,
+    start: usize,
+    len: usize,
+}
+
+impl AlignedBuffer {
+    /// A buffer of `len` bytes aligned to `align`, a power of two
+    fn new(len: usize, align: usize) -> Self {
+        let bytes = vec![0; len + align];
+        let start = bytes.as_ptr().align_offset(align).min(align);
+        Self { bytes, start, len }
+    }
+
+    fn as_slice(&self) -> &[u8] {
+        self.bytes
+            .get(self.start..self.start + self.len)
+            .expect("allocated with room to align")
+    }
+
+    fn as_mut_slice(&mut self) -> &mut [u8] {
+        self.bytes
+            .get_mut(self.start..self.start + self.len)
+            .expect("allocated with room to align")
+    }
+}
+
+/// Deallocate the blocks of `len` bytes at `offset`, keeping the file size.
+/// Returns false if the file system doesn't support it.
+#[cfg(target_os = "linux")]
+fn punch_hole(fd: &File, offset: u64, len: u64) -> Result<bool, Error> {
+    use rustix::fs::{fallocate, FallocateFlags};
+    use rustix::io::Errno;
+
+    match fallocate(
+        fd,
+        FallocateFlags::PUNCH_HOLE | FallocateFlags::KEEP_SIZE,
+        offset,
+        len,
+    ) {
+        Ok(()) => Ok(true),
+        Err(Errno::OPNOTSUPP | Errno::NOSYS) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn punch_hole(_fd: &File, _offset: u64, _len: u64) -> Result<bool, Error> {
+    Ok(false)
 }
 
 /// A reader that can predictively read from a file, avoiding reading past boundaries, but reading in 1k chunks
 struct PredictiveReader {
-    fd: File,
-    buffer: [u8; Self::PREDICTIVE_READ_BUFFER_SIZE],
+    fd: Arc<File>,
+    hook: Option<Arc<ReadHook>>,
+    buffer: AlignedBuffer,
+    /// With direct I/O, the size of the aligned blocks to read
+    direct_block: Option<u64>,
     offset: u64,
     len: usize,
     pos: usize,
@@ -124,16 +658,27 @@ impl PredictiveReader {
     const PREDICTIVE_READ_BUFFER_SIZE: usize = 1024;
 
     fn new(fb: &FileBacked, start: u64) -> Self {
-        let fd = fb
-            .fd
-            .lock()
-            .expect("poisoned lock")
-            .try_clone()
-            .expect("resource exhaustion");
+        Self::from_parts(fb.fd.clone(), &fb.read_hook, fb.direct_block, start)
+    }
+
+    fn from_parts(
+        fd: Arc<File>,
+        hook: &ReadHookSlot,
+        direct_block: Option<u64>,
+        start: u64,
+    ) -> Self {
+        let hook = hook.0.lock().expect("poisoned lock").clone();
+
+        let buffer = match direct_block {
+            Some(block) => AlignedBuffer::new(block as usize, block as usize),
+            None => AlignedBuffer::new(Self::PREDICTIVE_READ_BUFFER_SIZE, 1),
+        };
 
         Self {
             fd,
-            buffer: [0u8; Self::PREDICTIVE_READ_BUFFER_SIZE],
+            hook,
+            buffer,
+            direct_block,
             offset: start,
             len: 0,
             pos: 0,
@@ -144,16 +689,36 @@ impl PredictiveReader {
 impl Read for PredictiveReader {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize, std::io::Error> {
         if self.len == self.pos {
-            let bytes_left_in_page = Self::PREDICTIVE_READ_BUFFER_SIZE
-                - (self.offset % Self::PREDICTIVE_READ_BUFFER_SIZE as u64) as usize;
-            self.fd.seek(std::io::SeekFrom::Start(self.offset))?;
-            let read = self.fd.read(&mut self.buffer[..bytes_left_in_page])?;
-            self.offset += read as u64;
+            // with direct I/O, the whole block holding the offset is read
+            let (start, len) = match self.direct_block {
+                Some(block) => (self.offset / block * block, block as usize),
+                None => (
+                    self.offset,
+                    Self::PREDICTIVE_READ_BUFFER_SIZE
+                        - (self.offset % Self::PREDICTIVE_READ_BUFFER_SIZE as u64) as usize,
+                ),
+            };
+            if let Some(hook) = &self.hook {
+                hook(start, len);
+            }
+            let started = Instant::now();
+            let read = self
+                .fd
+                .read_at(&mut self.buffer.as_mut_slice()[..len], start)?;
+            histogram!("firewood.io.read.latency").record(started.elapsed().as_secs_f64());
+            counter!("firewood.io.read.bytes").increment(read as u64);
+            ReadStats::add_bytes_read(read);
+            let skip = (self.offset - start) as usize;
+            if read <= skip {
+                return Ok(0);
+            }
+            self.offset = start + read as u64;
             self.len = read;
-            self.pos = 0;
+            self.pos = skip;
         }
         let max_to_return = std::cmp::min(buf.len(), self.len - self.pos);
-        buf[..max_to_return].copy_from_slice(&self.buffer[self.pos..self.pos + max_to_return]);
+        buf[..max_to_return]
+            .copy_from_slice(&self.buffer.as_slice()[self.pos..self.pos + max_to_return]);
         self.pos += max_to_return;
         Ok(max_to_return)
     }
@@ -162,6 +727,7 @@ impl Read for PredictiveReader {
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::nodestore::NodeStoreHeader;
     use std::io::Write;
     use tempfile::NamedTempFile;
 
@@ -225,4 +791,178 @@ mod test {
         assert_eq!(reader.read_to_string(&mut buf).unwrap(), 11000);
         assert_eq!(buf.len(), 11000);
     }
+
+    #[test]
+    fn read_only() {
+        let mut tf = NamedTempFile::new().unwrap();
+        write!(tf.as_file_mut(), "hello world").unwrap();
+        let open = |truncate| {
+            FileBacked::new(
+                tf.path().to_path_buf(),
+                NonZero::new(10).unwrap(),
+                NonZero::new(10).unwrap(),
+                truncate,
+            )
+        };
+        let writer = open(false).unwrap();
+
+        let reader = FileBacked::open_read_only(
+            tf.path().to_path_buf(),
+            NonZero::new(10).unwrap(),
+            NonZero::new(10).unwrap(),
+        )
+        .unwrap();
+        let mut buf = String::new();
+        reader
+            .stream_from(6)
+            .unwrap()
+            .read_to_string(&mut buf)
+            .unwrap();
+        assert_eq!(buf, "world");
+        // areas start past the header
+        let witness = WriteWitness::area();
+        let offset = NodeStoreHeader::SIZE;
+        assert!(reader.write(&witness, offset, b"j").is_err());
+        // the writer can still write, but not truncate the file
+        writer.write(&witness, offset, b"j").unwrap();
+        let err = open(true).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::WouldBlock);
+        assert_eq!(reader.size().unwrap(), offset + 1);
+
+        drop(reader);
+        open(true).unwrap();
+        assert_eq!(writer.size().unwrap(), 0);
+    }
+
+    #[test]
+    fn shed_cache() {
+        let tf = NamedTempFile::new().unwrap();
+        let fb = FileBacked::new(
+            tf.path().to_path_buf(),
+            NonZero::new(100).unwrap(),
+            NonZero::new(10).unwrap(),
+            false,
+        )
+        .unwrap();
+        let node = Arc::new(Node::Leaf(crate::LeafNode {
+            partial_path: crate::Path::new(),
+            value: smallvec::smallvec![b'v'],
+        }));
+        let addrs: Vec<LinearAddress> = (1..=10u64)
+            .map(|i| LinearAddress::new(i * 8).unwrap())
+            .collect();
+        fb.write_cached_nodes(addrs.iter().map(|addr| (addr, &node)))
+            .unwrap();
+        // touch the first node so it is the most recently used
+        assert!(fb.read_cached_node(addrs[0]).is_some());
+
+        assert_eq!(fb.shed_cache(0.25), 3);
+        assert_eq!(fb.cached_nodes(), 7);
+        assert!(fb.read_cached_node(addrs[0]).is_some());
+        assert!(fb.read_cached_node(addrs[1]).is_none());
+        assert!(fb.read_cached_node(addrs[3]).is_none());
+        assert!(fb.read_cached_node(addrs[4]).is_some());
+
+        assert_eq!(fb.shed_cache(f64::NAN), 0);
+        assert_eq!(fb.shed_cache(2.0), 7);
+        assert_eq!(fb.cached_nodes(), 0);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn direct_io() {
+        let tf = NamedTempFile::new().unwrap();
+        let fb = match FileBacked::new(
+            tf.path().to_path_buf(),
+            NonZero::new(10).unwrap(),
+            NonZero::new(10).unwrap(),
+            false,
+        )
+        .unwrap()
+        .with_direct_io(true)
+        {
+            Ok(fb) => fb,
+            // the file system doesn't support direct I/O
+            Err(err) if err.kind() == ErrorKind::InvalidInput => return,
+            Err(err) => panic!("{err}"),
+        };
+        let block = fb.direct_block.unwrap();
+
+        // writes that start and end within blocks keep their neighbors, in
+        // the first block where areas can be
+        let start = NodeStoreHeader::SIZE.next_multiple_of(block);
+        let witness = WriteWitness::area();
+        fb.write(&witness, start + 10, b"hello world").unwrap();
+        let parts = [IoSlice::new(b"across "), IoSlice::new(b"a block")];
+        fb.write_vectored(&witness, start + block - 3, &parts)
+            .unwrap();
+        fb.write(&witness, start + 13, b"p").unwrap();
+        assert_eq!(fb.size().unwrap() % block, 0);
+
+        let mut bytes = [0; 11];
+        fb.stream_from(start + 10)
+            .unwrap()
+            .read_exact(&mut bytes)
+            .unwrap();
+        assert_eq!(&bytes, b"helpo world");
+        let mut bytes = [0; 14];
+        fb.stream_from(start + block - 3)
+            .unwrap()
+            .read_exact(&mut bytes)
+            .unwrap();
+        assert_eq!(&bytes, b"across a block");
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn punch_hole() {
+        const MIB: u64 = 1 << 20;
+        let tf = NamedTempFile::new().unwrap();
+        let fb = FileBacked::new(
+            tf.path().to_path_buf(),
+            NonZero::new(10).unwrap(),
+            NonZero::new(10).unwrap(),
+            false,
+        )
+        .unwrap()
+        .with_hole_punch_threshold(Some(MIB));
+        let witness = WriteWitness::area();
+        fb.write(&witness, MIB, &vec![0xab; 8 * MIB as usize])
+            .unwrap();
+        fb.fd.sync_all().unwrap();
+        let allocated = fb.allocated_bytes().unwrap();
+
+        // too small to punch
+        fb.release_free_space(&witness, MIB + 100, MIB / 2).unwrap();
+        assert_eq!(fb.allocated_bytes().unwrap(), allocated);
+
+        fb.release_free_space(&witness, MIB + 100, 4 * MIB).unwrap();
+        let punched = allocated - fb.allocated_bytes().unwrap();
+        if punched == 0 {
+            // the file system can't punch holes
+            return;
+        }
+        assert!(punched >= 3 * MIB, "only {punched} bytes were released");
+
+        // the bytes outside the whole blocks released are untouched
+        let mut bytes = vec![0; 5 * MIB as usize];
+        fb.stream_from(MIB).unwrap().read_exact(&mut bytes).unwrap();
+        let first_block = fb.block_size as usize;
+        assert!(bytes[..first_block].iter().all(|byte| *byte == 0xab));
+        assert!(bytes[first_block..4 * MIB as usize]
+            .iter()
+            .all(|byte| *byte == 0));
+        assert!(bytes[4 * MIB as usize + 100..]
+            .iter()
+            .all(|byte| *byte == 0xab));
+
+        // a punched range can be written again
+        fb.write(&witness, 2 * MIB, b"again").unwrap();
+        let mut again = [0; 5];
+        fb.stream_from(2 * MIB)
+            .unwrap()
+            .read_exact(&mut again)
+            .unwrap();
+        assert_eq!(&again, b"again");
+    }
 }
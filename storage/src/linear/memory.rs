@@ -2,15 +2,21 @@
 // See the file LICENSE.md for licensing terms.
 
 use super::{ReadableStorage, WritableStorage};
+use crate::region::{RegionLocks, WriteWitness};
+use crate::AllocationPolicy;
 use std::{
     io::{Cursor, Read},
+    num::NonZero,
     sync::Mutex,
 };
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 /// An in-memory impelementation of [WritableStorage] and [ReadableStorage]
 pub struct MemStore {
     bytes: Mutex<Vec<u8>>,
+    regions: RegionLocks,
+    allocation_policy: AllocationPolicy,
+    hash_threads: NonZero<usize>,
 }
 
 impl MemStore {
@@ -18,12 +24,45 @@ impl MemStore {
     pub const fn new(bytes: Vec<u8>) -> Self {
         Self {
             bytes: Mutex::new(bytes),
+            regions: RegionLocks::new(),
+            allocation_policy: AllocationPolicy::Scatter,
+            hash_threads: NonZero::<usize>::MIN,
+        }
+    }
+
+    /// Set how proposals on this store place their new nodes
+    pub const fn with_allocation_policy(mut self, policy: AllocationPolicy) -> Self {
+        self.allocation_policy = policy;
+        self
+    }
+
+    /// Set how many threads may hash the new nodes of a large proposal on
+    /// this store
+    pub const fn with_hash_threads(mut self, threads: NonZero<usize>) -> Self {
+        self.hash_threads = threads;
+        self
+    }
+}
+
+impl Default for MemStore {
+    fn default() -> Self {
+        Self {
+            bytes: Default::default(),
+            regions: Default::default(),
+            allocation_policy: Default::default(),
+            hash_threads: NonZero::<usize>::MIN,
         }
     }
 }
 
 impl WritableStorage for MemStore {
-    fn write(&self, offset: u64, object: &[u8]) -> Result<usize, std::io::Error> {
+    fn write(
+        &self,
+        witness: &WriteWitness<'_>,
+        offset: u64,
+        object: &[u8],
+    ) -> Result<usize, std::io::Error> {
+        witness.check(offset, object.len() as u64);
         let offset = offset as usize;
         let mut guard = self.bytes.lock().expect("poisoned lock");
         if offset + object.len() > guard.len() {
@@ -32,6 +71,10 @@ impl WritableStorage for MemStore {
         guard[offset..offset + object.len()].copy_from_slice(object);
         Ok(object.len())
     }
+
+    fn region_locks(&self) -> &RegionLocks {
+        &self.regions
+    }
 }
 
 impl ReadableStorage for MemStore {
@@ -50,6 +93,14 @@ impl ReadableStorage for MemStore {
     fn size(&self) -> Result<u64, std::io::Error> {
         Ok(self.bytes.lock().expect("poisoned lock").len() as u64)
     }
+
+    fn allocation_policy(&self) -> AllocationPolicy {
+        self.allocation_policy
+    }
+
+    fn hash_threads(&self) -> NonZero<usize> {
+        self.hash_threads
+    }
 }
 
 #[allow(clippy::unwrap_used)]
@@ -68,13 +119,13 @@ mod test {
     #[test_case(&[(0,&[1, 2, 3]),(2,&[4])],(0,&[1,2,4]); "overwrite end of store")]
     #[test_case(&[(0,&[1, 2, 3]),(2,&[4,5])],(0,&[1,2,4,5]); "overwrite/extend end of store")]
     fn test_in_mem_write_linear_store(writes: &[(u64, &[u8])], expected: (u64, &[u8])) {
-        let store = MemStore {
-            bytes: Mutex::new(vec![]),
-        };
+        let store = MemStore::new(vec![]);
         assert_eq!(store.size().unwrap(), 0);
 
         for write in writes {
-            store.write(write.0, write.1).unwrap();
+            store
+                .write(&WriteWitness::unchecked(), write.0, write.1)
+                .unwrap();
         }
 
         let mut reader = store.stream_from(expected.0).unwrap();
@@ -17,14 +17,19 @@
 //!
 //! Each type is described in more detail below.
 
+use std::cell::Cell;
 use std::fmt::Debug;
-use std::io::{Error, Read};
+use std::io::{Error, IoSlice, Read};
 use std::num::NonZero;
 use std::sync::Arc;
 
-use crate::{LinearAddress, Node};
+use crate::region::{RegionLocks, WriteWitness};
+use crate::{AllocationPolicy, LinearAddress, Node, NodeFormat};
 pub(super) mod filebacked;
 pub mod memory;
+pub(super) mod readahead;
+#[cfg(feature = "remote")]
+pub(super) mod remote;
 
 /// Trait for readable storage.
 pub trait ReadableStorage: Debug + Sync + Send {
@@ -47,25 +52,94 @@ pub trait ReadableStorage: Debug + Sync + Send {
         None
     }
 
+    /// Offer a node just read from storage to the cache (if any). `depth` is
+    /// the number of levels below the root it was read at, if it was read
+    /// while descending from the root.
+    fn cache_read_node(&self, _addr: LinearAddress, _depth: Option<usize>, _node: &Arc<Node>) {}
+
     /// Fetch the next pointer from the freelist cache
     fn free_list_cache(&self, _addr: LinearAddress) -> Option<Option<LinearAddress>> {
         None
     }
+
+    /// How proposals on this storage place their new nodes
+    fn allocation_policy(&self) -> AllocationPolicy {
+        AllocationPolicy::default()
+    }
+
+    /// How many threads may hash the new nodes of a large proposal on this
+    /// storage
+    fn hash_threads(&self) -> NonZero<usize> {
+        NonZero::<usize>::MIN
+    }
+
+    /// How far iterations on this storage read ahead of themselves
+    fn read_ahead(&self) -> readahead::ReadAhead {
+        readahead::ReadAhead::default()
+    }
+
+    /// Start reading the nodes at `addrs`, stored in `format`, in the
+    /// background, for an iteration that will reach them soon (if reading
+    /// ahead is turned on)
+    fn prefetch(&self, _addrs: Vec<LinearAddress>, _format: NodeFormat, _checksums: bool) {}
+
+    /// Take the node at `addr` out of the nodes read ahead, if it is there
+    fn take_prefetched(&self, _addr: LinearAddress) -> Option<Arc<Node>> {
+        None
+    }
 }
 
+/// How many bytes of adjacent node areas are written with a single call,
+/// unless the storage says otherwise
+pub const DEFAULT_MAX_FLUSH_WRITE: usize = 1 << 20;
+
 /// Trait for writable storage.
 pub trait WritableStorage: ReadableStorage {
     /// Writes the given object at the specified offset.
     ///
     /// # Arguments
     ///
+    /// * `witness` - Permission to write this range; implementations should call
+    ///   [WriteWitness::check] before writing.
     /// * `offset` - The offset at which to write the object.
     /// * `object` - The object to write.
     ///
     /// # Returns
     ///
     /// The number of bytes written, or an error if the write operation fails.
-    fn write(&self, offset: u64, object: &[u8]) -> Result<usize, Error>;
+    fn write(&self, witness: &WriteWitness<'_>, offset: u64, object: &[u8])
+        -> Result<usize, Error>;
+
+    /// Writes all of `parts`, one after another, starting at `offset`, and
+    /// returns the number of bytes written. Storage that can should write
+    /// them with a single call.
+    fn write_vectored(
+        &self,
+        witness: &WriteWitness<'_>,
+        offset: u64,
+        parts: &[IoSlice<'_>],
+    ) -> Result<usize, Error> {
+        let mut written = 0;
+        for part in parts {
+            written += self.write(witness, offset + written as u64, part)?;
+        }
+        Ok(written)
+    }
+
+    /// The most bytes of adjacent node areas flushing a proposal writes
+    /// with a single call
+    fn max_flush_write(&self) -> usize {
+        DEFAULT_MAX_FLUSH_WRITE
+    }
+
+    /// How many threads may write the new nodes of a large proposal when it
+    /// is committed
+    fn flush_parallelism(&self) -> NonZero<usize> {
+        NonZero::<usize>::MIN
+    }
+
+    /// The locks for the reserved regions of this storage
+    fn region_locks(&self) -> &RegionLocks;
 
     /// Write all nodes to the cache (if any)
     fn write_cached_nodes<'a>(
@@ -80,4 +154,67 @@ pub trait WritableStorage: ReadableStorage {
 
     /// Add a new entry to the freelist cache
     fn add_to_free_list_cache(&self, _addr: LinearAddress, _next: Option<LinearAddress>) {}
+
+    /// The `len` bytes at `offset` are free and hold nothing that will be
+    /// read before they are written again, so the storage may give back the
+    /// space they take. Like a write, this needs a `witness` for the range.
+    fn release_free_space(
+        &self,
+        _witness: &WriteWitness<'_>,
+        _offset: u64,
+        _len: u64,
+    ) -> Result<(), Error> {
+        Ok(())
+    }
+}
+
+/// Storage reads done by the current thread. Callers take a snapshot with
+/// [ReadStats::current] before an operation and subtract it afterwards to
+/// attribute reads to that operation.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReadStats {
+    /// The number of node reads that missed the cache
+    pub cache_misses: u64,
+    /// The number of bytes read from storage
+    pub bytes_read: u64,
+}
+
+thread_local! {
+    static READ_STATS: Cell<ReadStats> = const {
+        Cell::new(ReadStats {
+            cache_misses: 0,
+            bytes_read: 0,
+        })
+    };
+}
+
+impl ReadStats {
+    /// The reads done by the current thread so far
+    pub fn current() -> Self {
+        READ_STATS.get()
+    }
+
+    /// The reads done between `earlier` and this snapshot
+    pub const fn since(&self, earlier: &Self) -> Self {
+        Self {
+            cache_misses: self.cache_misses.wrapping_sub(earlier.cache_misses),
+            bytes_read: self.bytes_read.wrapping_sub(earlier.bytes_read),
+        }
+    }
+
+    pub(crate) fn add_cache_miss() {
+        READ_STATS.with(|stats| {
+            let mut current = stats.get();
+            current.cache_misses += 1;
+            stats.set(current);
+        });
+    }
+
+    pub(crate) fn add_bytes_read(bytes: usize) {
+        READ_STATS.with(|stats| {
+            let mut current = stats.get();
+            current.bytes_read += bytes as u64;
+            stats.set(current);
+        });
+    }
 }
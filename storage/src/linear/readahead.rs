@@ -0,0 +1,221 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Reading nodes ahead of an iteration on a background thread, for
+//! [FileBacked::with_read_ahead](crate::FileBacked::with_read_ahead).
+//!
+//! When an iteration descends into a child of a branch, it asks for the
+//! next siblings of that child, so they are read while it walks the
+//! child's subtree. The nodes read ahead are staged apart from the node
+//! cache, so that nodes the iteration never reaches don't evict the ones
+//! point lookups keep coming back to. A staged node is taken out when it is
+//! read, and only then offered to the node cache. The oldest staged nodes
+//! are dropped to make room for new ones.
+
+use std::num::NonZero;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+
+use lru::LruCache;
+use metrics::counter;
+
+use crate::{Child, LinearAddress, Node, NodeFormat};
+
+/// How far an iteration reads ahead of itself
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct ReadAhead {
+    /// How many of the next siblings of a child are read when an iteration
+    /// descends into it. 0, the default, reads nothing ahead.
+    pub width: usize,
+    /// How many levels of each of those siblings are read: 1 reads only the
+    /// siblings, 2 also reads the first `width` children of those that are
+    /// branches, and so on
+    pub depth: usize,
+}
+
+/// How many nodes read ahead can be staged
+const STAGED_NODES: usize = 4096;
+
+/// How many requests can wait for the thread. Requests past this are
+/// dropped, since reading ahead is only a hint.
+const QUEUED_REQUESTS: usize = 64;
+
+/// Reads the node at an address, stored in a format, checking checksums or
+/// not
+pub(super) type ReadNode =
+    Box<dyn Fn(LinearAddress, NodeFormat, bool) -> Result<Node, std::io::Error> + Send>;
+
+/// Nodes to read ahead
+struct Request {
+    addrs: Vec<LinearAddress>,
+    format: NodeFormat,
+    checksums: bool,
+}
+
+/// The nodes read ahead that no read has taken yet
+#[derive(Debug)]
+struct Staged {
+    nodes: LruCache<LinearAddress, Arc<Node>>,
+    /// Bumped whenever nodes are written or freed, so that a node read
+    /// before that isn't staged after it
+    epoch: u64,
+}
+
+/// The thread that reads nodes ahead, and the nodes it has staged
+#[derive(Debug)]
+pub(super) struct ReadAheadThread {
+    config: ReadAhead,
+    sender: Option<SyncSender<Request>>,
+    staged: Arc<Mutex<Staged>>,
+    stopping: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ReadAheadThread {
+    /// Start the thread, which reads nodes with `read`
+    pub(super) fn start(config: ReadAhead, read: ReadNode) -> Result<Self, std::io::Error> {
+        let (sender, receiver) = sync_channel::<Request>(QUEUED_REQUESTS);
+        let staged = Arc::new(Mutex::new(Staged {
+            nodes: LruCache::new(NonZero::new(STAGED_NODES).expect("non-zero")),
+            epoch: 0,
+        }));
+        let stopping = Arc::new(AtomicBool::new(false));
+        let thread = std::thread::Builder::new()
+            .name("firewood-readahead".into())
+            .spawn({
+                let staged = staged.clone();
+                let stopping = stopping.clone();
+                move || {
+                    let reader = Reader {
+                        config,
+                        read,
+                        staged,
+                        stopping,
+                    };
+                    for request in receiver {
+                        for addr in request.addrs.iter().copied() {
+                            reader.stage(addr, &request, config.depth);
+                        }
+                    }
+                }
+            })?;
+        Ok(Self {
+            config,
+            sender: Some(sender),
+            staged,
+            stopping,
+            thread: Some(thread),
+        })
+    }
+
+    pub(super) const fn config(&self) -> ReadAhead {
+        self.config
+    }
+
+    /// Whether the node at `addr` is staged
+    pub(super) fn is_staged(&self, addr: &LinearAddress) -> bool {
+        self.staged
+            .lock()
+            .expect("poisoned lock")
+            .nodes
+            .contains(addr)
+    }
+
+    /// Ask the thread to read the nodes at `addrs`, if it isn't too busy
+    pub(super) fn request(&self, addrs: Vec<LinearAddress>, format: NodeFormat, checksums: bool) {
+        let sender = self.sender.as_ref().expect("only taken on drop");
+        let request = Request {
+            addrs,
+            format,
+            checksums,
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(request) {
+            counter!("firewood.readahead.dropped").increment(1);
+        }
+    }
+
+    /// Take the node at `addr` out of the staged nodes
+    pub(super) fn take(&self, addr: &LinearAddress) -> Option<Arc<Node>> {
+        let node = self.staged.lock().expect("poisoned lock").nodes.pop(addr);
+        if node.is_some() {
+            counter!("firewood.readahead.hit").increment(1);
+        }
+        node
+    }
+
+    /// Drop the staged nodes at `addrs`, and any being read now
+    pub(super) fn invalidate<'a>(&self, addrs: impl Iterator<Item = &'a LinearAddress>) {
+        let mut staged = self.staged.lock().expect("poisoned lock");
+        staged.epoch += 1;
+        for addr in addrs {
+            staged.nodes.pop(addr);
+        }
+    }
+}
+
+impl Drop for ReadAheadThread {
+    fn drop(&mut self) {
+        // the thread skips what is queued, and ends once the channel is closed
+        self.stopping.store(true, Ordering::Relaxed);
+        self.sender = None;
+        if let Some(thread) = self.thread.take() {
+            if thread.join().is_err() {
+                crate::logger::warn!("The read-ahead thread panicked");
+            }
+        }
+    }
+}
+
+/// What the thread reads nodes with
+struct Reader {
+    config: ReadAhead,
+    read: ReadNode,
+    staged: Arc<Mutex<Staged>>,
+    stopping: Arc<AtomicBool>,
+}
+
+impl Reader {
+    /// Read and stage the node at `addr`, and `depth - 1` levels below it
+    fn stage(&self, addr: LinearAddress, request: &Request, depth: usize) {
+        if depth == 0 || self.stopping.load(Ordering::Relaxed) {
+            return;
+        }
+        let epoch = {
+            let staged = self.staged.lock().expect("poisoned lock");
+            if staged.nodes.contains(&addr) {
+                return;
+            }
+            staged.epoch
+        };
+        let Ok(node) = (self.read)(addr, request.format, request.checksums) else {
+            // the iteration reports the error when it reaches the node
+            counter!("firewood.readahead.failed").increment(1);
+            return;
+        };
+        let children: Vec<_> = match &node {
+            Node::Branch(branch) if depth > 1 => branch
+                .children
+                .iter()
+                .filter_map(|child| match child {
+                    Some(Child::AddressWithHash(addr, _)) => Some(*addr),
+                    _ => None,
+                })
+                .take(self.config.width)
+                .collect(),
+            _ => Vec::new(),
+        };
+        {
+            let mut staged = self.staged.lock().expect("poisoned lock");
+            if staged.epoch != epoch {
+                return;
+            }
+            staged.nodes.put(addr, Arc::new(node));
+        }
+        counter!("firewood.readahead.read").increment(1);
+        for child in children {
+            self.stage(child, request, depth - 1);
+        }
+    }
+}
@@ -0,0 +1,624 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! A read-only [ReadableStorage] for replicas of a database that lives
+//! somewhere else.
+//!
+//! The remote file is split into extents of [EXTENT_SIZE] bytes, which are
+//! fetched with a [RangeFetcher] the first time they are read. Fetched
+//! extents are written to a sparse local cache file at the same offsets, so
+//! every later read of them is local, even after the replica is reopened. A
+//! sidecar file next to the cache records which extents it holds.
+//!
+//! Fetched extents are checked against the digests the remote publishes, if
+//! it publishes any, and [RemoteBacked::open] checks the root hash, so a
+//! tampered remote is detected instead of served. Failures are reported as
+//! a [RemoteError] inside the returned [Error]; nothing is retried here.
+
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, ErrorKind, Read};
+use std::os::unix::fs::FileExt;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+
+use metrics::counter;
+use sha2::{Digest, Sha256};
+
+use crate::{Committed, HashedNodeReader, NodeStore, TrieHash};
+
+use super::{ReadStats, ReadableStorage};
+
+/// The unit in which the remote file is fetched and cached
+pub const EXTENT_SIZE: u64 = 64 * 1024;
+
+/// Fetches byte ranges of the remote file a [RemoteBacked] replicates
+pub trait RangeFetcher: fmt::Debug + Send + Sync {
+    /// The size of the remote file, in bytes
+    fn size(&self) -> Result<u64, Error>;
+
+    /// Fetch up to `len` bytes starting at `offset`. Returning fewer bytes
+    /// is only expected at the end of the file.
+    fn fetch(&self, offset: u64, len: usize) -> Result<Vec<u8>, Error>;
+
+    /// The SHA-256 digest of extent `index`, if the remote publishes one.
+    /// See [extent_digests].
+    fn extent_digest(&self, _index: u64) -> Result<Option<[u8; 32]>, Error> {
+        Ok(None)
+    }
+}
+
+/// Why a read from a [RemoteBacked] failed. These are returned inside an
+/// [Error]; use [RemoteError::from_io] to get them back out.
+#[derive(Debug)]
+pub enum RemoteError {
+    /// The fetcher failed. Retrying the read may succeed.
+    Fetch {
+        /// The offset of the extent being fetched
+        offset: u64,
+        /// The error from the fetcher
+        source: Error,
+    },
+    /// The fetcher returned fewer bytes than the extent holds
+    ShortFetch {
+        /// The offset of the extent being fetched
+        offset: u64,
+        /// The number of bytes in the extent
+        expected: usize,
+        /// The number of bytes returned
+        got: usize,
+    },
+    /// A fetched extent doesn't match the digest the remote published for it
+    Digest {
+        /// The index of the extent
+        extent: u64,
+    },
+    /// The remote trie doesn't have the expected root hash
+    RootMismatch {
+        /// The root hash the replica was opened with
+        expected: TrieHash,
+        /// The root hash of the remote trie
+        found: Option<TrieHash>,
+    },
+}
+
+impl RemoteError {
+    /// The [RemoteError] inside `err`, if it came from a [RemoteBacked]
+    pub fn from_io(err: &Error) -> Option<&Self> {
+        err.get_ref()?.downcast_ref()
+    }
+}
+
+impl Display for RemoteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Fetch { offset, source } => {
+                write!(f, "fetching the extent at {offset} failed: {source}")
+            }
+            Self::ShortFetch {
+                offset,
+                expected,
+                got,
+            } => write!(
+                f,
+                "fetching the extent at {offset} returned {got} of {expected} bytes"
+            ),
+            Self::Digest { extent } => write!(f, "extent {extent} doesn't match its digest"),
+            Self::RootMismatch { expected, found } => {
+                write!(f, "expected root hash {expected:?}, found {found:?}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RemoteError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Fetch { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+impl From<RemoteError> for Error {
+    fn from(err: RemoteError) -> Self {
+        let kind = match &err {
+            RemoteError::Fetch { source, .. } => source.kind(),
+            RemoteError::ShortFetch { .. } => ErrorKind::UnexpectedEof,
+            RemoteError::Digest { .. } | RemoteError::RootMismatch { .. } => ErrorKind::InvalidData,
+        };
+        Error::new(kind, err)
+    }
+}
+
+/// A [RangeFetcher] that reads a local file, as a reference implementation
+/// and for testing
+#[derive(Debug)]
+pub struct FileFetcher {
+    file: File,
+    digests: Option<Box<[[u8; 32]]>>,
+}
+
+impl FileFetcher {
+    /// Fetch from the file at `path`
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        Ok(Self {
+            file: File::open(path)?,
+            digests: None,
+        })
+    }
+
+    /// Publish `digests`, as computed by [extent_digests] from a trusted
+    /// copy of the file
+    pub fn with_digests(self, digests: Vec<[u8; 32]>) -> Self {
+        Self {
+            digests: Some(digests.into()),
+            ..self
+        }
+    }
+}
+
+impl RangeFetcher for FileFetcher {
+    fn size(&self) -> Result<u64, Error> {
+        Ok(self.file.metadata()?.len())
+    }
+
+    fn fetch(&self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        let mut bytes = vec![0; len];
+        let mut read = 0;
+        while let Some(rest) = bytes.get_mut(read..).filter(|rest| !rest.is_empty()) {
+            match self.file.read_at(rest, offset + read as u64)? {
+                0 => break,
+                n => read += n,
+            }
+        }
+        bytes.truncate(read);
+        Ok(bytes)
+    }
+
+    fn extent_digest(&self, index: u64) -> Result<Option<[u8; 32]>, Error> {
+        Ok(self
+            .digests
+            .as_ref()
+            .and_then(|digests| digests.get(index as usize).copied()))
+    }
+}
+
+/// The digest of each extent of the file at `path`, for a remote to publish
+/// alongside it
+pub fn extent_digests(path: &Path) -> Result<Vec<[u8; 32]>, Error> {
+    let mut file = File::open(path)?;
+    let mut digests = Vec::new();
+    let mut extent = Vec::with_capacity(EXTENT_SIZE as usize);
+    loop {
+        extent.clear();
+        (&mut file).take(EXTENT_SIZE).read_to_end(&mut extent)?;
+        if extent.is_empty() {
+            return Ok(digests);
+        }
+        digests.push(Sha256::digest(&extent).into());
+    }
+}
+
+/// The local copy of the extents fetched so far
+#[derive(Debug)]
+struct Shared {
+    fetcher: Arc<dyn RangeFetcher>,
+    size: u64,
+    cache: File,
+    /// Holds the expected root hash, then one byte per extent that is set
+    /// once the extent is in `cache`
+    present_file: File,
+    present: Box<[AtomicBool]>,
+    /// One lock per extent being fetched, so concurrent reads of an extent
+    /// fetch it once
+    fetching: Mutex<HashMap<u64, Arc<Mutex<()>>>>,
+}
+
+impl Shared {
+    const PRESENT_HEADER_LEN: u64 = 32;
+
+    fn is_present(&self, index: u64) -> bool {
+        self.present
+            .get(index as usize)
+            .is_some_and(|present| present.load(Ordering::Acquire))
+    }
+
+    /// Make sure extent `index` is in the cache file
+    fn ensure_extent(&self, index: u64) -> Result<(), Error> {
+        if self.is_present(index) {
+            return Ok(());
+        }
+
+        let slot = self
+            .fetching
+            .lock()
+            .expect("poisoned lock")
+            .entry(index)
+            .or_default()
+            .clone();
+        let _fetching = slot.lock().expect("poisoned lock");
+        if self.is_present(index) {
+            // fetched while we waited
+            return Ok(());
+        }
+
+        let result = self.fetch_extent(index);
+        self.fetching.lock().expect("poisoned lock").remove(&index);
+        result
+    }
+
+    fn fetch_extent(&self, index: u64) -> Result<(), Error> {
+        let offset = index * EXTENT_SIZE;
+        let expected = EXTENT_SIZE.min(self.size.saturating_sub(offset)) as usize;
+        counter!("firewood.remote.fetch").increment(1);
+        let bytes = self
+            .fetcher
+            .fetch(offset, expected)
+            .map_err(|source| RemoteError::Fetch { offset, source })?;
+        if bytes.len() != expected {
+            return Err(RemoteError::ShortFetch {
+                offset,
+                expected,
+                got: bytes.len(),
+            }
+            .into());
+        }
+        if let Some(digest) = self.fetcher.extent_digest(index)? {
+            if <[u8; 32]>::from(Sha256::digest(&bytes)) != digest {
+                return Err(RemoteError::Digest { extent: index }.into());
+            }
+        }
+
+        // the extent must be durable before it is marked as present
+        self.cache.write_all_at(&bytes, offset)?;
+        self.cache.sync_data()?;
+        self.present_file
+            .write_all_at(&[1], Self::PRESENT_HEADER_LEN + index)?;
+        if let Some(present) = self.present.get(index as usize) {
+            present.store(true, Ordering::Release);
+        }
+        Ok(())
+    }
+}
+
+/// A read-only [ReadableStorage] that fetches a remote file on demand and
+/// keeps what it fetched in a local cache file. See the [module
+/// documentation](self).
+#[derive(Debug)]
+pub struct RemoteBacked {
+    shared: Arc<Shared>,
+}
+
+impl RemoteBacked {
+    /// Create a replica of the remote file behind `fetcher`, caching fetched
+    /// extents in the file at `cache_path`. The cache is kept if it was
+    /// filled for the same `root_hash`, and cleared otherwise.
+    pub fn new(
+        fetcher: Arc<dyn RangeFetcher>,
+        cache_path: PathBuf,
+        root_hash: &TrieHash,
+    ) -> Result<Self, Error> {
+        let size = fetcher.size()?;
+        let extents = size.div_ceil(EXTENT_SIZE);
+
+        let open = |path: &Path| {
+            OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(false)
+                .open(path)
+        };
+        let cache = open(&cache_path)?;
+        let mut present_path = cache_path.into_os_string();
+        present_path.push(".extents");
+        let present_file = open(Path::new(&present_path))?;
+
+        let mut recorded = vec![0; (Shared::PRESENT_HEADER_LEN + extents) as usize];
+        let reusable = present_file.read_exact_at(&mut recorded, 0).is_ok()
+            && recorded.get(..Shared::PRESENT_HEADER_LEN as usize) == Some(&root_hash[..]);
+        if !reusable {
+            // filled for another revision, or never filled
+            cache.set_len(0)?;
+            cache.set_len(size)?;
+            recorded.fill(0);
+            recorded
+                .iter_mut()
+                .zip(root_hash.iter())
+                .for_each(|(byte, hash)| *byte = *hash);
+            present_file.set_len(0)?;
+            present_file.write_all_at(&recorded, 0)?;
+            present_file.sync_data()?;
+        }
+
+        let present = recorded
+            .iter()
+            .skip(Shared::PRESENT_HEADER_LEN as usize)
+            .map(|byte| AtomicBool::new(*byte != 0))
+            .collect();
+        Ok(Self {
+            shared: Arc::new(Shared {
+                fetcher,
+                size,
+                cache,
+                present_file,
+                present,
+                fetching: Default::default(),
+            }),
+        })
+    }
+
+    /// Open the trie in the remote file, which must have root hash
+    /// `root_hash`. See [RemoteBacked::new].
+    pub fn open(
+        fetcher: Arc<dyn RangeFetcher>,
+        cache_path: PathBuf,
+        root_hash: &TrieHash,
+    ) -> Result<NodeStore<Committed, Self>, Error> {
+        let storage = Arc::new(Self::new(fetcher, cache_path, root_hash)?);
+        let nodestore = NodeStore::open(storage)?;
+        let found = nodestore.root_hash()?;
+        if found.as_ref() != Some(root_hash) {
+            return Err(RemoteError::RootMismatch {
+                expected: root_hash.clone(),
+                found,
+            }
+            .into());
+        }
+        Ok(nodestore)
+    }
+
+    /// The number of extents in the local cache file
+    pub fn cached_extents(&self) -> usize {
+        self.shared
+            .present
+            .iter()
+            .filter(|present| present.load(Ordering::Relaxed))
+            .count()
+    }
+}
+
+impl ReadableStorage for RemoteBacked {
+    fn stream_from(&self, addr: u64) -> Result<Box<dyn Read>, Error> {
+        Ok(Box::new(RemoteReader {
+            shared: self.shared.clone(),
+            offset: addr,
+        }))
+    }
+
+    fn size(&self) -> Result<u64, Error> {
+        Ok(self.shared.size)
+    }
+}
+
+/// Reads from the local cache file, fetching each extent as it is reached
+struct RemoteReader {
+    shared: Arc<Shared>,
+    offset: u64,
+}
+
+impl Read for RemoteReader {
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, Error> {
+        if self.offset >= self.shared.size {
+            return Ok(0);
+        }
+        let index = self.offset / EXTENT_SIZE;
+        self.shared.ensure_extent(index)?;
+
+        let extent_end = ((index + 1) * EXTENT_SIZE).min(self.shared.size);
+        let len = buf.len().min((extent_end - self.offset) as usize);
+        let buf = buf.get_mut(..len).unwrap_or_default();
+        let read = self.shared.cache.read_at(buf, self.offset)?;
+        ReadStats::add_bytes_read(read);
+        self.offset += read as u64;
+        Ok(read)
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used, clippy::indexing_slicing)]
+mod test {
+    use std::io::Write;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Barrier;
+    use std::thread;
+
+    use tempfile::{tempdir, NamedTempFile};
+
+    use super::*;
+
+    /// Counts the extents fetched from the inner fetcher
+    #[derive(Debug)]
+    struct Counting<F> {
+        inner: F,
+        fetches: AtomicUsize,
+    }
+
+    impl<F: RangeFetcher> RangeFetcher for Counting<F> {
+        fn size(&self) -> Result<u64, Error> {
+            self.inner.size()
+        }
+
+        fn fetch(&self, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+            self.fetches.fetch_add(1, Ordering::Relaxed);
+            // give concurrent readers a chance to pile up
+            thread::sleep(std::time::Duration::from_millis(5));
+            self.inner.fetch(offset, len)
+        }
+
+        fn extent_digest(&self, index: u64) -> Result<Option<[u8; 32]>, Error> {
+            self.inner.extent_digest(index)
+        }
+    }
+
+    /// Fails every fetch
+    #[derive(Debug)]
+    struct Failing;
+
+    impl RangeFetcher for Failing {
+        fn size(&self) -> Result<u64, Error> {
+            Ok(EXTENT_SIZE)
+        }
+
+        fn fetch(&self, _offset: u64, _len: usize) -> Result<Vec<u8>, Error> {
+            Err(Error::new(ErrorKind::TimedOut, "remote unavailable"))
+        }
+    }
+
+    /// A remote file three and a half extents long, with distinct contents
+    fn remote_file() -> (NamedTempFile, Vec<u8>) {
+        let contents: Vec<u8> = (0..EXTENT_SIZE * 7 / 2).map(|i| (i % 251) as u8).collect();
+        let mut file = NamedTempFile::new().unwrap();
+        file.write_all(&contents).unwrap();
+        (file, contents)
+    }
+
+    fn counting(path: &Path) -> Arc<Counting<FileFetcher>> {
+        Arc::new(Counting {
+            inner: FileFetcher::new(path).unwrap(),
+            fetches: AtomicUsize::new(0),
+        })
+    }
+
+    fn read_at(storage: &RemoteBacked, offset: u64, len: usize) -> Result<Vec<u8>, Error> {
+        let mut bytes = Vec::new();
+        storage
+            .stream_from(offset)?
+            .take(len as u64)
+            .read_to_end(&mut bytes)?;
+        Ok(bytes)
+    }
+
+    #[test]
+    fn reads_fetch_once() {
+        let (remote, contents) = remote_file();
+        let dir = tempdir().unwrap();
+        let cache_path = dir.path().join("cache");
+        let root = TrieHash::from([1; 32]);
+
+        let fetcher = counting(remote.path());
+        let storage = RemoteBacked::new(fetcher.clone(), cache_path.clone(), &root).unwrap();
+        assert_eq!(storage.size().unwrap(), contents.len() as u64);
+
+        // a read across an extent boundary fetches both extents
+        let start = EXTENT_SIZE - 10;
+        assert_eq!(
+            read_at(&storage, start, 20).unwrap(),
+            &contents[start as usize..start as usize + 20]
+        );
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 2);
+        assert_eq!(storage.cached_extents(), 2);
+
+        // the last, partial extent and reads past the end
+        let tail = read_at(&storage, EXTENT_SIZE * 3, usize::MAX).unwrap();
+        assert_eq!(tail, &contents[EXTENT_SIZE as usize * 3..]);
+        assert!(read_at(&storage, contents.len() as u64, 1)
+            .unwrap()
+            .is_empty());
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 3);
+
+        // everything is served locally now, even after reopening
+        assert_eq!(read_at(&storage, 0, usize::MAX).unwrap(), contents);
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 4);
+        drop(storage);
+        let fetcher = counting(remote.path());
+        let storage = RemoteBacked::new(fetcher.clone(), cache_path.clone(), &root).unwrap();
+        assert_eq!(read_at(&storage, 0, usize::MAX).unwrap(), contents);
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 0);
+
+        // a cache filled for another root is cleared
+        let storage =
+            RemoteBacked::new(fetcher.clone(), cache_path, &TrieHash::from([2; 32])).unwrap();
+        assert_eq!(storage.cached_extents(), 0);
+        assert_eq!(read_at(&storage, 0, 1).unwrap(), &contents[..1]);
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn concurrent_reads_fetch_once() {
+        let (remote, contents) = remote_file();
+        let dir = tempdir().unwrap();
+        let fetcher = counting(remote.path());
+        let storage = Arc::new(
+            RemoteBacked::new(
+                fetcher.clone(),
+                dir.path().join("cache"),
+                &TrieHash::default(),
+            )
+            .unwrap(),
+        );
+
+        let barrier = Arc::new(Barrier::new(8));
+        let readers: Vec<_> = (0..8)
+            .map(|reader| {
+                let storage = storage.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    read_at(&storage, reader * 100, 100).unwrap()
+                })
+            })
+            .collect();
+        for (reader, handle) in readers.into_iter().enumerate() {
+            let offset = reader * 100;
+            assert_eq!(handle.join().unwrap(), &contents[offset..offset + 100]);
+        }
+        assert_eq!(fetcher.fetches.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn tampered_extent() {
+        let (mut remote, contents) = remote_file();
+        let digests = extent_digests(remote.path()).unwrap();
+        assert_eq!(digests.len(), 4);
+
+        // change one byte of the second extent after publishing the digests
+        let offset = EXTENT_SIZE + 7;
+        remote
+            .as_file_mut()
+            .write_all_at(&[contents[offset as usize] ^ 1], offset)
+            .unwrap();
+
+        let dir = tempdir().unwrap();
+        let fetcher = FileFetcher::new(remote.path())
+            .unwrap()
+            .with_digests(digests);
+        let storage = RemoteBacked::new(
+            Arc::new(fetcher),
+            dir.path().join("cache"),
+            &TrieHash::default(),
+        )
+        .unwrap();
+
+        assert_eq!(read_at(&storage, 0, 10).unwrap(), &contents[..10]);
+        let err = read_at(&storage, offset, 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        assert!(matches!(
+            RemoteError::from_io(&err),
+            Some(RemoteError::Digest { extent: 1 })
+        ));
+        // nothing bad was cached
+        assert_eq!(storage.cached_extents(), 1);
+    }
+
+    #[test]
+    fn failed_fetch() {
+        let dir = tempdir().unwrap();
+        let storage = RemoteBacked::new(
+            Arc::new(Failing),
+            dir.path().join("cache"),
+            &TrieHash::default(),
+        )
+        .unwrap();
+        let err = read_at(&storage, 0, 1).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::TimedOut);
+        assert!(matches!(
+            RemoteError::from_io(&err),
+            Some(RemoteError::Fetch { offset: 0, .. })
+        ));
+        assert_eq!(storage.cached_extents(), 0);
+    }
+}
@@ -78,7 +78,10 @@ impl<'de> Deserialize<'de> for BranchNode {
         let mut children: [Option<Child>; BranchNode::MAX_CHILDREN] =
             [const { None }; BranchNode::MAX_CHILDREN];
         for (offset, addr, hash) in s.children.iter() {
-            children[*offset as usize] = Some(Child::AddressWithHash(*addr, hash.clone()));
+            let child = children
+                .get_mut(*offset as usize)
+                .ok_or_else(|| serde::de::Error::custom(format!("invalid child index {offset}")))?;
+            *child = Some(Child::AddressWithHash(*addr, hash.clone()));
         }
 
         Ok(BranchNode {
@@ -124,6 +127,19 @@ impl BranchNode {
     #[cfg(not(feature = "branch_factor_256"))]
     pub const MAX_CHILDREN: usize = 16;
 
+    /// The number of child indices, or nibbles, each byte of a key is split
+    /// into, so a key of `n` bytes is `n * NIBBLES_PER_BYTE` nibbles long
+    #[cfg(feature = "branch_factor_256")]
+    pub const NIBBLES_PER_BYTE: usize = 1;
+
+    /// The number of child indices, or nibbles, each byte of a key is split
+    /// into, so a key of `n` bytes is `n * NIBBLES_PER_BYTE` nibbles long
+    #[cfg(not(feature = "branch_factor_256"))]
+    pub const NIBBLES_PER_BYTE: usize = 2;
+
+    /// The number of bits of a key each nibble takes
+    pub const BITS_PER_NIBBLE: usize = 8 / Self::NIBBLES_PER_BYTE;
+
     /// Returns the address of the child at the given index.
     /// Panics if `child_index` >= [BranchNode::MAX_CHILDREN].
     pub fn child(&self, child_index: u8) -> &Option<Child> {
@@ -154,8 +170,36 @@ impl BranchNode {
             },
         )
     }
+
+    /// The number of children, hashed or not
+    pub fn num_children(&self) -> usize {
+        self.children.iter().flatten().count()
+    }
+
+    /// Whether the branch has neither children nor a value, so that it
+    /// holds no keys
+    pub fn is_empty(&self) -> bool {
+        self.value.is_none() && self.num_children() == 0
+    }
+
+    /// The index of the only child, if the branch has exactly one. Such a
+    /// branch without a value can be folded into its child.
+    pub fn has_single_child(&self) -> Option<u8> {
+        let mut indices = self
+            .children
+            .iter()
+            .enumerate()
+            .filter_map(|(index, child)| child.as_ref().map(|_| index as u8));
+        match (indices.next(), indices.next()) {
+            (Some(index), None) => Some(index),
+            _ => None,
+        }
+    }
 }
 
+// a nibble indexes every child, and nothing more
+const _: () = assert!(BranchNode::MAX_CHILDREN == 1 << BranchNode::BITS_PER_NIBBLE);
+
 impl From<&LeafNode> for BranchNode {
     fn from(leaf: &LeafNode) -> Self {
         BranchNode {
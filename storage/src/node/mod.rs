@@ -8,7 +8,6 @@ use serde::{Deserialize, Serialize};
 use smallvec::SmallVec;
 use std::io::{Error, ErrorKind, Read, Write};
 use std::num::NonZero;
-use std::vec;
 use std::{fmt::Debug, sync::Arc};
 
 mod branch;
@@ -19,6 +18,7 @@ pub use branch::BranchNode;
 pub use branch::Child;
 pub use leaf::LeafNode;
 
+use crate::nodestore::MAX_AREA_SIZE;
 use crate::Path;
 
 /// A node, either a Branch or Leaf
@@ -54,6 +54,57 @@ impl From<LeafNode> for Node {
     }
 }
 
+/// How the children of a branch are laid out when it is stored; see
+/// [Node::as_bytes]. The version a file uses is recorded in its header, so
+/// files written in an older layout are read as they were written.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NodeFormat {
+    /// Each child is its index, then its address in 8 bytes and its hash
+    Fixed = 0,
+    /// Each child is the number of indices skipped since the previous
+    /// child, then its address as a varint and its hash. Addresses below
+    /// 2^49 take at most 7 bytes, so this saves at least a byte per child.
+    Compact = 1,
+    /// The children are a bitmap of the indices that have one, then the
+    /// address as a varint and the hash of each, in index order. Partial
+    /// paths are packed two nibbles to a byte, unless a nibble is a byte.
+    Bitmap = 2,
+}
+
+impl NodeFormat {
+    /// The format new files are written in
+    pub const LATEST: Self = Self::Bitmap;
+
+    /// Every format, oldest first
+    pub const ALL: [Self; 3] = [Self::Fixed, Self::Compact, Self::Bitmap];
+
+    /// The format with the version `version`, as recorded in a header
+    pub fn from_version(version: u64) -> Result<Self, Error> {
+        match version {
+            0 => Ok(Self::Fixed),
+            1 => Ok(Self::Compact),
+            2 => Ok(Self::Bitmap),
+            _ => Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("unknown node format {version}"),
+            )),
+        }
+    }
+
+    /// The version recorded in a header for this format
+    pub const fn version(self) -> u64 {
+        self as u64
+    }
+
+    /// Whether partial paths are packed two nibbles to a byte
+    const fn packs_paths(self) -> bool {
+        matches!(self, Self::Bitmap) && BranchNode::BITS_PER_NIBBLE == 4
+    }
+}
+
+/// The bytes of the bitmap of a branch's children in [NodeFormat::Bitmap]
+const CHILD_BITMAP_LEN: usize = BranchNode::MAX_CHILDREN / 8;
+
 #[cfg(not(feature = "branch_factor_256"))]
 bitfield! {
     struct BranchFirstByte(u8);
@@ -216,17 +267,27 @@ impl Node {
     /// The remaining bytes are in the following order:
     ///   - The partial path, possibly preceeded by the length if it is longer than 3 nibbles (varint encoded)
     ///   - The number of children, if the branch factor is 256
-    ///   - The children. If the number of children == [BranchNode::MAX_CHILDREN], then the children are just
-    ///     addresses with hashes. Otherwise, they are offset, address, hash tuples.
+    ///   - The children. In [NodeFormat::Bitmap], a bitmap with bit `i % 8` of byte `i / 8` set
+    ///     if there is a child at index `i`, followed by the varint address and hash of each
+    ///     child in index order. In the other formats, if the number of children ==
+    ///     [BranchNode::MAX_CHILDREN], then the children are just addresses with hashes.
+    ///     Otherwise, they are offset, address, hash tuples.
+    ///     In [NodeFormat::Fixed], the offset is the child's index and the address is 8 bytes.
+    ///     In [NodeFormat::Compact], the offset is the number of indices skipped since the
+    ///     previous child, and the address is varint encoded.
+    ///
+    /// In [NodeFormat::Bitmap], partial paths of branches and leaves are stored two nibbles
+    /// to a byte, high nibble first, and an odd length leaves the last low nibble 0. Their
+    /// lengths are still counted in nibbles.
     ///
     /// For a leaf:
     ///  - Byte 0:
     ///    - Bit 0: always 1
-    ///    - Bits 1-7: the length of the partial path. If the partial path is longer than 126 nibbles, this is set to
+    ///    - Bits 1-7: the length of the partial path. If the partial path is 126 nibbles or longer, this is set to
     ///      126 and the length is encoded in the next byte.
     ///
     /// The remaining bytes are in the following order:
-    ///    - The partial path, possibly preceeded by the length if it is longer than 126 nibbles (varint encoded)
+    ///    - The partial path, possibly preceeded by the length if it is 126 nibbles or longer (varint encoded)
     ///    - The value, always preceeded by the length, varint encoded
     ///
     /// Note that this means the first byte cannot be 255, which would be a leaf with 127 nibbles. We save this extra
@@ -236,7 +297,7 @@ impl Node {
     /// we always have one of those, we include it as a parameter for serialization.
     ///
     /// TODO: We could pack two bytes of the partial path into one and handle the odd byte length
-    pub fn as_bytes<T: ExtendableBytes>(&self, prefix: u8, encoded: &mut T) {
+    pub fn as_bytes<T: ExtendableBytes>(&self, format: NodeFormat, prefix: u8, encoded: &mut T) {
         match self {
             Node::Branch(b) => {
                 let child_iter = b
@@ -276,7 +337,7 @@ impl Node {
                         .write_varint(b.partial_path.len())
                         .expect("writing to vec should succeed");
                 }
-                encoded.extend_from_slice(&b.partial_path);
+                write_partial_path(format, &b.partial_path, encoded);
 
                 // encode the value. For tries that have the same length keys, this is always empty
                 if let Some(v) = &b.value {
@@ -287,31 +348,51 @@ impl Node {
                 }
 
                 // encode the children
-                if childcount == BranchNode::MAX_CHILDREN {
-                    for (_, child) in child_iter {
-                        if let Child::AddressWithHash(address, hash) = child {
-                            encoded.extend_from_slice(&address.get().to_ne_bytes());
-                            encoded.extend_from_slice(hash);
-                        } else {
-                            panic!("attempt to serialize to persist a branch with a child that is not an AddressWithHash");
+                if format == NodeFormat::Bitmap {
+                    let mut bitmap = [0u8; CHILD_BITMAP_LEN];
+                    for (position, _) in child_iter.clone() {
+                        if let Some(byte) = bitmap.get_mut(position / 8) {
+                            *byte |= 1 << (position % 8);
                         }
                     }
-                } else {
-                    for (position, child) in child_iter {
+                    encoded.extend_from_slice(&bitmap);
+                }
+                let mut next_position = 0;
+                for (position, child) in child_iter {
+                    if childcount != BranchNode::MAX_CHILDREN && format != NodeFormat::Bitmap {
+                        let offset = match format {
+                            NodeFormat::Fixed => position,
+                            NodeFormat::Compact | NodeFormat::Bitmap => position - next_position,
+                        };
                         encoded
-                            .write_varint(position)
+                            .write_varint(offset)
                             .expect("writing to vec should succeed");
-                        if let Child::AddressWithHash(address, hash) = child {
-                            encoded.extend_from_slice(&address.get().to_ne_bytes());
-                            encoded.extend_from_slice(hash);
-                        } else {
-                            panic!("attempt to serialize to persist a branch with a child that is not an AddressWithHash");
+                        next_position = position + 1;
+                    }
+                    if let Child::AddressWithHash(address, hash) = child {
+                        match format {
+                            NodeFormat::Fixed => {
+                                encoded.extend_from_slice(&address.get().to_ne_bytes())
+                            }
+                            NodeFormat::Compact | NodeFormat::Bitmap => {
+                                encoded
+                                    .write_varint(address.get())
+                                    .expect("writing to vec should succeed");
+                            }
                         }
+                        encoded.extend_from_slice(hash);
+                    } else {
+                        panic!("attempt to serialize to persist a branch with a child that is not an AddressWithHash");
                     }
                 }
             }
             Node::Leaf(l) => {
-                let first_byte: LeafFirstByte = LeafFirstByte::new(1, l.partial_path.0.len() as u8);
+                let pp_len = l
+                    .partial_path
+                    .0
+                    .len()
+                    .min(MAX_LEAF_FIRST_BYTE_PATH_LEN as usize);
+                let first_byte: LeafFirstByte = LeafFirstByte::new(1, pp_len as u8);
 
                 const OPTIMIZE_LEAVES_FOR_SIZE: usize = 128;
                 encoded.reserve(OPTIMIZE_LEAVES_FOR_SIZE);
@@ -319,12 +400,12 @@ impl Node {
                 encoded.push(first_byte.0);
 
                 // encode the partial path, including the length if it didn't fit above
-                if l.partial_path.0.len() >= 127 {
+                if pp_len == MAX_LEAF_FIRST_BYTE_PATH_LEN as usize {
                     encoded
                         .write_varint(l.partial_path.len())
                         .expect("write to array should succeed");
                 }
-                encoded.extend_from_slice(&l.partial_path);
+                write_partial_path(format, &l.partial_path, encoded);
 
                 // encode the value
                 encoded
@@ -335,8 +416,15 @@ impl Node {
         }
     }
 
-    /// Given a reader, return a [Node] from those bytes
-    pub fn from_reader(mut serialized: impl Read) -> Result<Self, std::io::Error> {
+    /// Given a reader, return a [Node] from those bytes, written in `format`.
+    ///
+    /// The bytes may come from a corrupt file, so this never panics: invalid
+    /// input is reported as an [ErrorKind::InvalidData] error. Lengths are
+    /// checked against the largest possible node before anything is allocated.
+    pub fn from_reader(
+        format: NodeFormat,
+        mut serialized: impl Read,
+    ) -> Result<Self, std::io::Error> {
         let mut first_byte: [u8; 1] = [0];
         serialized.read_exact(&mut first_byte)?;
         match first_byte[0] {
@@ -345,22 +433,15 @@ impl Node {
                 Err(Error::new(ErrorKind::Other, "attempt to read freed area"))
             }
             leaf_first_byte if leaf_first_byte & 1 == 1 => {
-                let partial_path_len = if leaf_first_byte < 255 {
-                    // less than 126 nibbles
-                    LeafFirstByte(leaf_first_byte).partial_path_length() as usize
-                } else {
-                    serialized.read_varint()?
+                let partial_path_len = match LeafFirstByte(leaf_first_byte).partial_path_length() {
+                    // 126 nibbles or more
+                    MAX_LEAF_FIRST_BYTE_PATH_LEN => serialized.read_varint()?,
+                    len => len as usize,
                 };
+                let partial_path = read_partial_path(format, &mut serialized, partial_path_len)?;
 
-                let mut partial_path = vec![0u8; partial_path_len];
-                serialized.read_exact(&mut partial_path)?;
-
-                let mut value_len_buf = [0u8; 1];
-                serialized.read_exact(&mut value_len_buf)?;
-                let value_len = value_len_buf[0] as usize;
-
-                let mut value = vec![0u8; value_len];
-                serialized.read_exact(&mut value)?;
+                let value_len = serialized.read_varint()?;
+                let value = read_bytes(&mut serialized, value_len, "value")?;
 
                 Ok(Node::Leaf(LeafNode {
                     partial_path: Path::from(partial_path),
@@ -384,56 +465,55 @@ impl Node {
                 if partial_path_len > MAX_ENCODED_PARTIAL_PATH_LEN {
                     partial_path_len = serialized.read_varint()?;
                 }
-
-                let mut partial_path = vec![0u8; partial_path_len];
-                serialized.read_exact(&mut partial_path)?;
+                let partial_path = read_partial_path(format, &mut serialized, partial_path_len)?;
 
                 let value = if has_value {
-                    let mut value_len_buf = [0u8; 1];
-                    serialized.read_exact(&mut value_len_buf)?;
-                    let value_len = value_len_buf[0] as usize;
-
-                    let mut value = vec![0u8; value_len];
-                    serialized.read_exact(&mut value)?;
-                    Some(value.into())
+                    let value_len = serialized.read_varint()?;
+                    Some(read_bytes(&mut serialized, value_len, "value")?.into())
                 } else {
                     None
                 };
 
                 let mut children = [const { None }; BranchNode::MAX_CHILDREN];
-                if childcount == 0 {
+                if format == NodeFormat::Bitmap {
+                    let mut bitmap = [0u8; CHILD_BITMAP_LEN];
+                    serialized.read_exact(&mut bitmap)?;
+                    let present: u32 = bitmap.iter().map(|byte| byte.count_ones()).sum();
+                    if present == 0 || present as usize % BranchNode::MAX_CHILDREN != childcount {
+                        return Err(Error::new(
+                            ErrorKind::InvalidData,
+                            format!("{present} children in the bitmap, not {childcount}"),
+                        ));
+                    }
+                    for (position, child) in children.iter_mut().enumerate() {
+                        let byte = bitmap.get(position / 8).copied().unwrap_or_default();
+                        if byte & (1 << (position % 8)) != 0 {
+                            *child = Some(read_child(format, &mut serialized)?);
+                        }
+                    }
+                } else if childcount == 0 {
                     // branch is full of all children
                     for child in children.iter_mut() {
-                        // TODO: we can read them all at once
-                        let mut address_buf = [0u8; 8];
-                        serialized.read_exact(&mut address_buf)?;
-                        let address = u64::from_ne_bytes(address_buf);
-
-                        let mut hash = [0u8; 32];
-                        serialized.read_exact(&mut hash)?;
-
-                        *child = Some(Child::AddressWithHash(
-                            NonZero::new(address).ok_or(Error::other("zero address in child"))?,
-                            hash.into(),
-                        ));
+                        *child = Some(read_child(format, &mut serialized)?);
                     }
                 } else {
+                    let mut next_position: usize = 0;
                     for _ in 0..childcount {
-                        let mut position_buf = [0u8; 1];
-                        serialized.read_exact(&mut position_buf)?;
-                        let position = position_buf[0] as usize;
-
-                        let mut address_buf = [0u8; 8];
-                        serialized.read_exact(&mut address_buf)?;
-                        let address = u64::from_ne_bytes(address_buf);
-
-                        let mut hash = [0u8; 32];
-                        serialized.read_exact(&mut hash)?;
-
-                        children[position] = Some(Child::AddressWithHash(
-                            NonZero::new(address).ok_or(Error::other("zero address in child"))?,
-                            hash.into(),
-                        ));
+                        let offset: usize = serialized.read_varint()?;
+                        let position = match format {
+                            NodeFormat::Fixed => offset,
+                            NodeFormat::Compact | NodeFormat::Bitmap => {
+                                next_position.saturating_add(offset)
+                            }
+                        };
+                        let child = children.get_mut(position).ok_or_else(|| {
+                            Error::new(
+                                ErrorKind::InvalidData,
+                                format!("invalid child index {position}"),
+                            )
+                        })?;
+                        *child = Some(read_child(format, &mut serialized)?);
+                        next_position = position + 1;
                     }
                 }
 
@@ -447,6 +527,100 @@ impl Node {
     }
 }
 
+/// The largest partial path length stored directly in a [LeafFirstByte]. Longer
+/// paths store this value and put the length in a varint after the first byte.
+const MAX_LEAF_FIRST_BYTE_PATH_LEN: u8 = 126;
+
+/// Write a partial path the way `format` stores it
+fn write_partial_path<T: ExtendableBytes>(format: NodeFormat, path: &[u8], encoded: &mut T) {
+    if !format.packs_paths() {
+        encoded.extend_from_slice(path);
+        return;
+    }
+    for pair in path.chunks(2) {
+        let (high, low) = match pair {
+            [high, low] => (*high, *low),
+            [high] => (*high, 0),
+            _ => unreachable!("chunks of at most two"),
+        };
+        encoded.push(high << 4 | low);
+    }
+}
+
+/// Read a partial path of `len` nibbles stored in `format`, each of which
+/// must be a valid child index
+fn read_partial_path(
+    format: NodeFormat,
+    serialized: &mut impl Read,
+    len: usize,
+) -> Result<Vec<u8>, Error> {
+    if format.packs_paths() {
+        let packed = read_bytes(serialized, len.div_ceil(2), "partial path")?;
+        let mut partial_path: Vec<u8> = packed
+            .iter()
+            .flat_map(|byte| [byte >> 4, byte & 0xf])
+            .collect();
+        if partial_path.len() > len && partial_path.pop() != Some(0) {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "the partial path is padded with a nonzero nibble",
+            ));
+        }
+        return Ok(partial_path);
+    }
+    let partial_path = read_bytes(serialized, len, "partial path")?;
+    if let Some(nibble) = partial_path
+        .iter()
+        .find(|nibble| **nibble as usize >= BranchNode::MAX_CHILDREN)
+    {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid nibble {nibble} in partial path"),
+        ));
+    }
+    Ok(partial_path)
+}
+
+/// Read `len` bytes of a serialized node. Reading stops at the end of the
+/// input, so a corrupt length can't cause a large allocation.
+fn read_bytes(serialized: &mut impl Read, len: usize, what: &str) -> Result<Vec<u8>, Error> {
+    if len as u64 > MAX_AREA_SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("{what} length {len} is larger than any node"),
+        ));
+    }
+    let mut bytes = Vec::new();
+    serialized.take(len as u64).read_to_end(&mut bytes)?;
+    if bytes.len() != len {
+        return Err(Error::new(
+            ErrorKind::UnexpectedEof,
+            format!("{what} is truncated"),
+        ));
+    }
+    Ok(bytes)
+}
+
+/// Read the address and hash of a child of a branch serialized in `format`
+fn read_child(format: NodeFormat, serialized: &mut impl Read) -> Result<Child, Error> {
+    let address = match format {
+        NodeFormat::Fixed => {
+            let mut address_buf = [0u8; 8];
+            serialized.read_exact(&mut address_buf)?;
+            u64::from_ne_bytes(address_buf)
+        }
+        NodeFormat::Compact | NodeFormat::Bitmap => serialized.read_varint()?,
+    };
+
+    let mut hash = [0u8; 32];
+    serialized.read_exact(&mut hash)?;
+
+    Ok(Child::AddressWithHash(
+        NonZero::new(address).ok_or(Error::new(ErrorKind::InvalidData, "zero address in child"))?,
+        hash.into(),
+    ))
+}
+
 /// A path iterator item, which has the key nibbles up to this point,
 /// a node, the address of the node, and the nibble that points to the
 /// next child down the list
@@ -466,9 +640,10 @@ pub struct PathIterItem {
 #[cfg(test)]
 mod test {
     use crate::{
-        node::{BranchNode, LeafNode, Node},
+        node::{BranchNode, LeafNode, Node, NodeFormat},
         Child, LinearAddress, Path,
     };
+    use std::io::ErrorKind;
     use test_case::test_case;
 
     #[test_case(
@@ -494,19 +669,230 @@ mod test {
                 Some(Child::AddressWithHash(LinearAddress::new(1).unwrap(), std::array::from_fn::<u8, 32, _>(|i| i as u8).into()))
         )})), 652; "full branch node with long partial path and value"
     )]
+    #[test_case(
+        Node::Leaf(LeafNode {
+            partial_path: Path::from(vec![5; 127]),
+            value: vec![6; 200].into()
+        }), 332; "leaf node with 127 nibble partial path and long value")]
+    #[test_case(
+        Node::Leaf(LeafNode {
+            partial_path: Path::from(vec![5; 300]),
+            value: vec![6; 20].into()
+        }), 325; "leaf node with very long partial path")]
+    #[test_case(Node::Branch(Box::new(BranchNode {
+        partial_path: Path::from(vec![0, 1, 2]),
+        value: Some(vec![4; 300].into()),
+        children: std::array::from_fn(|i| {
+            if i == 3 {
+                Some(Child::AddressWithHash(LinearAddress::new(1).unwrap(), std::array::from_fn::<u8, 32, _>(|i| i as u8).into()))
+            } else {
+                None
+            }
+        })})), 349; "branch node with long value"
+    )]
     #[allow(unused_variables)]
     fn test_serialize_deserialize(node: Node, expected_length: usize) {
         use crate::node::Node;
         use std::io::Cursor;
 
-        let mut serialized = Vec::new();
-        node.as_bytes(0, &mut serialized);
-        #[cfg(not(feature = "branch_factor_256"))] // TODO: enable this test for branch_factor_256
-        assert_eq!(serialized.len(), expected_length);
-        let mut cursor = Cursor::new(&serialized);
-        cursor.set_position(1);
-        let deserialized = Node::from_reader(cursor).unwrap();
+        for format in NodeFormat::ALL {
+            let mut serialized = Vec::new();
+            node.as_bytes(format, 0, &mut serialized);
+            #[cfg(not(feature = "branch_factor_256"))]
+            // TODO: enable this test for branch_factor_256
+            if format == NodeFormat::Fixed {
+                assert_eq!(serialized.len(), expected_length);
+            }
+            let mut cursor = Cursor::new(&serialized);
+            cursor.set_position(1);
+            let deserialized = Node::from_reader(format, cursor).unwrap();
+
+            assert_eq!(node, deserialized);
+        }
+    }
+
+    #[test_case(&[0b0000_0100, 16, 1, 0, 0, 0, 0, 0, 0, 0], ErrorKind::InvalidData; "child index out of range")]
+    #[test_case(&[0b0000_0100, 0x80, 0x01], ErrorKind::InvalidData; "multibyte child index out of range")]
+    #[test_case(&[0b1100_0000, 0xff, 0xff, 0xff, 0xff, 0x0f], ErrorKind::InvalidData; "huge partial path length")]
+    #[test_case(&[0b1111_1101, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01], ErrorKind::InvalidData; "partial path length overflows")]
+    #[test_case(&[0b0000_0001, 0x80, 0x80, 0x80, 0x80, 0x01], ErrorKind::InvalidData; "huge value length")]
+    #[test_case(&[0b0000_0001, 0x7f, 1, 2, 3], ErrorKind::UnexpectedEof; "truncated value")]
+    #[test_case(&[0; 41], ErrorKind::InvalidData; "zero child address")]
+    #[test_case(&[0b0000_0011, 0x10, 0], ErrorKind::InvalidData; "invalid nibble")]
+    #[test_case(&[], ErrorKind::UnexpectedEof; "empty")]
+    fn test_deserialize_invalid(serialized: &[u8], kind: ErrorKind) {
+        let err = Node::from_reader(NodeFormat::Fixed, serialized).unwrap_err();
+        assert_eq!(err.kind(), kind, "{err}");
+    }
+
+    #[test]
+    fn compact_children() {
+        let full = |address| {
+            Node::Branch(Box::new(BranchNode {
+                partial_path: Path::new(),
+                value: None,
+                children: std::array::from_fn(|i| {
+                    Some(Child::AddressWithHash(
+                        LinearAddress::new(address + i as u64 * 256).unwrap(),
+                        [i as u8; 32].into(),
+                    ))
+                }),
+            }))
+        };
+        let len = |node: &Node, format| {
+            let mut serialized = Vec::new();
+            node.as_bytes(format, 0, &mut serialized);
+            let decoded = Node::from_reader(format, serialized.get(1..).unwrap()).unwrap();
+            assert_eq!(&decoded, node);
+            serialized.len()
+        };
+
+        // addresses from 2^14 up to 2^21 take 3 bytes instead of 8
+        let node = full(1 << 14);
+        let fixed = len(&node, NodeFormat::Fixed);
+        let compact = len(&node, NodeFormat::Compact);
+        assert_eq!(fixed - compact, BranchNode::MAX_CHILDREN * 5);
+
+        // the largest addresses take 10 bytes
+        let node = full(u64::MAX - 256 * BranchNode::MAX_CHILDREN as u64);
+        assert_eq!(
+            len(&node, NodeFormat::Compact) - len(&node, NodeFormat::Fixed),
+            BranchNode::MAX_CHILDREN * 2
+        );
+
+        // two children, the second one past the last index
+        #[cfg(not(feature = "branch_factor_256"))]
+        {
+            let mut serialized = vec![0b0000_1000, 15, 1];
+            serialized.extend_from_slice(&[0; 32]);
+            serialized.push(0);
+            let err = Node::from_reader(NodeFormat::Compact, serialized.as_slice()).unwrap_err();
+            assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}");
+        }
+    }
+
+    #[test]
+    fn bitmap_children() {
+        use rand::rngs::StdRng;
+        use rand::{Rng, SeedableRng};
+
+        let mut rng = StdRng::seed_from_u64(533);
+        // every occupancy pattern of a branch with 16 children, or as many
+        // random ones with 256
+        for pattern in 1..=u16::MAX {
+            let children = std::array::from_fn(|i| {
+                let present = match BranchNode::MAX_CHILDREN {
+                    16 => pattern >> i & 1 == 1,
+                    _ => i == pattern as usize % BranchNode::MAX_CHILDREN || rng.gen_bool(0.05),
+                };
+                present.then(|| {
+                    let address = (rng.gen::<u64>() >> rng.gen_range(0..64u32)).max(1);
+                    Child::AddressWithHash(
+                        LinearAddress::new(address).unwrap(),
+                        rng.gen::<[u8; 32]>().into(),
+                    )
+                })
+            });
+            let partial_path: Vec<u8> = (0..rng.gen_range(0..6))
+                .map(|_| rng.gen_range(0..BranchNode::MAX_CHILDREN) as u8)
+                .collect();
+            let value = rng
+                .gen_bool(0.5)
+                .then(|| vec![7; rng.gen_range(0..4)].into_boxed_slice());
+            let node = Node::Branch(Box::new(BranchNode {
+                partial_path: Path::from(partial_path),
+                value,
+                children,
+            }));
+            for format in NodeFormat::ALL {
+                let mut serialized = Vec::new();
+                node.as_bytes(format, 0, &mut serialized);
+                let decoded = Node::from_reader(format, serialized.get(1..).unwrap()).unwrap();
+                assert_eq!(decoded, node, "{format:?}, pattern {pattern:#x}");
+            }
+        }
+    }
+
+    #[cfg(not(feature = "branch_factor_256"))]
+    #[test]
+    fn bitmap_is_smaller() {
+        let len = |node: &Node, format| {
+            let mut serialized = Vec::new();
+            node.as_bytes(format, 0, &mut serialized);
+            serialized.len()
+        };
+        let child = || {
+            Some(Child::AddressWithHash(
+                LinearAddress::new(1).unwrap(),
+                [0; 32].into(),
+            ))
+        };
+
+        // three children and a path of four nibbles: two bytes of bitmap
+        // instead of three offsets, and two bytes of path instead of four
+        let mut branch = BranchNode {
+            partial_path: Path::from(vec![1, 2, 3, 4]),
+            value: None,
+            children: [const { None }; BranchNode::MAX_CHILDREN],
+        };
+        for index in [2, 7, 11] {
+            branch.update_child(index, child());
+        }
+        let node = Node::Branch(Box::new(branch));
+        assert_eq!(
+            len(&node, NodeFormat::Compact) - len(&node, NodeFormat::Bitmap),
+            3
+        );
+
+        // the path of a leaf under a 32 byte key takes half as many bytes
+        let node = Node::Leaf(LeafNode {
+            partial_path: Path::from(vec![9; 63]),
+            value: vec![1].into(),
+        });
+        assert_eq!(
+            len(&node, NodeFormat::Compact) - len(&node, NodeFormat::Bitmap),
+            31
+        );
+    }
 
-        assert_eq!(node, deserialized);
+    #[cfg(not(feature = "branch_factor_256"))]
+    #[test_case(&[0b0000_0100, 0b0000_0011, 0], ErrorKind::InvalidData; "more children than counted")]
+    #[test_case(&[0b0000_1000, 0b0000_0001, 0], ErrorKind::InvalidData; "fewer children than counted")]
+    #[test_case(&[0b0000_0000, 0, 0], ErrorKind::InvalidData; "empty bitmap")]
+    #[test_case(&[0b0000_0100, 0b0000_0001], ErrorKind::UnexpectedEof; "truncated bitmap")]
+    #[test_case(&[0b0000_0011, 0x51, 0], ErrorKind::InvalidData; "nonzero pad nibble")]
+    #[test_case(&[0b0000_0111, 0x12], ErrorKind::UnexpectedEof; "truncated packed path")]
+    fn test_deserialize_invalid_bitmap(serialized: &[u8], kind: ErrorKind) {
+        let err = Node::from_reader(NodeFormat::Bitmap, serialized).unwrap_err();
+        assert_eq!(err.kind(), kind, "{err}");
+    }
+
+    #[test]
+    fn branch_children() {
+        let child = || {
+            Some(Child::AddressWithHash(
+                LinearAddress::new(1).unwrap(),
+                [0; 32].into(),
+            ))
+        };
+        let mut branch = BranchNode {
+            partial_path: Path::new(),
+            value: None,
+            children: [const { None }; BranchNode::MAX_CHILDREN],
+        };
+        assert!(branch.is_empty());
+        assert_eq!(branch.num_children(), 0);
+        assert_eq!(branch.has_single_child(), None);
+
+        branch.value = Some(Box::new([1]));
+        assert!(!branch.is_empty());
+
+        branch.update_child(3, child());
+        assert_eq!(branch.num_children(), 1);
+        assert_eq!(branch.has_single_child(), Some(3));
+
+        branch.update_child(0, child());
+        assert_eq!(branch.num_children(), 2);
+        assert_eq!(branch.has_single_child(), None);
     }
 }
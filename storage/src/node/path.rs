@@ -11,6 +11,8 @@ use std::{
     iter::once,
 };
 
+use super::BranchNode;
+
 static NIBBLES: [u8; 16] = [0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15];
 
 /// Path is part or all of a node's path in the trie.
@@ -195,11 +197,6 @@ impl Iterator for NibblesIterator<'_> {
 }
 
 impl<'a> NibblesIterator<'a> {
-    #[cfg(not(feature = "branch_factor_256"))]
-    const BYTES_PER_NIBBLE: usize = 2;
-    #[cfg(feature = "branch_factor_256")]
-    const BYTES_PER_NIBBLE: usize = 1;
-
     #[inline(always)]
     const fn is_empty(&self) -> bool {
         self.head == self.tail
@@ -211,7 +208,7 @@ impl<'a> NibblesIterator<'a> {
         NibblesIterator {
             data,
             head: 0,
-            tail: Self::BYTES_PER_NIBBLE * data.len(),
+            tail: BranchNode::NIBBLES_PER_BYTE * data.len(),
         }
     }
 }
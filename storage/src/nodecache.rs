@@ -0,0 +1,419 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! The node cache of a [FileBacked](crate::FileBacked) store, and the
+//! policies that decide which nodes it keeps.
+//!
+//! The cache has up to two segments, each a least recently used list. Nodes
+//! are evicted from the probationary segment first; the protected segment
+//! only gives up nodes to make room for the ones it admits, and those go
+//! back to probation rather than being dropped.
+
+use std::num::NonZero;
+use std::sync::Arc;
+
+use lru::LruCache;
+
+use crate::{BranchNode, LinearAddress, Node};
+
+/// Which nodes the node cache admits, and which it evicts first
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum NodeCachePolicy {
+    /// One least recently used list of the nodes written by commits. Nodes
+    /// read from storage aren't admitted.
+    #[default]
+    WritesOnly,
+    /// One least recently used list of the nodes written by commits and the
+    /// nodes read from storage
+    Lru,
+    /// Nodes are admitted to probation, and move to the protected segment
+    /// the first time they are found there
+    Segmented {
+        /// The share of the cache the protected segment can hold
+        protected_fraction: f64,
+    },
+    /// Nodes read near the root, which every lookup passes through, are
+    /// admitted to the protected segment, and deeper ones to probation.
+    /// Nodes written by commits go to probation, since their depth isn't
+    /// known.
+    ByDepth {
+        /// Nodes this many levels or fewer below the root are protected; the
+        /// root is at level 0
+        protected_depth: usize,
+        /// The share of the cache the protected segment can hold
+        protected_fraction: f64,
+        /// Whether leaves are cached at all. A lookup rarely reads the same
+        /// leaf again, so leaves can be left on disk.
+        admit_leaves: bool,
+    },
+}
+
+/// Where a node goes when it is offered to the cache
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Admission {
+    /// It isn't cached
+    Reject,
+    /// The segment evicted first
+    Probation,
+    /// The segment evicted last
+    Protected,
+}
+
+impl NodeCachePolicy {
+    /// Where a node read from storage goes, if it was read `depth` levels
+    /// below the root, or from somewhere other than a descent from the root
+    pub(crate) const fn read_admission(&self, depth: Option<usize>, is_leaf: bool) -> Admission {
+        match *self {
+            NodeCachePolicy::WritesOnly => Admission::Reject,
+            NodeCachePolicy::Lru | NodeCachePolicy::Segmented { .. } => Admission::Probation,
+            NodeCachePolicy::ByDepth {
+                admit_leaves: false,
+                ..
+            } if is_leaf => Admission::Reject,
+            NodeCachePolicy::ByDepth {
+                protected_depth, ..
+            } => match depth {
+                Some(depth) if depth <= protected_depth => Admission::Protected,
+                _ => Admission::Probation,
+            },
+        }
+    }
+
+    /// Where a node written by a commit goes
+    pub(crate) const fn write_admission(&self, is_leaf: bool) -> Admission {
+        match *self {
+            NodeCachePolicy::ByDepth {
+                admit_leaves: false,
+                ..
+            } if is_leaf => Admission::Reject,
+            _ => Admission::Probation,
+        }
+    }
+
+    /// The share of the cache the protected segment can hold
+    const fn protected_fraction(&self) -> f64 {
+        match *self {
+            NodeCachePolicy::WritesOnly | NodeCachePolicy::Lru => 0.0,
+            NodeCachePolicy::Segmented { protected_fraction }
+            | NodeCachePolicy::ByDepth {
+                protected_fraction, ..
+            } => protected_fraction,
+        }
+    }
+}
+
+/// How much of the node cache is in use, from
+/// [FileBacked::cache_usage](crate::FileBacked::cache_usage)
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct CacheUsage {
+    /// The number of nodes cached
+    pub entries: usize,
+    /// The number of nodes it can hold
+    pub capacity: usize,
+    /// Roughly how many bytes of memory the cached nodes take: their
+    /// paths and values and the nodes themselves, but not the cache's
+    /// bookkeeping
+    pub bytes: usize,
+}
+
+/// Roughly how many bytes of memory `node` takes
+fn approximate_size(node: &Node) -> usize {
+    let inline = match node {
+        Node::Branch(_) => std::mem::size_of::<Node>() + std::mem::size_of::<BranchNode>(),
+        Node::Leaf(_) => std::mem::size_of::<Node>(),
+    };
+    inline + node.partial_path().len() + node.value().map_or(0, <[u8]>::len)
+}
+
+/// Nodes cached by address, split into segments by a [NodeCachePolicy]
+#[derive(Debug)]
+pub(crate) struct NodeCache {
+    policy: NodeCachePolicy,
+    /// The only segment of the single list policies
+    probation: LruCache<LinearAddress, Arc<Node>>,
+    protected: Option<LruCache<LinearAddress, Arc<Node>>>,
+}
+
+impl NodeCache {
+    pub(crate) fn new(policy: NodeCachePolicy, capacity: NonZero<usize>) -> Self {
+        let fraction = policy.protected_fraction();
+        let fraction = if fraction.is_nan() {
+            0.0
+        } else {
+            fraction.clamp(0.0, 1.0)
+        };
+        // probation always keeps at least one entry
+        let protected = (capacity.get() as f64 * fraction).round() as usize;
+        let protected = NonZero::new(protected.min(capacity.get() - 1));
+        let probation = NonZero::new(capacity.get() - protected.map_or(0, NonZero::get))
+            .expect("protected leaves room for probation");
+        Self {
+            policy,
+            probation: LruCache::new(probation),
+            protected: protected.map(LruCache::new),
+        }
+    }
+
+    pub(crate) const fn policy(&self) -> NodeCachePolicy {
+        self.policy
+    }
+
+    /// The number of nodes it can hold
+    pub(crate) fn capacity(&self) -> NonZero<usize> {
+        let protected = self
+            .protected
+            .as_ref()
+            .map_or(0, |protected| protected.cap().get());
+        self.probation
+            .cap()
+            .checked_add(protected)
+            .expect("sum of two capacities")
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.probation.len() + self.protected.as_ref().map_or(0, LruCache::len)
+    }
+
+    /// How many nodes it holds, and roughly how much memory they take. This
+    /// visits every cached node.
+    pub(crate) fn usage(&self) -> CacheUsage {
+        let bytes = self
+            .probation
+            .iter()
+            .chain(self.protected.iter().flat_map(LruCache::iter))
+            .map(|(_, node)| approximate_size(node))
+            .sum();
+        CacheUsage {
+            entries: self.len(),
+            capacity: self.capacity().get(),
+            bytes,
+        }
+    }
+
+    /// Whether the node at `addr` is cached, without counting as a use
+    pub(crate) fn contains(&self, addr: &LinearAddress) -> bool {
+        self.probation.contains(addr)
+            || self
+                .protected
+                .as_ref()
+                .is_some_and(|protected| protected.contains(addr))
+    }
+
+    pub(crate) fn get(&mut self, addr: &LinearAddress) -> Option<Arc<Node>> {
+        if let Some(node) = self
+            .protected
+            .as_mut()
+            .and_then(|protected| protected.get(addr))
+        {
+            return Some(node.clone());
+        }
+        if !matches!(self.policy, NodeCachePolicy::Segmented { .. }) {
+            return self.probation.get(addr).cloned();
+        }
+        // found again while on probation, so it is worth protecting
+        let node = self.probation.pop(addr)?;
+        self.admit(*addr, node.clone(), Admission::Protected);
+        Some(node)
+    }
+
+    /// Offer a node read `depth` levels below the root to the cache
+    pub(crate) fn admit_read(
+        &mut self,
+        addr: LinearAddress,
+        depth: Option<usize>,
+        node: &Arc<Node>,
+    ) {
+        let admission = self
+            .policy
+            .read_admission(depth, matches!(**node, Node::Leaf(_)));
+        self.admit(addr, node.clone(), admission);
+    }
+
+    /// Offer a node written by a commit to the cache
+    pub(crate) fn admit_written(&mut self, addr: LinearAddress, node: &Arc<Node>) {
+        let admission = self.policy.write_admission(matches!(**node, Node::Leaf(_)));
+        self.admit(addr, node.clone(), admission);
+    }
+
+    fn admit(&mut self, addr: LinearAddress, node: Arc<Node>, admission: Admission) {
+        match (admission, &mut self.protected) {
+            (Admission::Reject, _) => {}
+            (Admission::Probation, _) | (Admission::Protected, None) => {
+                if self
+                    .protected
+                    .as_mut()
+                    .and_then(|protected| protected.pop(&addr))
+                    .is_none()
+                {
+                    self.probation.put(addr, node);
+                } else {
+                    // already protected; keep it there with the new node
+                    self.admit(addr, node, Admission::Protected);
+                }
+            }
+            (Admission::Protected, Some(protected)) => {
+                self.probation.pop(&addr);
+                if let Some((evicted, evicted_node)) = protected.push(addr, node) {
+                    if evicted != addr {
+                        self.probation.put(evicted, evicted_node);
+                    }
+                }
+            }
+        }
+    }
+
+    pub(crate) fn pop(&mut self, addr: &LinearAddress) {
+        self.probation.pop(addr);
+        if let Some(protected) = &mut self.protected {
+            protected.pop(addr);
+        }
+    }
+
+    /// Evict the node that goes first, returning false if it was empty
+    pub(crate) fn evict(&mut self) -> bool {
+        self.probation.pop_lru().is_some()
+            || self
+                .protected
+                .as_mut()
+                .and_then(LruCache::pop_lru)
+                .is_some()
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod test {
+    use super::*;
+    use crate::{BranchNode, LeafNode, Path};
+    use smallvec::SmallVec;
+
+    fn by_depth(admit_leaves: bool) -> NodeCachePolicy {
+        NodeCachePolicy::ByDepth {
+            protected_depth: 2,
+            protected_fraction: 0.5,
+            admit_leaves,
+        }
+    }
+
+    fn leaf() -> Arc<Node> {
+        Arc::new(Node::Leaf(LeafNode {
+            partial_path: Path::from([1u8]),
+            value: SmallVec::from_slice(b"v"),
+        }))
+    }
+
+    fn branch() -> Arc<Node> {
+        Arc::new(Node::Branch(Box::new(BranchNode {
+            partial_path: Path::from([1u8]),
+            value: Some(b"v".to_vec().into_boxed_slice()),
+            children: [const { None }; BranchNode::MAX_CHILDREN],
+        })))
+    }
+
+    fn addr(addr: u64) -> LinearAddress {
+        LinearAddress::new(addr).unwrap()
+    }
+
+    #[test]
+    fn admission_by_depth() {
+        use Admission::*;
+        let policy = by_depth(false);
+        for (depth, expected) in [
+            (Some(0), Protected),
+            (Some(2), Protected),
+            (Some(3), Probation),
+            (Some(40), Probation),
+            (None, Probation),
+        ] {
+            assert_eq!(policy.read_admission(depth, false), expected, "{depth:?}");
+            // leaves stay on disk at any depth
+            assert_eq!(policy.read_admission(depth, true), Reject, "{depth:?}");
+            assert_eq!(
+                by_depth(true).read_admission(depth, true),
+                expected,
+                "{depth:?}"
+            );
+        }
+        assert_eq!(policy.write_admission(false), Probation);
+        assert_eq!(policy.write_admission(true), Reject);
+        assert_eq!(by_depth(true).write_admission(true), Probation);
+    }
+
+    #[test]
+    fn admission_of_single_list_policies() {
+        use Admission::*;
+        for depth in [Some(0), Some(5), None] {
+            for is_leaf in [false, true] {
+                let policy = NodeCachePolicy::WritesOnly;
+                assert_eq!(policy.read_admission(depth, is_leaf), Reject);
+                assert_eq!(policy.write_admission(is_leaf), Probation);
+                let policy = NodeCachePolicy::Lru;
+                assert_eq!(policy.read_admission(depth, is_leaf), Probation);
+                let policy = NodeCachePolicy::Segmented {
+                    protected_fraction: 0.5,
+                };
+                assert_eq!(policy.read_admission(depth, is_leaf), Probation);
+            }
+        }
+    }
+
+    #[test]
+    fn shallow_nodes_outlast_deep_ones() {
+        let capacity = NonZero::new(4).unwrap();
+        let mut cache = NodeCache::new(by_depth(true), capacity);
+        assert_eq!(cache.capacity(), capacity);
+        cache.admit_read(addr(8), Some(0), &branch());
+        cache.admit_read(addr(16), Some(1), &branch());
+        // a scan of deep nodes only evicts other deep nodes
+        for i in 0..100 {
+            cache.admit_read(addr(1024 + i * 8), Some(10), &branch());
+        }
+        assert_eq!(cache.len(), 4);
+        assert!(cache.get(&addr(8)).is_some());
+        assert!(cache.get(&addr(16)).is_some());
+
+        // a third shallow node moves the oldest to probation
+        cache.admit_read(addr(24), Some(2), &branch());
+        assert_eq!(cache.len(), 4);
+        assert!(cache.get(&addr(24)).is_some());
+        assert!(cache.evict());
+        assert!(cache.evict());
+        assert!(cache.get(&addr(8)).is_none());
+        assert!(cache.get(&addr(16)).is_some());
+    }
+
+    #[test]
+    fn segmented_protects_nodes_found_again() {
+        let policy = NodeCachePolicy::Segmented {
+            protected_fraction: 0.5,
+        };
+        let mut cache = NodeCache::new(policy, NonZero::new(4).unwrap());
+        cache.admit_read(addr(8), None, &leaf());
+        assert!(cache.get(&addr(8)).is_some());
+        for i in 0..100 {
+            cache.admit_read(addr(1024 + i * 8), None, &leaf());
+        }
+        assert!(cache.get(&addr(8)).is_some());
+
+        cache.pop(&addr(8));
+        assert!(cache.get(&addr(8)).is_none());
+        assert_eq!(cache.len(), 2);
+    }
+
+    #[test]
+    fn single_list_keeps_the_whole_capacity() {
+        let mut cache = NodeCache::new(NodeCachePolicy::Lru, NonZero::new(3).unwrap());
+        for i in 1..=5 {
+            cache.admit_read(addr(i * 8), Some(0), &branch());
+        }
+        assert_eq!(cache.len(), 3);
+        assert!(cache.get(&addr(8)).is_none());
+        assert!(cache.get(&addr(40)).is_some());
+        // nothing read is kept by the default
+        let mut cache = NodeCache::new(NodeCachePolicy::WritesOnly, NonZero::new(3).unwrap());
+        cache.admit_read(addr(8), Some(0), &branch());
+        assert_eq!(cache.len(), 0);
+        cache.admit_written(addr(8), &leaf());
+        assert_eq!(cache.len(), 1);
+    }
+}
@@ -7,9 +7,10 @@ use arc_swap::ArcSwap;
 use bincode::{DefaultOptions, Options as _};
 use bytemuck_derive::{AnyBitPattern, NoUninit};
 use fastrace::local::LocalSpan;
+use integer_encoding::VarInt;
 use metrics::counter;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 
 /// The [NodeStore] handles the serialization of nodes and
@@ -52,16 +53,18 @@ use std::fmt::Debug;
 /// I --> |commit|N("New commit NodeStore&lt;Committed, S&gt;")
 /// style E color:#FFFFFF, fill:#AA00FF, stroke:#AA00FF
 /// ```
-use std::io::{Error, ErrorKind, Write};
+use std::io::{Error, ErrorKind, IoSlice, Read, Write};
 use std::iter::once;
-use std::mem::{offset_of, take};
+use std::mem::take;
 use std::num::NonZeroU64;
 use std::ops::Deref;
 use std::sync::Arc;
 
-use crate::hashednode::hash_node;
-use crate::node::{ByteCounter, Node};
-use crate::{Child, FileBacked, Path, ReadableStorage, TrieHash};
+use crate::checksum::{crc32c, ChecksumReader, CorruptNode, CHECKSUM_LEN};
+use crate::hashednode::{count_unhashed, hash_node, hash_unhashed, PendingHashes};
+use crate::node::{ByteCounter, Node, NodeFormat};
+use crate::region::{FreeListRegion, HeaderRegion, WriteWitness};
+use crate::{BranchNode, Child, Path, ReadAhead, ReadableStorage, TrieHash};
 
 use super::linear::WritableStorage;
 
@@ -94,7 +97,9 @@ const AREA_SIZES: [u64; 23] = [
 ];
 
 fn serializer() -> impl bincode::Options {
-    DefaultOptions::new().with_varint_encoding()
+    DefaultOptions::new()
+        .with_varint_encoding()
+        .with_limit(MAX_AREA_SIZE)
 }
 
 // TODO: automate this, must stay in sync with above
@@ -127,7 +132,7 @@ fn index_name(index: AreaIndex) -> &'static str {
     }
 }
 
-/// The type of an index into the [AREA_SIZES] array
+/// The type of an index into the `AREA_SIZES` array
 /// This is not usize because we can store this as a single byte
 pub type AreaIndex = u8;
 
@@ -135,7 +140,7 @@ pub type AreaIndex = u8;
 // Implement try_into() for it.
 const NUM_AREA_SIZES: usize = AREA_SIZES.len();
 const MIN_AREA_SIZE: u64 = AREA_SIZES[0];
-const MAX_AREA_SIZE: u64 = AREA_SIZES[NUM_AREA_SIZES - 1];
+pub(crate) const MAX_AREA_SIZE: u64 = AREA_SIZES[NUM_AREA_SIZES - 1];
 
 /// Returns the index in `BLOCK_SIZES` of the smallest block size >= `n`.
 fn area_size_to_index(n: u64) -> Result<AreaIndex, Error> {
@@ -182,6 +187,7 @@ enum Area<T, U> {
 ///  - Byte 0: The index of the area size
 ///  - Byte 1: 0x255 if free, otherwise the low-order bit indicates Branch or Leaf
 ///  - Bytes 2..n: The actual data
+///  - For a node, the CRC32C of bytes 1..n, little-endian, in the 4 bytes after them
 #[derive(PartialEq, Eq, Clone, Debug, Deserialize, Serialize)]
 struct StoredArea<T> {
     /// Index in [AREA_SIZES] of this area's size
@@ -208,22 +214,141 @@ impl<T: ReadInMemoryNode, S: ReadableStorage> NodeStore<T, S> {
         Ok((index, size))
     }
 
+    /// The address of the root node, if the trie is not empty
+    pub const fn root_address(&self) -> Option<LinearAddress> {
+        self.header.root_address
+    }
+
+    /// The size of the store, from the start of the header to the end of
+    /// the last area
+    pub const fn store_size(&self) -> u64 {
+        self.header.size
+    }
+
+    /// The format nodes are stored in
+    pub fn node_format(&self) -> NodeFormat {
+        self.header.node_format()
+    }
+
+    /// The total size of the free areas this revision can allocate from
+    pub const fn free_bytes(&self) -> u64 {
+        self.header.free_bytes
+    }
+
+    /// The total size of the areas after the header that aren't free: the
+    /// nodes of the retained revisions, and any areas a crash leaked
+    pub const fn allocated_bytes(&self) -> u64 {
+        self.header
+            .size
+            .saturating_sub(NodeStoreHeader::SIZE)
+            .saturating_sub(self.header.free_bytes)
+    }
+
+    /// The number of commits that led to this revision. Databases created
+    /// before heights were recorded start counting from zero.
+    pub const fn height(&self) -> u64 {
+        self.header.height
+    }
+
+    /// The roots of the unpromoted revisions recorded in the header, with
+    /// their heights
+    pub fn unpromoted_roots(&self) -> Vec<(LinearAddress, u64)> {
+        self.header
+            .unpromoted
+            .iter()
+            .zip(self.header.unpromoted_heights)
+            .filter_map(|(root, height)| root.map(|root| (root, height)))
+            .collect()
+    }
+
+    /// The prefix of keys reserved for firewood itself, if one was recorded
+    pub fn reserved_prefix(&self) -> Option<&[u8]> {
+        self.header.reserved_keys.prefix()
+    }
+
+    /// Returns true if `key` is in the reserved key space
+    pub fn is_reserved(&self, key: &[u8]) -> bool {
+        self.reserved_prefix()
+            .is_some_and(|prefix| key.starts_with(prefix))
+    }
+
+    /// Returns true if `key` is in the reserved key space and reserved keys
+    /// are hidden from users
+    pub fn is_hidden(&self, key: &[u8]) -> bool {
+        self.hidden_prefix()
+            .is_some_and(|prefix| key.starts_with(prefix))
+    }
+
+    /// The reserved prefix, if reserved keys are hidden from users
+    pub fn hidden_prefix(&self) -> Option<&[u8]> {
+        self.reserved_prefix()
+            .filter(|_| self.header.reserved_keys.hidden != 0)
+    }
+
     /// Read a [Node] from the provided [LinearAddress].
     /// `addr` is the address of a StoredArea in the ReadableStorage.
     pub fn read_node_from_disk(&self, addr: LinearAddress) -> Result<Arc<Node>, Error> {
+        self.read_node_from_disk_at(addr, None)
+    }
+
+    /// Like [NodeStore::read_node_from_disk], but tells the cache the node
+    /// is `depth` levels below the root, if that is known
+    fn read_node_from_disk_at(
+        &self,
+        addr: LinearAddress,
+        depth: Option<usize>,
+    ) -> Result<Arc<Node>, Error> {
         if let Some(node) = self.storage.read_cached_node(addr) {
             return Ok(node);
         }
+        // read ahead of an iteration; only offered to the cache now it is used
+        if let Some(node) = self.storage.take_prefetched(addr) {
+            self.storage.cache_read_node(addr, depth, &node);
+            return Ok(node);
+        }
 
-        debug_assert!(addr.get() % 8 == 0);
+        let _span = LocalSpan::enter_with_local_parent("read_and_deserialize");
 
-        let addr = addr.get() + 1; // skip the length byte
+        let node: Arc<Node> = self.read_node_uncached(addr)?.into();
+        self.storage.cache_read_node(addr, depth, &node);
+        Ok(node)
+    }
 
-        let _span = LocalSpan::enter_with_local_parent("read_and_deserialize");
+    /// Read the [Node] at `addr` from storage, even if it is cached, and
+    /// without caching it. For checking what is actually stored.
+    pub fn read_node_uncached(&self, addr: LinearAddress) -> Result<Node, Error> {
+        check_area_address(addr)?;
+
+        // skip the length byte
+        let stream = self.storage.stream_from(addr.get() + 1)?;
+        read_area_node(
+            addr,
+            stream,
+            self.header.node_format(),
+            self.header.node_checksums != 0,
+        )
+    }
 
-        let area_stream = self.storage.stream_from(addr)?;
-        let node = Node::from_reader(area_stream)?;
-        Ok(node.into())
+    /// The area after the one at `addr` on free list `index`, or None if
+    /// `addr` doesn't hold an area of that list
+    fn read_free_area(
+        &self,
+        addr: LinearAddress,
+        index: AreaIndex,
+    ) -> Result<Option<Option<LinearAddress>>, Error> {
+        if check_area_address(addr).is_err() || addr.get() >= self.header.size {
+            return Ok(None);
+        }
+        let stream = self.storage.stream_from(addr.get())?;
+        let area: Result<StoredArea<Area<Node, FreeArea>>, _> =
+            serializer().deserialize_from(stream);
+        Ok(match area {
+            Ok(StoredArea {
+                area_size_index,
+                area: Area::Free(area),
+            }) if area_size_index == index => Some(area.next_free_block),
+            _ => None,
+        })
     }
 }
 
@@ -238,7 +363,7 @@ impl<S: ReadableStorage> NodeStore<Committed, S> {
 
         drop(stream);
 
-        if header.version != Version::new() {
+        if !header.version.is_readable() {
             return Err(Error::new(
                 ErrorKind::InvalidData,
                 "Incompatible firewood version",
@@ -250,6 +375,7 @@ impl<S: ReadableStorage> NodeStore<Committed, S> {
                 "Database cannot be opened due to difference in endianness",
             ));
         }
+        header.check()?;
 
         let mut nodestore = Self {
             header,
@@ -283,6 +409,56 @@ impl<S: ReadableStorage> NodeStore<Committed, S> {
             },
         })
     }
+
+    /// Open the revision rooted at `root`, which must be the root of a
+    /// durable revision sharing this store's storage, at `height`
+    pub fn open_root(&self, root: LinearAddress, height: u64) -> Result<Self, Error> {
+        let mut header = self.header;
+        header.root_address = Some(root);
+        header.height = height;
+        let root_node = self.read_node_from_disk(root)?;
+        Ok(Self {
+            header,
+            kind: Committed {
+                deleted: Default::default(),
+                root_hash: Some(hash_node(&root_node, &Path(Default::default()))),
+            },
+            storage: self.storage.clone(),
+        })
+    }
+
+    /// Forget the unpromoted revisions recorded in the header. The next
+    /// header written from this revision or its descendants won't list them.
+    pub fn clear_unpromoted(&mut self) {
+        self.header.unpromoted = Default::default();
+        self.header.unpromoted_heights = Default::default();
+    }
+
+    /// Set how reserved keys are treated. `prefix` is only recorded if no
+    /// prefix was recorded before, since changing it would expose or strand
+    /// existing reserved keys. Fails if `prefix` is empty or longer than
+    /// [MAX_RESERVED_PREFIX_LEN].
+    pub fn set_reserved_keys(&mut self, prefix: &[u8], hidden: bool) -> Result<(), Error> {
+        let reserved = &mut self.header.reserved_keys;
+        if reserved.len == 0 {
+            let Some(dest) = reserved.prefix.get_mut(..prefix.len()) else {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    format!("reserved key prefix is longer than {MAX_RESERVED_PREFIX_LEN} bytes"),
+                ));
+            };
+            if prefix.is_empty() {
+                return Err(Error::new(
+                    ErrorKind::InvalidInput,
+                    "reserved key prefix is empty",
+                ));
+            }
+            dest.copy_from_slice(prefix);
+            reserved.len = prefix.len() as u64;
+        }
+        reserved.hidden = hidden as u64;
+        Ok(())
+    }
 }
 
 /// Some nodestore kinds implement Parentable.
@@ -311,18 +487,21 @@ impl<S> NodeStore<Arc<ImmutableProposal>, S> {
     /// When an immutable proposal commits, we need to reparent any proposal that
     /// has the committed proposal as it's parent
     pub fn commit_reparent(&self, other: &Arc<NodeStore<Arc<ImmutableProposal>, S>>) -> bool {
+        if self.is_parent_of(other) {
+            other
+                .kind
+                .parent
+                .store(NodeStoreParent::Committed(self.kind.root_hash()).into());
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Returns true if `other` was proposed on top of this proposal
+    pub fn is_parent_of(&self, other: &NodeStore<Arc<ImmutableProposal>, S>) -> bool {
         match *other.kind.parent.load() {
-            NodeStoreParent::Proposed(ref parent) => {
-                if Arc::ptr_eq(&self.kind, parent) {
-                    other
-                        .kind
-                        .parent
-                        .store(NodeStoreParent::Committed(self.kind.root_hash()).into());
-                    true
-                } else {
-                    false
-                }
-            }
+            NodeStoreParent::Proposed(ref parent) => Arc::ptr_eq(&self.kind, parent),
             NodeStoreParent::Committed(_) => false,
         }
     }
@@ -381,6 +560,12 @@ impl<S: ReadableStorage> NodeStore<MutableProposal, S> {
     pub fn mut_root(&mut self) -> &mut Option<Node> {
         &mut self.kind.root
     }
+
+    /// Returns the root of this proposal without copying it, unlike
+    /// [RootReader::root_node].
+    pub const fn root_ref(&self) -> Option<&Node> {
+        self.kind.root.as_ref()
+    }
 }
 
 impl<S: WritableStorage> NodeStore<MutableProposal, S> {
@@ -388,10 +573,7 @@ impl<S: WritableStorage> NodeStore<MutableProposal, S> {
     /// This is used during testing and during the creation of an in-memory merkle for proofs
     pub fn new_empty_proposal(storage: Arc<S>) -> Self {
         let header = NodeStoreHeader::new();
-        let header_bytes = bytemuck::bytes_of(&header);
-        storage
-            .write(0, header_bytes)
-            .expect("failed to write header");
+        HeaderRegion::write(&*storage, &header).expect("failed to write header");
         NodeStore {
             header,
             kind: MutableProposal {
@@ -404,7 +586,148 @@ impl<S: WritableStorage> NodeStore<MutableProposal, S> {
     }
 }
 
+/// How a proposal places its new nodes in storage
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum AllocationPolicy {
+    /// Each new node takes the smallest free area that fits it, wherever
+    /// that is
+    #[default]
+    Scatter,
+    /// A proposal's new nodes are placed next to each other, in the order
+    /// they are hashed, in space reserved for the whole proposal. The space
+    /// comes from one free area big enough to hold all of them, or else from
+    /// the end of the store. The part the proposal doesn't use is freed when
+    /// it is committed.
+    Reserve,
+}
+
+/// Space reserved for the new nodes of one proposal. Nodes are placed at
+/// `next`, which moves towards `end`.
+#[derive(Debug)]
+struct Reservation {
+    next: u64,
+    end: u64,
+}
+
+impl Reservation {
+    /// Take an area of `area_size` bytes from the front of the reservation,
+    /// if there is room
+    fn take(&mut self, area_size: u64) -> Option<LinearAddress> {
+        if self.end - self.next < area_size {
+            return None;
+        }
+        let addr = LinearAddress::new(self.next)?;
+        self.next += area_size;
+        Some(addr)
+    }
+
+    /// The unused end of the reservation, as areas of valid sizes, largest
+    /// first. Every area size is a multiple of the smallest one, so the
+    /// pieces cover it exactly.
+    fn unused(&self) -> Vec<(LinearAddress, AreaIndex)> {
+        let mut pieces = Vec::new();
+        let mut next = self.next;
+        while let Some(index) = AREA_SIZES.iter().rposition(|&size| size <= self.end - next) {
+            let addr = LinearAddress::new(next).expect("reservations never start at 0");
+            pieces.push((addr, index as AreaIndex));
+            next += AREA_SIZES[index];
+        }
+        debug_assert_eq!(next, self.end);
+        pieces
+    }
+}
+
+/// An area taken off a free list that isn't a free area of that list's
+/// size, so the free list is corrupt. Reusing it could overwrite a live
+/// node, so allocating fails instead, with an [Error] of kind
+/// [ErrorKind::InvalidData] that wraps this; see [FreeListCorruption::from_io].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeListCorruption {
+    /// The address taken off the free list
+    pub address: LinearAddress,
+    /// The index of the free list
+    pub index: AreaIndex,
+}
+
+impl FreeListCorruption {
+    /// The [FreeListCorruption] that `err` reports, if any
+    pub fn from_io(err: &Error) -> Option<Self> {
+        err.get_ref()?.downcast_ref::<Self>().copied()
+    }
+}
+
+impl std::fmt::Display for FreeListCorruption {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "free list {} is corrupt: the area at {} isn't a free area of its size",
+            self.index, self.address
+        )
+    }
+}
+
+impl std::error::Error for FreeListCorruption {}
+
+impl From<FreeListCorruption> for Error {
+    fn from(corrupt: FreeListCorruption) -> Self {
+        Error::new(ErrorKind::InvalidData, corrupt)
+    }
+}
+
 impl<S: ReadableStorage> NodeStore<Arc<ImmutableProposal>, S> {
+    /// Pops the head of the smallest non-empty free list whose areas are at
+    /// least as large as the ones of free list `index_wanted`. Returns the
+    /// address of the area and the index of the free list it came from.
+    ///
+    /// The area is checked to be a free area of the list's size before it is
+    /// taken, and if it isn't, this fails with a [FreeListCorruption] and
+    /// leaves the free list as it was. An area found in the free list cache
+    /// isn't read, except in debug builds, which also assert that the cache
+    /// agrees with the area.
+    fn take_free_area(
+        &mut self,
+        index_wanted: AreaIndex,
+    ) -> Result<Option<(LinearAddress, AreaIndex)>, Error> {
+        let Some((index, address)) = self
+            .header
+            .free_lists
+            .iter()
+            .enumerate()
+            .skip(index_wanted as usize)
+            .find_map(|(index, head)| Some((index, (*head)?)))
+        else {
+            return Ok(None);
+        };
+        // Get the next free block of the list.
+        let next = match self.storage.free_list_cache(address) {
+            Some(next) if !cfg!(debug_assertions) => {
+                trace!("free_head@{address}(cached): {next:?} size:{index}");
+                next
+            }
+            cached => {
+                let Some(next) = self.read_free_area(address, index as AreaIndex)? else {
+                    return Err(FreeListCorruption {
+                        address,
+                        index: index as AreaIndex,
+                    }
+                    .into());
+                };
+                if let Some(cached) = cached {
+                    debug_assert_eq!(
+                        cached, next,
+                        "the free list cache disagrees with the free area at {address}"
+                    );
+                }
+                next
+            }
+        };
+
+        // Update the free list to point to the next free block.
+        self.header.free_lists[index] = next;
+        self.header.free_bytes = self.header.free_bytes.saturating_sub(AREA_SIZES[index]);
+        Ok(Some((address, index as AreaIndex)))
+    }
+
     /// Attempts to allocate `n` bytes from the free lists.
     /// If successful returns the address of the newly allocated area
     /// and the index of the free list that was used.
@@ -414,54 +737,18 @@ impl<S: ReadableStorage> NodeStore<Arc<ImmutableProposal>, S> {
         // Find the smallest free list that can fit this size.
         let index_wanted = area_size_to_index(n)?;
 
-        if let Some((index, free_stored_area_addr)) = self
-            .header
-            .free_lists
-            .iter_mut()
-            .enumerate()
-            .skip(index_wanted as usize)
-            .find(|item| item.1.is_some())
-        {
-            let address = free_stored_area_addr
-                .take()
-                .expect("impossible due to find earlier");
-            // Get the first free block of sufficient size.
-            if let Some(free_head) = self.storage.free_list_cache(address) {
-                trace!("free_head@{address}(cached): {free_head:?} size:{index}");
-                *free_stored_area_addr = free_head;
-            } else {
-                let free_area_addr = address.get();
-                let free_head_stream = self.storage.stream_from(free_area_addr)?;
-                let free_head: StoredArea<Area<Node, FreeArea>> = serializer()
-                    .deserialize_from(free_head_stream)
-                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
-                let StoredArea {
-                    area: Area::Free(free_head),
-                    area_size_index: read_index,
-                } = free_head
-                else {
-                    return Err(Error::new(
-                        ErrorKind::InvalidData,
-                        "Attempted to read a non-free area",
-                    ));
-                };
-                debug_assert_eq!(read_index as usize, index);
-
-                // Update the free list to point to the next free block.
-                *free_stored_area_addr = free_head.next_free_block;
-            }
-
-            counter!("firewood.space.reused", "index" => index_name(index as u8))
-                .increment(AREA_SIZES[index]);
-            counter!("firewood.space.wasted", "index" => index_name(index as u8))
-                .increment(AREA_SIZES[index] - n);
+        if let Some((address, index)) = self.take_free_area(index_wanted)? {
+            counter!("firewood.space.reused", "index" => index_name(index))
+                .increment(AREA_SIZES[index as usize]);
+            counter!("firewood.space.wasted", "index" => index_name(index))
+                .increment(AREA_SIZES[index as usize] - n);
 
             // Return the address of the newly allocated block.
             trace!(
                 "Allocating from free list: addr: {address:?}, size: {}",
                 index
             );
-            return Ok(Some((address, index as AreaIndex)));
+            return Ok(Some((address, index)));
         }
 
         trace!("No free blocks of sufficient size {index_wanted} found");
@@ -480,18 +767,19 @@ impl<S: ReadableStorage> NodeStore<Arc<ImmutableProposal>, S> {
         Ok((addr, index))
     }
 
-    /// Returns the length of the serialized area for a node.
-    fn stored_len(node: &Node) -> u64 {
+    /// Returns the length of the serialized area for a node written in
+    /// `format`, including its checksum.
+    fn stored_len(format: NodeFormat, node: &Node) -> u64 {
         let mut bytecounter = ByteCounter::new();
-        node.as_bytes(0, &mut bytecounter);
-        bytecounter.count()
+        node.as_bytes(format, 0, &mut bytecounter);
+        bytecounter.count() + CHECKSUM_LEN as u64
     }
 
     /// Returns an address that can be used to store the given `node` and updates
     /// `self.header` to reflect the allocation. Doesn't actually write the node to storage.
     /// Also returns the index of the free list the node was allocated from.
     pub fn allocate_node(&mut self, node: &Node) -> Result<(LinearAddress, AreaIndex), Error> {
-        let stored_area_size = Self::stored_len(node);
+        let stored_area_size = Self::stored_len(self.header.node_format(), node);
 
         // Attempt to allocate from a free list.
         // If we can't allocate from a free list, allocate past the existing
@@ -503,9 +791,128 @@ impl<S: ReadableStorage> NodeStore<Arc<ImmutableProposal>, S> {
 
         Ok((addr, index))
     }
+
+    /// Like [Self::allocate_node], but takes the area from the front of
+    /// `reservation` while there is room left in it.
+    fn allocate_node_in(
+        &mut self,
+        node: &Node,
+        reservation: &mut Option<Reservation>,
+    ) -> Result<(LinearAddress, AreaIndex), Error> {
+        if let Some(reservation) = reservation {
+            let index = area_size_to_index(Self::stored_len(self.header.node_format(), node))?;
+            if let Some(addr) = reservation.take(AREA_SIZES[index as usize]) {
+                trace!("Allocating from reservation: addr: {addr:?}, size: {index}");
+                return Ok((addr, index));
+            }
+        }
+        self.allocate_node(node)
+    }
+
+    /// Returns the most the serialized area for a node whose children may
+    /// not be hashed yet can take. A hashed child takes the most bytes when
+    /// it is at the largest address.
+    fn hashed_stored_len(format: NodeFormat, node: &Node) -> u64 {
+        let Node::Branch(branch) = node else {
+            return Self::stored_len(format, node);
+        };
+        let placeholder = LinearAddress::MAX;
+        let shallow = Node::Branch(Box::new(BranchNode {
+            partial_path: branch.partial_path.clone(),
+            value: None,
+            children: branch.children.each_ref().map(|child| {
+                child
+                    .as_ref()
+                    .map(|_| Child::AddressWithHash(placeholder, TrieHash::default()))
+            }),
+        }));
+        let value_len = branch
+            .value
+            .as_ref()
+            .map_or(0, |value| value.len().required_space() + value.len());
+        Self::stored_len(format, &shallow) + value_len as u64
+    }
+
+    /// Returns the total size of the areas needed by the nodes of `node`'s
+    /// subtree that aren't in storage yet, and how many of them there are.
+    fn unstored_area_size(format: NodeFormat, node: &Node) -> Result<(u64, usize), Error> {
+        let index = area_size_to_index(Self::hashed_stored_len(format, node))?;
+        let mut size = AREA_SIZES[index as usize];
+        let mut count = 1;
+        if let Node::Branch(branch) = node {
+            for child in branch.children.iter().flatten() {
+                if let Child::Node(child) = child {
+                    let (child_size, child_count) = Self::unstored_area_size(format, child)?;
+                    size += child_size;
+                    count += child_count;
+                }
+            }
+        }
+        Ok((size, count))
+    }
+
+    /// Reserves space for the nodes of the trie at `root` that aren't in
+    /// storage yet, preferring the smallest free area that holds all of
+    /// them. Returns None when there is only one such node.
+    fn reserve(&mut self, root: &Node) -> Result<Option<Reservation>, Error> {
+        let (needed, count) = Self::unstored_area_size(self.header.node_format(), root)?;
+        if count < 2 {
+            return Ok(None);
+        }
+
+        if needed <= MAX_AREA_SIZE {
+            if let Some((addr, index)) = self.take_free_area(area_size_to_index(needed)?)? {
+                trace!("Reserving {needed} bytes from free list {index} at {addr:?}");
+                counter!("firewood.space.reserved", "from" => "free_list").increment(needed);
+                return Ok(Some(Reservation {
+                    next: addr.get(),
+                    end: addr.get() + AREA_SIZES[index as usize],
+                }));
+            }
+        }
+
+        let start = self.header.size;
+        self.header.size += needed;
+        trace!("Reserving {needed} bytes from end at {start}");
+        counter!("firewood.space.reserved", "from" => "end").increment(needed);
+        Ok(Some(Reservation {
+            next: start,
+            end: self.header.size,
+        }))
+    }
 }
 
 impl<S: WritableStorage> NodeStore<Committed, S> {
+    /// Record that this revision was made by commit number `height`
+    pub fn set_height(&mut self, height: u64) {
+        self.header.height = height;
+    }
+
+    /// Write `node` at the end of the store and return its address, for
+    /// loading a trie bottom-up into a new store without proposals. The
+    /// children of `node` must already be stored, and nothing else may be
+    /// writing to the store. The header is not written.
+    pub fn append_node(&mut self, node: &Node) -> Result<LinearAddress, Error> {
+        let format = self.header.node_format();
+        let stored_len = NodeStore::<Arc<ImmutableProposal>, S>::stored_len(format, node);
+        let index = area_size_to_index(stored_len)?;
+        let addr = LinearAddress::new(self.header.size).expect("node store size can't be 0");
+        self.storage.write(
+            &WriteWitness::area(),
+            addr.get(),
+            &area_bytes(format, node, index),
+        )?;
+        self.header.size += AREA_SIZES[index as usize];
+        Ok(addr)
+    }
+
+    /// Make the stored node at `root`, whose hash is `root_hash`, the root
+    /// of this revision. The header is not written.
+    pub fn set_root(&mut self, root: LinearAddress, root_hash: TrieHash) {
+        self.header.root_address = Some(root);
+        self.kind.root_hash = Some(root_hash);
+    }
+
     /// Deletes the [Node] at the given address, updating the next pointer at
     /// the given addr, and changing the header of this committed nodestore to
     /// have the address on the freelist
@@ -515,35 +922,165 @@ impl<S: WritableStorage> NodeStore<Committed, S> {
         let (area_size_index, _) = self.area_index_and_size(addr)?;
         trace!("Deleting node at {addr:?} of size {}", area_size_index);
         counter!("firewood.delete_node", "index" => index_name(area_size_index)).increment(1);
-        counter!("firewood.space.freed", "index" => index_name(area_size_index))
-            .increment(AREA_SIZES[area_size_index as usize]);
+        self.free_area(addr, area_size_index)
+    }
 
-        // The area that contained the node is now free.
-        let area: Area<Node, FreeArea> = Area::Free(FreeArea {
-            next_free_block: self.header.free_lists[area_size_index as usize],
-        });
+    /// Frees the unused part of the space `proposal` reserved for its nodes.
+    /// Must be called when committing `proposal`, before its free lists are
+    /// written.
+    pub fn free_unused_reservation(&mut self, proposal: &ImmutableProposal) -> Result<(), Error> {
+        for &(addr, area_size_index) in proposal.unused_reservation.iter() {
+            counter!("firewood.space.unreserved").increment(AREA_SIZES[area_size_index as usize]);
+            self.free_area(addr, area_size_index)?;
+        }
+        Ok(())
+    }
 
+    /// Puts the areas of `freed`, which an interrupted commit was freeing,
+    /// back on the free lists, skipping the ones it got to. A free list that
+    /// leads to an area that isn't free, as when the commit wrote nodes over
+    /// areas it took from a free list that was never persisted, is cut short
+    /// there, leaking the areas after it rather than handing them out twice.
+    pub fn recover_freed(&mut self, freed: &[LinearAddress]) -> Result<FreeListRecovery, Error> {
+        let mut recovery = FreeListRecovery::default();
+        let mut free = HashSet::new();
+        self.header.free_bytes = 0;
+        for index in 0..NUM_AREA_SIZES as AreaIndex {
+            let mut last = None;
+            let mut next = self.header.free_lists[index as usize];
+            while let Some(addr) = next {
+                match self.read_free_area(addr, index)? {
+                    Some(after) if free.insert(addr) => {
+                        self.header.free_bytes += AREA_SIZES[index as usize];
+                        last = Some(addr);
+                        next = after;
+                    }
+                    _ => {
+                        recovery.cut += 1;
+                        match last {
+                            None => self.header.free_lists[index as usize] = None,
+                            Some(last) => {
+                                self.write_free_area(last, index, None)?;
+                            }
+                        }
+                        break;
+                    }
+                }
+            }
+        }
+
+        for &addr in freed {
+            check_area_address(addr)?;
+            if addr.get() >= self.header.size {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("freed address {addr} is past the end of the store"),
+                ));
+            }
+            if free.insert(addr) {
+                let (area_size_index, _) = self.area_index_and_size(addr)?;
+                self.free_area(addr, area_size_index)?;
+                recovery.requeued += 1;
+            }
+        }
+        Ok(recovery)
+    }
+
+    /// The areas on the free lists, list by list. Each list is followed
+    /// until an area that isn't a free area of the list's size within the
+    /// store, since the rest of the list can't be found, or until an area
+    /// that was already listed, which starts a cycle.
+    pub fn free_list_areas(&self) -> Result<Vec<FreeListArea>, Error> {
+        let mut areas = Vec::new();
+        let mut listed = HashSet::new();
+        for (index, head) in self.header.free_lists.iter().enumerate() {
+            let mut next = *head;
+            while let Some(address) = next {
+                let record = self.read_free_area(address, index as AreaIndex)?;
+                areas.push(FreeListArea {
+                    address,
+                    size: AREA_SIZES[index],
+                    valid: record.is_some(),
+                });
+                if !listed.insert(address) {
+                    break;
+                }
+                next = record.flatten();
+            }
+        }
+        Ok(areas)
+    }
+
+    /// Writes a free area record at `addr`, returning its length
+    fn write_free_area(
+        &self,
+        addr: LinearAddress,
+        area_size_index: AreaIndex,
+        next_free_block: Option<LinearAddress>,
+    ) -> Result<u64, Error> {
         let stored_area = StoredArea {
             area_size_index,
-            area,
+            area: Area::<Node, FreeArea>::Free(FreeArea { next_free_block }),
         };
-
         let stored_area_bytes = serializer()
             .serialize(&stored_area)
             .map_err(|e| Error::new(ErrorKind::InvalidData, e))?;
+        self.storage
+            .write(&WriteWitness::area(), addr.into(), &stored_area_bytes)?;
+        Ok(stored_area_bytes.len() as u64)
+    }
+
+    /// Puts the area at `addr`, of the size at `area_size_index`, at the head
+    /// of its free list
+    fn free_area(&mut self, addr: LinearAddress, area_size_index: AreaIndex) -> Result<(), Error> {
+        counter!("firewood.space.freed", "index" => index_name(area_size_index))
+            .increment(AREA_SIZES[area_size_index as usize]);
 
-        self.storage.write(addr.into(), &stored_area_bytes)?;
+        // The area that contained the node is now free.
+        let record_len = self.write_free_area(
+            addr,
+            area_size_index,
+            self.header.free_lists[area_size_index as usize],
+        )?;
+        // nothing past the free area record is read until the area is reused
+        self.storage.release_free_space(
+            &WriteWitness::area(),
+            addr.get() + record_len,
+            AREA_SIZES[area_size_index as usize] - record_len,
+        )?;
 
         self.storage
             .add_to_free_list_cache(addr, self.header.free_lists[area_size_index as usize]);
 
         // The newly freed block is now the head of the free list.
         self.header.free_lists[area_size_index as usize] = Some(addr);
+        self.header.free_bytes += AREA_SIZES[area_size_index as usize];
 
         Ok(())
     }
 }
 
+/// What [NodeStore::recover_freed] did to the free lists
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FreeListRecovery {
+    /// Freed areas that weren't on a free list yet, and were put on one
+    pub requeued: usize,
+    /// Free lists that were cut short at an area that wasn't free
+    pub cut: usize,
+}
+
+/// An area on a free list, from [NodeStore::free_list_areas]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FreeListArea {
+    /// The address of the area
+    pub address: LinearAddress,
+    /// The size of the areas on its free list
+    pub size: u64,
+    /// False if the area doesn't hold a free area of that size, which ends
+    /// its list
+    pub valid: bool,
+}
+
 /// An error from doing an update
 #[derive(Debug)]
 pub enum UpdateError {
@@ -558,7 +1095,7 @@ impl From<Error> for UpdateError {
 }
 
 /// Can be used by filesystem tooling such as "file" to identify
-/// the version of firewood used to create this [NodeStore] file.
+/// the layout of this [NodeStore] file.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, NoUninit, AnyBitPattern)]
 #[repr(transparent)]
 struct Version {
@@ -568,48 +1105,125 @@ struct Version {
 impl Version {
     const SIZE: u64 = std::mem::size_of::<Self>() as u64;
 
-    /// construct a [Version] header from the firewood version
+    /// The version recorded in the files this version creates. It names the
+    /// layout of the file rather than the release, and only changes when a
+    /// release can't read the files of the one before, or its own files
+    /// can't be read by it.
+    const CURRENT: &'static str = "firewood-v1";
+
+    /// The versions recorded by older files that this version still opens.
+    /// Releases up to 0.0.4 recorded their own version. Their nodes have no
+    /// checksums, which `node_checksums` records.
+    const READABLE: [&'static str; 1] = ["firewood 0.0.4"];
+
+    /// construct a [Version] header for the files this version creates
     fn new() -> Self {
+        Self::named(Self::CURRENT)
+    }
+
+    /// construct a [Version] header recording `version`
+    fn named(version: &str) -> Self {
         let mut version_bytes: [u8; Self::SIZE as usize] = Default::default();
-        let version = env!("CARGO_PKG_VERSION");
-        let _ = version_bytes
-            .as_mut_slice()
-            .write_all(format!("firewood {}", version).as_bytes());
+        let _ = version_bytes.as_mut_slice().write_all(version.as_bytes());
         Self {
             bytes: version_bytes,
         }
     }
+
+    /// Whether this version can open a file with this header
+    fn is_readable(&self) -> bool {
+        *self == Self::new()
+            || Self::READABLE
+                .iter()
+                .any(|version| *self == Self::named(version))
+    }
 }
 
 pub type FreeLists = [Option<LinearAddress>; NUM_AREA_SIZES];
 
+/// The most unpromoted revisions that can be recorded in the header
+pub const MAX_UNPROMOTED: usize = 16;
+
+/// The longest reserved key prefix that can be recorded in the header
+pub const MAX_RESERVED_PREFIX_LEN: usize = 16;
+
+/// Proposals with fewer new nodes than this are hashed on one thread,
+/// whatever [ReadableStorage::hash_threads] allows, since starting threads
+/// would cost more than it saves
+pub const PARALLEL_HASH_THRESHOLD: usize = 4096;
+
+/// Commits with fewer new nodes than this write them on one thread,
+/// whatever [WritableStorage::flush_parallelism] allows
+pub const PARALLEL_FLUSH_THRESHOLD: usize = 4096;
+
+/// The prefix of keys reserved for firewood itself, and whether those keys
+/// are hidden from users. Headers written before this existed are all zero,
+/// meaning no prefix has been recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize, NoUninit, AnyBitPattern)]
+#[repr(C)]
+pub(crate) struct ReservedKeys {
+    len: u64,
+    hidden: u64,
+    prefix: [u8; MAX_RESERVED_PREFIX_LEN],
+}
+
+impl ReservedKeys {
+    fn prefix(&self) -> Option<&[u8]> {
+        match self.len {
+            0 => None,
+            len => self.prefix.get(..len as usize),
+        }
+    }
+}
+
 /// Persisted metadata for a [NodeStore].
 /// The [NodeStoreHeader] is at the start of the ReadableStorage.
 #[derive(Copy, Debug, PartialEq, Eq, Serialize, Deserialize, Clone, NoUninit, AnyBitPattern)]
 #[repr(C)]
-struct NodeStoreHeader {
+pub(crate) struct NodeStoreHeader {
     /// Identifies the version of firewood used to create this [NodeStore].
     version: Version,
     /// always "1"; verifies endianness
-    endian_test: u64,
-    size: u64,
+    pub(crate) endian_test: u64,
+    pub(crate) size: u64,
     /// Element i is the pointer to the first free block of size `BLOCK_SIZES[i]`.
-    free_lists: FreeLists,
+    pub(crate) free_lists: FreeLists,
     root_address: Option<LinearAddress>,
+    /// The keys reserved for firewood itself
+    reserved_keys: ReservedKeys,
+    /// The roots of durable revisions that were committed but not promoted
+    /// to `root_address`, when an external authority decides which root is
+    /// canonical
+    unpromoted: [Option<LinearAddress>; MAX_UNPROMOTED],
+    /// The total size of the areas on the free lists. Databases created
+    /// before this was tracked start counting from zero.
+    free_bytes: u64,
+    /// The number of commits that led to the revision at `root_address`
+    height: u64,
+    /// The heights of the revisions in `unpromoted`
+    unpromoted_heights: [u64; MAX_UNPROMOTED],
+    /// Nonzero if every node is followed by its checksum, which reads
+    /// verify. Files created before checksums were written are read
+    /// without verifying them, until they are compacted.
+    node_checksums: u64,
+    /// The [NodeFormat::version] nodes are written in. Files created with an
+    /// older format keep writing nodes in it until they are compacted.
+    node_format: u64,
 }
 
 impl NodeStoreHeader {
     /// The first SIZE bytes of the ReadableStorage are reserved for the
     /// [NodeStoreHeader].
     /// We also want it aligned to a disk block
-    const SIZE: u64 = 2048;
+    pub(crate) const SIZE: u64 = 2048;
 
     /// Number of extra bytes to write on the first creation of the NodeStoreHeader
     /// (zero-padded)
     /// also a compile time check to prevent setting SIZE too small
-    const EXTRA_BYTES: usize = Self::SIZE as usize - std::mem::size_of::<NodeStoreHeader>();
+    pub(crate) const EXTRA_BYTES: usize =
+        Self::SIZE as usize - std::mem::size_of::<NodeStoreHeader>();
 
-    fn new() -> Self {
+    pub(crate) fn new() -> Self {
         Self {
             // The store just contains the header at this point
             size: Self::SIZE,
@@ -617,8 +1231,106 @@ impl NodeStoreHeader {
             root_address: None,
             version: Version::new(),
             free_lists: Default::default(),
+            reserved_keys: bytemuck::Zeroable::zeroed(),
+            unpromoted: Default::default(),
+            free_bytes: 0,
+            height: 0,
+            unpromoted_heights: Default::default(),
+            node_checksums: 1,
+            node_format: NodeFormat::LATEST.version(),
         }
     }
+
+    /// The format nodes are written in
+    fn node_format(&self) -> NodeFormat {
+        NodeFormat::from_version(self.node_format).expect("checked when the header was read")
+    }
+}
+
+impl NodeStoreHeader {
+    /// Check the fields of a header read from storage, which may be corrupt,
+    /// before anything is read through them
+    fn check(&self) -> Result<(), Error> {
+        if self.size < Self::SIZE {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!("header size {} is smaller than the header", self.size),
+            ));
+        }
+        NodeFormat::from_version(self.node_format)?;
+        if self.reserved_keys.len > MAX_RESERVED_PREFIX_LEN as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                format!(
+                    "reserved prefix length {} is too long",
+                    self.reserved_keys.len
+                ),
+            ));
+        }
+
+        let addresses = self
+            .free_lists
+            .iter()
+            .chain(std::iter::once(&self.root_address))
+            .chain(self.unpromoted.iter())
+            .flatten();
+        for addr in addresses {
+            check_area_address(*addr)?;
+            if addr.get() >= self.size {
+                return Err(Error::new(
+                    ErrorKind::InvalidData,
+                    format!("address {addr} is past the end of the store"),
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Serialize `node` in `format` into the area with size index
+/// `area_size_index`: the index, then the node, then the checksum of the node
+fn area_bytes(format: NodeFormat, node: &Node, area_size_index: AreaIndex) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    node.as_bytes(format, area_size_index, &mut bytes);
+    let checksum = crc32c(0, bytes.get(1..).unwrap_or_default());
+    bytes.extend_from_slice(&checksum.to_le_bytes());
+    bytes
+}
+
+/// Returns an error if `addr` can't be the start of an area
+pub(crate) fn check_area_address(addr: LinearAddress) -> Result<(), Error> {
+    if addr.get() % 8 != 0 || addr.get() < NodeStoreHeader::SIZE {
+        return Err(Error::new(
+            ErrorKind::InvalidData,
+            format!("invalid area address {addr}"),
+        ));
+    }
+    Ok(())
+}
+
+/// Read the node stored in the area at `addr` from `stream`, which starts
+/// after the area's length byte, checking its checksum if `checksums`
+pub(crate) fn read_area_node<R: Read>(
+    addr: LinearAddress,
+    stream: R,
+    format: NodeFormat,
+    checksums: bool,
+) -> Result<Node, Error> {
+    let mut area_stream = ChecksumReader::new(stream);
+    let node = Node::from_reader(format, &mut area_stream)?;
+    if checksums {
+        let actual = area_stream.checksum();
+        let expected = area_stream.read_stored()?;
+        if actual != expected {
+            return Err(CorruptNode {
+                address: addr,
+                expected,
+                actual,
+            }
+            .into());
+        }
+    }
+    Ok(node)
 }
 
 /// A [FreeArea] is stored at the start of the area that contained a node that
@@ -647,6 +1359,21 @@ impl<T> TrieReader for T where T: NodeReader + RootReader {}
 pub trait NodeReader {
     /// Returns the node at `addr`.
     fn read_node(&self, addr: LinearAddress) -> Result<Arc<Node>, Error>;
+
+    /// Returns the node at `addr`, which is `depth` levels below the root.
+    /// The depth lets the node cache favor nodes near the root.
+    fn read_node_at_depth(&self, addr: LinearAddress, _depth: usize) -> Result<Arc<Node>, Error> {
+        self.read_node(addr)
+    }
+
+    /// How far an iteration reads ahead of itself
+    fn read_ahead(&self) -> ReadAhead {
+        ReadAhead::default()
+    }
+
+    /// Start reading the nodes at `addrs` in the background, for an
+    /// iteration that will reach them soon. See [ReadAhead].
+    fn prefetch(&self, _addrs: &[LinearAddress]) {}
 }
 
 impl<T> NodeReader for T
@@ -657,6 +1384,18 @@ where
     fn read_node(&self, addr: LinearAddress) -> Result<Arc<Node>, Error> {
         self.deref().read_node(addr)
     }
+
+    fn read_node_at_depth(&self, addr: LinearAddress, depth: usize) -> Result<Arc<Node>, Error> {
+        self.deref().read_node_at_depth(addr, depth)
+    }
+
+    fn read_ahead(&self) -> ReadAhead {
+        self.deref().read_ahead()
+    }
+
+    fn prefetch(&self, addrs: &[LinearAddress]) {
+        self.deref().prefetch(addrs)
+    }
 }
 
 impl<T> RootReader for T
@@ -719,6 +1458,9 @@ pub struct ImmutableProposal {
     parent: Arc<ArcSwap<NodeStoreParent>>,
     /// The hash of the root node for this proposal
     root_hash: Option<TrieHash>,
+    /// The part of the space reserved for `new` that it doesn't use, freed
+    /// when this proposal is committed
+    unused_reservation: Box<[(LinearAddress, AreaIndex)]>,
 }
 
 impl ImmutableProposal {
@@ -733,6 +1475,18 @@ impl ImmutableProposal {
             _ => false,
         }
     }
+
+    /// Returns true if the parent of this proposal is committed, whether or
+    /// not it is still the most recent commit
+    pub fn parent_is_committed(&self) -> bool {
+        matches!(
+            <Arc<ArcSwap<NodeStoreParent>> as arc_swap::access::DynAccess<Arc<_>>>::load(
+                &self.parent,
+            )
+            .as_ref(),
+            NodeStoreParent::Committed(_)
+        )
+    }
 }
 
 impl ReadInMemoryNode for ImmutableProposal {
@@ -856,13 +1610,17 @@ impl<S: WritableStorage> From<NodeStore<ImmutableProposal, S>> for NodeStore<Com
 
 impl<S: ReadableStorage> NodeStore<Arc<ImmutableProposal>, S> {
     /// Hashes `node`, which is at the given `path_prefix`, and its children recursively.
-    /// Returns the hashed node and its hash.
+    /// Returns the hashed node and its hash. The hashes already worked out
+    /// by [hash_unhashed], if any, are used rather than hashing again;
+    /// either way, nodes are allocated in the same order.
     fn hash_helper(
         &mut self,
         mut node: Node,
         path_prefix: &mut Path,
         new_nodes: &mut HashMap<LinearAddress, (u8, Arc<Node>)>,
-    ) -> (LinearAddress, TrieHash) {
+        reservation: &mut Option<Reservation>,
+        mut hashes: Option<PendingHashes>,
+    ) -> Result<(LinearAddress, TrieHash), Error> {
         // Allocate addresses and calculate hashes for all new nodes
         match node {
             Node::Branch(ref mut b) => {
@@ -885,8 +1643,14 @@ impl<S: ReadableStorage> NodeStore<Arc<ImmutableProposal>, S> {
                         .0
                         .extend(b.partial_path.0.iter().copied().chain(once(nibble as u8)));
 
-                    let (child_addr, child_hash) =
-                        self.hash_helper(child_node, path_prefix, new_nodes);
+                    let child_hashes = hashes.as_mut().and_then(|hashes| hashes.take_child(nibble));
+                    let (child_addr, child_hash) = self.hash_helper(
+                        child_node,
+                        path_prefix,
+                        new_nodes,
+                        reservation,
+                        child_hashes,
+                    )?;
                     *child = Some(Child::AddressWithHash(child_addr, child_hash));
                     path_prefix.0.truncate(original_length);
                 }
@@ -894,70 +1658,180 @@ impl<S: ReadableStorage> NodeStore<Arc<ImmutableProposal>, S> {
             Node::Leaf(_) => {}
         }
 
-        let hash = hash_node(&node, path_prefix);
-        let (addr, size) = self.allocate_node(&node).expect("TODO handle error");
+        let hash = match hashes {
+            Some(hashes) => hashes.hash,
+            None => hash_node(&node, path_prefix),
+        };
+        let (addr, size) = self.allocate_node_in(&node, reservation)?;
 
         new_nodes.insert(addr, (size, Arc::new(node)));
 
-        (addr, hash)
+        Ok((addr, hash))
     }
 }
 
 impl<T, S: WritableStorage> NodeStore<T, S> {
+    /// Persist the freelist from this nodestore to storage.
+    #[fastrace::trace(short_name = true)]
+    pub fn flush_freelist(&self) -> Result<(), Error> {
+        // Write the free lists to storage
+        FreeListRegion::write(&*self.storage, &self.header.free_lists)
+    }
+
     /// Persist the header from this proposal to storage.
     pub fn flush_header(&self) -> Result<(), Error> {
-        let header_bytes = bytemuck::bytes_of(&self.header);
-        self.storage.write(0, header_bytes)?;
-        Ok(())
+        HeaderRegion::write(&*self.storage, &self.header)
     }
 
     /// Persist the header, including all the padding
     /// This is only done the first time we write the header
     pub fn flush_header_with_padding(&self) -> Result<(), Error> {
-        let header_bytes = bytemuck::bytes_of(&self.header)
-            .iter()
-            .copied()
-            .chain(std::iter::repeat(0u8).take(NodeStoreHeader::EXTRA_BYTES))
-            .collect::<Box<[u8]>>();
-        debug_assert_eq!(header_bytes.len(), NodeStoreHeader::SIZE as usize);
+        HeaderRegion::write_with_padding(&*self.storage, &self.header)
+    }
 
-        self.storage.write(0, &header_bytes)?;
-        Ok(())
+    /// Persist the header from this nodestore, but pointing at `root` at
+    /// `height` and listing `unpromoted` as the unpromoted revisions, with
+    /// their heights. The free lists and size always come from the newest
+    /// revision, while the root may be an older one.
+    pub fn flush_header_with_root(
+        &self,
+        root: Option<LinearAddress>,
+        height: u64,
+        unpromoted: &[(LinearAddress, u64)],
+    ) -> Result<(), Error> {
+        if unpromoted.len() > MAX_UNPROMOTED {
+            return Err(Error::new(
+                ErrorKind::InvalidInput,
+                format!("at most {MAX_UNPROMOTED} unpromoted revisions can be recorded"),
+            ));
+        }
+        let mut header = self.header;
+        header.root_address = root;
+        header.height = height;
+        header.unpromoted = Default::default();
+        header.unpromoted_heights = Default::default();
+        let slots = header
+            .unpromoted
+            .iter_mut()
+            .zip(&mut header.unpromoted_heights);
+        for ((slot, slot_height), (addr, height)) in slots.zip(unpromoted) {
+            *slot = Some(*addr);
+            *slot_height = *height;
+        }
+        HeaderRegion::write(&*self.storage, &header)
     }
 }
 
 impl<S: WritableStorage> NodeStore<Arc<ImmutableProposal>, S> {
-    /// Persist the freelist from this proposal to storage.
-    #[fastrace::trace(short_name = true)]
-    pub fn flush_freelist(&self) -> Result<(), Error> {
-        // Write the free lists to storage
-        let free_list_bytes = bytemuck::bytes_of(&self.header.free_lists);
-        let free_list_offset = offset_of!(NodeStoreHeader, free_lists) as u64;
-        self.storage.write(free_list_offset, free_list_bytes)?;
-        Ok(())
-    }
-
-    /// Persist all the nodes of a proposal to storage.
+    /// Persist all the nodes of a proposal to storage. Large proposals
+    /// are split into runs of adjacent addresses, each written by its own
+    /// thread, up to [WritableStorage::flush_parallelism] of them.
     #[fastrace::trace(short_name = true)]
     pub fn flush_nodes(&self) -> Result<(), Error> {
-        for (addr, (area_size_index, node)) in self.kind.new.iter() {
-            let mut stored_area_bytes = Vec::new();
-            node.as_bytes(*area_size_index, &mut stored_area_bytes);
-            self.storage
-                .write(addr.get(), stored_area_bytes.as_slice())?;
+        // new nodes are often allocated next to each other, so adjacent
+        // areas are written together, in address order
+        let mut areas: Vec<_> = self.kind.new.iter().collect();
+        areas.sort_unstable_by_key(|(addr, _)| **addr);
+        let threads = self.storage.flush_parallelism().get();
+        if threads < 2 || areas.len() < PARALLEL_FLUSH_THRESHOLD {
+            self.flush_areas(&areas)?;
+        } else {
+            // each thread writes a contiguous run, so coalescing still
+            // works within it
+            let run_len = areas.len().div_ceil(threads);
+            std::thread::scope(|scope| {
+                let handles: Vec<_> = areas
+                    .chunks(run_len)
+                    .map(|run| scope.spawn(move || self.flush_areas(run)))
+                    .collect();
+                handles.into_iter().try_for_each(|handle| {
+                    handle
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+            })?;
         }
+        counter!("firewood.commit.nodes_written").increment(self.kind.new.len() as u64);
 
         self.storage
             .write_cached_nodes(self.kind.new.iter().map(|(addr, (_, node))| (addr, node)))?;
 
         Ok(())
     }
+
+    /// Write `areas`, which are sorted by address, coalescing adjacent ones
+    fn flush_areas(&self, areas: &[(&LinearAddress, &(u8, Arc<Node>))]) -> Result<(), Error> {
+        let max_write = self.storage.max_flush_write();
+        let mut write = CoalescedWrite::default();
+        for (addr, (area_size_index, node)) in areas {
+            let stored_area_bytes = area_bytes(self.header.node_format(), node, *area_size_index);
+            let area_size = AREA_SIZES
+                .get(*area_size_index as usize)
+                .copied()
+                .unwrap_or(MAX_AREA_SIZE);
+            if !write.extends_to(addr.get(), stored_area_bytes.len(), max_write) {
+                write.flush(&*self.storage)?;
+            }
+            write.push(addr.get(), area_size, stored_area_bytes);
+        }
+        write.flush(&*self.storage)
+    }
 }
 
-impl NodeStore<Arc<ImmutableProposal>, FileBacked> {
+/// Adjacent node areas that [NodeStore::flush_nodes] writes with a single
+/// call. The areas can't have gaps between them, since a gap may hold a
+/// node of another revision.
+#[derive(Debug, Default)]
+struct CoalescedWrite {
+    /// Where the first area starts
+    offset: u64,
+    /// The bytes of each area. All but the last are padded to the end of
+    /// their area, so each starts where its area does.
+    parts: Vec<Vec<u8>>,
+    /// Where the last area starts
+    last: u64,
+    /// Where the last area ends
+    end: u64,
+}
+
+impl CoalescedWrite {
+    /// Whether `len` bytes for the area at `addr` can be added to this
+    /// write, keeping it within `max_write` bytes
+    fn extends_to(&self, addr: u64, len: usize, max_write: usize) -> bool {
+        self.parts.is_empty()
+            || (addr == self.end && (addr - self.offset) as usize + len <= max_write)
+    }
+
+    /// Add `bytes` for the area at `addr` of `area_size` bytes, which
+    /// [CoalescedWrite::extends_to] allowed
+    fn push(&mut self, addr: u64, area_size: u64, bytes: Vec<u8>) {
+        match self.parts.last_mut() {
+            None => self.offset = addr,
+            Some(last) => last.resize((addr - self.last) as usize, 0),
+        }
+        self.parts.push(bytes);
+        self.last = addr;
+        self.end = addr + area_size;
+    }
+
+    /// Write the areas added so far, and start over
+    fn flush<S: WritableStorage>(&mut self, storage: &S) -> Result<(), Error> {
+        if self.parts.is_empty() {
+            return Ok(());
+        }
+        let parts: Vec<_> = self.parts.iter().map(|part| IoSlice::new(part)).collect();
+        let written = storage.write_vectored(&WriteWitness::area(), self.offset, &parts)?;
+        counter!("firewood.flush.write_calls").increment(1);
+        counter!("firewood.flush.bytes").increment(written as u64);
+        self.parts.clear();
+        Ok(())
+    }
+}
+
+impl<S> NodeStore<Arc<ImmutableProposal>, S> {
     /// Return a Committed version of this proposal, which doesn't have any modified nodes.
     /// This function is used during commit.
-    pub fn as_committed(&self) -> NodeStore<Committed, FileBacked> {
+    pub fn as_committed(&self) -> NodeStore<Committed, S> {
         NodeStore {
             header: self.header,
             kind: Committed {
@@ -973,6 +1847,15 @@ impl<S: ReadableStorage> From<NodeStore<MutableProposal, S>>
     for NodeStore<Arc<ImmutableProposal>, S>
 {
     fn from(val: NodeStore<MutableProposal, S>) -> Self {
+        Self::try_from_mutable(val).expect("allocating the new nodes failed")
+    }
+}
+
+impl<S: ReadableStorage> NodeStore<Arc<ImmutableProposal>, S> {
+    /// Hash the new nodes of `val` and allocate their areas, as the [From]
+    /// conversion does, but return an error rather than panic if allocating
+    /// fails, such as on a corrupt free list; see [FreeListCorruption].
+    pub fn try_from_mutable(val: NodeStore<MutableProposal, S>) -> Result<Self, Error> {
         let NodeStore {
             header,
             kind,
@@ -986,6 +1869,7 @@ impl<S: ReadableStorage> From<NodeStore<MutableProposal, S>>
                 deleted: kind.deleted.into(),
                 parent: Arc::new(ArcSwap::new(Arc::new(kind.parent))),
                 root_hash: None,
+                unused_reservation: Default::default(),
             }),
             storage,
         };
@@ -993,12 +1877,30 @@ impl<S: ReadableStorage> From<NodeStore<MutableProposal, S>>
         let Some(root) = kind.root else {
             // This trie is now empty.
             nodestore.header.root_address = None;
-            return nodestore;
+            return Ok(nodestore);
         };
 
         // Hashes the trie and returns the address of the new root.
         let mut new_nodes = HashMap::new();
-        let (root_addr, root_hash) = nodestore.hash_helper(root, &mut Path::new(), &mut new_nodes);
+        let mut reservation = match nodestore.storage.allocation_policy() {
+            // a corrupt free list fails the proposal here, rather than
+            // falling back to allocating nodes one at a time from it
+            AllocationPolicy::Reserve => nodestore.reserve(&root)?,
+            AllocationPolicy::Scatter => None,
+        };
+        // a large trie is hashed on several threads first, if the storage
+        // allows it
+        let threads = nodestore.storage.hash_threads().get();
+        let hashes = (threads > 1
+            && count_unhashed(&root, PARALLEL_HASH_THRESHOLD) >= PARALLEL_HASH_THRESHOLD)
+            .then(|| hash_unhashed(&root, &mut Path::new(), threads));
+        let (root_addr, root_hash) = nodestore.hash_helper(
+            root,
+            &mut Path::new(),
+            &mut new_nodes,
+            &mut reservation,
+            hashes,
+        )?;
 
         nodestore.header.root_address = Some(root_addr);
         let immutable_proposal =
@@ -1008,9 +1910,14 @@ impl<S: ReadableStorage> From<NodeStore<MutableProposal, S>>
             deleted: immutable_proposal.deleted,
             parent: immutable_proposal.parent,
             root_hash: Some(root_hash),
+            unused_reservation: reservation
+                .as_ref()
+                .map(Reservation::unused)
+                .unwrap_or_default()
+                .into(),
         });
 
-        nodestore
+        Ok(nodestore)
     }
 }
 
@@ -1022,6 +1929,37 @@ impl<T: ReadInMemoryNode, S: ReadableStorage> NodeReader for NodeStore<T, S> {
 
         self.read_node_from_disk(addr)
     }
+
+    fn read_node_at_depth(&self, addr: LinearAddress, depth: usize) -> Result<Arc<Node>, Error> {
+        if let Some(node) = self.kind.read_in_memory_node(addr) {
+            return Ok(node);
+        }
+
+        self.read_node_from_disk_at(addr, Some(depth))
+    }
+
+    fn read_ahead(&self) -> ReadAhead {
+        self.storage.read_ahead()
+    }
+
+    fn prefetch(&self, addrs: &[LinearAddress]) {
+        if self.storage.read_ahead().width == 0 {
+            return;
+        }
+        // nodes of a proposal that aren't written yet are already in memory
+        let addrs: Vec<_> = addrs
+            .iter()
+            .filter(|addr| self.kind.read_in_memory_node(**addr).is_none())
+            .copied()
+            .collect();
+        if !addrs.is_empty() {
+            self.storage.prefetch(
+                addrs,
+                self.header.node_format(),
+                self.header.node_checksums != 0,
+            );
+        }
+    }
 }
 
 impl<S: ReadableStorage> RootReader for NodeStore<MutableProposal, S> {
@@ -1077,6 +2015,14 @@ where
     }
 }
 
+impl<S> NodeStore<Committed, S> {
+    /// The nodes of the parent revision that this one no longer uses, which
+    /// are freed when this revision is reaped
+    pub fn deleted(&self) -> &[LinearAddress] {
+        &self.kind.deleted
+    }
+}
+
 impl<S: WritableStorage> NodeStore<Committed, S> {
     /// adjust the freelist of this proposal to reflect the freed nodes in the oldest proposal
     pub fn reap_deleted(mut self, proposal: &mut NodeStore<Committed, S>) -> Result<(), Error> {
@@ -1088,6 +2034,30 @@ impl<S: WritableStorage> NodeStore<Committed, S> {
         }
         Ok(())
     }
+
+    /// The areas [NodeStore::reap_deleted] would free, with their area size
+    /// indexes, which reading them takes. Nothing is written, so this can
+    /// run off the commit path, and [NodeStore::free_resolved] frees them
+    /// later.
+    pub fn resolve_deleted(self) -> Result<Vec<(LinearAddress, AreaIndex)>, Error> {
+        self.storage
+            .invalidate_cached_nodes(self.kind.deleted.iter());
+        self.kind
+            .deleted
+            .iter()
+            .map(|&addr| Ok((addr, self.area_index_and_size(addr)?.0)))
+            .collect()
+    }
+
+    /// Free the areas [NodeStore::resolve_deleted] looked up, as
+    /// [NodeStore::reap_deleted] does, without reading them
+    pub fn free_resolved(&mut self, areas: &[(LinearAddress, AreaIndex)]) -> Result<(), Error> {
+        for &(addr, area_size_index) in areas {
+            counter!("firewood.delete_node", "index" => index_name(area_size_index)).increment(1);
+            self.free_area(addr, area_size_index)?;
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1095,6 +2065,8 @@ impl<S: WritableStorage> NodeStore<Committed, S> {
 mod tests {
     use std::array::from_fn;
 
+    use std::collections::HashSet;
+
     use crate::linear::memory::MemStore;
     use crate::{BranchNode, LeafNode};
     use arc_swap::access::DynGuard;
@@ -1206,15 +2178,16 @@ mod tests {
     fn test_serialized_len<N: Into<Node>>(node: N) {
         let node = node.into();
 
-        let computed_length =
-            NodeStore::<std::sync::Arc<ImmutableProposal>, MemStore>::stored_len(&node);
+        for format in NodeFormat::ALL {
+            let computed_length =
+                NodeStore::<std::sync::Arc<ImmutableProposal>, MemStore>::stored_len(format, &node);
 
-        let mut serialized = Vec::new();
-        node.as_bytes(0, &mut serialized);
-        assert_eq!(serialized.len() as u64, computed_length);
+            let serialized = area_bytes(format, &node, 0);
+            assert_eq!(serialized.len() as u64, computed_length);
+        }
     }
     #[test]
-    #[should_panic(expected = "Node size 16777225 is too large")]
+    #[should_panic(expected = "is too large")]
     fn giant_node() {
         let memstore = MemStore::new(vec![]);
         let mut node_store = NodeStore::new_empty_proposal(memstore.into());
@@ -1231,4 +2204,407 @@ mod tests {
         let immutable = NodeStore::<Arc<ImmutableProposal>, _>::from(node_store);
         println!("{:?}", immutable); // should not be reached, but need to consume immutable to avoid optimization removal
     }
+
+    fn open_with_header(header: &NodeStoreHeader) -> Result<NodeStore<Committed, MemStore>, Error> {
+        let memstore = MemStore::new(vec![]);
+        HeaderRegion::write_with_padding(&memstore, header).unwrap();
+        NodeStore::open(memstore.into())
+    }
+
+    #[test_case(|h| h.root_address = LinearAddress::new(4097); "unaligned root")]
+    #[test_case(|h| h.root_address = LinearAddress::new(8); "root inside header")]
+    #[test_case(|h| h.root_address = LinearAddress::new(4096); "root past end")]
+    #[test_case(|h| h.free_lists[3] = LinearAddress::new(2050); "unaligned free list head")]
+    #[test_case(|h| h.unpromoted[0] = LinearAddress::new(1 << 40); "unpromoted past end")]
+    #[test_case(|h| h.reserved_keys.len = u64::MAX; "reserved prefix too long")]
+    #[test_case(|h| h.size = 0; "size smaller than header")]
+    fn corrupt_header(corrupt: fn(&mut NodeStoreHeader)) {
+        let mut header = NodeStoreHeader::new();
+        corrupt(&mut header);
+        let err = open_with_header(&header).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}");
+    }
+
+    #[test]
+    fn open_older_versions() {
+        // a file from a release that recorded its own version still opens
+        let mut header = NodeStoreHeader::new();
+        header.version = Version::named("firewood 0.0.4");
+        let opened = open_with_header(&header).unwrap();
+        assert_eq!(opened.header.version, header.version);
+
+        // but one from a version this one doesn't know is refused
+        header.version = Version::named("firewood 99.0.0");
+        let err = open_with_header(&header).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}");
+    }
+
+    #[test]
+    fn free_list_with_wrong_area_size() {
+        let memstore = MemStore::new(vec![]);
+        let node_store = NodeStore::new_empty_committed(memstore.into()).unwrap();
+
+        // an area of size index 2 on free list 1
+        let free_area: StoredArea<Area<Node, FreeArea>> = StoredArea {
+            area_size_index: 2,
+            area: Area::Free(FreeArea {
+                next_free_block: None,
+            }),
+        };
+        let bytes = serializer().serialize(&free_area).unwrap();
+        let addr = LinearAddress::new(NodeStoreHeader::SIZE).unwrap();
+        node_store
+            .storage
+            .write(&WriteWitness::area(), addr.get(), &bytes)
+            .unwrap();
+        let proposal = NodeStore::new(Arc::new(node_store)).unwrap();
+        let mut proposal = NodeStore::<Arc<ImmutableProposal>, _>::from(proposal);
+        proposal.header.free_lists[1] = Some(addr);
+        proposal.header.size += 64;
+
+        let err = proposal.allocate_from_freed(20).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}");
+    }
+
+    #[test]
+    fn free_list_head_not_free() {
+        let memstore = MemStore::new(vec![]);
+        let node_store = NodeStore::new_empty_committed(memstore.into()).unwrap();
+
+        // a live leaf on free list 1
+        let leaf = Node::Leaf(LeafNode {
+            partial_path: Path::from([1, 2]),
+            value: SmallVec::from_slice(&[3]),
+        });
+        let mut bytes = Vec::new();
+        leaf.as_bytes(node_store.node_format(), 1, &mut bytes);
+        let addr = LinearAddress::new(NodeStoreHeader::SIZE).unwrap();
+        node_store
+            .storage
+            .write(&WriteWitness::area(), addr.get(), &bytes)
+            .unwrap();
+        let proposal = NodeStore::new(Arc::new(node_store)).unwrap();
+        let mut proposal = NodeStore::<Arc<ImmutableProposal>, _>::from(proposal);
+        proposal.header.free_lists[1] = Some(addr);
+        proposal.header.size += 32;
+        let free_bytes = proposal.header.free_bytes;
+
+        let err = proposal.allocate_from_freed(20).unwrap_err();
+        assert_eq!(
+            FreeListCorruption::from_io(&err),
+            Some(FreeListCorruption {
+                address: addr,
+                index: 1
+            })
+        );
+        // the area stays on the free list, rather than being handed out
+        assert_eq!(proposal.header.free_lists[1], Some(addr));
+        assert_eq!(proposal.header.free_bytes, free_bytes);
+    }
+
+    #[test]
+    fn node_formats() {
+        for format in NodeFormat::ALL {
+            let memstore = MemStore::new(vec![]);
+            let mut parent = NodeStore::new_empty_committed(memstore.into()).unwrap();
+            parent.header.node_format = format.version();
+            parent.flush_header_with_padding().unwrap();
+            let proposal = propose(&Arc::new(parent), wide_trie(16, 10));
+            let committed = commit_and_reopen(&proposal);
+            assert_eq!(committed.header.node_format(), format);
+
+            let root = committed.root_address().unwrap();
+            let Node::Branch(branch) = &*committed.read_node(root).unwrap() else {
+                panic!("the root is a branch");
+            };
+            assert_eq!(branch.num_children(), 16);
+            for (i, child) in branch.children.iter().flatten().enumerate() {
+                let Child::AddressWithHash(leaf, _) = child else {
+                    panic!("committed nodes are hashed");
+                };
+                let leaf = committed.read_node(*leaf).unwrap();
+                assert_eq!(leaf.value(), Some(&[i as u8; 10][..]));
+            }
+        }
+
+        // a format from a newer version
+        let memstore = MemStore::new(vec![]);
+        let mut nodestore = NodeStore::new_empty_committed(memstore.into()).unwrap();
+        nodestore.header.node_format = 3;
+        nodestore.flush_header_with_padding().unwrap();
+        let err = NodeStore::open(nodestore.storage.clone()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData, "{err}");
+    }
+
+    /// A branch with a value and `leaves` leaf children of `value_len` bytes
+    fn wide_trie(leaves: usize, value_len: usize) -> Node {
+        Node::Branch(Box::new(BranchNode {
+            partial_path: Path::from([1]),
+            value: Some(vec![7; 5].into_boxed_slice()),
+            children: from_fn(|i| {
+                (i < leaves).then(|| {
+                    Child::Node(Node::Leaf(LeafNode {
+                        partial_path: Path::from([2, 3]),
+                        value: SmallVec::from_vec(vec![i as u8; value_len]),
+                    }))
+                })
+            }),
+        }))
+    }
+
+    fn reserving_store() -> Arc<NodeStore<Committed, MemStore>> {
+        let memstore = MemStore::new(vec![]).with_allocation_policy(AllocationPolicy::Reserve);
+        let node_store = NodeStore::new_empty_committed(memstore.into()).unwrap();
+        node_store.flush_header_with_padding().unwrap();
+        Arc::new(node_store)
+    }
+
+    fn propose(
+        parent: &Arc<NodeStore<Committed, MemStore>>,
+        root: Node,
+    ) -> NodeStore<Arc<ImmutableProposal>, MemStore> {
+        let mut proposal = NodeStore::new(parent.clone()).unwrap();
+        proposal.mut_root().replace(root);
+        proposal.into()
+    }
+
+    /// Write `proposal` the way the revision manager commits it, then open
+    /// the store again as if after a crash
+    fn commit_and_reopen(
+        proposal: &NodeStore<Arc<ImmutableProposal>, MemStore>,
+    ) -> NodeStore<Committed, MemStore> {
+        let mut committed = proposal.as_committed();
+        committed.free_unused_reservation(&proposal.kind).unwrap();
+        committed.flush_freelist().unwrap();
+        proposal.flush_nodes().unwrap();
+        committed.flush_header().unwrap();
+        NodeStore::open(proposal.storage.clone()).unwrap()
+    }
+
+    /// The new nodes of `proposal` as (address, area size), by address
+    fn new_areas(proposal: &NodeStore<Arc<ImmutableProposal>, MemStore>) -> Vec<(u64, u64)> {
+        let mut areas: Vec<_> = proposal
+            .kind
+            .new
+            .iter()
+            .map(|(addr, (index, _))| (addr.get(), AREA_SIZES[*index as usize]))
+            .collect();
+        areas.sort();
+        areas
+    }
+
+    /// Check that every area of `nodestore` is either reachable from its
+    /// root or on a free list
+    fn assert_no_leaks(nodestore: &NodeStore<Committed, MemStore>) {
+        let mut reachable = HashSet::new();
+        let mut stack: Vec<_> = nodestore.root_address().into_iter().collect();
+        while let Some(addr) = stack.pop() {
+            reachable.insert(addr);
+            if let Node::Branch(branch) = &*nodestore.read_node(addr).unwrap() {
+                stack.extend(branch.children.iter().flatten().map(|child| match child {
+                    Child::AddressWithHash(addr, _) => *addr,
+                    Child::Node(_) => panic!("committed nodes are hashed"),
+                }));
+            }
+        }
+
+        let mut free = HashSet::new();
+        for mut next in nodestore.header.free_lists {
+            while let Some(addr) = next {
+                free.insert(addr);
+                let stream = nodestore.storage.stream_from(addr.get()).unwrap();
+                let area: StoredArea<Area<Node, FreeArea>> =
+                    serializer().deserialize_from(stream).unwrap();
+                let Area::Free(area) = area.area else {
+                    panic!("free list entry {addr} is not free");
+                };
+                next = area.next_free_block;
+            }
+        }
+
+        let mut addr = NodeStoreHeader::SIZE;
+        let mut free_bytes = 0;
+        while addr < nodestore.header.size {
+            let area = LinearAddress::new(addr).unwrap();
+            assert!(
+                reachable.contains(&area) ^ free.contains(&area),
+                "area at {addr} is leaked or both used and free"
+            );
+            let size = nodestore.area_index_and_size(area).unwrap().1;
+            if free.contains(&area) {
+                free_bytes += size;
+            }
+            addr += size;
+        }
+        assert_eq!(addr, nodestore.header.size);
+        assert_eq!(free_bytes, nodestore.free_bytes());
+    }
+
+    #[test]
+    fn reserved_from_end() {
+        let parent = reserving_store();
+        let proposal = propose(&parent, wide_trie(5, 100));
+
+        // the estimate is exact, so the nodes fill the reservation
+        let areas = new_areas(&proposal);
+        let mut end = NodeStoreHeader::SIZE;
+        for (addr, size) in &areas {
+            assert_eq!(*addr, end);
+            end += size;
+        }
+        assert_eq!(proposal.header.size, end);
+        assert!(proposal.kind.unused_reservation.is_empty());
+
+        // the parent's header is a copy, so dropping the proposal gives
+        // back its reservation
+        drop(proposal);
+        assert_eq!(parent.header.size, NodeStoreHeader::SIZE);
+        let proposal = propose(&parent, wide_trie(5, 100));
+        assert_eq!(new_areas(&proposal), areas);
+    }
+
+    #[test]
+    fn reserved_from_free_area() {
+        let parent = reserving_store();
+
+        // a revision with one large leaf, freed by a later revision
+        let proposal = propose(
+            &parent,
+            Node::Leaf(LeafNode {
+                partial_path: Path::from([1]),
+                value: SmallVec::from_vec(vec![0; 20000]),
+            }),
+        );
+        let mut committed = commit_and_reopen(&proposal);
+        let freed = committed.root_address().unwrap();
+        let (_, freed_size) = committed.area_index_and_size(freed).unwrap();
+        committed.delete_node(freed).unwrap();
+        committed.header.root_address = None;
+        committed.flush_freelist().unwrap();
+        committed.flush_header().unwrap();
+        let size = committed.header.size;
+
+        let proposal = propose(&Arc::new(committed), wide_trie(5, 100));
+        assert_eq!(proposal.header.size, size);
+        let areas = new_areas(&proposal);
+        let mut end = freed.get();
+        for (addr, area_size) in &areas {
+            assert_eq!(*addr, end);
+            end += area_size;
+        }
+        let unused = &proposal.kind.unused_reservation;
+        assert_eq!(unused.first().unwrap().0.get(), end);
+        let unused_size: u64 = unused
+            .iter()
+            .map(|(_, index)| AREA_SIZES[*index as usize])
+            .sum();
+        assert_eq!(end + unused_size, freed.get() + freed_size);
+
+        // after a crash, the unused part of the reservation is still free
+        let reopened = commit_and_reopen(&proposal);
+        assert_no_leaks(&reopened);
+        for (addr, index) in unused.iter() {
+            assert!(reopened.header.free_lists[*index as usize].is_some());
+            assert_eq!(reopened.area_index_and_size(*addr).unwrap().0, *index);
+        }
+    }
+
+    #[test]
+    fn recover_interrupted_free() {
+        let parent =
+            Arc::new(NodeStore::new_empty_committed(MemStore::new(vec![]).into()).unwrap());
+        parent.flush_header_with_padding().unwrap();
+        let proposal = propose(&parent, wide_trie(5, 100));
+        let freed: Vec<_> = new_areas(&proposal)
+            .into_iter()
+            .map(|(addr, _)| LinearAddress::new(addr).unwrap())
+            .collect();
+        let mut committed = commit_and_reopen(&proposal);
+        let storage = committed.storage.clone();
+
+        // a later revision emptied the trie, and reaping the nodes it
+        // deleted stopped before the free lists were written
+        committed.header.root_address = None;
+        committed.flush_header().unwrap();
+        let mut reaping = NodeStore::open(storage.clone()).unwrap();
+        for addr in freed.iter().take(2) {
+            reaping.delete_node(*addr).unwrap();
+        }
+        drop(reaping);
+
+        let mut reopened = NodeStore::open(storage.clone()).unwrap();
+        let recovery = reopened.recover_freed(&freed).unwrap();
+        let expected = FreeListRecovery {
+            requeued: freed.len(),
+            cut: 0,
+        };
+        assert_eq!(recovery, expected);
+        reopened.flush_header().unwrap();
+        let mut reopened = NodeStore::open(storage.clone()).unwrap();
+        assert_no_leaks(&reopened);
+        // recovering again finds them all free
+        assert_eq!(
+            reopened.recover_freed(&freed).unwrap(),
+            FreeListRecovery::default()
+        );
+
+        // nodes written over free areas, with the free lists from before
+        let free_lists = reopened.header.free_lists;
+        let proposal = propose(&Arc::new(reopened), wide_trie(3, 100));
+        let mut committed = commit_and_reopen(&proposal);
+        committed.header.free_lists = free_lists;
+        committed.flush_header().unwrap();
+        let mut reopened = NodeStore::open(storage).unwrap();
+        assert!(reopened.recover_freed(&[]).unwrap().cut > 0);
+        assert_eq!(
+            reopened.recover_freed(&[]).unwrap(),
+            FreeListRecovery::default()
+        );
+        let root = reopened.root_address().unwrap();
+        assert!(reopened.read_node(root).is_ok());
+    }
+
+    #[test]
+    fn corrupt_node() {
+        let parent =
+            Arc::new(NodeStore::new_empty_committed(MemStore::new(vec![]).into()).unwrap());
+        parent.flush_header_with_padding().unwrap();
+        let proposal = propose(&parent, wide_trie(5, 100));
+        let mut committed = commit_and_reopen(&proposal);
+        let root = committed.root_address().unwrap();
+        let Node::Branch(branch) = &*committed.read_node(root).unwrap() else {
+            panic!("the root is a branch");
+        };
+        let Some(Child::AddressWithHash(leaf, _)) = branch.children.first().unwrap() else {
+            panic!("the root has a first child");
+        };
+        let leaf = *leaf;
+        let leaf_node = committed.read_node(leaf).unwrap();
+
+        // flip a bit of the last byte of the leaf's value
+        let stored_len = NodeStore::<Arc<ImmutableProposal>, MemStore>::stored_len(
+            committed.header.node_format(),
+            &leaf_node,
+        );
+        let offset = leaf.get() + stored_len - CHECKSUM_LEN as u64 - 1;
+        let mut byte = [0];
+        let mut stream = committed.storage.stream_from(offset).unwrap();
+        stream.read_exact(&mut byte).unwrap();
+        let [byte] = byte;
+        committed
+            .storage
+            .write(&WriteWitness::area(), offset, &[byte ^ 1])
+            .unwrap();
+
+        let err = committed.read_node(leaf).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        let corrupt = CorruptNode::from_io(&err).unwrap();
+        assert_eq!(corrupt.address, leaf);
+        assert_ne!(corrupt.expected, corrupt.actual);
+        assert!(committed.read_node(root).is_ok());
+
+        // files written before nodes had checksums are read unverified
+        committed.header.node_checksums = 0;
+        assert_ne!(committed.read_node(leaf).unwrap(), leaf_node);
+    }
 }
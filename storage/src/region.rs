@@ -0,0 +1,335 @@
+// Copyright (C) 2024, Ava Labs, Inc. All rights reserved.
+// See the file LICENSE.md for licensing terms.
+
+//! Ownership of the reserved regions at the start of a [NodeStore](crate::NodeStore).
+//!
+//! The first [NodeStoreHeader::SIZE] bytes of storage are not node areas; they
+//! hold the header, which in turn contains the heads of the free lists. More
+//! than one component wants to update these small shared regions, so every
+//! write to them goes through a typed accessor ([HeaderRegion] or
+//! [FreeListRegion]) that takes a lock for just that region.
+//!
+//! [WritableStorage::write] takes a [WriteWitness], which can only be obtained
+//! by holding a region's lock or, for node areas, from inside this crate. In
+//! debug builds, a write that strays into a reserved region without a witness
+//! for it panics.
+//!
+//! The locks are blocking rather than async: [WritableStorage::write] is
+//! synchronous, and a lock is only held for the one write it guards, never
+//! across an `.await`, so a task holding it can't be suspended while another
+//! task on the same thread waits for it.
+
+use std::io::Error;
+use std::mem::{offset_of, size_of};
+use std::sync::{Mutex, MutexGuard};
+
+use smallvec::SmallVec;
+
+use crate::nodestore::{FreeLists, NodeStoreHeader};
+use crate::WritableStorage;
+
+/// Identifies one of the reserved regions of storage
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RegionId {
+    /// The whole `NodeStoreHeader`, including its padding
+    Header,
+    /// The free list heads, which live inside the header
+    FreeLists,
+}
+
+/// A reserved range of storage and the component that owns it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Region {
+    /// Which region this is
+    pub id: RegionId,
+    /// The offset of the first byte of the region
+    pub offset: u64,
+    /// The length of the region in bytes
+    pub len: u64,
+    /// The component responsible for writing this region
+    pub owner: &'static str,
+}
+
+impl Region {
+    /// Returns true if `[offset, offset + len)` lies entirely inside this region
+    pub const fn contains(&self, offset: u64, len: u64) -> bool {
+        offset >= self.offset && offset + len <= self.offset + self.len
+    }
+
+    /// Returns true if `[offset, offset + len)` shares at least one byte with this region
+    pub const fn overlaps(&self, offset: u64, len: u64) -> bool {
+        len > 0 && offset < self.offset + self.len && self.offset < offset + len
+    }
+}
+
+/// The layout of the reserved regions. [RegionId::FreeLists] is nested inside
+/// [RegionId::Header], so writing the header requires both locks, and the
+/// bytes they share are never written by two writers at once.
+#[derive(Debug)]
+pub struct RegionMap;
+
+impl RegionMap {
+    /// Every reserved region
+    pub const REGIONS: [Region; 2] = [
+        Region {
+            id: RegionId::Header,
+            offset: 0,
+            len: NodeStoreHeader::SIZE,
+            owner: "commit: root move",
+        },
+        Region {
+            id: RegionId::FreeLists,
+            offset: offset_of!(NodeStoreHeader, free_lists) as u64,
+            len: size_of::<FreeLists>() as u64,
+            owner: "commit: free list flush",
+        },
+    ];
+
+    /// Look up a region by its id
+    pub const fn region(id: RegionId) -> Region {
+        match id {
+            RegionId::Header => Self::REGIONS[0],
+            RegionId::FreeLists => Self::REGIONS[1],
+        }
+    }
+
+    /// Returns true if any byte of `[offset, offset + len)` is reserved
+    pub fn is_reserved(offset: u64, len: u64) -> bool {
+        Self::REGIONS
+            .iter()
+            .any(|region| region.overlaps(offset, len))
+    }
+}
+
+/// The locks guarding each reserved region of one storage instance. See the
+/// module docs for why they are blocking locks.
+#[derive(Debug, Default)]
+pub struct RegionLocks {
+    header: Mutex<()>,
+    free_lists: Mutex<()>,
+}
+
+impl RegionLocks {
+    /// Create a new set of unlocked region locks
+    pub const fn new() -> Self {
+        Self {
+            header: Mutex::new(()),
+            free_lists: Mutex::new(()),
+        }
+    }
+
+    /// Lock a region for writing. Locks are always taken in the order of
+    /// [RegionMap::REGIONS], so nested regions can't deadlock.
+    fn lock(&self, id: RegionId) -> RegionGuard<'_> {
+        let mut guards = SmallVec::new();
+        if id == RegionId::Header {
+            guards.push(self.header.lock().expect("poisoned lock"));
+        }
+        guards.push(self.free_lists.lock().expect("poisoned lock"));
+
+        RegionGuard {
+            region: RegionMap::region(id),
+            _guards: guards,
+        }
+    }
+}
+
+/// Held while writing a reserved region
+#[derive(Debug)]
+struct RegionGuard<'a> {
+    region: Region,
+    _guards: SmallVec<[MutexGuard<'a, ()>; 2]>,
+}
+
+impl RegionGuard<'_> {
+    const fn witness(&self) -> WriteWitness<'_> {
+        WriteWitness {
+            region: Some(&self.region),
+        }
+    }
+}
+
+/// Permission to write to a range of storage, passed to [WritableStorage::write].
+///
+/// A witness for a reserved region only exists while that region's lock is
+/// held. Any other witness only permits writes to node areas.
+#[derive(Debug, Clone, Copy)]
+pub struct WriteWitness<'a> {
+    region: Option<&'a Region>,
+}
+
+impl WriteWitness<'_> {
+    /// A witness for writing node areas, which never overlap a reserved region
+    pub(crate) const fn area() -> WriteWitness<'static> {
+        WriteWitness { region: None }
+    }
+
+    /// A witness that permits writing anywhere, for testing storage directly
+    #[cfg(test)]
+    pub(crate) const fn unchecked() -> WriteWitness<'static> {
+        const ANYWHERE: Region = Region {
+            id: RegionId::Header,
+            offset: 0,
+            len: u64::MAX,
+            owner: "test",
+        };
+        WriteWitness {
+            region: Some(&ANYWHERE),
+        }
+    }
+
+    /// In debug builds, panic if this witness does not permit writing `len`
+    /// bytes at `offset`. Implementations of [WritableStorage] call this
+    /// before writing.
+    pub fn check(&self, offset: u64, len: u64) {
+        match self.region {
+            Some(region) => debug_assert!(
+                region.contains(offset, len),
+                "write of {len} bytes at {offset} is outside of {region:?}"
+            ),
+            None => debug_assert!(
+                !RegionMap::is_reserved(offset, len),
+                "write of {len} bytes at {offset} overlaps a reserved region"
+            ),
+        }
+    }
+}
+
+/// The only way to write the [RegionId::Header] region
+#[derive(Debug)]
+pub(crate) struct HeaderRegion;
+
+impl HeaderRegion {
+    /// Write the header, leaving any padding after it untouched
+    pub(crate) fn write<S: WritableStorage>(
+        storage: &S,
+        header: &NodeStoreHeader,
+    ) -> Result<(), Error> {
+        Self::write_bytes(storage, bytemuck::bytes_of(header))
+    }
+
+    /// Write the header followed by zeroes up to [NodeStoreHeader::SIZE]
+    pub(crate) fn write_with_padding<S: WritableStorage>(
+        storage: &S,
+        header: &NodeStoreHeader,
+    ) -> Result<(), Error> {
+        let header_bytes = bytemuck::bytes_of(header)
+            .iter()
+            .copied()
+            .chain(std::iter::repeat_n(0u8, NodeStoreHeader::EXTRA_BYTES))
+            .collect::<Box<[u8]>>();
+        debug_assert_eq!(header_bytes.len(), NodeStoreHeader::SIZE as usize);
+
+        Self::write_bytes(storage, &header_bytes)
+    }
+
+    fn write_bytes<S: WritableStorage>(storage: &S, bytes: &[u8]) -> Result<(), Error> {
+        let guard = storage.region_locks().lock(RegionId::Header);
+        storage.write(&guard.witness(), guard.region.offset, bytes)?;
+        Ok(())
+    }
+}
+
+/// The only way to write the [RegionId::FreeLists] region
+#[derive(Debug)]
+pub(crate) struct FreeListRegion;
+
+impl FreeListRegion {
+    /// Write the heads of every free list
+    pub(crate) fn write<S: WritableStorage>(
+        storage: &S,
+        free_lists: &FreeLists,
+    ) -> Result<(), Error> {
+        let guard = storage.region_locks().lock(RegionId::FreeLists);
+        storage.write(
+            &guard.witness(),
+            guard.region.offset,
+            bytemuck::bytes_of(free_lists),
+        )?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::unwrap_used)]
+mod tests {
+    use std::io::Read;
+    use std::num::NonZeroU64;
+    use std::sync::Arc;
+    use std::thread;
+
+    use super::*;
+    use crate::{MemStore, ReadableStorage};
+
+    fn read_header(storage: &MemStore) -> NodeStoreHeader {
+        let mut header = NodeStoreHeader::new();
+        storage
+            .stream_from(0)
+            .unwrap()
+            .read_exact(bytemuck::bytes_of_mut(&mut header))
+            .unwrap();
+        header
+    }
+
+    #[test]
+    fn free_lists_nested_in_header() {
+        let header = RegionMap::region(RegionId::Header);
+        let free_lists = RegionMap::region(RegionId::FreeLists);
+        assert!(header.contains(free_lists.offset, free_lists.len));
+        assert!(RegionMap::is_reserved(NodeStoreHeader::SIZE - 1, 1));
+        assert!(!RegionMap::is_reserved(NodeStoreHeader::SIZE, 1024));
+    }
+
+    #[test]
+    fn concurrent_region_writes() {
+        let storage = Arc::new(MemStore::new(vec![]));
+        HeaderRegion::write_with_padding(&*storage, &NodeStoreHeader::new()).unwrap();
+
+        let writers: Vec<_> = (1..=8u64)
+            .map(|writer| {
+                let storage = storage.clone();
+                thread::spawn(move || {
+                    for _ in 0..100 {
+                        if writer % 2 == 0 {
+                            let mut header = NodeStoreHeader::new();
+                            header.size = writer;
+                            header.free_lists = [NonZeroU64::new(writer); 23];
+                            HeaderRegion::write(&*storage, &header).unwrap();
+                        } else {
+                            let free_lists = [NonZeroU64::new(writer); 23];
+                            FreeListRegion::write(&*storage, &free_lists).unwrap();
+                        }
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // every free list head must come from the same write
+        let header = read_header(&storage);
+        let head = header.free_lists[0];
+        assert!(head.is_some());
+        assert!(header.free_lists.iter().all(|h| *h == head));
+        assert_eq!(header.endian_test, 1);
+        assert_eq!(storage.size().unwrap(), NodeStoreHeader::SIZE);
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "overlaps a reserved region")]
+    fn area_write_into_header() {
+        let storage = MemStore::new(vec![]);
+        storage.write(&WriteWitness::area(), 8, &[1]).unwrap();
+    }
+
+    #[test]
+    #[cfg(debug_assertions)]
+    #[should_panic(expected = "is outside of")]
+    fn free_list_witness_outside_region() {
+        let storage = MemStore::new(vec![]);
+        let guard = storage.region_locks().lock(RegionId::FreeLists);
+        storage.write(&guard.witness(), 0, &[1]).unwrap();
+    }
+}
@@ -43,6 +43,15 @@ impl From<[u8; 32]> for TrieHash {
     }
 }
 
+impl TryFrom<&[u8]> for TrieHash {
+    type Error = std::array::TryFromSliceError;
+
+    /// The hash in `value`, which must be exactly 32 bytes long
+    fn try_from(value: &[u8]) -> Result<Self, Self::Error> {
+        <[u8; 32]>::try_from(value).map(Into::into)
+    }
+}
+
 impl From<GenericArray<u8, typenum::U32>> for TrieHash {
     fn from(value: GenericArray<u8, typenum::U32>) -> Self {
         TrieHash(value)